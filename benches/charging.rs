@@ -0,0 +1,121 @@
+//! Compares the charging styles of the gas metering by running the metered modules on wasmi.
+//!
+//! Run with `cargo bench --features wasm-tools,wasmi --bench charging`. Every style meters the
+//! same module with the default rules and runs its exports with enough gas; the times are the
+//! median of `RUNS` runs.
+
+use std::time::{Duration, Instant};
+
+use pwasm_utils::backend::{Backend, ParityWasm, WasmTools};
+use pwasm_utils::parity_wasm::elements;
+use pwasm_utils::rules;
+use wasmi::{Caller, Config, Engine, Linker, Module, Store};
+
+const RUNS: usize = 21;
+
+const SOURCE: &str = r#"
+(module
+	(func $fib (export "fib") (param i32) (result i32)
+		(if (result i32) (i32.lt_u (local.get 0) (i32.const 2))
+			(then (local.get 0))
+			(else
+				(i32.add
+					(call $fib (i32.sub (local.get 0) (i32.const 1)))
+					(call $fib (i32.sub (local.get 0) (i32.const 2)))))))
+	(func (export "sum") (param i32) (result i32)
+		(local i32)
+		(block
+			(loop
+				(br_if 1 (i32.eqz (local.get 0)))
+				(local.set 1 (i32.add (local.get 1) (local.get 0)))
+				(local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+				(br 0)))
+		(local.get 1)))
+"#;
+
+/// The exports which are run with their argument.
+const EXPORTS: &[(&str, i32)] = &[("fib", 25), ("sum", 1_000_000)];
+
+/// How the module is metered and encoded.
+enum Style {
+	/// `inject_gas_counter`, the gas function traps.
+	Trapping,
+	/// `inject_gas_counter_with_tail_calls` with a regular call of the out of gas function.
+	Call,
+	/// `inject_gas_counter_with_tail_calls` with `return_call` of the out of gas function.
+	ReturnCall,
+}
+
+impl Style {
+	const ALL: &'static [Style] = &[Style::Trapping, Style::Call, Style::ReturnCall];
+
+	fn name(&self) -> &'static str {
+		match self {
+			Style::Trapping => "trapping gas function",
+			Style::Call => "call on out of gas",
+			Style::ReturnCall => "return_call on out of gas",
+		}
+	}
+
+	fn instrument(&self, module: elements::Module) -> Vec<u8> {
+		let rules = rules::Set::default();
+		match self {
+			Style::Trapping => ParityWasm.encode(pwasm_utils::inject_gas_counter(module, &rules, "env").unwrap()),
+			Style::Call => ParityWasm.encode(pwasm_utils::inject_gas_counter_with_tail_calls(module, &rules, "env").unwrap()),
+			Style::ReturnCall => WasmTools.encode_with_tail_calls(
+				pwasm_utils::inject_gas_counter_with_tail_calls(module, &rules, "env").unwrap(),
+			),
+		}
+			.unwrap()
+	}
+
+	fn link(&self, linker: &mut Linker<u64>) {
+		match self {
+			Style::Trapping => linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i32| {
+				let gas = caller.data_mut();
+				*gas = gas.saturating_sub(amount as u32 as u64);
+			}),
+			Style::Call | Style::ReturnCall => linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i32| -> i32 {
+				let gas = caller.data_mut();
+				*gas = gas.saturating_sub(amount as u32 as u64);
+				(*gas == 0) as i32
+			}),
+		}
+			.unwrap();
+	}
+}
+
+fn median(mut times: Vec<Duration>) -> Duration {
+	times.sort();
+	times[times.len() / 2]
+}
+
+fn main() {
+	let module: elements::Module = elements::deserialize_buffer(&wabt::wat2wasm(SOURCE).unwrap()).unwrap();
+	let mut config = Config::default();
+	config.wasm_tail_call(true);
+	let engine = Engine::new(&config);
+
+	for style in Style::ALL {
+		let binary = style.instrument(module.clone());
+		let metered = Module::new(&engine, &binary[..]).unwrap();
+		let mut store = Store::new(&engine, u64::MAX);
+		let mut linker = Linker::new(&engine);
+		style.link(&mut linker);
+		let instance = linker.instantiate(&mut store, &metered).unwrap().start(&mut store).unwrap();
+
+		print!("{:<28}{:>6} bytes", style.name(), binary.len());
+		for (name, argument) in EXPORTS {
+			let func = instance.get_typed_func::<i32, i32>(&store, name).unwrap();
+			let times = (0..RUNS)
+				.map(|_| {
+					let start = Instant::now();
+					func.call(&mut store, *argument).unwrap();
+					start.elapsed()
+				})
+				.collect();
+			print!("{:>10} {:>8.2?}", name, median(times));
+		}
+		println!();
+	}
+}
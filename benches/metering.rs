@@ -0,0 +1,101 @@
+//! Measures the time and the memory allocated by the gas metering of a module of several
+//! megabytes.
+//!
+//! Run with `cargo bench --bench metering`. The times are the median of `RUNS` runs, the
+//! allocated bytes are the ones of the last run and don't include cloning the input.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use pwasm_utils::parity_wasm::{builder, elements};
+use pwasm_utils::rules;
+use pwasm_utils::{GasConfig, GlobalGasConfig};
+
+const RUNS: usize = 11;
+const FUNCTIONS: usize = 2_000;
+const DATA_BYTES: usize = 4 << 20;
+
+/// The system allocator, counting the bytes allocated.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout)
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::Relaxed);
+		System.realloc(ptr, layout, new_size)
+	}
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A module with `FUNCTIONS` functions looping over a memory growing it, and a data segment of
+/// `DATA_BYTES` bytes.
+fn module() -> elements::Module {
+	use elements::Instruction::*;
+
+	let mut module = builder::module()
+		.memory().with_min(64).build()
+		.data().offset(I32Const(0)).value(vec![7; DATA_BYTES]).build();
+	for _ in 0..FUNCTIONS {
+		let mut instructions = vec![Loop(elements::BlockType::NoResult)];
+		for _ in 0..100 {
+			instructions.extend(vec![GetLocal(0), I32Const(1), I32Add, SetLocal(0)]);
+		}
+		instructions.extend(vec![
+			GetLocal(0), I32Const(1_000), I32LtU, BrIf(0),
+			End,
+			GetLocal(0), GrowMemory(0),
+			End,
+		]);
+		module = module.function()
+			.signature().with_param(elements::ValueType::I32).with_result(elements::ValueType::I32).build()
+			.body().with_instructions(elements::Instructions::new(instructions)).build()
+			.build();
+	}
+	module.build()
+}
+
+fn measure<F: Fn(elements::Module)>(module: &elements::Module, meter: F) -> (Duration, usize) {
+	let mut times = Vec::with_capacity(RUNS);
+	let mut allocated = 0;
+	for _ in 0..RUNS {
+		let input = module.clone();
+		let before = ALLOCATED.load(Ordering::Relaxed);
+		let start = Instant::now();
+		meter(input);
+		times.push(start.elapsed());
+		allocated = ALLOCATED.load(Ordering::Relaxed) - before;
+	}
+	times.sort();
+	(times[RUNS / 2], allocated)
+}
+
+fn main() {
+	let module = module();
+	let size = elements::serialize(module.clone()).unwrap().len();
+	let rules = rules::Set::default().with_grow_cost(1);
+	println!("module of {} bytes", size);
+
+	let (time, allocated) = measure(&module, |module| {
+		pwasm_utils::inject_gas_counter(module, &rules, GasConfig::default()).unwrap();
+	});
+	println!("{:<28}{:>10.2?}{:>12} bytes allocated", "inject_gas_counter", time, allocated);
+
+	let (time, allocated) = measure(&module, |module| {
+		let config = GlobalGasConfig::new("gas_left").with_trap_reason("trap_reason");
+		pwasm_utils::inject_gas_counter_with_global(module, &rules, config).unwrap();
+	});
+	println!("{:<28}{:>10.2?}{:>12} bytes allocated", "inject_gas_counter_with_global", time, allocated);
+}
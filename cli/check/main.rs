@@ -1,4 +1,4 @@
-use pwasm_utils::logger;
+use pwasm_utils::{io, logger};
 use clap::{App, Arg};
 use parity_wasm::elements;
 
@@ -42,12 +42,12 @@ fn main() {
 						.arg(Arg::with_name("input")
 							.index(1)
 							.required(true)
-							.help("Input WASM file"))
+							.help("Input WASM file, or - for stdin"))
 						.get_matches();
 
 	let input = matches.value_of("input").expect("is required; qed");
 
-	let module = parity_wasm::deserialize_file(&input).expect("Input module deserialization failed");
+	let module = io::deserialize_file(input).expect("Input module deserialization failed");
 
 	for section in module.sections() {
 		match section {
@@ -4,13 +4,14 @@ fn main() {
 	let args = std::env::args().collect::<Vec<_>>();
 	if args.len() != 3 {
 		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
+		println!("Use - for stdin/stdout");
 		return;
 	}
 
 	let module = pwasm_utils::externalize(
-		parity_wasm::deserialize_file(&args[1]).expect("Module to deserialize ok"),
+		pwasm_utils::io::deserialize_file(&args[1]).expect("Module to deserialize ok"),
 		vec!["_free", "_malloc", "_memcpy", "_memset", "_memmove"],
 	);
 
-	parity_wasm::serialize_to_file(&args[2], module).expect("Module to serialize ok");
+	pwasm_utils::io::serialize_to_file(&args[2], module).expect("Module to serialize ok");
 }
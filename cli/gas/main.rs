@@ -1,4 +1,4 @@
-use pwasm_utils::{self as utils, logger};
+use pwasm_utils::{self as utils, io, logger};
 use std::env;
 
 fn main() {
@@ -7,15 +7,16 @@ fn main() {
 	let args = env::args().collect::<Vec<_>>();
 	if args.len() != 3 {
 		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
+		println!("Use - for stdin/stdout");
 		return;
 	}
 
 	// Loading module
-	let module = parity_wasm::deserialize_file(&args[1]).expect("Module deserialization to succeed");
+	let module = io::deserialize_file(&args[1]).expect("Module deserialization to succeed");
 
 	let result = utils::inject_gas_counter(
 		module, &utils::rules::Set::default(), "env"
-	).expect("Failed to inject gas. Some forbidden opcodes?");
+	).map_err(|(_, e)| e).expect("Failed to inject gas");
 
-	parity_wasm::serialize_to_file(&args[2], result).expect("Module serialization to succeed")
+	io::serialize_to_file(&args[2], result).expect("Module serialization to succeed")
 }
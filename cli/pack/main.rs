@@ -1,4 +1,4 @@
-use pwasm_utils::{self as utils, logger};
+use pwasm_utils::{self as utils, io, logger};
 use clap::{App, Arg};
 
 fn main() {
@@ -10,17 +10,17 @@ fn main() {
 		.arg(Arg::with_name("input")
 			.index(1)
 			.required(true)
-			.help("Input WASM file"))
+			.help("Input WASM file, or - for stdin"))
 		.arg(Arg::with_name("output")
 			.index(2)
 			.required(true)
-			.help("Output WASM file"))
+			.help("Output WASM file, or - for stdout"))
 		.get_matches();
 
 	let input = matches.value_of("input").expect("is required; qed");
 	let output = matches.value_of("output").expect("is required; qed");
 
-	let module = parity_wasm::deserialize_file(&input).expect("Input module deserialization failed");
+	let module = io::deserialize_file(input).expect("Input module deserialization failed");
 	let ctor_module = module.clone();
 	let raw_module = parity_wasm::serialize(module).expect("Serialization failed");
 
@@ -29,5 +29,5 @@ fn main() {
 	// Optimize constructor, since it does not need everything
 	utils::optimize(&mut result_module, vec![target_runtime.symbols().call]).expect("Optimization failed");
 
-	parity_wasm::serialize_to_file(&output, result_module).expect("Serialization failed");
+	io::serialize_to_file(output, result_module).expect("Serialization failed");
 }
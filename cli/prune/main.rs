@@ -1,4 +1,4 @@
-use pwasm_utils::{self as utils, logger};
+use pwasm_utils::{self as utils, io, logger};
 use clap::{App, Arg};
 
 fn main() {
@@ -10,11 +10,11 @@ fn main() {
 		.arg(Arg::with_name("input")
 			.index(1)
 			.required(true)
-			.help("Input WASM file"))
+			.help("Input WASM file, or - for stdin"))
 		.arg(Arg::with_name("output")
 			.index(2)
 			.required(true)
-			.help("Output WASM file"))
+			.help("Output WASM file, or - for stdout"))
 		.arg(Arg::with_name("exports")
 			.long("exports")
 			.short("e")
@@ -32,12 +32,12 @@ fn main() {
 	let input = matches.value_of("input").expect("is required; qed");
 	let output = matches.value_of("output").expect("is required; qed");
 
-	let mut module = parity_wasm::deserialize_file(&input).unwrap();
+	let mut module = io::deserialize_file(input).unwrap();
 
 	// Invoke optimizer
 	//   Contract is supposed to have only these functions as public api
 	//   All other symbols not usable by this list is optimized away
 	utils::optimize(&mut module, exports).expect("Optimizer failed");
 
-	parity_wasm::serialize_to_file(&output, module).expect("Serialization failed");
+	io::serialize_to_file(output, module).expect("Serialization failed");
 }
@@ -1,4 +1,4 @@
-use pwasm_utils::{logger, stack_height};
+use pwasm_utils::{io, logger, stack_height};
 use std::env;
 
 fn main() {
@@ -7,6 +7,7 @@ fn main() {
 	let args = env::args().collect::<Vec<_>>();
 	if args.len() != 3 {
 		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
+		println!("Use - for stdin/stdout");
 		return;
 	}
 
@@ -14,11 +15,11 @@ fn main() {
 	let output_file = &args[2];
 
 	// Loading module
-	let module = parity_wasm::deserialize_file(&input_file).expect("Module deserialization to succeed");
+	let module = io::deserialize_file(input_file).expect("Module deserialization to succeed");
 
 	let result = stack_height::inject_limiter(
 		module, 1024
 	).expect("Failed to inject stack height counter");
 
-	parity_wasm::serialize_to_file(&output_file, result).expect("Module serialization to succeed")
+	io::serialize_to_file(output_file, result).expect("Module serialization to succeed")
 }
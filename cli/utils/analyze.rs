@@ -0,0 +1,212 @@
+//! `analyze` subcommand: reports cost characteristics of a module.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements::{self, ImportCountType, Instruction};
+use pwasm_utils::{self as utils, io, stack_height};
+use serde::Serialize;
+
+use super::{rules, Error};
+
+#[derive(Debug, Serialize)]
+pub struct FunctionReport {
+	/// Index of the function in the function index space of the original module.
+	pub index: u32,
+	/// Number of calls to the gas function injected into the function.
+	pub metered_blocks: usize,
+	/// Sum of the gas charged by all metered blocks of the function.
+	pub total_gas: u64,
+	/// The largest amount of gas charged at once.
+	pub max_block_gas: u32,
+	/// Stack cost as computed by the stack height limiter.
+	pub stack_cost: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstantiationReport {
+	pub initial_memory_pages: u32,
+	pub data_segments: usize,
+	pub data_bytes: usize,
+	pub table_elements: usize,
+	pub globals: usize,
+	pub has_start: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub functions: Vec<FunctionReport>,
+	pub max_stack_cost: u32,
+	pub opcodes: BTreeMap<String, usize>,
+	pub instantiation: InstantiationReport,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}", self.file)?;
+
+		writeln!(f, "\ngas (function: blocks, total, max block, stack cost)")?;
+		for function in &self.functions {
+			writeln!(
+				f,
+				"  {:>6}: {:>6} {:>10} {:>10} {:>8}",
+				function.index,
+				function.metered_blocks,
+				function.total_gas,
+				function.max_block_gas,
+				function.stack_cost,
+			)?;
+		}
+		writeln!(f, "  max stack cost: {}", self.max_stack_cost)?;
+
+		writeln!(f, "\nopcodes")?;
+		let mut opcodes = self.opcodes.iter().collect::<Vec<_>>();
+		opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+		for (opcode, count) in opcodes {
+			writeln!(f, "  {:<24} {:>8}", opcode, count)?;
+		}
+
+		let instantiation = &self.instantiation;
+		writeln!(f, "\ninstantiation")?;
+		writeln!(f, "  initial memory pages: {}", instantiation.initial_memory_pages)?;
+		writeln!(f, "  data segments: {} ({} bytes)", instantiation.data_segments, instantiation.data_bytes)?;
+		writeln!(f, "  table elements: {}", instantiation.table_elements)?;
+		writeln!(f, "  globals: {}", instantiation.globals)?;
+		writeln!(f, "  start function: {}", if instantiation.has_start { "yes" } else { "no" })
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("analyze")
+		.about("Reports gas, opcode, stack and instantiation cost characteristics of a module")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("rules")
+			.long("rules")
+			.short("r")
+			.takes_value(true)
+			.help("TOML file with the gas rules. Default rules are used if not specified"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let rules = rules::load(matches.value_of("rules"))?;
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = analyze(input, module, &rules)?;
+	super::print_report(&report, matches);
+
+	Ok(true)
+}
+
+pub fn analyze(file: &str, module: elements::Module, rules: &utils::rules::Set) -> Result<Report, Error> {
+	let mut opcodes = BTreeMap::new();
+	for body in module.code_section().map(|s| s.bodies()).unwrap_or(&[]) {
+		for instruction in body.code().elements() {
+			let name = instruction.to_string();
+			let name = name.split_whitespace().next().unwrap_or_default().to_string();
+			*opcodes.entry(name).or_insert(0) += 1;
+		}
+	}
+
+	let stack_costs = stack_height::compute_stack_costs(&module)
+		.map_err(|e| Error::Analysis(format!("{:?}", e)))?;
+
+	let instantiation = InstantiationReport {
+		initial_memory_pages: module.memory_section()
+			.and_then(|s| s.entries().first())
+			.map_or(0, |m| m.limits().initial()),
+		data_segments: module.data_section().map_or(0, |s| s.entries().len()),
+		data_bytes: module.data_section()
+			.map_or(0, |s| s.entries().iter().map(|d| d.value().len()).sum()),
+		table_elements: module.elements_section()
+			.map_or(0, |s| s.entries().iter().map(|e| e.members().len()).sum()),
+		globals: module.globals_space(),
+		has_start: module.start_section().is_some(),
+	};
+
+	// Dry-run the gas metering pass and inspect the injected charges.
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+	let gas_func = imported_funcs;
+	let metered = utils::inject_gas_counter(module, rules, "env")
+		.map_err(|(_, e)| Error::Gas(e))?;
+
+	let mut functions = Vec::new();
+	for (index, body) in metered.code_section().map(|s| s.bodies()).unwrap_or(&[]).iter().enumerate() {
+		let index = index as u32 + imported_funcs;
+		let stack_cost = match stack_costs.get(index as usize) {
+			Some(stack_cost) => *stack_cost,
+			// Functions appended by the gas pass are not part of the original module.
+			None => break,
+		};
+
+		let charges = body.code().elements()
+			.windows(2)
+			.filter_map(|pair| match pair {
+				[Instruction::I32Const(cost), Instruction::Call(func)] if *func == gas_func => Some(*cost as u32),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+
+		functions.push(FunctionReport {
+			index,
+			metered_blocks: charges.len(),
+			total_gas: charges.iter().map(|cost| *cost as u64).sum(),
+			max_block_gas: charges.iter().cloned().max().unwrap_or(0),
+			stack_cost,
+		});
+	}
+
+	Ok(Report {
+		file: file.to_string(),
+		max_stack_cost: stack_costs.iter().cloned().max().unwrap_or(0),
+		functions,
+		opcodes,
+		instantiation,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reports_costs() {
+		let module = elements::deserialize_buffer(&wabt::wat2wasm(r#"
+			(module
+				(import "env" "ext" (func))
+				(memory 2)
+				(data (i32.const 0) "abc")
+				(func (param i32) (result i32)
+					get_local 0
+					if (result i32)
+						i32.const 1
+					else
+						i32.const 2
+					end))
+		"#).unwrap()).unwrap();
+
+		let report = analyze("test.wasm", module, &utils::rules::Set::default()).unwrap();
+
+		assert_eq!(report.functions.len(), 1);
+		assert_eq!(report.functions[0].index, 1);
+		assert_eq!(report.functions[0].metered_blocks, 3);
+		assert_eq!(report.functions[0].total_gas, 4);
+		assert_eq!(report.functions[0].max_block_gas, 2);
+		assert_eq!(report.functions[0].stack_cost, 2);
+		assert_eq!(report.opcodes["i32.const"], 2);
+		assert_eq!(report.instantiation.initial_memory_pages, 2);
+		assert_eq!(report.instantiation.data_bytes, 3);
+	}
+}
@@ -0,0 +1,112 @@
+//! `bounds` subcommand: reports static bounds on the gas charged by each exported function.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::analysis::{self, ExportGas};
+use pwasm_utils::io;
+use serde::Serialize;
+
+use super::{rules, Error};
+
+/// Name of the custom section the bounds are embedded in with `--output`.
+const SECTION_NAME: &str = "gas_bounds";
+
+#[derive(Debug, Serialize)]
+pub struct ExportReport {
+	pub name: String,
+	pub function: u32,
+	pub min: u64,
+	/// The upper bound, or `None` if it is unbounded.
+	pub max: Option<u64>,
+	pub host_calls: bool,
+}
+
+impl From<ExportGas> for ExportReport {
+	fn from(bounds: ExportGas) -> Self {
+		ExportReport {
+			name: bounds.name,
+			function: bounds.function,
+			min: bounds.min,
+			max: bounds.max,
+			host_calls: bounds.host_calls,
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub exports: Vec<ExportReport>,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}", self.file)?;
+		for export in &self.exports {
+			let max = export.max.map_or_else(|| "unbounded".to_string(), |max| max.to_string());
+			writeln!(
+				f,
+				"  {} (function {}): {} to {}{}",
+				export.name,
+				export.function,
+				export.min,
+				max,
+				if export.host_calls { ", plus host functions" } else { "" },
+			)?;
+		}
+		Ok(())
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("bounds")
+		.about("Reports static lower and upper bounds on the gas charged by each exported function")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("rules")
+			.long("rules")
+			.short("r")
+			.takes_value(true)
+			.help("TOML file with the gas rules. Default rules are used if not specified"))
+		.arg(Arg::with_name("output")
+			.long("output")
+			.short("o")
+			.takes_value(true)
+			.help("Output WASM file, or - for stdout, with the bounds embedded in a custom section"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let rules = rules::load(matches.value_of("rules"))?;
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let mut module = super::deserialize(&bytes, input, matches)?;
+
+	let report = Report {
+		file: input.to_string(),
+		exports: analysis::gas_bounds(&module, &rules).map_err(Error::Gas)?
+			.into_iter()
+			.map(Into::into)
+			.collect(),
+	};
+
+	match matches.value_of("output") {
+		Some(output) => {
+			let payload = serde_json::to_vec(&report.exports).expect("reports are always serializable; qed");
+			module.set_custom_section(SECTION_NAME, payload);
+			io::serialize_to_file(output, module).map_err(Error::Encoding)?;
+			super::print_report_for(&report, matches, output);
+		}
+		None => super::print_report(&report, matches),
+	}
+
+	Ok(true)
+}
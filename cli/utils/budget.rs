@@ -0,0 +1,121 @@
+//! `budget` subcommand: checks a module against a size budget before deployment.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::analysis::{self, Budget, Exceeded, SizeReport, Suggestion};
+use pwasm_utils::io;
+use serde::Serialize;
+
+use super::{rules, Error};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub passed: bool,
+	pub original_size: usize,
+	pub size: usize,
+	pub data_size: usize,
+	pub functions: usize,
+	pub exceeded: Vec<String>,
+	pub suggestions: Vec<String>,
+}
+
+impl Report {
+	fn new(file: &str, report: SizeReport) -> Self {
+		Report {
+			file: file.to_string(),
+			passed: report.fits(),
+			original_size: report.original_size,
+			size: report.size,
+			data_size: report.data_size,
+			functions: report.functions,
+			exceeded: report.exceeded.iter().map(|exceeded| match exceeded {
+				Exceeded::Size { actual, limit } =>
+					format!("instrumented size is {} bytes, the limit is {}", actual, limit),
+				Exceeded::DataSize { actual, limit } =>
+					format!("data segments take {} bytes, the limit is {}", actual, limit),
+				Exceeded::Functions { actual, limit } =>
+					format!("{} functions are defined, the limit is {}", actual, limit),
+			}).collect(),
+			suggestions: report.suggestions.iter().map(|suggestion| match suggestion {
+				Suggestion::LargeFunction { index, size } =>
+					format!("function {} takes {} bytes", index, size),
+				Suggestion::LargeDataSegment { index, size } =>
+					format!("data segment {} takes {} bytes", index, size),
+				Suggestion::MeteringOverhead { index, overhead } =>
+					format!("gas metering adds {} bytes to function {}", overhead, index),
+			}).collect(),
+		}
+	}
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {}", self.file, if self.passed { "fits" } else { "over budget" })?;
+		writeln!(f, "  size: {} bytes ({} before instrumentation)", self.size, self.original_size)?;
+		writeln!(f, "  data: {} bytes", self.data_size)?;
+		writeln!(f, "  functions: {}", self.functions)?;
+		for exceeded in &self.exceeded {
+			writeln!(f, "  exceeded: {}", exceeded)?;
+		}
+		for suggestion in &self.suggestions {
+			writeln!(f, "  consider: {}", suggestion)?;
+		}
+		Ok(())
+	}
+}
+
+fn limit_arg(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+	Arg::with_name(name)
+		.long(name)
+		.takes_value(true)
+		.help(help)
+}
+
+fn limit(matches: &ArgMatches, name: &str) -> Result<Option<usize>, Error> {
+	matches.value_of(name)
+		.map(|value| value.parse()
+			.map_err(|_| Error::Analysis(format!("--{} should be a positive integer", name))))
+		.transpose()
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("budget")
+		.about("Checks the size of a module after gas metering against a budget")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(limit_arg("max-size", "Maximal size of the instrumented module in bytes"))
+		.arg(limit_arg("max-data-size", "Maximal total size of the data segments in bytes"))
+		.arg(limit_arg("max-functions", "Maximal number of defined functions"))
+		.arg(Arg::with_name("rules")
+			.long("rules")
+			.short("r")
+			.takes_value(true)
+			.help("TOML file with the gas rules. Default rules are used if not specified"))
+		.arg(super::format_arg())
+}
+
+/// Runs the subcommand. Returns whether the module fits into the budget.
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+
+	let mut budget = Budget::new().with_rules(rules::load(matches.value_of("rules"))?);
+	budget.max_size = limit(matches, "max-size")?;
+	budget.max_data_size = limit(matches, "max-data-size")?;
+	budget.max_functions = limit(matches, "max-functions")?;
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = Report::new(input, analysis::size_budget(&module, &budget).map_err(Error::Gas)?);
+	super::print_report(&report, matches);
+
+	Ok(report.passed)
+}
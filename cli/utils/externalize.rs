@@ -0,0 +1,34 @@
+//! `externalize` subcommand: replaces functions of a module with imports.
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils as utils;
+
+use super::Error;
+
+pub fn subcommand() -> App<'static, 'static> {
+	super::io_args(SubCommand::with_name("externalize")
+		.about("Replaces the exported functions with the given names by imports from env"))
+		.arg(Arg::with_name("functions")
+			.long("functions")
+			.short("f")
+			.takes_value(true)
+			.default_value("_free,_malloc,_memcpy,_memset,_memmove")
+			.help("Comma-separated list of functions to import instead"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let functions: Vec<&str> = matches.value_of("functions").expect("has a default value; qed").split(',').collect();
+
+	super::transform(matches, |module| {
+		// `externalize` expects these to hold.
+		if module.import_section().is_none() {
+			return Err(Error::Analysis("the module has no import section to add the imports to".into()));
+		}
+		let exports = module.export_section().map_or(&[][..], |section| section.entries());
+		if let Some(missing) = functions.iter().find(|function| !exports.iter().any(|entry| entry.field() == **function)) {
+			return Err(Error::Analysis(format!("`{}` is not exported", missing)));
+		}
+		Ok(utils::externalize(module, functions))
+	})
+}
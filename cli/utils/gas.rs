@@ -0,0 +1,167 @@
+//! `gas` subcommand: injects gas metering into a module.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements;
+use pwasm_utils::{self as utils, debug_offsets, io, strip};
+use serde::Serialize;
+
+use super::{rules, Error};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub original_size: usize,
+	pub metered_size: usize,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} -> {} bytes", self.file, self.original_size, self.metered_size)
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("gas")
+		.about("Injects gas metering into a module")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("output")
+			.long("output")
+			.short("o")
+			.takes_value(true)
+			.help("Output WASM file, or - for stdout. The input file is overwritten if not specified"))
+		.arg(Arg::with_name("rules")
+			.long("rules")
+			.short("r")
+			.takes_value(true)
+			.help("TOML file with the gas rules. Default rules are used if not specified"))
+		.arg(Arg::with_name("module")
+			.long("module")
+			.takes_value(true)
+			.default_value("env")
+			.help("Module from which the gas function is imported"))
+		.arg(Arg::with_name("field")
+			.long("field")
+			.takes_value(true)
+			.default_value("gas")
+			.help("Field name under which the gas function is imported"))
+		.arg(Arg::with_name("i64")
+			.long("i64")
+			.help("Pass gas amounts to the gas function as i64"))
+		.arg(Arg::with_name("fold_charges")
+			.long("fold-charges")
+			.help("Fold the charges the module already makes by calling the gas function into the injected ones"))
+		.arg(Arg::with_name("exit_charges")
+			.long("exit-charges")
+			.help("Charge leaf functions with simple control flow once at their exit"))
+		.arg(Arg::with_name("loop_charges")
+			.long("loop-charges")
+			.help("Charge only at function entries and loop headers, for the code up to the next loop"))
+		.arg(Arg::with_name("join_charges")
+			.long("join-charges")
+			.help("Charge the code following an if at the end of its arms when they are the only ways to reach it"))
+		.arg(Arg::with_name("merge_returns")
+			.long("merge-returns")
+			.help("Charge the code following blocks which may return with the code preceding them"))
+		.arg(Arg::with_name("merge_loop_branches")
+			.long("merge-loop-branches")
+			.help("Charge the code following a br_if to a loop with the code preceding the br_if"))
+		.arg(Arg::with_name("inline_grow_charges")
+			.long("inline-grow-charges")
+			.help("Charge memory.grow by code inlined at each memory.grow instead of an added function"))
+		.arg(Arg::with_name("remove_dead_code")
+			.long("remove-dead-code")
+			.conflicts_with("debug_offsets")
+			.help("Remove the code following br, return and unreachable up to the end of its block before metering"))
+		.arg(Arg::with_name("exempt")
+			.long("exempt")
+			.takes_value(true)
+			.help("Comma-separated names of exported functions whose bodies are not metered"))
+		.arg(Arg::with_name("strict")
+			.long("strict")
+			.help("Fail on instructions whose class has no entry in the rules instead of charging the regular cost"))
+		.arg(Arg::with_name("validate_output")
+			.long("validate-output")
+			.help("Validate the metered module, failing if it is invalid"))
+		.arg(Arg::with_name("debug_offsets")
+			.long("debug-offsets")
+			.help("Embed a table translating the code offsets of the debug info into the metered module"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let output = matches.value_of("output").unwrap_or(input);
+	let mut config = utils::GasConfig::new(
+		matches.value_of("module").expect("has a default value; qed"),
+		matches.value_of("field").expect("has a default value; qed"),
+	);
+	if matches.is_present("i64") {
+		config = config.with_i64_amounts();
+	}
+	if matches.is_present("fold_charges") {
+		config = config.with_folded_charges();
+	}
+	if matches.is_present("exit_charges") {
+		config = config.with_exit_charges();
+	}
+	if matches.is_present("loop_charges") {
+		config = config.with_loop_charges();
+	}
+	if matches.is_present("join_charges") {
+		config = config.with_joined_charges();
+	}
+	if matches.is_present("merge_returns") {
+		config = config.with_merged_returns();
+	}
+	if matches.is_present("merge_loop_branches") {
+		config = config.with_merged_loop_branches();
+	}
+	if matches.is_present("inline_grow_charges") {
+		config = config.with_inline_grow_charges();
+	}
+	if matches.is_present("validate_output") {
+		config = config.with_output_validation();
+	}
+	let exempt: Vec<&str> = matches.value_of("exempt").map_or(Vec::new(), |exempt| exempt.split(',').collect());
+	config = config.with_exempt_exports(&exempt);
+	let rules = rules::load(matches.value_of("rules"))?;
+	let rules = if matches.is_present("strict") { rules.with_unknown_policy(utils::rules::UnknownPolicy::Reject) } else { rules };
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let mut module = super::deserialize(&bytes, input, matches)?;
+	if matches.is_present("remove_dead_code") {
+		strip::remove_dead_code(&mut module);
+	}
+
+	let metered = utils::inject_gas_counter(module, &rules, config)
+		.map_err(|(_, e)| Error::Gas(e))?;
+	let metered = if matches.is_present("debug_offsets") {
+		let encoded = elements::serialize(metered.clone()).map_err(Error::Encoding)?;
+		let table = debug_offsets::gas_offset_table(&bytes, &encoded)
+			.map_err(|e| Error::Analysis(format!("{:?}", e)))?;
+		let mut metered = metered;
+		table.embed(&mut metered);
+		elements::serialize(metered).map_err(Error::Encoding)?
+	} else {
+		elements::serialize(metered).map_err(Error::Encoding)?
+	};
+	io::write(output, &metered).map_err(Error::Io)?;
+
+	let report = Report {
+		file: input.to_string(),
+		original_size: bytes.len(),
+		metered_size: metered.len(),
+	};
+	super::print_report_for(&report, matches, output);
+
+	Ok(true)
+}
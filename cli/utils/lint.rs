@@ -0,0 +1,90 @@
+//! `lint` subcommand: warns about common pitfalls of contracts.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::io;
+use pwasm_utils::lint::{self, Lint};
+use serde::Serialize;
+
+use super::Error;
+
+#[derive(Debug, Serialize)]
+pub struct WarningReport {
+	pub code: &'static str,
+	pub lint: &'static str,
+	pub location: String,
+	pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub warnings: Vec<WarningReport>,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} warnings", self.file, self.warnings.len())?;
+		for warning in &self.warnings {
+			writeln!(f, "  {} {} at {}: {}", warning.code, warning.lint, warning.location, warning.message)?;
+		}
+		Ok(())
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("lint")
+		.about("Warns about common pitfalls of contracts")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("allow")
+			.long("allow")
+			.short("A")
+			.takes_value(true)
+			.multiple(true)
+			.number_of_values(1)
+			.value_name("CODE")
+			.help("Code of a lint to skip, e.g. W003"))
+		.arg(Arg::with_name("deny")
+			.long("deny-warnings")
+			.short("D")
+			.help("Exit with status 1 if there are any warnings"))
+		.arg(super::format_arg())
+}
+
+/// Runs the subcommand. Returns false if there are warnings and they are denied.
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let allowed = matches.values_of("allow")
+		.into_iter()
+		.flatten()
+		.map(|code| Lint::from_code(code).ok_or_else(|| Error::Analysis(format!("--allow: unknown lint `{}`", code))))
+		.collect::<Result<Vec<_>, _>>()?;
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = Report {
+		file: input.to_string(),
+		warnings: lint::check(&module)
+			.into_iter()
+			.filter(|warning| !allowed.contains(&warning.lint))
+			.map(|warning| WarningReport {
+				code: warning.lint.code(),
+				lint: warning.lint.description(),
+				location: warning.location.to_string(),
+				message: warning.message,
+			})
+			.collect(),
+	};
+	super::print_report(&report, matches);
+
+	Ok(report.warnings.is_empty() || !matches.is_present("deny"))
+}
@@ -0,0 +1,262 @@
+//! Command-line front-end bundling the utilities of this crate as subcommands.
+
+use pwasm_utils::{logger, version, GasError, OptimizerError, PackingError};
+
+mod analyze;
+mod bounds;
+mod budget;
+mod externalize;
+mod gas;
+mod lint;
+mod map;
+mod pack;
+mod prune;
+mod repair;
+mod rules;
+mod stack_height;
+mod stats;
+mod storage;
+mod strip;
+mod triage;
+mod validate;
+
+use std::{fmt, io};
+
+use clap::{App, AppSettings, Arg, ArgMatches, crate_version};
+use parity_wasm::elements;
+use serde::Serialize;
+
+#[derive(Debug)]
+pub enum Error {
+	Io(io::Error),
+	Decoding(elements::Error, String),
+	UnsupportedVersion { found: u32, supported: u32, file: String },
+	Encoding(elements::Error),
+	Profile(String),
+	Rules(String),
+	Analysis(String),
+	Gas(GasError),
+	Text(String, String),
+	StackHeight(pwasm_utils::stack_height::Error),
+	Pruning(OptimizerError),
+	Packing(PackingError),
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		use self::Error::*;
+		match self {
+			Io(io) => write!(f, "Generic i/o error: {}", io),
+			Decoding(err, file) => write!(f, "Decoding error ({}). Must be a valid wasm file {}. Pointed wrong file?", err, file),
+			UnsupportedVersion { found, supported, file } => write!(
+				f,
+				"{} is for version {} of the binary format, only version {} is supported. Pass --any-version to try anyway",
+				file, found, supported,
+			),
+			Encoding(err) => write!(f, "Encoding error ({}). Almost impossible to happen, no free disk space?", err),
+			Profile(msg) => write!(f, "Invalid profile: {}", msg),
+			Rules(msg) => write!(f, "Invalid gas rules: {}", msg),
+			Analysis(msg) => write!(f, "Analysis failed: {}", msg),
+			Gas(err) => write!(f, "Gas metering failed: {}", err),
+			Text(err, file) => write!(f, "Parsing error ({}). Must be a valid wat file {}", err, file),
+			StackHeight(err) => write!(f, "Stack height limiting failed: {:?}", err),
+			Pruning(_) => write!(f, "Pruning failed due to missing export section"),
+			Packing(err) => write!(f, "Packing failed: {}", err),
+		}
+	}
+}
+
+/// The `--format` argument shared by all subcommands printing a report.
+fn format_arg() -> Arg<'static, 'static> {
+	Arg::with_name("format")
+		.long("format")
+		.takes_value(true)
+		.default_value("pretty")
+		.possible_values(&["pretty", "json"])
+		.help("Report format")
+}
+
+/// The `--any-version` argument shared by all subcommands reading a module.
+fn any_version_arg() -> Arg<'static, 'static> {
+	Arg::with_name("any_version")
+		.long("any-version")
+		.global(true)
+		.help("Try to parse modules of an unsupported binary format version as the supported one")
+}
+
+/// The `input` and `--output` arguments shared by all subcommands rewriting a module.
+fn io_args(app: App<'static, 'static>) -> App<'static, 'static> {
+	app
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("output")
+			.long("output")
+			.short("o")
+			.takes_value(true)
+			.help("Output WASM file, or - for stdout. The input file is overwritten if not specified"))
+}
+
+/// Deserialize the module `bytes` read from `file`, checking its version unless `--any-version`
+/// is given. Modules in the text format are converted first.
+fn deserialize(bytes: &[u8], file: &str, matches: &ArgMatches) -> Result<elements::Module, Error> {
+	if !bytes.starts_with(b"\0asm") {
+		let binary = wabt::wat2wasm(bytes).map_err(|err| Error::Text(err.to_string(), file.to_string()))?;
+		return deserialize(&binary, file, matches);
+	}
+	let result = if matches.is_present("any_version") {
+		version::deserialize_buffer_any_version(bytes)
+	} else {
+		version::deserialize_buffer(bytes)
+	};
+	result.map_err(|err| match err {
+		version::Error::UnsupportedVersion { found, supported } =>
+			Error::UnsupportedVersion { found, supported, file: file.to_string() },
+		version::Error::Decoding(err) => Error::Decoding(err, file.to_string()),
+	})
+}
+
+/// The JSON schema of a report.
+///
+/// JSON reports carry the `schema_version` of their schema. Within a version, fields are only
+/// added, so consumers can ignore the fields they don't know. Removing or renaming a field or
+/// changing its meaning bumps the version of the report.
+trait Schema: Serialize + fmt::Display {
+	const SCHEMA_VERSION: u32;
+}
+
+/// A report in JSON form, preceded by the version of its schema.
+#[derive(Serialize)]
+struct Versioned<'a, R> {
+	schema_version: u32,
+	#[serde(flatten)]
+	report: &'a R,
+}
+
+/// The `report` as pretty-printed JSON.
+fn to_json<R: Schema>(report: &R) -> String {
+	let versioned = Versioned { schema_version: R::SCHEMA_VERSION, report };
+	serde_json::to_string_pretty(&versioned).expect("reports are always serializable; qed")
+}
+
+/// Format the `report` as requested by the `--format` argument.
+fn format_report<R: Schema>(report: &R, matches: &ArgMatches) -> String {
+	match matches.value_of("format") {
+		Some("json") => format!("{}\n", to_json(report)),
+		_ => report.to_string(),
+	}
+}
+
+/// Print the `report` in the format requested by the `--format` argument.
+fn print_report<R: Schema>(report: &R, matches: &ArgMatches) {
+	print!("{}", format_report(report, matches));
+}
+
+/// Print the `report` of a subcommand writing a module to `output`.
+///
+/// The report goes to stderr if the module itself is written to stdout.
+fn print_report_for<R: Schema>(report: &R, matches: &ArgMatches, output: &str) {
+	if output == pwasm_utils::io::STDIO {
+		eprint!("{}", format_report(report, matches));
+	} else {
+		print_report(report, matches);
+	}
+}
+
+/// Report of a subcommand applying a pass to a module.
+#[derive(Debug, Serialize)]
+pub struct SizeReport {
+	pub file: String,
+	pub original_size: usize,
+	pub size: usize,
+}
+
+impl Schema for SizeReport {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for SizeReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} -> {} bytes", self.file, self.original_size, self.size)
+	}
+}
+
+/// Read the module given as `input`, apply `pass` to it and write it to `--output`.
+fn transform<F>(matches: &ArgMatches, pass: F) -> Result<bool, Error>
+where
+	F: FnOnce(elements::Module) -> Result<elements::Module, Error>,
+{
+	let input = matches.value_of("input").expect("is required; qed");
+	let output = matches.value_of("output").unwrap_or(input);
+
+	let bytes = pwasm_utils::io::read(input).map_err(Error::Io)?;
+	let module = pass(deserialize(&bytes, input, matches)?)?;
+	let transformed = elements::serialize(module).map_err(Error::Encoding)?;
+	pwasm_utils::io::write(output, &transformed).map_err(Error::Io)?;
+
+	let report = SizeReport {
+		file: input.to_string(),
+		original_size: bytes.len(),
+		size: transformed.len(),
+	};
+	print_report_for(&report, matches, output);
+	Ok(true)
+}
+
+fn do_main() -> Result<bool, Error> {
+	logger::init();
+
+	let matches = App::new("wasm-utils")
+		.version(crate_version!())
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.arg(any_version_arg())
+		.subcommand(validate::subcommand())
+		.subcommand(lint::subcommand())
+		.subcommand(analyze::subcommand())
+		.subcommand(budget::subcommand())
+		.subcommand(bounds::subcommand())
+		.subcommand(gas::subcommand())
+		.subcommand(map::subcommand())
+		.subcommand(strip::subcommand())
+		.subcommand(storage::subcommand())
+		.subcommand(repair::subcommand())
+		.subcommand(triage::subcommand())
+		.subcommand(stack_height::subcommand())
+		.subcommand(prune::subcommand())
+		.subcommand(externalize::subcommand())
+		.subcommand(pack::subcommand())
+		.subcommand(stats::subcommand())
+		.get_matches();
+
+	match matches.subcommand() {
+		("validate", Some(matches)) => validate::run(matches),
+		("lint", Some(matches)) => lint::run(matches),
+		("analyze", Some(matches)) => analyze::run(matches),
+		("budget", Some(matches)) => budget::run(matches),
+		("bounds", Some(matches)) => bounds::run(matches),
+		("gas", Some(matches)) => gas::run(matches),
+		("map", Some(matches)) => map::run(matches),
+		("strip", Some(matches)) => strip::run(matches),
+		("storage", Some(matches)) => storage::run(matches),
+		("repair", Some(matches)) => repair::run(matches),
+		("triage", Some(matches)) => triage::run(matches),
+		("stack-height", Some(matches)) => stack_height::run(matches),
+		("prune", Some(matches)) => prune::run(matches),
+		("externalize", Some(matches)) => externalize::run(matches),
+		("pack", Some(matches)) => pack::run(matches),
+		("stats", Some(matches)) => stats::run(matches),
+		_ => unreachable!("subcommand is required; qed"),
+	}
+}
+
+fn main() {
+	match do_main() {
+		Ok(true) => {},
+		Ok(false) => std::process::exit(1),
+		Err(e) => {
+			eprintln!("{}", e);
+			std::process::exit(2)
+		}
+	}
+}
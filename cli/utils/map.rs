@@ -0,0 +1,123 @@
+//! `map` subcommand: prints a hierarchical summary of the imports, functions and data of a module.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::io;
+use pwasm_utils::report::{self, ModuleMap};
+use serde::Serialize;
+
+use super::Error;
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+	pub field: String,
+	pub kind: &'static str,
+	pub index: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportModuleReport {
+	pub module: String,
+	pub imports: Vec<ImportReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FunctionReport {
+	pub index: u32,
+	pub name: Option<String>,
+	pub exports: Vec<String>,
+	pub instructions: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceReport {
+	pub path: String,
+	pub functions: Vec<FunctionReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataReport {
+	pub index: u32,
+	pub offset: Option<u32>,
+	pub size: usize,
+	pub label: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub imports: Vec<ImportModuleReport>,
+	pub namespaces: Vec<NamespaceReport>,
+	pub data: Vec<DataReport>,
+	#[serde(skip)]
+	map: ModuleMap,
+}
+
+impl From<(String, ModuleMap)> for Report {
+	fn from((file, map): (String, ModuleMap)) -> Self {
+		Report {
+			file,
+			imports: map.imports.iter().map(|group| ImportModuleReport {
+				module: group.module.clone(),
+				imports: group.imports.iter().map(|import| ImportReport {
+					field: import.field.clone(),
+					kind: import.kind.as_str(),
+					index: import.index,
+				}).collect(),
+			}).collect(),
+			namespaces: map.namespaces.iter().map(|namespace| NamespaceReport {
+				path: namespace.path.clone(),
+				functions: namespace.functions.iter().map(|function| FunctionReport {
+					index: function.index,
+					name: function.name.clone(),
+					exports: function.exports.clone(),
+					instructions: function.instructions,
+				}).collect(),
+			}).collect(),
+			data: map.data.iter().map(|segment| DataReport {
+				index: segment.index,
+				offset: segment.offset,
+				size: segment.size,
+				label: segment.label.clone(),
+			}).collect(),
+			map,
+		}
+	}
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}", self.file)?;
+		write!(f, "{}", self.map)
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("map")
+		.about("Prints a summary of the imports by module, the functions by namespace and the data segments")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(super::format_arg().possible_value("html"))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = Report::from((input.to_string(), report::module_map(&module)));
+	match matches.value_of("format") {
+		Some("html") => print!("{}", report.map.to_html()),
+		_ => super::print_report(&report, matches),
+	}
+
+	Ok(true)
+}
@@ -0,0 +1,34 @@
+//! `pack` subcommand: packs a module into a constructor module deploying it.
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements;
+use pwasm_utils::{self as utils, TargetRuntime};
+
+use super::Error;
+
+pub fn subcommand() -> App<'static, 'static> {
+	super::io_args(SubCommand::with_name("pack")
+		.about("Packs a module into a constructor module returning it, and prunes the constructor"))
+		.arg(Arg::with_name("target")
+			.long("target")
+			.takes_value(true)
+			.default_value("pwasm")
+			.possible_values(&["pwasm", "substrate"])
+			.help("Runtime whose symbols the constructor uses"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let target = match matches.value_of("target") {
+		Some("substrate") => TargetRuntime::substrate(),
+		_ => TargetRuntime::pwasm(),
+	};
+
+	super::transform(matches, |module| {
+		let raw_module = elements::serialize(module.clone()).map_err(Error::Encoding)?;
+		let mut packed = utils::pack_instance(raw_module, module, &target).map_err(Error::Packing)?;
+		// The constructor only needs what deploys the module.
+		utils::optimize(&mut packed, vec![target.symbols().call]).map_err(Error::Pruning)?;
+		Ok(packed)
+	})
+}
@@ -0,0 +1,28 @@
+//! `prune` subcommand: removes everything the given exports don't use from a module.
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::{self as utils, TargetRuntime};
+
+use super::Error;
+
+pub fn subcommand() -> App<'static, 'static> {
+	super::io_args(SubCommand::with_name("prune")
+		.about("Removes the exports other than the given ones and everything only they use"))
+		.arg(Arg::with_name("exports")
+			.long("exports")
+			.short("e")
+			.takes_value(true)
+			.value_name("functions")
+			.default_value(TargetRuntime::pwasm().symbols().call)
+			.help("Comma-separated list of exports to keep"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let exports = matches.value_of("exports").expect("has a default value; qed").split(',').collect();
+
+	super::transform(matches, |mut module| {
+		utils::optimize(&mut module, exports).map_err(Error::Pruning)?;
+		Ok(module)
+	})
+}
@@ -0,0 +1,73 @@
+//! `repair` subcommand: fixes the section layout of a module.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements;
+use pwasm_utils::{io, repair};
+use serde::Serialize;
+
+use super::Error;
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub original_size: usize,
+	pub repaired_size: usize,
+	pub repairs: Vec<String>,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} -> {} bytes", self.file, self.original_size, self.repaired_size)?;
+		if self.repairs.is_empty() {
+			writeln!(f, "  nothing to repair")?;
+		}
+		for repair in &self.repairs {
+			writeln!(f, "  {}", repair)?;
+		}
+		Ok(())
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("repair")
+		.about("Reorders sections, drops empty ones and merges split ones")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM file, or - for stdin"))
+		.arg(Arg::with_name("output")
+			.long("output")
+			.short("o")
+			.takes_value(true)
+			.help("Output WASM file, or - for stdout. The input file is overwritten if not specified"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let output = matches.value_of("output").unwrap_or(input);
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let (repaired, repairs) = repair::repair(&bytes)
+		.map_err(|e| Error::Analysis(format!("{}: {}", input, e)))?;
+	// The repair only fixes the layout, so make sure that the sections themselves are valid.
+	elements::deserialize_buffer::<elements::Module>(&repaired)
+		.map_err(|e| Error::Decoding(e, input.to_string()))?;
+	io::write(output, &repaired).map_err(Error::Io)?;
+
+	let report = Report {
+		file: input.to_string(),
+		original_size: bytes.len(),
+		repaired_size: repaired.len(),
+		repairs: repairs.iter().map(ToString::to_string).collect(),
+	};
+	super::print_report_for(&report, matches, output);
+
+	Ok(true)
+}
@@ -0,0 +1,49 @@
+//! Loading of gas rules from a TOML or JSON file.
+//!
+//! ```toml
+//! regular = 1
+//! grow = 8192
+//! well_known_intrinsics = true
+//! local = 1
+//! max_locals = 1024
+//!
+//! [instructions]
+//! div = 16
+//! float = "forbidden"
+//!
+//! [loop_multipliers]
+//! memcpy = 4
+//! "memset*" = 4
+//! ```
+//!
+//! Instruction classes are named as accepted by `InstructionType::from_str`. A class is either
+//! given a fixed cost, or is `"regular"` or `"forbidden"`. With `well_known_intrinsics`, calls to
+//! helper imports implementing 64-bit arithmetic are charged like the instructions they implement.
+//! The costs inside of loops of functions matching a pattern of `loop_multipliers`, by name or by
+//! prefix ending with `*`, are multiplied by the given factor. `grow` is either a cost per page,
+//! `"host"`, `"forbidden"`, a table of `tiers`, each a `[pages, price]` pair, or a table of
+//! `steps`, each a `[pages, fee]` pair.
+//! `local` is charged per declared local and `local_byte` per byte of declared locals on entry of
+//! a function, and functions declaring more than `max_locals` locals are rejected.
+//! `forbidden_instructions` lists instructions to reject by mnemonic, e.g. `"i64.div_s"`, and names
+//! no instruction is an error.
+//! `call` and `call_indirect` are fees charged for every such instruction on top of its cost.
+//! Classes missing from `instructions` are charged `regular` unless `unknown` is `"reject"`,
+//! `"forbid"`, which lists all of them the module uses, or a table with the cost to `charge`.
+//! Files with the `json` extension are read as JSON with the same fields.
+
+use pwasm_utils::rules;
+
+use super::Error;
+
+/// Load a rule set from the TOML or JSON file at `path`, or the default rule set if there is none.
+pub fn load(path: Option<&str>) -> Result<rules::Set, Error> {
+	match path {
+		Some(path) => rules::Set::from_file(path).map_err(|e| match e {
+			rules::LoadError::Io(e) => Error::Io(e),
+			e => Error::Rules(format!("{}: {}", path, e)),
+		}),
+		None => Ok(rules::Set::default()),
+	}
+}
+
@@ -0,0 +1,65 @@
+//! `stack-height` subcommand: injects the stack height limiter into a module.
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::stack_height::{self, FrameCost, LimiterConfig, OverflowTrap};
+
+use super::Error;
+
+pub fn subcommand() -> App<'static, 'static> {
+	super::io_args(SubCommand::with_name("stack-height")
+		.about("Injects the stack height limiter into a module"))
+		.arg(Arg::with_name("limit")
+			.long("limit")
+			.short("l")
+			.takes_value(true)
+			.default_value("1024")
+			.help("Stack limit, in the units of the stack costs"))
+		.arg(Arg::with_name("frame_cost")
+			.long("frame-cost")
+			.takes_value(true)
+			.default_value("locals")
+			.possible_values(&["locals", "frame"])
+			.help("Whether the stack cost of a function covers its parameters as well as its locals"))
+		.arg(Arg::with_name("trap_import")
+			.long("trap-import")
+			.takes_value(true)
+			.value_name("module.field")
+			.help("Call the function imported under this name on overflow before trapping"))
+		.arg(Arg::with_name("trap_reason")
+			.long("trap-reason")
+			.takes_value(true)
+			.value_name("export")
+			.help("Store the trap reason in the global exported under this name before trapping"))
+		.arg(Arg::with_name("validate_output")
+			.long("validate-output")
+			.help("Validate the instrumented module, failing if it is invalid"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let limit = matches.value_of("limit").expect("has a default value; qed").parse()
+		.map_err(|_| Error::Analysis("--limit should be a non-negative integer".into()))?;
+
+	let mut config = LimiterConfig::new();
+	if matches.value_of("frame_cost") == Some("frame") {
+		config = config.with_frame_cost(FrameCost::Frame);
+	}
+	if let Some(import) = matches.value_of("trap_import") {
+		let mut parts = import.splitn(2, '.');
+		let (module, field) = match (parts.next(), parts.next()) {
+			(Some(module), Some(field)) => (module, field),
+			_ => return Err(Error::Analysis("--trap-import should be of the form module.field".into())),
+		};
+		config = config.with_trap(OverflowTrap::HostFunction { module, field });
+	}
+	if let Some(export_name) = matches.value_of("trap_reason") {
+		config = config.with_trap_reason(export_name);
+	}
+	if matches.is_present("validate_output") {
+		config = config.with_output_validation();
+	}
+
+	super::transform(matches, |module| {
+		stack_height::inject_limiter_with_config(module, limit, config).map_err(Error::StackHeight)
+	})
+}
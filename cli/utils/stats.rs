@@ -0,0 +1,161 @@
+//! `stats` subcommand: reports the size of each section and the number of entities of a module.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements::{self, ImportCountType, Section};
+use pwasm_utils::io;
+use serde::Serialize;
+
+use super::Error;
+
+#[derive(Debug, Serialize)]
+pub struct SectionReport {
+	pub name: String,
+	/// Size of the section including its id and size.
+	pub size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub size: usize,
+	pub sections: Vec<SectionReport>,
+	pub types: usize,
+	pub imported_functions: usize,
+	pub imported_globals: usize,
+	pub functions: usize,
+	pub globals: usize,
+	pub exports: usize,
+	pub instructions: usize,
+	pub data_segments: usize,
+	pub data_bytes: usize,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} bytes", self.file, self.size)?;
+		for section in &self.sections {
+			writeln!(f, "  {:<20} {:>8} bytes", section.name, section.size)?;
+		}
+		writeln!(f, "types:          {}", self.types)?;
+		writeln!(f, "functions:      {} ({} imported)", self.functions, self.imported_functions)?;
+		writeln!(f, "globals:        {} ({} imported)", self.globals, self.imported_globals)?;
+		writeln!(f, "exports:        {}", self.exports)?;
+		writeln!(f, "instructions:   {}", self.instructions)?;
+		writeln!(f, "data segments:  {} ({} bytes)", self.data_segments, self.data_bytes)
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("stats")
+		.about("Prints the size of each section and the number of functions, globals and exports")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = stats(input, &module)?;
+	super::print_report(&report, matches);
+
+	Ok(true)
+}
+
+fn section_name(section: &Section) -> String {
+	match section {
+		Section::Unparsed { id, .. } => format!("unknown {}", id),
+		Section::Custom(custom) => format!("custom {}", custom.name()),
+		Section::Type(_) => "type".into(),
+		Section::Import(_) => "import".into(),
+		Section::Function(_) => "function".into(),
+		Section::Table(_) => "table".into(),
+		Section::Memory(_) => "memory".into(),
+		Section::Global(_) => "global".into(),
+		Section::Export(_) => "export".into(),
+		Section::Start(_) => "start".into(),
+		Section::Element(_) => "element".into(),
+		Section::DataCount(_) => "data count".into(),
+		Section::Code(_) => "code".into(),
+		Section::Data(_) => "data".into(),
+		Section::Name(_) => "custom name".into(),
+		Section::Reloc(reloc) => format!("custom {}", reloc.name()),
+	}
+}
+
+/// Collect the statistics of `module` read from `file`.
+pub fn stats(file: &str, module: &elements::Module) -> Result<Report, Error> {
+	let sections = module.sections()
+		.iter()
+		.map(|section| Ok(SectionReport {
+			name: section_name(section),
+			size: elements::serialize(section.clone()).map_err(Error::Encoding)?.len(),
+		}))
+		.collect::<Result<Vec<_>, Error>>()?;
+	let data = module.data_section().map_or(&[][..], |section| section.entries());
+
+	Ok(Report {
+		file: file.to_string(),
+		size: elements::serialize(module.clone()).map_err(Error::Encoding)?.len(),
+		sections,
+		types: module.type_section().map_or(0, |section| section.types().len()),
+		imported_functions: module.import_count(ImportCountType::Function),
+		imported_globals: module.import_count(ImportCountType::Global),
+		functions: module.functions_space(),
+		globals: module.globals_space(),
+		exports: module.export_section().map_or(0, |section| section.entries().len()),
+		instructions: module.code_section()
+			.map_or(&[][..], |section| section.bodies())
+			.iter()
+			.map(|body| body.code().elements().len())
+			.sum(),
+		data_segments: data.len(),
+		data_bytes: data.iter().map(|segment| segment.value().len()).sum(),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counts() {
+		let module: elements::Module = elements::deserialize_buffer(&wabt::wat2wasm(r#"
+			(module
+				(import "env" "f" (func))
+				(global i32 (i32.const 0))
+				(memory 1)
+				(data (i32.const 0) "abc")
+				(func (export "call") (drop (i32.const 1))))
+		"#).unwrap()).unwrap();
+
+		let report = stats("test.wasm", &module).unwrap();
+
+		assert_eq!(
+			report.sections.iter().map(|section| section.name.as_str()).collect::<Vec<_>>(),
+			vec!["type", "import", "function", "memory", "global", "export", "code", "data"],
+		);
+		// The sizes include the ids and sizes of the sections, but not the preamble.
+		assert_eq!(report.sections.iter().map(|section| section.size).sum::<usize>() + 8, report.size);
+		assert_eq!((report.functions, report.imported_functions), (2, 1));
+		assert_eq!((report.globals, report.imported_globals), (1, 0));
+		assert_eq!(report.exports, 1);
+		assert_eq!(report.instructions, 3);
+		assert_eq!((report.data_segments, report.data_bytes), (1, 3));
+
+		let json: serde_json::Value = serde_json::from_str(&crate::to_json(&report)).unwrap();
+		assert_eq!(json["schema_version"], 1);
+		assert_eq!(json["exports"], 1);
+	}
+}
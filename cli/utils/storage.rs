@@ -0,0 +1,156 @@
+//! `storage` subcommand: reports the storage calls each exported function may execute.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::analysis::{self, Access, ExportAccess, Key, StorageFunctions};
+use pwasm_utils::io;
+use serde::Serialize;
+
+use super::Error;
+
+/// Storage functions of the NEAR runtime, which are used if none are given.
+const DEFAULT_READS: &[&str] = &["storage_read", "storage_has_key"];
+const DEFAULT_WRITES: &[&str] = &["storage_write", "storage_remove"];
+/// The key pointer follows the key length in the storage functions of the NEAR runtime.
+const DEFAULT_KEY_PARAM: &str = "1";
+
+#[derive(Debug, Serialize)]
+pub struct CallSiteReport {
+	pub function: u32,
+	pub offset: usize,
+	pub field: String,
+	pub write: bool,
+	/// The constant key argument, if it is one.
+	pub key: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportReport {
+	pub name: String,
+	pub function: u32,
+	pub reads: usize,
+	pub writes: usize,
+	pub static_keys: usize,
+	pub indirect_calls: bool,
+	pub call_sites: Vec<CallSiteReport>,
+}
+
+impl From<ExportAccess> for ExportReport {
+	fn from(access: ExportAccess) -> Self {
+		ExportReport {
+			reads: access.reads(),
+			writes: access.writes(),
+			static_keys: access.static_keys(),
+			indirect_calls: access.indirect_calls,
+			call_sites: access.call_sites.into_iter().map(|call_site| CallSiteReport {
+				function: call_site.function,
+				offset: call_site.offset,
+				field: call_site.field,
+				write: call_site.access == Access::Write,
+				key: match call_site.key {
+					Key::Constant(key) => Some(key),
+					Key::Dynamic => None,
+				},
+			}).collect(),
+			name: access.name,
+			function: access.function,
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub exports: Vec<ExportReport>,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}", self.file)?;
+		for export in &self.exports {
+			writeln!(
+				f,
+				"  {} (function {}): {} reads, {} writes, {} with static keys{}",
+				export.name,
+				export.function,
+				export.reads,
+				export.writes,
+				export.static_keys,
+				if export.indirect_calls { ", may call indirectly" } else { "" },
+			)?;
+			for call_site in &export.call_sites {
+				let key = call_site.key.map_or_else(|| "dynamic".to_string(), |key| key.to_string());
+				writeln!(
+					f,
+					"    {} in function {} at {}, key {}",
+					call_site.field, call_site.function, call_site.offset, key,
+				)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("storage")
+		.about("Reports the storage calls which each exported function may execute")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("read")
+			.long("read")
+			.takes_value(true)
+			.multiple(true)
+			.number_of_values(1)
+			.help("Name of an imported function reading from the storage"))
+		.arg(Arg::with_name("write")
+			.long("write")
+			.takes_value(true)
+			.multiple(true)
+			.number_of_values(1)
+			.help("Name of an imported function writing to the storage"))
+		.arg(Arg::with_name("key-param")
+			.long("key-param")
+			.takes_value(true)
+			.default_value(DEFAULT_KEY_PARAM)
+			.help("Index of the parameter holding the key in the storage functions"))
+		.arg(super::format_arg())
+}
+
+fn storage_functions(matches: &ArgMatches) -> Result<StorageFunctions, Error> {
+	let key_param = matches.value_of("key-param").expect("has a default value; qed").parse()
+		.map_err(|_| Error::Analysis("--key-param should be a non-negative integer".into()))?;
+	let mut functions = StorageFunctions::new().with_key_param(key_param);
+
+	if matches.is_present("read") || matches.is_present("write") {
+		functions.reads = matches.values_of("read").into_iter().flatten().map(Into::into).collect();
+		functions.writes = matches.values_of("write").into_iter().flatten().map(Into::into).collect();
+	} else {
+		functions.reads = DEFAULT_READS.iter().map(|read| read.to_string()).collect();
+		functions.writes = DEFAULT_WRITES.iter().map(|write| write.to_string()).collect();
+	}
+
+	Ok(functions)
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let functions = storage_functions(matches)?;
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = Report {
+		file: input.to_string(),
+		exports: analysis::storage_access(&module, &functions).into_iter().map(Into::into).collect(),
+	};
+	super::print_report(&report, matches);
+
+	Ok(true)
+}
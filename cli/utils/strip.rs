@@ -0,0 +1,194 @@
+//! `strip` subcommand: shrinks a module and reports the bytes saved by each pass.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements;
+use pwasm_utils::{io, strip};
+use serde::Serialize;
+
+use super::Error;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Savings {
+	/// Re-encoding the module with minimal LEB128 integers.
+	pub leb: usize,
+	/// Removing custom sections other than the name section.
+	pub custom_sections: usize,
+	/// Removing the name section.
+	pub names: usize,
+	/// Removing parameters functions never access.
+	pub params: usize,
+	/// Merging identical function types.
+	pub types: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub original_size: usize,
+	pub stripped_size: usize,
+	pub removed_sections: usize,
+	pub removed_types: usize,
+	pub removed_params: usize,
+	pub saved: Savings,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} -> {} bytes", self.file, self.original_size, self.stripped_size)?;
+		writeln!(f, "  leb minimization: {}", self.saved.leb)?;
+		writeln!(f, "  custom sections:  {} ({} removed)", self.saved.custom_sections, self.removed_sections)?;
+		writeln!(f, "  names:            {}", self.saved.names)?;
+		writeln!(f, "  unused params:    {} ({} removed)", self.saved.params, self.removed_params)?;
+		writeln!(f, "  type dedup:       {} ({} removed)", self.saved.types, self.removed_types)
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("strip")
+		.about("Strips custom sections, merges duplicate types and minimizes the encoding of a module")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("output")
+			.long("output")
+			.short("o")
+			.takes_value(true)
+			.help("Output WASM file, or - for stdout. The input file is overwritten if not specified"))
+		.arg(Arg::with_name("keep_names")
+			.long("keep-names")
+			.help("Keep the name section"))
+		.arg(Arg::with_name("unused_params")
+			.long("unused-params")
+			.help("Remove the parameters functions never access, except from exported functions"))
+		.arg(Arg::with_name("change_exports")
+			.long("change-exports")
+			.requires("unused_params")
+			.help("Remove unused parameters from exported functions too, changing the ABI of the module"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let output = matches.value_of("output").unwrap_or(input);
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let params = if matches.is_present("change_exports") {
+		Params::RemoveUnusedWithExports
+	} else if matches.is_present("unused_params") {
+		Params::RemoveUnused
+	} else {
+		Params::Keep
+	};
+	let (module, report) = strip(input, bytes.len(), module, matches.is_present("keep_names"), params)?;
+
+	io::serialize_to_file(output, module).map_err(Error::Encoding)?;
+	super::print_report_for(&report, matches, output);
+
+	Ok(true)
+}
+
+fn serialized_size(module: &elements::Module) -> Result<usize, Error> {
+	elements::serialize(module.clone())
+		.map(|bytes| bytes.len())
+		.map_err(|e| Error::Analysis(format!("failed to serialize the module: {}", e)))
+}
+
+/// Which parameters the stripping removes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Params {
+	Keep,
+	/// Parameters functions never access, except from exported functions.
+	RemoveUnused,
+	/// Parameters functions never access, including exported functions.
+	RemoveUnusedWithExports,
+}
+
+/// Run the stripping passes on `module`, measuring the size after each of them.
+pub fn strip(
+	file: &str,
+	original_size: usize,
+	mut module: elements::Module,
+	keep_names: bool,
+	params: Params,
+) -> Result<(elements::Module, Report), Error> {
+	let mut saved = Savings::default();
+
+	let mut size = serialized_size(&module)?;
+	saved.leb = original_size.saturating_sub(size);
+
+	let mut removed_sections = strip::strip_custom_sections(&mut module, true);
+	let next = serialized_size(&module)?;
+	saved.custom_sections = size - next;
+	size = next;
+
+	if !keep_names && strip::strip_names(&mut module) {
+		removed_sections += 1;
+		let next = serialized_size(&module)?;
+		saved.names = size - next;
+		size = next;
+	}
+
+	let removed_params = match params {
+		Params::Keep => 0,
+		Params::RemoveUnused => strip::remove_unused_params(&mut module, false),
+		Params::RemoveUnusedWithExports => strip::remove_unused_params(&mut module, true),
+	};
+	let next = serialized_size(&module)?;
+	saved.params = size.saturating_sub(next);
+	size = next;
+
+	let removed_types = strip::dedup_types(&mut module);
+	let next = serialized_size(&module)?;
+	saved.types = size - next;
+	size = next;
+
+	let report = Report {
+		file: file.to_string(),
+		original_size,
+		stripped_size: size,
+		removed_sections,
+		removed_types,
+		removed_params,
+		saved,
+	};
+	Ok((module, report))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reports_savings() {
+		let mut module: elements::Module = elements::deserialize_buffer(&wabt::wat2wasm(r#"
+			(module
+				(type (func))
+				(type (func))
+				(func (type 0))
+				(func (type 1)))
+		"#).unwrap()).unwrap();
+		module.set_custom_section("name", vec![0; 4]);
+		module.set_custom_section("producers", vec![0; 8]);
+		let original_size = elements::serialize(module.clone()).unwrap().len();
+
+		let (module, report) = strip("test.wasm", original_size, module, false, Params::RemoveUnused).unwrap();
+
+		assert_eq!(report.saved.leb, 0);
+		assert_eq!(report.saved.custom_sections, 20);
+		assert_eq!(report.saved.names, 11);
+		assert_eq!(report.saved.types, 3);
+		assert_eq!((report.saved.params, report.removed_params), (0, 0));
+		assert_eq!(report.removed_sections, 2);
+		assert_eq!(report.removed_types, 1);
+		assert_eq!(report.stripped_size, elements::serialize(module).unwrap().len());
+	}
+}
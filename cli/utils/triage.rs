@@ -0,0 +1,76 @@
+//! `triage` subcommand: diagnoses modules which fail to deserialize.
+
+use std::fmt;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pwasm_utils::repair::section_name;
+use pwasm_utils::{io, triage};
+use serde::Serialize;
+
+use super::Error;
+
+#[derive(Debug, Serialize)]
+pub struct SectionReport {
+	pub name: String,
+	pub offset: usize,
+	pub size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub size: usize,
+	pub valid_bytes: usize,
+	pub sections: Vec<SectionReport>,
+	pub problem: Option<String>,
+	pub cause: Option<String>,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {} of {} bytes intact", self.file, self.valid_bytes, self.size)?;
+		for section in &self.sections {
+			writeln!(f, "  {} section at {}, {} bytes", section.name, section.offset, section.size)?;
+		}
+		match (&self.problem, &self.cause) {
+			(Some(problem), Some(cause)) => writeln!(f, "  {}\n  likely cause: {}", problem, cause),
+			_ => writeln!(f, "  no problem found"),
+		}
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("triage")
+		.about("Reports how far a module can be parsed and why it fails to deserialize")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM file, or - for stdin"))
+		.arg(super::format_arg())
+}
+
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let bytes = io::read(input).map_err(Error::Io)?;
+
+	let inspection = triage::inspect(&bytes);
+	let report = Report {
+		file: input.to_string(),
+		size: bytes.len(),
+		valid_bytes: inspection.valid_bytes,
+		sections: inspection.sections.iter().map(|section| SectionReport {
+			name: section_name(section.id).to_string(),
+			offset: section.offset,
+			size: section.size,
+		}).collect(),
+		problem: inspection.problem.as_ref().map(ToString::to_string),
+		cause: inspection.problem.as_ref().map(|problem| problem.cause().to_string()),
+	};
+	super::print_report(&report, matches);
+
+	Ok(inspection.problem.is_none())
+}
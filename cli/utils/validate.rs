@@ -0,0 +1,471 @@
+//! `validate` subcommand: checks a module against the rules of a runtime profile.
+
+use std::{fs, fmt};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use parity_wasm::elements::{self, External, Instruction, Internal, Type, ValueType};
+use pwasm_utils::{io, rules::InstructionType};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+/// Rules a module has to satisfy, loaded from a TOML profile.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+	pub imports: ImportRules,
+	pub exports: ExportRules,
+	pub limits: Limits,
+	pub features: FeatureRules,
+	pub determinism: DeterminismRules,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportRules {
+	/// The only module name imports may come from.
+	pub module: Option<String>,
+	/// Whitelist of imported function names. Any function may be imported if not specified.
+	pub functions: Option<Vec<String>>,
+	pub allow_memory: bool,
+	pub allow_table: bool,
+	pub allow_globals: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportRules {
+	/// Exports which must be present in the module.
+	pub required: Vec<String>,
+	/// Whether all exported functions must have the `() -> ()` signature.
+	pub unit_functions: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+	pub max_functions: Option<u32>,
+	pub max_locals: Option<u32>,
+	pub max_globals: Option<u32>,
+	pub max_memory_pages: Option<u32>,
+	pub max_table_size: Option<u32>,
+	pub max_size: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureRules {
+	/// Post-MVP features the module may use.
+	pub allowed: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeterminismRules {
+	/// Reject floating point types and instructions.
+	pub forbid_floats: bool,
+	/// Reject modules with a start function.
+	pub forbid_start: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Violation {
+	pub check: &'static str,
+	pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+	pub file: String,
+	pub passed: bool,
+	pub features: Vec<&'static str>,
+	pub violations: Vec<Violation>,
+}
+
+impl super::Schema for Report {
+	const SCHEMA_VERSION: u32 = 1;
+}
+
+impl fmt::Display for Report {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{}: {}", self.file, if self.passed { "ok" } else { "FAILED" })?;
+		if !self.features.is_empty() {
+			writeln!(f, "  features: {}", self.features.join(", "))?;
+		}
+		for violation in &self.violations {
+			writeln!(f, "  [{}] {}", violation.check, violation.message)?;
+		}
+		Ok(())
+	}
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+	SubCommand::with_name("validate")
+		.about("Checks a module against the rules of a runtime profile")
+		.arg(Arg::with_name("input")
+			.index(1)
+			.required(true)
+			.help("Input WASM or WAT file, or - for stdin"))
+		.arg(Arg::with_name("profile")
+			.long("profile")
+			.short("p")
+			.takes_value(true)
+			.required(true)
+			.help("TOML profile describing the rules of the runtime"))
+		.arg(super::format_arg())
+}
+
+/// Runs the subcommand. Returns whether the module passed all checks.
+pub fn run(matches: &ArgMatches) -> Result<bool, Error> {
+	let input = matches.value_of("input").expect("is required; qed");
+	let profile_path = matches.value_of("profile").expect("is required; qed");
+
+	let profile_source = fs::read_to_string(profile_path).map_err(Error::Io)?;
+	let profile: Profile = toml::from_str(&profile_source)
+		.map_err(|e| Error::Profile(format!("{}: {}", profile_path, e)))?;
+
+	let bytes = io::read(input).map_err(Error::Io)?;
+	let module = super::deserialize(&bytes, input, matches)?;
+
+	let report = validate(input, bytes.len(), &module, &profile);
+	super::print_report(&report, matches);
+
+	Ok(report.passed)
+}
+
+/// Runs all checks of the `profile` against the `module`.
+pub fn validate(file: &str, size: usize, module: &elements::Module, profile: &Profile) -> Report {
+	let mut violations = Vec::new();
+
+	check_imports(module, &profile.imports, &mut violations);
+	check_exports(module, &profile.exports, &mut violations);
+	check_limits(module, size, &profile.limits, &mut violations);
+
+	let features = detect_features(module);
+	for feature in &features {
+		if !profile.features.allowed.iter().any(|allowed| allowed == feature) {
+			violations.push(Violation {
+				check: "features",
+				message: format!("feature '{}' is not allowed", feature),
+			});
+		}
+	}
+
+	check_determinism(module, &profile.determinism, &mut violations);
+
+	Report {
+		file: file.to_string(),
+		passed: violations.is_empty(),
+		features,
+		violations,
+	}
+}
+
+fn check_imports(module: &elements::Module, rules: &ImportRules, violations: &mut Vec<Violation>) {
+	let entries = module.import_section().map(|s| s.entries()).unwrap_or(&[]);
+	for entry in entries {
+		let mut violation = |message: String| violations.push(Violation { check: "imports", message });
+
+		if let Some(expected) = &rules.module {
+			if entry.module() != expected {
+				violation(format!("'{}::{}' is not imported from '{}'", entry.module(), entry.field(), expected));
+			}
+		}
+		match entry.external() {
+			External::Function(_) => {
+				if let Some(functions) = &rules.functions {
+					if !functions.iter().any(|name| name == entry.field()) {
+						violation(format!("function '{}' is not supported by the runtime", entry.field()));
+					}
+				}
+			}
+			External::Memory(_) if !rules.allow_memory => {
+				violation(format!("memory import '{}' is not allowed", entry.field()));
+			}
+			External::Table(_) if !rules.allow_table => {
+				violation(format!("table import '{}' is not allowed", entry.field()));
+			}
+			External::Global(_) if !rules.allow_globals => {
+				violation(format!("global import '{}' is not allowed", entry.field()));
+			}
+			_ => {}
+		}
+	}
+}
+
+fn check_exports(module: &elements::Module, rules: &ExportRules, violations: &mut Vec<Violation>) {
+	let entries = module.export_section().map(|s| s.entries()).unwrap_or(&[]);
+
+	for required in &rules.required {
+		if !entries.iter().any(|entry| entry.field() == required) {
+			violations.push(Violation {
+				check: "exports",
+				message: format!("required export '{}' is missing", required),
+			});
+		}
+	}
+
+	if !rules.unit_functions {
+		return;
+	}
+	for entry in entries {
+		if let Internal::Function(func_idx) = entry.internal() {
+			let is_unit = matches!(
+				func_type(module, *func_idx),
+				Some(ty) if ty.params().is_empty() && ty.results().is_empty()
+			);
+			if !is_unit {
+				violations.push(Violation {
+					check: "exports",
+					message: format!("exported function '{}' should have signature () -> ()", entry.field()),
+				});
+			}
+		}
+	}
+}
+
+fn check_limit(violations: &mut Vec<Violation>, what: &str, actual: usize, max: Option<u32>) {
+	if let Some(max) = max {
+		if actual > max as usize {
+			violations.push(Violation {
+				check: "limits",
+				message: format!("{} is {}, the limit is {}", what, actual, max),
+			});
+		}
+	}
+}
+
+fn check_limits(module: &elements::Module, size: usize, limits: &Limits, violations: &mut Vec<Violation>) {
+	check_limit(violations, "module size", size, limits.max_size);
+	check_limit(violations, "number of functions", module.functions_space(), limits.max_functions);
+	check_limit(violations, "number of globals", module.globals_space(), limits.max_globals);
+
+	let max_locals = module.code_section()
+		.map(|s| s.bodies())
+		.unwrap_or(&[])
+		.iter()
+		.map(|body| body.locals().iter().map(|l| l.count() as usize).sum::<usize>())
+		.max()
+		.unwrap_or(0);
+	check_limit(violations, "maximum number of locals in a function", max_locals, limits.max_locals);
+
+	let memory_limits = module.memory_section()
+		.map(|s| s.entries().iter().map(|m| *m.limits()).collect::<Vec<_>>())
+		.unwrap_or_default()
+		.into_iter()
+		.chain(
+			module.import_section().map(|s| s.entries()).unwrap_or(&[])
+				.iter()
+				.filter_map(|entry| match entry.external() {
+					External::Memory(m) => Some(*m.limits()),
+					_ => None,
+				})
+		);
+	for limits_entry in memory_limits {
+		let pages = limits_entry.maximum().unwrap_or(limits_entry.initial());
+		if limits.max_memory_pages.is_some() && limits_entry.maximum().is_none() {
+			violations.push(Violation {
+				check: "limits",
+				message: "memory does not declare a maximum size".into(),
+			});
+		}
+		check_limit(violations, "memory size in pages", pages as usize, limits.max_memory_pages);
+	}
+
+	let table_sizes = module.table_section()
+		.map(|s| s.entries().iter().map(|t| t.limits().initial()).collect::<Vec<_>>())
+		.unwrap_or_default();
+	for size in table_sizes {
+		check_limit(violations, "table size", size as usize, limits.max_table_size);
+	}
+}
+
+/// Detects post-MVP features used by the module.
+///
+/// Proposals which require new instructions or encodings can't be parsed at all and are reported
+/// as decoding errors instead.
+fn detect_features(module: &elements::Module) -> Vec<&'static str> {
+	let mut features = Vec::new();
+
+	let imports_mutable_global = module.import_section().map(|s| s.entries()).unwrap_or(&[])
+		.iter()
+		.any(|entry| match entry.external() {
+			External::Global(ty) => ty.is_mutable(),
+			_ => false,
+		});
+	let globals = module.global_section().map(|s| s.entries()).unwrap_or(&[]);
+	let imported_globals = module.import_count(elements::ImportCountType::Global);
+	let exports_mutable_global = module.export_section().map(|s| s.entries()).unwrap_or(&[])
+		.iter()
+		.any(|entry| match entry.internal() {
+			Internal::Global(idx) => matches!(
+				(*idx as usize).checked_sub(imported_globals).and_then(|idx| globals.get(idx)),
+				Some(global) if global.global_type().is_mutable()
+			),
+			_ => false,
+		});
+	if imports_mutable_global || exports_mutable_global {
+		features.push("mutable-globals");
+	}
+
+	let multi_value = module.type_section().map(|s| s.types()).unwrap_or(&[])
+		.iter()
+		.any(|Type::Function(ty)| ty.results().len() > 1);
+	if multi_value {
+		features.push("multi-value");
+	}
+
+	#[cfg(feature = "atomics")]
+	{
+		let imported_memories = module.import_section().map(|s| s.entries()).unwrap_or(&[])
+			.iter()
+			.filter_map(|entry| match entry.external() {
+				External::Memory(ty) => Some(ty),
+				_ => None,
+			});
+		let shared_memory = module.memory_section().map(|s| s.entries()).unwrap_or(&[])
+			.iter()
+			.chain(imported_memories)
+			.any(|ty| ty.limits().shared());
+		let atomics = module.code_section().map(|s| s.bodies()).unwrap_or(&[])
+			.iter()
+			.any(|body| body.code().elements().iter().any(|instruction| matches!(instruction, Instruction::Atomics(_))));
+		if shared_memory || atomics {
+			features.push("threads");
+		}
+	}
+
+	features
+}
+
+fn check_determinism(module: &elements::Module, rules: &DeterminismRules, violations: &mut Vec<Violation>) {
+	if rules.forbid_start && module.start_section().is_some() {
+		violations.push(Violation {
+			check: "determinism",
+			message: "start function is not allowed".into(),
+		});
+	}
+
+	if !rules.forbid_floats {
+		return;
+	}
+
+	let is_float = |ty: &ValueType| matches!(ty, ValueType::F32 | ValueType::F64);
+	let float_signature = module.type_section().map(|s| s.types()).unwrap_or(&[])
+		.iter()
+		.any(|Type::Function(ty)| ty.params().iter().chain(ty.results()).any(is_float));
+	let float_global = module.global_section().map(|s| s.entries()).unwrap_or(&[])
+		.iter()
+		.any(|global| is_float(&global.global_type().content_type()));
+	if float_signature || float_global {
+		violations.push(Violation {
+			check: "determinism",
+			message: "floating point types are not allowed".into(),
+		});
+	}
+
+	let bodies = module.code_section().map(|s| s.bodies()).unwrap_or(&[]);
+	for (index, body) in bodies.iter().enumerate() {
+		let float_local = body.locals().iter().any(|local| is_float(&local.value_type()));
+		let float_instruction = body.code().elements().iter().any(is_float_instruction);
+		if float_local || float_instruction {
+			violations.push(Violation {
+				check: "determinism",
+				message: format!("function body {} uses floating point operations", index),
+			});
+		}
+	}
+}
+
+fn is_float_instruction(instruction: &Instruction) -> bool {
+	match InstructionType::op(instruction) {
+		InstructionType::Float
+		| InstructionType::FloatConst
+		| InstructionType::FloatComparison
+		| InstructionType::FloatConversion
+		| InstructionType::Reinterpretation => true,
+		_ => matches!(
+			instruction,
+			Instruction::F32Load(..) | Instruction::F64Load(..)
+				| Instruction::F32Store(..) | Instruction::F64Store(..)
+		),
+	}
+}
+
+fn func_type(module: &elements::Module, func_idx: u32) -> Option<&elements::FunctionType> {
+	let imported = module.import_section().map(|s| s.entries()).unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Function(type_idx) => Some(*type_idx),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	let type_idx = match imported.get(func_idx as usize) {
+		Some(type_idx) => *type_idx,
+		None => module.function_section()?
+			.entries()
+			.get(func_idx as usize - imported.len())?
+			.type_ref(),
+	};
+	match module.type_section()?.types().get(type_idx as usize)? {
+		Type::Function(ty) => Some(ty),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pwasm_utils::testing::parse_wat;
+
+	const PROFILE: &str = r#"
+		[imports]
+		module = "env"
+		functions = ["gas"]
+		allow_memory = true
+
+		[exports]
+		required = ["call"]
+		unit_functions = true
+
+		[limits]
+		max_memory_pages = 16
+
+		[determinism]
+		forbid_floats = true
+	"#;
+
+	#[test]
+	fn passes() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "gas" (func (param i32)))
+				(import "env" "memory" (memory 1 16))
+				(func (export "call")))
+		"#);
+		let profile: Profile = toml::from_str(PROFILE).unwrap();
+
+		let report = validate("test.wasm", 0, &module, &profile);
+		assert!(report.passed, "{}", report);
+	}
+
+	#[test]
+	fn reports_violations() {
+		let module = parse_wat(r#"
+			(module
+				(import "ext" "gas" (func (param i32)))
+				(import "env" "storage_read" (func))
+				(memory 1)
+				(global (export "g") (mut i32) (i32.const 0))
+				(func (export "call") (param f32)))
+		"#);
+		let profile: Profile = toml::from_str(PROFILE).unwrap();
+
+		let report = validate("test.wasm", 0, &module, &profile);
+		assert!(!report.passed);
+		assert_eq!(report.features, vec!["mutable-globals"]);
+		assert_eq!(
+			report.violations.iter().map(|v| v.check).collect::<Vec<_>>(),
+			vec!["imports", "imports", "exports", "limits", "features", "determinism"]
+		);
+	}
+}
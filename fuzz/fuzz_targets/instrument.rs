@@ -0,0 +1,25 @@
+//! Instruments arbitrary modules with the checks enabled. Modules which parity-wasm decodes must
+//! be instrumented or rejected with an error, never make the passes panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pwasm_utils::parity_wasm::elements;
+use pwasm_utils::{inject_gas_counter, pipeline, rules, stack_height, GasConfig};
+
+fuzz_target!(|bytes: &[u8]| {
+	let module: elements::Module = match elements::deserialize_buffer(bytes) {
+		Ok(module) => module,
+		Err(_) => return,
+	};
+	let rules = rules::Set::default().with_grow_cost(1);
+	let gas_config = GasConfig::default().with_checks();
+	let limiter_config = stack_height::LimiterConfig::new().with_checks();
+
+	let _ = inject_gas_counter(module.clone(), &rules, gas_config);
+	let _ = stack_height::inject_limiter_with_config(module.clone(), 1024, limiter_config);
+	let config = pipeline::PipelineConfig::new()
+		.with_gas(&rules, gas_config)
+		.with_stack_limit(1024, limiter_config);
+	let _ = pipeline::instrument(module, &config);
+});
@@ -0,0 +1,188 @@
+//! The calls between the functions of a module.
+
+use crate::std::collections::BTreeSet;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Instruction};
+
+/// The calls a function makes and how it may be called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCalls {
+	/// Index of the type of the function.
+	pub type_ref: u32,
+	/// Functions called directly, by index in the function index space, in ascending order.
+	/// Imported functions call none.
+	pub direct: Vec<u32>,
+	/// Type indices of the indirect calls, in ascending order.
+	pub indirect: Vec<u32>,
+	/// Whether the function may be called indirectly: it is in an element segment, and either an
+	/// indirect call of the module has its signature or the table is imported or exported, so
+	/// that other modules may call it.
+	pub indirectly_callable: bool,
+}
+
+/// Call graph of a module, see `call_graph`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallGraph {
+	/// Calls of each function, by index in the function index space.
+	pub functions: Vec<FunctionCalls>,
+	/// Index of the first type equal to each type, as signatures are compared by structure.
+	signatures: Vec<u32>,
+}
+
+impl CallGraph {
+	fn signature(&self, type_ref: u32) -> u32 {
+		self.signatures.get(type_ref as usize).cloned().unwrap_or(type_ref)
+	}
+
+	/// Functions an indirect call with the type `type_ref` may call, in ascending order.
+	///
+	/// If the table is imported, functions of other modules may be called as well.
+	pub fn indirect_targets(&self, type_ref: u32) -> Vec<u32> {
+		let signature = self.signature(type_ref);
+		self.functions.iter()
+			.enumerate()
+			.filter(|(_, function)| function.indirectly_callable && self.signature(function.type_ref) == signature)
+			.map(|(index, _)| index as u32)
+			.collect()
+	}
+
+	/// Functions `function` may call directly or indirectly, in ascending order.
+	pub fn callees(&self, function: u32) -> Vec<u32> {
+		let calls = match self.functions.get(function as usize) {
+			Some(calls) => calls,
+			None => return Vec::new(),
+		};
+		let mut callees = calls.direct.iter().cloned().collect::<BTreeSet<_>>();
+		for type_ref in &calls.indirect {
+			callees.extend(self.indirect_targets(*type_ref));
+		}
+		callees.into_iter().collect()
+	}
+
+	/// Functions which may call `function` directly or indirectly, in ascending order.
+	pub fn callers(&self, function: u32) -> Vec<u32> {
+		(0..self.functions.len() as u32)
+			.filter(|caller| self.callees(*caller).contains(&function))
+			.collect()
+	}
+
+	/// Functions which may be executed by calling the `roots`, including them, in ascending order.
+	pub fn reachable(&self, roots: &[u32]) -> Vec<u32> {
+		let mut visited = BTreeSet::new();
+		let mut pending = roots.to_vec();
+		while let Some(function) = pending.pop() {
+			if (function as usize) < self.functions.len() && visited.insert(function) {
+				pending.extend(self.callees(function));
+			}
+		}
+		visited.into_iter().collect()
+	}
+}
+
+/// Build the call graph of the `module` from the calls in all function bodies and the functions
+/// in the element segments.
+pub fn call_graph(module: &elements::Module) -> CallGraph {
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let signatures = types.iter()
+		.map(|ty| types.iter().position(|other| other == ty).expect("the type itself is found; qed") as u32)
+		.collect::<Vec<_>>();
+
+	let imports = module.import_section().map_or(&[][..], |import_section| import_section.entries());
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	let mut functions = imports.iter()
+		.filter_map(|entry| match *entry.external() {
+			elements::External::Function(type_ref) => Some((type_ref, None)),
+			_ => None,
+		})
+		.chain(
+			module.function_section()
+				.map_or(&[][..], |function_section| function_section.entries())
+				.iter()
+				.zip(bodies.iter().map(Some))
+				.map(|(func, body)| (func.type_ref(), body))
+		)
+		.map(|(type_ref, body)| {
+			let mut direct = BTreeSet::new();
+			let mut indirect = BTreeSet::new();
+			for instruction in body.map_or(&[][..], |body| body.code().elements()) {
+				match *instruction {
+					Instruction::Call(func) => { direct.insert(func); }
+					Instruction::CallIndirect(type_ref, _) => { indirect.insert(type_ref); }
+					_ => {}
+				}
+			}
+			FunctionCalls {
+				type_ref,
+				direct: direct.into_iter().collect(),
+				indirect: indirect.into_iter().collect(),
+				indirectly_callable: false,
+			}
+		})
+		.collect::<Vec<_>>();
+
+	let shared_table = imports.iter().any(|entry| matches!(entry.external(), elements::External::Table(_)))
+		|| module.export_section()
+			.map_or(&[][..], |export_section| export_section.entries())
+			.iter()
+			.any(|entry| matches!(entry.internal(), elements::Internal::Table(_)));
+	let signature = |type_ref: u32| signatures.get(type_ref as usize).cloned().unwrap_or(type_ref);
+	let called_signatures = functions.iter()
+		.flat_map(|function| function.indirect.iter().map(|type_ref| signature(*type_ref)))
+		.collect::<BTreeSet<_>>();
+	for segment in module.elements_section().map_or(&[][..], |elements_section| elements_section.entries()) {
+		for member in segment.members() {
+			if let Some(function) = functions.get_mut(*member as usize) {
+				function.indirectly_callable |= shared_table || called_signatures.contains(&signature(function.type_ref));
+			}
+		}
+	}
+
+	CallGraph { functions, signatures }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn direct_and_indirect_calls() {
+		let module = parse_wat(r#"
+			(module
+				(type $void (func))
+				(type $unary (func (param i32)))
+				(type $void_again (func))
+				(import "env" "ext" (func $ext))
+				(table 2 anyfunc)
+				(elem (i32.const 0) $leaf $unary)
+				(func $main (export "main")
+					call $leaf
+					call $ext
+					call $leaf
+					i32.const 0
+					call_indirect (type $void_again))
+				(func $leaf (type $void))
+				(func $unary (type $unary))
+				(func $dead
+					call $main))
+		"#);
+
+		let graph = call_graph(&module);
+
+		assert_eq!(graph.functions[1], FunctionCalls {
+			type_ref: 0,
+			direct: vec![0, 2],
+			indirect: vec![2],
+			indirectly_callable: false,
+		});
+		assert!(graph.functions[2].indirectly_callable);
+		// The table has no indirect calls of its signature.
+		assert!(!graph.functions[3].indirectly_callable);
+
+		assert_eq!(graph.indirect_targets(2), vec![2]);
+		assert_eq!(graph.callees(1), vec![0, 2]);
+		assert_eq!(graph.callers(2), vec![1]);
+		assert_eq!(graph.reachable(&[1]), vec![0, 1, 2]);
+	}
+}
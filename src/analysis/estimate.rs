@@ -0,0 +1,558 @@
+//! Experimental estimation of the gas charged by a call with concrete arguments.
+//!
+//! The exported function is evaluated on the module instrumented with gas metering, tracking
+//! the values which only depend on the arguments and constants. Loops bounded by such values, as
+//! they are common in view calls, are unrolled this way. As soon as the control flow depends on
+//! anything else, e.g. the memory, a mutable global or the result of a host function, the static
+//! bounds of `gas_bounds` are returned instead.
+
+use crate::std::collections::BTreeMap;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, ImportCountType, Instruction, ValueType};
+
+use crate::visit::{function_arities, stack_effect};
+use crate::gas::{self, inject_gas_counter};
+use crate::rules;
+
+/// Number of instructions after which the evaluation gives up.
+const MAX_STEPS: usize = 1_000_000;
+/// Depth of nested calls after which the evaluation gives up.
+const MAX_DEPTH: usize = 256;
+
+/// An argument of the call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+	I32(i32),
+	I64(i64),
+}
+
+/// Estimated gas charged by a call.
+///
+/// As with `gas_bounds`, only the gas charged by the injected metering is covered, the costs of
+/// host functions are up to the runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Estimate {
+	/// The call returns after being charged exactly this amount of gas.
+	Exact(u64),
+	/// The call traps after being charged exactly this amount of gas.
+	Trap(u64),
+	/// The evaluation couldn't decide the control flow, these are the static bounds of the export.
+	Bounds { min: u64, max: Option<u64> },
+}
+
+/// Error of the estimation.
+#[derive(Debug)]
+pub enum Error {
+	/// The module can't be instrumented with gas metering.
+	Gas(gas::Error),
+	/// There is no exported function with the given name.
+	NoSuchExport,
+	/// The arguments don't match the parameters of the exported function.
+	Arguments,
+}
+
+impl From<gas::Error> for Error {
+	fn from(err: gas::Error) -> Self {
+		Error::Gas(err)
+	}
+}
+
+/// Why the evaluation of a function stopped before it returned.
+enum Stop {
+	Trap,
+	Undecided,
+}
+
+/// The `else` and `end` positions of every control block, by position of the instruction opening it.
+type BlockEnds = BTreeMap<usize, (Option<usize>, usize)>;
+
+fn block_ends(code: &[Instruction]) -> BlockEnds {
+	let mut ends = BTreeMap::new();
+	let mut open = Vec::new();
+	for (pos, instruction) in code.iter().enumerate() {
+		match *instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => open.push((pos, None)),
+			Instruction::Else => {
+				if let Some((_, else_pos)) = open.last_mut() {
+					*else_pos = Some(pos);
+				}
+			}
+			Instruction::End => {
+				if let Some((start, else_pos)) = open.pop() {
+					ends.insert(start, (else_pos, pos));
+				}
+			}
+			_ => {}
+		}
+	}
+	ends
+}
+
+/// A control block entered by the evaluation.
+struct Frame {
+	is_loop: bool,
+	/// Position of the instruction opening the block.
+	start: usize,
+	/// Position of the `end` of the block.
+	end: usize,
+	/// Height of the value stack when the block was entered.
+	height: usize,
+	/// Number of values left by the block.
+	arity: usize,
+}
+
+fn block_arity(block_type: elements::BlockType) -> usize {
+	match block_type {
+		elements::BlockType::NoResult => 0,
+		elements::BlockType::Value(_) => 1,
+	}
+}
+
+fn zero(value_type: ValueType) -> Option<Value> {
+	match value_type {
+		ValueType::I32 => Some(Value::I32(0)),
+		ValueType::I64 => Some(Value::I64(0)),
+		_ => None,
+	}
+}
+
+fn is_true(value: Option<Value>) -> Result<bool, Stop> {
+	match value {
+		Some(Value::I32(value)) => Ok(value != 0),
+		_ => Err(Stop::Undecided),
+	}
+}
+
+fn pop(stack: &mut Vec<Option<Value>>) -> Result<Option<Value>, Stop> {
+	stack.pop().ok_or(Stop::Undecided)
+}
+
+/// Evaluate the unary operator `instruction`, or return `None` if it's not modeled.
+fn unary(instruction: &Instruction, value: Value) -> Option<Value> {
+	use self::Value::{I32, I64};
+
+	Some(match (instruction, value) {
+		(Instruction::I32Eqz, I32(a)) => I32((a == 0) as i32),
+		(Instruction::I64Eqz, I64(a)) => I32((a == 0) as i32),
+		(Instruction::I32WrapI64, I64(a)) => I32(a as i32),
+		(Instruction::I64ExtendSI32, I32(a)) => I64(a as i64),
+		(Instruction::I64ExtendUI32, I32(a)) => I64(a as u32 as i64),
+		_ => return None,
+	})
+}
+
+/// Evaluate the binary operator `instruction`, or return `None` if it's not modeled or traps.
+fn binary(instruction: &Instruction, lhs: Value, rhs: Value) -> Option<Value> {
+	use parity_wasm::elements::Instruction::*;
+	use self::Value::{I32, I64};
+
+	Some(match (instruction, lhs, rhs) {
+		(I32Add, I32(a), I32(b)) => I32(a.wrapping_add(b)),
+		(I32Sub, I32(a), I32(b)) => I32(a.wrapping_sub(b)),
+		(I32Mul, I32(a), I32(b)) => I32(a.wrapping_mul(b)),
+		(I32DivS, I32(a), I32(b)) => I32(a.checked_div(b)?),
+		(I32DivU, I32(a), I32(b)) => I32((a as u32).checked_div(b as u32)? as i32),
+		(I32RemS, I32(a), I32(b)) => I32(a.checked_rem(b)?),
+		(I32RemU, I32(a), I32(b)) => I32((a as u32).checked_rem(b as u32)? as i32),
+		(I32And, I32(a), I32(b)) => I32(a & b),
+		(I32Or, I32(a), I32(b)) => I32(a | b),
+		(I32Xor, I32(a), I32(b)) => I32(a ^ b),
+		(I32Shl, I32(a), I32(b)) => I32(a.wrapping_shl(b as u32)),
+		(I32ShrS, I32(a), I32(b)) => I32(a.wrapping_shr(b as u32)),
+		(I32ShrU, I32(a), I32(b)) => I32((a as u32).wrapping_shr(b as u32) as i32),
+		(I32Eq, I32(a), I32(b)) => I32((a == b) as i32),
+		(I32Ne, I32(a), I32(b)) => I32((a != b) as i32),
+		(I32LtS, I32(a), I32(b)) => I32((a < b) as i32),
+		(I32LtU, I32(a), I32(b)) => I32(((a as u32) < b as u32) as i32),
+		(I32GtS, I32(a), I32(b)) => I32((a > b) as i32),
+		(I32GtU, I32(a), I32(b)) => I32((a as u32 > b as u32) as i32),
+		(I32LeS, I32(a), I32(b)) => I32((a <= b) as i32),
+		(I32LeU, I32(a), I32(b)) => I32((a as u32 <= b as u32) as i32),
+		(I32GeS, I32(a), I32(b)) => I32((a >= b) as i32),
+		(I32GeU, I32(a), I32(b)) => I32((a as u32 >= b as u32) as i32),
+
+		(I64Add, I64(a), I64(b)) => I64(a.wrapping_add(b)),
+		(I64Sub, I64(a), I64(b)) => I64(a.wrapping_sub(b)),
+		(I64Mul, I64(a), I64(b)) => I64(a.wrapping_mul(b)),
+		(I64DivS, I64(a), I64(b)) => I64(a.checked_div(b)?),
+		(I64DivU, I64(a), I64(b)) => I64((a as u64).checked_div(b as u64)? as i64),
+		(I64RemS, I64(a), I64(b)) => I64(a.checked_rem(b)?),
+		(I64RemU, I64(a), I64(b)) => I64((a as u64).checked_rem(b as u64)? as i64),
+		(I64And, I64(a), I64(b)) => I64(a & b),
+		(I64Or, I64(a), I64(b)) => I64(a | b),
+		(I64Xor, I64(a), I64(b)) => I64(a ^ b),
+		(I64Shl, I64(a), I64(b)) => I64(a.wrapping_shl(b as u32)),
+		(I64ShrS, I64(a), I64(b)) => I64(a.wrapping_shr(b as u32)),
+		(I64ShrU, I64(a), I64(b)) => I64((a as u64).wrapping_shr(b as u32) as i64),
+		(I64Eq, I64(a), I64(b)) => I32((a == b) as i32),
+		(I64Ne, I64(a), I64(b)) => I32((a != b) as i32),
+		(I64LtS, I64(a), I64(b)) => I32((a < b) as i32),
+		(I64LtU, I64(a), I64(b)) => I32(((a as u64) < b as u64) as i32),
+		(I64GtS, I64(a), I64(b)) => I32((a > b) as i32),
+		(I64GtU, I64(a), I64(b)) => I32((a as u64 > b as u64) as i32),
+		(I64LeS, I64(a), I64(b)) => I32((a <= b) as i32),
+		(I64LeU, I64(a), I64(b)) => I32((a as u64 <= b as u64) as i32),
+		(I64GeS, I64(a), I64(b)) => I32((a >= b) as i32),
+		(I64GeU, I64(a), I64(b)) => I32((a as u64 >= b as u64) as i32),
+		_ => return None,
+	})
+}
+
+struct Evaluator<'a> {
+	/// The module instrumented with gas metering.
+	module: &'a elements::Module,
+	bodies: &'a [elements::FuncBody],
+	ends: Vec<BlockEnds>,
+	arities: Vec<(usize, usize)>,
+	/// Number of imported functions, including the gas function.
+	imported_funcs: u32,
+	gas_func: u32,
+	/// Values of the globals, if they are known.
+	globals: Vec<Option<Value>>,
+	steps: usize,
+	gas: u64,
+}
+
+impl<'a> Evaluator<'a> {
+	fn new(module: &'a elements::Module, gas_func: u32) -> Self {
+		let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+		let imported_globals = module.import_count(ImportCountType::Global);
+
+		// Immutable globals initialized with a constant are known, mutable ones depend on the state.
+		let globals = vec![None; imported_globals]
+			.into_iter()
+			.chain(
+				module.global_section()
+					.map_or(&[][..], |global_section| global_section.entries())
+					.iter()
+					.map(|global| match global.init_expr().code() {
+						_ if global.global_type().is_mutable() => None,
+						[Instruction::I32Const(value), Instruction::End] => Some(Value::I32(*value)),
+						[Instruction::I64Const(value), Instruction::End] => Some(Value::I64(*value)),
+						_ => None,
+					})
+			)
+			.collect();
+
+		Evaluator {
+			module,
+			bodies,
+			ends: bodies.iter().map(|body| block_ends(body.code().elements())).collect(),
+			arities: function_arities(module),
+			imported_funcs: module.import_count(ImportCountType::Function) as u32,
+			gas_func,
+			globals,
+			steps: 0,
+			gas: 0,
+		}
+	}
+
+	/// Evaluate a call to the defined function `func` with the `args`, returning its results.
+	fn call(&mut self, func: u32, args: Vec<Option<Value>>, depth: usize) -> Result<Vec<Option<Value>>, Stop> {
+		if depth > MAX_DEPTH {
+			return Err(Stop::Undecided);
+		}
+		let index = func.checked_sub(self.imported_funcs).ok_or(Stop::Undecided)? as usize;
+		let body = self.bodies.get(index).ok_or(Stop::Undecided)?;
+		let code = body.code().elements();
+		let results = self.arities[func as usize].1;
+
+		let mut locals = args;
+		for local in body.locals() {
+			locals.resize(locals.len() + local.count() as usize, zero(local.value_type()));
+		}
+
+		let mut stack: Vec<Option<Value>> = Vec::new();
+		let mut frames = vec![Frame { is_loop: false, start: 0, end: code.len() - 1, height: 0, arity: results }];
+		let mut pos = 0;
+		loop {
+			self.steps += 1;
+			if self.steps > MAX_STEPS {
+				return Err(Stop::Undecided);
+			}
+
+			// Label of the block to branch to, if the instruction branches.
+			let mut branch = None;
+			match code[pos] {
+				Instruction::Block(block_type) | Instruction::Loop(block_type) => {
+					frames.push(Frame {
+						is_loop: matches!(code[pos], Instruction::Loop(_)),
+						start: pos,
+						end: self.ends[index][&pos].1,
+						height: stack.len(),
+						arity: block_arity(block_type),
+					});
+				}
+				Instruction::If(block_type) => {
+					let (else_pos, end) = self.ends[index][&pos];
+					let condition = is_true(pop(&mut stack)?)?;
+					match else_pos {
+						// Without an `else`, the block is skipped as a whole.
+						None if !condition => pos = end,
+						_ => {
+							frames.push(Frame {
+								is_loop: false,
+								start: pos,
+								end,
+								height: stack.len(),
+								arity: block_arity(block_type),
+							});
+							if !condition {
+								pos = else_pos.expect("checked by the arm above; qed");
+							}
+						}
+					}
+				}
+				Instruction::Else => {
+					// The `then` branch is done, leave the block at its `end`.
+					pos = frames.last().ok_or(Stop::Undecided)?.end;
+					continue;
+				}
+				Instruction::End => {
+					frames.pop();
+					if frames.is_empty() {
+						return Ok(stack.split_off(stack.len().saturating_sub(results)));
+					}
+				}
+				Instruction::Br(label) => branch = Some(label),
+				Instruction::BrIf(label) => {
+					if is_true(pop(&mut stack)?)? {
+						branch = Some(label);
+					}
+				}
+				Instruction::BrTable(ref table) => {
+					let selected = match pop(&mut stack)? {
+						Some(Value::I32(selected)) => selected as u32 as usize,
+						_ => return Err(Stop::Undecided),
+					};
+					branch = Some(*table.table.get(selected).unwrap_or(&table.default));
+				}
+				Instruction::Return => branch = Some(frames.len() as u32 - 1),
+				Instruction::Unreachable => return Err(Stop::Trap),
+
+				Instruction::Call(callee) if callee == self.gas_func => match pop(&mut stack)? {
+					Some(Value::I32(amount)) => self.gas += amount as u32 as u64,
+					Some(Value::I64(amount)) => self.gas += amount as u64,
+					None => return Err(Stop::Undecided),
+				},
+				Instruction::Call(callee) => {
+					let (params, results) = *self.arities.get(callee as usize).ok_or(Stop::Undecided)?;
+					let args = stack.split_off(stack.len().checked_sub(params).ok_or(Stop::Undecided)?);
+					if callee < self.imported_funcs {
+						// The results of host functions are not known.
+						stack.resize(stack.len() + results, None);
+					} else {
+						stack.extend(self.call(callee, args, depth + 1)?);
+					}
+				}
+				Instruction::CallIndirect(_, _) => return Err(Stop::Undecided),
+
+				Instruction::GetLocal(local) => stack.push(*locals.get(local as usize).ok_or(Stop::Undecided)?),
+				Instruction::SetLocal(local) | Instruction::TeeLocal(local) => {
+					let value = pop(&mut stack)?;
+					*locals.get_mut(local as usize).ok_or(Stop::Undecided)? = value;
+					if let Instruction::TeeLocal(_) = code[pos] {
+						stack.push(value);
+					}
+				}
+				Instruction::GetGlobal(global) => stack.push(self.globals.get(global as usize).cloned().flatten()),
+				Instruction::SetGlobal(global) => {
+					let value = pop(&mut stack)?;
+					if let Some(known) = self.globals.get_mut(global as usize) {
+						*known = value;
+					}
+				}
+				Instruction::I32Const(value) => stack.push(Some(Value::I32(value))),
+				Instruction::I64Const(value) => stack.push(Some(Value::I64(value))),
+				Instruction::Select => {
+					let condition = pop(&mut stack)?;
+					let rhs = pop(&mut stack)?;
+					let lhs = pop(&mut stack)?;
+					stack.push(match is_true(condition) {
+						Ok(true) => lhs,
+						Ok(false) => rhs,
+						Err(_) if lhs == rhs => lhs,
+						Err(_) => None,
+					});
+				}
+
+				ref instruction => {
+					let (pops, pushes) = stack_effect(instruction, self.module, &self.arities).ok_or(Stop::Undecided)?;
+					let operands = stack.split_off(stack.len().checked_sub(pops).ok_or(Stop::Undecided)?);
+					let result = match (pushes, &operands[..]) {
+						(1, [Some(value)]) => unary(instruction, *value),
+						(1, [Some(lhs), Some(rhs)]) => binary(instruction, *lhs, *rhs),
+						_ => None,
+					};
+					stack.resize(stack.len() + pushes, result);
+				}
+			}
+
+			if let Some(label) = branch {
+				let target = frames.len().checked_sub(label as usize + 1).ok_or(Stop::Undecided)?;
+				let frame = &frames[target];
+				if frame.is_loop {
+					// Loops have no parameters, so nothing is kept when branching to their start.
+					stack.truncate(frame.height);
+					pos = frame.start + 1;
+					frames.truncate(target + 1);
+					continue;
+				}
+
+				let kept = stack.split_off(stack.len().checked_sub(frame.arity).ok_or(Stop::Undecided)?);
+				stack.truncate(frame.height);
+				stack.extend(kept);
+				if target == 0 {
+					return Ok(stack);
+				}
+				pos = frame.end + 1;
+				frames.truncate(target);
+				continue;
+			}
+
+			pos += 1;
+		}
+	}
+}
+
+/// The parameters of the function `func`.
+fn function_params(module: &elements::Module, func: u32) -> Option<&[ValueType]> {
+	let type_ref = module.import_section()
+		.map_or(&[][..], |import_section| import_section.entries())
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Function(type_ref) => Some(*type_ref),
+			_ => None,
+		})
+		.chain(
+			module.function_section()
+				.map_or(&[][..], |function_section| function_section.entries())
+				.iter()
+				.map(|func| func.type_ref())
+		)
+		.nth(func as usize)?;
+	match module.type_section()?.types().get(type_ref as usize)? {
+		elements::Type::Function(ty) => Some(ty.params()),
+	}
+}
+
+/// Estimate the gas charged by a call to the function exported as `export` with the `args`,
+/// when the `module` is instrumented with gas metering according to the `rules`.
+///
+/// This is experimental. The estimate is exact if the control flow only depends on the arguments
+/// and constants, otherwise the static bounds of the export are returned.
+pub fn with_args(
+	module: &elements::Module,
+	rules: &rules::Set,
+	export: &str,
+	args: &[Value],
+) -> Result<Estimate, Error> {
+	let func = module.export_section()
+		.map_or(&[][..], |export_section| export_section.entries())
+		.iter()
+		.find_map(|entry| match *entry.internal() {
+			elements::Internal::Function(func) if entry.field() == export => Some(func),
+			_ => None,
+		})
+		.ok_or(Error::NoSuchExport)?;
+
+	let params = function_params(module, func).ok_or(Error::NoSuchExport)?;
+	let matches_params = params.len() == args.len() && params.iter().zip(args).all(|(param, arg)| {
+		matches!((param, arg), (ValueType::I32, Value::I32(_)) | (ValueType::I64, Value::I64(_)))
+	});
+	if !matches_params {
+		return Err(Error::Arguments);
+	}
+
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+	if func >= imported_funcs {
+		let metered = inject_gas_counter(module.clone(), rules, "env").map_err(|(_, e)| e)?;
+		// The gas function is imported after the other functions and shifts the defined ones by one.
+		let mut evaluator = Evaluator::new(&metered, imported_funcs);
+		let result = evaluator.call(func + 1, args.iter().cloned().map(Some).collect(), 0);
+		match result {
+			Ok(_) => return Ok(Estimate::Exact(evaluator.gas)),
+			Err(Stop::Trap) => return Ok(Estimate::Trap(evaluator.gas)),
+			Err(Stop::Undecided) => {}
+		}
+	}
+
+	let bounds = super::gas_bounds(module, rules)?
+		.into_iter()
+		.find(|bounds| bounds.name == export)
+		.expect("gas_bounds covers all exported functions; qed");
+	Ok(Estimate::Bounds { min: bounds.min, max: bounds.max })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn estimate(source: &str, export: &str, args: &[Value]) -> Estimate {
+		let module = elements::deserialize_buffer(&wabt::wat2wasm(source).unwrap()).unwrap();
+		with_args(&module, &rules::Set::default(), export, args).unwrap()
+	}
+
+	const SUM: &str = r#"
+		(module
+			(import "env" "input" (func $input (result i32)))
+			(func $sum (export "sum") (param $n i32) (result i32)
+				(local $i i32)
+				(local $acc i32)
+				block
+					loop
+						get_local $i
+						get_local $n
+						i32.ge_s
+						br_if 1
+						get_local $acc
+						get_local $i
+						i32.add
+						set_local $acc
+						get_local $i
+						i32.const 1
+						i32.add
+						set_local $i
+						br 0
+					end
+				end
+				get_local $acc)
+			(func (export "sum_input") (result i32)
+				call $input
+				call $sum)
+			(func (export "checked") (param i32)
+				get_local 0
+				if
+					unreachable
+				end))
+	"#;
+
+	#[test]
+	fn unrolls_loops() {
+		// The loop charges 4 for the exit check and 9 for the rest of an iteration, the function 2 on
+		// entry and 1 on exit.
+		assert_eq!(estimate(SUM, "sum", &[Value::I32(0)]), Estimate::Exact(2 + 4 + 1));
+		assert_eq!(estimate(SUM, "sum", &[Value::I32(3)]), Estimate::Exact(2 + 3 * (4 + 9) + 4 + 1));
+	}
+
+	#[test]
+	fn falls_back_to_bounds() {
+		assert!(matches!(estimate(SUM, "sum_input", &[]), Estimate::Bounds { max: None, .. }));
+	}
+
+	#[test]
+	fn traps() {
+		assert_eq!(estimate(SUM, "checked", &[Value::I32(0)]), Estimate::Exact(2));
+		assert_eq!(estimate(SUM, "checked", &[Value::I32(1)]), Estimate::Trap(3));
+	}
+
+	#[test]
+	fn checks_arguments() {
+		let module = elements::deserialize_buffer(&wabt::wat2wasm(SUM).unwrap()).unwrap();
+		let rules = rules::Set::default();
+		assert!(matches!(with_args(&module, &rules, "sum", &[]), Err(Error::Arguments)));
+		assert!(matches!(with_args(&module, &rules, "sum", &[Value::I64(1)]), Err(Error::Arguments)));
+		assert!(matches!(with_args(&module, &rules, "missing", &[]), Err(Error::NoSuchExport)));
+	}
+}
@@ -0,0 +1,449 @@
+//! Static bounds on the gas charged by each exported function.
+
+use crate::std::collections::BTreeMap;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, ImportCountType, Instruction};
+
+use crate::gas::{self, inject_gas_counter};
+use crate::rules;
+
+/// Bounds on the gas charged by a call to an exported function which returns normally.
+///
+/// Only the gas charged by the injected metering is covered, the costs of host functions are up
+/// to the runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportGas {
+	/// Name of the export.
+	pub name: String,
+	/// Index of the exported function in the function index space.
+	pub function: u32,
+	/// Gas charged by every call.
+	pub min: u64,
+	/// Gas charged by a call at most, or `None` if it can't be bounded statically, which is the
+	/// case if a loop, a recursion, an indirect call or `memory.grow` may be executed.
+	pub max: Option<u64>,
+	/// Whether an imported function may be called, whose cost is not included.
+	pub host_calls: bool,
+}
+
+/// Upper bound on the gas charged by a call to a defined function along its most expensive path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionBound {
+	/// Index of the function in the function index space.
+	pub function: u32,
+	/// Gas charged by a call at most, or `None` if it can't be bounded statically, which is the
+	/// case if a loop, a recursion, an indirect call or `memory.grow` may be executed.
+	pub max: Option<u64>,
+	/// Whether an imported function may be called, whose cost is not included.
+	pub host_calls: bool,
+}
+
+/// What the metered body of a defined function charges and calls.
+struct FunctionGas {
+	/// Sum of all charges of the body.
+	charges: u64,
+	/// Sum of the charges which are executed by every call.
+	min_charges: u64,
+	/// Defined functions called, by index in the code section, with whether every call executes
+	/// the call.
+	callees: Vec<(usize, bool)>,
+	/// Whether the body contains a loop, an indirect call or a call to the grow counter.
+	unbounded: bool,
+	host_calls: bool,
+}
+
+/// Whether a branch to `label` at the control `depth` leaves the function.
+fn leaves_function(label: u32, depth: u32) -> bool {
+	label == depth
+}
+
+/// Gather the charges and calls of the metered function body `code`.
+///
+/// The functions following the `defined_funcs` of the original module are the ones appended by the
+/// gas pass, i.e. the grow counter.
+fn function_gas(code: &[Instruction], gas_func: u32, imported_funcs: u32, defined_funcs: u32) -> FunctionGas {
+	let mut function = FunctionGas {
+		charges: 0,
+		min_charges: 0,
+		callees: Vec::new(),
+		unbounded: false,
+		host_calls: false,
+	};
+
+	// Every call returning normally executes the instructions outside of any block up to the
+	// first one which may leave the function. Branches out of nested blocks don't skip any of
+	// them, and a trap in a nested block means that the block is not executed by such a call.
+	let mut depth = 0u32;
+	let mut always = true;
+	for (pos, instruction) in code.iter().enumerate() {
+		match *instruction {
+			Instruction::Call(func) if func == gas_func => {
+				if let Some(Instruction::I32Const(cost)) = pos.checked_sub(1).map(|pos| &code[pos]) {
+					function.charges += *cost as u32 as u64;
+					if always && depth == 0 {
+						function.min_charges += *cost as u32 as u64;
+					}
+				}
+			}
+			Instruction::Call(func) if func < imported_funcs => function.host_calls = true,
+			Instruction::Call(func) if func - imported_funcs < defined_funcs => {
+				function.callees.push(((func - imported_funcs) as usize, always && depth == 0));
+			}
+			Instruction::Call(_) | Instruction::CallIndirect(_, _) | Instruction::Loop(_) =>
+				function.unbounded = true,
+			_ => {}
+		}
+
+		match *instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => depth += 1,
+			Instruction::End => depth = depth.saturating_sub(1),
+			Instruction::Return => always = false,
+			Instruction::Unreachable => always &= depth != 0,
+			Instruction::Br(label) | Instruction::BrIf(label) => always &= !leaves_function(label, depth),
+			Instruction::BrTable(ref table) => {
+				always &= !table.table.iter().chain(Some(&table.default))
+					.any(|label| leaves_function(*label, depth));
+			}
+			_ => {}
+		}
+	}
+
+	function
+}
+
+/// Bounds of the function `index` in the code section, memoized in `memo`.
+///
+/// A function is on `stack` while its callees are bounded. A recursive call is unbounded and
+/// contributes nothing to the lower bound, which may therefore be lower than necessary.
+fn bounds(
+	index: usize,
+	functions: &[FunctionGas],
+	memo: &mut BTreeMap<usize, (u64, Option<u64>, bool)>,
+	stack: &mut Vec<usize>,
+) -> (u64, Option<u64>, bool) {
+	if let Some(bounds) = memo.get(&index) {
+		return *bounds;
+	}
+	if stack.contains(&index) {
+		return (0, None, false);
+	}
+
+	let function = &functions[index];
+	stack.push(index);
+	let mut min = function.min_charges;
+	let mut max = if function.unbounded { None } else { Some(function.charges) };
+	let mut host_calls = function.host_calls;
+	for &(callee, always) in &function.callees {
+		let (callee_min, callee_max, callee_host_calls) = bounds(callee, functions, memo, stack);
+		if always {
+			min = min.saturating_add(callee_min);
+		}
+		max = match (max, callee_max) {
+			(Some(max), Some(callee_max)) => Some(max.saturating_add(callee_max)),
+			_ => None,
+		};
+		host_calls |= callee_host_calls;
+	}
+	stack.pop();
+
+	memo.insert(index, (min, max, host_calls));
+	(min, max, host_calls)
+}
+
+/// Compute static lower and upper bounds on the gas charged by each exported function of the
+/// `module` when it is instrumented with gas metering according to the `rules`.
+///
+/// The bounds are exact for functions without branches. The lower bound only counts code which
+/// every call executes, the upper bound counts every charge once and is unbounded if any code may
+/// be executed repeatedly or dynamically.
+pub fn gas_bounds(module: &elements::Module, rules: &rules::Set) -> Result<Vec<ExportGas>, gas::Error> {
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+	let defined_funcs = module.code_section().map_or(0, |code_section| code_section.bodies().len()) as u32;
+
+	let metered = inject_gas_counter(module.clone(), rules, "env").map_err(|(_, e)| e)?;
+	// The gas function is imported after the other functions and shifts the defined ones by one.
+	let gas_func = imported_funcs;
+	let functions = metered.code_section()
+		.map_or(&[][..], |code_section| code_section.bodies())
+		.iter()
+		.take(defined_funcs as usize)
+		.map(|body| function_gas(body.code().elements(), gas_func, imported_funcs + 1, defined_funcs))
+		.collect::<Vec<_>>();
+
+	let mut memo = BTreeMap::new();
+	Ok(module.export_section()
+		.map_or(&[][..], |export_section| export_section.entries())
+		.iter()
+		.filter_map(|entry| match *entry.internal() {
+			elements::Internal::Function(func) => Some((entry.field(), func)),
+			_ => None,
+		})
+		.map(|(name, func)| {
+			let (min, max, host_calls) = match func.checked_sub(imported_funcs) {
+				Some(index) => bounds(index as usize, &functions, &mut memo, &mut Vec::new()),
+				// An imported function is charged by the host only.
+				None => (0, Some(0), true),
+			};
+			ExportGas { name: name.into(), function: func, min, max, host_calls }
+		})
+		.collect())
+}
+
+/// A block of a metered body while it is walked by `worst_case`.
+struct PathFrame {
+	/// Gas charged at the start of the block along the most expensive path, if it is reachable.
+	entry: Option<u64>,
+	/// Gas charged at the end of the block along the most expensive path reaching it by a branch,
+	/// if there is one.
+	exit: Option<u64>,
+	/// Whether the block is an `if` without an `else` so far, whose end is reached from its
+	/// start if the condition doesn't hold.
+	open_if: bool,
+}
+
+/// Add the path charging `current` to the paths reaching the end of the block `label` refers to.
+fn branch(frames: &mut [PathFrame], label: u32, current: Option<u64>) {
+	if let Some(target) = frames.len().checked_sub(1 + label as usize) {
+		frames[target].exit = frames[target].exit.max(current);
+	}
+}
+
+/// Worst case of the function `index` in the code section, memoized in `memo`, along with
+/// whether it may call imported functions.
+///
+/// The metered bodies are walked along all paths through their blocks, adding the charges and the
+/// worst cases of the called functions, and keeping the most expensive path at the end of each
+/// block. Paths ending with `unreachable` count like the ones returning.
+fn worst_case(
+	index: usize,
+	bodies: &[elements::FuncBody],
+	gas_func: u32,
+	memo: &mut BTreeMap<usize, (Option<u64>, bool)>,
+	stack: &mut Vec<usize>,
+) -> (Option<u64>, bool) {
+	if let Some(bound) = memo.get(&index) {
+		return *bound;
+	}
+	if stack.contains(&index) {
+		return (None, false);
+	}
+
+	stack.push(index);
+	let code = bodies[index].code().elements();
+	let mut host_calls = false;
+	// The most expensive path up to the current instruction, if it is reachable.
+	let mut current = Some(0u64);
+	let mut frames = vec![PathFrame { entry: current, exit: None, open_if: false }];
+	let mut bounded = true;
+	for (pos, instruction) in code.iter().enumerate() {
+		match *instruction {
+			Instruction::Call(func) if func == gas_func => {
+				if let Some(Instruction::I32Const(cost)) = pos.checked_sub(1).map(|pos| &code[pos]) {
+					current = current.map(|current| current.saturating_add(*cost as u32 as u64));
+				}
+			}
+			Instruction::Call(func) if func < gas_func => host_calls = true,
+			Instruction::Call(func) if ((func - gas_func - 1) as usize) < bodies.len() => {
+				let (callee_max, callee_host_calls) = worst_case((func - gas_func - 1) as usize, bodies, gas_func, memo, stack);
+				host_calls |= callee_host_calls;
+				match callee_max {
+					Some(callee_max) => current = current.map(|current| current.saturating_add(callee_max)),
+					None => bounded = false,
+				}
+			}
+			// The grow counter, indirect calls and loops.
+			Instruction::Call(_) | Instruction::CallIndirect(_, _) | Instruction::Loop(_) => bounded = false,
+			Instruction::Block(_) => frames.push(PathFrame { entry: current, exit: None, open_if: false }),
+			Instruction::If(_) => frames.push(PathFrame { entry: current, exit: None, open_if: true }),
+			Instruction::Else => {
+				let frame = frames.last_mut().expect("the body is well formed after the gas metering; qed");
+				frame.exit = frame.exit.max(current);
+				frame.open_if = false;
+				current = frame.entry;
+			}
+			Instruction::End => {
+				let frame = frames.pop().expect("the body is well formed after the gas metering; qed");
+				current = current.max(frame.exit);
+				if frame.open_if {
+					current = current.max(frame.entry);
+				}
+			}
+			Instruction::Br(label) => {
+				branch(&mut frames, label, current);
+				current = None;
+			}
+			Instruction::BrIf(label) => branch(&mut frames, label, current),
+			Instruction::BrTable(ref table) => {
+				for label in table.table.iter().chain(Some(&table.default)) {
+					branch(&mut frames, *label, current);
+				}
+				current = None;
+			}
+			Instruction::Return | Instruction::Unreachable => {
+				frames[0].exit = frames[0].exit.max(current);
+				current = None;
+			}
+			_ => {}
+		}
+		if !bounded {
+			break;
+		}
+	}
+	stack.pop();
+
+	// The final `end` of the body joins all paths.
+	let bound = (if bounded { Some(current.unwrap_or(0)) } else { None }, host_calls);
+	memo.insert(index, bound);
+	bound
+}
+
+/// Compute an upper bound on the gas charged by each function defined by the `module` when it is
+/// instrumented with gas metering according to the `rules`, in the order of the code section.
+///
+/// Unlike the upper bounds of `gas_bounds`, which count every charge, the bound is the cost of
+/// the most expensive path through the metered blocks, e.g. the more expensive arm of an `if`,
+/// so it is exact for functions without branches depending on their arguments. Functions which
+/// may run a loop, a recursion, an indirect call or `memory.grow` are unbounded.
+pub fn worst_case_gas(module: &elements::Module, rules: &rules::Set) -> Result<Vec<FunctionBound>, gas::Error> {
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+	let defined_funcs = module.code_section().map_or(0, |code_section| code_section.bodies().len());
+
+	let metered = inject_gas_counter(module.clone(), rules, "env").map_err(|(_, e)| e)?;
+	// The gas function is imported after the other functions and shifts the defined ones by one.
+	let bodies = metered.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	let bodies = &bodies[..defined_funcs];
+
+	let mut memo = BTreeMap::new();
+	Ok((0..defined_funcs)
+		.map(|index| {
+			let (max, host_calls) = worst_case(index, bodies, imported_funcs, &mut memo, &mut Vec::new());
+			FunctionBound { function: imported_funcs + index as u32, max, host_calls }
+		})
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	fn bounds_of(source: &str) -> Vec<(String, u64, Option<u64>, bool)> {
+		gas_bounds(&parse_wat(source), &rules::Set::default())
+			.unwrap()
+			.into_iter()
+			.map(|export| (export.name, export.min, export.max, export.host_calls))
+			.collect()
+	}
+
+	#[test]
+	fn straight_line() {
+		assert_eq!(
+			bounds_of(r#"
+				(module
+					(func (export "add") (param i32 i32) (result i32)
+						get_local 0
+						get_local 1
+						i32.add))
+			"#),
+			vec![("add".into(), 3, Some(3), false)],
+		);
+	}
+
+	#[test]
+	fn branches() {
+		assert_eq!(
+			bounds_of(r#"
+				(module
+					(func (export "select") (param i32) (result i32)
+						get_local 0
+						if (result i32)
+							i32.const 1
+						else
+							i32.const 2
+							i32.const 3
+							i32.add
+						end)
+					(func (export "early") (param i32)
+						get_local 0
+						br_if 0
+						nop
+						nop))
+			"#),
+			vec![
+				("select".into(), 2, Some(6), false),
+				("early".into(), 2, Some(4), false),
+			],
+		);
+	}
+
+	#[test]
+	fn worst_case_paths() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(func $select (param i32) (result i32)
+					get_local 0
+					if (result i32)
+						i32.const 1
+					else
+						i32.const 2
+						i32.const 3
+						i32.add
+					end)
+				(func (param i32)
+					block
+						get_local 0
+						br_if 0
+						i32.const 1
+						call $select
+						drop
+						return
+					end
+					call $ext)
+				(func
+					loop
+					end))
+		"#);
+
+		let bounds = worst_case_gas(&module, &rules::Set::default()).unwrap();
+
+		assert_eq!(bounds, vec![
+			FunctionBound { function: 1, max: Some(5), host_calls: false },
+			// The call of `$select` is on the more expensive path, which returns early.
+			FunctionBound { function: 2, max: Some(3 + 4 + 5), host_calls: true },
+			FunctionBound { function: 3, max: None, host_calls: false },
+		]);
+	}
+
+	#[test]
+	fn calls() {
+		assert_eq!(
+			bounds_of(r#"
+				(module
+					(import "env" "ext" (func $ext))
+					(func $callee
+						nop
+						nop)
+					(func (export "caller") (param i32)
+						call $callee
+						get_local 0
+						if
+							call $ext
+						end)
+					(func $recursive (export "recursive")
+						call $recursive)
+					(func (export "loop")
+						loop
+							nop
+						end))
+			"#),
+			vec![
+				("caller".into(), 5, Some(6), true),
+				("recursive".into(), 1, None, false),
+				("loop".into(), 1, None, false),
+			],
+		);
+	}
+}
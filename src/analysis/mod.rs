@@ -0,0 +1,257 @@
+//! Pre-deployment checks of a module against resource budgets, and static analyses of what it
+//! may do when executed.
+
+mod call_graph;
+pub mod estimate;
+mod gas_bounds;
+#[cfg(feature = "wasm-tools")]
+pub mod raw;
+mod stack;
+mod storage;
+mod whatif;
+
+use crate::std::cmp::Reverse;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, ImportCountType};
+
+use crate::gas::{self, inject_gas_counter};
+use crate::rules;
+
+pub use self::call_graph::{call_graph, CallGraph, FunctionCalls};
+pub use self::gas_bounds::{gas_bounds, worst_case_gas, ExportGas, FunctionBound};
+pub use self::stack::{operand_depths, stack_effect, BlockDepth, DepthError, ModuleContext, OperandDepths};
+pub use self::storage::{storage_access, Access, CallSite, ExportAccess, Key, StorageFunctions};
+pub use self::whatif::{whatif, CostModel, FunctionCost};
+
+/// Number of entries listed by each kind of suggestion.
+const SUGGESTIONS: usize = 3;
+
+/// Limits a module has to fit in to be deployed. Limits which are `None` are not checked.
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+	/// Maximal size of the module after gas metering, in bytes.
+	pub max_size: Option<usize>,
+	/// Maximal total size of the data segments, in bytes.
+	pub max_data_size: Option<usize>,
+	/// Maximal number of functions defined by the module.
+	pub max_functions: Option<usize>,
+	/// Rules used to instrument the module with gas metering.
+	pub rules: rules::Set,
+}
+
+impl Budget {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn with_max_size(mut self, max_size: usize) -> Self {
+		self.max_size = Some(max_size);
+		self
+	}
+
+	pub fn with_max_data_size(mut self, max_data_size: usize) -> Self {
+		self.max_data_size = Some(max_data_size);
+		self
+	}
+
+	pub fn with_max_functions(mut self, max_functions: usize) -> Self {
+		self.max_functions = Some(max_functions);
+		self
+	}
+
+	pub fn with_rules(mut self, rules: rules::Set) -> Self {
+		self.rules = rules;
+		self
+	}
+}
+
+/// A limit of the budget which the module exceeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+	Size { actual: usize, limit: usize },
+	DataSize { actual: usize, limit: usize },
+	Functions { actual: usize, limit: usize },
+}
+
+/// What to look at in order to fit into the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suggestion {
+	/// One of the largest function bodies, by index in the function index space.
+	LargeFunction { index: u32, size: usize },
+	/// One of the largest data segments, by index in the data section.
+	LargeDataSegment { index: usize, size: usize },
+	/// One of the functions growing the most because of gas metering.
+	MeteringOverhead { index: u32, overhead: usize },
+}
+
+/// Result of `size_budget`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeReport {
+	/// Size of the module before gas metering.
+	pub original_size: usize,
+	/// Size of the module after gas metering.
+	pub size: usize,
+	/// Total size of the data segments.
+	pub data_size: usize,
+	/// Number of functions defined by the module.
+	pub functions: usize,
+	/// The limits of the budget exceeded by the module.
+	pub exceeded: Vec<Exceeded>,
+	/// Suggestions for the exceeded limits, largest first.
+	pub suggestions: Vec<Suggestion>,
+}
+
+impl SizeReport {
+	/// Whether the module fits into the budget.
+	pub fn fits(&self) -> bool {
+		self.exceeded.is_empty()
+	}
+}
+
+fn body_sizes(module: &elements::Module) -> Vec<usize> {
+	module.code_section()
+		.map_or(&[][..], |code_section| code_section.bodies())
+		.iter()
+		.map(|body| elements::serialize(body.clone()).map_or(0, |bytes| bytes.len()))
+		.collect()
+}
+
+/// Keep the `SUGGESTIONS` largest entries by `key`.
+fn largest<T, F: Fn(&T) -> usize>(mut entries: Vec<T>, key: F) -> Vec<T> {
+	entries.sort_by_key(|entry| Reverse(key(entry)));
+	entries.truncate(SUGGESTIONS);
+	entries
+}
+
+/// Check the `module` against the `budget` after instrumenting it with gas metering.
+///
+/// If any limit is exceeded, the report lists the largest contributors to it: the largest
+/// functions and the functions with the biggest metering overhead for the module size, and the
+/// largest data segments for the module and data size.
+pub fn size_budget(module: &elements::Module, budget: &Budget) -> Result<SizeReport, gas::Error> {
+	let encoded_size = |module: &elements::Module| {
+		elements::serialize(module.clone()).map_or(0, |bytes| bytes.len())
+	};
+
+	let original_size = encoded_size(module);
+	let original_bodies = body_sizes(module);
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+
+	let metered = inject_gas_counter(module.clone(), &budget.rules, "env").map_err(|(_, e)| e)?;
+	let size = encoded_size(&metered);
+	let metered_bodies = body_sizes(&metered);
+
+	let data_segments = module.data_section()
+		.map_or(&[][..], |data_section| data_section.entries())
+		.iter()
+		.map(|segment| segment.value().len())
+		.collect::<Vec<_>>();
+	let data_size = data_segments.iter().sum();
+	let functions = original_bodies.len();
+
+	let mut exceeded = Vec::new();
+	if let Some(limit) = budget.max_size.filter(|limit| size > *limit) {
+		exceeded.push(Exceeded::Size { actual: size, limit });
+	}
+	if let Some(limit) = budget.max_data_size.filter(|limit| data_size > *limit) {
+		exceeded.push(Exceeded::DataSize { actual: data_size, limit });
+	}
+	if let Some(limit) = budget.max_functions.filter(|limit| functions > *limit) {
+		exceeded.push(Exceeded::Functions { actual: functions, limit });
+	}
+
+	let size_exceeded = exceeded.iter().any(|e| matches!(e, Exceeded::Size { .. }));
+	let data_exceeded = exceeded.iter().any(|e| matches!(e, Exceeded::DataSize { .. }));
+
+	let mut suggestions = Vec::new();
+	if size_exceeded {
+		let function_sizes = original_bodies.iter().enumerate()
+			.map(|(index, size)| Suggestion::LargeFunction { index: imported_funcs + index as u32, size: *size })
+			.collect();
+		suggestions.extend(largest(function_sizes, |s| match s {
+			Suggestion::LargeFunction { size, .. } => *size,
+			_ => 0,
+		}));
+
+		let overheads = original_bodies.iter().zip(metered_bodies.iter()).enumerate()
+			.map(|(index, (original, metered))| Suggestion::MeteringOverhead {
+				index: imported_funcs + index as u32,
+				overhead: metered.saturating_sub(*original),
+			})
+			.collect();
+		suggestions.extend(largest(overheads, |s| match s {
+			Suggestion::MeteringOverhead { overhead, .. } => *overhead,
+			_ => 0,
+		}));
+	}
+	if size_exceeded || data_exceeded {
+		let segments = data_segments.iter().enumerate()
+			.map(|(index, size)| Suggestion::LargeDataSegment { index, size: *size })
+			.collect();
+		suggestions.extend(largest(segments, |s| match s {
+			Suggestion::LargeDataSegment { size, .. } => *size,
+			_ => 0,
+		}));
+	}
+
+	Ok(SizeReport {
+		original_size,
+		size,
+		data_size,
+		functions,
+		exceeded,
+		suggestions,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	const MODULE: &str = r#"
+		(module
+			(memory 1)
+			(data (i32.const 0) "abcd")
+			(data (i32.const 8) "abcdefgh")
+			(func)
+			(func (param i32) (result i32)
+				get_local 0
+				if (result i32)
+					i32.const 1
+				else
+					i32.const 2
+				end))
+	"#;
+
+	#[test]
+	fn fits() {
+		let report = size_budget(
+			&parse_wat(MODULE),
+			&Budget::new().with_max_size(1024).with_max_data_size(12).with_max_functions(2),
+		).unwrap();
+
+		assert!(report.fits());
+		assert!(report.suggestions.is_empty());
+		assert_eq!(report.data_size, 12);
+		assert_eq!(report.functions, 2);
+		assert!(report.size > report.original_size);
+	}
+
+	#[test]
+	fn exceeds() {
+		let report = size_budget(
+			&parse_wat(MODULE),
+			&Budget::new().with_max_size(10).with_max_functions(1),
+		).unwrap();
+
+		assert_eq!(report.exceeded, vec![
+			Exceeded::Size { actual: report.size, limit: 10 },
+			Exceeded::Functions { actual: 2, limit: 1 },
+		]);
+		assert_eq!(report.suggestions[0], Suggestion::LargeFunction { index: 1, size: 13 });
+		assert!(report.suggestions.contains(&Suggestion::LargeDataSegment { index: 1, size: 8 }));
+		assert!(matches!(report.suggestions[2], Suggestion::MeteringOverhead { index: 1, .. }));
+	}
+}
@@ -0,0 +1,279 @@
+//! Analyses reading the binary with wasmparser instead of decoding it into a parity-wasm module.
+//!
+//! The analyses only walk the sections they need and borrow names from the binary, so they are
+//! cheaper than decoding the module when nothing has to be instrumented. The binary is not
+//! validated, only the parts read are checked to be well formed.
+//!
+//! Exported behind the `wasm-tools` feature.
+
+use crate::std::collections::{BTreeMap, BTreeSet};
+use crate::std::vec::Vec;
+
+use wasmparser::{BinaryReaderError, ExternalKind, FuncType, Payload, TypeRef, VisitOperator};
+
+/// Number of occurrences of each instruction in the function bodies, by wasmparser operator name,
+/// e.g. `I32Add`. The `End` of each body is counted as well.
+pub fn opcode_histogram(bytes: &[u8]) -> Result<BTreeMap<&'static str, usize>, BinaryReaderError> {
+	let mut visitor = Visitor::default();
+	visit_bodies(bytes, &mut visitor)?;
+	Ok(visitor.histogram)
+}
+
+/// Proposals beyond the MVP the module uses, e.g. `sign_extension` or `simd`.
+///
+/// The proposals of the instructions are named as in wasmparser. `multi_value` is reported for
+/// function types with more than one result and `mutable_global` for imported or exported
+/// mutable globals.
+pub fn features(bytes: &[u8]) -> Result<BTreeSet<&'static str>, BinaryReaderError> {
+	let mut visitor = Visitor::default();
+	let mut features = BTreeSet::new();
+	let mut globals = Vec::new();
+	for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+		match payload? {
+			Payload::TypeSection(reader) => {
+				for ty in reader.into_iter_err_on_gc_types() {
+					if ty?.results().len() > 1 {
+						features.insert("multi_value");
+					}
+				}
+			}
+			Payload::ImportSection(reader) => {
+				for import in reader {
+					if let TypeRef::Global(ty) = import?.ty {
+						if ty.mutable {
+							features.insert("mutable_global");
+						}
+						globals.push(ty.mutable);
+					}
+				}
+			}
+			Payload::GlobalSection(reader) => {
+				for global in reader {
+					globals.push(global?.ty.mutable);
+				}
+			}
+			Payload::ExportSection(reader) => {
+				for export in reader {
+					let export = export?;
+					if export.kind == ExternalKind::Global && globals.get(export.index as usize) == Some(&true) {
+						features.insert("mutable_global");
+					}
+				}
+			}
+			Payload::CodeSectionEntry(body) => visit_body(&body, &mut visitor)?,
+			_ => {}
+		}
+	}
+	features.extend(visitor.proposals.into_iter().filter(|proposal| *proposal != "mvp"));
+	Ok(features)
+}
+
+/// Imports and exports of a module, with the signatures of the functions among them.
+#[derive(Debug, Clone, Default)]
+pub struct Abi<'a> {
+	pub imports: Vec<Import<'a>>,
+	pub exports: Vec<Export<'a>>,
+}
+
+/// An import, see `Abi`.
+#[derive(Debug, Clone)]
+pub struct Import<'a> {
+	pub module: &'a str,
+	pub name: &'a str,
+	pub ty: TypeRef,
+	/// Signature of an imported function.
+	pub signature: Option<FuncType>,
+}
+
+/// An export, see `Abi`.
+#[derive(Debug, Clone)]
+pub struct Export<'a> {
+	pub name: &'a str,
+	pub kind: ExternalKind,
+	pub index: u32,
+	/// Signature of an exported function.
+	pub signature: Option<FuncType>,
+}
+
+/// The imports and exports of the module. The code section is skipped without being read.
+pub fn abi(bytes: &[u8]) -> Result<Abi<'_>, BinaryReaderError> {
+	let mut abi = Abi::default();
+	let mut types = Vec::new();
+	// Type index of each function of the function space.
+	let mut functions = Vec::new();
+	for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+		match payload? {
+			Payload::TypeSection(reader) => {
+				for ty in reader.into_iter_err_on_gc_types() {
+					types.push(ty?);
+				}
+			}
+			Payload::ImportSection(reader) => {
+				for import in reader {
+					let import = import?;
+					let signature = match import.ty {
+						TypeRef::Func(ty) => {
+							functions.push(ty);
+							types.get(ty as usize).cloned()
+						}
+						_ => None,
+					};
+					abi.imports.push(Import { module: import.module, name: import.name, ty: import.ty, signature });
+				}
+			}
+			Payload::FunctionSection(reader) => {
+				for ty in reader {
+					functions.push(ty?);
+				}
+			}
+			Payload::ExportSection(reader) => {
+				for export in reader {
+					let export = export?;
+					let signature = match export.kind {
+						ExternalKind::Func => functions.get(export.index as usize)
+							.and_then(|ty| types.get(*ty as usize))
+							.cloned(),
+						_ => None,
+					};
+					abi.exports.push(Export { name: export.name, kind: export.kind, index: export.index, signature });
+				}
+			}
+			_ => {}
+		}
+	}
+	Ok(abi)
+}
+
+fn visit_bodies(bytes: &[u8], visitor: &mut Visitor) -> Result<(), BinaryReaderError> {
+	for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+		if let Payload::CodeSectionEntry(body) = payload? {
+			visit_body(&body, visitor)?;
+		}
+	}
+	Ok(())
+}
+
+fn visit_body(body: &wasmparser::FunctionBody, visitor: &mut Visitor) -> Result<(), BinaryReaderError> {
+	let mut reader = body.get_operators_reader()?;
+	while !reader.eof() {
+		reader.visit_operator(visitor)?;
+	}
+	Ok(())
+}
+
+/// Counts the instructions and collects the proposals they belong to.
+#[derive(Default)]
+struct Visitor {
+	histogram: BTreeMap<&'static str, usize>,
+	proposals: BTreeSet<&'static str>,
+}
+
+impl Visitor {
+	fn record(&mut self, name: &'static str, proposal: &'static str) {
+		*self.histogram.entry(name).or_insert(0) += 1;
+		self.proposals.insert(proposal);
+	}
+}
+
+macro_rules! define_visit_operator {
+	($(@$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+		$(
+			fn $visit(&mut self $($(, _: $argty)*)?) {
+				self.record(stringify!($op), stringify!($proposal));
+			}
+		)*
+	};
+}
+
+impl<'a> VisitOperator<'a> for Visitor {
+	type Output = ();
+
+	wasmparser::for_each_operator!(define_visit_operator);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements;
+	use wasmparser::ValType;
+	use crate::testing::wat2wasm;
+
+	const SOURCE: &str = r#"
+		(module
+			(import "env" "gas" (func $gas (param i32)))
+			(import "env" "counter" (global $counter (mut i32)))
+			(memory (export "memory") 1)
+			(global $limit (mut i32) (i32.const 10))
+			(export "limit" (global $limit))
+			(func $split (export "split") (param i64) (result i32 i32)
+				(i32.wrap_i64 (local.get 0))
+				(i32.wrap_i64 (i64.shr_u (local.get 0) (i64.const 32))))
+			(func (export "sum") (param i64) (result i32)
+				(call $gas (i32.const 1))
+				(i32.add (call $split (local.get 0)))
+				(i32.extend8_s)))
+	"#;
+
+	#[test]
+	fn histogram() {
+		let histogram = opcode_histogram(&wat2wasm(SOURCE)).unwrap();
+		assert_eq!(histogram["LocalGet"], 3);
+		assert_eq!(histogram["I32WrapI64"], 2);
+		assert_eq!(histogram["End"], 2);
+		assert_eq!(histogram["I32Extend8S"], 1);
+
+		// The same instructions as decoded by parity-wasm.
+		let bytes = wat2wasm(r#"
+			(module
+				(memory 1)
+				(func (export "sum") (param i32) (result i32)
+					(local i32)
+					(block
+						(loop
+							(br_if 1 (i32.eqz (local.get 0)))
+							(local.set 1 (i32.add (local.get 1) (i32.load (local.get 0))))
+							(local.set 0 (i32.sub (local.get 0) (i32.const 4)))
+							(br 0)))
+					(local.get 1)))
+		"#);
+		let module: elements::Module = elements::deserialize_buffer(&bytes).unwrap();
+		let instructions: usize = module.code_section().unwrap().bodies()
+			.iter()
+			.map(|body| body.code().elements().len())
+			.sum();
+		assert_eq!(opcode_histogram(&bytes).unwrap().values().sum::<usize>(), instructions);
+	}
+
+	#[test]
+	fn detected_features() {
+		let features = features(&wat2wasm(SOURCE)).unwrap();
+		assert_eq!(
+			features.into_iter().collect::<Vec<_>>(),
+			vec!["multi_value", "mutable_global", "sign_extension"],
+		);
+
+		let mvp = wat2wasm(r#"(module (func (export "f") (result i32) (i32.const 1)))"#);
+		assert!(super::features(&mvp).unwrap().is_empty());
+	}
+
+	#[test]
+	fn imports_and_exports() {
+		let bytes = wat2wasm(SOURCE);
+		let abi = abi(&bytes).unwrap();
+
+		let imports: Vec<_> = abi.imports.iter().map(|import| (import.module, import.name)).collect();
+		assert_eq!(imports, vec![("env", "gas"), ("env", "counter")]);
+		assert_eq!(abi.imports[0].signature.as_ref().unwrap().params(), &[ValType::I32]);
+		assert!(abi.imports[1].signature.is_none());
+
+		let exports: Vec<_> = abi.exports.iter().map(|export| (export.name, export.kind, export.index)).collect();
+		assert_eq!(exports, vec![
+			("memory", ExternalKind::Memory, 0),
+			("limit", ExternalKind::Global, 1),
+			("split", ExternalKind::Func, 1),
+			("sum", ExternalKind::Func, 2),
+		]);
+		let split = abi.exports[2].signature.as_ref().unwrap();
+		assert_eq!((split.params(), split.results()), (&[ValType::I64][..], &[ValType::I32, ValType::I32][..]));
+	}
+}
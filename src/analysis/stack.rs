@@ -0,0 +1,345 @@
+//! Stack effects of instructions and depths of the operand stack, for quick static checks of
+//! function bodies without validating the whole module.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, BlockType, Instruction};
+
+use crate::rules::StackEffect;
+use crate::visit::{self, visit, Frame, FrameKind, Frames, Visitor};
+
+/// What the stack effects of the instructions of a module depend on.
+#[derive(Debug)]
+pub struct ModuleContext<'a> {
+	module: &'a elements::Module,
+	/// Number of parameters and results of every function, by function index.
+	arities: Vec<(usize, usize)>,
+}
+
+impl<'a> ModuleContext<'a> {
+	pub fn new(module: &'a elements::Module) -> Self {
+		ModuleContext { module, arities: visit::function_arities(module) }
+	}
+}
+
+/// Number of values popped and pushed by `instruction`.
+///
+/// `None` is returned for instructions changing the control flow, whose effect depends on the
+/// enclosing blocks, and for calls of functions or types the module doesn't declare.
+pub fn stack_effect(instruction: &Instruction, ctx: &ModuleContext) -> Option<StackEffect> {
+	visit::stack_effect(instruction, ctx.module, &ctx.arities)
+		.map(|(pops, pushes)| StackEffect { pops: pops as u32, pushes: pushes as u32 })
+}
+
+/// Maximal depth of the operand stack within a `block`, `loop` or `if`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockDepth {
+	/// Position of the instruction opening the block.
+	pub start_pos: usize,
+	/// Maximal number of operands pushed within the block, including its nested blocks.
+	pub max_depth: u32,
+}
+
+/// Result of `operand_depths`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperandDepths {
+	/// Maximal number of operands on the stack during the execution of the function body.
+	pub function: u32,
+	/// The blocks of the function body, by position.
+	pub blocks: Vec<BlockDepth>,
+}
+
+/// Reason why the operand depths of a function body couldn't be determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthError {
+	/// The module doesn't define a function with the given index.
+	NoSuchFunction(u32),
+	/// The control blocks of the body are not properly nested.
+	MalformedBody,
+	/// The instruction at the given position takes more operands than its block has.
+	Underflow(usize),
+	/// The block ending at the given position leaves other values than its results.
+	Leftover(usize),
+	/// The instruction at the given position refers to a function, type or label which doesn't
+	/// exist.
+	UnknownReference(usize),
+}
+
+/// A block being walked by `DepthVisitor`.
+struct BlockState {
+	/// Height of the operand stack when the block was entered.
+	entry: u32,
+	/// Maximal height of the operand stack within the block.
+	max: u32,
+	/// Number of values left by the block.
+	results: u32,
+	/// Number of values taken by branches to the block.
+	label_arity: u32,
+	/// Whether the rest of the block is unreachable, in which case the stack is polymorphic.
+	unreachable: bool,
+}
+
+impl BlockState {
+	fn new(entry: u32, results: u32, is_loop: bool) -> Self {
+		BlockState {
+			entry,
+			max: entry,
+			results,
+			label_arity: if is_loop { 0 } else { results },
+			unreachable: false,
+		}
+	}
+}
+
+struct DepthVisitor<'a, 'b> {
+	ctx: &'a ModuleContext<'b>,
+	height: u32,
+	/// The open blocks, in the same order as the frames.
+	blocks: Vec<BlockState>,
+	/// The closed blocks other than the function block.
+	depths: Vec<BlockDepth>,
+	function: u32,
+}
+
+impl<'a, 'b> DepthVisitor<'a, 'b> {
+	fn top(&mut self) -> &mut BlockState {
+		self.blocks.last_mut().expect("the function block is open while instructions are visited; qed")
+	}
+
+	fn pop(&mut self, pos: usize, count: u32) -> Result<(), DepthError> {
+		let height = self.height;
+		let block = self.top();
+		let available = height - block.entry;
+		if count > available && !block.unreachable {
+			return Err(DepthError::Underflow(pos));
+		}
+		self.height -= count.min(available);
+		Ok(())
+	}
+
+	fn push(&mut self, count: u32) {
+		self.height += count;
+		let height = self.height;
+		let block = self.top();
+		block.max = block.max.max(height);
+	}
+
+	/// Mark the rest of the current block as unreachable.
+	fn diverge(&mut self) {
+		let block = self.top();
+		block.unreachable = true;
+		let entry = block.entry;
+		self.height = entry;
+	}
+
+	/// Pop the results of the current block, which must be the only values it left.
+	fn pop_results(&mut self, pos: usize) -> Result<(), DepthError> {
+		let results = self.top().results;
+		self.pop(pos, results)?;
+		let height = self.height;
+		let block = self.top();
+		if !block.unreachable && height != block.entry {
+			return Err(DepthError::Leftover(pos));
+		}
+		Ok(())
+	}
+
+	fn label_arity(&self, pos: usize, label: u32, frames: &Frames) -> Result<u32, DepthError> {
+		frames.target_index(label)
+			.and_then(|index| self.blocks.get(index))
+			.map(|block| block.label_arity)
+			.ok_or(DepthError::UnknownReference(pos))
+	}
+}
+
+fn block_results(instruction: &Instruction) -> u32 {
+	match instruction {
+		Instruction::Block(BlockType::Value(_))
+		| Instruction::Loop(BlockType::Value(_))
+		| Instruction::If(BlockType::Value(_)) => 1,
+		_ => 0,
+	}
+}
+
+impl<'a, 'b> Visitor for DepthVisitor<'a, 'b> {
+	type Error = DepthError;
+
+	fn enter_block(&mut self, pos: usize, instruction: &Instruction, _frames: &Frames) -> Result<(), DepthError> {
+		if let Instruction::If(_) = instruction {
+			self.pop(pos, 1)?;
+		}
+		let is_loop = matches!(instruction, Instruction::Loop(_));
+		self.blocks.push(BlockState::new(self.height, block_results(instruction), is_loop));
+		Ok(())
+	}
+
+	fn visit_else(&mut self, pos: usize, _frames: &Frames) -> Result<(), DepthError> {
+		self.pop_results(pos)?;
+		let block = self.top();
+		block.unreachable = false;
+		let entry = block.entry;
+		self.height = entry;
+		Ok(())
+	}
+
+	fn leave_block(&mut self, pos: usize, frame: &Frame, _frames: &Frames) -> Result<(), DepthError> {
+		self.pop_results(pos)?;
+		let block = self.blocks.pop().expect("blocks are pushed along with the frames; qed");
+		let max_depth = block.max - block.entry;
+		if frame.kind == FrameKind::Function {
+			self.function = max_depth;
+			return Ok(());
+		}
+
+		self.height = block.entry;
+		self.push(block.results);
+		let parent = self.top();
+		parent.max = parent.max.max(block.max);
+		self.depths.push(BlockDepth { start_pos: frame.start_pos, max_depth });
+		Ok(())
+	}
+
+	fn visit_instruction(&mut self, pos: usize, instruction: &Instruction, frames: &Frames) -> Result<(), DepthError> {
+		match instruction {
+			Instruction::Unreachable => self.diverge(),
+			Instruction::Return => {
+				let results = self.blocks[0].results;
+				self.pop(pos, results)?;
+				self.diverge();
+			}
+			Instruction::Br(label) => {
+				let arity = self.label_arity(pos, *label, frames)?;
+				self.pop(pos, arity)?;
+				self.diverge();
+			}
+			Instruction::BrIf(label) => {
+				let arity = self.label_arity(pos, *label, frames)?;
+				self.pop(pos, 1)?;
+				self.pop(pos, arity)?;
+				self.push(arity);
+			}
+			Instruction::BrTable(table) => {
+				let arity = self.label_arity(pos, table.default, frames)?;
+				for label in table.table.iter() {
+					self.label_arity(pos, *label, frames)?;
+				}
+				self.pop(pos, 1)?;
+				self.pop(pos, arity)?;
+				self.diverge();
+			}
+			_ => {
+				let effect = stack_effect(instruction, self.ctx).ok_or(DepthError::UnknownReference(pos))?;
+				self.pop(pos, effect.pops)?;
+				self.push(effect.pushes);
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Determine the maximal depths of the operand stack within the body of the function with the
+/// given index and within each of its blocks.
+///
+/// Depths are counted from the entry of the function or block, so that values pushed before
+/// aren't included. This doesn't validate the body otherwise, e.g. operand types are ignored.
+pub fn operand_depths(ctx: &ModuleContext, func_index: u32) -> Result<OperandDepths, DepthError> {
+	let imported_funcs = ctx.module.import_count(elements::ImportCountType::Function) as u32;
+	let body = func_index.checked_sub(imported_funcs)
+		.and_then(|index| ctx.module.code_section()?.bodies().get(index as usize))
+		.ok_or(DepthError::NoSuchFunction(func_index))?;
+	let results = ctx.arities.get(func_index as usize)
+		.ok_or(DepthError::NoSuchFunction(func_index))?
+		.1;
+
+	let mut visitor = DepthVisitor {
+		ctx,
+		height: 0,
+		blocks: vec![BlockState::new(0, results as u32, false)],
+		depths: Vec::new(),
+		function: 0,
+	};
+	visit(body.code().elements(), &mut visitor).map_err(|e| match e {
+		visit::Error::Visitor(e) => e,
+		_ => DepthError::MalformedBody,
+	})?;
+
+	let mut blocks = visitor.depths;
+	blocks.sort_unstable_by_key(|block| block.start_pos);
+	Ok(OperandDepths { function: visitor.function, blocks })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn effects() {
+		let module = parse_wat(r#"
+			(module
+				(type (func (param i32 i64) (result i32)))
+				(func (param i32 i32 i32))
+				(table 1 funcref))
+		"#);
+		let ctx = ModuleContext::new(&module);
+		let effect = |instruction| stack_effect(&instruction, &ctx).map(|e| (e.pops, e.pushes));
+
+		assert_eq!(effect(Instruction::Select), Some((3, 1)));
+		assert_eq!(effect(Instruction::Call(0)), Some((3, 0)));
+		assert_eq!(effect(Instruction::CallIndirect(0, 0)), Some((3, 1)));
+		assert_eq!(effect(Instruction::Call(1)), None);
+		assert_eq!(effect(Instruction::Br(0)), None);
+	}
+
+	#[test]
+	fn depths() {
+		let module = parse_wat(r#"
+			(module
+				(func (param i32) (result i32)
+					i32.const 1
+					block (result i32)
+						i32.const 2
+						i32.const 3
+						i32.const 4
+						local.get 0
+						br_if 0
+						drop
+						i32.add
+					end
+					local.get 0
+					if (result i32)
+						i32.const 5
+						return
+					else
+						i32.const 6
+						i32.const 7
+						i32.mul
+					end
+					i32.add
+					i32.add))
+		"#);
+		let depths = operand_depths(&ModuleContext::new(&module), 0).unwrap();
+
+		assert_eq!(depths.function, 5);
+		assert_eq!(depths.blocks, vec![
+			BlockDepth { start_pos: 1, max_depth: 4 },
+			BlockDepth { start_pos: 11, max_depth: 2 },
+		]);
+	}
+
+	#[test]
+	fn errors() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "f" (func))
+				(func (result i32)
+					i32.const 1
+					i32.const 2))
+		"#);
+		let ctx = ModuleContext::new(&module);
+
+		assert_eq!(operand_depths(&ctx, 0), Err(DepthError::NoSuchFunction(0)));
+		assert_eq!(operand_depths(&ctx, 1), Err(DepthError::Leftover(2)));
+		assert_eq!(operand_depths(&ctx, 2), Err(DepthError::NoSuchFunction(2)));
+	}
+}
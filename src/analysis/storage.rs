@@ -0,0 +1,289 @@
+//! Static analysis of the calls to host functions accessing the storage.
+
+use crate::std::collections::BTreeSet;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Instruction};
+
+use crate::analysis::call_graph;
+use crate::visit::{function_arities, stack_effect};
+
+/// Names of the imported functions which access the storage.
+#[derive(Debug, Clone, Default)]
+pub struct StorageFunctions {
+	/// Field names of the functions reading from the storage.
+	pub reads: Vec<String>,
+	/// Field names of the functions writing to the storage.
+	pub writes: Vec<String>,
+	/// Index of the parameter holding the key, or a pointer to it, in all these functions.
+	pub key_param: u32,
+}
+
+impl StorageFunctions {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn with_read(mut self, field: &str) -> Self {
+		self.reads.push(field.into());
+		self
+	}
+
+	pub fn with_write(mut self, field: &str) -> Self {
+		self.writes.push(field.into());
+		self
+	}
+
+	pub fn with_key_param(mut self, key_param: u32) -> Self {
+		self.key_param = key_param;
+		self
+	}
+
+	fn access(&self, field: &str) -> Option<Access> {
+		if self.reads.iter().any(|read| read == field) {
+			Some(Access::Read)
+		} else if self.writes.iter().any(|write| write == field) {
+			Some(Access::Write)
+		} else {
+			None
+		}
+	}
+}
+
+/// Kind of a storage access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+	Read,
+	Write,
+}
+
+/// The key argument of a storage call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+	/// The argument is a constant, e.g. the address of a key in a data segment.
+	Constant(i64),
+	/// The argument is computed at runtime.
+	Dynamic,
+}
+
+/// A call to a storage function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+	/// Index of the calling function in the function index space.
+	pub function: u32,
+	/// Position of the call in the body of the calling function.
+	pub offset: usize,
+	/// Field name of the called storage function.
+	pub field: String,
+	pub access: Access,
+	pub key: Key,
+}
+
+/// The storage calls which an exported function may execute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportAccess {
+	/// Name of the export.
+	pub name: String,
+	/// Index of the exported function in the function index space.
+	pub function: u32,
+	/// Storage calls in the exported function and all functions it calls directly or transitively.
+	/// Each call site is listed once, no matter how often it may be executed.
+	pub call_sites: Vec<CallSite>,
+	/// Whether an indirect call may be executed, in which case calls to storage functions may
+	/// happen which are not listed.
+	pub indirect_calls: bool,
+}
+
+impl ExportAccess {
+	/// Number of call sites reading from the storage.
+	pub fn reads(&self) -> usize {
+		self.call_sites.iter().filter(|call_site| call_site.access == Access::Read).count()
+	}
+
+	/// Number of call sites writing to the storage.
+	pub fn writes(&self) -> usize {
+		self.call_sites.iter().filter(|call_site| call_site.access == Access::Write).count()
+	}
+
+	/// Number of call sites with a constant key argument.
+	pub fn static_keys(&self) -> usize {
+		self.call_sites.iter().filter(|call_site| call_site.key != Key::Dynamic).count()
+	}
+}
+
+/// Find the key argument of the call at `pos`, which takes `params` arguments.
+///
+/// The instructions before the call are walked back until the one pushing the argument. This only
+/// succeeds within straight-line code, otherwise the key is considered dynamic.
+fn key_argument(
+	code: &[Instruction],
+	pos: usize,
+	params: usize,
+	key_param: usize,
+	module: &elements::Module,
+	arities: &[(usize, usize)],
+) -> Key {
+	if key_param >= params {
+		return Key::Dynamic
+	}
+
+	// Number of values on the stack above the key argument.
+	let mut depth = params - key_param - 1;
+	for (pos, instruction) in code[..pos].iter().enumerate().rev() {
+		if crate::visit::is_copy_padding(code, pos) {
+			continue;
+		}
+
+		let (pops, pushes) = match stack_effect(instruction, module, arities) {
+			Some(effect) => effect,
+			None => return Key::Dynamic,
+		};
+		if depth < pushes {
+			return match *instruction {
+				Instruction::I32Const(value) => Key::Constant(value.into()),
+				Instruction::I64Const(value) => Key::Constant(value),
+				_ => Key::Dynamic,
+			}
+		}
+		depth = depth - pushes + pops;
+	}
+	Key::Dynamic
+}
+
+/// Find the calls to storage `functions` which each exported function may execute.
+pub fn storage_access(module: &elements::Module, functions: &StorageFunctions) -> Vec<ExportAccess> {
+	let arities = function_arities(module);
+	let imported_funcs = module.import_count(elements::ImportCountType::Function);
+
+	let storage_imports = module.import_section()
+		.map_or(&[][..], |import_section| import_section.entries())
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+		.map(|entry| functions.access(entry.field()).map(|access| (entry.field(), access)))
+		.collect::<Vec<_>>();
+
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+
+	// Storage calls, by defined function.
+	let call_sites = bodies.iter().enumerate()
+		.map(|(index, body)| {
+			let code = body.code().elements();
+			let mut call_sites = Vec::new();
+			for (pos, instruction) in code.iter().enumerate() {
+				if let Instruction::Call(func) = *instruction {
+					if let Some(Some((field, access))) = storage_imports.get(func as usize) {
+						let params = arities[func as usize].0;
+						let key_param = functions.key_param as usize;
+						call_sites.push(CallSite {
+							function: (imported_funcs + index) as u32,
+							offset: pos,
+							field: String::from(*field),
+							access: *access,
+							key: key_argument(code, pos, params, key_param, module, &arities),
+						});
+					}
+				}
+			}
+			call_sites
+		})
+		.collect::<Vec<_>>();
+	let graph = call_graph(module);
+
+	module.export_section()
+		.map_or(&[][..], |export_section| export_section.entries())
+		.iter()
+		.filter_map(|entry| match *entry.internal() {
+			elements::Internal::Function(func) => Some((entry.field(), func)),
+			_ => None,
+		})
+		.map(|(name, func)| {
+			let mut access = ExportAccess {
+				name: name.into(),
+				function: func,
+				call_sites: Vec::new(),
+				indirect_calls: false,
+			};
+
+			// Visit all functions reachable from the export by direct calls.
+			let mut visited = BTreeSet::new();
+			let mut pending = vec![func];
+			while let Some(func) = pending.pop() {
+				if !visited.insert(func) {
+					continue;
+				}
+				if let Some(calls) = graph.functions.get(func as usize) {
+					access.indirect_calls |= !calls.indirect.is_empty();
+					pending.extend(calls.direct.iter().cloned());
+				}
+				if let Some(call_sites) = (func as usize).checked_sub(imported_funcs).and_then(|index| call_sites.get(index)) {
+					access.call_sites.extend(call_sites.iter().cloned());
+				}
+			}
+
+			access.call_sites.sort_by_key(|call_site| (call_site.function, call_site.offset));
+			access
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn counts_storage_calls() {
+		let module = parse_wat(r#"
+			(module
+				(type $void (func))
+				(import "env" "storage_read" (func (param i64 i64 i64) (result i64)))
+				(import "env" "storage_write" (func (param i64 i64 i64 i64 i64) (result i64)))
+				(memory 1)
+				(table 1 anyfunc)
+				(func $get (export "get") (param i64)
+					i64.const 4
+					i64.const 1024
+					i64.const 0
+					call 0
+					drop)
+				(func (export "set") (param i64)
+					call $get
+					i64.const 4
+					get_local 0
+					i64.const 8
+					i64.const 2048
+					i64.const 0
+					call 1
+					drop)
+				(func (export "dynamic") (param i64)
+					i64.const 4
+					get_local 0
+					i64.const 1
+					i64.add
+					i64.const 0
+					call 0
+					drop
+					i32.const 0
+					call_indirect (type $void)))
+		"#);
+		let functions = StorageFunctions::new()
+			.with_read("storage_read")
+			.with_write("storage_write")
+			.with_key_param(1);
+
+		let access = storage_access(&module, &functions);
+
+		assert_eq!(access.len(), 3);
+		assert_eq!(access[0].name, "get");
+		assert_eq!((access[0].reads(), access[0].writes(), access[0].static_keys()), (1, 0, 1));
+		assert_eq!(access[0].call_sites[0].key, Key::Constant(1024));
+		assert!(!access[0].indirect_calls);
+
+		assert_eq!((access[1].reads(), access[1].writes(), access[1].static_keys()), (1, 1, 1));
+		assert_eq!(access[1].call_sites[1].key, Key::Dynamic);
+
+		assert_eq!((access[2].reads(), access[2].writes(), access[2].static_keys()), (1, 0, 0));
+		assert!(access[2].indirect_calls);
+	}
+}
@@ -0,0 +1,134 @@
+//! Static costs of the functions of a module under changed instruction costs.
+
+use crate::std::collections::BTreeMap;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, ImportCountType};
+
+use crate::gas::{self, ClassCost, ClassCosts};
+use crate::rules::{InstructionType, Rules};
+
+/// Static cost of a function, the sum of the costs of its metered blocks, i.e. the gas charged if
+/// each of them runs once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionCost {
+	/// Index of the function in the function index space.
+	pub function: u32,
+	/// Static cost under the base rules.
+	pub base: u64,
+	/// Static cost with the overrides applied to the base rules.
+	pub cost: u64,
+}
+
+/// The static costs of the functions of a module under some base rules along with the costs of
+/// their instructions by class, from which the static costs under other instruction costs follow
+/// without determining the metered blocks again.
+#[derive(Debug, Clone)]
+pub struct CostModel {
+	/// The base cost and the costs by class of each defined function.
+	functions: Vec<(u32, u64, ClassCosts)>,
+}
+
+impl CostModel {
+	/// Determine the metered blocks of the `module` with the `base_rules` and gather the costs of
+	/// their instructions.
+	pub fn new<R: Rules + ?Sized>(module: &elements::Module, base_rules: &R) -> Result<Self, gas::Error> {
+		let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+		let functions = gas::plan_class_costs(module, base_rules)?
+			.into_iter()
+			.enumerate()
+			.map(|(index, (blocks, class_costs))| {
+				let base = blocks.iter().fold(0u64, |base, block| base.saturating_add(block.cost));
+				(imported_funcs + index as u32, base, class_costs)
+			})
+			.collect();
+		Ok(CostModel { functions })
+	}
+
+	/// The static costs of the defined functions with the cost of each instruction of the classes
+	/// in `overrides` replaced by the given one, in the order of the code section.
+	///
+	/// The surcharges of calls, the costs of the locals and the adjustments of `Rules::block_cost`
+	/// stay those of the base rules, as does the loop multiplier, which applies to the overridden
+	/// costs as well. The last override of a class counts. Costs beyond `u64::MAX` saturate.
+	pub fn costs(&self, overrides: &[(InstructionType, u32)]) -> Vec<FunctionCost> {
+		let overrides = overrides.iter().cloned().collect::<BTreeMap<_, _>>();
+		self.functions.iter()
+			.map(|(function, base, class_costs)| {
+				let cost = overrides.iter().fold(*base, |cost, (class, class_cost)| {
+					match class_costs.get(class) {
+						Some(ClassCost { count, cost: base_cost }) => cost
+							.saturating_sub(*base_cost)
+							.saturating_add(count.saturating_mul(u64::from(*class_cost))),
+						None => cost,
+					}
+				});
+				FunctionCost { function: *function, base: *base, cost }
+			})
+			.collect()
+	}
+}
+
+/// Compute the static costs of the functions defined by the `module` under the `base_rules` with
+/// the cost of each instruction of the classes in `overrides` replaced by the given one.
+///
+/// Exploring many overrides is faster by building a `CostModel` once, which is what this does
+/// for a single one.
+pub fn whatif<R: Rules + ?Sized>(
+	module: &elements::Module,
+	base_rules: &R,
+	overrides: &[(InstructionType, u32)],
+) -> Result<Vec<FunctionCost>, gas::Error> {
+	Ok(CostModel::new(module, base_rules)?.costs(overrides))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn overrides_match_metering() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext (param i32)))
+				(memory 1)
+				(func (export "spin") (param i32)
+					loop
+						get_local 0
+						i32.load
+						call $ext
+						get_local 0
+						br_if 0
+					end)
+				(func (param i32) (result i32)
+					get_local 0
+					i32.const 3
+					i32.div_u))
+		"#);
+		let base_rules = rules::Set::default()
+			.with_call_cost(5)
+			.with_loop_multiplier("spin", 2)
+			.with_instruction_cost(rules::InstructionType::Div, 10);
+		let overrides = [(rules::InstructionType::Load, 7), (rules::InstructionType::Div, 0)];
+
+		let costs = whatif(&module, &base_rules, &overrides).unwrap();
+
+		let overridden_rules = base_rules.clone()
+			.with_instruction_cost(rules::InstructionType::Load, 7)
+			.with_instruction_cost(rules::InstructionType::Div, 0);
+		let static_costs = |rules: &rules::Set| {
+			gas::plan_metering(&module, rules, &gas::GasConfig::default(), &|_| true)
+				.unwrap()
+				.iter()
+				.map(|blocks| blocks.iter().map(|block| block.cost).sum::<u64>())
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(costs.iter().map(|cost| cost.base).collect::<Vec<_>>(), static_costs(&base_rules));
+		assert_eq!(costs.iter().map(|cost| cost.cost).collect::<Vec<_>>(), static_costs(&overridden_rules));
+		// The loop itself is charged outside of it, the calls with their surcharge inside.
+		assert_eq!(costs[0], FunctionCost { function: 1, base: 1 + 2 * 10, cost: 1 + 2 * 16 });
+		assert_eq!(costs[1], FunctionCost { function: 2, base: 12, cost: 2 });
+	}
+}
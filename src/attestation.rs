@@ -0,0 +1,543 @@
+//! Verification of artifacts instrumented by third parties before accepting them.
+//!
+//! A chain accepting code which was instrumented elsewhere can't trust the instrumentation. It can
+//! run `verify_artifact` instead of instrumenting the code itself, which checks that the artifact
+//! is instrumented at least as strictly as the `Profile` requires:
+//!
+//! - every metered block charges at least its cost under the rules of the profile, which is
+//!   determined from the function body with the charges removed,
+//! - every call of a function with a stack cost is wrapped with the stack height checks of the
+//!   stack limiter, with at least its stack cost and at most the stack limit of the profile, and
+//!   functions called from outside of the module are reached through thunks,
+//! - the module stays within the limits and imports only what is allowed,
+//! - the custom sections are stripped.
+//!
+//! The checks recognize the instrumentation of this crate. Artifacts charging more than needed,
+//! e.g. instrumented with more expensive rules, pass as well.
+
+use std::fmt;
+
+use parity_wasm::elements::{self, BlockType, Instruction, ValueType};
+
+use crate::build_support::Profile;
+use crate::gas::{self, GasConfig};
+use crate::rules::Rules;
+use crate::stack_height;
+use crate::version;
+
+/// Limits an artifact has to stay within. Limits which are `None` are not checked.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+	/// Maximal size of the artifact, in bytes.
+	pub max_size: Option<usize>,
+	/// Maximal number of functions defined by the artifact.
+	pub max_functions: Option<usize>,
+	/// Maximal size of the memory, in pages. The memory has to declare a maximum size then.
+	pub max_memory_pages: Option<u32>,
+}
+
+impl Limits {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn with_max_size(mut self, max_size: usize) -> Self {
+		self.max_size = Some(max_size);
+		self
+	}
+
+	pub fn with_max_functions(mut self, max_functions: usize) -> Self {
+		self.max_functions = Some(max_functions);
+		self
+	}
+
+	pub fn with_max_memory_pages(mut self, max_memory_pages: u32) -> Self {
+		self.max_memory_pages = Some(max_memory_pages);
+		self
+	}
+}
+
+/// A check an artifact failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+	/// The check which failed: `decoding`, `limits`, `imports`, `custom sections`, `gas` or
+	/// `stack`.
+	pub check: &'static str,
+	pub message: String,
+}
+
+/// The result of all checks of an artifact.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attestation {
+	/// The checks which were run, in order.
+	pub checks: Vec<&'static str>,
+	pub violations: Vec<Violation>,
+}
+
+impl Attestation {
+	/// Whether the artifact passed all checks.
+	pub fn passed(&self) -> bool {
+		self.violations.is_empty()
+	}
+
+	fn violation(&mut self, check: &'static str, message: String) {
+		self.violations.push(Violation { check, message });
+	}
+}
+
+impl fmt::Display for Attestation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{} ({})", if self.passed() { "ok" } else { "FAILED" }, self.checks.join(", "))?;
+		for violation in &self.violations {
+			writeln!(f, "  [{}] {}", violation.check, violation.message)?;
+		}
+		Ok(())
+	}
+}
+
+/// Check that the artifact `bytes` is instrumented according to the `profile` and stays within
+/// the allowed imports and the limits of the profile.
+///
+/// See the module-level documentation for what is checked.
+pub fn verify_artifact(bytes: &[u8], profile: &Profile) -> Attestation {
+	let mut attestation = Attestation::default();
+	attestation.checks.push("decoding");
+	let module = match version::deserialize_buffer(bytes) {
+		Ok(module) => module,
+		Err(e) => {
+			attestation.violation("decoding", format!("{:?}", e));
+			return attestation;
+		},
+	};
+
+	attestation.checks.push("limits");
+	check_limits(&module, bytes.len(), &profile.limits, &mut attestation);
+	if let Some(allowed_imports) = &profile.allowed_imports {
+		attestation.checks.push("imports");
+		check_imports(&module, allowed_imports, profile, &mut attestation);
+	}
+	if let Some(keep_names) = profile.strip {
+		attestation.checks.push("custom sections");
+		check_custom_sections(&module, keep_names, &mut attestation);
+	}
+
+	// The stack height checks were added after the charges, so they are removed first.
+	let (module, thunks) = match profile.stack_limit {
+		Some(stack_limit) => {
+			attestation.checks.push("stack");
+			verify_stack(module, stack_limit, &mut attestation)
+		},
+		None => (module, Vec::new()),
+	};
+	if let Some(rules) = &profile.gas {
+		attestation.checks.push("gas");
+		verify_gas(module, rules, profile, &thunks, &mut attestation);
+	}
+	attestation
+}
+
+fn check_limit(attestation: &mut Attestation, what: &str, value: usize, limit: Option<usize>) {
+	if let Some(limit) = limit {
+		if value > limit {
+			attestation.violation("limits", format!("{} is {}, the limit is {}", what, value, limit));
+		}
+	}
+}
+
+fn check_limits(module: &elements::Module, size: usize, limits: &Limits, attestation: &mut Attestation) {
+	check_limit(attestation, "artifact size", size, limits.max_size);
+	let functions = module.function_section().map_or(0, |section| section.entries().len());
+	check_limit(attestation, "number of functions", functions, limits.max_functions);
+
+	let imported_memories = module.import_section()
+		.map_or(&[][..], |section| section.entries())
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Memory(memory) => Some(memory),
+			_ => None,
+		});
+	let memories = module.memory_section().map_or(&[][..], |section| section.entries());
+	for memory in imported_memories.chain(memories) {
+		let limits_entry = memory.limits();
+		if limits.max_memory_pages.is_some() && limits_entry.maximum().is_none() {
+			attestation.violation("limits", "memory does not declare a maximum size".into());
+		}
+		let pages = limits_entry.maximum().unwrap_or_else(|| limits_entry.initial());
+		check_limit(attestation, "memory size in pages", pages as usize, limits.max_memory_pages.map(|pages| pages as usize));
+	}
+}
+
+fn check_imports(
+	module: &elements::Module,
+	allowed_imports: &[(String, String)],
+	profile: &Profile,
+	attestation: &mut Attestation,
+) {
+	let entries = module.import_section().map_or(&[][..], |section| section.entries());
+	for entry in entries {
+		let is_gas = profile.gas.is_some() && entry.module() == profile.gas_module && entry.field() == profile.gas_field;
+		let allowed = allowed_imports.iter().any(|(module, field)| entry.module() == module && entry.field() == field);
+		if !is_gas && !allowed {
+			attestation.violation("imports", format!("'{}::{}' is not allowed", entry.module(), entry.field()));
+		}
+	}
+}
+
+fn check_custom_sections(module: &elements::Module, keep_names: bool, attestation: &mut Attestation) {
+	for section in module.sections() {
+		let name = match section {
+			elements::Section::Custom(custom) if !(keep_names && custom.name() == "name") => custom.name(),
+			elements::Section::Name(_) if !keep_names => "name",
+			elements::Section::Reloc(_) => "reloc",
+			_ => continue,
+		};
+		attestation.violation("custom sections", format!("custom section '{}' is not stripped", name));
+	}
+}
+
+/// Number of instructions of a call wrapped by the stack height limiter.
+const LIMITED_CALL_LEN: usize = 15;
+
+/// A call wrapped with the stack height checks.
+struct LimitedCall {
+	callee: u32,
+	cost: i32,
+	limit: i32,
+	global: u32,
+}
+
+/// The call wrapped with the stack height checks starting at `pos`, as the stack height limiter
+/// instruments it when trapping with `unreachable`.
+fn limited_call(instructions: &[Instruction], pos: usize) -> Option<LimitedCall> {
+	use parity_wasm::elements::Instruction::*;
+
+	match instructions.get(pos..pos + LIMITED_CALL_LEN)? {
+		[
+			GetGlobal(global), I32Const(cost), I32Add, SetGlobal(global_1),
+			GetGlobal(global_2), I32Const(limit), I32GtU, If(BlockType::NoResult), Unreachable, End,
+			Call(callee),
+			GetGlobal(global_3), I32Const(cost_1), I32Sub, SetGlobal(global_4),
+		] if [global_1, global_2, global_3, global_4].iter().all(|other| *other == global) && cost == cost_1 => {
+			Some(LimitedCall { callee: *callee, cost: *cost, limit: *limit, global: *global })
+		},
+		_ => None,
+	}
+}
+
+/// Whether the body of the function is a thunk of the stack height limiter with `params`
+/// parameters: it passes them on to a limited call.
+fn is_thunk(body: &elements::FuncBody, params: usize) -> bool {
+	let instructions = body.code().elements();
+	body.locals().is_empty()
+		&& instructions.len() == params + LIMITED_CALL_LEN + 1
+		&& instructions[..params].iter()
+			.enumerate()
+			.all(|(index, instruction)| *instruction == Instruction::GetLocal(index as u32))
+		&& limited_call(instructions, params).is_some()
+		&& instructions[params + LIMITED_CALL_LEN] == Instruction::End
+}
+
+/// Check the stack height checks of the module against the `stack_limit`.
+///
+/// Returns the module with the checks removed and whether each defined function is a thunk.
+fn verify_stack(
+	mut module: elements::Module,
+	stack_limit: u32,
+	attestation: &mut Attestation,
+) -> (elements::Module, Vec<bool>) {
+	let imported_funcs = module.import_count(elements::ImportCountType::Function);
+	let thunks: Vec<bool> = module.code_section()
+		.map_or(&[][..], |section| section.bodies())
+		.iter()
+		.enumerate()
+		.map(|(index, body)| {
+			let params = stack_height::resolve_func_type((imported_funcs + index) as u32, &module)
+				.map_or(0, |ty| ty.params().len());
+			is_thunk(body, params)
+		})
+		.collect();
+
+	// Replace the limited calls by plain ones, remembering where they were.
+	let mut limited_calls = Vec::new();
+	let mut plain_calls = Vec::new();
+	let mut global_sets = Vec::new();
+	if let Some(code_section) = module.code_section_mut() {
+		for (index, body) in code_section.bodies_mut().iter_mut().enumerate() {
+			let func = (imported_funcs + index) as u32;
+			let instructions = body.code_mut().elements_mut();
+			let original = std::mem::take(instructions);
+			let mut pos = 0;
+			while pos < original.len() {
+				if let Some(call) = limited_call(&original, pos) {
+					instructions.push(Instruction::Call(call.callee));
+					limited_calls.push((func, pos, call));
+					pos += LIMITED_CALL_LEN;
+					continue;
+				}
+				match original[pos] {
+					Instruction::Call(callee) => plain_calls.push((func, pos, callee)),
+					Instruction::SetGlobal(global) => global_sets.push((func, pos, global)),
+					_ => {},
+				}
+				instructions.push(original[pos].clone());
+				pos += 1;
+			}
+		}
+	}
+
+	let costs = match stack_height::compute_stack_costs(&module) {
+		Ok(costs) => costs,
+		Err(e) => {
+			attestation.violation("stack", format!("the stack costs can't be computed: {:?}", e));
+			return (module, thunks);
+		},
+	};
+	let cost = |func: u32| costs.get(func as usize).copied().unwrap_or(0);
+
+	let global = limited_calls.first().map(|(_, _, call)| call.global);
+	for (func, pos, call) in &limited_calls {
+		if Some(call.global) != global {
+			attestation.violation("stack", format!("function {} checks another stack height global at {}", func, pos));
+		}
+		if call.limit as u32 > stack_limit {
+			attestation.violation("stack", format!(
+				"function {} checks the stack limit {} at {}, the limit is {}",
+				func, call.limit as u32, pos, stack_limit,
+			));
+		}
+		if (call.cost as u32) < cost(call.callee) {
+			attestation.violation("stack", format!(
+				"function {} adds {} to the stack height for the call of function {} at {}, its stack cost is {}",
+				func, call.cost as u32, call.callee, pos, cost(call.callee),
+			));
+		}
+	}
+	for (func, pos, callee) in plain_calls {
+		if cost(callee) > 0 {
+			attestation.violation("stack", format!(
+				"function {} calls function {} at {} without checking the stack height",
+				func, callee, pos,
+			));
+		}
+	}
+	for (func, pos, set_global) in global_sets {
+		if Some(set_global) == global {
+			attestation.violation("stack", format!("function {} sets the stack height global at {}", func, pos));
+		}
+	}
+
+	let exports = module.export_section().map_or(&[][..], |section| section.entries());
+	for entry in exports {
+		match *entry.internal() {
+			elements::Internal::Global(index) if Some(index) == global => {
+				attestation.violation("stack", format!("the stack height global is exported as '{}'", entry.field()));
+			},
+			_ => {},
+		}
+	}
+	let exported = exports.iter().filter_map(|entry| match *entry.internal() {
+		elements::Internal::Function(index) => Some(index),
+		_ => None,
+	});
+	let table_entries = module.elements_section()
+		.map_or(&[][..], |section| section.entries())
+		.iter()
+		.flat_map(|segment| segment.members())
+		.copied();
+	for func in exported.chain(table_entries).chain(module.start_section()) {
+		let is_thunk = (func as usize).checked_sub(imported_funcs)
+			.and_then(|index| thunks.get(index))
+			.copied()
+			.unwrap_or(false);
+		if cost(func) > 0 && !is_thunk {
+			attestation.violation("stack", format!("function {} is called from outside without a thunk", func));
+		}
+	}
+
+	(module, thunks)
+}
+
+/// Check the charges of the module against the `rules`, skipping the `thunks`.
+fn verify_gas(
+	mut module: elements::Module,
+	rules: &crate::rules::Set,
+	profile: &Profile,
+	thunks: &[bool],
+	attestation: &mut Attestation,
+) {
+	let bodies = module.code_section().map_or(0, |section| section.bodies().len());
+	let gas_func = module.import_section()
+		.map_or(&[][..], |section| section.entries())
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+		.position(|entry| entry.module() == profile.gas_module && entry.field() == profile.gas_field)
+		.map(|index| index as u32);
+	let gas_func = match gas_func {
+		Some(gas_func) => gas_func,
+		None => {
+			if bodies > 0 {
+				attestation.violation("gas", format!(
+					"the gas function '{}::{}' is not imported",
+					profile.gas_module, profile.gas_field,
+				));
+			}
+			return;
+		},
+	};
+	let takes_amount = match stack_height::resolve_func_type(gas_func, &module) {
+		Ok(ty) => ty.params() == [ValueType::I32] && ty.results().is_empty(),
+		Err(_) => false,
+	};
+	if !takes_amount {
+		attestation.violation("gas", "the gas function does not take an i32 amount".into());
+		return;
+	}
+
+	// The functions charging for `memory.grow` are the ones the gas metering adds.
+	let imported_funcs = module.import_count(elements::ImportCountType::Function);
+	let grow_metering = rules.grow_metering();
+	let grow_counter = gas::grow_counter(&*grow_metering, gas_func, false);
+	let grow_counter_type = elements::FunctionType::new(vec![ValueType::I32], vec![ValueType::I32]);
+	let grow_counters: Vec<bool> = module.code_section()
+		.map_or(&[][..], |section| section.bodies())
+		.iter()
+		.enumerate()
+		.map(|(index, body)| {
+			grow_metering.is_charged()
+				&& *body == grow_counter.code
+				&& stack_height::resolve_func_type((imported_funcs + index) as u32, &module).ok() == Some(&grow_counter_type)
+		})
+		.collect();
+	let is_grow_counter = |func: u32| (func as usize).checked_sub(imported_funcs)
+		.and_then(|index| grow_counters.get(index))
+		.copied()
+		.unwrap_or(false);
+
+	// Remove the charges, remembering the amount charged before each remaining instruction, and
+	// restore the `memory.grow` instructions.
+	let mut charges = Vec::with_capacity(bodies);
+	if let Some(code_section) = module.code_section_mut() {
+		for (index, body) in code_section.bodies_mut().iter_mut().enumerate() {
+			let instructions = body.code_mut().elements_mut();
+			let original = std::mem::take(instructions);
+			let mut charged = vec![0u64; original.len() + 1];
+			let mut pos = 0;
+			while pos < original.len() {
+				match original.get(pos..pos + 2) {
+					Some([Instruction::I32Const(amount), Instruction::Call(func)]) if *func == gas_func => {
+						charged[instructions.len()] += u64::from(*amount as u32);
+						pos += 2;
+						continue;
+					},
+					_ => {},
+				}
+				instructions.push(match original[pos] {
+					Instruction::Call(func) if is_grow_counter(func) => Instruction::GrowMemory(0),
+					Instruction::GrowMemory(_) if grow_metering.is_charged() && !grow_counters[index] => {
+						attestation.violation("gas", format!(
+							"function {} grows the memory at {} without charging for it",
+							imported_funcs + index, pos,
+						));
+						original[pos].clone()
+					},
+					ref instruction => instruction.clone(),
+				});
+				pos += 1;
+			}
+			charges.push(charged);
+		}
+	}
+
+	let skip = |index: usize| thunks.get(index).copied().unwrap_or(false) || grow_counters[index];
+	let config = GasConfig::new(&profile.gas_module, &profile.gas_field);
+	let metered_blocks = match gas::plan_metering(&module, rules, &config, &|index| !skip(index)) {
+		Ok(metered_blocks) => metered_blocks,
+		Err(e) => {
+			attestation.violation("gas", format!("the charges can't be determined: {}", e));
+			return;
+		},
+	};
+	for (index, (blocks, charged)) in metered_blocks.iter().zip(&charges).enumerate() {
+		let func = imported_funcs + index;
+		for block in blocks {
+			if charged[block.start_pos] < block.cost {
+				attestation.violation("gas", format!(
+					"function {} charges {} for the block at {}, its cost is {}",
+					func, charged[block.start_pos], block.start_pos, block.cost,
+				));
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+	use crate::testing::parse_wat;
+
+	const SOURCE: &str = r#"
+	(module
+		(import "env" "log" (func $log (param i32)))
+		(memory 1 16)
+		(func $helper (param i32) (result i32)
+			get_local 0
+			i32.const 1
+			i32.add)
+		(func (export "call") (param i32)
+			get_local 0
+			call $helper
+			call $log
+			i32.const 1
+			grow_memory
+			drop))
+	"#;
+
+	fn profile() -> Profile {
+		Profile::new()
+			.with_gas(rules::Set::default().with_grow_cost(1))
+			.with_stack_limit(1024)
+			.with_stripping(false)
+			.with_allowed_import("env", "log")
+			.with_limits(Limits::new().with_max_memory_pages(16))
+	}
+
+	fn artifact(module: elements::Module) -> Vec<u8> {
+		elements::serialize(module).unwrap()
+	}
+
+	#[test]
+	fn accepts_instrumented() {
+		let profile = profile();
+		let instrumented = profile.instrument(parse_wat(SOURCE)).unwrap();
+		let attestation = verify_artifact(&artifact(instrumented), &profile);
+		assert_eq!(attestation.violations, vec![]);
+		assert_eq!(attestation.checks, vec!["decoding", "limits", "imports", "custom sections", "stack", "gas"]);
+	}
+
+	#[test]
+	fn rejects_tampered() {
+		let profile = profile();
+		let attestation = verify_artifact(&artifact(parse_wat(SOURCE)), &profile);
+		let checks: Vec<_> = attestation.violations.iter().map(|violation| violation.check).collect();
+		assert_eq!(checks, vec!["custom sections", "stack", "stack", "gas"]);
+
+		// Lower the first charge of the exported function.
+		let mut instrumented = profile.instrument(parse_wat(SOURCE)).unwrap();
+		let body = &mut instrumented.code_section_mut().unwrap().bodies_mut()[1];
+		body.code_mut().elements_mut()[0] = Instruction::I32Const(1);
+		let attestation = verify_artifact(&artifact(instrumented), &profile);
+		assert_eq!(attestation.violations.len(), 1);
+		assert_eq!(attestation.violations[0].check, "gas");
+
+		let other = Profile::new().with_allowed_import("env", "gas").with_limits(Limits::new().with_max_memory_pages(8));
+		let attestation = verify_artifact(&artifact(parse_wat(SOURCE)), &other);
+		assert_eq!(
+			attestation.violations,
+			vec![
+				Violation { check: "limits", message: "memory size in pages is 16, the limit is 8".into() },
+				Violation { check: "imports", message: "'env::log' is not allowed".into() },
+			],
+		);
+	}
+}
@@ -0,0 +1,169 @@
+//! Backends decoding and encoding the modules the passes of this crate run on.
+//!
+//! The passes work on the `parity_wasm` representation of a module. parity-wasm itself is no
+//! longer maintained, so with the `wasm-tools` feature modules can instead be decoded with
+//! `wasmparser` and encoded with `wasm-encoder`. The passes and their output are the same
+//! regardless of the backend, only decoding and encoding differ: `WasmTools` rejects modules
+//! using anything parity-wasm can't represent rather than misreading them.
+//!
+//! `decode` and `encode` use `WasmTools` if the feature is enabled and `ParityWasm` otherwise.
+//! `BackendKind` selects the backend at runtime instead, e.g. per call from a configuration, so
+//! that consumers can compare the backends on the same modules before parity-wasm is removed.
+
+#[cfg(feature = "wasm-tools")]
+mod wasm_tools;
+
+use crate::std::fmt;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+
+#[cfg(feature = "wasm-tools")]
+pub use self::wasm_tools::WasmTools;
+
+/// Reason why a module couldn't be decoded or encoded.
+#[derive(Debug)]
+pub enum Error {
+	/// parity-wasm failed to decode or encode the module.
+	ParityWasm(elements::Error),
+	/// wasmparser failed to decode the module.
+	#[cfg(feature = "wasm-tools")]
+	Parser(wasmparser::BinaryReaderError),
+	/// The module uses something the passes can't represent, e.g. an operator of an unsupported
+	/// proposal.
+	Unsupported(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::ParityWasm(err) => write!(f, "{}", err),
+			#[cfg(feature = "wasm-tools")]
+			Error::Parser(err) => write!(f, "{}", err),
+			Error::Unsupported(what) => write!(f, "Unsupported: {}", what),
+		}
+	}
+}
+
+impl From<elements::Error> for Error {
+	fn from(err: elements::Error) -> Self {
+		Error::ParityWasm(err)
+	}
+}
+
+#[cfg(feature = "wasm-tools")]
+impl From<wasmparser::BinaryReaderError> for Error {
+	fn from(err: wasmparser::BinaryReaderError) -> Self {
+		Error::Parser(err)
+	}
+}
+
+/// Decoder and encoder of modules.
+pub trait Backend {
+	/// Decode the binary `bytes` into a module the passes can run on.
+	fn decode(&self, bytes: &[u8]) -> Result<elements::Module, Error>;
+	/// Encode the `module` into its binary form.
+	fn encode(&self, module: elements::Module) -> Result<Vec<u8>, Error>;
+}
+
+/// Backend decoding and encoding modules with parity-wasm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParityWasm;
+
+impl Backend for ParityWasm {
+	fn decode(&self, bytes: &[u8]) -> Result<elements::Module, Error> {
+		Ok(elements::deserialize_buffer(bytes)?)
+	}
+
+	fn encode(&self, module: elements::Module) -> Result<Vec<u8>, Error> {
+		Ok(elements::serialize(module)?)
+	}
+}
+
+/// Backend selected at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackendKind {
+	/// See `ParityWasm`.
+	ParityWasm,
+	/// See `WasmTools`.
+	#[cfg(feature = "wasm-tools")]
+	WasmTools,
+}
+
+impl BackendKind {
+	/// All backends enabled by the cargo features.
+	pub const ALL: &'static [BackendKind] = &[
+		BackendKind::ParityWasm,
+		#[cfg(feature = "wasm-tools")]
+		BackendKind::WasmTools,
+	];
+
+	/// Name of the backend, `parity-wasm` or `wasm-tools`.
+	pub fn name(&self) -> &'static str {
+		match self {
+			BackendKind::ParityWasm => "parity-wasm",
+			#[cfg(feature = "wasm-tools")]
+			BackendKind::WasmTools => "wasm-tools",
+		}
+	}
+
+	/// The backend with the given name, if it is enabled by the cargo features.
+	pub fn from_name(name: &str) -> Option<BackendKind> {
+		BackendKind::ALL.iter().cloned().find(|kind| kind.name() == name)
+	}
+}
+
+/// The backend selected by the cargo features, see `DefaultBackend`.
+impl Default for BackendKind {
+	#[cfg(feature = "wasm-tools")]
+	fn default() -> Self {
+		BackendKind::WasmTools
+	}
+
+	#[cfg(not(feature = "wasm-tools"))]
+	fn default() -> Self {
+		BackendKind::ParityWasm
+	}
+}
+
+impl fmt::Display for BackendKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "{}", self.name())
+	}
+}
+
+impl Backend for BackendKind {
+	fn decode(&self, bytes: &[u8]) -> Result<elements::Module, Error> {
+		match self {
+			BackendKind::ParityWasm => ParityWasm.decode(bytes),
+			#[cfg(feature = "wasm-tools")]
+			BackendKind::WasmTools => WasmTools.decode(bytes),
+		}
+	}
+
+	fn encode(&self, module: elements::Module) -> Result<Vec<u8>, Error> {
+		match self {
+			BackendKind::ParityWasm => ParityWasm.encode(module),
+			#[cfg(feature = "wasm-tools")]
+			BackendKind::WasmTools => WasmTools.encode(module),
+		}
+	}
+}
+
+/// The backend selected by the cargo features.
+#[cfg(feature = "wasm-tools")]
+pub type DefaultBackend = WasmTools;
+/// The backend selected by the cargo features.
+#[cfg(not(feature = "wasm-tools"))]
+pub type DefaultBackend = ParityWasm;
+
+/// Decode `bytes` with the backend selected by the cargo features.
+pub fn decode(bytes: &[u8]) -> Result<elements::Module, Error> {
+	DefaultBackend::default().decode(bytes)
+}
+
+/// Encode `module` with the backend selected by the cargo features.
+pub fn encode(module: elements::Module) -> Result<Vec<u8>, Error> {
+	DefaultBackend::default().encode(module)
+}
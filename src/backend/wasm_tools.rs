@@ -0,0 +1,738 @@
+use crate::std::borrow::Cow;
+use crate::std::convert::TryFrom;
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, BlockType, Instruction, Section, ValueType};
+#[cfg(feature = "sign_ext")]
+use parity_wasm::elements::SignExtInstruction;
+use wasm_encoder::{self as encoder, Encode};
+use wasmparser::{Operator, Payload};
+
+use super::{Backend, Error};
+
+/// Backend decoding modules with wasmparser and encoding them with wasm-encoder.
+///
+/// Only what parity-wasm can represent is supported, as enabled by the cargo features. Modules
+/// using anything else, e.g. 64-bit memories or reference types, fail with
+/// `Error::Unsupported`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WasmTools;
+
+impl Backend for WasmTools {
+	fn decode(&self, bytes: &[u8]) -> Result<elements::Module, Error> {
+		let mut sections = Vec::new();
+		for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+			let section = match payload? {
+				Payload::Version { encoding: wasmparser::Encoding::Module, .. } => continue,
+				Payload::TypeSection(reader) => {
+					let mut types = Vec::new();
+					for group in reader {
+						let group = group?;
+						if group.is_explicit_rec_group() {
+							return Err(unsupported(group));
+						}
+						for ty in group.into_types() {
+							match ty.composite_type {
+								wasmparser::CompositeType::Func(ref func) if ty.is_final && ty.supertype_idx.is_none() => {
+									types.push(elements::Type::Function(elements::FunctionType::new(
+										decode_value_types(func.params())?,
+										decode_value_types(func.results())?,
+									)));
+								}
+								_ => return Err(unsupported(ty)),
+							}
+						}
+					}
+					Section::Type(elements::TypeSection::with_types(types))
+				}
+				Payload::ImportSection(reader) => {
+					let mut entries = Vec::new();
+					for import in reader {
+						let import = import?;
+						let external = match import.ty {
+							wasmparser::TypeRef::Func(index) => elements::External::Function(index),
+							wasmparser::TypeRef::Table(ty) => elements::External::Table(decode_table_type(ty)?),
+							wasmparser::TypeRef::Memory(ty) => elements::External::Memory(decode_memory_type(ty)?),
+							wasmparser::TypeRef::Global(ty) => elements::External::Global(decode_global_type(ty)?),
+							other => return Err(unsupported(other)),
+						};
+						entries.push(elements::ImportEntry::new(import.module.into(), import.name.into(), external));
+					}
+					Section::Import(elements::ImportSection::with_entries(entries))
+				}
+				Payload::FunctionSection(reader) => {
+					let entries = reader.into_iter()
+						.map(|ty| ty.map(elements::Func::new))
+						.collect::<Result<_, _>>()?;
+					Section::Function(elements::FunctionSection::with_entries(entries))
+				}
+				Payload::TableSection(reader) => {
+					let mut entries = Vec::new();
+					for table in reader {
+						let table = table?;
+						match table.init {
+							wasmparser::TableInit::RefNull => entries.push(decode_table_type(table.ty)?),
+							_ => return Err(unsupported(table.ty)),
+						}
+					}
+					Section::Table(elements::TableSection::with_entries(entries))
+				}
+				Payload::MemorySection(reader) => {
+					let mut entries = Vec::new();
+					for memory in reader {
+						entries.push(decode_memory_type(memory?)?);
+					}
+					Section::Memory(elements::MemorySection::with_entries(entries))
+				}
+				Payload::GlobalSection(reader) => {
+					let mut entries = Vec::new();
+					for global in reader {
+						let global = global?;
+						entries.push(elements::GlobalEntry::new(
+							decode_global_type(global.ty)?,
+							decode_init_expr(global.init_expr)?,
+						));
+					}
+					Section::Global(elements::GlobalSection::with_entries(entries))
+				}
+				Payload::ExportSection(reader) => {
+					let mut entries = Vec::new();
+					for export in reader {
+						let export = export?;
+						let internal = match export.kind {
+							wasmparser::ExternalKind::Func => elements::Internal::Function(export.index),
+							wasmparser::ExternalKind::Table => elements::Internal::Table(export.index),
+							wasmparser::ExternalKind::Memory => elements::Internal::Memory(export.index),
+							wasmparser::ExternalKind::Global => elements::Internal::Global(export.index),
+							other => return Err(unsupported(other)),
+						};
+						entries.push(elements::ExportEntry::new(export.name.into(), internal));
+					}
+					Section::Export(elements::ExportSection::with_entries(entries))
+				}
+				Payload::StartSection { func, .. } => Section::Start(func),
+				Payload::ElementSection(reader) => {
+					let mut entries = Vec::new();
+					for element in reader {
+						let element = element?;
+						let (index, offset) = match element.kind {
+							wasmparser::ElementKind::Active { table_index, offset_expr } =>
+								(table_index.unwrap_or(0), decode_init_expr(offset_expr)?),
+							_ => return Err(Error::Unsupported("passive or declared element segment".into())),
+						};
+						let members = match element.items {
+							wasmparser::ElementItems::Functions(reader) => reader.into_iter().collect::<Result<_, _>>()?,
+							wasmparser::ElementItems::Expressions(ty, _) => return Err(unsupported(ty)),
+						};
+						entries.push(elements::ElementSegment::new(index, Some(offset), members));
+					}
+					Section::Element(elements::ElementSection::with_entries(entries))
+				}
+				Payload::DataCountSection { count, .. } => Section::DataCount(count),
+				Payload::DataSection(reader) => {
+					let mut entries = Vec::new();
+					for data in reader {
+						let data = data?;
+						match data.kind {
+							wasmparser::DataKind::Active { memory_index, offset_expr } => entries.push(elements::DataSegment::new(
+								memory_index,
+								Some(decode_init_expr(offset_expr)?),
+								data.data.to_vec(),
+							)),
+							other => return Err(unsupported(other)),
+						}
+					}
+					Section::Data(elements::DataSection::with_entries(entries))
+				}
+				// The bodies are added to the section as they are decoded.
+				Payload::CodeSectionStart { .. } => Section::Code(elements::CodeSection::with_bodies(Vec::new())),
+				Payload::CodeSectionEntry(body) => {
+					let mut locals = Vec::new();
+					for local in body.get_locals_reader()? {
+						let (count, ty) = local?;
+						locals.push(elements::Local::new(count, decode_value_type(ty)?));
+					}
+					let mut instructions = Vec::new();
+					for op in body.get_operators_reader()? {
+						instructions.push(decode_instruction(op?)?);
+					}
+					match sections.last_mut() {
+						Some(Section::Code(code)) => code.bodies_mut()
+							.push(elements::FuncBody::new(locals, elements::Instructions::new(instructions))),
+						_ => unreachable!("function bodies directly follow the start of the code section; qed"),
+					}
+					continue;
+				}
+				Payload::CustomSection(reader) => Section::Custom(
+					elements::CustomSection::new(reader.name().into(), reader.data().to_vec())
+				),
+				Payload::UnknownSection { id, contents, .. } => Section::Unparsed { id, payload: contents.to_vec() },
+				Payload::End(_) => break,
+				other => return Err(unsupported(other)),
+			};
+			sections.push(section);
+		}
+		Ok(elements::Module::new(sections))
+	}
+
+	fn encode(&self, module: elements::Module) -> Result<Vec<u8>, Error> {
+		encode(module, false)
+	}
+}
+
+impl WasmTools {
+	/// Same as `encode`, but a `call` followed by `return` is encoded as `return_call` of the
+	/// tail call proposal if the called function has the results of the calling one.
+	///
+	/// `return_call` can't be decoded again, as parity-wasm has no such instruction.
+	pub fn encode_with_tail_calls(&self, module: elements::Module) -> Result<Vec<u8>, Error> {
+		encode(module, true)
+	}
+}
+
+fn encode(module: elements::Module, tail_calls: bool) -> Result<Vec<u8>, Error> {
+	// The results of every function in the function index space, to find the tail calls.
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let imported_types = module.import_section()
+		.map_or(&[][..], |import_section| import_section.entries())
+		.iter()
+		.filter_map(|entry| match *entry.external() {
+			elements::External::Function(type_ref) => Some(type_ref),
+			_ => None,
+		});
+	let defined_types = module.function_section()
+		.map_or(&[][..], |function_section| function_section.entries())
+		.iter()
+		.map(|func| func.type_ref());
+	let results: Vec<Vec<ValueType>> = imported_types.chain(defined_types)
+		.map(|type_ref| match types.get(type_ref as usize) {
+			Some(elements::Type::Function(func)) => func.results().to_vec(),
+			None => Vec::new(),
+		})
+		.collect();
+	let imported_funcs = module.import_count(elements::ImportCountType::Function);
+
+	let mut encoded = encoder::Module::new();
+	for section in module.into_sections() {
+		match section {
+			Section::Type(section) => {
+				let mut types = encoder::TypeSection::new();
+				for ty in section.types() {
+					let elements::Type::Function(func) = ty;
+					types.function(encode_value_types(func.params()), encode_value_types(func.results()));
+				}
+				encoded.section(&types);
+			}
+			Section::Import(section) => {
+				let mut imports = encoder::ImportSection::new();
+				for entry in section.entries() {
+					let ty = match entry.external() {
+						elements::External::Function(index) => encoder::EntityType::Function(*index),
+						elements::External::Table(ty) => encoder::EntityType::Table(encode_table_type(ty)),
+						elements::External::Memory(ty) => encoder::EntityType::Memory(encode_memory_type(ty)),
+						elements::External::Global(ty) => encoder::EntityType::Global(encode_global_type(ty)),
+					};
+					imports.import(entry.module(), entry.field(), ty);
+				}
+				encoded.section(&imports);
+			}
+			Section::Function(section) => {
+				let mut functions = encoder::FunctionSection::new();
+				for func in section.entries() {
+					functions.function(func.type_ref());
+				}
+				encoded.section(&functions);
+			}
+			Section::Table(section) => {
+				let mut tables = encoder::TableSection::new();
+				for ty in section.entries() {
+					tables.table(encode_table_type(ty));
+				}
+				encoded.section(&tables);
+			}
+			Section::Memory(section) => {
+				let mut memories = encoder::MemorySection::new();
+				for ty in section.entries() {
+					memories.memory(encode_memory_type(ty));
+				}
+				encoded.section(&memories);
+			}
+			Section::Global(section) => {
+				let mut globals = encoder::GlobalSection::new();
+				for entry in section.entries() {
+					globals.global(encode_global_type(entry.global_type()), &encode_init_expr(entry.init_expr())?);
+				}
+				encoded.section(&globals);
+			}
+			Section::Export(section) => {
+				let mut exports = encoder::ExportSection::new();
+				for entry in section.entries() {
+					let (kind, index) = match *entry.internal() {
+						elements::Internal::Function(index) => (encoder::ExportKind::Func, index),
+						elements::Internal::Table(index) => (encoder::ExportKind::Table, index),
+						elements::Internal::Memory(index) => (encoder::ExportKind::Memory, index),
+						elements::Internal::Global(index) => (encoder::ExportKind::Global, index),
+					};
+					exports.export(entry.field(), kind, index);
+				}
+				encoded.section(&exports);
+			}
+			Section::Start(function_index) => {
+				encoded.section(&encoder::StartSection { function_index });
+			}
+			Section::Element(section) => {
+				let mut segments = encoder::ElementSection::new();
+				for segment in section.entries() {
+					let offset = segment.offset().as_ref()
+						.ok_or_else(|| Error::Unsupported("passive element segment".into()))?;
+					// The table index is only encoded if it is not the default one.
+					let index = Some(segment.index()).filter(|index| *index != 0);
+					segments.active(index, &encode_init_expr(offset)?, encoder::Elements::Functions(segment.members()));
+				}
+				encoded.section(&segments);
+			}
+			Section::DataCount(count) => {
+				encoded.section(&encoder::DataCountSection { count });
+			}
+			Section::Code(section) => {
+				let mut bodies = encoder::CodeSection::new();
+				for (index, body) in section.bodies().iter().enumerate() {
+					let mut function = encoder::Function::new(
+						body.locals().iter().map(|local| (local.count(), encode_value_type(local.value_type())))
+					);
+					let own_results = results.get(imported_funcs + index);
+					let mut instructions = body.code().elements().iter().peekable();
+					while let Some(instruction) = instructions.next() {
+						// `call f` followed by `return` becomes `return_call f` if f returns the
+						// results of the function, as the operand stack is discarded otherwise.
+						if let Instruction::Call(callee) = *instruction {
+							if tail_calls
+								&& instructions.peek() == Some(&&Instruction::Return)
+								&& own_results.is_some()
+								&& results.get(callee as usize) == own_results
+							{
+								function.instruction(&encoder::Instruction::ReturnCall(callee));
+								instructions.next();
+								continue;
+							}
+						}
+						function.instruction(&encode_instruction(instruction)?);
+					}
+					bodies.function(&function);
+				}
+				encoded.section(&bodies);
+			}
+			Section::Data(section) => {
+				let mut segments = encoder::DataSection::new();
+				for segment in section.entries() {
+					let offset = segment.offset().as_ref()
+						.ok_or_else(|| Error::Unsupported("passive data segment".into()))?;
+					segments.active(segment.index(), &encode_init_expr(offset)?, segment.value().iter().copied());
+				}
+				encoded.section(&segments);
+			}
+			// Custom sections, including the parsed ones, are passed through as parity-wasm encodes them.
+			section @ Section::Custom(_) |
+			section @ Section::Name(_) |
+			section @ Section::Reloc(_) |
+			section @ Section::Unparsed { .. } => {
+				encoded.section(&Passthrough(elements::serialize(section)?));
+			}
+		}
+	}
+	Ok(encoded.finish())
+}
+
+/// Section encoded by parity-wasm, including its id.
+struct Passthrough(Vec<u8>);
+
+impl Encode for Passthrough {
+	fn encode(&self, sink: &mut Vec<u8>) {
+		sink.extend_from_slice(&self.0[1..]);
+	}
+}
+
+impl encoder::Section for Passthrough {
+	fn id(&self) -> u8 {
+		self.0[0]
+	}
+}
+
+fn unsupported<T: fmt::Debug>(what: T) -> Error {
+	Error::Unsupported(format!("{:?}", what))
+}
+
+fn decode_value_type(ty: wasmparser::ValType) -> Result<ValueType, Error> {
+	match ty {
+		wasmparser::ValType::I32 => Ok(ValueType::I32),
+		wasmparser::ValType::I64 => Ok(ValueType::I64),
+		wasmparser::ValType::F32 => Ok(ValueType::F32),
+		wasmparser::ValType::F64 => Ok(ValueType::F64),
+		#[cfg(feature = "simd")]
+		wasmparser::ValType::V128 => Ok(ValueType::V128),
+		other => Err(unsupported(other)),
+	}
+}
+
+fn decode_value_types(types: &[wasmparser::ValType]) -> Result<Vec<ValueType>, Error> {
+	types.iter().map(|ty| decode_value_type(*ty)).collect()
+}
+
+fn decode_table_type(ty: wasmparser::TableType) -> Result<elements::TableType, Error> {
+	if ty.element_type != wasmparser::RefType::FUNCREF {
+		return Err(unsupported(ty));
+	}
+	Ok(elements::TableType::new(ty.initial, ty.maximum))
+}
+
+fn decode_memory_type(ty: wasmparser::MemoryType) -> Result<elements::MemoryType, Error> {
+	let initial = u32::try_from(ty.initial).map_err(|_| unsupported(ty))?;
+	let maximum = ty.maximum.map(u32::try_from).transpose().map_err(|_| unsupported(ty))?;
+	if ty.memory64 {
+		return Err(unsupported(ty));
+	}
+	#[cfg(not(feature = "atomics"))]
+	if ty.shared {
+		return Err(unsupported(ty));
+	}
+	#[allow(unused_mut)]
+	let mut memory = elements::MemoryType::new(initial, maximum);
+	#[cfg(feature = "atomics")]
+	memory.set_shared(ty.shared);
+	Ok(memory)
+}
+
+fn decode_global_type(ty: wasmparser::GlobalType) -> Result<elements::GlobalType, Error> {
+	Ok(elements::GlobalType::new(decode_value_type(ty.content_type)?, ty.mutable))
+}
+
+fn decode_init_expr(expr: wasmparser::ConstExpr) -> Result<elements::InitExpr, Error> {
+	let mut code = Vec::new();
+	for op in expr.get_operators_reader() {
+		code.push(decode_instruction(op?)?);
+	}
+	Ok(elements::InitExpr::new(code))
+}
+
+fn decode_block_type(ty: wasmparser::BlockType) -> Result<BlockType, Error> {
+	match ty {
+		wasmparser::BlockType::Empty => Ok(BlockType::NoResult),
+		wasmparser::BlockType::Type(ty) => Ok(BlockType::Value(decode_value_type(ty)?)),
+		other => Err(unsupported(other)),
+	}
+}
+
+fn decode_memarg(memarg: &wasmparser::MemArg) -> Result<(u32, u32), Error> {
+	match u32::try_from(memarg.offset) {
+		Ok(offset) if memarg.memory == 0 => Ok((memarg.align.into(), offset)),
+		_ => Err(unsupported(memarg)),
+	}
+}
+
+fn encode_value_type(ty: ValueType) -> encoder::ValType {
+	match ty {
+		ValueType::I32 => encoder::ValType::I32,
+		ValueType::I64 => encoder::ValType::I64,
+		ValueType::F32 => encoder::ValType::F32,
+		ValueType::F64 => encoder::ValType::F64,
+		#[cfg(feature = "simd")]
+		ValueType::V128 => encoder::ValType::V128,
+	}
+}
+
+fn encode_value_types(types: &[ValueType]) -> impl ExactSizeIterator<Item = encoder::ValType> + '_ {
+	types.iter().map(|ty| encode_value_type(*ty))
+}
+
+fn encode_table_type(ty: &elements::TableType) -> encoder::TableType {
+	encoder::TableType {
+		element_type: encoder::RefType::FUNCREF,
+		minimum: ty.limits().initial(),
+		maximum: ty.limits().maximum(),
+	}
+}
+
+fn encode_memory_type(ty: &elements::MemoryType) -> encoder::MemoryType {
+	encoder::MemoryType {
+		minimum: ty.limits().initial().into(),
+		maximum: ty.limits().maximum().map(Into::into),
+		memory64: false,
+		#[cfg(feature = "atomics")]
+		shared: ty.limits().shared(),
+		#[cfg(not(feature = "atomics"))]
+		shared: false,
+	}
+}
+
+fn encode_global_type(ty: &elements::GlobalType) -> encoder::GlobalType {
+	encoder::GlobalType {
+		val_type: encode_value_type(ty.content_type()),
+		mutable: ty.is_mutable(),
+	}
+}
+
+fn encode_init_expr(expr: &elements::InitExpr) -> Result<encoder::ConstExpr, Error> {
+	// The encoder terminates the expression itself.
+	let mut bytes = Vec::new();
+	for instruction in expr.code().iter().take_while(|instruction| **instruction != Instruction::End) {
+		encode_instruction(instruction)?.encode(&mut bytes);
+	}
+	Ok(encoder::ConstExpr::raw(bytes))
+}
+
+fn encode_block_type(ty: BlockType) -> encoder::BlockType {
+	match ty {
+		BlockType::NoResult => encoder::BlockType::Empty,
+		BlockType::Value(ty) => encoder::BlockType::Result(encode_value_type(ty)),
+	}
+}
+
+fn encode_memarg(align: u32, offset: u32) -> encoder::MemArg {
+	encoder::MemArg { offset: offset.into(), align, memory_index: 0 }
+}
+
+/// Conversions of the instructions without immediates. Both wasmparser and wasm-encoder follow the
+/// names of the specification, which differ from parity-wasm for some conversions.
+macro_rules! plain_instructions {
+	($($same:ident),*; $($tools:ident => $parity:ident),*) => {
+		fn decode_plain(op: &Operator) -> Option<Instruction> {
+			match op {
+				$(Operator::$same => Some(Instruction::$same),)*
+				$(Operator::$tools => Some(Instruction::$parity),)*
+				_ => None,
+			}
+		}
+
+		fn encode_plain(instruction: &Instruction) -> Option<encoder::Instruction<'static>> {
+			match instruction {
+				$(Instruction::$same => Some(encoder::Instruction::$same),)*
+				$(Instruction::$parity => Some(encoder::Instruction::$tools),)*
+				_ => None,
+			}
+		}
+	};
+}
+
+plain_instructions! {
+	Unreachable, Nop, Else, End, Return, Drop, Select,
+	I32Eqz, I32Eq, I32Ne, I32LtS, I32LtU, I32GtS, I32GtU, I32LeS, I32LeU, I32GeS, I32GeU,
+	I64Eqz, I64Eq, I64Ne, I64LtS, I64LtU, I64GtS, I64GtU, I64LeS, I64LeU, I64GeS, I64GeU,
+	F32Eq, F32Ne, F32Lt, F32Gt, F32Le, F32Ge,
+	F64Eq, F64Ne, F64Lt, F64Gt, F64Le, F64Ge,
+	I32Clz, I32Ctz, I32Popcnt, I32Add, I32Sub, I32Mul, I32DivS, I32DivU, I32RemS, I32RemU,
+	I32And, I32Or, I32Xor, I32Shl, I32ShrS, I32ShrU, I32Rotl, I32Rotr,
+	I64Clz, I64Ctz, I64Popcnt, I64Add, I64Sub, I64Mul, I64DivS, I64DivU, I64RemS, I64RemU,
+	I64And, I64Or, I64Xor, I64Shl, I64ShrS, I64ShrU, I64Rotl, I64Rotr,
+	F32Abs, F32Neg, F32Ceil, F32Floor, F32Trunc, F32Nearest, F32Sqrt,
+	F32Add, F32Sub, F32Mul, F32Div, F32Min, F32Max, F32Copysign,
+	F64Abs, F64Neg, F64Ceil, F64Floor, F64Trunc, F64Nearest, F64Sqrt,
+	F64Add, F64Sub, F64Mul, F64Div, F64Min, F64Max, F64Copysign,
+	I32WrapI64, F32DemoteF64, F64PromoteF32,
+	I32ReinterpretF32, I64ReinterpretF64, F32ReinterpretI32, F64ReinterpretI64;
+	I32TruncF32S => I32TruncSF32, I32TruncF32U => I32TruncUF32,
+	I32TruncF64S => I32TruncSF64, I32TruncF64U => I32TruncUF64,
+	I64ExtendI32S => I64ExtendSI32, I64ExtendI32U => I64ExtendUI32,
+	I64TruncF32S => I64TruncSF32, I64TruncF32U => I64TruncUF32,
+	I64TruncF64S => I64TruncSF64, I64TruncF64U => I64TruncUF64,
+	F32ConvertI32S => F32ConvertSI32, F32ConvertI32U => F32ConvertUI32,
+	F32ConvertI64S => F32ConvertSI64, F32ConvertI64U => F32ConvertUI64,
+	F64ConvertI32S => F64ConvertSI32, F64ConvertI32U => F64ConvertUI32,
+	F64ConvertI64S => F64ConvertSI64, F64ConvertI64U => F64ConvertUI64
+}
+
+/// Conversions of the loads and stores, which all take a memory immediate.
+macro_rules! memory_instructions {
+	($($name:ident),*) => {
+		fn decode_memory(op: &Operator) -> Option<Result<Instruction, Error>> {
+			match op {
+				$(Operator::$name { memarg } => Some(decode_memarg(memarg).map(|(align, offset)| Instruction::$name(align, offset))),)*
+				_ => None,
+			}
+		}
+
+		fn encode_memory(instruction: &Instruction) -> Option<encoder::Instruction<'static>> {
+			match *instruction {
+				$(Instruction::$name(align, offset) => Some(encoder::Instruction::$name(encode_memarg(align, offset))),)*
+				_ => None,
+			}
+		}
+	};
+}
+
+memory_instructions! {
+	I32Load, I64Load, F32Load, F64Load,
+	I32Load8S, I32Load8U, I32Load16S, I32Load16U,
+	I64Load8S, I64Load8U, I64Load16S, I64Load16U, I64Load32S, I64Load32U,
+	I32Store, I64Store, F32Store, F64Store,
+	I32Store8, I32Store16, I64Store8, I64Store16, I64Store32
+}
+
+fn decode_instruction(op: Operator) -> Result<Instruction, Error> {
+	if let Some(instruction) = decode_plain(&op) {
+		return Ok(instruction);
+	}
+	if let Some(instruction) = decode_memory(&op) {
+		return instruction;
+	}
+	Ok(match op {
+		Operator::Block { blockty } => Instruction::Block(decode_block_type(blockty)?),
+		Operator::Loop { blockty } => Instruction::Loop(decode_block_type(blockty)?),
+		Operator::If { blockty } => Instruction::If(decode_block_type(blockty)?),
+		Operator::Br { relative_depth } => Instruction::Br(relative_depth),
+		Operator::BrIf { relative_depth } => Instruction::BrIf(relative_depth),
+		Operator::BrTable { targets } => Instruction::BrTable(Box::new(elements::BrTableData {
+			table: targets.targets().collect::<Result<Vec<_>, _>>()?.into_boxed_slice(),
+			default: targets.default(),
+		})),
+		Operator::Call { function_index } => Instruction::Call(function_index),
+		Operator::CallIndirect { type_index, table_index: 0, .. } => Instruction::CallIndirect(type_index, 0),
+		Operator::LocalGet { local_index } => Instruction::GetLocal(local_index),
+		Operator::LocalSet { local_index } => Instruction::SetLocal(local_index),
+		Operator::LocalTee { local_index } => Instruction::TeeLocal(local_index),
+		Operator::GlobalGet { global_index } => Instruction::GetGlobal(global_index),
+		Operator::GlobalSet { global_index } => Instruction::SetGlobal(global_index),
+		Operator::MemorySize { mem: 0, .. } => Instruction::CurrentMemory(0),
+		Operator::MemoryGrow { mem: 0, .. } => Instruction::GrowMemory(0),
+		Operator::I32Const { value } => Instruction::I32Const(value),
+		Operator::I64Const { value } => Instruction::I64Const(value),
+		Operator::F32Const { value } => Instruction::F32Const(value.bits()),
+		Operator::F64Const { value } => Instruction::F64Const(value.bits()),
+		#[cfg(feature = "sign_ext")]
+		Operator::I32Extend8S => Instruction::SignExt(SignExtInstruction::I32Extend8S),
+		#[cfg(feature = "sign_ext")]
+		Operator::I32Extend16S => Instruction::SignExt(SignExtInstruction::I32Extend16S),
+		#[cfg(feature = "sign_ext")]
+		Operator::I64Extend8S => Instruction::SignExt(SignExtInstruction::I64Extend8S),
+		#[cfg(feature = "sign_ext")]
+		Operator::I64Extend16S => Instruction::SignExt(SignExtInstruction::I64Extend16S),
+		#[cfg(feature = "sign_ext")]
+		Operator::I64Extend32S => Instruction::SignExt(SignExtInstruction::I64Extend32S),
+		other => return Err(unsupported(other)),
+	})
+}
+
+fn encode_instruction(instruction: &Instruction) -> Result<encoder::Instruction<'static>, Error> {
+	if let Some(instruction) = encode_plain(instruction) {
+		return Ok(instruction);
+	}
+	if let Some(instruction) = encode_memory(instruction) {
+		return Ok(instruction);
+	}
+	Ok(match *instruction {
+		Instruction::Block(ty) => encoder::Instruction::Block(encode_block_type(ty)),
+		Instruction::Loop(ty) => encoder::Instruction::Loop(encode_block_type(ty)),
+		Instruction::If(ty) => encoder::Instruction::If(encode_block_type(ty)),
+		Instruction::Br(depth) => encoder::Instruction::Br(depth),
+		Instruction::BrIf(depth) => encoder::Instruction::BrIf(depth),
+		Instruction::BrTable(ref data) => encoder::Instruction::BrTable(Cow::Owned(data.table.to_vec()), data.default),
+		Instruction::Call(index) => encoder::Instruction::Call(index),
+		Instruction::CallIndirect(ty, table) => encoder::Instruction::CallIndirect { ty, table: table.into() },
+		Instruction::GetLocal(index) => encoder::Instruction::LocalGet(index),
+		Instruction::SetLocal(index) => encoder::Instruction::LocalSet(index),
+		Instruction::TeeLocal(index) => encoder::Instruction::LocalTee(index),
+		Instruction::GetGlobal(index) => encoder::Instruction::GlobalGet(index),
+		Instruction::SetGlobal(index) => encoder::Instruction::GlobalSet(index),
+		Instruction::CurrentMemory(memory) => encoder::Instruction::MemorySize(memory.into()),
+		Instruction::GrowMemory(memory) => encoder::Instruction::MemoryGrow(memory.into()),
+		Instruction::I32Const(value) => encoder::Instruction::I32Const(value),
+		Instruction::I64Const(value) => encoder::Instruction::I64Const(value),
+		Instruction::F32Const(bits) => encoder::Instruction::F32Const(f32::from_bits(bits)),
+		Instruction::F64Const(bits) => encoder::Instruction::F64Const(f64::from_bits(bits)),
+		#[cfg(feature = "sign_ext")]
+		Instruction::SignExt(ref instruction) => match instruction {
+			SignExtInstruction::I32Extend8S => encoder::Instruction::I32Extend8S,
+			SignExtInstruction::I32Extend16S => encoder::Instruction::I32Extend16S,
+			SignExtInstruction::I64Extend8S => encoder::Instruction::I64Extend8S,
+			SignExtInstruction::I64Extend16S => encoder::Instruction::I64Extend16S,
+			SignExtInstruction::I64Extend32S => encoder::Instruction::I64Extend32S,
+		},
+		ref other => return Err(unsupported(other)),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::ParityWasm;
+
+	const SOURCE: &str = r#"
+	(module
+		(import "env" "gas" (func $gas (param i32)))
+		(import "env" "memory" (memory 1 16))
+		(global $g (mut i64) (i64.const -1))
+		(table 2 funcref)
+		(elem (i32.const 0) $f $f)
+		(data (i32.const 8) "hello")
+		(func $f (export "f") (param i32) (result i32)
+			(local f32 f64)
+			(local.set 1 (f32.const nan:0x200000))
+			(local.set 2 (f64.convert_i32_s (local.get 0)))
+			(block $outer
+				(loop $inner
+					(br_table $outer $inner (local.get 0))
+				)
+			)
+			(global.set $g (i64.extend_i32_u (i32.load offset=4 (i32.const 0))))
+			(drop (memory.grow (memory.size)))
+			(call_indirect (param i32) (result i32) (local.get 0) (i32.const 1))
+		)
+	)
+	"#;
+
+	#[test]
+	fn same_as_parity_wasm() {
+		let bytes = wabt::wat2wasm(SOURCE).unwrap();
+		let module = WasmTools.decode(&bytes).unwrap();
+		assert_eq!(module, ParityWasm.decode(&bytes).unwrap());
+		assert_eq!(WasmTools.encode(module.clone()).unwrap(), ParityWasm.encode(module.clone()).unwrap());
+
+		let module = crate::inject_gas_counter(module, &crate::rules::Set::default(), "env").unwrap();
+		assert_eq!(WasmTools.encode(module.clone()).unwrap(), ParityWasm.encode(module).unwrap());
+	}
+
+	#[test]
+	fn encodes_tail_calls() {
+		let bytes = wabt::wat2wasm(r#"
+		(module
+			(func $f (result i32) (i32.const 1))
+			(func $g (result i64) (i64.const 1))
+			(func (result i32) (call $f) (return))
+			(func (result i32) (drop (call $g)) (call $f) (return))
+			(func (result i32) (drop (call $g)) (return (i32.const 0))))
+		"#).unwrap();
+		let module = WasmTools.decode(&bytes).unwrap();
+
+		let tail_calls = |binary: &[u8]| wasmparser::Parser::new(0).parse_all(binary)
+			.filter_map(|payload| match payload.unwrap() {
+				wasmparser::Payload::CodeSectionEntry(body) => Some(body.get_operators_reader().unwrap()
+					.into_iter()
+					.filter_map(|op| match op.unwrap() {
+						Operator::ReturnCall { function_index } => Some(function_index),
+						_ => None,
+					})
+					.collect::<Vec<_>>()),
+				_ => None,
+			})
+			.collect::<Vec<_>>();
+		// `call $g` is not followed by `return` and `$g` has other results anyway.
+		let expected: Vec<Vec<u32>> = vec![vec![], vec![], vec![0], vec![0], vec![]];
+		assert_eq!(tail_calls(&WasmTools.encode_with_tail_calls(module.clone()).unwrap()), expected);
+		assert_eq!(tail_calls(&WasmTools.encode(module).unwrap()), vec![Vec::<u32>::new(); 5]);
+	}
+
+	#[test]
+	fn rejects_unsupported() {
+		for source in &[
+			r#"(module (memory i64 1))"#,
+			r#"(module (table 1 externref))"#,
+			r#"(module (func (drop (ref.null func))))"#,
+		] {
+			let bytes = wabt::wat2wasm(source).unwrap();
+			match WasmTools.decode(&bytes) {
+				Err(Error::Unsupported(_)) => {}
+				other => panic!("{} decoded as {:?}", source, other),
+			}
+		}
+	}
+}
@@ -0,0 +1,562 @@
+//! Helpers for running the instrumentation from a build script or an xtask.
+//!
+//! ```no_run
+//! use pwasm_utils::build_support::{instrument_artifact, Profile};
+//! use pwasm_utils::rules;
+//!
+//! let profile = Profile::new()
+//!     .with_gas(rules::Set::default())
+//!     .with_stack_limit(16 * 1024)
+//!     .with_stripping(false);
+//! let instrumented = instrument_artifact("target/wasm32-unknown-unknown/release/contract.wasm", &profile)
+//!     .expect("instrumentation failed");
+//! println!("cargo:warning=instrumented contract: {}", instrumented.display());
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::{fmt, fs, io, thread};
+
+use parity_wasm::elements;
+
+use crate::attestation::Limits;
+use crate::backend::{self, Backend, BackendKind};
+use crate::gas;
+use crate::hash;
+use crate::rules;
+use crate::source_map::SourceMap;
+use crate::stack_height;
+use crate::strip;
+use crate::version;
+
+/// Extension of the files written by `instrument_artifact`, placed before `.wasm`.
+pub const INSTRUMENTED_SUFFIX: &str = "instrumented";
+
+#[derive(Debug)]
+pub enum Error {
+	Io(io::Error),
+	Decoding(elements::Error),
+	UnsupportedVersion { found: u32, supported: u32 },
+	Encoding(elements::Error),
+	/// The backend other than parity-wasm failed to decode or encode the module.
+	Backend(backend::Error),
+	Gas(gas::Error),
+	StackHeight(stack_height::Error),
+}
+
+impl From<version::Error> for Error {
+	fn from(err: version::Error) -> Self {
+		match err {
+			version::Error::UnsupportedVersion { found, supported } => Error::UnsupportedVersion { found, supported },
+			version::Error::Decoding(err) => Error::Decoding(err),
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		use self::Error::*;
+		match self {
+			Io(err) => write!(f, "I/O error: {}", err),
+			Decoding(err) => write!(f, "Decoding error ({}). Must be a valid wasm file", err),
+			UnsupportedVersion { found, supported } =>
+				write!(f, "Unsupported binary format version {}, only version {} is supported", found, supported),
+			Encoding(err) => write!(f, "Encoding error ({})", err),
+			Backend(err) => write!(f, "Backend error ({})", err),
+			Gas(err) => write!(f, "Gas metering failed: {}", err),
+			StackHeight(err) => write!(f, "Stack height limiting failed: {:?}", err),
+		}
+	}
+}
+
+/// Key of an instrumented module in an `ArtifactStore`.
+pub type CacheKey = [u8; 32];
+
+/// Storage of instrumented modules, e.g. a database of a node, used by
+/// `Profile::instrument_all_cached` to skip modules which are already instrumented.
+///
+/// The key covers the original module, the profile and the version of this crate, so an artifact
+/// stays valid as long as it is stored under its key. Errors of the storage backend should be
+/// handled by the store itself, e.g. by treating them as a missing artifact.
+pub trait ArtifactStore {
+	/// The instrumented module stored under `key`, if any.
+	fn get(&self, key: &CacheKey) -> Option<Vec<u8>>;
+
+	/// Store the instrumented module `artifact` under `key`.
+	fn put(&self, key: CacheKey, artifact: Vec<u8>);
+}
+
+/// Instrumentation steps applied by `instrument_artifact`.
+///
+/// The steps run in a fixed order: stripping, gas metering and then stack height limiting. The
+/// allowed imports and the limits don't change the instrumentation, they are only checked by
+/// `attestation::verify_artifact`.
+#[derive(Debug, Clone)]
+pub struct Profile {
+	pub(crate) gas: Option<rules::Set>,
+	pub(crate) gas_module: String,
+	pub(crate) gas_field: String,
+	pub(crate) stack_limit: Option<u32>,
+	pub(crate) strip: Option<bool>,
+	pub(crate) allowed_imports: Option<Vec<(String, String)>>,
+	pub(crate) limits: Limits,
+	pub(crate) backend: BackendKind,
+}
+
+impl Default for Profile {
+	fn default() -> Self {
+		Profile {
+			gas: None,
+			gas_module: "env".into(),
+			gas_field: "gas".into(),
+			stack_limit: None,
+			strip: None,
+			allowed_imports: None,
+			limits: Limits::default(),
+			backend: BackendKind::ParityWasm,
+		}
+	}
+}
+
+impl Profile {
+	/// Profile which leaves the module as it is.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Inject gas metering using the given `rules`.
+	pub fn with_gas(mut self, rules: rules::Set) -> Self {
+		self.gas = Some(rules);
+		self
+	}
+
+	/// Import the gas function from `module` instead of `env`.
+	pub fn with_gas_module(mut self, module: &str) -> Self {
+		self.gas_module = module.into();
+		self
+	}
+
+	/// Import the gas function as `field` instead of `gas`.
+	pub fn with_gas_field(mut self, field: &str) -> Self {
+		self.gas_field = field.into();
+		self
+	}
+
+	/// Inject the stack height limiter with the given limit.
+	pub fn with_stack_limit(mut self, stack_limit: u32) -> Self {
+		self.stack_limit = Some(stack_limit);
+		self
+	}
+
+	/// Remove custom sections and merge duplicate types, keeping the name section if `keep_names` is set.
+	pub fn with_stripping(mut self, keep_names: bool) -> Self {
+		self.strip = Some(keep_names);
+		self
+	}
+
+	/// Allow the artifacts to import `field` from `module`. Any import is allowed unless one is
+	/// allowed this way, except for the gas function, which is always allowed.
+	pub fn with_allowed_import(mut self, module: &str, field: &str) -> Self {
+		self.allowed_imports.get_or_insert_with(Vec::new).push((module.into(), field.into()));
+		self
+	}
+
+	/// Require the artifacts to stay within `limits`.
+	pub fn with_limits(mut self, limits: Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Decode and encode the modules of `instrument_artifact` and `instrument_all` with `backend`
+	/// instead of parity-wasm, e.g. to try the `wasm-tools` backend on some of the modules.
+	pub fn with_backend(mut self, backend: BackendKind) -> Self {
+		self.backend = backend;
+		self
+	}
+
+	/// Run the instrumentation steps of the profile on `module`.
+	pub fn instrument(&self, module: elements::Module) -> Result<elements::Module, Error> {
+		self.instrument_with_source_map(module).map(|(module, _)| module)
+	}
+
+	/// Like `instrument`, but also returns the positions of the original instructions in the
+	/// instrumented function bodies, to translate trap locations back to the original code.
+	pub fn instrument_with_source_map(
+		&self,
+		mut module: elements::Module,
+	) -> Result<(elements::Module, SourceMap), Error> {
+		let mut source_map = None;
+		if let Some(keep_names) = self.strip {
+			strip::strip_custom_sections(&mut module, keep_names);
+			strip::dedup_types(&mut module);
+		}
+		if let Some(rules) = &self.gas {
+			let config = gas::GasConfig::new(&self.gas_module, &self.gas_field);
+			let (metered, report) = gas::inject_gas_counter_with_report(module, rules, config)
+				.map_err(|(_, e)| Error::Gas(e))?;
+			module = metered;
+			source_map = Some(report.source_map);
+		}
+		if let Some(stack_limit) = self.stack_limit {
+			let (limited, stack_map) = stack_height::inject_limiter_with_source_map(
+				module,
+				stack_limit,
+				stack_height::LimiterConfig::new(),
+			).map_err(Error::StackHeight)?;
+			module = limited;
+			source_map = Some(match source_map {
+				Some(source_map) => source_map.then(&stack_map),
+				None => stack_map,
+			});
+		}
+
+		// Without instrumentation, every instruction stays where it is.
+		let source_map = source_map.unwrap_or_else(|| SourceMap {
+			functions: module.code_section()
+				.map_or(&[][..], |code_section| code_section.bodies())
+				.iter()
+				.map(|body| (0..body.code().elements().len()).collect())
+				.collect(),
+		});
+		Ok((module, source_map))
+	}
+
+	/// Run the instrumentation steps of the profile on each of the serialized `modules`.
+	///
+	/// The modules are instrumented in parallel on all available cores, sharing the rules of the
+	/// profile. The results, serialized modules or errors, are in the order of `modules`.
+	pub fn instrument_all<I: Iterator<Item = Vec<u8>>>(&self, modules: I) -> Vec<Result<Vec<u8>, Error>> {
+		in_parallel(modules.collect(), |bytes| self.instrument_bytes(bytes))
+	}
+
+	/// Like `instrument_all`, but takes the modules which are already instrumented from `store`
+	/// and puts the others there.
+	pub fn instrument_all_cached<I, S>(&self, modules: I, store: &S) -> Vec<Result<Vec<u8>, Error>>
+	where
+		I: Iterator<Item = Vec<u8>>,
+		S: ArtifactStore + Sync,
+	{
+		let fingerprint = self.fingerprint();
+		in_parallel(modules.collect(), |bytes| {
+			let key = cache_key(&fingerprint, bytes);
+			if let Some(artifact) = store.get(&key) {
+				return Ok(artifact);
+			}
+			let artifact = self.instrument_bytes(bytes)?;
+			store.put(key, artifact.clone());
+			Ok(artifact)
+		})
+	}
+
+	/// Key of the module `bytes` instrumented with this profile in an `ArtifactStore`.
+	pub fn cache_key(&self, bytes: &[u8]) -> CacheKey {
+		cache_key(&self.fingerprint(), bytes)
+	}
+
+	/// A description of the profile which is the same for all equal profiles.
+	fn fingerprint(&self) -> String {
+		let mut fingerprint = format!(
+			"{} {:?} {:?} {:?} {:?} {:?}",
+			env!("CARGO_PKG_VERSION"),
+			self.gas.as_ref().map(rules::Set::fingerprint),
+			self.gas_module,
+			self.gas_field,
+			self.stack_limit,
+			self.strip,
+		);
+		// The keys of profiles with the default backend stay the same as before backends could be
+		// selected, the others don't share artifacts with them.
+		if self.backend != BackendKind::ParityWasm {
+			fingerprint.push(' ');
+			fingerprint.push_str(self.backend.name());
+		}
+		fingerprint
+	}
+
+	/// Parse a profile from JSON, e.g. for verifiers outside of Rust.
+	///
+	/// ```json
+	/// {
+	///     "gas": { "regular": 1, "instructions": { "div": 16 } },
+	///     "gas_module": "env",
+	///     "gas_field": "gas",
+	///     "stack_limit": 16384,
+	///     "strip": false,
+	///     "allowed_imports": [["env", "log"]],
+	///     "limits": { "max_size": 65536, "max_functions": 1000, "max_memory_pages": 16 },
+	///     "backend": "parity-wasm"
+	/// }
+	/// ```
+	///
+	/// All fields may be left out. `gas` is a rule set as accepted by `rules::Set::from_json`,
+	/// `strip` whether the name section is kept when stripping and `backend` the name of a backend
+	/// enabled by the cargo features, see `BackendKind::from_name`.
+	#[cfg(feature = "rules-file")]
+	pub fn from_json(source: &str) -> Result<Self, rules::LoadError> {
+		let spec: ProfileSpec = serde_json::from_str(source).map_err(rules::LoadError::Json)?;
+		let defaults = Profile::default();
+		Ok(Profile {
+			gas: spec.gas,
+			gas_module: spec.gas_module.unwrap_or(defaults.gas_module),
+			gas_field: spec.gas_field.unwrap_or(defaults.gas_field),
+			stack_limit: spec.stack_limit,
+			strip: spec.strip,
+			allowed_imports: spec.allowed_imports,
+			limits: Limits {
+				max_size: spec.limits.max_size,
+				max_functions: spec.limits.max_functions,
+				max_memory_pages: spec.limits.max_memory_pages,
+			},
+			backend: spec.backend.unwrap_or(defaults.backend),
+		})
+	}
+
+	fn instrument_bytes(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+		if let Some(found) = version::version(bytes).filter(|found| *found != version::SUPPORTED) {
+			return Err(Error::UnsupportedVersion { found, supported: version::SUPPORTED });
+		}
+		let module = self.backend.decode(bytes).map_err(|err| match err {
+			backend::Error::ParityWasm(err) => Error::Decoding(err),
+			err => Error::Backend(err),
+		})?;
+		let module = self.instrument(module)?;
+		self.backend.encode(module).map_err(|err| match err {
+			backend::Error::ParityWasm(err) => Error::Encoding(err),
+			err => Error::Backend(err),
+		})
+	}
+}
+
+#[cfg(feature = "rules-file")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileSpec {
+	#[serde(default)]
+	gas: Option<rules::Set>,
+	#[serde(default)]
+	gas_module: Option<String>,
+	#[serde(default)]
+	gas_field: Option<String>,
+	#[serde(default)]
+	stack_limit: Option<u32>,
+	#[serde(default)]
+	strip: Option<bool>,
+	#[serde(default)]
+	allowed_imports: Option<Vec<(String, String)>>,
+	#[serde(default)]
+	limits: LimitsSpec,
+	#[serde(default, deserialize_with = "deserialize_backend")]
+	backend: Option<BackendKind>,
+}
+
+#[cfg(feature = "rules-file")]
+fn deserialize_backend<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<BackendKind>, D::Error> {
+	let name = <String as serde::Deserialize>::deserialize(deserializer)?;
+	BackendKind::from_name(&name)
+		.map(Some)
+		.ok_or_else(|| serde::de::Error::custom(format!("unknown or disabled backend `{}`", name)))
+}
+
+#[cfg(feature = "rules-file")]
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LimitsSpec {
+	#[serde(default)]
+	max_size: Option<usize>,
+	#[serde(default)]
+	max_functions: Option<usize>,
+	#[serde(default)]
+	max_memory_pages: Option<u32>,
+}
+
+fn cache_key(fingerprint: &str, bytes: &[u8]) -> CacheKey {
+	let mut data = Vec::with_capacity(8 + fingerprint.len() + bytes.len());
+	data.extend_from_slice(&(fingerprint.len() as u64).to_le_bytes());
+	data.extend_from_slice(fingerprint.as_bytes());
+	data.extend_from_slice(bytes);
+	hash::sha256(&data)
+}
+
+/// Apply `f` to each of the `modules` on all available cores, keeping the order of the results.
+fn in_parallel<F>(modules: Vec<Vec<u8>>, f: F) -> Vec<Result<Vec<u8>, Error>>
+where
+	F: Fn(&[u8]) -> Result<Vec<u8>, Error> + Sync,
+{
+	let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(modules.len());
+	let next = AtomicUsize::new(0);
+	let results = Mutex::new((0..modules.len()).map(|_| None).collect::<Vec<_>>());
+
+	thread::scope(|scope| {
+		for _ in 0..threads {
+			scope.spawn(|| loop {
+				let index = next.fetch_add(1, Ordering::Relaxed);
+				let bytes = match modules.get(index) {
+					Some(bytes) => bytes,
+					None => break,
+				};
+				let result = f(bytes);
+				results.lock().expect("no thread panics while holding the lock; qed")[index] = Some(result);
+			});
+		}
+	});
+
+	results.into_inner()
+		.expect("no thread panics while holding the lock; qed")
+		.into_iter()
+		.map(|result| result.expect("every module is instrumented by one of the threads; qed"))
+		.collect()
+}
+
+/// Path of the instrumented counterpart of the artifact at `path`.
+///
+/// `contract.wasm` becomes `contract.instrumented.wasm` in the same directory.
+pub fn instrumented_path(path: &Path) -> PathBuf {
+	let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+	path.with_file_name(format!("{}.{}.wasm", stem, INSTRUMENTED_SUFFIX))
+}
+
+/// Instrument the wasm artifact at `path` according to the `profile`.
+///
+/// The result is written next to the artifact (see `instrumented_path`) and its path is
+/// returned. Build scripts are told to rerun if the artifact changes.
+pub fn instrument_artifact<P: AsRef<Path>>(path: P, profile: &Profile) -> Result<PathBuf, Error> {
+	let path = path.as_ref();
+	println!("cargo:rerun-if-changed={}", path.display());
+
+	let bytes = fs::read(path).map_err(Error::Io)?;
+	let bytes = profile.instrument_bytes(&bytes)?;
+
+	let output = instrumented_path(path);
+	fs::write(&output, bytes).map_err(Error::Io)?;
+
+	Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempdir::TempDir;
+
+	#[test]
+	fn instruments_artifact() {
+		let dir = TempDir::new("build_support").expect("create temp dir failed");
+		let artifact = dir.path().join("contract.wasm");
+		let wasm = wabt::wat2wasm(r#"
+			(module
+				(func (export "call")
+					i32.const 1
+					drop))
+		"#).unwrap();
+		fs::write(&artifact, wasm).unwrap();
+
+		let profile = Profile::new()
+			.with_gas(rules::Set::default())
+			.with_gas_module("host")
+			.with_stack_limit(1024)
+			.with_stripping(false);
+		let output = instrument_artifact(&artifact, &profile).unwrap();
+
+		assert_eq!(output, dir.path().join("contract.instrumented.wasm"));
+		let module = parity_wasm::deserialize_file(&output).unwrap();
+		let import = &module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("host", "gas"));
+		assert_eq!(module.global_section().map(|s| s.entries().len()), Some(1));
+	}
+	#[test]
+	fn source_map() {
+		let module = elements::deserialize_buffer(&wabt::wat2wasm(r#"
+			(module
+				(func $callee (result i32)
+					i32.const 1)
+				(func (export "call") (result i32)
+					call $callee))
+		"#).unwrap()).unwrap();
+
+		let profile = Profile::new()
+			.with_gas(rules::Set::default())
+			.with_stack_limit(1024);
+		let (module, source_map) = profile.instrument_with_source_map(module).unwrap();
+
+		// The gas function is imported first, so the callee is function 1.
+		let pos = source_map.translate(1, 0).unwrap();
+		let body = module.code_section().unwrap().bodies()[1].code().elements();
+		assert_eq!(body[pos], elements::Instruction::Call(1));
+		assert_eq!(source_map.original(1, 0), Some(0));
+	}
+
+	#[test]
+	fn instruments_all() {
+		let wasm = wabt::wat2wasm(r#"
+			(module
+				(func (export "call")
+					i32.const 1
+					drop))
+		"#).unwrap();
+		let modules = vec![wasm.clone(), b"not wasm".to_vec(), wasm];
+
+		let profile = Profile::new().with_gas(rules::Set::default());
+		let results = profile.instrument_all(modules.into_iter());
+
+		assert_eq!(results.len(), 3);
+		assert!(matches!(results[1], Err(Error::Decoding(_))));
+		for result in &[&results[0], &results[2]] {
+			let module: elements::Module = elements::deserialize_buffer(result.as_ref().unwrap()).unwrap();
+			assert_eq!(module.import_count(elements::ImportCountType::Function), 1);
+		}
+	}
+	#[cfg(all(feature = "wasm-tools", feature = "rules-file"))]
+	#[test]
+	fn backends_agree() {
+		let modules = ["gas", "stack-height"].iter()
+			.flat_map(|dir| fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(dir)).unwrap())
+			.map(|entry| wabt::wat2wasm(fs::read(entry.unwrap().path()).unwrap()).unwrap())
+			.collect::<Vec<_>>();
+		let profile = Profile::from_json(r#"{ "gas": { "regular": 1 }, "stack_limit": 1024 }"#).unwrap();
+		let wasm_tools = Profile::from_json(r#"{ "gas": { "regular": 1 }, "stack_limit": 1024, "backend": "wasm-tools" }"#)
+			.unwrap();
+		assert!(Profile::from_json(r#"{ "backend": "binaryen" }"#).is_err());
+		assert_ne!(profile.cache_key(&modules[0]), wasm_tools.cache_key(&modules[0]));
+
+		let expected = profile.instrument_all(modules.iter().cloned());
+		let results = wasm_tools.instrument_all(modules.into_iter());
+
+		for (result, expected) in results.iter().zip(&expected) {
+			let (result, expected) = (result.as_ref().unwrap(), expected.as_ref().unwrap());
+			assert_eq!(result, expected);
+			assert_eq!(
+				backend::WasmTools.decode(result).unwrap(),
+				backend::ParityWasm.decode(expected).unwrap(),
+			);
+		}
+	}
+
+	#[test]
+	fn instruments_all_cached() {
+		#[derive(Default)]
+		struct Store(Mutex<std::collections::HashMap<CacheKey, Vec<u8>>>);
+
+		impl ArtifactStore for Store {
+			fn get(&self, key: &CacheKey) -> Option<Vec<u8>> {
+				self.0.lock().unwrap().get(key).cloned()
+			}
+
+			fn put(&self, key: CacheKey, artifact: Vec<u8>) {
+				self.0.lock().unwrap().insert(key, artifact);
+			}
+		}
+
+		let first = wabt::wat2wasm("(module (func (export \"call\")))").unwrap();
+		let second = wabt::wat2wasm("(module (func (export \"deploy\")))").unwrap();
+		let profile = Profile::new().with_gas(rules::Set::default());
+		let store = Store::default();
+		store.put(profile.cache_key(&first), b"cached".to_vec());
+
+		let results = profile.instrument_all_cached(vec![first, second.clone()].into_iter(), &store);
+
+		assert_eq!(results[0].as_ref().unwrap(), b"cached");
+		assert_eq!(store.get(&profile.cache_key(&second)).as_ref(), results[1].as_ref().ok());
+		let other_profile = Profile::new().with_gas(rules::Set::default().with_grow_cost(1));
+		assert!(store.get(&other_profile.cache_key(&second)).is_none());
+	}
+}
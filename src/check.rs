@@ -0,0 +1,250 @@
+//! Checking that a module only refers to entities it has, before passes rely on it.
+//!
+//! parity-wasm decodes modules without validating them, so a module can refer to functions,
+//! types, globals or locals which don't exist, or have blocks which aren't properly nested. The
+//! passes assume well-formed modules and may panic on such ones. The passes run on untrusted code
+//! can be configured to call `well_formed` first and return its error instead, see
+//! `GasConfig::with_checks` and `LimiterConfig::with_checks`, which the pipeline follows as well.
+//! `inject_gas_counter_streaming` doesn't hold the function bodies in memory and is not covered.
+//!
+//! The check does not validate the types of the instructions, which the passes don't rely on.
+
+use crate::std::fmt;
+
+use parity_wasm::elements::{self, Instruction, Internal, Type};
+
+use crate::visit::{self, Frames, Visitor};
+
+/// Index space of an index which refers to nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+	Type,
+	Function,
+	Table,
+	Memory,
+	Global,
+	Local,
+	Label,
+}
+
+/// Error of `well_formed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+	/// `index` refers to nothing in the index space, at the given function and position in its
+	/// body if it is in one.
+	UnknownIndex { space: Space, index: u32, at: Option<(u32, usize)> },
+	/// The function section declares a different number of functions than there are bodies.
+	FunctionCount { declared: usize, bodies: usize },
+	/// The blocks of the body of the function aren't properly nested, at the given position.
+	MalformedBody { function: u32, pos: usize },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::UnknownIndex { space, index, at: None } =>
+				write!(f, "Unknown {:?} index {}", space, index),
+			Error::UnknownIndex { space, index, at: Some((function, pos)) } =>
+				write!(f, "Unknown {:?} index {} (function {}, instruction {})", space, index, function, pos),
+			Error::FunctionCount { declared, bodies } =>
+				write!(f, "{} functions are declared, but there are {} bodies", declared, bodies),
+			Error::MalformedBody { function, pos } =>
+				write!(f, "Malformed control flow (function {}, instruction {})", function, pos),
+		}
+	}
+}
+
+/// Sizes of the index spaces of a module.
+struct Spaces {
+	types: usize,
+	functions: usize,
+	tables: usize,
+	memories: usize,
+	globals: usize,
+	imported_globals: usize,
+}
+
+impl Spaces {
+	fn check(&self, space: Space, index: u32) -> Result<(), Error> {
+		let len = match space {
+			Space::Type => self.types,
+			Space::Function => self.functions,
+			Space::Table => self.tables,
+			Space::Memory => self.memories,
+			Space::Global => self.globals,
+			// Checked against the function body instead.
+			Space::Local | Space::Label => 0,
+		};
+		if (index as usize) < len {
+			Ok(())
+		} else {
+			Err(Error::UnknownIndex { space, index, at: None })
+		}
+	}
+
+	/// Check the instructions of an initializer expression, which can only refer to imported
+	/// globals.
+	fn check_init_expr(&self, init_expr: &elements::InitExpr) -> Result<(), Error> {
+		for instruction in init_expr.code() {
+			if let Instruction::GetGlobal(index) = *instruction {
+				if index as usize >= self.imported_globals {
+					return Err(Error::UnknownIndex { space: Space::Global, index, at: None });
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Check that all indices of the module refer to existing entities and that the blocks of all
+/// function bodies are properly nested.
+pub fn well_formed(module: &elements::Module) -> Result<(), Error> {
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let functions = module.function_section().map_or(&[][..], |function_section| function_section.entries());
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	if functions.len() != bodies.len() {
+		return Err(Error::FunctionCount { declared: functions.len(), bodies: bodies.len() });
+	}
+
+	let spaces = Spaces {
+		types: types.len(),
+		functions: module.functions_space(),
+		tables: module.table_space(),
+		memories: module.memory_space(),
+		globals: module.globals_space(),
+		imported_globals: module.import_count(elements::ImportCountType::Global),
+	};
+
+	for entry in module.import_section().map_or(&[][..], |import_section| import_section.entries()) {
+		if let elements::External::Function(type_ref) = *entry.external() {
+			spaces.check(Space::Type, type_ref)?;
+		}
+	}
+	for func in functions {
+		spaces.check(Space::Type, func.type_ref())?;
+	}
+	for global in module.global_section().map_or(&[][..], |global_section| global_section.entries()) {
+		spaces.check_init_expr(global.init_expr())?;
+	}
+	for entry in module.export_section().map_or(&[][..], |export_section| export_section.entries()) {
+		match *entry.internal() {
+			Internal::Function(index) => spaces.check(Space::Function, index)?,
+			Internal::Table(index) => spaces.check(Space::Table, index)?,
+			Internal::Memory(index) => spaces.check(Space::Memory, index)?,
+			Internal::Global(index) => spaces.check(Space::Global, index)?,
+		}
+	}
+	if let Some(start) = module.start_section() {
+		spaces.check(Space::Function, start)?;
+	}
+	for segment in module.elements_section().map_or(&[][..], |elements_section| elements_section.entries()) {
+		if let Some(offset) = segment.offset() {
+			spaces.check(Space::Table, segment.index())?;
+			spaces.check_init_expr(offset)?;
+		}
+		for member in segment.members() {
+			spaces.check(Space::Function, *member)?;
+		}
+	}
+	for segment in module.data_section().map_or(&[][..], |data_section| data_section.entries()) {
+		if let Some(offset) = segment.offset() {
+			spaces.check(Space::Memory, segment.index())?;
+			spaces.check_init_expr(offset)?;
+		}
+	}
+
+	let imported_funcs = module.import_count(elements::ImportCountType::Function);
+	for (index, (func, body)) in functions.iter().zip(bodies).enumerate() {
+		let function = (imported_funcs + index) as u32;
+		let params = match types.get(func.type_ref() as usize) {
+			Some(Type::Function(ty)) => ty.params().len() as u64,
+			None => 0,
+		};
+		let locals = body.locals().iter().map(|local| u64::from(local.count())).sum::<u64>();
+		let mut checker = BodyChecker { spaces: &spaces, locals: params + locals };
+		visit::visit(body.code().elements(), &mut checker).map_err(|e| match e {
+			visit::Error::Visitor((space, index, pos)) => Error::UnknownIndex { space, index, at: Some((function, pos)) },
+			visit::Error::UnexpectedElse(pos) | visit::Error::TrailingInstruction(pos) =>
+				Error::MalformedBody { function, pos },
+			visit::Error::UnclosedBlocks => Error::MalformedBody { function, pos: body.code().elements().len() },
+		})?;
+	}
+
+	Ok(())
+}
+
+/// Checks the indices of the instructions of a function body.
+struct BodyChecker<'a> {
+	spaces: &'a Spaces,
+	/// Number of parameters and declared locals.
+	locals: u64,
+}
+
+impl<'a> BodyChecker<'a> {
+	fn check(&self, space: Space, index: u32, pos: usize) -> Result<(), (Space, u32, usize)> {
+		self.spaces.check(space, index).map_err(|_| (space, index, pos))
+	}
+}
+
+impl<'a> Visitor for BodyChecker<'a> {
+	/// The index space, the index and the position of an unknown index.
+	type Error = (Space, u32, usize);
+
+	fn visit_instruction(&mut self, pos: usize, instruction: &Instruction, frames: &Frames) -> Result<(), Self::Error> {
+		use parity_wasm::elements::Instruction::*;
+
+		let label = |label: u32| {
+			if frames.target_index(label).is_some() { Ok(()) } else { Err((Space::Label, label, pos)) }
+		};
+		match instruction {
+			Call(index) => self.check(Space::Function, *index, pos),
+			CallIndirect(type_ref, table) => {
+				self.check(Space::Type, *type_ref, pos)?;
+				self.check(Space::Table, u32::from(*table), pos)
+			}
+			GetLocal(index) | SetLocal(index) | TeeLocal(index) if u64::from(*index) >= self.locals =>
+				Err((Space::Local, *index, pos)),
+			GetGlobal(index) | SetGlobal(index) => self.check(Space::Global, *index, pos),
+			Br(index) | BrIf(index) => label(*index),
+			BrTable(table) => table.table.iter().chain(Some(&table.default)).try_for_each(|index| label(*index)),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::builder;
+
+	fn module_with_body(instructions: Vec<Instruction>) -> elements::Module {
+		builder::module()
+			.global().value_type().i32().init_expr(Instruction::I32Const(0)).build()
+			.function()
+				.signature().with_param(elements::ValueType::I32).build()
+				.body().with_instructions(elements::Instructions::new(instructions)).build()
+				.build()
+			.build()
+	}
+
+	#[test]
+	fn unknown_indices() {
+		use parity_wasm::elements::Instruction::*;
+
+		let well_formed_body = vec![GetLocal(0), SetGlobal(0), Block(elements::BlockType::NoResult), Br(1), End, End];
+		assert_eq!(well_formed(&module_with_body(well_formed_body)), Ok(()));
+
+		let unknown = |instruction, space, index| {
+			let module = module_with_body(vec![instruction, End]);
+			assert_eq!(well_formed(&module), Err(Error::UnknownIndex { space, index, at: Some((0, 0)) }));
+		};
+		unknown(Call(1), Space::Function, 1);
+		unknown(CallIndirect(0, 0), Space::Table, 0);
+		unknown(GetLocal(1), Space::Local, 1);
+		unknown(GetGlobal(1), Space::Global, 1);
+		unknown(Br(1), Space::Label, 1);
+
+		let unbalanced = module_with_body(vec![Block(elements::BlockType::NoResult), End]);
+		assert_eq!(well_formed(&unbalanced), Err(Error::MalformedBody { function: 0, pos: 2 }));
+	}
+}
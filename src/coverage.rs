@@ -0,0 +1,223 @@
+//! The pass that records which metered blocks of a module are executed, for code coverage of
+//! contracts.
+//!
+//! Every metered block, as divided by the gas metering with the same rules, is given an ID in the
+//! order of the function bodies. On entry of the block, the `i32` counter of the ID in a region of
+//! the memory reserved by the caller is incremented, wrapping around. The region holds the
+//! counters of all IDs in order, 4 bytes each, and must be covered by the initial size of the
+//! memory, so that the counters never trap. The host reads the region after running the module.
+//!
+//! The custom section `SECTION_NAME` added to the module maps the IDs back to the functions and
+//! the positions of the blocks in their original bodies, see `CoverageMap`. No function indices
+//! change.
+
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Instruction};
+
+use crate::gas::{self, GasConfig};
+use crate::memory_peak::initial_pages;
+use crate::repair::{read_var_u32, write_var_u32};
+use crate::rules::Rules;
+
+/// Name of the custom section holding the coverage map.
+pub const SECTION_NAME: &str = "coverage";
+
+/// Size of a page of memory, in bytes.
+const PAGE_SIZE: u64 = 65536;
+
+/// Error of the coverage instrumentation.
+#[derive(Debug)]
+pub enum Error {
+	/// The metered blocks can't be determined.
+	Gas(gas::Error),
+	/// The module neither defines nor imports a memory.
+	NoMemory,
+	/// The counters end at `end`, past the initial size of the memory, `size` bytes.
+	OutOfBounds { end: u64, size: u64 },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Gas(e) => write!(f, "{}", e),
+			Error::NoMemory => write!(f, "Module has no memory"),
+			Error::OutOfBounds { end, size } =>
+				write!(f, "Counters end at {}, past the initial memory of {} bytes", end, size),
+		}
+	}
+}
+
+/// Mapping of the block IDs to the blocks they count.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageMap {
+	/// Address of the counter of the first block.
+	pub region: u32,
+	/// Function index and position in the original function body of the first instruction of
+	/// each block, by ID.
+	pub blocks: Vec<(u32, u32)>,
+}
+
+impl CoverageMap {
+	/// Address of the counter of the block with the given ID.
+	pub fn address(&self, id: u32) -> u64 {
+		u64::from(self.region) + 4 * u64::from(id)
+	}
+
+	/// Encode the map as the region address and the number of blocks followed by the function
+	/// index and position of each, all as unsigned LEB128 integers.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		write_var_u32(&mut bytes, self.region);
+		write_var_u32(&mut bytes, self.blocks.len() as u32);
+		for &(function, pos) in &self.blocks {
+			write_var_u32(&mut bytes, function);
+			write_var_u32(&mut bytes, pos);
+		}
+		bytes
+	}
+
+	/// Decode a map encoded by `to_bytes`.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+		let mut pos = 0;
+		let region = read_var_u32(bytes, &mut pos)?;
+		let count = read_var_u32(bytes, &mut pos)?;
+		let blocks = (0..count)
+			.map(|_| Some((read_var_u32(bytes, &mut pos)?, read_var_u32(bytes, &mut pos)?)))
+			.collect::<Option<Vec<_>>>()?;
+		if pos != bytes.len() {
+			return None;
+		}
+		Some(CoverageMap { region, blocks })
+	}
+
+	/// The map embedded into the `module` by `inject_coverage`, if there is a valid one.
+	pub fn from_module(module: &elements::Module) -> Option<Self> {
+		module.custom_sections()
+			.find(|section| section.name() == SECTION_NAME)
+			.and_then(|section| CoverageMap::from_bytes(section.payload()))
+	}
+}
+
+/// Append the instructions incrementing the counter at `address`.
+fn increment(instructions: &mut Vec<Instruction>, address: u32) {
+	instructions.extend(vec![
+		Instruction::I32Const(0),
+		Instruction::I32Const(0),
+		Instruction::I32Load(2, address),
+		Instruction::I32Const(1),
+		Instruction::I32Add,
+		Instruction::I32Store(2, address),
+	]);
+}
+
+/// Instrument a module to count the executions of its metered blocks in the memory region
+/// starting at `region`.
+///
+/// The metered blocks are the ones `inject_gas_counter` charges with the default `GasConfig`.
+/// Returns the module along with the map of the block IDs, which is embedded into it as well. See
+/// module-level documentation for more details.
+///
+/// The function fails if the module has no memory, if the counters don't fit into its initial
+/// size or if the gas metering fails, returning the original module along with the `Error`.
+pub fn inject_coverage<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	region: u32,
+)
+	-> Result<(elements::Module, CoverageMap), (elements::Module, Error)>
+{
+	let size = match initial_pages(&module) {
+		Some(pages) => u64::from(pages) * PAGE_SIZE,
+		None => return Err((module, Error::NoMemory)),
+	};
+	let metered_blocks = match gas::plan_metering(&module, rules, &GasConfig::default(), &|_| true) {
+		Ok(metered_blocks) => metered_blocks,
+		Err(e) => return Err((module, Error::Gas(e))),
+	};
+
+	let imported_funcs = module.import_count(elements::ImportCountType::Function) as u32;
+	let mut map = CoverageMap { region, blocks: Vec::new() };
+	for (index, blocks) in metered_blocks.iter().enumerate() {
+		let function = imported_funcs + index as u32;
+		map.blocks.extend(blocks.iter().map(|block| (function, block.start_pos as u32)));
+	}
+	let end = map.address(map.blocks.len() as u32);
+	if end > size {
+		return Err((module, Error::OutOfBounds { end, size }));
+	}
+
+	let mut module = module;
+	let mut id = 0;
+	if let Some(code_section) = module.code_section_mut() {
+		for (func_body, blocks) in code_section.bodies_mut().iter_mut().zip(metered_blocks) {
+			let original = func_body.code_mut().elements_mut();
+			let mut instructions = Vec::with_capacity(original.len() + 6 * blocks.len());
+			let mut blocks = blocks.into_iter().peekable();
+			for (pos, instruction) in original.drain(..).enumerate() {
+				if blocks.next_if(|block| block.start_pos == pos).is_some() {
+					increment(&mut instructions, map.address(id) as u32);
+					id += 1;
+				}
+				instructions.push(instruction);
+			}
+			*original = instructions;
+		}
+	}
+	module.set_custom_section(SECTION_NAME, map.to_bytes());
+
+	Ok((module, map))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+	use parity_wasm::elements::Instruction::*;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn counts_blocks() {
+		let source = r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(memory 1)
+				(func (param i32)
+					get_local 0
+					if
+						call $ext
+					end)
+				(func
+					nop))
+		"#;
+		let rules = rules::Set::default();
+
+		let (module, map) = inject_coverage(parse_wat(source), &rules, 65524).unwrap();
+
+		assert_eq!(map, CoverageMap { region: 65524, blocks: vec![(1, 0), (1, 2), (2, 0)] });
+		assert_eq!(
+			module.code_section().unwrap().bodies()[0].code().elements(),
+			&[
+				I32Const(0), I32Const(0), I32Load(2, 65524), I32Const(1), I32Add, I32Store(2, 65524),
+				GetLocal(0),
+				If(elements::BlockType::NoResult),
+					I32Const(0), I32Const(0), I32Load(2, 65528), I32Const(1), I32Add, I32Store(2, 65528),
+					Call(0),
+				End,
+				End,
+			][..]
+		);
+		assert_eq!(CoverageMap::from_module(&module), Some(map));
+
+		let binary = elements::serialize(module).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+
+		// The counter of the third block would end past the first page.
+		match inject_coverage(parse_wat(source), &rules, 65528) {
+			Err((_, Error::OutOfBounds { end: 65540, size: 65536 })) => {}
+			other => panic!("Unexpected result {:?}", other.map(|(_, map)| map)),
+		}
+		assert!(matches!(inject_coverage(parse_wat("(module)"), &rules, 0), Err((_, Error::NoMemory))));
+	}
+}
@@ -0,0 +1,121 @@
+//! Names of the entities injected by the passes, for debugging instrumented modules.
+//!
+//! By default the passes only name the functions they inject if the module has a name section, the
+//! gas function after its import and the function replacing `memory.grow` as `grow_counter`. With
+//! `DebugNames` configured, the injected entities are named as below, adding the name section if
+//! needed, and optionally exported under these names, so that traces and profiles of development
+//! runtimes are readable. This changes the output of the passes, so it is off by default to keep
+//! production builds byte for byte stable.
+//!
+//! parity-wasm can't represent names of globals, so the stack height global is only named by its
+//! export.
+
+use parity_wasm::elements;
+
+/// Name of the imported gas function.
+pub const GAS_IMPORT: &str = "__gas_import";
+/// Name of the function charging for `memory.grow`.
+pub const GROW_COUNTER: &str = "__grow_counter";
+/// Name of the global holding the current stack height.
+pub const STACK_DEPTH: &str = "__stack_depth";
+
+/// How the passes name the entities they inject.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DebugNames {
+	/// Whether the injected entities are exported under their names as well. Exporting the stack
+	/// height global requires the mutable globals extension.
+	pub export: bool,
+}
+
+impl DebugNames {
+	pub fn new() -> Self {
+		DebugNames { export: false }
+	}
+
+	/// Export the injected entities under their names as well.
+	pub fn with_exports(mut self) -> Self {
+		self.export = true;
+		self
+	}
+
+	/// Name the injected `entity` as `name` and export it if configured. Nothing is exported if
+	/// the name is already taken.
+	///
+	/// The name section of the module, if any, must be parsed already.
+	pub(crate) fn apply(&self, module: &mut elements::Module, name: &str, entity: elements::Internal) {
+		if let elements::Internal::Function(index) = entity {
+			if module.names_section().is_none() {
+				module.insert_section(elements::Section::Name(elements::NameSection::new(None, None, None)))
+					.expect("custom sections can always be inserted; qed");
+			}
+			let name_section = module.names_section_mut().expect("the name section is added above; qed");
+			name_section.functions_mut()
+				.get_or_insert_with(Default::default)
+				.names_mut()
+				.insert(index, name.into());
+		}
+
+		if !self.export {
+			return;
+		}
+		let exports = module.export_section().map_or(&[][..], |section| section.entries());
+		if exports.iter().any(|entry| entry.field() == name) {
+			return;
+		}
+		let entry = elements::ExportEntry::new(name.into(), entity);
+		match module.export_section_mut() {
+			Some(section) => section.entries_mut().push(entry),
+			None => module.insert_section(elements::Section::Export(elements::ExportSection::with_entries(vec![entry])))
+				.expect("there is no export section yet; qed"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{rules, GasConfig};
+	use crate::stack_height::{self, LimiterConfig};
+	use crate::testing::parse_wat;
+
+	fn instrument(debug: Option<DebugNames>) -> elements::Module {
+		let module = parse_wat(r#"
+		(module
+			(memory 1)
+			(func (export "call") (param i32) (result i32)
+				(memory.grow (local.get 0))))
+		"#);
+		let mut gas_config = GasConfig::default();
+		let mut limiter_config = LimiterConfig::new();
+		if let Some(debug) = debug {
+			gas_config = gas_config.with_debug_names(debug);
+			limiter_config = limiter_config.with_debug_names(debug);
+		}
+		let module = crate::inject_gas_counter(module, &rules::Set::default().with_grow_cost(1), gas_config).unwrap();
+		stack_height::inject_limiter_with_config(module, 1024, limiter_config).unwrap()
+	}
+
+	fn export_names(module: &elements::Module) -> Vec<&str> {
+		module.export_section().unwrap().entries().iter().map(|entry| entry.field()).collect()
+	}
+
+	#[test]
+	fn names_and_exports() {
+		let module = instrument(Some(DebugNames::new().with_exports()));
+		let names = module.names_section().unwrap().functions().as_ref().unwrap().names();
+		assert_eq!(names.get(0).map(String::as_str), Some(GAS_IMPORT));
+		assert_eq!(names.get(2).map(String::as_str), Some(GROW_COUNTER));
+		assert_eq!(export_names(&module), vec!["call", GAS_IMPORT, GROW_COUNTER, STACK_DEPTH]);
+
+		let module = instrument(Some(DebugNames::new()));
+		assert!(module.names_section().is_some());
+		assert_eq!(export_names(&module), vec!["call"]);
+	}
+
+	#[test]
+	fn off_by_default() {
+		let module = instrument(None);
+		assert!(module.names_section().is_none());
+		assert_eq!(export_names(&module), vec!["call"]);
+	}
+}
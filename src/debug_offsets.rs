@@ -0,0 +1,255 @@
+//! Translation of code offsets for the debug information of instrumented modules.
+//!
+//! DWARF debug information of WebAssembly modules refers to code by its offset from the start of
+//! the code section payload. Gas metering inserts instructions and re-encodes the module, so these
+//! offsets don't match the instrumented module anymore. Rewriting the `.debug_*` sections requires
+//! a full DWARF implementation, so this module computes a table translating the offsets instead,
+//! which debuggers and symbolizers can apply to the addresses they read.
+
+use std::io::Cursor;
+
+use parity_wasm::elements::{self, Deserialize, Instruction};
+
+use crate::repair::{read_var_u32, write_var_u32};
+
+/// Name of the custom section holding an embedded offset table.
+pub const SECTION_NAME: &str = "code_offsets";
+
+const CODE_SECTION: u8 = 10;
+
+/// Error of the computation of an offset table.
+#[derive(Debug)]
+pub enum Error {
+	/// The code section of a module can't be decoded.
+	Decoding(elements::Error),
+	/// The code sections of the modules have a different number of function bodies, so the
+	/// instrumented module is not the original one with gas metering.
+	Mismatch,
+}
+
+impl From<elements::Error> for Error {
+	fn from(err: elements::Error) -> Self {
+		Error::Decoding(err)
+	}
+}
+
+/// Translation of code offsets of a module to the module instrumented from it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OffsetTable {
+	/// Pairs of original and instrumented offsets of the starts of the function bodies, of their
+	/// instructions and of their ends, sorted by original offset.
+	pub entries: Vec<(u32, u32)>,
+}
+
+impl OffsetTable {
+	/// Translate the original `offset`, e.g. an address in the DWARF line table.
+	///
+	/// An offset within an instruction is translated relative to the start of the instruction.
+	/// Returns `None` for offsets before the first function body.
+	pub fn translate(&self, offset: u32) -> Option<u32> {
+		let index = match self.entries.binary_search_by_key(&offset, |&(original, _)| original) {
+			Ok(index) => index,
+			Err(index) => index.checked_sub(1)?,
+		};
+		let (original, instrumented) = self.entries[index];
+		Some(instrumented + (offset - original))
+	}
+
+	/// Encode the table as the number of entries followed by the offset pairs, all as unsigned
+	/// LEB128 integers.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		write_var_u32(&mut bytes, self.entries.len() as u32);
+		for &(original, instrumented) in &self.entries {
+			write_var_u32(&mut bytes, original);
+			write_var_u32(&mut bytes, instrumented);
+		}
+		bytes
+	}
+
+	/// Decode a table encoded by `to_bytes`.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+		let mut pos = 0;
+		let count = read_var_u32(bytes, &mut pos)?;
+		let entries = (0..count)
+			.map(|_| Some((read_var_u32(bytes, &mut pos)?, read_var_u32(bytes, &mut pos)?)))
+			.collect::<Option<Vec<_>>>()?;
+		if pos != bytes.len() {
+			return None;
+		}
+		Some(OffsetTable { entries })
+	}
+
+	/// Embed the table into the `module` as the custom section `SECTION_NAME`.
+	///
+	/// Custom sections follow the code section, so this doesn't change the offsets.
+	pub fn embed(&self, module: &mut elements::Module) {
+		module.set_custom_section(SECTION_NAME, self.to_bytes());
+	}
+}
+
+/// A decoded function body with the offsets of its parts.
+struct Body {
+	/// Offset of the local declarations.
+	start: u32,
+	/// Instructions with their offsets.
+	instructions: Vec<(u32, Instruction)>,
+	/// Offset following the body.
+	end: u32,
+}
+
+/// Decode the function bodies of the code section of the module `bytes`, keeping the offsets of
+/// their instructions as encoded, LEB128 padding included.
+fn bodies(bytes: &[u8]) -> Result<Vec<Body>, Error> {
+	let malformed = || Error::Decoding(elements::Error::UnexpectedEof);
+
+	// Skip the magic number and the version.
+	let mut pos = 8;
+	let payload = loop {
+		let id = *bytes.get(pos).ok_or(Error::Decoding(elements::Error::Other("no code section")))?;
+		pos += 1;
+		let size = read_var_u32(bytes, &mut pos).ok_or_else(malformed)? as usize;
+		let end = pos.checked_add(size).filter(|end| *end <= bytes.len()).ok_or_else(malformed)?;
+		if id == CODE_SECTION {
+			break &bytes[pos..end];
+		}
+		pos = end;
+	};
+
+	let mut pos = 0;
+	let count = read_var_u32(payload, &mut pos).ok_or_else(malformed)?;
+	let mut bodies = Vec::new();
+	for _ in 0..count {
+		let size = read_var_u32(payload, &mut pos).ok_or_else(malformed)? as usize;
+		let start = pos;
+		let end = pos.checked_add(size).filter(|end| *end <= payload.len()).ok_or_else(malformed)?;
+
+		let local_groups = read_var_u32(payload, &mut pos).ok_or_else(malformed)?;
+		for _ in 0..local_groups {
+			read_var_u32(payload, &mut pos).ok_or_else(malformed)?;
+			// The value type.
+			pos += 1;
+		}
+
+		let mut cursor = Cursor::new(payload.get(pos..end).ok_or_else(malformed)?);
+		let mut instructions = Vec::new();
+		while (cursor.position() as usize) < end - pos {
+			let offset = (pos + cursor.position() as usize) as u32;
+			instructions.push((offset, Instruction::deserialize(&mut cursor)?));
+		}
+
+		bodies.push(Body { start: start as u32, instructions, end: end as u32 });
+		pos = end;
+	}
+	Ok(bodies)
+}
+
+/// Whether the `instrumented` instruction is the `original` one, where the gas function was
+/// imported as `gas_func`.
+fn is_instrumented(original: &Instruction, instrumented: &Instruction, gas_func: u32) -> bool {
+	match (original, instrumented) {
+		(Instruction::Call(original), Instruction::Call(instrumented)) =>
+			*instrumented == if *original >= gas_func { original + 1 } else { *original },
+		// `memory.grow` is replaced by a call to the grow counter.
+		(Instruction::GrowMemory(_), Instruction::Call(_)) => true,
+		_ => original == instrumented,
+	}
+}
+
+/// Whether a charge was inserted at `pos` of the instrumented `instructions`.
+fn is_charge(instructions: &[(u32, Instruction)], pos: usize, gas_func: u32) -> bool {
+	match instructions.get(pos..pos + 2) {
+		Some([(_, Instruction::I32Const(_)), (_, Instruction::Call(func))])
+		| Some([(_, Instruction::I64Const(_)), (_, Instruction::Call(func))]) => *func == gas_func,
+		_ => false,
+	}
+}
+
+/// Compute the offset table from the `original` module to the `instrumented` one produced from it
+/// by `inject_gas_counter`, both given as encoded.
+///
+/// Instructions which were removed by the instrumentation, e.g. folded charges, are translated to
+/// the instruction following them.
+pub fn gas_offset_table(original: &[u8], instrumented: &[u8]) -> Result<OffsetTable, Error> {
+	let module = elements::deserialize_buffer::<elements::Module>(original)?;
+	let gas_func = module.import_count(elements::ImportCountType::Function) as u32;
+
+	let original = bodies(original)?;
+	let instrumented = bodies(instrumented)?;
+	// The grow counter is appended to the functions.
+	if instrumented.len() < original.len() {
+		return Err(Error::Mismatch);
+	}
+
+	let mut entries = Vec::new();
+	for (original, instrumented) in original.iter().zip(&instrumented) {
+		entries.push((original.start, instrumented.start));
+
+		let mut pos = 0;
+		for (offset, instruction) in &original.instructions {
+			while is_charge(&instrumented.instructions, pos, gas_func) {
+				pos += 2;
+			}
+			match instrumented.instructions.get(pos) {
+				Some((translated, candidate)) if is_instrumented(instruction, candidate, gas_func) => {
+					entries.push((*offset, *translated));
+					pos += 1;
+				}
+				Some((translated, _)) => entries.push((*offset, *translated)),
+				None => entries.push((*offset, instrumented.end)),
+			}
+		}
+
+		entries.push((original.end, instrumented.end));
+	}
+
+	Ok(OffsetTable { entries })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::rules;
+
+	#[test]
+	fn translates_offsets() {
+		let original = wabt::wat2wasm(r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(func (param i32)
+					get_local 0
+					if
+						call $ext
+					end)
+				(func
+					nop))
+		"#).unwrap();
+		let module = elements::deserialize_buffer(&original).unwrap();
+		let instrumented = crate::inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+		let mut instrumented_module = instrumented.clone();
+		let instrumented = elements::serialize(instrumented).unwrap();
+
+		let table = gas_offset_table(&original, &instrumented).unwrap();
+
+		let original_bodies = bodies(&original).unwrap();
+		let instrumented_bodies = bodies(&instrumented).unwrap();
+		let translate = |body: usize, instruction: usize| {
+			table.translate(original_bodies[body].instructions[instruction].0).unwrap()
+		};
+		let offset = |body: usize, instruction: usize| instrumented_bodies[body].instructions[instruction].0;
+
+		// `get_local 0` follows the charge of the body.
+		assert_eq!(translate(0, 0), offset(0, 2));
+		// `call $ext` follows the charge of the `if`.
+		assert_eq!(translate(0, 2), offset(0, 6));
+		assert_eq!(instrumented_bodies[0].instructions[6].1, Instruction::Call(0));
+		assert_eq!(translate(1, 0), offset(1, 2));
+		assert_eq!(table.translate(original_bodies[1].end), Some(instrumented_bodies[1].end));
+		assert_eq!(table.translate(0), None);
+
+		table.embed(&mut instrumented_module);
+		let embedded = instrumented_module.custom_sections().find(|section| section.name() == SECTION_NAME).unwrap();
+		assert_eq!(OffsetTable::from_bytes(embedded.payload()), Some(table));
+	}
+}
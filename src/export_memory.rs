@@ -0,0 +1,130 @@
+use crate::std::fmt;
+use crate::std::string::String;
+
+use parity_wasm::elements;
+
+/// Reason why the memory of a module can't be exported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The module has no memory.
+	NoMemory,
+	/// The module imports its memory instead of defining it.
+	ImportedMemory {
+		module: String,
+		field: String,
+	},
+	/// Something other than the memory is already exported under the name.
+	NameTaken(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::NoMemory => write!(f, "The module has no memory to export"),
+			Error::ImportedMemory { module, field } => write!(
+				f,
+				"The module imports its memory from `{}` `{}` instead of defining it",
+				module, field,
+			),
+			Error::NameTaken(name) => write!(f, "Something other than the memory is already exported as `{}`", name),
+		}
+	}
+}
+
+/// Export the memory defined by the module under `name`, as many hosts require it to be exported
+/// as `memory`.
+///
+/// An existing export of the memory under another name is renamed. Returns whether the module was
+/// changed, it is not if the memory is already exported under `name`.
+///
+/// Fails if the module doesn't define a memory, in particular if it imports its memory, or if
+/// something else is already exported under `name`.
+pub fn export_memory(module: &mut elements::Module, name: &str) -> Result<bool, Error> {
+	let imported_memories = module.import_count(elements::ImportCountType::Memory) as u32;
+	let defined_memories = module.memory_section().map_or(0, |section| section.entries().len());
+	if defined_memories == 0 {
+		let import = module.import_section()
+			.map_or(&[][..], |section| section.entries())
+			.iter()
+			.find(|entry| matches!(entry.external(), elements::External::Memory(_)));
+		return Err(match import {
+			Some(import) => Error::ImportedMemory { module: import.module().into(), field: import.field().into() },
+			None => Error::NoMemory,
+		});
+	}
+
+	let exports = module.export_section().map_or(&[][..], |section| section.entries());
+	let is_memory = |entry: &elements::ExportEntry| matches!(entry.internal(), elements::Internal::Memory(_));
+	match exports.iter().find(|entry| entry.field() == name) {
+		Some(entry) if is_memory(entry) => return Ok(false),
+		Some(_) => return Err(Error::NameTaken(name.into())),
+		None => {}
+	}
+
+	if let Some(section) = module.export_section_mut() {
+		if let Some(entry) = section.entries_mut().iter_mut().find(|entry| is_memory(entry)) {
+			*entry.field_mut() = name.into();
+			return Ok(true);
+		}
+	}
+
+	let entry = elements::ExportEntry::new(name.into(), elements::Internal::Memory(imported_memories));
+	match module.export_section_mut() {
+		Some(section) => section.entries_mut().push(entry),
+		None => module.insert_section(elements::Section::Export(elements::ExportSection::with_entries(vec![entry])))
+			.expect("there is no export section yet; qed"),
+	}
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	fn memory_exports(module: &elements::Module) -> Vec<&str> {
+		module.export_section()
+			.map_or(&[][..], |section| section.entries())
+			.iter()
+			.filter(|entry| matches!(entry.internal(), elements::Internal::Memory(0)))
+			.map(|entry| entry.field())
+			.collect()
+	}
+
+	#[test]
+	fn adds_export() {
+		let mut module = parse_wat(r#"(module (memory 1) (func (export "call")))"#);
+		assert_eq!(export_memory(&mut module, "memory"), Ok(true));
+		assert_eq!(memory_exports(&module), vec!["memory"]);
+
+		// The export section is created in its place.
+		let mut module = parse_wat(r#"(module (memory 1) (func))"#);
+		assert_eq!(export_memory(&mut module, "memory"), Ok(true));
+		assert_eq!(memory_exports(&module), vec!["memory"]);
+		let module = elements::deserialize_buffer::<elements::Module>(&elements::serialize(module).unwrap()).unwrap();
+		assert_eq!(memory_exports(&module), vec!["memory"]);
+	}
+
+	#[test]
+	fn renames_export() {
+		let mut module = parse_wat(r#"(module (memory (export "mem") 1))"#);
+		assert_eq!(export_memory(&mut module, "memory"), Ok(true));
+		assert_eq!(memory_exports(&module), vec!["memory"]);
+		assert_eq!(export_memory(&mut module, "memory"), Ok(false));
+	}
+
+	#[test]
+	fn errors() {
+		let mut module = parse_wat(r#"(module (import "env" "memory" (memory 1)))"#);
+		assert_eq!(
+			export_memory(&mut module, "memory"),
+			Err(Error::ImportedMemory { module: "env".into(), field: "memory".into() }),
+		);
+
+		let mut module = parse_wat(r#"(module (func))"#);
+		assert_eq!(export_memory(&mut module, "memory"), Err(Error::NoMemory));
+
+		let mut module = parse_wat(r#"(module (memory 1) (func (export "memory")))"#);
+		assert_eq!(export_memory(&mut module, "memory"), Err(Error::NameTaken("memory".into())));
+	}
+}
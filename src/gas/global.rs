@@ -0,0 +1,281 @@
+//! Gas metering through a mutable global instead of calls to an imported function.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+use parity_wasm::elements::{Instruction, ValueType};
+
+use super::{determine_module_metered_blocks, inject_grow_counter, insert_metering, Error};
+use crate::rules::{GrowMetering, Rules};
+use crate::sections;
+use crate::trap_reason::{self, set_reason};
+
+/// Append the instructions charging `cost` to the remaining gas held by `gas_global`.
+///
+/// The cost is on top of the stack if `cost` is `None`, in which case it is kept in the i64
+/// local `cost_local`. Before trapping, `trap_reason::OUT_OF_GAS` is stored in the global
+/// `reason_global` if given.
+fn charge(
+	instructions: &mut Vec<Instruction>,
+	gas_global: u32,
+	cost: Option<u64>,
+	cost_local: u32,
+	reason_global: Option<u32>,
+) {
+	use parity_wasm::elements::Instruction::*;
+
+	let push_cost = |instructions: &mut Vec<Instruction>| match cost {
+		Some(cost) => instructions.push(I64Const(cost as i64)),
+		None => instructions.push(GetLocal(cost_local)),
+	};
+
+	// Trap if there is not enough gas left.
+	instructions.push(GetGlobal(gas_global));
+	push_cost(instructions);
+	instructions.push(I64LtU);
+	instructions.push(If(elements::BlockType::NoResult));
+	if let Some(reason_global) = reason_global {
+		set_reason(instructions, reason_global, trap_reason::OUT_OF_GAS);
+	}
+	instructions.push(Unreachable);
+	instructions.push(End);
+
+	instructions.push(GetGlobal(gas_global));
+	push_cost(instructions);
+	instructions.push(I64Sub);
+	instructions.push(SetGlobal(gas_global));
+}
+
+/// Add a function charging for `memory.grow`, which replaces all `memory.grow` instructions.
+fn add_grow_counter(
+	module: &mut elements::Module,
+	grow_metering: &dyn GrowMetering,
+	gas_global: u32,
+	reason_global: Option<u32>,
+) {
+	use parity_wasm::elements::Instruction::*;
+
+	// The cost is kept in the local following the ones used by the strategy.
+	let cost_local = grow_metering.scratch_locals() + 1;
+	let mut instructions = Vec::new();
+	grow_metering.amount(&mut instructions, true);
+	instructions.push(SetLocal(cost_local));
+	charge(&mut instructions, gas_global, None, cost_local, reason_global);
+	instructions.extend(vec![GetLocal(0), GrowMemory(0), End]);
+
+	sections::push_function(
+		module,
+		elements::FunctionType::new(vec![ValueType::I32], vec![ValueType::I32]),
+		elements::FuncBody::new(
+			vec![elements::Local::new(cost_local, ValueType::I64)],
+			elements::Instructions::new(instructions),
+		),
+	);
+}
+
+/// Names of the globals added by `inject_gas_counter_with_global`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlobalGasConfig<'a> {
+	/// Export name of the global holding the remaining gas.
+	pub export_name: &'a str,
+	/// Export name of the trap reason global, if one is set before trapping.
+	pub trap_reason: Option<&'a str>,
+}
+
+impl<'a> GlobalGasConfig<'a> {
+	pub fn new(export_name: &'a str) -> Self {
+		GlobalGasConfig { export_name, trap_reason: None }
+	}
+
+	/// Store `trap_reason::OUT_OF_GAS` in the global exported as `export_name` before trapping
+	/// for running out of gas, see the `trap_reason` module.
+	pub fn with_trap_reason(mut self, export_name: &'a str) -> Self {
+		self.trap_reason = Some(export_name);
+		self
+	}
+}
+
+/// The global holding the remaining gas is exported under the given name.
+impl<'a> From<&'a str> for GlobalGasConfig<'a> {
+	fn from(export_name: &'a str) -> Self {
+		GlobalGasConfig::new(export_name)
+	}
+}
+
+/// Transforms a given module into one that charges gas by decrementing a mutable global.
+///
+/// This is the counterpart of `inject_gas_counter` for runtimes which prefer to avoid a host call
+/// per metered block. A mutable `i64` global holding the remaining gas is added to the module and
+/// exported under the name given by `config`, which is either a `GlobalGasConfig` or just the
+/// name, so that the host can set it before and read it after execution.
+/// Every metered block starts with a check which traps if the remaining gas is less than the cost
+/// of the block, followed by the decrement of the global. Note that exporting a mutable global
+/// requires the mutable globals extension.
+///
+/// `memory.grow` is charged as in `inject_gas_counter`, by an added function which decrements
+/// the global before growing the memory. No function indices change.
+///
+/// The function fails if the module contains any operation forbidden by gas rule set or a function
+/// body with malformed control flow, returning the original module along with the `Error`.
+pub fn inject_gas_counter_with_global<'a, R: Rules, C: Into<GlobalGasConfig<'a>>>(
+	mut module: elements::Module,
+	rules: &R,
+	config: C,
+)
+	-> Result<elements::Module, (elements::Module, Error)>
+{
+	let config = config.into();
+	let mut metered_blocks = match determine_module_metered_blocks(&module, rules, None, &|_| true) {
+		Ok(metered_blocks) => metered_blocks.into_iter(),
+		Err(e) => return Err((module, e)),
+	};
+
+	let total_func = module.functions_space() as u32;
+	let gas_global = sections::push_global(&mut module, elements::GlobalEntry::new(
+		elements::GlobalType::new(ValueType::I64, true),
+		elements::InitExpr::new(vec![Instruction::I64Const(0), Instruction::End]),
+	));
+	sections::push_export(
+		&mut module,
+		elements::ExportEntry::new(config.export_name.into(), elements::Internal::Global(gas_global)),
+	);
+	let reason_global = config.trap_reason.map(|export_name| trap_reason::reason_global(&mut module, export_name));
+
+	let grow_metering = rules.grow_metering();
+	let mut need_grow_counter = false;
+	if let Some(code_section) = module.code_section_mut() {
+		for func_body in code_section.bodies_mut() {
+			let blocks = metered_blocks.next()
+				.expect("metered blocks are determined for every function body; qed");
+			insert_metering(func_body.code_mut(), blocks, None, |cost, instructions| {
+				charge(instructions, gas_global, Some(cost), 0, reason_global)
+			})
+				.expect("metered blocks are determined from the same function body; qed");
+			if grow_metering.is_charged()
+				&& inject_grow_counter(func_body.code_mut(), total_func) > 0
+			{
+				need_grow_counter = true;
+			}
+		}
+	}
+
+	if need_grow_counter {
+		add_grow_counter(&mut module, &*grow_metering, gas_global, reason_global);
+	}
+	Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+	use parity_wasm::elements::Instruction::*;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn decrements_global() {
+		let module = parse_wat(r#"
+			(module
+				(global (import "env" "g") i32)
+				(global i32 (i32.const 0))
+				(func (export "call")
+					i32.const 1
+					drop))
+		"#);
+
+		let injected = inject_gas_counter_with_global(module, &rules::Set::default(), "gas_left")
+			.unwrap();
+
+		assert_eq!(
+			injected.code_section().unwrap().bodies()[0].code().elements(),
+			&[
+				GetGlobal(2), I64Const(2), I64LtU, If(elements::BlockType::NoResult), Unreachable, End,
+				GetGlobal(2), I64Const(2), I64Sub, SetGlobal(2),
+				I32Const(1),
+				Drop,
+				End,
+			][..]
+		);
+
+		let global = &injected.global_section().unwrap().entries()[1];
+		assert!(global.global_type().is_mutable());
+		assert_eq!(global.global_type().content_type(), ValueType::I64);
+
+		let export = injected.export_section().unwrap().entries().iter()
+			.find(|e| e.field() == "gas_left")
+			.expect("gas global is exported");
+		assert_eq!(export.internal(), &elements::Internal::Global(2));
+
+		let binary = elements::serialize(injected).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn sets_trap_reason() {
+		let module = parse_wat(r#"
+			(module
+				(func (export "call")
+					nop))
+		"#);
+
+		let config = GlobalGasConfig::new("gas_left").with_trap_reason("trap_reason");
+		let injected = inject_gas_counter_with_global(module, &rules::Set::default(), config).unwrap();
+
+		assert_eq!(
+			&injected.code_section().unwrap().bodies()[0].code().elements()[..7],
+			&[
+				GetGlobal(0), I64Const(1), I64LtU, If(elements::BlockType::NoResult),
+				I32Const(trap_reason::OUT_OF_GAS), SetGlobal(1), Unreachable,
+			][..]
+		);
+		let export = injected.export_section().unwrap().entries().iter()
+			.find(|e| e.field() == "trap_reason")
+			.expect("trap reason global is exported");
+		assert_eq!(export.internal(), &elements::Internal::Global(1));
+
+		let binary = elements::serialize(injected).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn charges_memory_grow() {
+		let module = parse_wat(r#"
+			(module
+				(memory 1)
+				(func (param i32) (result i32)
+					get_local 0
+					grow_memory))
+		"#);
+
+		let rules = rules::Set::default().with_grow_cost(10);
+		let injected = inject_gas_counter_with_global(module, &rules, "gas_left").unwrap();
+
+		assert_eq!(injected.functions_space(), 2);
+		assert!(injected.code_section().unwrap().bodies()[0].code().elements().contains(&Call(1)));
+
+		let binary = elements::serialize(injected).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn keeps_sections_in_place() {
+		let mut module = parse_wat(r#"
+			(module
+				(memory 1)
+				(func (param i32) (result i32)
+					get_local 0
+					grow_memory))
+		"#);
+		module.sections_mut().insert(0, elements::Section::Custom(elements::CustomSection::new("meta".into(), vec![1])));
+
+		let rules = rules::Set::default().with_grow_cost(10);
+		let injected = inject_gas_counter_with_global(module, &rules, "gas_left").unwrap();
+
+		match &injected.sections()[0] {
+			elements::Section::Custom(custom) => assert_eq!(custom.name(), "meta"),
+			section => panic!("custom section moved, found {:?} first", section),
+		}
+		let binary = elements::serialize(injected).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+}
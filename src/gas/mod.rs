@@ -2,17 +2,116 @@
 //!
 //! The primary public interface is the `inject_gas_counter` function which transforms a given
 //! module into one that charges gas for code to be executed. See function documentation for usage
-//! and details.
-
+//! and details. `inject_gas_counter_with_global` does the same with a mutable global holding the
+//! remaining gas instead of an imported function.
+
+mod global;
+#[cfg(feature = "legacy")]
+pub mod legacy;
+#[cfg(feature = "std")]
+mod streaming;
+mod tail_call;
 #[cfg(test)]
 mod validation;
+#[cfg(test)]
+mod mutation;
 
-use crate::std::cmp::min;
+use crate::std::cmp::{self, min};
+use crate::std::collections::{BTreeMap, BTreeSet};
+use crate::std::fmt;
 use crate::std::mem;
+use crate::std::string::String;
 use crate::std::vec::Vec;
 
 use parity_wasm::{elements, elements::ValueType, builder};
-use crate::rules::Rules;
+use crate::check;
+use crate::debug_names::{self, DebugNames};
+use crate::rules::{GrowMetering, InstructionType, Rules, StackEffect, UnknownPolicy};
+use crate::sections::{self, resolve_type};
+use crate::source_map::SourceMap;
+use crate::visit::{self, visit, Frame, Frames, Visitor};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+pub use self::global::{inject_gas_counter_with_global, GlobalGasConfig};
+#[cfg(feature = "std")]
+pub use self::streaming::{inject_gas_counter_streaming, StreamError};
+pub use self::tail_call::inject_gas_counter_with_tail_calls;
+
+/// The reason why a function body could not be instrumented.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+	/// The rule set forbids the instruction.
+	ForbiddenInstruction(elements::Instruction),
+	/// The control flow of the body is not properly structured, e.g. there is an unbalanced `end`
+	/// or a branch to a label which does not exist.
+	MalformedBody,
+	/// The cost of a metered block does not fit into the type of the gas amount.
+	CostOverflow,
+	/// The function declares more locals than the rule set allows.
+	TooManyLocals { declared: u64, limit: u32 },
+	/// The rule set doesn't know the instruction and rejects unknown instructions, see
+	/// `UnknownPolicy::Reject`.
+	UnknownInstruction(elements::Instruction),
+	/// The classes of all instructions of the module the rule set doesn't know, if it forbids
+	/// unknown instructions, see `UnknownPolicy::Forbid`.
+	UnknownInstructions(Vec<InstructionType>),
+	/// The module is not well formed, only returned if checking it is enabled, see
+	/// `GasConfig::with_checks`.
+	Malformed(check::Error),
+	/// The metered module is not valid, only returned if validating it is enabled, see
+	/// `GasConfig::with_output_validation`.
+	InvalidOutput(crate::validation::Error),
+}
+
+/// Error of the gas metering instrumentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+	/// What went wrong.
+	pub kind: ErrorKind,
+	/// Index of the function in the function index space of the original module, if known.
+	pub function: Option<u32>,
+	/// Position of the offending instruction in the function body.
+	pub offset: usize,
+}
+
+impl Error {
+	fn new(kind: ErrorKind, offset: usize) -> Self {
+		Error { kind, function: None, offset }
+	}
+
+	fn in_function(self, function: u32) -> Self {
+		Error { function: Some(function), ..self }
+	}
+}
+
+impl fmt::Display for ErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			ErrorKind::ForbiddenInstruction(instruction) =>
+				write!(f, "Instruction `{}` is forbidden by the gas rules", instruction),
+			ErrorKind::MalformedBody => write!(f, "Malformed control flow"),
+			ErrorKind::CostOverflow => write!(f, "Cost of a metered block overflows the gas amount"),
+			ErrorKind::TooManyLocals { declared, limit } =>
+				write!(f, "Function declares {} locals, more than the limit of {}", declared, limit),
+			ErrorKind::UnknownInstruction(instruction) =>
+				write!(f, "Instruction `{}` is unknown to the gas rules", instruction),
+			ErrorKind::UnknownInstructions(classes) =>
+				write!(f, "Instructions of the classes {:?} are unknown to the gas rules", classes),
+			ErrorKind::Malformed(e) => write!(f, "Malformed module: {}", e),
+			ErrorKind::InvalidOutput(e) => write!(f, "Metered module is invalid: {}", e),
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self.function {
+			Some(function) => write!(f, "{} (function {}, instruction {})", self.kind, function, self.offset),
+			None => write!(f, "{} (instruction {})", self.kind, self.offset),
+		}
+	}
+}
 
 pub fn update_call_index(instructions: &mut elements::Instructions, inserted_index: u32) {
 	use parity_wasm::elements::Instruction::*;
@@ -59,21 +158,44 @@ struct ControlBlock {
 	/// Whether the control block is a loop. Loops have the distinguishing feature that branches to
 	/// them jump to the beginning of the block, not the end as with the other control blocks.
 	is_loop: bool,
+
+	/// Whether a branch targets the control block.
+	is_targeted: bool,
+
+	/// For each arm of an `if` closed so far, the finalized blocks its last metered block was
+	/// charged in, which all paths falling through to the end of the arm run.
+	arms: Vec<Vec<usize>>,
+
+	/// The finalized blocks the cost of the active metered block is charged in instead of a charge
+	/// of its own, if they all lead to it and all paths to it run one of them.
+	active_join: Vec<usize>,
 }
 
 /// A block of code that metering instructions will be inserted at the beginning of. Metered blocks
 /// are constructed with the property that, in the absence of any traps, either all instructions in
 /// the block are executed or none are.
 #[derive(Debug)]
-pub(crate) struct MeteredBlock {
+pub struct MeteredBlock {
 	/// Index of the first instruction (aka `Opcode`) in the block.
-	start_pos: usize,
+	pub start_pos: usize,
 	/// Sum of costs of all instructions until end of the block.
-	cost: u32,
+	pub cost: u64,
+}
+
+/// The instructions of a class charged in a function body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ClassCost {
+	/// Number of the instructions, each counted with the loop multiplier if it applies.
+	pub(crate) count: u64,
+	/// Sum of their costs without the surcharges of calls, with the loop multiplier.
+	pub(crate) cost: u64,
 }
 
+/// The costs of the instructions charged in a function body, by class.
+pub(crate) type ClassCosts = BTreeMap<InstructionType, ClassCost>;
+
 /// Counter is used to manage state during the gas metering algorithm implemented by
-/// `inject_counter`.
+/// `determine_metered_blocks`.
 struct Counter {
 	/// A stack of control blocks. This stack grows when new control blocks are opened with
 	/// `block`, `loop`, and `if` and shrinks when control blocks are closed with `end`. The first
@@ -84,13 +206,34 @@ struct Counter {
 
 	/// A list of metered blocks that have been finalized, meaning they will no longer change.
 	finalized_blocks: Vec<MeteredBlock>,
+
+	/// Factor applied to the cost of instructions inside of loops.
+	loop_multiplier: u32,
+
+	/// Which conservative choices of the division into metered blocks are relaxed.
+	merging: Merging,
+}
+
+/// The conservative choices of the division into metered blocks which are relaxed, each trading
+/// precision for fewer charges, see the corresponding options of `GasConfig`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Merging {
+	/// Whether the code following an `if` with an `else` is charged at the end of both arms, if
+	/// they are the only ways to reach it, instead of having a charge of its own.
+	join_arms: bool,
+	/// Whether branches out of the function don't end the metered blocks of the enclosing blocks.
+	returns: bool,
+	/// Whether conditional branches to loops don't end the metered block they are in.
+	loop_branches: bool,
 }
 
 impl Counter {
-	fn new() -> Counter {
+	fn new(loop_multiplier: u32, merging: Merging) -> Counter {
 		Counter {
 			stack: Vec::new(),
 			finalized_blocks: Vec::new(),
+			loop_multiplier,
+			merging,
 		}
 	}
 
@@ -104,18 +247,21 @@ impl Counter {
 				cost: 0,
 			},
 			is_loop,
+			is_targeted: false,
+			arms: Vec::new(),
+			active_join: Vec::new(),
 		})
 	}
 
 	/// Close the last control block. The cursor is the position of the final (pseudo-)instruction
 	/// in the block.
-	fn finalize_control_block(&mut self, cursor: usize) -> Result<(), ()> {
+	fn finalize_control_block(&mut self, cursor: usize) -> Result<(), ErrorKind> {
 		// This either finalizes the active metered block or merges its cost into the active
 		// metered block in the previous control block on the stack.
-		self.finalize_metered_block(cursor)?;
+		self.finish_arm(cursor)?;
 
 		// Pop the control block stack.
-		let closing_control_block = self.stack.pop().ok_or_else(|| ())?;
+		let closing_control_block = self.stack.pop().ok_or(ErrorKind::MalformedBody)?;
 		let closing_control_index = self.stack.len();
 
 		if self.stack.is_empty() {
@@ -124,7 +270,7 @@ impl Counter {
 
 		// Update the lowest_forward_br_target for the control block now on top of the stack.
 		{
-			let control_block = self.stack.last_mut().ok_or_else(|| ())?;
+			let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedBody)?;
 			control_block.lowest_forward_br_target = min(
 				control_block.lowest_forward_br_target,
 				closing_control_block.lowest_forward_br_target
@@ -136,26 +282,58 @@ impl Counter {
 		let may_br_out = closing_control_block.lowest_forward_br_target < closing_control_index;
 		if may_br_out {
 			self.finalize_metered_block(cursor)?;
+
+			// The code following an `if` is only reached from the ends of its arms if it has an
+			// `else` and no branch targets it. Its cost is then charged at the end of both arms.
+			let arms = closing_control_block.arms;
+			let joinable = self.merging.join_arms
+				&& !closing_control_block.is_targeted
+				&& arms.len() == 2
+				&& arms.iter().all(|blocks| !blocks.is_empty());
+			if joinable {
+				let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedBody)?;
+				control_block.active_join = arms.concat();
+			}
 		}
 
 		Ok(())
 	}
 
-	/// Finalize the current active metered block.
+	/// Finalize the active metered block of an arm of an `if`, or of any other control block, at
+	/// its end or `else`, recording the finalized blocks it is charged in.
+	fn finish_arm(&mut self, cursor: usize) -> Result<(), ErrorKind> {
+		let charged_in = self.finalize_metered_block(cursor)?;
+		let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedBody)?;
+		control_block.arms.push(charged_in);
+		Ok(())
+	}
+
+	/// Finalize the current active metered block, returning the indices of the finalized blocks
+	/// it is charged in.
 	///
-	/// Finalized blocks have final cost which will not change later.
-	fn finalize_metered_block(&mut self, cursor: usize) -> Result<(), ()> {
-		let closing_metered_block = {
-			let control_block = self.stack.last_mut().ok_or_else(|| ())?;
-			mem::replace(
+	/// Finalized blocks have final cost which will not change later, except for the cost of the
+	/// blocks joining them.
+	fn finalize_metered_block(&mut self, cursor: usize) -> Result<Vec<usize>, ErrorKind> {
+		let (closing_metered_block, join) = {
+			let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedBody)?;
+			let closing_metered_block = mem::replace(
 				&mut control_block.active_metered_block,
 				MeteredBlock {
 					start_pos: cursor + 1,
 					cost: 0,
 				}
-			)
+			);
+			(closing_metered_block, mem::take(&mut control_block.active_join))
 		};
 
+		if !join.is_empty() {
+			for &index in &join {
+				let block = &mut self.finalized_blocks[index];
+				block.cost = block.cost.checked_add(closing_metered_block.cost).ok_or(ErrorKind::CostOverflow)?;
+			}
+			return Ok(join);
+		}
+
 		// If the block was opened with a `block`, then its start position will be set to that of
 		// the active metered block in the control block one higher on the stack. This is because
 		// any instructions between a `block` and the first branch are part of the same basic block
@@ -167,35 +345,49 @@ impl Counter {
 				.expect("last_index is greater than 0; last_index is stack size - 1; qed");
 			let prev_metered_block = &mut prev_control_block.active_metered_block;
 			if closing_metered_block.start_pos == prev_metered_block.start_pos {
-				prev_metered_block.cost += closing_metered_block.cost;
-				return Ok(())
+				prev_metered_block.cost = prev_metered_block.cost
+					.checked_add(closing_metered_block.cost)
+					.ok_or(ErrorKind::CostOverflow)?;
+				return Ok(Vec::new())
 			}
 		}
 
 		if closing_metered_block.cost > 0 {
 			self.finalized_blocks.push(closing_metered_block);
+			return Ok(vec![self.finalized_blocks.len() - 1]);
 		}
-		Ok(())
+		Ok(Vec::new())
 	}
 
 	/// Handle a branch instruction in the program. The cursor is the index of the branch
 	/// instruction in the program. The indices are the stack positions of the target control
 	/// blocks. Recall that the index is 0 for a `return` and relatively indexed from the top of
-	/// the stack by the label of `br`, `br_if`, and `br_table` instructions.
-	fn branch(&mut self, cursor: usize, indices: &[usize]) -> Result<(), ()> {
-		self.finalize_metered_block(cursor)?;
+	/// the stack by the label of `br`, `br_if`, and `br_table` instructions. `conditional` holds for
+	/// `br_if`, which may continue with the following instruction.
+	fn branch(&mut self, cursor: usize, indices: &[usize], conditional: bool) -> Result<(), ErrorKind> {
+		// If the branch is not taken, the following code runs. Charging it with the preceding code
+		// charges it in vain each time the branch is taken, which for a branch to a loop is at most
+		// once per iteration.
+		let to_loops = indices.iter().all(|&index| matches!(self.stack.get(index), Some(block) if block.is_loop));
+		if !(conditional && to_loops && self.merging.loop_branches) {
+			self.finalize_metered_block(cursor)?;
+		}
 
 		// Update the lowest_forward_br_target of the current control block.
 		for &index in indices {
 			let target_is_loop = {
-				let target_block = self.stack.get(index).ok_or_else(|| ())?;
+				let target_block = self.stack.get_mut(index).ok_or(ErrorKind::MalformedBody)?;
+				target_block.is_targeted = true;
 				target_block.is_loop
 			};
-			if target_is_loop {
+			// Like a branch to a loop, a branch out of the function doesn't continue after the end
+			// of any enclosing block. If the code following them is charged with the code preceding
+			// them, it is charged in vain when the function returns early, at most once per call.
+			if target_is_loop || (index == 0 && self.merging.returns) {
 				continue;
 			}
 
-			let control_block = self.stack.last_mut().ok_or_else(|| ())?;
+			let control_block = self.stack.last_mut().ok_or(ErrorKind::MalformedBody)?;
 			control_block.lowest_forward_br_target =
 				min(control_block.lowest_forward_br_target, index);
 		}
@@ -203,21 +395,34 @@ impl Counter {
 		Ok(())
 	}
 
-	/// Returns the stack index of the active control block. Returns None if stack is empty.
-	fn active_control_block_index(&self) -> Option<usize> {
-		self.stack.len().checked_sub(1)
-	}
-
 	/// Get a reference to the currently active metered block.
-	fn active_metered_block(&mut self) -> Result<&mut MeteredBlock, ()> {
-		let top_block = self.stack.last_mut().ok_or_else(|| ())?;
+	fn active_metered_block(&mut self) -> Result<&mut MeteredBlock, ErrorKind> {
+		let top_block = self.stack.last_mut().ok_or(ErrorKind::MalformedBody)?;
 		Ok(&mut top_block.active_metered_block)
 	}
 
+	/// Add an amount charged explicitly by the module to the current block. Unlike instruction
+	/// costs, it is not subject to the loop multiplier.
+	fn prepay(&mut self, amount: u64) -> Result<(), ErrorKind> {
+		let top_block = self.active_metered_block()?;
+		top_block.cost = top_block.cost.checked_add(amount).ok_or(ErrorKind::CostOverflow)?;
+		Ok(())
+	}
+
+	/// Factor applied to the cost of the current instruction, the loop multiplier inside of loops.
+	fn multiplier(&self) -> u64 {
+		if self.stack.iter().any(|control_block| control_block.is_loop) {
+			u64::from(self.loop_multiplier)
+		} else {
+			1
+		}
+	}
+
 	/// Increment the cost of the current block by the specified value.
-	fn increment(&mut self, val: u32) -> Result<(), ()> {
+	fn increment(&mut self, val: u32) -> Result<(), ErrorKind> {
+		let val = u64::from(val) * self.multiplier();
 		let top_block = self.active_metered_block()?;
-		top_block.cost = top_block.cost.checked_add(val).ok_or_else(|| ())?;
+		top_block.cost = top_block.cost.checked_add(val).ok_or(ErrorKind::CostOverflow)?;
 		Ok(())
 	}
 }
@@ -234,134 +439,658 @@ fn inject_grow_counter(instructions: &mut elements::Instructions, grow_counter_f
 	counter
 }
 
-fn add_grow_counter<R: Rules>(
-	module: elements::Module,
-	rules: &R,
-	gas_func: u32
-) -> elements::Module {
+/// How `memory.grow` is charged in the function bodies.
+pub(crate) enum GrowCharging {
+	/// `memory.grow` is left as is.
+	Uncharged,
+	/// `memory.grow` is replaced by calls of the function with the given index.
+	Counter(u32),
+	/// The charge is inlined before each `memory.grow`. The `amount` instructions push the amount
+	/// for the number of pages in local 0, using `scratch_locals` `i64` locals from local 1 on.
+	Inline { amount: Vec<elements::Instruction>, scratch_locals: u32 },
+}
+
+impl GrowCharging {
+	/// The charging of `memory.grow` by `grow_metering` as configured, where `counter_func` is the
+	/// index the function replacing `memory.grow` would be added at.
+	pub(crate) fn new(grow_metering: &dyn GrowMetering, counter_func: u32, config: &GasConfig) -> Self {
+		if !grow_metering.is_charged() {
+			return GrowCharging::Uncharged;
+		}
+		if !config.inline_grow_charges {
+			return GrowCharging::Counter(counter_func);
+		}
+		let mut amount = Vec::new();
+		grow_metering.amount(&mut amount, config.i64_amounts);
+		GrowCharging::Inline { amount, scratch_locals: grow_metering.scratch_locals() }
+	}
+}
+
+/// Index of the `i32` local keeping the number of pages when charging `memory.grow` inline in the
+/// body of a function with `params` parameters, which is followed by `scratch_locals` locals.
+///
+/// Fails if the locals added to the body don't fit into the local index space.
+fn grow_pages_local(params: u32, func_body: &elements::FuncBody, scratch_locals: u32) -> Result<u32, Error> {
+	let pages = func_body.locals()
+		.iter()
+		.try_fold(params, |count, local| count.checked_add(local.count()));
+	// The new locals have to be counted by an `u32` as well.
+	let limit = u32::MAX.saturating_sub(1).saturating_sub(scratch_locals);
+	match pages {
+		Some(pages) if pages <= limit => Ok(pages),
+		_ => {
+			let declared = u64::from(params) + func_body.locals().iter().map(|local| u64::from(local.count())).sum::<u64>();
+			Err(Error::new(ErrorKind::TooManyLocals { declared, limit }, 0))
+		}
+	}
+}
+
+/// Charge the amount computed by `amount` before each `memory.grow` of the function body, whose
+/// function has `params` parameters, by calling `gas_func`. The locals used by the charge are
+/// added to the body if it grows the memory.
+///
+/// Returns the new position of every instruction of the body.
+fn inline_grow_charges(
+	func_body: &mut elements::FuncBody,
+	params: u32,
+	amount: &[elements::Instruction],
+	scratch_locals: u32,
+	gas_func: u32,
+) -> Vec<usize> {
 	use parity_wasm::elements::Instruction::*;
-	use crate::rules::MemoryGrowCost;
 
-	let cost = match rules.memory_grow_cost() {
-		None => return module,
-		Some(MemoryGrowCost::Linear(val)) => val.get(),
-	};
+	let len = func_body.code().elements().len();
+	if !func_body.code().elements().iter().any(|instruction| matches!(instruction, GrowMemory(_))) {
+		return (0..len).collect();
+	}
 
-	let mut b = builder::from_module(module);
-	b.push_function(
-		builder::function()
-			.signature().with_param(ValueType::I32).with_result(ValueType::I32).build()
-			.body()
-				.with_instructions(elements::Instructions::new(vec![
-					GetLocal(0),
-					GetLocal(0),
-					I32Const(cost as i32),
-					I32Mul,
-					// todo: there should be strong guarantee that it does not return anything on stack?
-					Call(gas_func),
-					GrowMemory(0),
-					End,
-				]))
-				.build()
+	// The number of pages is kept in a new `i32` local, followed by the scratch locals.
+	let pages = grow_pages_local(params, func_body, scratch_locals)
+		.expect("the locals of bodies growing the memory are checked when planning; qed");
+	func_body.locals_mut().push(elements::Local::new(1, ValueType::I32));
+	if scratch_locals > 0 {
+		func_body.locals_mut().push(elements::Local::new(scratch_locals, ValueType::I64));
+	}
+	let charge: Vec<_> = [TeeLocal(0)].iter()
+		.chain(amount)
+		.map(|instruction| match *instruction {
+			GetLocal(index) => GetLocal(pages + index),
+			SetLocal(index) => SetLocal(pages + index),
+			TeeLocal(index) => TeeLocal(pages + index),
+			ref instruction => instruction.clone(),
+		})
+		.chain(Some(Call(gas_func)))
+		.collect();
+
+	let instructions = func_body.code_mut().elements_mut();
+	let original = mem::take(instructions);
+	let mut positions = Vec::with_capacity(len);
+	for instruction in original {
+		if let GrowMemory(_) = instruction {
+			instructions.extend(charge.iter().cloned());
+		}
+		positions.push(instructions.len());
+		instructions.push(instruction);
+	}
+	positions
+}
+
+/// The function charging for `memory.grow` by calling the gas function `gas_func`, which replaces
+/// the `memory.grow` instructions. It has the type signature [i32] -> [i32].
+pub(crate) fn grow_counter(
+	grow_metering: &dyn GrowMetering,
+	gas_func: u32,
+	i64_amounts: bool,
+) -> builder::FunctionDefinition {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut instructions = vec![GetLocal(0)];
+	grow_metering.amount(&mut instructions, i64_amounts);
+	instructions.extend(vec![
+		// todo: there should be strong guarantee that it does not return anything on stack?
+		Call(gas_func),
+		GrowMemory(0),
+		End,
+	]);
+
+	let mut locals = Vec::new();
+	if grow_metering.scratch_locals() > 0 {
+		locals.push(elements::Local::new(grow_metering.scratch_locals(), ValueType::I64));
+	}
+
+	builder::function()
+		.signature().with_param(ValueType::I32).with_result(ValueType::I32).build()
+		.body()
+			.with_locals(locals)
+			.with_instructions(elements::Instructions::new(instructions))
 			.build()
+		.build()
+}
+
+fn add_grow_counter(
+	mut module: elements::Module,
+	grow_metering: &dyn GrowMetering,
+	gas_func: u32,
+	i64_amounts: bool,
+) -> elements::Module {
+	let grow_counter_func = sections::push_function(
+		&mut module,
+		elements::FunctionType::new(vec![ValueType::I32], vec![ValueType::I32]),
+		grow_counter(grow_metering, gas_func, i64_amounts).code,
 	);
 
-	b.build()
+	if let Some(function_names) = module.names_section_mut().and_then(|name_section| name_section.functions_mut().as_mut()) {
+		function_names.names_mut().insert(grow_counter_func, "grow_counter".into());
+	}
+	module
+}
+
+/// Parse the name section of the module if it is still a custom section.
+///
+/// Subsections which parity-wasm can't parse are dropped, as their indices can't be updated.
+fn parse_names(mut module: elements::Module) -> elements::Module {
+	if module.names_section().is_some() || !module.has_names_section() {
+		return module;
+	}
+	crate::optimizer::drop_extended_names(&mut module);
+	module.parse_names().unwrap_or_else(|(_, module)| module)
 }
 
-pub(crate) fn determine_metered_blocks<R: Rules>(
-	instructions: &elements::Instructions,
-	rules: &R,
-) -> Result<Vec<MeteredBlock>, ()> {
+/// Increment the indices of the `names` from `index` on, as a function is inserted there.
+fn shift_names<T: Default>(names: &mut elements::IndexMap<T>, index: u32) {
+	*names = mem::take(names)
+		.into_iter()
+		.map(|(func, name)| (if func >= index { func + 1 } else { func }, name))
+		.collect();
+}
+
+/// The amount charged by the `i32.const N; call $gas` pair starting at `pos`, where `$gas` is the
+/// function `prepaid_func` already imported by the module.
+fn prepaid_charge(instructions: &[elements::Instruction], pos: usize, prepaid_func: u32) -> Option<u64> {
 	use parity_wasm::elements::Instruction::*;
 
-	let mut counter = Counter::new();
+	match instructions.get(pos..pos + 2)? {
+		[I32Const(amount), Call(func)] if *func == prepaid_func => Some(u64::from(*amount as u32)),
+		[I64Const(amount), Call(func)] if *func == prepaid_func => Some(*amount as u64),
+		_ => None,
+	}
+}
+
+/// Whether the instruction at `pos` belongs to a pair charging gas explicitly, see
+/// `prepaid_charge`.
+pub(crate) fn is_prepaid_charge(instructions: &[elements::Instruction], pos: usize, prepaid_func: Option<u32>) -> bool {
+	match prepaid_func {
+		Some(prepaid_func) => prepaid_charge(instructions, pos, prepaid_func).is_some()
+			|| pos.checked_sub(1).and_then(|pos| prepaid_charge(instructions, pos, prepaid_func)).is_some(),
+		None => false,
+	}
+}
 
-	// Begin an implicit function (i.e. `func...end`) block.
-	counter.begin_control_block(0, false);
+/// Visitor computing the metered blocks of a function body with a `Counter`.
+struct MeteringVisitor<'a, R: ?Sized> {
+	counter: Counter,
+	rules: &'a R,
+	/// Instructions implemented by imported intrinsics, by function index.
+	intrinsics: &'a BTreeMap<u32, elements::Instruction>,
+	/// The function body being visited.
+	instructions: &'a [elements::Instruction],
+	/// The gas function already imported by the module, whose explicit charges are folded.
+	prepaid_func: Option<u32>,
+	/// The module of the function body, used to determine the stack effect of calls.
+	module: &'a elements::Module,
+	/// Number of parameters and results of every function of the module, by function index.
+	arities: &'a [(usize, usize)],
+	/// The costs of the charged instructions by class, if they are gathered.
+	class_costs: Option<&'a mut ClassCosts>,
+}
 
-	for cursor in 0..instructions.elements().len() {
-		let instruction = &instructions.elements()[cursor];
-		let instruction_cost = rules.instruction_cost(instruction).ok_or(())?;
+impl<'a, R: Rules + ?Sized> MeteringVisitor<'a, R> {
+	/// The instruction `instruction` is charged like. Calls to intrinsics are charged like the
+	/// instruction they implement.
+	fn charged_as<'b>(&'b self, instruction: &'b elements::Instruction) -> &'b elements::Instruction {
 		match instruction {
-			Block(_) => {
-				counter.increment(instruction_cost)?;
+			elements::Instruction::Call(func) => self.intrinsics.get(func).unwrap_or(instruction),
+			_ => instruction,
+		}
+	}
+
+	/// The cost charged for a call on top of the cost of the instruction.
+	fn surcharge(&self, instruction: &elements::Instruction) -> u32 {
+		match instruction {
+			elements::Instruction::Call(_) => self.rules.call_cost(),
+			elements::Instruction::CallIndirect(_, _) => self.rules.call_indirect_cost(),
+			_ => 0,
+		}
+	}
+
+	fn instruction_cost(&self, pos: usize, instruction: &elements::Instruction) -> Result<u32, Error> {
+		let instruction = self.charged_as(instruction);
+		// Unknown instructions which are charged are priced by `instruction_cost` like the others.
+		let charged = matches!(self.rules.unknown_policy(), UnknownPolicy::Charge(_));
+		if !charged && !self.rules.is_known(instruction) {
+			return Err(Error::new(ErrorKind::UnknownInstruction(instruction.clone()), pos));
+		}
+		let forbidden = match instruction {
+			elements::Instruction::GrowMemory(_) => self.rules.grow_metering().is_forbidden(),
+			_ => false,
+		};
+		let effect = visit::stack_effect(instruction, self.module, self.arities)
+			.map(|(pops, pushes)| StackEffect { pops: pops as u32, pushes: pushes as u32 });
+		let cost = self.rules.instruction_cost_with_effect(instruction, effect)
+			.filter(|_| !forbidden)
+			.ok_or_else(|| Error::new(ErrorKind::ForbiddenInstruction(instruction.clone()), pos))?;
+		cost.checked_add(self.surcharge(instruction)).ok_or_else(|| Error::new(ErrorKind::CostOverflow, pos))
+	}
+
+	/// Charge the instruction at `pos` in the current block.
+	fn charge(&mut self, pos: usize, instruction: &elements::Instruction) -> Result<(), Error> {
+		let instruction_cost = self.instruction_cost(pos, instruction)?;
+		let charged_as = self.charged_as(instruction);
+		let class = InstructionType::op(charged_as);
+		let cost = u64::from(instruction_cost - self.surcharge(charged_as));
+		let multiplier = self.counter.multiplier();
+		if let Some(class_costs) = self.class_costs.as_mut() {
+			let class_cost = class_costs.entry(class).or_default();
+			class_cost.count = class_cost.count.saturating_add(multiplier);
+			class_cost.cost = class_cost.cost.saturating_add(cost * multiplier);
+		}
+		self.counter.increment(instruction_cost).map_err(|kind| Error::new(kind, pos))
+	}
+}
+
+impl<'a, R: Rules + ?Sized> Visitor for MeteringVisitor<'a, R> {
+	type Error = Error;
+
+	fn enter_block(
+		&mut self,
+		pos: usize,
+		instruction: &elements::Instruction,
+		frames: &Frames,
+	) -> Result<(), Error> {
+		use parity_wasm::elements::Instruction::*;
 
+		let at = |kind| Error::new(kind, pos);
+		self.charge(pos, instruction)?;
+
+		let is_loop = matches!(frames.top(), Some(frame) if frame.is_loop());
+		match instruction {
+			Block(_) => {
 				// Begin new block. The cost of the following opcodes until `end` or `else` will
 				// be included into this block. The start position is set to that of the previous
 				// active metered block to signal that they should be merged in order to reduce
 				// unnecessary metering instructions.
-				let top_block_start_pos = counter.active_metered_block()?.start_pos;
-				counter.begin_control_block(top_block_start_pos, false);
-			}
-			If(_) => {
-				counter.increment(instruction_cost)?;
-				counter.begin_control_block(cursor + 1, false);
+				let top_block_start_pos = self.counter.active_metered_block().map_err(at)?.start_pos;
+				self.counter.begin_control_block(top_block_start_pos, is_loop);
 			}
-			Loop(_) => {
-				counter.increment(instruction_cost)?;
-				counter.begin_control_block(cursor + 1, true);
+			_ => self.counter.begin_control_block(pos + 1, is_loop),
+		}
+		Ok(())
+	}
+
+	fn visit_else(&mut self, pos: usize, _frames: &Frames) -> Result<(), Error> {
+		self.instruction_cost(pos, &elements::Instruction::Else)?;
+		self.counter.finish_arm(pos).map_err(|kind| Error::new(kind, pos))
+	}
+
+	fn leave_block(&mut self, pos: usize, _frame: &Frame, _frames: &Frames) -> Result<(), Error> {
+		self.instruction_cost(pos, &elements::Instruction::End)?;
+		self.counter.finalize_control_block(pos).map_err(|kind| Error::new(kind, pos))
+	}
+
+	fn visit_instruction(
+		&mut self,
+		pos: usize,
+		instruction: &elements::Instruction,
+		frames: &Frames,
+	) -> Result<(), Error> {
+		use parity_wasm::elements::Instruction::*;
+
+		let at = |kind| Error::new(kind, pos);
+		if let Some(prepaid_func) = self.prepaid_func {
+			// Explicit charges are folded into the block, so neither the constant nor the call
+			// are charged themselves.
+			if let Some(amount) = prepaid_charge(self.instructions, pos, prepaid_func) {
+				return self.counter.prepay(amount).map_err(at);
 			}
-			End => {
-				counter.finalize_control_block(cursor)?;
-			},
-			Else => {
-				counter.finalize_metered_block(cursor)?;
+			if is_prepaid_charge(self.instructions, pos, self.prepaid_func) {
+				return Ok(());
 			}
-			Br(label) | BrIf(label) => {
-				counter.increment(instruction_cost)?;
+		}
 
+		self.charge(pos, instruction)?;
+		match instruction {
+			Br(label) | BrIf(label) => {
 				// Label is a relative index into the control stack.
-				let active_index = counter.active_control_block_index().ok_or_else(|| ())?;
-				let target_index = active_index.checked_sub(*label as usize).ok_or_else(|| ())?;
-				counter.branch(cursor, &[target_index])?;
+				let target_index = frames.target_index(*label).ok_or_else(|| at(ErrorKind::MalformedBody))?;
+				self.counter.branch(pos, &[target_index], matches!(instruction, BrIf(_))).map_err(at)?;
 			}
 			BrTable(br_table_data) => {
-				counter.increment(instruction_cost)?;
-
-				let active_index = counter.active_control_block_index().ok_or_else(|| ())?;
 				let target_indices = [br_table_data.default]
 					.iter()
 					.chain(br_table_data.table.iter())
-					.map(|label| active_index.checked_sub(*label as usize))
+					.map(|label| frames.target_index(*label))
 					.collect::<Option<Vec<_>>>()
-					.ok_or_else(|| ())?;
-				counter.branch(cursor, &target_indices)?;
+					.ok_or_else(|| at(ErrorKind::MalformedBody))?;
+				self.counter.branch(pos, &target_indices, false).map_err(at)?;
 			}
 			Return => {
-				counter.increment(instruction_cost)?;
-				counter.branch(cursor, &[0])?;
+				self.counter.branch(pos, &[0], false).map_err(at)?;
 			}
-			_ => {
-				// An ordinal non control flow instruction increments the cost of the current block.
-				counter.increment(instruction_cost)?;
+			// An ordinal non control flow instruction only increments the cost of the current block.
+			_ => {}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+pub(crate) fn determine_metered_blocks<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+) -> Result<Vec<MeteredBlock>, Error> {
+	let module = elements::Module::default();
+	ModuleMetering::new(&module, rules, None).instruction_blocks(instructions, 1, 0, Merging::default(), None)
+}
+
+#[cfg(test)]
+pub(crate) fn determine_joined_blocks<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+) -> Result<Vec<MeteredBlock>, Error> {
+	let module = elements::Module::default();
+	let merging = Merging { join_arms: true, ..Merging::default() };
+	ModuleMetering::new(&module, rules, None).instruction_blocks(instructions, 1, 0, merging, None)
+}
+
+/// What the metered blocks of the function bodies of a module depend on besides the bodies.
+pub(crate) struct ModuleMetering<'a, R: ?Sized> {
+	module: &'a elements::Module,
+	rules: &'a R,
+	/// The gas function already imported by the module, whose explicit charges are folded.
+	prepaid_func: Option<u32>,
+	imported_funcs: u32,
+	/// Instructions implemented by imported intrinsics, by function index.
+	intrinsics: BTreeMap<u32, elements::Instruction>,
+	/// Names of the functions, by function index.
+	names: BTreeMap<u32, Vec<String>>,
+	/// Number of parameters and results of every function, by function index.
+	arities: Vec<(usize, usize)>,
+}
+
+impl<'a, R: Rules + ?Sized> ModuleMetering<'a, R> {
+	/// If `prepaid_func` is given, the explicit charges by calls to it are folded into the blocks,
+	/// see `GasConfig::with_folded_charges`.
+	///
+	/// The function bodies are not looked at, so the code section may be left out of the module.
+	pub(crate) fn new(module: &'a elements::Module, rules: &'a R, prepaid_func: Option<u32>) -> Self {
+		let intrinsics = module.import_section()
+			.map_or(&[][..], |import_section| import_section.entries())
+			.iter()
+			.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+			.enumerate()
+			.filter_map(|(index, entry)| {
+				rules.intrinsic(entry.module(), entry.field()).map(|instruction| (index as u32, instruction))
+			})
+			.collect();
+
+		ModuleMetering {
+			module,
+			rules,
+			prepaid_func,
+			imported_funcs: module.import_count(elements::ImportCountType::Function) as u32,
+			intrinsics,
+			names: function_names(module),
+			arities: visit::function_arities(module),
+		}
+	}
+
+	/// Fail with the classes of all instructions of the bodies at the positions in the code section
+	/// for which `include` holds which the rules don't know, if the rules forbid them.
+	fn forbid_unknown(&self, include: &(dyn Fn(usize) -> bool + Sync)) -> Result<(), Error> {
+		if self.rules.unknown_policy() != UnknownPolicy::Forbid {
+			return Ok(());
+		}
+
+		let bodies = self.module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+		let classes: BTreeSet<_> = bodies.iter()
+			.enumerate()
+			.filter(|(index, _)| include(*index))
+			.flat_map(|(_, func_body)| func_body.code().elements())
+			.map(|instruction| match instruction {
+				elements::Instruction::Call(func) => self.intrinsics.get(func).unwrap_or(instruction),
+				_ => instruction,
+			})
+			.filter(|instruction| !self.rules.is_known(instruction))
+			.map(InstructionType::op)
+			.collect();
+		if classes.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::new(ErrorKind::UnknownInstructions(classes.into_iter().collect()), 0))
+		}
+	}
+
+	/// Determine the metered blocks of the function body at `index` in the code section, with the
+	/// costs given by `Rules::block_cost`.
+	pub(crate) fn metered_blocks(&self, index: usize, func_body: &elements::FuncBody) -> Result<Vec<MeteredBlock>, Error> {
+		self.blocks(index, func_body, Merging::default(), None)
+	}
+
+	/// Same as `metered_blocks`, along with the costs of the charged instructions by class.
+	pub(crate) fn class_costs(
+		&self,
+		index: usize,
+		func_body: &elements::FuncBody,
+	) -> Result<(Vec<MeteredBlock>, ClassCosts), Error> {
+		let mut class_costs = BTreeMap::new();
+		let blocks = self.blocks(index, func_body, Merging::default(), Some(&mut class_costs))?;
+		Ok((blocks, class_costs))
+	}
+
+	/// Same as `metered_blocks`, with the conservative choices relaxed according to `merging`,
+	/// adding the costs of the charged instructions to `class_costs` if given.
+	fn blocks(
+		&self,
+		index: usize,
+		func_body: &elements::FuncBody,
+		merging: Merging,
+		class_costs: Option<&mut ClassCosts>,
+	) -> Result<Vec<MeteredBlock>, Error> {
+		let index = self.imported_funcs + index as u32;
+		let locals: u64 = func_body.locals().iter().map(|local| u64::from(local.count())).sum();
+		if let Some(limit) = self.rules.max_locals() {
+			if locals > u64::from(limit) {
+				return Err(Error::new(ErrorKind::TooManyLocals { declared: locals, limit }, 0).in_function(index));
 			}
 		}
+
+		let loop_multiplier = self.names.get(&index)
+			.and_then(|names| names.iter().find_map(|name| self.rules.loop_multiplier(name)))
+			.unwrap_or(1);
+		// Saturated costs are rejected as overflowing the gas amount.
+		let (local_cost, byte_cost) = (u64::from(self.rules.local_cost()), u64::from(self.rules.local_byte_cost()));
+		let entry_cost = func_body.locals()
+			.iter()
+			.map(|local| {
+				let cost = local_cost.saturating_add(value_bytes(local.value_type()) * byte_cost);
+				u64::from(local.count()).saturating_mul(cost)
+			})
+			.fold(0u64, u64::saturating_add);
+		let mut blocks = self.instruction_blocks(func_body.code(), loop_multiplier, entry_cost, merging, class_costs)
+			.map_err(|e| e.in_function(index))?;
+		for block in &mut blocks {
+			block.cost = self.rules.block_cost(index, block);
+		}
+		Ok(blocks)
+	}
+
+	/// Determine the metered blocks of a function body, charging `entry_cost` in the block the
+	/// function is entered with.
+	fn instruction_blocks(
+		&self,
+		instructions: &elements::Instructions,
+		loop_multiplier: u32,
+		entry_cost: u64,
+		merging: Merging,
+		class_costs: Option<&mut ClassCosts>,
+	) -> Result<Vec<MeteredBlock>, Error> {
+		let mut visitor = MeteringVisitor {
+			counter: Counter::new(loop_multiplier, merging),
+			rules: self.rules,
+			intrinsics: &self.intrinsics,
+			instructions: instructions.elements(),
+			prepaid_func: self.prepaid_func,
+			module: self.module,
+			arities: &self.arities,
+			class_costs,
+		};
+
+		// Begin an implicit function (i.e. `func...end`) block.
+		visitor.counter.begin_control_block(0, false);
+		visitor.counter.prepay(entry_cost).map_err(|kind| Error::new(kind, 0))?;
+
+		visit(instructions.elements(), &mut visitor).map_err(|e| match e {
+			visit::Error::Visitor(e) => e,
+			visit::Error::UnexpectedElse(pos) | visit::Error::TrailingInstruction(pos) =>
+				Error::new(ErrorKind::MalformedBody, pos),
+			visit::Error::UnclosedBlocks =>
+				Error::new(ErrorKind::MalformedBody, instructions.elements().len()),
+		})?;
+
+		let mut finalized_blocks = visitor.counter.finalized_blocks;
+		finalized_blocks.sort_unstable_by_key(|block| block.start_pos);
+		Ok(finalized_blocks)
+	}
+
+	/// Determine the metered blocks of the function body at `index` in the code section as
+	/// charged according to the `config`, checking that their costs fit into the gas amount.
+	pub(crate) fn planned_blocks(
+		&self,
+		index: usize,
+		func_body: &elements::FuncBody,
+		config: &GasConfig,
+	) -> Result<Vec<MeteredBlock>, Error> {
+		// Without checks, the function section may declare fewer functions than there are bodies.
+		let function = self.imported_funcs + index as u32;
+		let params = self.arities.get(function as usize)
+			.ok_or_else(|| Error::new(ErrorKind::MalformedBody, 0).in_function(function))?
+			.0 as u32;
+
+		// Charges at loops and exits sum the costs of the blocks, which count joined code twice.
+		let merging = Merging {
+			join_arms: config.join_charges && !config.loop_charges && !config.exit_charges,
+			returns: config.merge_returns,
+			loop_branches: config.merge_loop_branches,
+		};
+		let blocks = self.blocks(index, func_body, merging, None)?;
+		let grow_metering = self.rules.grow_metering();
+		if config.inline_grow_charges
+			&& grow_metering.is_charged()
+			&& func_body.code().elements().iter().any(|instruction| matches!(instruction, elements::Instruction::GrowMemory(_)))
+		{
+			grow_pages_local(params, func_body, grow_metering.scratch_locals())
+				.map_err(|e| e.in_function(function))?;
+		}
+		let blocks = if config.loop_charges {
+			charge_at_loops(func_body.code().elements(), blocks)
+				.map_err(|e| e.in_function(function))?
+		} else if config.exit_charges {
+			charge_at_exits(func_body.code().elements(), blocks, self.prepaid_func)
+		} else {
+			blocks
+		};
+
+		// Block costs must fit into an i32 reinterpreted as u32, or into an i64 if the amount is one.
+		let max_cost = if config.i64_amounts { i64::MAX as u64 } else { u64::from(u32::MAX) };
+		if let Some(block) = blocks.iter().find(|block| block.cost > max_cost) {
+			return Err(Error::new(ErrorKind::CostOverflow, block.start_pos).in_function(self.imported_funcs + index as u32));
+		}
+		Ok(blocks)
 	}
+}
 
-	counter.finalized_blocks.sort_unstable_by_key(|block| block.start_pos);
-	Ok(counter.finalized_blocks)
+/// Size of a value of type `value_type` in bytes.
+fn value_bytes(value_type: ValueType) -> u64 {
+	match value_type {
+		ValueType::I32 | ValueType::F32 => 4,
+		ValueType::I64 | ValueType::F64 => 8,
+		#[cfg(feature = "simd")]
+		ValueType::V128 => 16,
+	}
 }
 
-pub fn inject_counter<R: Rules>(
-	instructions: &mut elements::Instructions,
+/// Determine the metered blocks of every function body of the module for which `include` holds,
+/// given its position in the code section. The other bodies have no metered blocks.
+///
+/// If `prepaid_func` is given, the explicit charges by calls to it are folded into the blocks,
+/// see `GasConfig::with_folded_charges`.
+fn determine_module_metered_blocks<R: Rules + ?Sized>(
+	module: &elements::Module,
 	rules: &R,
-	gas_func: u32,
-) -> Result<(), ()> {
-	let blocks = determine_metered_blocks(instructions, rules)?;
-	insert_metering_calls(instructions, blocks, gas_func)
+	prepaid_func: Option<u32>,
+	include: &(dyn Fn(usize) -> bool + Sync),
+) -> Result<Vec<Vec<MeteredBlock>>, Error> {
+	let metering = ModuleMetering::new(module, rules, prepaid_func);
+	metering.forbid_unknown(include)?;
+	let determine = |(index, func_body): (usize, &elements::FuncBody)| {
+		if !include(index) {
+			return Ok(Vec::new());
+		}
+		metering.metered_blocks(index, func_body)
+	};
+
+	// The results are collected in order before looking for an error, so that the error of the
+	// first failing function is returned regardless of how the bodies are scheduled.
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	#[cfg(feature = "parallel")]
+	let metered_blocks: Vec<_> = bodies.par_iter().enumerate().map(determine).collect();
+	#[cfg(not(feature = "parallel"))]
+	let metered_blocks = bodies.iter().enumerate().map(determine);
+	metered_blocks.into_iter().collect()
+}
+
+/// Names of the functions of the module, by function index, taken from the name section and the
+/// exports.
+fn function_names(module: &elements::Module) -> BTreeMap<u32, Vec<String>> {
+	let mut names: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+
+	for entry in module.export_section().map_or(&[][..], |export_section| export_section.entries()) {
+		if let elements::Internal::Function(index) = *entry.internal() {
+			names.entry(index).or_default().push(entry.field().into());
+		}
+	}
+
+	// The name section is not parsed on deserialization by default, in which case it is still
+	// a custom section.
+	let parsed_module;
+	let module = if module.names_section().is_none() && module.has_names_section() {
+		parsed_module = module.clone().parse_names().unwrap_or_else(|(_, module)| module);
+		&parsed_module
+	} else {
+		module
+	};
+	if let Some(function_names) = module.names_section().and_then(|name_section| name_section.functions()) {
+		for (index, name) in function_names.names() {
+			names.entry(index).or_default().push(name.clone());
+		}
+	}
+
+	names
 }
 
-// Then insert metering calls into a sequence of instructions given the block locations and costs.
-fn insert_metering_calls(
+// Then insert metering instructions into a sequence of instructions given the block locations and
+// costs. `charge` appends the instructions charging the given cost. The explicit charges by calls
+// to `prepaid_func`, which were folded into the blocks, are removed. Returns the positions of the
+// inserted charges and the new positions of the original instructions.
+fn insert_metering<F>(
 	instructions: &mut elements::Instructions,
 	blocks: Vec<MeteredBlock>,
-	gas_func: u32,
+	prepaid_func: Option<u32>,
+	charge: F,
 )
-	-> Result<(), ()>
+	-> Result<(Vec<usize>, Vec<usize>), Error>
+	where F: Fn(u64, &mut Vec<elements::Instruction>)
 {
-	use parity_wasm::elements::Instruction::*;
-
 	// To do this in linear time, construct a new vector of instructions, copying over old
 	// instructions one by one and injecting new ones as required.
 	let new_instrs_len = instructions.elements().len() + 2 * blocks.len();
@@ -370,13 +1099,19 @@ fn insert_metering_calls(
 	);
 	let new_instrs = instructions.elements_mut();
 
+	let prepaid: Vec<bool> = (0..original_instrs.len())
+		.map(|pos| is_prepaid_charge(&original_instrs, pos, prepaid_func))
+		.collect();
+
+	let mut charge_positions = Vec::with_capacity(blocks.len());
+	let mut positions = Vec::with_capacity(original_instrs.len());
 	let mut block_iter = blocks.into_iter().peekable();
 	for (original_pos, instr) in original_instrs.into_iter().enumerate() {
 		// If there the next block starts at this position, inject metering instructions.
 		let used_block = if let Some(block) = block_iter.peek() {
 			if block.start_pos == original_pos {
-				new_instrs.push(I32Const(block.cost as i32));
-				new_instrs.push(Call(gas_func));
+				charge_positions.push(new_instrs.len());
+				charge(block.cost, new_instrs);
 				true
 			} else { false }
 		} else { false };
@@ -385,102 +1120,618 @@ fn insert_metering_calls(
 			block_iter.next();
 		}
 
-		// Copy over the original instruction.
-		new_instrs.push(instr);
+		// Copy over the original instruction. A removed charge maps to the instruction following it.
+		positions.push(new_instrs.len());
+		if !prepaid[original_pos] {
+			new_instrs.push(instr);
+		}
 	}
 
-	if block_iter.next().is_some() {
-		return Err(());
+	if let Some(block) = block_iter.next() {
+		return Err(Error::new(ErrorKind::MalformedBody, block.start_pos));
 	}
 
-	Ok(())
+	Ok((charge_positions, positions))
 }
 
-/// Transforms a given module into one that charges gas for code to be executed by proxy of an
-/// imported gas metering function.
-///
-/// The output module imports a function "gas" from the specified module with type signature
-/// [i32] -> []. The argument is the amount of gas required to continue execution. The external
-/// function is meant to keep track of the total amount of gas used and trap or otherwise halt
-/// execution of the runtime if the gas usage exceeds some allowed limit.
-///
-/// The body of each function is divided into metered blocks, and the calls to charge gas are
-/// inserted at the beginning of every such block of code. A metered block is defined so that,
-/// unless there is a trap, either all of the instructions are executed or none are. These are
-/// similar to basic blocks in a control flow graph, except that in some cases multiple basic
-/// blocks can be merged into a single metered block. This is the case if any path through the
-/// control flow graph containing one basic block also contains another.
-///
-/// Charging gas is at the beginning of each metered block ensures that 1) all instructions
-/// executed are already paid for, 2) instructions that will not be executed are not charged for
-/// unless execution traps, and 3) the number of calls to "gas" is minimized. The corollary is that
-/// modules instrumented with this metering code may charge gas for instructions not executed in
-/// the event of a trap.
+/// The positions of the `return` instructions and the final `end` of a leaf function without
+/// loops or branches out of the function, which are all the points where it can exit.
 ///
-/// Additionally, each `memory.grow` instruction found in the module is instrumented to first make
-/// a call to charge gas for the additional pages requested. This cannot be done as part of the
-/// block level gas charges as the gas cost is not static and depends on the stack argument to
-/// `memory.grow`.
-///
-/// The above transformations are performed for every function body defined in the module. This
-/// function also rewrites all function indices references by code, table elements, etc., since
-/// the addition of an imported functions changes the indices of module-defined functions.
-///
-/// This routine runs in time linear in the size of the input module.
-///
-/// The function fails if the module contains any operation forbidden by gas rule set, returning
-/// the original module as an Err.
-pub fn inject_gas_counter<R: Rules>(
-	module: elements::Module,
-	rules: &R,
-	gas_module_name: &str,
-)
-	-> Result<elements::Module, elements::Module>
-{
-	// Injecting gas counting external
-	let mut mbuilder = builder::from_module(module);
-	let import_sig = mbuilder.push_signature(
-		builder::signature()
-			.with_param(ValueType::I32)
-			.build_sig()
-		);
-
-	mbuilder.push_import(
-		builder::import()
-			.module(gas_module_name)
-			.field("gas")
-			.external().func(import_sig)
-			.build()
-		);
+/// Returns `None` for other functions. Calls to `prepaid_func` whose charges are folded don't
+/// count as calls.
+fn exit_points(instructions: &[elements::Instruction], prepaid_func: Option<u32>) -> Option<Vec<usize>> {
+	use parity_wasm::elements::Instruction::*;
 
-	// back to plain module
-	let mut module = mbuilder.build();
+	let mut depth = 0usize;
+	let mut exits = Vec::new();
+	for (pos, instruction) in instructions.iter().enumerate() {
+		match instruction {
+			Call(_) if is_prepaid_charge(instructions, pos, prepaid_func) => {}
+			Call(_) | CallIndirect(_, _) | Loop(_) | GrowMemory(_) => return None,
+			Block(_) | If(_) => depth += 1,
+			End if depth == 0 => exits.push(pos),
+			End => depth -= 1,
+			Return => exits.push(pos),
+			Br(label) | BrIf(label) if *label as usize >= depth => return None,
+			BrTable(table) if table.table.iter().chain(Some(&table.default)).any(|label| *label as usize >= depth) =>
+				return None,
+			_ => {}
+		}
+	}
+	Some(exits)
+}
 
-	// calculate actual function index of the imported definition
-	//    (subtract all imports that are NOT functions)
+/// Replace the metered blocks of a leaf function by charges of the whole cost of its body at each
+/// of its exits, if the function is eligible and this takes fewer charges.
+fn charge_at_exits(
+	instructions: &[elements::Instruction],
+	blocks: Vec<MeteredBlock>,
+	prepaid_func: Option<u32>,
+) -> Vec<MeteredBlock> {
+	let exits = match exit_points(instructions, prepaid_func) {
+		Some(exits) if exits.len() < blocks.len() => exits,
+		_ => return blocks,
+	};
+	// Keep the blocks if the whole cost overflows, it is rejected later if a block does as well.
+	let cost = match blocks.iter().try_fold(0u64, |cost, block| cost.checked_add(block.cost)) {
+		Some(cost) => cost,
+		None => return blocks,
+	};
+	exits.into_iter().map(|start_pos| MeteredBlock { start_pos, cost }).collect()
+}
 
-	let gas_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
-	let total_func = module.functions_space() as u32;
-	let mut need_grow_counter = false;
-	let mut error = false;
+/// Replace the metered blocks of a function body by one charge at its entry and one at the start
+/// of the body of each loop, each of the sum of the costs of the blocks up to the next loop.
+///
+/// Without loops, the code between two of these points runs at most once each time the first is
+/// reached, so the sum is an upper bound of the cost of any path through it.
+fn charge_at_loops(instructions: &[elements::Instruction], blocks: Vec<MeteredBlock>) -> Result<Vec<MeteredBlock>, Error> {
+	use parity_wasm::elements::Instruction::*;
+
+	// The position of the charge covering each instruction, 0 for the function entry and the
+	// first instruction of the body for loops.
+	let mut charge_positions = Vec::with_capacity(instructions.len());
+	let mut frames = Vec::new();
+	let mut charge_position = 0;
+	for (pos, instruction) in instructions.iter().enumerate() {
+		charge_positions.push(charge_position);
+		match instruction {
+			Block(_) | If(_) => frames.push(charge_position),
+			Loop(_) => {
+				frames.push(charge_position);
+				charge_position = pos + 1;
+			}
+			End => charge_position = frames.pop().unwrap_or(0),
+			_ => {}
+		}
+	}
+
+	let mut costs = BTreeMap::new();
+	for block in blocks {
+		let charge_position = charge_positions.get(block.start_pos).copied().unwrap_or(0);
+		let cost = costs.entry(charge_position).or_insert(0u64);
+		*cost = cost.checked_add(block.cost).ok_or_else(|| Error::new(ErrorKind::CostOverflow, charge_position))?;
+	}
+	Ok(costs.into_iter().map(|(start_pos, cost)| MeteredBlock { start_pos, cost }).collect())
+}
+
+/// Determine the metered blocks of the function bodies of the `module` for which `include` holds,
+/// as charged according to the `config`, checking that their costs fit into the gas amount.
+pub(crate) fn plan_metering<R: Rules + ?Sized>(
+	module: &elements::Module,
+	rules: &R,
+	config: &GasConfig,
+	include: &(dyn Fn(usize) -> bool + Sync),
+) -> Result<Vec<Vec<MeteredBlock>>, Error> {
+	let metering = ModuleMetering::new(module, rules, config.prepaid_func(module));
+	metering.forbid_unknown(include)?;
+	let plan = |(index, func_body): (usize, &elements::FuncBody)| {
+		if !include(index) {
+			return Ok(Vec::new());
+		}
+		metering.planned_blocks(index, func_body, config)
+	};
+
+	// The results are collected in order before looking for an error, so that the error of the
+	// first failing function is returned regardless of how the bodies are scheduled.
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	#[cfg(feature = "parallel")]
+	let metered_blocks: Vec<_> = bodies.par_iter().enumerate().map(plan).collect();
+	#[cfg(not(feature = "parallel"))]
+	let metered_blocks = bodies.iter().enumerate().map(plan);
+	metered_blocks.into_iter().collect()
+}
+
+/// Determine the metered blocks of all function bodies of the `module` as charged by default, along
+/// with the costs of their charged instructions by class.
+pub(crate) fn plan_class_costs<R: Rules + ?Sized>(
+	module: &elements::Module,
+	rules: &R,
+) -> Result<Vec<(Vec<MeteredBlock>, ClassCosts)>, Error> {
+	let metering = ModuleMetering::new(module, rules, None);
+	metering.forbid_unknown(&|_| true)?;
+	module.code_section()
+		.map_or(&[][..], |code_section| code_section.bodies())
+		.iter()
+		.enumerate()
+		.map(|(index, func_body)| metering.class_costs(index, func_body))
+		.collect()
+}
+
+/// Name and signature of the imported gas metering function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasConfig<'a> {
+	/// Module from which the function is imported.
+	pub module: &'a str,
+	/// Field name of the function.
+	pub field: &'a str,
+	/// Whether the function takes the gas amount as `i64` instead of `i32`.
+	pub i64_amounts: bool,
+	/// Whether explicit charges by a function the module already imports are folded.
+	pub fold_charges: bool,
+	/// Whether eligible leaf functions are charged at their exits.
+	pub exit_charges: bool,
+	/// Whether `memory.grow` is charged by inlined code instead of an added function.
+	pub inline_grow_charges: bool,
+	/// Whether functions are only charged at their entry and at the start of their loops.
+	pub loop_charges: bool,
+	/// Whether the code following an `if` is charged at the end of its arms.
+	pub join_charges: bool,
+	/// Whether branches out of the function don't end the metered blocks of the enclosing blocks.
+	pub merge_returns: bool,
+	/// Whether conditional branches to loops don't end the metered block they are in.
+	pub merge_loop_branches: bool,
+	/// Whether the module is checked to be well formed first.
+	pub checked: bool,
+	/// Whether the metered module is validated.
+	pub validate_output: bool,
+	/// How the injected functions are named, if not as by default.
+	pub debug_names: Option<DebugNames>,
+	/// Indices of functions whose bodies are not metered.
+	pub exempt_functions: &'a [u32],
+	/// Names of exported functions whose bodies are not metered.
+	pub exempt_exports: &'a [&'a str],
+}
+
+impl<'a> GasConfig<'a> {
+	pub fn new(module: &'a str, field: &'a str) -> Self {
+		GasConfig {
+			module,
+			field,
+			i64_amounts: false,
+			fold_charges: false,
+			exit_charges: false,
+			inline_grow_charges: false,
+			loop_charges: false,
+			join_charges: false,
+			merge_returns: false,
+			merge_loop_branches: false,
+			checked: false,
+			validate_output: false,
+			debug_names: None,
+			exempt_functions: &[],
+			exempt_exports: &[],
+		}
+	}
+
+	/// Fold the charges the module already makes on its own into the injected ones.
+	///
+	/// If the module already imports the gas function, e.g. because its SDK charges for some
+	/// operations, each `i32.const N; call $gas` pair (`i64.const` for `i64` amounts) is removed
+	/// and `N` added to the cost of the metered block containing it. The import itself is kept.
+	/// Explicit charges are not subject to loop multipliers.
+	pub fn with_folded_charges(mut self) -> Self {
+		self.fold_charges = true;
+		self
+	}
+
+	/// Charge leaf functions with simple control flow once at their exit instead of at the start
+	/// of each metered block.
+	///
+	/// A function is eligible if it calls no functions, has no loops, does not grow the memory and
+	/// does not branch out of its body other than by `return`. Each execution of it then reaches
+	/// exactly one of its exits, where the cost of the whole body is charged. This is only done if
+	/// it takes fewer charges than the metered blocks, e.g. for accessors with an `if`. Note that
+	/// the code not executed in the function is charged as well, nothing is charged for it if it
+	/// traps, and it runs before it is paid for, so it must not have effects which survive
+	/// running out of gas.
+	pub fn with_exit_charges(mut self) -> Self {
+		self.exit_charges = true;
+		self
+	}
+
+	/// Charge `memory.grow` by code inlined before each `memory.grow` instead of replacing it by
+	/// calls of an added function.
+	///
+	/// This saves a call per `memory.grow` and a function index, at the price of the code charging
+	/// for it being repeated, and of an `i32` local and the scratch locals of the grow strategy
+	/// being added to each function growing the memory.
+	pub fn with_inline_grow_charges(mut self) -> Self {
+		self.inline_grow_charges = true;
+		self
+	}
+
+	/// Charge functions only when they are entered and at the start of each iteration of their
+	/// loops, for the code up to the next loop, instead of at the start of each metered block.
+	///
+	/// This takes far fewer charges, at the price of precision: the code of all branches up to the
+	/// next loop is charged, whether it runs or not, as well as the code after a loop, which is
+	/// charged before it. The costs of the instructions are the same as without it. Exit charges
+	/// have no effect with it, a function without loops is charged once at its entry anyway.
+	pub fn with_loop_charges(mut self) -> Self {
+		self.loop_charges = true;
+		self
+	}
+
+	/// Charge the code following an `if` with an `else` together with the last metered block of
+	/// each arm, instead of at its start, when the arms are the only ways to reach it.
+	///
+	/// The code following an `if` is already charged with the code preceding it, unless an arm may
+	/// branch out of the `if`. In that case, it needs a charge of its own, which this saves if no
+	/// branch targets the `if` itself: every path to the code then runs one of the arms to its end.
+	/// Each path is charged the same as without it, only earlier. The block costs of the report
+	/// then count the joined code in both arms. Exit and loop charges take precedence.
+	pub fn with_joined_charges(mut self) -> Self {
+		self.join_charges = true;
+		self
+	}
+
+	/// Don't end the metered blocks of the blocks enclosing a `return`, or a branch to the label of
+	/// the function body, at their `end`.
+	///
+	/// By default, the code following a block which may be left by returning has a charge of its
+	/// own, so that it is only charged if it runs. With this, it is charged with the code preceding
+	/// the block instead. No code is charged later than it runs, and the code charged in vain when
+	/// the function returns early is at most the one up to the end of the function, once per call.
+	/// This mostly saves charges for loops which may return from their body, as in a search: the
+	/// code following the `if` holding the `return` is charged once per iteration with the rest of
+	/// the loop body instead of on its own.
+	pub fn with_merged_returns(mut self) -> Self {
+		self.merge_returns = true;
+		self
+	}
+
+	/// Don't end the metered block a `br_if` to a loop is in at the `br_if`.
+	///
+	/// By default, the code following a conditional branch to a loop has a charge of its own, so
+	/// that it is only charged if the branch is not taken. With this, it is charged with the code
+	/// preceding the branch instead. No code is charged later than it runs, and the code charged
+	/// in vain each time the branch is taken is the rest of its metered block, which ends at the
+	/// next branch or at the end of the innermost loop or `if` holding it at the latest. This saves
+	/// a charge per iteration of loops continuing from the middle of their body, e.g. `continue`
+	/// statements, at the price of charging the rest of the body in the iterations taking them.
+	pub fn with_merged_loop_branches(mut self) -> Self {
+		self.merge_loop_branches = true;
+		self
+	}
+
+	/// Index of the function the module already imports under the name and signature of the
+	/// gas function, if charges are to be folded.
+	pub(crate) fn prepaid_func(&self, module: &elements::Module) -> Option<u32> {
+		if !self.fold_charges {
+			return None;
+		}
+		let amount_type = if self.i64_amounts { ValueType::I64 } else { ValueType::I32 };
+		let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+		module.import_section()?
+			.entries()
+			.iter()
+			.filter_map(|entry| match entry.external() {
+				elements::External::Function(type_ref) => Some((entry, *type_ref)),
+				_ => None,
+			})
+			.position(|(entry, type_ref)| {
+				entry.module() == self.module && entry.field() == self.field && match types.get(type_ref as usize) {
+					Some(elements::Type::Function(ty)) => ty.params() == [amount_type] && ty.results().is_empty(),
+					None => false,
+				}
+			})
+			.map(|index| index as u32)
+	}
+
+	/// Import the function with the type signature [i64] -> [], which allows block costs above
+	/// `u32::MAX`.
+	pub fn with_i64_amounts(mut self) -> Self {
+		self.i64_amounts = true;
+		self
+	}
+
+	/// Check that the module is well formed before metering it, see `check::well_formed`, so
+	/// that malformed modules, e.g. untrusted ones, are rejected with `ErrorKind::Malformed`
+	/// instead of making the metering panic.
+	pub fn with_checks(mut self) -> Self {
+		self.checked = true;
+		self
+	}
+
+	/// Validate the metered module, see `validation::validate`, so that a bug of the metering
+	/// producing an invalid module is reported with `ErrorKind::InvalidOutput` instead of being
+	/// found by the runtime loading it.
+	///
+	/// The error tells the function and the position in the metered module, whose function indices
+	/// are shifted by the gas function. An invalid input module most likely fails as well. The
+	/// module is copied beforehand, so that it can be given back on failure.
+	pub fn with_output_validation(mut self) -> Self {
+		self.validate_output = true;
+		self
+	}
+
+	/// Name the gas function and the function replacing `memory.grow` as configured by `debug`,
+	/// see the `debug_names` module.
+	pub fn with_debug_names(mut self, debug: DebugNames) -> Self {
+		self.debug_names = Some(debug);
+		self
+	}
+
+	/// Don't meter the bodies of the functions with the given indices in the function index space
+	/// of the original module, e.g. trusted shims whose cost is accounted for separately.
+	///
+	/// The calls in their bodies are still updated to the shifted function indices, but no gas is
+	/// charged in them, neither for their blocks nor for `memory.grow`, and charges the module
+	/// makes on its own in them are not folded. Indices of imported functions are ignored.
+	pub fn with_exempt_functions(mut self, functions: &'a [u32]) -> Self {
+		self.exempt_functions = functions;
+		self
+	}
+
+	/// Don't meter the bodies of the functions exported under the given names, in the same way as
+	/// `with_exempt_functions`. Names which aren't exported functions are ignored.
+	pub fn with_exempt_exports(mut self, exports: &'a [&'a str]) -> Self {
+		self.exempt_exports = exports;
+		self
+	}
+
+	/// Whether each function defined by the module is exempt from metering.
+	pub(crate) fn exempt(&self, module: &elements::Module) -> Vec<bool> {
+		let imported_funcs = module.import_count(elements::ImportCountType::Function);
+		// Bodies a malformed module declares no function for are metered, which reports them.
+		let defined_funcs = cmp::max(
+			module.function_section().map_or(0, |function_section| function_section.entries().len()),
+			module.code_section().map_or(0, |code_section| code_section.bodies().len()),
+		);
+		let exported = module.export_section()
+			.map_or(&[][..], |export_section| export_section.entries())
+			.iter()
+			.filter(|entry| self.exempt_exports.contains(&entry.field()))
+			.filter_map(|entry| match entry.internal() {
+				elements::Internal::Function(index) => Some(*index),
+				_ => None,
+			});
+
+		let mut exempt = vec![false; defined_funcs];
+		for index in self.exempt_functions.iter().copied().chain(exported) {
+			if let Some(exempt) = (index as usize).checked_sub(imported_funcs).and_then(|index| exempt.get_mut(index)) {
+				*exempt = true;
+			}
+		}
+		exempt
+	}
+}
+
+impl Default for GasConfig<'static> {
+	fn default() -> Self {
+		GasConfig::new("env", "gas")
+	}
+}
+
+/// The function is imported as `gas` from the given module.
+impl<'a> From<&'a str> for GasConfig<'a> {
+	fn from(module: &'a str) -> Self {
+		GasConfig::new(module, "gas")
+	}
+}
+
+/// A metered block of a function body, as listed in a `MeteringReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockReport {
+	/// Position of the first instruction of the block in the original function body.
+	pub original_offset: usize,
+	/// Position of the charge of the block in the metered function body. The instructions of the
+	/// block follow the charge, unless the block is charged at an exit of the function.
+	pub offset: usize,
+	/// Gas charged for the block.
+	pub cost: u64,
+}
+
+/// The metered blocks of a function body, as listed in a `MeteringReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionReport {
+	/// Index of the function in the function index space of the original module.
+	pub function: u32,
+	/// The metered blocks in the order of the function body.
+	pub blocks: Vec<BlockReport>,
+}
+
+/// Where `inject_gas_counter_with_report` inserted the charges and what they cost.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MeteringReport {
+	/// Index of the imported gas function in the metered module.
+	pub gas_func: u32,
+	/// The metered blocks of every function body defined in the module.
+	pub functions: Vec<FunctionReport>,
+	/// The positions of the original instructions in the metered function bodies.
+	pub source_map: SourceMap,
+}
+
+/// Transforms a given module into one that charges gas for code to be executed by proxy of an
+/// imported gas metering function.
+///
+/// The output module imports a function with type signature [i32] -> [] (or [i64] -> [] if
+/// requested) under the name given by `config`, which is either a `GasConfig` or just the module
+/// name, in which case the function is imported as "gas". The argument is the amount of gas required to continue execution. The
+/// external function is meant to keep track of the total amount of gas used and trap or otherwise
+/// halt execution of the runtime if the gas usage exceeds some allowed limit.
+///
+/// The body of each function is divided into metered blocks, and the calls to charge gas are
+/// inserted at the beginning of every such block of code. A metered block is defined so that,
+/// unless there is a trap, either all of the instructions are executed or none are. These are
+/// similar to basic blocks in a control flow graph, except that in some cases multiple basic
+/// blocks can be merged into a single metered block. This is the case if any path through the
+/// control flow graph containing one basic block also contains another.
+///
+/// Charging gas is at the beginning of each metered block ensures that 1) all instructions
+/// executed are already paid for, 2) instructions that will not be executed are not charged for
+/// unless execution traps, and 3) the number of calls to "gas" is minimized. The corollary is that
+/// modules instrumented with this metering code may charge gas for instructions not executed in
+/// the event of a trap.
+///
+/// Calls don't end metered blocks, in any configuration: the instructions following a call are
+/// charged together with the ones preceding it. If the callee runs out of gas, the caller was
+/// charged for the rest of its block as for any other trap.
+///
+/// Additionally, each `memory.grow` instruction found in the module is instrumented to first make
+/// a call to charge gas for the additional pages requested, as priced by the `GrowMetering` of the
+/// rule set. This cannot be done as part of the block level gas charges as the gas cost is not
+/// static and depends on the stack argument to `memory.grow`.
+///
+/// The above transformations are performed for every function body defined in the module. This
+/// function also rewrites all function indices references by code, table elements, etc., since
+/// the addition of an imported functions changes the indices of module-defined functions.
+///
+/// If the module already charges gas by calling the gas function itself, these charges can be
+/// folded into the injected ones, see `GasConfig::with_folded_charges`.
+///
+/// This routine runs in time linear in the size of the input module.
+///
+/// The function fails if the module contains any operation forbidden by gas rule set or a function
+/// body with malformed control flow, returning the original module along with the `Error`.
+pub fn inject_gas_counter<'a, R: Rules, C: Into<GasConfig<'a>>>(
+	module: elements::Module,
+	rules: &R,
+	config: C,
+)
+	-> Result<elements::Module, (elements::Module, Error)>
+{
+	inject_gas_counter_with_report(module, rules, config).map(|(module, _)| module)
+}
+
+/// Same as `inject_gas_counter`, but also returns a report listing the metered blocks of every
+/// function body with their positions before and after the instrumentation and their costs, e.g.
+/// for audit tooling.
+pub fn inject_gas_counter_with_report<'a, R: Rules, C: Into<GasConfig<'a>>>(
+	module: elements::Module,
+	rules: &R,
+	config: C,
+)
+	-> Result<(elements::Module, MeteringReport), (elements::Module, Error)>
+{
+	let config = config.into();
+	if config.checked {
+		if let Err(e) = check::well_formed(&module) {
+			return Err((module, Error::new(ErrorKind::Malformed(e), 0)));
+		}
+	}
+	let original = if config.validate_output { Some(module.clone()) } else { None };
+	// The function is imported before the new one, so its index does not change.
+	let prepaid_func = config.prepaid_func(&module);
+	let exempt = config.exempt(&module);
+
+	// Determine the metered blocks before touching the module, so that it can be given back
+	// unchanged on failure. Rewriting call indices below does not move any instruction.
+	let metered_blocks = match plan_metering(&module, rules, &config, &|index| !exempt[index]) {
+		Ok(metered_blocks) => metered_blocks,
+		Err(e) => return Err((module, e)),
+	};
+	let imported_funcs = module.import_count(elements::ImportCountType::Function) as u32;
+	let mut report = MeteringReport {
+		gas_func: imported_funcs,
+		functions: metered_blocks.iter()
+			.enumerate()
+			.map(|(index, blocks)| FunctionReport {
+				function: imported_funcs + index as u32,
+				blocks: blocks.iter()
+					.map(|block| BlockReport { original_offset: block.start_pos, offset: 0, cost: block.cost })
+					.collect(),
+			})
+			.collect(),
+		source_map: SourceMap::default(),
+	};
+	let arities = visit::function_arities(&module);
+	let (mut module, gas_func) = import_gas_func(module, &config, Vec::new());
+	let total_func = module.functions_space() as u32;
+	let grow_metering = rules.grow_metering();
+	let grow = GrowCharging::new(&*grow_metering, total_func, &config);
+	let mut need_grow_counter = false;
+
+	if let Some(code_section) = module.code_section_mut() {
+		let meter = |((func_body, blocks), function_report): (
+			(&mut elements::FuncBody, Vec<MeteredBlock>),
+			&mut FunctionReport,
+		)| {
+			if exempt[(function_report.function - imported_funcs) as usize] {
+				update_call_index(func_body.code_mut(), gas_func);
+				return ((0..func_body.code().elements().len()).collect(), false);
+			}
+			let params = arities[function_report.function as usize].0 as u32;
+			let (offsets, positions, grows) =
+				meter_body(func_body, params, blocks, gas_func, prepaid_func, &grow, &config);
+			for (block, offset) in function_report.blocks.iter_mut().zip(offsets) {
+				block.offset = offset;
+			}
+			(positions, grows)
+		};
+
+		// The bodies are instrumented independently, the results are collected in order.
+		#[cfg(feature = "parallel")]
+		let results: Vec<_> = code_section.bodies_mut()
+			.par_iter_mut()
+			.zip(metered_blocks)
+			.zip(report.functions.par_iter_mut())
+			.map(meter)
+			.collect();
+		#[cfg(not(feature = "parallel"))]
+		let results = code_section.bodies_mut()
+			.iter_mut()
+			.zip(metered_blocks)
+			.zip(report.functions.iter_mut())
+			.map(meter);
+		for (positions, grows) in results {
+			report.source_map.functions.push(positions);
+			need_grow_counter |= grows;
+		}
+	}
+
+	let module = finish_module(module, &*grow_metering, gas_func, &config, need_grow_counter);
+	if let Some(original) = original {
+		if let Err(e) = crate::validation::validate(&module) {
+			return Err((original, Error::new(ErrorKind::InvalidOutput(e), 0)));
+		}
+	}
+	Ok((module, report))
+}
+
+/// Import the gas function into the `module` and shift the indices of the functions defined by it
+/// everywhere but in the function bodies. The gas function has the given `results` after the
+/// amount. Returns the module and the index of the gas function.
+fn import_gas_func(
+	module: elements::Module,
+	config: &GasConfig,
+	results: Vec<ValueType>,
+) -> (elements::Module, u32) {
+	// The function names have to be shifted along with the indices.
+	let mut module = parse_names(module);
+
+	let amount_type = if config.i64_amounts { ValueType::I64 } else { ValueType::I32 };
+	let import_sig = resolve_type(&mut module, elements::FunctionType::new(vec![amount_type], results));
+	let import = elements::ImportEntry::new(
+		config.module.into(),
+		config.field.into(),
+		elements::External::Function(import_sig),
+	);
+	match module.import_section_mut() {
+		Some(import_section) => import_section.entries_mut().push(import),
+		None => module.insert_section(elements::Section::Import(elements::ImportSection::with_entries(vec![import])))
+			.expect("there is no import section yet; qed"),
+	}
+
+	// calculate actual function index of the imported definition
+	//    (subtract all imports that are NOT functions)
+
+	let gas_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
 
 	// Updating calling addresses (all calls to function index >= `gas_func` should be incremented)
 	for section in module.sections_mut() {
 		match section {
-			elements::Section::Code(code_section) => {
-				for func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), gas_func);
-					if inject_counter(func_body.code_mut(), rules, gas_func).is_err() {
-						error = true;
-						break;
-					}
-					if rules.memory_grow_cost().is_some()
-						&& inject_grow_counter(func_body.code_mut(), total_func) > 0
-					{
-						need_grow_counter = true;
-					}
-				}
-			},
 			elements::Section::Export(export_section) => {
 				for export in export_section.entries_mut() {
 					if let elements::Internal::Function(func_index) = export.internal_mut() {
@@ -501,13 +1752,80 @@ pub fn inject_gas_counter<R: Rules>(
 			elements::Section::Start(start_idx) => {
 				if *start_idx >= gas_func { *start_idx += 1}
 			},
+			elements::Section::Name(name_section) => {
+				if let Some(function_names) = name_section.functions_mut() {
+					shift_names(function_names.names_mut(), gas_func);
+					function_names.names_mut().insert(gas_func, config.field.into());
+				}
+				if let Some(local_names) = name_section.locals_mut() {
+					shift_names(local_names.local_names_mut(), gas_func);
+				}
+			},
 			_ => { }
 		}
 	}
+	(module, gas_func)
+}
 
-	if error { return Err(module); }
+/// Insert the charges of the metered `blocks` into the function body, with the function indices
+/// shifted by the import of `gas_func`, and charge `memory.grow` as given by `grow`. `params` is
+/// the number of parameters of the function.
+///
+/// Returns the positions of the charges, the positions of the original instructions and whether
+/// `memory.grow` was replaced by calls of the function charging for it.
+fn meter_body(
+	func_body: &mut elements::FuncBody,
+	params: u32,
+	blocks: Vec<MeteredBlock>,
+	gas_func: u32,
+	prepaid_func: Option<u32>,
+	grow: &GrowCharging,
+	config: &GasConfig,
+) -> (Vec<usize>, Vec<usize>, bool) {
+	update_call_index(func_body.code_mut(), gas_func);
+	let (offsets, positions) = insert_metering(func_body.code_mut(), blocks, prepaid_func, |cost, instructions| {
+		instructions.push(if config.i64_amounts {
+			elements::Instruction::I64Const(cost as i64)
+		} else {
+			elements::Instruction::I32Const(cost as i32)
+		});
+		instructions.push(elements::Instruction::Call(gas_func));
+	})
+		.expect("metered blocks are determined from the same function body; qed");
+	match grow {
+		GrowCharging::Uncharged => (offsets, positions, false),
+		GrowCharging::Counter(grow_counter_func) => {
+			let grows = inject_grow_counter(func_body.code_mut(), *grow_counter_func) > 0;
+			(offsets, positions, grows)
+		}
+		GrowCharging::Inline { amount, scratch_locals } => {
+			let shifted = inline_grow_charges(func_body, params, amount, *scratch_locals, gas_func);
+			let offsets = offsets.into_iter().map(|offset| shifted[offset]).collect();
+			let positions = positions.into_iter().map(|position| shifted[position]).collect();
+			(offsets, positions, false)
+		}
+	}
+}
 
-	if need_grow_counter { Ok(add_grow_counter(module, rules, gas_func)) } else { Ok(module) }
+/// Add the function replacing `memory.grow` if it is needed and name the injected functions.
+fn finish_module(
+	mut module: elements::Module,
+	grow_metering: &dyn GrowMetering,
+	gas_func: u32,
+	config: &GasConfig,
+	need_grow_counter: bool,
+) -> elements::Module {
+	if need_grow_counter {
+		module = add_grow_counter(module, grow_metering, gas_func, config.i64_amounts);
+	}
+	if let Some(debug) = config.debug_names {
+		debug.apply(&mut module, debug_names::GAS_IMPORT, elements::Internal::Function(gas_func));
+		if need_grow_counter {
+			let grow_counter_func = module.functions_space() as u32 - 1;
+			debug.apply(&mut module, debug_names::GROW_COUNTER, elements::Internal::Function(grow_counter_func));
+		}
+	}
+	module
 }
 
 #[cfg(test)]
@@ -516,14 +1834,7 @@ mod tests {
 	use parity_wasm::elements::Instruction::*;
 	use super::*;
 	use crate::rules;
-
-	pub fn get_function_body(module: &elements::Module, index: usize)
-		-> Option<&[elements::Instruction]>
-	{
-		module.code_section()
-			.and_then(|code_section| code_section.bodies().get(index))
-			.map(|func_body| func_body.code().elements())
-	}
+	use crate::testing::{get_function_body, parse_wat, validate_module};
 
 	#[test]
 	fn simple_grow() {
@@ -566,8 +1877,17 @@ mod tests {
 			&vec![
 				GetLocal(0),
 				GetLocal(0),
-				I32Const(10000),
-				I32Mul,
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				SetLocal(1),
+				I64Const(u32::MAX as i64),
+				GetLocal(1),
+				GetLocal(1),
+				I64Const(u32::MAX as i64),
+				I64GtU,
+				Select,
+				I32WrapI64,
 				Call(0),
 				GrowMemory(0),
 				End,
@@ -578,6 +1898,107 @@ mod tests {
 		wabt::wasm2wat(&binary).unwrap();
 	}
 
+	#[test]
+	fn inline_grow() {
+		let module = builder::module()
+			.memory().build()
+			.function()
+				.signature().with_param(ValueType::I32).build()
+				.body()
+					.with_locals(vec![elements::Local::new(1, ValueType::I64)])
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetLocal(0),
+							GrowMemory(0),
+							Drop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let (injected_module, report) = inject_gas_counter_with_report(
+			module,
+			&rules::Set::default().with_grow_cost(10000),
+			GasConfig::default().with_inline_grow_charges(),
+		).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(3),
+				Call(0),
+				GetLocal(0),
+				TeeLocal(2),
+				GetLocal(2),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				SetLocal(3),
+				I64Const(u32::MAX as i64),
+				GetLocal(3),
+				GetLocal(3),
+				I64Const(u32::MAX as i64),
+				I64GtU,
+				Select,
+				I32WrapI64,
+				Call(0),
+				GrowMemory(0),
+				Drop,
+				End
+			][..]
+		);
+		assert_eq!(injected_module.functions_space(), 2);
+		let func_body = &injected_module.code_section().unwrap().bodies()[0];
+		assert_eq!(func_body.locals(), &[
+			elements::Local::new(1, ValueType::I64),
+			elements::Local::new(1, ValueType::I32),
+			elements::Local::new(1, ValueType::I64),
+		][..]);
+		assert_eq!(report.source_map.functions[0], vec![2, 17, 18, 19]);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn inline_grow_too_many_locals() {
+		let module = builder::module()
+			.memory().build()
+			.function()
+				.signature().with_param(ValueType::I32).build()
+				.body()
+					.with_locals(vec![elements::Local::new(u32::MAX - 2, ValueType::I64)])
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetLocal(0),
+							GrowMemory(0),
+							Drop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		// The flat strategy needs the pages local and one scratch local.
+		let (original, error) = inject_gas_counter(
+			module.clone(),
+			&rules::Set::default().with_grow_cost(10000),
+			GasConfig::default().with_inline_grow_charges(),
+		).unwrap_err();
+		assert_eq!(original, module);
+		assert_eq!(error, Error {
+			kind: ErrorKind::TooManyLocals { declared: u64::from(u32::MAX) - 1, limit: u32::MAX - 2 },
+			function: Some(0),
+			offset: 0,
+		});
+
+		// Without the charge, no locals are added.
+		assert!(inject_gas_counter(module, &rules::Set::default(), GasConfig::default().with_inline_grow_charges()).is_ok());
+	}
+
 	#[test]
 	fn grow_no_gas_no_track() {
 		let module = builder::module()
@@ -617,6 +2038,917 @@ mod tests {
 		wabt::wasm2wat(&binary).unwrap();
 	}
 
+	#[test]
+	fn grow_strategies() {
+		let grow = r#"
+			(module
+				(memory 1)
+				(func (param i32) (result i32)
+					get_local 0
+					grow_memory))
+		"#;
+
+		let tiered = rules::Set::default()
+			.with_grow_strategy(rules::GrowStrategy::Tiered(vec![(16, 10), (64, 100)]));
+		let injected_module = inject_gas_counter(parse_wat(grow), &tiered, "env").unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[3], Call(2));
+		let grow_counter = &injected_module.code_section().unwrap().bodies()[1];
+		assert_eq!(grow_counter.locals(), &[elements::Local::new(3, elements::ValueType::I64)][..]);
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+
+		let stepped = rules::Set::default()
+			.with_grow_strategy(rules::GrowStrategy::Stepped(vec![(16, 1000), (64, 10000)]));
+		let injected_module = inject_gas_counter(parse_wat(grow), &stepped, "env").unwrap();
+		let grow_counter = &injected_module.code_section().unwrap().bodies()[1];
+		assert_eq!(grow_counter.locals(), &[elements::Local::new(2, elements::ValueType::I64)][..]);
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+
+		let host = rules::Set::default().with_grow_strategy(rules::GrowStrategy::HostDelegated);
+		let injected_module = inject_gas_counter(parse_wat(grow), &host, "env").unwrap();
+		assert_eq!(injected_module.functions_space(), 2);
+
+		let forbidden = rules::Set::default().with_grow_strategy(rules::GrowStrategy::Forbidden);
+		let error = inject_gas_counter(parse_wat(grow), &forbidden, "env").unwrap_err().1;
+		assert_eq!(error.kind, ErrorKind::ForbiddenInstruction(GrowMemory(0)));
+	}
+
+	#[test]
+	fn names() {
+		let bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(r#"
+				(module
+					(import "env" "ext" (func $ext))
+					(memory 1)
+					(func $helper (param $pages i32) (result i32)
+						get_local $pages
+						grow_memory)
+					(func $call (export "call")
+						call $ext))
+			"#)
+			.unwrap();
+		let module = elements::deserialize_buffer(bytes.as_ref()).unwrap();
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default().with_grow_cost(1), "env").unwrap();
+		let injected_module = elements::deserialize_buffer::<elements::Module>(&serialize(injected_module).unwrap())
+			.unwrap()
+			.parse_names()
+			.unwrap();
+
+		let name_section = injected_module.names_section().unwrap();
+		let names = name_section.functions().unwrap().names()
+			.iter()
+			.map(|(index, name)| (index, name.as_str()))
+			.collect::<Vec<_>>();
+		assert_eq!(names, vec![(0, "ext"), (1, "gas"), (2, "helper"), (3, "call"), (4, "grow_counter")]);
+		let local_names = name_section.locals().unwrap().local_names();
+		assert_eq!(local_names.get(2).and_then(|locals| locals.get(0)).map(String::as_str), Some("pages"));
+	}
+
+	#[test]
+	fn report() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(func (param i32)
+					get_local 0
+					if
+						call $ext
+					end))
+		"#);
+
+		let (injected_module, report) = inject_gas_counter_with_report(module, &rules::Set::default(), "env").unwrap();
+
+		assert_eq!(report.gas_func, 1);
+		assert_eq!(report.functions.len(), 1);
+		assert_eq!(report.functions[0].function, 1);
+		assert_eq!(
+			report.functions[0].blocks,
+			vec![
+				BlockReport { original_offset: 0, offset: 0, cost: 2 },
+				BlockReport { original_offset: 2, offset: 4, cost: 1 },
+			],
+		);
+		let body = get_function_body(&injected_module, 0).unwrap();
+		for block in &report.functions[0].blocks {
+			assert_eq!(body[block.offset..block.offset + 2], [I32Const(block.cost as i32), Call(report.gas_func)]);
+		}
+		assert_eq!(report.source_map.functions, vec![vec![2, 3, 6, 7, 8]]);
+		assert_eq!(body[6], Call(0));
+	}
+
+	#[test]
+	fn custom_import_name() {
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body().build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(
+			module,
+			&rules::Set::default(),
+			GasConfig::new("metering", "charge"),
+		).unwrap();
+
+		let import = &injected_module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("metering", "charge"));
+	}
+
+	#[test]
+	fn reuses_type() {
+		let module = parse_wat(r#"
+			(module
+				(type (func (param i32) (result i32)))
+				(type (func (param i32)))
+				(func (param i32)
+					(drop (memory.grow (get_local 0))))
+				(memory 1))
+		"#);
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default().with_grow_cost(1), "env").unwrap();
+
+		let types = injected_module.type_section().unwrap().types();
+		assert_eq!(types.len(), 2);
+		let import = &injected_module.import_section().unwrap().entries()[0];
+		assert_eq!(import.external(), &elements::External::Function(1));
+		let functions = injected_module.function_section().unwrap().entries();
+		assert_eq!(functions.iter().map(|func| func.type_ref()).collect::<Vec<_>>(), vec![1, 0]);
+	}
+
+	#[test]
+	fn keeps_section_order() {
+		let mut module = parse_wat(r#"
+			(module
+				(func (param i32)
+					(drop (memory.grow (get_local 0))))
+				(memory 1)
+				(data (i32.const 0) "gas"))
+		"#);
+		let code_pos = module.sections().iter()
+			.position(|section| matches!(section, elements::Section::Code(_)))
+			.unwrap();
+		module.sections_mut().insert(code_pos, elements::Section::Custom(
+			elements::CustomSection::new("before_code".into(), vec![1, 2, 3]),
+		));
+		module.sections_mut().insert(0, elements::Section::Custom(
+			elements::CustomSection::new("first".into(), vec![]),
+		));
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default().with_grow_cost(1), "env").unwrap();
+
+		let ids = injected_module.sections().iter()
+			.map(|section| match section {
+				elements::Section::Custom(custom) => custom.name(),
+				elements::Section::Type(_) => "type",
+				elements::Section::Import(_) => "import",
+				elements::Section::Function(_) => "function",
+				elements::Section::Memory(_) => "memory",
+				elements::Section::Code(_) => "code",
+				elements::Section::Data(_) => "data",
+				_ => "other",
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(ids, vec!["first", "type", "import", "function", "memory", "before_code", "code", "data"]);
+		assert_eq!(injected_module.function_section().unwrap().entries().len(), 2);
+		assert_eq!(injected_module.code_section().unwrap().bodies().len(), 2);
+	}
+
+	#[test]
+	fn i64_amounts() {
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(vec![Nop, Nop, End]))
+					.build()
+				.build()
+			.build();
+		let rules = rules::Set::new(
+			1,
+			vec![(rules::InstructionType::Nop, rules::Metering::Fixed(u32::MAX))].into_iter().collect(),
+		);
+
+		let (_, error) = inject_gas_counter(module.clone(), &rules, "env")
+			.expect_err("Should be error because the block cost does not fit into i32");
+		assert_eq!(error, Error { kind: ErrorKind::CostOverflow, function: Some(0), offset: 0 });
+
+		let injected_module = inject_gas_counter(
+			module,
+			&rules,
+			GasConfig::new("env", "gas").with_i64_amounts(),
+		).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I64Const(2 * u32::MAX as i64), Call(0), Nop, Nop, End][..]
+		);
+		let gas_type = &injected_module.type_section().unwrap().types()[1];
+		assert_eq!(
+			gas_type,
+			&elements::Type::Function(elements::FunctionType::new(vec![ValueType::I64], vec![]))
+		);
+	}
+
+	#[test]
+	fn near_overflow_costs() {
+		use crate::analysis::estimate::{self, Estimate, Value};
+
+		// The loop multiplier applies to the whole cost of the instructions in the loop.
+		let looping = parse_wat(r#"
+			(module
+				(func $spin (export "spin")
+					loop
+						nop
+					end))
+		"#);
+		let rules = rules::Set::new(
+			1,
+			vec![(rules::InstructionType::Nop, rules::Metering::Fixed(u32::MAX))].into_iter().collect(),
+		).with_loop_multiplier("spin", u32::MAX);
+		let (_, error) = inject_gas_counter(looping, &rules, GasConfig::new("env", "gas").with_i64_amounts())
+			.expect_err("Should be error because the block cost does not fit into i64");
+		assert_eq!(error.kind, ErrorKind::CostOverflow);
+
+		// The amount charged for growing the memory is capped instead of wrapping around.
+		let grow = parse_wat(r#"
+			(module
+				(memory 1)
+				(func (export "grow") (param i32) (result i32)
+					get_local 0
+					grow_memory))
+		"#);
+		let rules = rules::Set::default().with_grow_cost(u32::MAX);
+		assert_eq!(
+			estimate::with_args(&grow, &rules, "grow", &[Value::I32(1)]).unwrap(),
+			Estimate::Exact(2 + u64::from(u32::MAX)),
+		);
+		assert_eq!(
+			estimate::with_args(&grow, &rules, "grow", &[Value::I32(2)]).unwrap(),
+			Estimate::Exact(2 + u64::from(u32::MAX)),
+		);
+		assert_eq!(
+			estimate::with_args(&grow, &rules::Set::default().with_grow_cost(3), "grow", &[Value::I32(2)]).unwrap(),
+			Estimate::Exact(2 + 6),
+		);
+	}
+
+	#[test]
+	fn instruction_costs() {
+		let module = parse_wat(r#"
+			(module
+				(memory 1)
+				(func (param i32) (result i32)
+					get_local 0
+					i32.load
+					get_local 0
+					i32.div_u
+					i32.const 1
+					i32.add))
+		"#);
+		let rules = rules::Set::default()
+			.with_instruction_cost(rules::InstructionType::Div, 20)
+			.with_instruction_cost(rules::InstructionType::Load, 5)
+			.with_instruction_cost(rules::InstructionType::Local, 0);
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(27));
+	}
+
+	#[test]
+	fn call_costs() {
+		let module = parse_wat(r#"
+			(module
+				(type $unary (func (param i64) (result i64)))
+				(import "env" "__wasm_i64_mul" (func $mul (param i64 i64) (result i64)))
+				(table 1 funcref)
+				(elem (i32.const 0) $id)
+				(func $id (type $unary) (local.get 0))
+				(func (param i64) (result i64)
+					(call_indirect (type $unary)
+						(call $mul (call $id (local.get 0)) (i64.const 3))
+						(i32.const 0))))
+		"#);
+		let rules = rules::Set::default()
+			.with_call_cost(10)
+			.with_call_indirect_cost(25)
+			.with_well_known_intrinsics();
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		// Six instructions, with the call of the intrinsic charged like `i64.mul`.
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[0], I32Const(6 + 10 + 25));
+	}
+
+	#[test]
+	fn exempt_functions() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(memory 1)
+				(func $shim (export "shim") (param i32) (result i32)
+					call $ext
+					(memory.grow (local.get 0)))
+				(func (param i32) (result i32)
+					(call $shim (local.get 0)))
+				(func
+					call $ext))
+		"#);
+		let rules = rules::Set::default().with_grow_cost(1);
+		let exempt = [3];
+		let config = GasConfig::default().with_exempt_functions(&exempt).with_exempt_exports(&["shim"]);
+
+		let (injected_module, report) = inject_gas_counter_with_report(module, &rules, config).unwrap();
+
+		// The shims only have their calls shifted, and `memory.grow` is not charged in them.
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![Call(0), GetLocal(0), GrowMemory(0), End][..],
+		);
+		assert_eq!(get_function_body(&injected_module, 2).unwrap(), &vec![Call(0), End][..]);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![I32Const(2), Call(1), GetLocal(0), Call(2), End][..],
+		);
+		assert_eq!(injected_module.functions_space(), 5);
+		assert!(report.functions[0].blocks.is_empty());
+		assert_eq!(report.source_map.functions[0], vec![0, 1, 2, 3]);
+	}
+
+	#[test]
+	fn block_costs() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(func (param i32) (result i32)
+					(i32.add (local.get 0) (i32.const 1)))
+				(func
+					call $ext
+					(block
+						call $ext)))
+		"#);
+		let rules = rules::Set::default();
+		// Double the costs of the function at index 2, which is the second one defined.
+		let corrected = rules::BlockCosts::new(&rules, |function, block: &MeteredBlock| {
+			if function == 2 { block.cost * 2 } else { block.cost }
+		});
+
+		let injected_module = inject_gas_counter(module, &corrected, "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(3));
+		// Two `call` and `block`.
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[0], I32Const(2 * 3));
+	}
+
+	#[test]
+	fn forbidden_instructions() {
+		let source = r#"
+			(module
+				(memory 1)
+				(func (param i64) (result i64)
+					(drop (memory.grow (i32.const 1)))
+					(i64.div_u (local.get 0) (i64.const 3))
+					(i64.div_s (i64.const 7))))
+		"#;
+
+		let rules = rules::Set::default().with_forbidden(rules::InstructionType::GrowMemory);
+		let (_, error) = inject_gas_counter(parse_wat(source), &rules, "env").unwrap_err();
+		assert_eq!(error, Error::new(ErrorKind::ForbiddenInstruction(GrowMemory(0)), 1).in_function(0));
+
+		let rules = rules::Set::default().with_forbidden_instruction(I64DivS);
+		let (_, error) = inject_gas_counter(parse_wat(source), &rules, "env").unwrap_err();
+		assert_eq!(error, Error::new(ErrorKind::ForbiddenInstruction(I64DivS), 7).in_function(0));
+
+		// Forbidden instructions are known to rules rejecting unknown ones.
+		let rules = rules.with_instruction_cost(rules::InstructionType::Div, 10)
+			.with_unknown_policy(rules::UnknownPolicy::Reject);
+		let (_, error) = inject_gas_counter(parse_wat(source), &rules, "env").unwrap_err();
+		assert_eq!(error, Error::new(ErrorKind::UnknownInstruction(I32Const(1)), 0).in_function(0));
+	}
+
+	#[test]
+	fn unknown_instructions() {
+		let source = r#"
+			(module
+				(memory 1)
+				(func (param i32) (result i32)
+					get_local 0
+					i32.load
+					i32.const 1
+					i32.add)
+				(func (param i32)
+					get_local 0
+					i32.const 1
+					i32.add
+					drop))
+		"#;
+		let rules = rules::Set::default()
+			.with_instruction_cost(rules::InstructionType::Local, 1)
+			.with_instruction_cost(rules::InstructionType::Const, 1)
+			.with_instruction_cost(rules::InstructionType::ControlFlow, 1);
+
+		let charged = rules.clone().with_unknown_policy(rules::UnknownPolicy::Charge(10));
+		let injected_module = inject_gas_counter(parse_wat(source), &charged, "env").unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(22));
+
+		let rejecting = rules.clone().with_unknown_policy(rules::UnknownPolicy::Reject);
+		let (_, error) = inject_gas_counter(parse_wat(source), &rejecting, "env").unwrap_err();
+		assert_eq!(error, Error::new(ErrorKind::UnknownInstruction(I32Load(2, 0)), 1).in_function(0));
+
+		let forbidding = rules.with_unknown_policy(rules::UnknownPolicy::Forbid);
+		let (_, error) = inject_gas_counter(parse_wat(source), &forbidding, "env").unwrap_err();
+		let classes = vec![rules::InstructionType::Add, rules::InstructionType::Load];
+		assert_eq!(error, Error::new(ErrorKind::UnknownInstructions(classes), 0));
+	}
+
+	#[test]
+	fn locals() {
+		let module = parse_wat(r#"
+			(module
+				(func (param i32) (local i32 i64) (local f32)
+					loop
+						get_local 0
+						br_if 0
+					end))
+		"#);
+
+		let rules = rules::Set::default().with_local_cost(10).with_loop_multiplier("*", 2);
+		let injected_module = inject_gas_counter(module.clone(), &rules, "env").unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(31));
+
+		// 16 bytes of locals.
+		let by_width = rules.clone().with_local_byte_cost(2);
+		let injected_module = inject_gas_counter(module.clone(), &by_width, "env").unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(31 + 32));
+
+		let (_, error) = inject_gas_counter(module.clone(), &rules.with_max_locals(2), "env")
+			.expect_err("Should be error because the function declares 3 locals");
+		assert_eq!(error, Error { kind: ErrorKind::TooManyLocals { declared: 3, limit: 2 }, function: Some(0), offset: 0 });
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default().with_max_locals(3), "env").unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(1));
+	}
+
+	#[test]
+	fn costs_by_stack_effect() {
+		/// Charges every instruction by the number of values it moves, and 1 for control flow.
+		struct DataMovement;
+
+		impl Rules for DataMovement {
+			fn instruction_cost(&self, _instruction: &elements::Instruction) -> Option<u32> {
+				Some(1)
+			}
+
+			fn instruction_cost_with_effect(
+				&self,
+				_instruction: &elements::Instruction,
+				effect: Option<StackEffect>,
+			) -> Option<u32> {
+				Some(effect.map_or(1, |effect| effect.pops + effect.pushes))
+			}
+
+			fn memory_grow_cost(&self) -> Option<rules::MemoryGrowCost> {
+				None
+			}
+		}
+
+		let module = parse_wat(r#"
+			(module
+				(func $add (param i32 i32) (result i32)
+					(i32.add (local.get 0) (local.get 1)))
+				(func (param i32) (result i32)
+					(select
+						(call $add (local.get 0) (i32.const 1))
+						(i32.const 2)
+						(local.get 0))
+					(drop (i32.const 3))
+					nop))
+		"#);
+
+		let injected_module = inject_gas_counter(module, &DataMovement, "env").unwrap();
+
+		// Two `local.get` and `i32.add`.
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(1 + 1 + 3));
+		// Two `local.get`, three `i32.const`, `call`, `select` and `drop`, while `nop` moves nothing.
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[0], I32Const(2 + 3 + 3 + 4 + 1));
+	}
+
+	#[cfg(feature = "sign_ext")]
+	#[test]
+	fn sign_extension() {
+		let mut features = wabt::Features::new();
+		features.enable_sign_extension();
+		let module = elements::deserialize_buffer(&wabt::wat2wasm_with_features(r#"
+			(module
+				(func (param i32) (result i64)
+					get_local 0
+					i32.extend8_s
+					i64.extend_s/i32
+					i64.extend32_s))
+		"#, features).unwrap()).unwrap();
+		let rules = rules::Set::default()
+			.with_instruction_cost(rules::InstructionType::SignExtension, 4);
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(10));
+	}
+
+	#[cfg(feature = "multi_value")]
+	#[test]
+	fn multi_value() {
+		let module = parse_wat(r#"
+			(module
+				(func $pair (param i32) (result i32 i32)
+					get_local 0
+					(block (result i32)
+						get_local 0
+						get_local 0
+						br_if 0
+						drop
+						i32.const 1)
+					return)
+				(func (result i32)
+					i32.const 1
+					call $pair
+					i32.add))
+		"#);
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[..2], [I32Const(6), Call(0)]);
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[7..9], [I32Const(2), Call(0)]);
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[..2], [I32Const(3), Call(0)]);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[cfg(feature = "simd")]
+	#[test]
+	fn simd() {
+		use parity_wasm::elements::SimdInstruction::*;
+
+		// wabt emits the final SIMD opcodes, which differ from the draft decoded by parity-wasm.
+		let module = builder::module()
+			.function()
+				.signature().with_result(elements::ValueType::I32).build()
+				.body()
+					.with_instructions(elements::Instructions::new(vec![
+						Simd(V128Const(Box::new([1; 16]))),
+						Simd(V128Const(Box::new([2; 16]))),
+						Simd(I32x4Add),
+						Simd(I32x4ExtractLane(0)),
+						End,
+					]))
+					.build()
+				.build()
+			.build();
+		let rules = rules::Set::default()
+			.with_instruction_cost(rules::InstructionType::Simd, 3);
+
+		let injected_module = inject_gas_counter(module.clone(), &rules, "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(12));
+
+		let error = inject_gas_counter(module, &rules.with_forbidden_simd(), "env").unwrap_err().1;
+		assert_eq!(error.kind, ErrorKind::ForbiddenInstruction(Simd(V128Const(Box::new([1; 16])))));
+	}
+
+	#[cfg(feature = "atomics")]
+	#[test]
+	fn atomics() {
+		use parity_wasm::elements::AtomicsInstruction::*;
+		use parity_wasm::elements::MemArg;
+
+		let memarg = MemArg { align: 2, offset: 0 };
+		let module = builder::module()
+			.memory().build()
+			.function()
+				.signature().with_result(elements::ValueType::I32).build()
+				.body()
+					.with_instructions(elements::Instructions::new(vec![
+						I32Const(0),
+						I32Const(1),
+						Atomics(I32AtomicStore(memarg.clone())),
+						I32Const(0),
+						I32Const(1),
+						Atomics(I32AtomicRmwAdd(memarg.clone())),
+						I32Const(0),
+						I32Const(1),
+						Atomics(AtomicWake(memarg.clone())),
+						I32Add,
+						End,
+					]))
+					.build()
+				.build()
+			.build();
+		let rules = rules::Set::default()
+			.with_instruction_cost(rules::InstructionType::AtomicStore, 2)
+			.with_instruction_cost(rules::InstructionType::AtomicRmw, 3)
+			.with_instruction_cost(rules::InstructionType::AtomicWait, 10);
+
+		let injected_module = inject_gas_counter(module.clone(), &rules, "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(22));
+
+		let error = inject_gas_counter(module, &rules.with_forbidden_atomics(), "env").unwrap_err().1;
+		assert_eq!(error.kind, ErrorKind::ForbiddenInstruction(Atomics(I32AtomicStore(memarg))));
+	}
+
+	#[cfg(feature = "bulk")]
+	#[test]
+	fn bulk_memory() {
+		let mut features = wabt::Features::new();
+		features.enable_bulk_memory();
+		let module = elements::deserialize_buffer(&wabt::wat2wasm_with_features(r#"
+			(module
+				(memory 1)
+				(func (param i32)
+					get_local 0
+					i32.const 0
+					i32.const 16
+					memory.fill
+					get_local 0
+					i32.const 16
+					i32.const 16
+					memory.copy))
+		"#, features).unwrap()).unwrap();
+		let rules = rules::Set::default()
+			.with_instruction_cost(rules::InstructionType::BulkMemory, 100);
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(206));
+	}
+
+	#[test]
+	fn intrinsics() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func (param i64 i64) (result i64)))
+				(import "env" "__divdi3" (func (param i64 i64) (result i64)))
+				(func (result i64)
+					i64.const 1
+					i64.const 2
+					call 0
+					i64.const 3
+					call 1))
+		"#);
+		let rules = rules::Set::new(
+			1,
+			vec![(rules::InstructionType::Div, rules::Metering::Fixed(100))].into_iter().collect(),
+		).with_well_known_intrinsics();
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap()[0],
+			I32Const(104)
+		);
+	}
+
+	#[test]
+	fn folded_charges() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "gas" (func (param i32)))
+				(func (param i32)
+					i32.const 50
+					call 0
+					get_local 0
+					if
+						i32.const 7
+						call 0
+					end))
+		"#);
+		let rules = rules::Set::default();
+
+		let injected_module = inject_gas_counter(module.clone(), &rules, GasConfig::default().with_folded_charges()).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(52),
+				Call(1),
+				GetLocal(0),
+				If(elements::BlockType::NoResult),
+					I32Const(7),
+					Call(1),
+				End,
+				End,
+			][..]
+		);
+
+		// Without folding, the explicit charges are metered like any other call.
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[0], I32Const(4));
+	}
+
+	#[test]
+	fn exit_charges() {
+		let module = parse_wat(r#"
+			(module
+				(func (param i32) (result i32)
+					get_local 0
+					if (result i32)
+						i32.const 1
+					else
+						i32.const 2
+					end)
+				(func (param i32)
+					loop
+						get_local 0
+						br_if 0
+					end))
+		"#);
+		let config = GasConfig::default().with_exit_charges();
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default(), config).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				GetLocal(0),
+				If(elements::BlockType::Value(elements::ValueType::I32)),
+					I32Const(1),
+				Else,
+					I32Const(2),
+				End,
+				I32Const(4),
+				Call(0),
+				End,
+			][..]
+		);
+		// Functions with loops are not eligible.
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[..2], [I32Const(1), Call(0)]);
+	}
+
+	#[test]
+	fn loop_charges() {
+		let source = r#"
+			(module
+				(func (param i32) (result i32)
+					get_local 0
+					i32.const 1
+					i32.add
+					set_local 0
+					loop
+						get_local 0
+						i32.eqz
+						if
+							i32.const 1
+							set_local 0
+						end
+						get_local 0
+						br_if 0
+					end
+					get_local 0))
+		"#;
+		let config = GasConfig::default().with_loop_charges();
+
+		let injected_module = inject_gas_counter(parse_wat(source), &rules::Set::default(), config).unwrap();
+
+		// The code after the loop is charged at the entry, both branches of the `if` at the start
+		// of the loop body.
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(6),
+				Call(0),
+				GetLocal(0),
+				I32Const(1),
+				I32Add,
+				SetLocal(0),
+				Loop(elements::BlockType::NoResult),
+					I32Const(7),
+					Call(0),
+					GetLocal(0),
+					I32Eqz,
+					If(elements::BlockType::NoResult),
+						I32Const(1),
+						SetLocal(0),
+					End,
+					GetLocal(0),
+					BrIf(0),
+				End,
+				GetLocal(0),
+				End,
+			][..]
+		);
+
+		let charges = |module: &elements::Module| get_function_body(module, 0).unwrap()
+			.iter()
+			.filter(|instruction| **instruction == Call(0))
+			.count();
+		let metered = inject_gas_counter(parse_wat(source), &rules::Set::default(), "env").unwrap();
+		assert!(charges(&metered) > charges(&injected_module));
+	}
+
+	#[test]
+	fn joined_charges() {
+		let source = r#"
+			(module
+				(func (param i32) (result i32)
+					block
+						get_local 0
+						if
+							get_local 0
+							br_if 1
+							i32.const 1
+							set_local 0
+						else
+							i32.const 2
+							set_local 0
+						end
+						get_local 0
+						drop
+					end
+					get_local 0))
+		"#;
+		let config = GasConfig::default().with_joined_charges();
+
+		let injected_module = inject_gas_counter(parse_wat(source), &rules::Set::default(), config).unwrap();
+
+		// The code following the `if` is charged with the last block of each arm.
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(4),
+				Call(0),
+				Block(elements::BlockType::NoResult),
+					GetLocal(0),
+					If(elements::BlockType::NoResult),
+						I32Const(2),
+						Call(0),
+						GetLocal(0),
+						BrIf(1),
+						I32Const(4),
+						Call(0),
+						I32Const(1),
+						SetLocal(0),
+					Else,
+						I32Const(4),
+						Call(0),
+						I32Const(2),
+						SetLocal(0),
+					End,
+					GetLocal(0),
+					Drop,
+				End,
+				GetLocal(0),
+				End,
+			][..]
+		);
+
+		// Without an `else`, the code following the `if` is reached without running an arm.
+		let without_else = source.replace("else\n\t\t\t\t\t\t\ti32.const 2\n\t\t\t\t\t\t\tset_local 0\n", "");
+		let injected_module = inject_gas_counter(parse_wat(&without_else), &rules::Set::default(), config).unwrap();
+		assert_eq!(get_function_body(&injected_module, 0).unwrap()[14..16], [I32Const(2), Call(0)]);
+	}
+
+	#[test]
+	fn loop_multipliers() {
+		let module = parse_wat(r#"
+			(module
+				(func (export "memcpy") (param i32)
+					loop
+						get_local 0
+						br_if 0
+					end)
+				(func (export "copy") (param i32)
+					loop
+						get_local 0
+						br_if 0
+					end))
+		"#);
+		let rules = rules::Set::default()
+			.with_loop_multiplier("mem*", 4)
+			.with_loop_multiplier("memset", 8);
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(1),
+				Call(0),
+				Loop(elements::BlockType::NoResult),
+					I32Const(8),
+					Call(0),
+					GetLocal(0),
+					BrIf(0),
+				End,
+				End,
+			][..]
+		);
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[3], I32Const(2));
+	}
+
 	#[test]
 	fn call_index() {
 		let module = builder::module()
@@ -697,18 +3029,146 @@ mod tests {
 		let rules = rules::Set::default().with_forbidden_floats();
 
 
-		if inject_gas_counter(module, &rules, "env").is_ok() {
-			panic!("Should be error because of the forbidden operation")
-		}
+		let (_, error) = inject_gas_counter(module, &rules, "env")
+			.expect_err("Should be error because of the forbidden operation");
+		assert_eq!(error, Error {
+			kind: ErrorKind::ForbiddenInstruction(F32Const(555555)),
+			function: Some(0),
+			offset: 0,
+		});
+	}
+
+	#[test]
+	fn malformed_body() {
+		let module = builder::module()
+			.import().module("env").field("f").external().func(0).build()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(vec![Nop, Br(1), End]))
+					.build()
+				.build()
+			.build();
+
+		let (original, error) = inject_gas_counter(module.clone(), &rules::Set::default(), "env")
+			.expect_err("Should be error because of the branch out of the function");
+		assert_eq!(original, module);
+		assert_eq!(error, Error { kind: ErrorKind::MalformedBody, function: Some(1), offset: 1 });
 	}
 
-	fn parse_wat(source: &str) -> elements::Module {
-		let module_bytes = wabt::Wat2Wasm::new()
-			.validate(false)
-			.convert(source)
-			.expect("failed to parse module");
-		elements::deserialize_buffer(module_bytes.as_ref())
-			.expect("failed to parse module")
+	#[test]
+	fn body_without_declaration() {
+		let mut module = builder::module()
+			.function()
+				.signature().build()
+				.body().build()
+				.build()
+			.build();
+		module.function_section_mut().unwrap().entries_mut().clear();
+
+		let (original, error) = inject_gas_counter(module.clone(), &rules::Set::default(), "env")
+			.expect_err("Should be error because the body has no function declared");
+		assert_eq!(original, module);
+		assert_eq!(error, Error { kind: ErrorKind::MalformedBody, function: Some(0), offset: 0 });
+	}
+
+	#[test]
+	fn error_display() {
+		let body = |instructions| builder::module()
+			.function()
+				.signature().build()
+				.body().with_instructions(elements::Instructions::new(instructions)).build()
+				.build()
+			.build();
+		let error = |module, rules: &rules::Set| inject_gas_counter(module, rules, "env")
+			.expect_err("Should be error")
+			.1;
+
+		let forbidden = error(body(vec![Nop, F32Const(0), Drop, End]), &rules::Set::default().with_forbidden_floats());
+		assert_eq!(forbidden.kind, ErrorKind::ForbiddenInstruction(F32Const(0)));
+		assert_eq!(
+			forbidden.to_string(),
+			"Instruction `f32.const 0` is forbidden by the gas rules (function 0, instruction 1)"
+		);
+
+		let malformed = error(body(vec![Br(1), End]), &rules::Set::default());
+		assert_eq!(malformed.kind, ErrorKind::MalformedBody);
+		assert_eq!(malformed.to_string(), "Malformed control flow (function 0, instruction 0)");
+
+		let expensive = rules::Set::new(
+			1,
+			vec![(rules::InstructionType::Nop, rules::Metering::Fixed(u32::MAX))].into_iter().collect(),
+		);
+		let overflow = error(body(vec![Nop, Nop, End]), &expensive);
+		assert_eq!(overflow.kind, ErrorKind::CostOverflow);
+		assert_eq!(
+			overflow.to_string(),
+			"Cost of a metered block overflows the gas amount (function 0, instruction 0)"
+		);
+
+		assert_eq!(Error::new(ErrorKind::MalformedBody, 3).to_string(), "Malformed control flow (instruction 3)");
+	}
+
+	#[test]
+	fn invalid_output() {
+		let module = builder::module()
+			.function()
+				.signature().with_result(ValueType::I32).build()
+				.body()
+					.with_instructions(elements::Instructions::new(vec![I64Const(1), End]))
+					.build()
+				.build()
+			.build();
+		let config = GasConfig::default().with_output_validation();
+
+		let (original, error) = inject_gas_counter(module.clone(), &rules::Set::default(), config)
+			.expect_err("Should be error because the body returns an i64");
+		assert_eq!(original, module);
+		// The charge precedes the constant in the metered body.
+		assert_eq!(error.kind, ErrorKind::InvalidOutput(crate::validation::Error::Body {
+			function: 1,
+			pos: 3,
+			error: crate::validation::TypeError::Mismatch { expected: ValueType::I32, found: Some(ValueType::I64) },
+		}));
+
+		let valid = parse_wat(r#"(module (func (param i32) (result i32) get_local 0 i32.const 1 i32.add))"#);
+		assert!(inject_gas_counter(valid, &rules::Set::default(), config).is_ok());
+	}
+
+	#[test]
+	fn merged_returns_and_loop_branches() {
+		let source = r#"
+			(module
+				(memory 1)
+				(func $find (param $ptr i32) (param $len i32) (result i32)
+					(local $i i32)
+					(loop $next
+						(if (i32.eqz (i32.load8_u (i32.add (get_local $ptr) (get_local $i))))
+							(then (return (get_local $i))))
+						(set_local $i (i32.add (get_local $i) (i32.const 1)))
+						(br_if $next (i32.lt_u (get_local $i) (get_local $len)))
+						(set_local $i (i32.const -1)))
+					get_local $i))
+		"#;
+		// The costs of the charges in the order of the body.
+		let charges = |config: GasConfig| {
+			let injected_module = inject_gas_counter(parse_wat(source), &rules::Set::default(), config).unwrap();
+			let charges: Vec<_> = get_function_body(&injected_module, 0).unwrap()
+				.windows(2)
+				.filter_map(|pair| match pair {
+					[I32Const(cost), Call(0)] => Some(*cost),
+					_ => None,
+				})
+				.collect();
+			validate_module(injected_module);
+			charges
+		};
+
+		assert_eq!(charges(GasConfig::default()), vec![1, 6, 2, 8, 2, 1]);
+		// The code following the `if` and the loop is charged with the loop body and on entry.
+		assert_eq!(charges(GasConfig::default().with_merged_returns()), vec![2, 14, 2, 2]);
+		// The code following the `br_if` is charged with the loop body.
+		assert_eq!(charges(GasConfig::default().with_merged_loop_branches()), vec![1, 6, 2, 10, 1]);
 	}
 
 	macro_rules! test_gas_counter_injection {
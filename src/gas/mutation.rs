@@ -0,0 +1,201 @@
+//! Deterministic mutation harness for the gas metering algorithm.
+//!
+//! The cost computed for a function body should not depend on encoding choices that do not
+//! change its behaviour. This module takes the gas fixture modules and applies random,
+//! semantics-preserving mutations to each function body: wrapping stack-neutral code into an
+//! extra `block`, inserting `nop`s and splitting integer constants into a sum of two constants.
+//! For every mutated body the metered blocks must still be path-equivalent (checked with the
+//! control flow graph from the `validation` module), with and without joined charges, and the
+//! total cost must be exactly the original one plus the cost of the instructions introduced by
+//! the mutations.
+//!
+//! All randomness is derived from a fixed seed per round, so failures are reproducible.
+
+use super::{determine_joined_blocks, determine_metered_blocks};
+use super::validation::validate_metering_injections;
+use crate::rules::Set as RuleSet;
+use crate::rules::Rules;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, BlockType, FuncBody, Instruction};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Number of mutated variants checked for every function body.
+const ROUNDS: u64 = 64;
+
+/// Maximum number of mutations applied to a single variant.
+const MAX_MUTATIONS: usize = 16;
+
+/// Returns the position of the `end` matching the structured instruction at `start`.
+fn matching_end(instructions: &[Instruction], start: usize) -> Option<usize> {
+	let mut depth = 0usize;
+	for (pos, instruction) in instructions.iter().enumerate().skip(start) {
+		match instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => depth += 1,
+			Instruction::End => {
+				depth -= 1;
+				if depth == 0 {
+					return Some(pos);
+				}
+			}
+			_ => {}
+		}
+	}
+	None
+}
+
+/// Wraps `instructions[start..=end]` into a `block` without result and adjusts the labels of all
+/// branches inside the range which escape it.
+fn wrap_into_block(instructions: &mut Vec<Instruction>, start: usize, end: usize) {
+	let mut depth = 0u32;
+	for instruction in &mut instructions[start..=end] {
+		match instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => depth += 1,
+			Instruction::End => depth -= 1,
+			Instruction::Br(label) | Instruction::BrIf(label) if *label >= depth => *label += 1,
+			Instruction::BrTable(data) => {
+				for label in data.table.iter_mut().chain(Some(&mut data.default)) {
+					if *label >= depth { *label += 1 }
+				}
+			}
+			_ => {}
+		}
+	}
+	instructions.insert(end + 1, Instruction::End);
+	instructions.insert(start, Instruction::Block(BlockType::NoResult));
+}
+
+/// Applies a single random mutation to `instructions` and returns the cost of the instructions
+/// it introduced.
+fn mutate(instructions: &mut Vec<Instruction>, rules: &RuleSet, rng: &mut StdRng) -> u32 {
+	// The trailing `end` of the function body must stay in place.
+	let pos = rng.gen_range(0..instructions.len());
+	let cost = |instruction: &Instruction| rules.instruction_cost(instruction)
+		.expect("default rule set does not forbid instructions; qed");
+
+	match rng.gen_range(0..3) {
+		0 => {
+			// Wrap a nested block/loop without results, or a `nop`, into a new block. Those are
+			// stack-neutral, so the resulting body is equivalent.
+			let end = match instructions[pos] {
+				Instruction::Block(BlockType::NoResult) | Instruction::Loop(BlockType::NoResult) =>
+					matching_end(instructions, pos),
+				Instruction::Nop => Some(pos),
+				_ => None,
+			};
+			match end {
+				Some(end) => {
+					wrap_into_block(instructions, pos, end);
+					cost(&Instruction::Block(BlockType::NoResult))
+				}
+				None => 0,
+			}
+		}
+		1 => {
+			instructions.insert(pos, Instruction::Nop);
+			cost(&Instruction::Nop)
+		}
+		_ => {
+			let split = match instructions[pos] {
+				Instruction::I32Const(value) => {
+					let lhs: i32 = rng.gen();
+					Some([
+						Instruction::I32Const(lhs),
+						Instruction::I32Const(value.wrapping_sub(lhs)),
+						Instruction::I32Add,
+					])
+				}
+				Instruction::I64Const(value) => {
+					let lhs: i64 = rng.gen();
+					Some([
+						Instruction::I64Const(lhs),
+						Instruction::I64Const(value.wrapping_sub(lhs)),
+						Instruction::I64Add,
+					])
+				}
+				_ => None,
+			};
+			match split {
+				Some(split) => {
+					let added = cost(&split[1]) + cost(&split[2]);
+					instructions.splice(pos..=pos, split.iter().cloned());
+					added
+				}
+				None => 0,
+			}
+		}
+	}
+}
+
+fn total_cost(instructions: &[Instruction], rules: &RuleSet) -> u64 {
+	determine_metered_blocks(&elements::Instructions::new(instructions.to_vec()), rules)
+		.expect("fixture bodies are valid; qed")
+		.iter()
+		.map(|block| block.cost)
+		.sum()
+}
+
+fn check_body(body: &FuncBody, rules: &RuleSet) {
+	let original = body.code().elements();
+	let original_cost = total_cost(original, rules);
+
+	for seed in 0..ROUNDS {
+		let mut rng = StdRng::seed_from_u64(seed);
+		let mut instructions = original.to_vec();
+		let mut added_cost = 0;
+		for _ in 0..rng.gen_range(1..=MAX_MUTATIONS) {
+			added_cost += mutate(&mut instructions, rules, &mut rng);
+		}
+
+		let mutated = FuncBody::new(
+			body.locals().to_vec(),
+			elements::Instructions::new(instructions),
+		);
+		let blocks = determine_metered_blocks(mutated.code(), rules)
+			.expect("mutations preserve validity; qed");
+
+		assert!(
+			validate_metering_injections(&mutated, rules, &blocks).unwrap(),
+			"metered blocks are not path-equivalent (seed {})", seed,
+		);
+		let joined_blocks = determine_joined_blocks(mutated.code(), rules)
+			.expect("mutations preserve validity; qed");
+		assert!(
+			validate_metering_injections(&mutated, rules, &joined_blocks).unwrap(),
+			"joined blocks are not path-equivalent (seed {})", seed,
+		);
+		assert_eq!(
+			total_cost(mutated.code().elements(), rules),
+			original_cost + u64::from(added_cost),
+			"total cost depends on the encoding (seed {})", seed,
+		);
+	}
+}
+
+fn check_fixture(source: &str) {
+	let module_bytes = wabt::wat2wasm(source).expect("failed to parse fixture");
+	let module: elements::Module = elements::deserialize_buffer(&module_bytes)
+		.expect("failed to deserialize fixture");
+	let rules = RuleSet::default();
+
+	for body in module.code_section().iter().flat_map(|section| section.bodies()) {
+		check_body(body, &rules);
+	}
+}
+
+macro_rules! def_mutation_test {
+	( $name:ident ) => {
+		#[test]
+		fn $name() {
+			check_fixture(include_str!(
+				concat!("../../tests/fixtures/gas/", stringify!($name), ".wat")
+			));
+		}
+	};
+}
+
+def_mutation_test!(branch);
+def_mutation_test!(call);
+def_mutation_test!(ifs);
+def_mutation_test!(simple);
+def_mutation_test!(start);
@@ -0,0 +1,401 @@
+//! Gas metering of modules read from and written to streams, for modules too large to be held in
+//! memory along with a metered copy of their function bodies.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use parity_wasm::elements::{self, Deserialize, Serialize};
+
+use super::{finish_module, import_gas_func, meter_body, update_call_index, Error, GasConfig, GrowCharging, ModuleMetering};
+use crate::rules::Rules;
+
+const CUSTOM_SECTION_ID: u8 = 0;
+const CODE_SECTION_ID: u8 = 10;
+const DATA_SECTION_ID: u8 = 11;
+
+/// Error of `inject_gas_counter_streaming`.
+#[derive(Debug)]
+pub enum StreamError {
+	/// Reading the input or writing the output failed.
+	Io(io::Error),
+	/// The input is not a well-formed module.
+	Decoding(elements::Error),
+	/// The module can't be metered.
+	Gas(Error),
+}
+
+impl fmt::Display for StreamError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			StreamError::Io(e) => write!(f, "{}", e),
+			StreamError::Decoding(e) => write!(f, "{}", e),
+			StreamError::Gas(e) => write!(f, "Gas metering failed: {}", e),
+		}
+	}
+}
+
+impl From<io::Error> for StreamError {
+	fn from(e: io::Error) -> Self {
+		StreamError::Io(e)
+	}
+}
+
+impl From<elements::Error> for StreamError {
+	fn from(e: elements::Error) -> Self {
+		StreamError::Decoding(e)
+	}
+}
+
+impl From<Error> for StreamError {
+	fn from(e: Error) -> Self {
+		StreamError::Gas(e)
+	}
+}
+
+/// A range of bytes of the input.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+	start: u64,
+	len: u64,
+}
+
+fn read_span<I: Read + Seek>(input: &mut I, span: Span) -> Result<Vec<u8>, StreamError> {
+	input.seek(SeekFrom::Start(span.start))?;
+	let mut bytes = vec![0; span.len as usize];
+	input.read_exact(&mut bytes)?;
+	Ok(bytes)
+}
+
+fn copy_span<I: Read + Seek, O: Write>(input: &mut I, span: Span, output: &mut O) -> Result<(), StreamError> {
+	input.seek(SeekFrom::Start(span.start))?;
+	if io::copy(&mut input.by_ref().take(span.len), output)? != span.len {
+		return Err(StreamError::Decoding(elements::Error::UnexpectedEof));
+	}
+	Ok(())
+}
+
+fn read_var_u32<I: Read>(input: &mut I) -> Result<u32, StreamError> {
+	Ok(elements::VarUint32::deserialize(input)?.into())
+}
+
+fn write_var_u32<O: Write>(output: &mut O, value: u32) -> Result<(), StreamError> {
+	Ok(elements::VarUint32::from(value).serialize(output)?)
+}
+
+/// Position of the section with the given id in a well-ordered module.
+fn section_order(id: u8) -> u8 {
+	match id {
+		// The data count section precedes the code section.
+		12 => CODE_SECTION_ID,
+		CODE_SECTION_ID | DATA_SECTION_ID => id + 1,
+		id => id,
+	}
+}
+
+/// The sections of the input which are decoded, and where to find the others.
+struct Skeleton {
+	/// The module without its function bodies, with an empty code section in place of the one
+	/// of the input. The sections copied from the input stand in as empty data sections and as
+	/// unparsed sections respectively.
+	module: elements::Module,
+	/// The sections copied from the input, in order.
+	copied: Vec<Span>,
+	/// The function bodies, including their size.
+	bodies: Vec<Span>,
+}
+
+fn read_skeleton<I: Read + Seek>(input: &mut I) -> Result<Skeleton, StreamError> {
+	let mut header = [0; 8];
+	input.read_exact(&mut header)?;
+	if header[..4] != *b"\0asm" {
+		return Err(StreamError::Decoding(elements::Error::InvalidMagic));
+	}
+	let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+	if version != 1 {
+		return Err(StreamError::Decoding(elements::Error::UnsupportedVersion(version)));
+	}
+
+	// The sizes are checked against the length of the input before anything is read into memory.
+	let input_len = input.seek(SeekFrom::End(0))?;
+	input.seek(SeekFrom::Start(header.len() as u64))?;
+
+	let mut sections = Vec::new();
+	let mut copied = Vec::new();
+	let mut bodies = Vec::new();
+	let mut last_order = 0;
+	loop {
+		let start = input.stream_position()?;
+		let mut id = [0];
+		if input.read(&mut id)? == 0 {
+			break;
+		}
+		let id = id[0];
+		let size = u64::from(read_var_u32(input)?);
+		let payload_start = input.stream_position()?;
+		let end = payload_start + size;
+		if end > input_len {
+			return Err(StreamError::Decoding(elements::Error::UnexpectedEof));
+		}
+
+		if id != CUSTOM_SECTION_ID {
+			let order = section_order(id);
+			if order < last_order {
+				return Err(StreamError::Decoding(elements::Error::SectionsOutOfOrder));
+			} else if order == last_order {
+				return Err(StreamError::Decoding(elements::Error::DuplicatedSections(id)));
+			}
+			last_order = order;
+		}
+
+		match id {
+			CODE_SECTION_ID => {
+				for _ in 0..read_var_u32(input)? {
+					let body_start = input.stream_position()?;
+					let body_size = u64::from(read_var_u32(input)?);
+					let body_end = input.stream_position()? + body_size;
+					if body_end > end {
+						return Err(StreamError::Decoding(elements::Error::InconsistentLength {
+							expected: size as usize,
+							actual: (body_end - payload_start) as usize,
+						}));
+					}
+					bodies.push(Span { start: body_start, len: body_end - body_start });
+					input.seek(SeekFrom::Start(body_end))?;
+				}
+				let actual = input.stream_position()?;
+				if actual != end {
+					return Err(StreamError::Decoding(elements::Error::InconsistentLength {
+						expected: size as usize,
+						actual: (actual - payload_start) as usize,
+					}));
+				}
+				sections.push(elements::Section::Code(elements::CodeSection::default()));
+			}
+			DATA_SECTION_ID => {
+				copied.push(Span { start, len: end - start });
+				sections.push(elements::Section::Data(elements::DataSection::default()));
+			}
+			CUSTOM_SECTION_ID if !is_name_section(input)? => {
+				copied.push(Span { start, len: end - start });
+				sections.push(elements::Section::Unparsed { id, payload: Vec::new() });
+			}
+			_ => {
+				let bytes = read_span(input, Span { start, len: end - start })?;
+				sections.push(elements::deserialize_buffer(&bytes)?);
+			}
+		}
+		input.seek(SeekFrom::Start(end))?;
+	}
+
+	let module = elements::Module::new(sections);
+	let functions = module.function_section().map_or(0, |function_section| function_section.entries().len());
+	if bodies.len() != functions {
+		return Err(StreamError::Decoding(elements::Error::InconsistentCode));
+	}
+	Ok(Skeleton { module, copied, bodies })
+}
+
+/// Whether the custom section whose payload starts at the current position is the name section.
+fn is_name_section<I: Read>(input: &mut I) -> Result<bool, StreamError> {
+	if read_var_u32(input)? != 4 {
+		return Ok(false);
+	}
+	let mut name = [0; 4];
+	input.read_exact(&mut name)?;
+	Ok(name == *b"name")
+}
+
+/// Same as `inject_gas_counter`, but reads the module from `input` and writes the metered module
+/// to `output` without holding the whole module in memory.
+///
+/// Only the sections which the instrumentation changes are decoded. The function bodies are
+/// metered one at a time, and the data section and custom sections other than the name section
+/// are copied from the input as they are. As the size of the code section precedes the function
+/// bodies, the metered bodies are written to `scratch` first, e.g. a temporary file, and copied
+/// to `output` from there. The output is the same as the one of `inject_gas_counter` for modules
+/// encoded in the canonical way. Nothing is written to `output` if the module can't be metered.
+pub fn inject_gas_counter_streaming<'a, I, O, S, R, C>(
+	input: &mut I,
+	output: &mut O,
+	scratch: &mut S,
+	rules: &R,
+	config: C,
+) -> Result<(), StreamError>
+where
+	I: Read + Seek,
+	O: Write,
+	S: Read + Write + Seek,
+	R: Rules,
+	C: Into<GasConfig<'a>>,
+{
+	let config = config.into();
+	let Skeleton { module, copied, bodies } = read_skeleton(input)?;
+
+	// The function is imported before the new one, so its index does not change.
+	let prepaid_func = config.prepaid_func(&module);
+	let exempt = config.exempt(&module);
+	let original = module.clone();
+	let metering = ModuleMetering::new(&original, rules, prepaid_func);
+
+	let (module, gas_func) = import_gas_func(module, &config, Vec::new());
+	let total_func = module.functions_space() as u32;
+	let grow_metering = rules.grow_metering();
+	let grow = GrowCharging::new(&*grow_metering, total_func, &config);
+
+	let scratch_start = scratch.stream_position()?;
+	let mut need_grow_counter = false;
+	for (index, span) in bodies.iter().enumerate() {
+		let mut func_body: elements::FuncBody = elements::deserialize_buffer(&read_span(input, *span)?)?;
+		if exempt[index] {
+			update_call_index(func_body.code_mut(), gas_func);
+		} else {
+			let blocks = metering.planned_blocks(index, &func_body, &config)?;
+			let params = metering.arities[metering.imported_funcs as usize + index].0 as u32;
+			let (_, _, grows) = meter_body(&mut func_body, params, blocks, gas_func, prepaid_func, &grow, &config);
+			need_grow_counter |= grows;
+		}
+		func_body.serialize(scratch)?;
+	}
+	let code_size = scratch.stream_position()? - scratch_start;
+	let module = finish_module(module, &*grow_metering, gas_func, &config, need_grow_counter);
+
+	output.write_all(b"\0asm")?;
+	output.write_all(&module.version().to_le_bytes())?;
+	let mut copied = copied.into_iter();
+	for section in module.into_sections() {
+		match section {
+			elements::Section::Data(_) | elements::Section::Unparsed { .. } => {
+				let span = copied.next().expect("the sections standing in for copied ones are kept in order; qed");
+				copy_span(input, span, output)?;
+			}
+			elements::Section::Code(code_section) => {
+				// The bodies added by the instrumentation follow the ones of the input.
+				let added = code_section.bodies()
+					.iter()
+					.map(|func_body| elements::serialize(func_body.clone()))
+					.collect::<Result<Vec<_>, _>>()?;
+				let count = (bodies.len() + added.len()) as u32;
+				let mut count_bytes = Vec::new();
+				write_var_u32(&mut count_bytes, count)?;
+				let size = count_bytes.len() as u64 + code_size + added.iter().map(|bytes| bytes.len() as u64).sum::<u64>();
+				if size > u64::from(u32::MAX) {
+					return Err(StreamError::Decoding(elements::Error::Other("code section too large")));
+				}
+
+				output.write_all(&[CODE_SECTION_ID])?;
+				write_var_u32(output, size as u32)?;
+				output.write_all(&count_bytes)?;
+				copy_span(scratch, Span { start: scratch_start, len: code_size }, output)?;
+				for bytes in added {
+					output.write_all(&bytes)?;
+				}
+			}
+			section => output.write_all(&elements::serialize(section)?)?,
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gas::{inject_gas_counter, ErrorKind};
+	use crate::rules;
+	use std::io::Cursor;
+
+	const MODULE: &str = r#"
+		(module
+			(import "env" "ext" (func $ext))
+			(memory 1)
+			(table 2 funcref)
+			(elem (i32.const 0) $grow $call)
+			(func $grow (param i32) (result i32)
+				get_local 0
+				if (result i32)
+					get_local 0
+					grow_memory
+				else
+					i32.const 0
+				end)
+			(func $call (export "call")
+				call $ext
+				i32.const 1
+				call $grow
+				drop)
+			(start $call)
+			(data (i32.const 0) "abcd"))
+	"#;
+
+	fn module_bytes() -> Vec<u8> {
+		let buf = wabt::Wat2Wasm::new().write_debug_names(true).convert(MODULE).unwrap();
+		let mut bytes = AsRef::<[u8]>::as_ref(&buf).to_vec();
+		// A custom section behind the name section.
+		bytes.extend_from_slice(&[0, 6, 5, b'h', b'e', b'l', b'l', b'o']);
+		bytes
+	}
+
+	#[test]
+	fn same_as_in_memory() {
+		let bytes = module_bytes();
+		let rules = rules::Set::default().with_grow_cost(1);
+		let config = GasConfig::default().with_folded_charges();
+
+		for config in [config, config.with_exempt_functions(&[1])] {
+			let mut output = Vec::new();
+			inject_gas_counter_streaming(&mut Cursor::new(&bytes), &mut output, &mut Cursor::new(Vec::new()), &rules, config).unwrap();
+
+			let module = elements::deserialize_buffer(&bytes).unwrap();
+			let expected = elements::serialize(inject_gas_counter(module, &rules, config).unwrap()).unwrap();
+			assert_eq!(output, expected);
+		}
+	}
+
+	#[test]
+	fn errors() {
+		let bytes = module_bytes();
+		let mut output = Vec::new();
+
+		let forbidden = rules::Set::default().with_grow_strategy(rules::GrowStrategy::Forbidden);
+		match inject_gas_counter_streaming(&mut Cursor::new(&bytes), &mut output, &mut Cursor::new(Vec::new()), &forbidden, "env") {
+			Err(StreamError::Gas(e)) => assert_eq!(e.kind, ErrorKind::ForbiddenInstruction(elements::Instruction::GrowMemory(0))),
+			result => panic!("unexpected result: {:?}", result),
+		}
+		assert!(output.is_empty());
+
+		let rules = rules::Set::default();
+		assert!(matches!(
+			inject_gas_counter_streaming(&mut Cursor::new(&bytes[..20]), &mut output, &mut Cursor::new(Vec::new()), &rules, "env"),
+			Err(StreamError::Decoding(_)) | Err(StreamError::Io(_)),
+		));
+		assert!(matches!(
+			inject_gas_counter_streaming(&mut Cursor::new(b"\0wasm\x01\0\0"), &mut output, &mut Cursor::new(Vec::new()), &rules, "env"),
+			Err(StreamError::Decoding(elements::Error::InvalidMagic)),
+		));
+	}
+
+	#[test]
+	fn rejects_sizes_beyond_section() {
+		let rules = rules::Set::default();
+		let mut output = Vec::new();
+
+		// A code section of 6 bytes with a function body claiming u32::MAX bytes.
+		let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+		bytes.extend_from_slice(&[CODE_SECTION_ID, 6, 1, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+		match inject_gas_counter_streaming(&mut Cursor::new(&bytes), &mut output, &mut Cursor::new(Vec::new()), &rules, "env") {
+			Err(StreamError::Decoding(elements::Error::InconsistentLength { expected, actual })) => {
+				assert_eq!(expected, 6);
+				assert_eq!(actual, 6 + u32::MAX as usize);
+			}
+			result => panic!("unexpected result: {:?}", result),
+		}
+
+		// A section claiming more bytes than the input has.
+		bytes.truncate(8);
+		bytes.extend_from_slice(&[DATA_SECTION_ID, 0xff, 0xff, 0xff, 0xff, 0x0f, 0]);
+		assert!(matches!(
+			inject_gas_counter_streaming(&mut Cursor::new(&bytes), &mut output, &mut Cursor::new(Vec::new()), &rules, "env"),
+			Err(StreamError::Decoding(elements::Error::UnexpectedEof)),
+		));
+		assert!(output.is_empty());
+	}
+}
@@ -0,0 +1,276 @@
+//! Experimental gas metering leaving the metered function through a tail call when the gas runs
+//! out.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+use parity_wasm::elements::{Instruction, ValueType};
+
+use super::{
+	determine_module_metered_blocks, import_gas_func, inject_grow_counter, insert_metering, update_call_index,
+	Error, GasConfig,
+};
+use crate::rules::{GrowMetering, Rules};
+use crate::sections;
+
+/// Append the instructions calling `gas_func` with the amount on top of the stack and leaving the
+/// function through `out_of_gas_func` if it returns a non-zero value.
+fn charge(instructions: &mut Vec<Instruction>, gas_func: u32, out_of_gas_func: u32) {
+	use parity_wasm::elements::Instruction::*;
+
+	instructions.extend(vec![
+		Call(gas_func),
+		If(elements::BlockType::NoResult),
+		Call(out_of_gas_func),
+		Return,
+		End,
+	]);
+}
+
+/// The function of the type signature [] -> `results` which is called when the gas runs out.
+fn out_of_gas(results: Vec<ValueType>) -> (elements::FunctionType, elements::FuncBody) {
+	(
+		elements::FunctionType::new(Vec::new(), results),
+		elements::FuncBody::new(Vec::new(), elements::Instructions::new(vec![Instruction::Unreachable, Instruction::End])),
+	)
+}
+
+/// The function charging for `memory.grow`, which replaces all `memory.grow` instructions.
+fn grow_counter(
+	grow_metering: &dyn GrowMetering,
+	gas_func: u32,
+	out_of_gas_func: u32,
+) -> (elements::FunctionType, elements::FuncBody) {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut instructions = Vec::new();
+	grow_metering.amount(&mut instructions, false);
+	charge(&mut instructions, gas_func, out_of_gas_func);
+	instructions.extend(vec![GetLocal(0), GrowMemory(0), End]);
+
+	let mut locals = Vec::new();
+	if grow_metering.scratch_locals() > 0 {
+		locals.push(elements::Local::new(grow_metering.scratch_locals(), ValueType::I64));
+	}
+	(
+		elements::FunctionType::new(vec![ValueType::I32], vec![ValueType::I32]),
+		elements::FuncBody::new(locals, elements::Instructions::new(instructions)),
+	)
+}
+
+/// Transforms a given module into one that charges gas like `inject_gas_counter`, except that the
+/// gas function reports running out of gas instead of trapping itself. This is an experimental
+/// charging style for engines supporting the tail call proposal, see the `charging` benchmark.
+///
+/// The gas function `gas` is imported from `gas_module_name` with the type signature
+/// [i32] -> [i32] and returns a non-zero value once the gas is exhausted. The metered function
+/// then leaves through an added function with the same results, which traps:
+/// `call $out_of_gas; return`. `backend::WasmTools::encode_with_tail_calls` encodes this as
+/// `return_call $out_of_gas`, while the other encoders keep the regular call, which needs no
+/// extension. One such function is added per distinct result type of the metered functions.
+///
+/// `memory.grow` is charged as in `inject_gas_counter`, by an added function calling the gas
+/// function before growing the memory. The function indices shift as in `inject_gas_counter`.
+///
+/// The function fails if the module contains any operation forbidden by gas rule set or a function
+/// body with malformed control flow, returning the original module along with the `Error`.
+pub fn inject_gas_counter_with_tail_calls<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	gas_module_name: &str,
+)
+	-> Result<elements::Module, (elements::Module, Error)>
+{
+	let metered_blocks = match determine_module_metered_blocks(&module, rules, None, &|_| true) {
+		Ok(metered_blocks) => metered_blocks,
+		Err(e) => return Err((module, e)),
+	};
+
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let results: Vec<Vec<ValueType>> = module.function_section()
+		.map_or(&[][..], |function_section| function_section.entries())
+		.iter()
+		.map(|func| match types.get(func.type_ref() as usize) {
+			Some(elements::Type::Function(ty)) => ty.results().to_vec(),
+			None => Vec::new(),
+		})
+		.collect();
+	let grow_metering = rules.grow_metering();
+	let need_grow_counter = grow_metering.is_charged() && module.code_section()
+		.map_or(&[][..], |code_section| code_section.bodies())
+		.iter()
+		.any(|func_body| func_body.code().elements().iter().any(|instruction| matches!(instruction, Instruction::GrowMemory(_))));
+
+	// The functions leaving on out of gas, one per distinct result type which needs one.
+	let mut out_of_gas_results: Vec<Vec<ValueType>> = Vec::new();
+	let needed = metered_blocks.iter()
+		.zip(&results)
+		.filter(|(blocks, _)| !blocks.is_empty())
+		.map(|(_, results)| &results[..])
+		.chain(if need_grow_counter { Some(&[ValueType::I32][..]) } else { None });
+	for results in needed {
+		if !out_of_gas_results.iter().any(|existing| existing[..] == *results) {
+			out_of_gas_results.push(results.to_vec());
+		}
+	}
+
+	let (mut module, gas_func) = import_gas_func(module, &GasConfig::new(gas_module_name, "gas"), vec![ValueType::I32]);
+	let total_func = module.functions_space() as u32;
+	let out_of_gas_func = |results: &[ValueType]| {
+		let position = out_of_gas_results.iter()
+			.position(|existing| existing[..] == *results)
+			.expect("a function is added for the results of every function with charges; qed");
+		total_func + position as u32
+	};
+	let grow_counter_func = total_func + out_of_gas_results.len() as u32;
+
+	if let Some(code_section) = module.code_section_mut() {
+		for ((func_body, blocks), results) in code_section.bodies_mut().iter_mut().zip(metered_blocks).zip(&results) {
+			update_call_index(func_body.code_mut(), gas_func);
+			insert_metering(func_body.code_mut(), blocks, None, |cost, instructions| {
+				instructions.push(Instruction::I32Const(cost as i32));
+				charge(instructions, gas_func, out_of_gas_func(results));
+			})
+				.expect("metered blocks are determined from the same function body; qed");
+			if need_grow_counter {
+				inject_grow_counter(func_body.code_mut(), grow_counter_func);
+			}
+		}
+	}
+
+	let grow_counter = if need_grow_counter {
+		Some(grow_counter(&*grow_metering, gas_func, out_of_gas_func(&[ValueType::I32])))
+	} else {
+		None
+	};
+	for results in out_of_gas_results {
+		let (signature, body) = out_of_gas(results);
+		sections::push_function(&mut module, signature, body);
+	}
+	if let Some((signature, body)) = grow_counter {
+		sections::push_function(&mut module, signature, body);
+	}
+	Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+	use crate::testing::{get_function_body, parse_wat, validate_module};
+	use parity_wasm::elements::Instruction::*;
+
+	#[test]
+	fn leaves_through_out_of_gas_function() {
+		let module = parse_wat(r#"
+			(module
+				(func (export "f") (param i32) (result i32)
+					get_local 0)
+				(func (export "g") (result i32)
+					i32.const 1
+					call 0)
+				(func (export "h")
+					nop))
+		"#);
+
+		let injected = inject_gas_counter_with_tail_calls(module, &rules::Set::default(), "env").unwrap();
+
+		// The gas function is imported first, followed by the three functions of the module and
+		// the out of gas functions for [i32] and [].
+		assert_eq!(injected.functions_space(), 6);
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&[I32Const(1), Call(0), If(elements::BlockType::NoResult), Call(4), Return, End, GetLocal(0), End][..]
+		);
+		assert_eq!(
+			get_function_body(&injected, 1).unwrap(),
+			&[I32Const(2), Call(0), If(elements::BlockType::NoResult), Call(4), Return, End, I32Const(1), Call(1), End][..]
+		);
+		assert_eq!(
+			get_function_body(&injected, 2).unwrap(),
+			&[I32Const(1), Call(0), If(elements::BlockType::NoResult), Call(5), Return, End, Nop, End][..]
+		);
+		assert_eq!(get_function_body(&injected, 3).unwrap(), &[Unreachable, End][..]);
+		assert_eq!(get_function_body(&injected, 4).unwrap(), &[Unreachable, End][..]);
+
+		validate_module(injected);
+	}
+
+	#[test]
+	fn charges_memory_grow() {
+		let module = parse_wat(r#"
+			(module
+				(memory 1)
+				(func (param i32)
+					get_local 0
+					grow_memory
+					drop))
+		"#);
+
+		let rules = rules::Set::default().with_grow_cost(10);
+		let injected = inject_gas_counter_with_tail_calls(module, &rules, "env").unwrap();
+
+		// The function returns nothing, the grow counter returns an i32.
+		assert_eq!(injected.functions_space(), 5);
+		assert!(get_function_body(&injected, 0).unwrap().contains(&Call(4)));
+		let grow_counter = get_function_body(&injected, 3).unwrap();
+		assert_eq!(&grow_counter[grow_counter.len() - 8..], &[
+			Call(0), If(elements::BlockType::NoResult), Call(3), Return, End, GetLocal(0), GrowMemory(0), End,
+		][..]);
+
+		validate_module(injected);
+	}
+
+	#[cfg(all(feature = "wasm-tools", feature = "wasmi"))]
+	#[test]
+	fn runs_with_tail_calls() {
+		use crate::backend::WasmTools;
+		use wasmi::{Caller, Config, Engine, Linker, Module, Store};
+
+		let module = parse_wat(r#"
+			(module
+				(func (export "sum") (param i32) (result i32)
+					(local i32)
+					(block
+						(loop
+							(br_if 1 (i32.eqz (get_local 0)))
+							(set_local 1 (i32.add (get_local 1) (get_local 0)))
+							(set_local 0 (i32.sub (get_local 0) (i32.const 1)))
+							(br 0)))
+					get_local 1))
+		"#);
+		let injected = inject_gas_counter_with_tail_calls(module, &rules::Set::default(), "env").unwrap();
+		let binary = WasmTools.encode_with_tail_calls(injected).unwrap();
+		assert!(wasmparser::Parser::new(0).parse_all(&binary).any(|payload| match payload.unwrap() {
+			wasmparser::Payload::CodeSectionEntry(body) => body.get_operators_reader().unwrap()
+				.into_iter()
+				.any(|op| matches!(op.unwrap(), wasmparser::Operator::ReturnCall { .. })),
+			_ => false,
+		}));
+
+		let mut config = Config::default();
+		config.wasm_tail_call(true);
+		let engine = Engine::new(&config);
+		let module = Module::new(&engine, &binary[..]).unwrap();
+		let run = |gas: u64| {
+			let mut store = Store::new(&engine, gas);
+			let mut linker = Linker::<u64>::new(&engine);
+			linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i32| -> i32 {
+				let gas = caller.data_mut();
+				match gas.checked_sub(amount as u32 as u64) {
+					Some(left) => {
+						*gas = left;
+						0
+					}
+					None => 1,
+				}
+			}).unwrap();
+			let instance = linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+			let sum = instance.get_typed_func::<i32, i32>(&store, "sum").unwrap();
+			sum.call(&mut store, 10).map_err(|e| e.to_string())
+		};
+
+		assert_eq!(run(1_000), Ok(55));
+		assert!(run(50).is_err());
+	}
+}
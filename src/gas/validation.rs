@@ -30,10 +30,10 @@ struct ControlFlowNode {
 	first_instr_pos: Option<usize>,
 
 	/// The actual gas cost of executing all instructions in the basic block.
-	actual_cost: u32,
+	actual_cost: u64,
 
 	/// The amount of gas charged by the injected metering instructions within this basic block.
-	charged_cost: u32,
+	charged_cost: u64,
 
 	/// Whether there are any other nodes in the graph that loop back to this one. Every cycle in
 	/// the control flow graph contains at least one node with this flag set.
@@ -89,11 +89,11 @@ impl ControlFlowGraph {
 		self.nodes.len() - 1
 	}
 
-	fn increment_actual_cost(&mut self, node_id: NodeId, cost: u32) {
+	fn increment_actual_cost(&mut self, node_id: NodeId, cost: u64) {
 		self.get_node_mut(node_id).actual_cost += cost;
 	}
 
-	fn increment_charged_cost(&mut self, node_id: NodeId, cost: u32) {
+	fn increment_charged_cost(&mut self, node_id: NodeId, cost: u64) {
 		self.get_node_mut(node_id).charged_cost += cost;
 	}
 
@@ -172,7 +172,7 @@ fn build_control_flow_graph(
 			graph.increment_charged_cost(active_node_id, next_metered_block.cost);
 		}
 
-		let instruction_cost = rules.instruction_cost(instruction).ok_or(())?;
+		let instruction_cost = u64::from(rules.instruction_cost(instruction).ok_or(())?);
 		match instruction {
 			Instruction::Block(_) => {
 				graph.increment_actual_cost(active_node_id, instruction_cost);
@@ -204,6 +204,9 @@ fn build_control_flow_graph(
 				let active_frame_idx = stack.len() - 1;
 				let prev_frame_idx = stack.len() - 2;
 
+				// The `then` branch continues at the exit of the `if` block.
+				graph.new_forward_edge(active_node_id, stack[active_frame_idx].exit_node);
+
 				let else_node_id = graph.add_node();
 				stack[active_frame_idx].active_node = else_node_id;
 
@@ -290,9 +293,9 @@ fn validate_graph_gas_costs(graph: &ControlFlowGraph) -> bool {
 	fn visit(
 		graph: &ControlFlowGraph,
 		node_id: NodeId,
-		mut total_actual: u32,
-		mut total_charged: u32,
-		loop_costs: &mut Map<NodeId, (u32, u32)>,
+		mut total_actual: u64,
+		mut total_charged: u64,
+		loop_costs: &mut Map<NodeId, (u64, u64)>,
 	) -> bool {
 		let node = graph.get_node(node_id);
 
@@ -336,7 +339,7 @@ fn validate_graph_gas_costs(graph: &ControlFlowGraph) -> bool {
 /// searching all paths through the control flow graph.
 ///
 /// This assumes that the function body has been validated already, otherwise this may panic.
-fn validate_metering_injections(
+pub(super) fn validate_metering_injections(
 	body: &FuncBody,
 	rules: &RuleSet,
 	blocks: &[MeteredBlock]
@@ -347,7 +350,7 @@ fn validate_metering_injections(
 
 mod tests {
 	use super::*;
-	use super::super::determine_metered_blocks;
+	use super::super::{determine_joined_blocks, determine_metered_blocks};
 
 	use parity_wasm::elements;
 	use binaryen::tools::translate_to_fuzz_mvp;
@@ -369,6 +372,10 @@ mod tests {
 				let metered_blocks = determine_metered_blocks(func_body.code(), &rules).unwrap();
 				let success = validate_metering_injections(func_body, &rules, &metered_blocks).unwrap();
 				assert!(success);
+
+				let joined_blocks = determine_joined_blocks(func_body.code(), &rules).unwrap();
+				let success = validate_metering_injections(func_body, &rules, &joined_blocks).unwrap();
+				assert!(success);
 			}
 		}
 	}
@@ -126,10 +126,10 @@ pub struct Table {
 
 /// Segment location.
 ///
-/// Reserved for future use. Currenty only `Default` variant is supported.
+/// Currenty only `Default` and, with the bulk memory operations, `Passive` variants are supported.
 #[derive(Debug)]
 pub enum SegmentLocation {
-	/// Not used currently.
+	/// Passive segment, which is only copied by `memory.init` or `table.init`.
 	Passive,
 	/// Default segment location with index `0`.
 	Default(Vec<Instruction>),
@@ -224,6 +224,14 @@ impl Module {
 		}).collect()
 	}
 
+	fn map_segment_location(&self, offset: &Option<elements::InitExpr>) -> SegmentLocation {
+		match offset {
+			Some(init_expr) => SegmentLocation::Default(self.map_instructions(init_expr.code())),
+			// Only passive segments of the bulk memory operations have no offset.
+			None => SegmentLocation::Passive,
+		}
+	}
+
 	fn generate_instructions(&self, instructions: &[Instruction]) -> Vec<elements::Instruction> {
 		use parity_wasm::elements::Instruction::*;
 		instructions.iter().map(|instruction| match instruction {
@@ -342,22 +350,7 @@ impl Module {
 				},
 				elements::Section::Element(element_section) => {
 					for element_segment in element_section.entries() {
-
-						// let location = if element_segment.passive() {
-						// 	SegmentLocation::Passive
-						// } else if element_segment.index() == 0 {
-						// 	SegmentLocation::Default(Vec::new())
-						// } else {
-						// 	SegmentLocation::WithIndex(element_segment.index(), Vec::new())
-						// };
-
-						// TODO: update parity-wasm and uncomment the above instead
-						let init_expr = element_segment
-							.offset()
-							.as_ref()
-							.expect("parity-wasm is compiled without bulk-memory operations")
-							.code();
-						let location = SegmentLocation::Default(res.map_instructions(init_expr));
+						let location = res.map_segment_location(element_segment.offset());
 
 						let funcs_map = element_segment
 							.members().iter()
@@ -385,14 +378,7 @@ impl Module {
 				},
 				elements::Section::Data(data_section) => {
 					for data_segment in data_section.entries() {
-						// TODO: update parity-wasm and use the same logic as in
-						// commented element segment branch
-						let init_expr = data_segment
-							.offset()
-							.as_ref()
-							.expect("parity-wasm is compiled without bulk-memory operations")
-							.code();
-						let location = SegmentLocation::Default(res.map_instructions(init_expr));
+						let location = res.map_segment_location(data_segment.offset());
 
 						res.data.push(DataSegment {
 							value: data_segment.value().to_vec(),
@@ -664,13 +650,13 @@ impl Module {
 				let element_segments = element_section.entries_mut();
 
 				for element in self.elements.iter() {
+					let mut elements_map = Vec::new();
+					for f in element.value.iter() {
+						elements_map.push(f.order().ok_or(Error::DetachedEntry)? as u32);
+					}
+
 					match &element.location {
 						SegmentLocation::Default(offset_expr) => {
-							let mut elements_map = Vec::new();
-							for f in element.value.iter() {
-								elements_map.push(f.order().ok_or(Error::DetachedEntry)? as u32);
-							}
-
 							element_segments.push(
 								elements::ElementSegment::new(
 									0,
@@ -679,6 +665,12 @@ impl Module {
 								)
 							);
 						},
+						#[cfg(feature = "bulk")]
+						SegmentLocation::Passive => {
+							let mut segment = elements::ElementSegment::new(0, None, elements_map);
+							segment.set_passive(true);
+							element_segments.push(segment);
+						},
 						_ => unreachable!("Other segment location types are never added"),
 					}
 				}
@@ -732,6 +724,12 @@ impl Module {
 								)
 							);
 						},
+						#[cfg(feature = "bulk")]
+						SegmentLocation::Passive => {
+							let mut segment = elements::DataSegment::new(0, None, data_entry.value.clone());
+							segment.set_passive(true);
+							data_segments.push(segment);
+						},
 						_ => unreachable!("Other segment location types are never added"),
 					}
 				}
@@ -975,4 +973,34 @@ mod tests {
 			"Call should be recalculated to 1"
 		);
 	}
+
+	#[cfg(feature = "bulk")]
+	#[test]
+	fn passive_segments() {
+		let mut features = wabt::Features::new();
+		features.enable_bulk_memory();
+		let wasm = wabt::wat2wasm_with_features(indoc!(r#"
+			(module
+				(memory 1)
+				(data "abc")
+				(data (i32.const 8) "def")
+				(func
+					i32.const 0
+					i32.const 0
+					i32.const 3
+					memory.init 0
+					data.drop 0))"#
+		), features).expect("failed to parse wat!");
+		let sample = super::parse(&wasm[..]).expect("error making representation");
+
+		assert!(matches!(sample.data[0].location, super::SegmentLocation::Passive));
+		assert!(matches!(sample.data[1].location, super::SegmentLocation::Default(_)));
+
+		let module = sample.generate().expect("Failed to generate module");
+		let data_section = module.data_section().expect("data section is generated");
+		assert!(data_section.entries()[0].passive());
+		assert!(!data_section.entries()[1].passive());
+
+		validate_sample(&sample);
+	}
 }
@@ -0,0 +1,173 @@
+//! Hashing of modules by their semantics rather than their bytes.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Local};
+
+use crate::strip;
+
+/// Bring the module to a canonical form.
+///
+/// Custom sections are removed, identical function types are merged and adjacent local
+/// declarations of the same type are joined. Encoding differences like padded LEB128 integers
+/// disappear once the module is serialized again.
+pub fn canonicalize(module: &mut elements::Module) {
+	strip::strip_custom_sections(module, false);
+	strip::dedup_types(module);
+
+	if let Some(code_section) = module.code_section_mut() {
+		for body in code_section.bodies_mut() {
+			let mut locals: Vec<Local> = Vec::with_capacity(body.locals().len());
+			for local in body.locals() {
+				if local.count() == 0 {
+					continue;
+				}
+				match locals.last_mut() {
+					Some(last) if last.value_type() == local.value_type() => {
+						*last = Local::new(last.count().saturating_add(local.count()), last.value_type());
+					}
+					_ => locals.push(*local),
+				}
+			}
+			*body.locals_mut() = locals;
+		}
+	}
+}
+
+/// Compute a hash of the module which does not depend on non-semantic details of its encoding.
+///
+/// Two modules have the same hash if their canonical forms (see `canonicalize`) serialize to the
+/// same bytes. The hash is the SHA-256 digest of these bytes.
+pub fn semantic_hash(module: &elements::Module) -> Result<[u8; 32], elements::Error> {
+	let mut module = module.clone();
+	canonicalize(&mut module);
+	Ok(sha256(&elements::serialize(module)?))
+}
+
+const K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 digest of `data`.
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+	let mut state: [u32; 8] = [
+		0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+	];
+
+	let mut message = data.to_vec();
+	message.push(0x80);
+	while message.len() % 64 != 56 {
+		message.push(0);
+	}
+	message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+	for chunk in message.chunks(64) {
+		let mut w = [0u32; 64];
+		for (i, word) in chunk.chunks(4).enumerate() {
+			w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+		}
+		for i in 16..64 {
+			let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+			let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+			w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+		}
+
+		let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+		for i in 0..64 {
+			let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+			let ch = (e & f) ^ (!e & g);
+			let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+			let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+			let maj = (a & b) ^ (a & c) ^ (b & c);
+			let t2 = s0.wrapping_add(maj);
+
+			h = g;
+			g = f;
+			f = e;
+			e = d.wrapping_add(t1);
+			d = c;
+			c = b;
+			b = a;
+			a = t1.wrapping_add(t2);
+		}
+
+		for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+			*s = s.wrapping_add(*v);
+		}
+	}
+
+	let mut digest = [0u8; 32];
+	for (bytes, word) in digest.chunks_mut(4).zip(state.iter()) {
+		bytes.copy_from_slice(&word.to_be_bytes());
+	}
+	digest
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements::ValueType;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn sha256_vectors() {
+		assert_eq!(
+			sha256(b""),
+			[
+				0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+				0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+			]
+		);
+		assert_eq!(
+			sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+			[
+				0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e, 0x60, 0x39,
+				0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4, 0x19, 0xdb, 0x06, 0xc1,
+			]
+		);
+	}
+
+	#[test]
+	fn ignores_encoding_details() {
+		let module = parse_wat(r#"
+			(module
+				(type (func (param i32)))
+				(func (type 0) (local i32 i32)
+					get_local 0
+					drop))
+		"#);
+
+		let mut variant = parse_wat(r#"
+			(module
+				(type (func (param i32)))
+				(type (func (param i32)))
+				(func (type 1) (local i32 i32)
+					get_local 0
+					drop))
+		"#);
+		variant.set_custom_section("producers", vec![1, 2, 3]);
+		*variant.code_section_mut().unwrap().bodies_mut()[0].locals_mut() = vec![
+			Local::new(1, ValueType::I32),
+			Local::new(0, ValueType::I64),
+			Local::new(1, ValueType::I32),
+		];
+
+		assert_eq!(semantic_hash(&module).unwrap(), semantic_hash(&variant).unwrap());
+
+		let different = parse_wat(r#"
+			(module
+				(type (func (param i32)))
+				(func (type 0) (local i32 i64)
+					get_local 0
+					drop))
+		"#);
+		assert_ne!(semantic_hash(&module).unwrap(), semantic_hash(&different).unwrap());
+	}
+}
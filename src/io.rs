@@ -0,0 +1,49 @@
+//! File helpers for the command-line tools which treat `-` as stdin/stdout.
+
+use std::fs;
+use std::io::{self, Read, Write};
+
+use parity_wasm::elements::{self, Module};
+
+use crate::version;
+
+/// Path which stands for stdin when reading and for stdout when writing.
+pub const STDIO: &str = "-";
+
+/// Read the whole file at `path`, or stdin if `path` is `-`.
+pub fn read(path: &str) -> io::Result<Vec<u8>> {
+	if path == STDIO {
+		let mut bytes = Vec::new();
+		io::stdin().lock().read_to_end(&mut bytes)?;
+		Ok(bytes)
+	} else {
+		fs::read(path)
+	}
+}
+
+/// Write `bytes` to the file at `path`, or stdout if `path` is `-`.
+pub fn write(path: &str, bytes: &[u8]) -> io::Result<()> {
+	if path == STDIO {
+		let stdout = io::stdout();
+		let mut stdout = stdout.lock();
+		stdout.write_all(bytes)?;
+		stdout.flush()
+	} else {
+		fs::write(path, bytes)
+	}
+}
+
+/// Like `parity_wasm::deserialize_file`, but reads stdin if `path` is `-` and checks the version
+/// as `version::deserialize_buffer` does.
+pub fn deserialize_file(path: &str) -> Result<Module, version::Error> {
+	let bytes = read(path)
+		.map_err(|e| elements::Error::HeapOther(format!("Can't read from the file: {:?}", e)))?;
+	version::deserialize_buffer(&bytes)
+}
+
+/// Like `parity_wasm::serialize_to_file`, but writes to stdout if `path` is `-`.
+pub fn serialize_to_file(path: &str, module: Module) -> Result<(), elements::Error> {
+	let bytes = elements::serialize(module)?;
+	write(path, &bytes)
+		.map_err(|e| elements::Error::HeapOther(format!("Can't create the file: {:?}", e)))
+}
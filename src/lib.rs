@@ -4,9 +4,13 @@
 #[macro_use]
 extern crate alloc;
 
+pub mod analysis;
+pub mod backend;
+pub mod debug_names;
 pub mod rules;
 
 mod build;
+mod export_memory;
 mod ext;
 mod gas;
 mod optimizer;
@@ -14,19 +18,63 @@ mod pack;
 mod runtime_type;
 mod graph;
 mod ref_list;
+mod sections;
 mod symbols;
 #[cfg(feature = "std")]
 mod export_globals;
+#[cfg(feature = "std")]
+pub mod attestation;
+#[cfg(feature = "std")]
+pub mod build_support;
+#[cfg(feature = "std")]
+pub mod debug_offsets;
+#[cfg(feature = "cli")]
+pub mod io;
+#[cfg(feature = "near-schedules")]
+pub mod near;
 #[cfg(feature = "cli")]
 pub mod logger;
-
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "wat")]
+pub mod wat;
+
+pub mod check;
+pub mod coverage;
+pub mod hash;
+pub mod lint;
+pub mod memory_pages;
+pub mod memory_peak;
+pub mod pipeline;
+pub mod profiling;
+pub mod repair;
+pub mod report;
+pub mod self_test;
+pub mod source_map;
+pub mod split;
 pub mod stack_height;
+pub mod strip;
+pub mod trap_reason;
+pub mod triage;
+pub mod validation;
+pub mod version;
+pub mod visit;
 
 pub use build::{build, Error as BuildError, SourceTarget};
+pub use export_memory::{export_memory, Error as MemoryExportError};
 pub use ext::{
 	externalize, externalize_mem, shrink_unknown_stack, underscore_funcs, ununderscore_funcs,
 };
-pub use gas::inject_gas_counter;
+pub use gas::{
+	inject_gas_counter, inject_gas_counter_with_global, inject_gas_counter_with_report,
+	inject_gas_counter_with_tail_calls, BlockReport,
+	Error as GasError, ErrorKind as GasErrorKind, FunctionReport, GasConfig, GlobalGasConfig, MeteredBlock,
+	MeteringReport,
+};
+#[cfg(feature = "std")]
+pub use gas::{inject_gas_counter_streaming, StreamError as GasStreamError};
+#[cfg(feature = "legacy")]
+pub use gas::legacy::inject_gas_counter as inject_gas_counter_legacy;
 pub use optimizer::{optimize, Error as OptimizerError};
 pub use pack::{pack_instance, Error as PackingError};
 pub use runtime_type::inject_runtime_type;
@@ -34,6 +82,8 @@ pub use graph::{Module, parse as graph_parse, generate as graph_generate};
 pub use ref_list::{RefList, Entry, EntryRef, DeleteTransaction};
 #[cfg(feature = "std")]
 pub use export_globals::export_mutable_globals;
+#[cfg(feature = "wat")]
+pub use wat::{inject_gas_counter_wat, inject_limiter_wat, optimize_wat};
 pub use parity_wasm;
 
 pub struct TargetSymbols {
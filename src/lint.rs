@@ -0,0 +1,299 @@
+//! Lints for common pitfalls of contracts.
+//!
+//! Unlike the checks of `check` or a runtime profile, a lint does not make a module invalid or
+//! rejected, it points out something which is likely a mistake or makes the contract fail on some
+//! runtimes. Each lint has a stable code, so that tools can allow or deny lints by code.
+
+use crate::std::fmt;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, External, Internal, Type, ValueType};
+
+/// A pitfall found by `check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Lint {
+	/// A mutable global is exported, which the MVP does not allow and which lets the host change
+	/// state of the contract behind its back.
+	ExportsMutableGlobal,
+	/// A memory is imported and another one is defined, which needs the multi-memory proposal.
+	ImportsAndDefinesMemory,
+	/// The module has a start function, which runs on instantiation, before any gas limit of a
+	/// call applies, and traps of which make the contract impossible to instantiate.
+	StartSection,
+	/// A table has no maximum size, so that the host has to allow it to grow without limit.
+	UnboundedTable,
+	/// An exported function has a floating point parameter or result, whose NaN bits the host may
+	/// not preserve.
+	FloatInExportedSignature,
+}
+
+impl Lint {
+	/// All lints, in the order of their codes.
+	pub const ALL: &'static [Lint] = &[
+		Lint::ExportsMutableGlobal,
+		Lint::ImportsAndDefinesMemory,
+		Lint::StartSection,
+		Lint::UnboundedTable,
+		Lint::FloatInExportedSignature,
+	];
+
+	/// The stable code of the lint.
+	pub fn code(&self) -> &'static str {
+		match self {
+			Lint::ExportsMutableGlobal => "W001",
+			Lint::ImportsAndDefinesMemory => "W002",
+			Lint::StartSection => "W003",
+			Lint::UnboundedTable => "W004",
+			Lint::FloatInExportedSignature => "W005",
+		}
+	}
+
+	/// Short description of the lint.
+	pub fn description(&self) -> &'static str {
+		match self {
+			Lint::ExportsMutableGlobal => "exports mutable global",
+			Lint::ImportsAndDefinesMemory => "imports both memory and defines memory",
+			Lint::StartSection => "start section present",
+			Lint::UnboundedTable => "unbounded table",
+			Lint::FloatInExportedSignature => "float in exported signature",
+		}
+	}
+
+	/// The lint with the given code.
+	pub fn from_code(code: &str) -> Option<Lint> {
+		Lint::ALL.iter().cloned().find(|lint| lint.code() == code)
+	}
+}
+
+/// Where in the module a lint applies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+	/// The export at `index` of the export section.
+	Export { index: u32, name: String },
+	/// The import at `index` of the import section.
+	Import { index: u32, module: String, field: String },
+	/// A function, by index in the function index space.
+	Function(u32),
+	/// A table, by index in the table index space.
+	Table(u32),
+	/// A memory, by index in the memory index space.
+	Memory(u32),
+}
+
+impl fmt::Display for Location {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Location::Export { index, name } => write!(f, "export {} '{}'", index, name),
+			Location::Import { index, module, field } => write!(f, "import {} '{}.{}'", index, module, field),
+			Location::Function(index) => write!(f, "function {}", index),
+			Location::Table(index) => write!(f, "table {}", index),
+			Location::Memory(index) => write!(f, "memory {}", index),
+		}
+	}
+}
+
+/// A lint found in a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+	pub lint: Lint,
+	pub location: Location,
+	/// Details of the finding.
+	pub message: String,
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} {} at {}: {}", self.lint.code(), self.lint.description(), self.location, self.message)
+	}
+}
+
+/// Lint the `module`, returning the warnings ordered by lint and then by location in the module.
+pub fn check(module: &elements::Module) -> Vec<Warning> {
+	let imports = module.import_section().map_or(&[][..], |import_section| import_section.entries());
+	let exports = module.export_section().map_or(&[][..], |export_section| export_section.entries());
+	let export_location = |index: usize, export: &elements::ExportEntry| Location::Export {
+		index: index as u32,
+		name: export.field().into(),
+	};
+	let mut warnings = Vec::new();
+
+	let global_mutability = imports.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Global(global_type) => Some(global_type.is_mutable()),
+			_ => None,
+		})
+		.chain(
+			module.global_section()
+				.map_or(&[][..], |global_section| global_section.entries())
+				.iter()
+				.map(|entry| entry.global_type().is_mutable())
+		)
+		.collect::<Vec<_>>();
+	for (index, export) in exports.iter().enumerate() {
+		if let Internal::Global(global) = *export.internal() {
+			if global_mutability.get(global as usize).cloned().unwrap_or(false) {
+				warnings.push(Warning {
+					lint: Lint::ExportsMutableGlobal,
+					location: export_location(index, export),
+					message: format!("global {} is mutable", global),
+				});
+			}
+		}
+	}
+
+	let defined_memories = module.memory_section().map_or(0, |memory_section| memory_section.entries().len());
+	if defined_memories != 0 {
+		for (index, entry) in imports.iter().enumerate() {
+			if let External::Memory(_) = entry.external() {
+				warnings.push(Warning {
+					lint: Lint::ImportsAndDefinesMemory,
+					location: Location::Import {
+						index: index as u32,
+						module: entry.module().into(),
+						field: entry.field().into(),
+					},
+					message: format!("the module defines {} more", defined_memories),
+				});
+			}
+		}
+	}
+
+	if let Some(start) = module.start_section() {
+		warnings.push(Warning {
+			lint: Lint::StartSection,
+			location: Location::Function(start),
+			message: "the start function runs on instantiation".into(),
+		});
+	}
+
+	let table_limits = imports.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Table(table_type) => Some(table_type.limits()),
+			_ => None,
+		})
+		.chain(
+			module.table_section()
+				.map_or(&[][..], |table_section| table_section.entries())
+				.iter()
+				.map(|table_type| table_type.limits())
+		);
+	for (index, limits) in table_limits.enumerate() {
+		if limits.maximum().is_none() {
+			warnings.push(Warning {
+				lint: Lint::UnboundedTable,
+				location: Location::Table(index as u32),
+				message: format!("initial size {} without maximum", limits.initial()),
+			});
+		}
+	}
+
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let function_types = imports.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Function(type_ref) => Some(*type_ref),
+			_ => None,
+		})
+		.chain(
+			module.function_section()
+				.map_or(&[][..], |function_section| function_section.entries())
+				.iter()
+				.map(|func| func.type_ref())
+		)
+		.collect::<Vec<_>>();
+	for (index, export) in exports.iter().enumerate() {
+		let function = match *export.internal() {
+			Internal::Function(function) => function,
+			_ => continue,
+		};
+		let func_type = match function_types.get(function as usize).and_then(|type_ref| types.get(*type_ref as usize)) {
+			Some(Type::Function(func_type)) => func_type,
+			None => continue,
+		};
+		let is_float = |value_type: &&ValueType| matches!(value_type, ValueType::F32 | ValueType::F64);
+		let params = func_type.params().iter().filter(is_float).count();
+		let results = func_type.results().iter().filter(is_float).count();
+		if params + results != 0 {
+			warnings.push(Warning {
+				lint: Lint::FloatInExportedSignature,
+				location: export_location(index, export),
+				message: format!("function {} has {} float parameters and {} float results", function, params, results),
+			});
+		}
+	}
+
+	warnings
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	#[test]
+	fn finds_pitfalls() {
+		let module = elements::Module::new(vec![
+			elements::Section::Type(elements::TypeSection::with_types(vec![
+				Type::Function(elements::FunctionType::new(vec![ValueType::I32, ValueType::F64], vec![])),
+			])),
+			elements::Section::Import(elements::ImportSection::with_entries(vec![
+				elements::ImportEntry::new("env".into(), "memory".into(), External::Memory(elements::MemoryType::new(1, None))),
+			])),
+			elements::Section::Function(elements::FunctionSection::with_entries(vec![elements::Func::new(0)])),
+			elements::Section::Table(elements::TableSection::with_entries(vec![elements::TableType::new(1, None)])),
+			elements::Section::Memory(elements::MemorySection::with_entries(vec![elements::MemoryType::new(1, None)])),
+			elements::Section::Global(elements::GlobalSection::with_entries(vec![
+				elements::GlobalEntry::new(
+					elements::GlobalType::new(ValueType::I32, false),
+					elements::InitExpr::new(vec![elements::Instruction::I32Const(0), elements::Instruction::End]),
+				),
+				elements::GlobalEntry::new(
+					elements::GlobalType::new(ValueType::I32, true),
+					elements::InitExpr::new(vec![elements::Instruction::I32Const(0), elements::Instruction::End]),
+				),
+			])),
+			elements::Section::Export(elements::ExportSection::with_entries(vec![
+				elements::ExportEntry::new("constant".into(), Internal::Global(0)),
+				elements::ExportEntry::new("counter".into(), Internal::Global(1)),
+				elements::ExportEntry::new("main".into(), Internal::Function(0)),
+			])),
+			elements::Section::Start(0),
+			elements::Section::Code(elements::CodeSection::with_bodies(vec![
+				elements::FuncBody::new(vec![], elements::Instructions::new(vec![elements::Instruction::End])),
+			])),
+		]);
+
+		let warnings = check(&module);
+
+		assert_eq!(
+			warnings.iter().map(|warning| (warning.lint, warning.location.clone())).collect::<Vec<_>>(),
+			vec![
+				(Lint::ExportsMutableGlobal, Location::Export { index: 1, name: "counter".into() }),
+				(Lint::ImportsAndDefinesMemory, Location::Import { index: 0, module: "env".into(), field: "memory".into() }),
+				(Lint::StartSection, Location::Function(0)),
+				(Lint::UnboundedTable, Location::Table(0)),
+				(Lint::FloatInExportedSignature, Location::Export { index: 2, name: "main".into() }),
+			]
+		);
+		assert_eq!(
+			warnings[0].to_string(),
+			"W001 exports mutable global at export 1 'counter': global 1 is mutable"
+		);
+		assert_eq!(Lint::from_code("W004"), Some(Lint::UnboundedTable));
+	}
+
+	#[test]
+	fn clean_contract() {
+		let module = parse_wat(r#"
+			(module
+				(memory (export "memory") 1 16)
+				(table 1 1 anyfunc)
+				(global i32 (i32.const 0))
+				(export "global" (global 0))
+				(func (export "main") (param i32) (result i64)
+					i64.const 0))
+		"#);
+
+		assert_eq!(check(&module), vec![]);
+	}
+}
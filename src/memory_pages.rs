@@ -0,0 +1,209 @@
+//! Rewriting of the initial size of the memory, so that hosts charging for the pages a module
+//! declares can normalize modules to a policy.
+//!
+//! Some SDKs declare a small memory and grow it at the start of the execution instead. Optionally,
+//! such warm-up grows at the beginning of the start function are removed as well when the new
+//! initial size already covers them.
+
+use crate::std::fmt;
+
+use parity_wasm::elements::{self, Instruction};
+
+/// Size of a page of memory, in bytes.
+const PAGE_SIZE: u64 = 65536;
+
+/// Error of `set_initial_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+	/// The module neither defines nor imports a memory.
+	NoMemory,
+	/// The requested number of pages is above the maximum of the memory.
+	AboveMaximum { pages: u32, maximum: u32 },
+	/// The data segment at the given index doesn't fit in the requested number of pages.
+	DataOutOfBounds { segment: usize },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::NoMemory => write!(f, "Module has no memory"),
+			Error::AboveMaximum { pages, maximum } =>
+				write!(f, "{} pages are above the maximum of the memory, {} pages", pages, maximum),
+			Error::DataOutOfBounds { segment } =>
+				write!(f, "Data segment {} doesn't fit in the memory", segment),
+		}
+	}
+}
+
+/// Outcome of `set_initial_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+	/// Initial number of pages of the memory before the rewrite.
+	pub initial: u32,
+	/// Number of warm-up grows removed from the start function.
+	pub removed_grows: usize,
+}
+
+/// Set the initial number of pages of the memory of the module, defined or imported, to `pages`.
+///
+/// The maximum of the memory is kept, and data segments at constant offsets must fit in the new
+/// size. With `remove_warm_up`, leading `(drop (memory.grow (i32.const n)))` sequences of the
+/// start function are removed for as long as the pages they grow are covered by the new size.
+pub fn set_initial_pages(module: &mut elements::Module, pages: u32, remove_warm_up: bool) -> Result<Report, Error> {
+	let initial = rewrite_memory(module, pages)?;
+	check_data(module, pages)?;
+
+	let removed_grows = if remove_warm_up {
+		remove_warm_up_grows(module, pages.saturating_sub(initial))
+	} else {
+		0
+	};
+
+	Ok(Report { initial, removed_grows })
+}
+
+/// The memory type with the initial size set to `pages`.
+fn with_initial(memory_type: &elements::MemoryType, pages: u32) -> Result<elements::MemoryType, Error> {
+	let limits = memory_type.limits();
+	if let Some(maximum) = limits.maximum() {
+		if pages > maximum {
+			return Err(Error::AboveMaximum { pages, maximum });
+		}
+	}
+
+	#[allow(unused_mut)]
+	let mut rewritten = elements::MemoryType::new(pages, limits.maximum());
+	#[cfg(feature = "atomics")]
+	rewritten.set_shared(limits.shared());
+	Ok(rewritten)
+}
+
+/// Rewrite the memory to `pages` initial pages, returning its previous initial size.
+fn rewrite_memory(module: &mut elements::Module, pages: u32) -> Result<u32, Error> {
+	if let Some(import_section) = module.import_section_mut() {
+		for entry in import_section.entries_mut() {
+			if let elements::External::Memory(memory_type) = entry.external() {
+				let initial = memory_type.limits().initial();
+				let rewritten = with_initial(memory_type, pages)?;
+				*entry = elements::ImportEntry::new(
+					entry.module().into(),
+					entry.field().into(),
+					elements::External::Memory(rewritten),
+				);
+				return Ok(initial);
+			}
+		}
+	}
+
+	let memory_type = module.memory_section_mut()
+		.and_then(|memory_section| memory_section.entries_mut().first_mut())
+		.ok_or(Error::NoMemory)?;
+	let initial = memory_type.limits().initial();
+	*memory_type = with_initial(memory_type, pages)?;
+	Ok(initial)
+}
+
+/// Check that the data segments at constant offsets fit in `pages` pages.
+fn check_data(module: &elements::Module, pages: u32) -> Result<(), Error> {
+	let size = pages as u64 * PAGE_SIZE;
+	let segments = module.data_section().map_or(&[][..], |data_section| data_section.entries());
+	for (index, segment) in segments.iter().enumerate() {
+		let offset = match segment.offset().as_ref().map(|offset| offset.code()) {
+			Some([Instruction::I32Const(offset), Instruction::End]) => *offset as u32 as u64,
+			_ => continue,
+		};
+		if offset + segment.value().len() as u64 > size {
+			return Err(Error::DataOutOfBounds { segment: index });
+		}
+	}
+	Ok(())
+}
+
+/// Remove the leading warm-up grows of the start function which grow at most `covered` pages in
+/// total, returning how many were removed.
+fn remove_warm_up_grows(module: &mut elements::Module, covered: u32) -> usize {
+	let imported_funcs = module.import_count(elements::ImportCountType::Function) as u32;
+	let start = match module.start_section() {
+		Some(start) if start >= imported_funcs => (start - imported_funcs) as usize,
+		_ => return 0,
+	};
+	let body = match module.code_section_mut().and_then(|code_section| code_section.bodies_mut().get_mut(start)) {
+		Some(body) => body,
+		None => return 0,
+	};
+
+	let instructions = body.code_mut().elements_mut();
+	let mut grown = 0u32;
+	let mut removed = 0;
+	while let [Instruction::I32Const(delta), Instruction::GrowMemory(0), Instruction::Drop, ..] = instructions[removed * 3..] {
+		match grown.checked_add(delta as u32) {
+			Some(total) if delta >= 0 && total <= covered => grown = total,
+			_ => break,
+		}
+		removed += 1;
+	}
+	instructions.drain(..removed * 3);
+	removed
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	fn limits(module: &elements::Module) -> (u32, Option<u32>) {
+		let limits = module.memory_section().unwrap().entries()[0].limits();
+		(limits.initial(), limits.maximum())
+	}
+
+	#[test]
+	fn rewrites_initial_pages() {
+		let mut module = parse_wat(r#"
+			(module
+				(memory 1 16)
+				(data (i32.const 65536) "\01"))
+		"#);
+		assert_eq!(set_initial_pages(&mut module, 1, false), Err(Error::DataOutOfBounds { segment: 0 }));
+		assert_eq!(set_initial_pages(&mut module, 17, false), Err(Error::AboveMaximum { pages: 17, maximum: 16 }));
+
+		let report = set_initial_pages(&mut module, 4, false).unwrap();
+		assert_eq!(report, Report { initial: 1, removed_grows: 0 });
+		assert_eq!(limits(&module), (4, Some(16)));
+
+		let mut imported = parse_wat(r#"(module (import "env" "memory" (memory 2)))"#);
+		assert_eq!(set_initial_pages(&mut imported, 3, false).unwrap().initial, 2);
+		match imported.import_section().unwrap().entries()[0].external() {
+			elements::External::Memory(memory_type) => assert_eq!(memory_type.limits().initial(), 3),
+			_ => panic!("Memory import expected"),
+		}
+
+		let mut no_memory = parse_wat("(module)");
+		assert_eq!(set_initial_pages(&mut no_memory, 1, false), Err(Error::NoMemory));
+	}
+
+	#[test]
+	fn removes_warm_up_grows() {
+		let source = r#"
+			(module
+				(memory 1)
+				(func $start
+					(drop (memory.grow (i32.const 2)))
+					(drop (memory.grow (i32.const 3)))
+					(drop (memory.grow (i32.const 1))))
+				(start $start))
+		"#;
+
+		let mut module = parse_wat(source);
+		assert_eq!(set_initial_pages(&mut module, 4, true).unwrap().removed_grows, 1);
+		let code = module.code_section().unwrap().bodies()[0].code().elements();
+		assert_eq!(&code[..2], &[Instruction::I32Const(3), Instruction::GrowMemory(0)]);
+
+		let mut module = parse_wat(source);
+		assert_eq!(set_initial_pages(&mut module, 7, true).unwrap().removed_grows, 3);
+		assert_eq!(module.code_section().unwrap().bodies()[0].code().elements(), &[Instruction::End]);
+
+		let mut module = parse_wat(source);
+		assert_eq!(set_initial_pages(&mut module, 7, false).unwrap().removed_grows, 0);
+		assert_eq!(module.code_section().unwrap().bodies()[0].code().elements().len(), 10);
+	}
+}
@@ -0,0 +1,314 @@
+//! The pass that records the peak memory usage of a module, so that runtimes can charge for
+//! the maximal amount of memory used during an execution rather than only for `memory.grow`.
+//!
+//! The peak, in pages of 64 KiB, is kept in a mutable global added to the module. It can be read
+//! by calling an exported function without parameters which returns it as `i32`. The global
+//! itself is not exported, so the host cannot change it and no extension is needed.
+//!
+//! By default the peak is the maximal size of the memory. It starts at the initial size and is
+//! updated by a function which replaces all `memory.grow` instructions.
+//!
+//! Optionally, the peak is the memory actually written to instead. Then it starts at a threshold,
+//! below which memory is considered to be always in use, and all stores are replaced by functions
+//! raising it to the page of the last byte written. Memory which is grown but never written to
+//! does not count in this case.
+
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+use parity_wasm::{builder, elements};
+use parity_wasm::elements::{Instruction, ValueType};
+
+/// Error of the memory peak instrumentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+	/// The module neither defines nor imports a memory.
+	NoMemory,
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::NoMemory => write!(f, "Module has no memory"),
+		}
+	}
+}
+
+/// Initial number of pages of the memory of the module.
+pub(crate) fn initial_pages(module: &elements::Module) -> Option<u32> {
+	let imported = module.import_section()
+		.map_or(&[][..], |import_section| import_section.entries())
+		.iter()
+		.find_map(|entry| match entry.external() {
+			elements::External::Memory(memory_type) => Some(memory_type.limits().initial()),
+			_ => None,
+		});
+	imported.or_else(|| {
+		module.memory_section()
+			.and_then(|memory_section| memory_section.entries().first())
+			.map(|memory_type| memory_type.limits().initial())
+	})
+}
+
+/// Type of the stored value and number of bytes written by the store `instruction`.
+fn store_width(instruction: &Instruction) -> Option<(ValueType, u32, u32)> {
+	use parity_wasm::elements::Instruction::*;
+
+	match *instruction {
+		I32Store(_, offset) => Some((ValueType::I32, 4, offset)),
+		I64Store(_, offset) => Some((ValueType::I64, 8, offset)),
+		F32Store(_, offset) => Some((ValueType::F32, 4, offset)),
+		F64Store(_, offset) => Some((ValueType::F64, 8, offset)),
+		I32Store8(_, offset) => Some((ValueType::I32, 1, offset)),
+		I32Store16(_, offset) => Some((ValueType::I32, 2, offset)),
+		I64Store8(_, offset) => Some((ValueType::I64, 1, offset)),
+		I64Store16(_, offset) => Some((ValueType::I64, 2, offset)),
+		I64Store32(_, offset) => Some((ValueType::I64, 4, offset)),
+		#[cfg(feature = "simd")]
+		Simd(elements::SimdInstruction::V128Store(ref memarg)) => Some((ValueType::V128, 16, memarg.offset)),
+		#[cfg(feature = "atomics")]
+		Atomics(ref atomic) => {
+			use parity_wasm::elements::AtomicsInstruction::*;
+
+			match *atomic {
+				I32AtomicStore(ref memarg) => Some((ValueType::I32, 4, memarg.offset)),
+				I64AtomicStore(ref memarg) => Some((ValueType::I64, 8, memarg.offset)),
+				I32AtomicStore8u(ref memarg) => Some((ValueType::I32, 1, memarg.offset)),
+				I32AtomicStore16u(ref memarg) => Some((ValueType::I32, 2, memarg.offset)),
+				I64AtomicStore8u(ref memarg) => Some((ValueType::I64, 1, memarg.offset)),
+				I64AtomicStore16u(ref memarg) => Some((ValueType::I64, 2, memarg.offset)),
+				I64AtomicStore32u(ref memarg) => Some((ValueType::I64, 4, memarg.offset)),
+				_ => None,
+			}
+		}
+		_ => None,
+	}
+}
+
+/// Append the instructions raising `peak_global` to the number of pages on top of the stack,
+/// which is kept in the local `pages_local`.
+fn raise_peak(instructions: &mut Vec<Instruction>, peak_global: u32, pages_local: u32) {
+	use parity_wasm::elements::Instruction::*;
+
+	instructions.extend(vec![
+		TeeLocal(pages_local),
+		GetGlobal(peak_global),
+		I32GtU,
+		If(elements::BlockType::NoResult),
+		GetLocal(pages_local),
+		SetGlobal(peak_global),
+		End,
+	]);
+}
+
+/// Function performing the store `instruction` and raising the peak to the page of the last
+/// byte written.
+fn store_function(instruction: &Instruction, peak_global: u32) -> builder::FunctionDefinition {
+	use parity_wasm::elements::Instruction::*;
+
+	let (value_type, width, offset) = store_width(instruction)
+		.expect("store functions are only generated for stores; qed");
+
+	// The number of pages up to the last byte written is `(end + 65535) / 65536`, where `end` is
+	// the effective address plus the width. It is computed as `i64` to not overflow.
+	let mut instructions = vec![
+		GetLocal(0),
+		GetLocal(1),
+		instruction.clone(),
+		GetLocal(0),
+		I64ExtendUI32,
+		I64Const(i64::from(offset) + i64::from(width) + 65535),
+		I64Add,
+		I64Const(16),
+		I64ShrU,
+		I32WrapI64,
+	];
+	raise_peak(&mut instructions, peak_global, 2);
+	instructions.push(End);
+
+	builder::function()
+		.signature().with_params(vec![ValueType::I32, value_type]).build()
+		.body()
+			.with_locals(vec![elements::Local::new(1, ValueType::I32)])
+			.with_instructions(elements::Instructions::new(instructions))
+			.build()
+		.build()
+}
+
+/// Function growing the memory and raising the peak to the new size.
+fn grow_function(peak_global: u32) -> builder::FunctionDefinition {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut instructions = vec![
+		GetLocal(0),
+		GrowMemory(0),
+		SetLocal(1),
+		CurrentMemory(0),
+	];
+	raise_peak(&mut instructions, peak_global, 2);
+	instructions.extend(vec![GetLocal(1), End]);
+
+	builder::function()
+		.signature().with_param(ValueType::I32).with_result(ValueType::I32).build()
+		.body()
+			.with_locals(vec![elements::Local::new(2, ValueType::I32)])
+			.with_instructions(elements::Instructions::new(instructions))
+			.build()
+		.build()
+}
+
+/// Instrument a module to record its peak memory usage.
+///
+/// The peak is returned by the function exported as `export_name`. If `store_threshold` is
+/// `Some`, the peak counts the pages written to, starting at the given number of pages or the
+/// initial size of the memory, whichever is less. Otherwise it is the size of the memory.
+/// See module-level documentation for more details. No function indices change.
+///
+/// # Errors
+///
+/// Returns `Err` if the module has no memory.
+pub fn inject_memory_peak(
+	module: elements::Module,
+	export_name: &str,
+	store_threshold: Option<u32>,
+) -> Result<elements::Module, Error> {
+	let initial = initial_pages(&module).ok_or(Error::NoMemory)?;
+
+	// Defined globals come after the imported ones, so the new global is the last one.
+	let peak_global = module.globals_space() as u32;
+	let total_func = module.functions_space() as u32;
+
+	let mut module = module;
+	let mut helpers: Vec<Instruction> = Vec::new();
+	if let Some(code_section) = module.code_section_mut() {
+		for func_body in code_section.bodies_mut() {
+			for instruction in func_body.code_mut().elements_mut() {
+				let replace = match *instruction {
+					Instruction::GrowMemory(_) => store_threshold.is_none(),
+					ref store => store_threshold.is_some() && store_width(store).is_some(),
+				};
+				if !replace {
+					continue;
+				}
+				let index = match helpers.iter().position(|helper| helper == instruction) {
+					Some(index) => index,
+					None => {
+						helpers.push(instruction.clone());
+						helpers.len() - 1
+					}
+				};
+				*instruction = Instruction::Call(total_func + index as u32);
+			}
+		}
+	}
+
+	let start = match store_threshold {
+		Some(threshold) => initial.min(threshold),
+		None => initial,
+	};
+
+	let mut mbuilder = builder::from_module(module);
+	mbuilder.push_global(
+		builder::global()
+			.with_type(ValueType::I32)
+			.mutable()
+			.init_expr(Instruction::I32Const(start as i32))
+			.build()
+	);
+	for helper in &helpers {
+		mbuilder.push_function(match helper {
+			Instruction::GrowMemory(_) => grow_function(peak_global),
+			store => store_function(store, peak_global),
+		});
+	}
+	mbuilder.push_function(
+		builder::function()
+			.signature().with_result(ValueType::I32).build()
+			.body()
+				.with_instructions(elements::Instructions::new(vec![
+					Instruction::GetGlobal(peak_global),
+					Instruction::End,
+				]))
+				.build()
+			.build()
+	);
+	mbuilder.push_export(
+		builder::export()
+			.field(export_name)
+			.internal().func(total_func + helpers.len() as u32)
+			.build()
+	);
+
+	Ok(mbuilder.build())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements::Instruction::*;
+	use crate::testing::parse_wat;
+
+	const MODULE: &str = r#"
+		(module
+			(memory 2)
+			(func (param i32) (result i32)
+				get_local 0
+				i32.const 1
+				i32.store offset=4
+				get_local 0
+				i64.const 1
+				i64.store8
+				get_local 0
+				i32.const 2
+				i32.store offset=4
+				get_local 0
+				grow_memory))
+	"#;
+
+	fn validate(module: elements::Module) {
+		let binary = elements::serialize(module).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn tracks_grow() {
+		let module = inject_memory_peak(parse_wat(MODULE), "peak_memory", None).unwrap();
+
+		let code = module.code_section().unwrap().bodies()[0].code().elements();
+		assert_eq!(code[code.len() - 2], Call(1));
+		assert_eq!(code.iter().filter(|instruction| **instruction == Call(1)).count(), 1);
+
+		let global = &module.global_section().unwrap().entries()[0];
+		assert!(global.global_type().is_mutable());
+		assert_eq!(global.init_expr().code(), &[I32Const(2), End][..]);
+
+		let export = module.export_section().unwrap().entries().iter()
+			.find(|e| e.field() == "peak_memory")
+			.expect("peak getter is exported");
+		assert_eq!(export.internal(), &elements::Internal::Function(2));
+
+		validate(module);
+	}
+
+	#[test]
+	fn tracks_stores() {
+		let module = inject_memory_peak(parse_wat(MODULE), "peak_memory", Some(1)).unwrap();
+
+		let code = module.code_section().unwrap().bodies()[0].code().elements();
+		assert_eq!(code[2], Call(1));
+		assert_eq!(code[5], Call(2));
+		assert_eq!(code[8], Call(1));
+		assert_eq!(code[10], GrowMemory(0));
+
+		let global = &module.global_section().unwrap().entries()[0];
+		assert_eq!(global.init_expr().code(), &[I32Const(1), End][..]);
+		assert_eq!(module.functions_space(), 4);
+
+		validate(module);
+	}
+
+	#[test]
+	fn no_memory() {
+		assert_eq!(inject_memory_peak(parse_wat("(module)"), "peak_memory", None), Err(Error::NoMemory));
+	}
+}
@@ -0,0 +1,166 @@
+//! The cost schedules NEAR mainnet instrumented contracts with, frozen at the values of each
+//! protocol version.
+//!
+//! Indexers and other tooling re-instrumenting historical contracts must use the parameters in
+//! effect when the contract was prepared. The values are those of the runtime configuration of
+//! nearcore, where the gas metering charges a unit per instruction and the host multiplies the
+//! charged units by `regular_op_cost`, so only the stack limit and the cost of `memory.grow`
+//! change the instrumented module. Protocol versions only enabled on nightly builds are left out.
+//!
+//! Exported behind the `near-schedules` feature. Do not change the schedules below, every change
+//! of them is a change of the instrumentation of past blocks; new protocol versions are appended.
+
+use crate::std::fmt;
+
+use parity_wasm::elements;
+
+use crate::gas::legacy;
+use crate::rules;
+use crate::stack_height;
+
+/// The parameters of the preparation of contracts from a protocol version on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NearSchedule {
+	/// Tag naming the schedule.
+	pub tag: &'static str,
+	/// First protocol version the schedule applies to.
+	pub protocol_version: u32,
+	/// Gas charged by the host per unit charged by the instrumented module, `wasm_regular_op_cost`.
+	pub regular_op_cost: u32,
+	/// Units charged per page of `memory.grow`, `wasm_grow_mem_cost`.
+	pub grow_mem_cost: u32,
+	/// Stack limit of the stack height limiter, `max_stack_height`.
+	pub max_stack_height: u32,
+	/// Version of the preparation of contracts, `contract_prepare_version`.
+	pub prepare_version: u32,
+	/// Maximal number of functions of a contract, `max_functions_number_per_contract`.
+	pub max_functions: Option<u64>,
+	/// Maximal number of locals of all functions of a contract together,
+	/// `max_locals_per_contract`.
+	pub max_locals_per_contract: Option<u64>,
+}
+
+/// The schedule of the genesis of mainnet.
+pub const GENESIS: NearSchedule = NearSchedule {
+	tag: "genesis",
+	protocol_version: 0,
+	regular_op_cost: 3_856_371,
+	grow_mem_cost: 1,
+	max_stack_height: 16_384,
+	prepare_version: 0,
+	max_functions: None,
+	max_locals_per_contract: None,
+};
+
+/// Lowered regular operation cost.
+pub const PROTOCOL_48: NearSchedule = NearSchedule {
+	tag: "lower-regular-op-cost",
+	protocol_version: 48,
+	regular_op_cost: 2_207_874,
+	..GENESIS
+};
+
+/// Lowered regular operation cost again and limited number of functions.
+pub const PROTOCOL_49: NearSchedule = NearSchedule {
+	tag: "limit-functions",
+	protocol_version: 49,
+	regular_op_cost: 822_756,
+	max_functions: Some(10_000),
+	..PROTOCOL_48
+};
+
+/// Preparation of contracts version 1.
+pub const PROTOCOL_50: NearSchedule = NearSchedule {
+	tag: "prepare-v1",
+	protocol_version: 50,
+	prepare_version: 1,
+	..PROTOCOL_49
+};
+
+/// Limited number of locals.
+pub const PROTOCOL_53: NearSchedule = NearSchedule {
+	tag: "limit-locals",
+	protocol_version: 53,
+	max_locals_per_contract: Some(1_000_000),
+	..PROTOCOL_50
+};
+
+/// All schedules, by increasing protocol version.
+pub const SCHEDULES: &[NearSchedule] = &[GENESIS, PROTOCOL_48, PROTOCOL_49, PROTOCOL_50, PROTOCOL_53];
+
+/// The schedule in effect at `protocol_version`.
+pub fn schedule(protocol_version: u32) -> &'static NearSchedule {
+	SCHEDULES.iter()
+		.rev()
+		.find(|schedule| schedule.protocol_version <= protocol_version)
+		.expect("the genesis schedule applies from protocol version 0; qed")
+}
+
+/// Error of `NearSchedule::instrument`.
+#[derive(Debug)]
+pub enum Error {
+	/// The gas metering rejected the module.
+	Gas,
+	StackHeight(stack_height::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Gas => write!(f, "Gas metering failed"),
+			Error::StackHeight(e) => write!(f, "Stack height limiting failed: {:?}", e),
+		}
+	}
+}
+
+impl NearSchedule {
+	/// The rules of the gas metering, charging a unit per instruction.
+	pub fn rules(&self) -> rules::Set {
+		rules::Set::new(1, Default::default()).with_grow_cost(self.grow_mem_cost)
+	}
+
+	/// Instrument the module like a contract prepared under this schedule: meter the gas with the
+	/// gas function imported from `env` and limit the stack height.
+	///
+	/// The gas metering is the one of pwasm-utils 0.18.1, see `inject_gas_counter_legacy`. The
+	/// checks of the contract against the limits of the schedule are left to the caller.
+	pub fn instrument(&self, module: elements::Module) -> Result<elements::Module, Error> {
+		let module = legacy::inject_gas_counter(module, &self.rules(), "env").map_err(|_| Error::Gas)?;
+		stack_height::inject_limiter(module, self.max_stack_height).map_err(Error::StackHeight)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lookup() {
+		assert_eq!(schedule(29), &GENESIS);
+		assert_eq!(schedule(48).regular_op_cost, 2_207_874);
+		assert_eq!(schedule(52), &PROTOCOL_50);
+		assert_eq!(schedule(u32::MAX), &PROTOCOL_53);
+		assert_eq!(schedule(53).max_functions, Some(10_000));
+
+		for pair in SCHEDULES.windows(2) {
+			assert!(pair[0].protocol_version < pair[1].protocol_version);
+		}
+	}
+
+	#[test]
+	fn instrument() {
+		let module = elements::deserialize_buffer(&wabt::wat2wasm(r#"
+			(module
+				(memory 1)
+				(func (export "grow") (param i32) (result i32)
+					(memory.grow (local.get 0))))
+		"#).unwrap()).unwrap();
+
+		let instrumented = schedule(53).instrument(module).unwrap();
+		let import = &instrumented.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("env", "gas"));
+		assert_eq!(instrumented.global_section().unwrap().entries().len(), 1);
+		// The exported function is called through a thunk checking the stack height.
+		assert_eq!(instrumented.functions_space(), 4);
+	}
+}
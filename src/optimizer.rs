@@ -7,6 +7,7 @@ use crate::std::mem;
 
 use log::trace;
 use parity_wasm::elements;
+use crate::repair::read_var_u32;
 use crate::symbols::{Symbol, expand_symbols, push_code_symbols, resolve_function};
 
 #[derive(Debug)]
@@ -25,63 +26,14 @@ pub fn optimize(
 	//   which in turn compile in unused imports and leaves unused functions
 
 	// try to parse name section
+	drop_extended_names(module);
 	let module_temp = mem::take(module);
 	let module_temp = module_temp
 		.parse_names()
 		.unwrap_or_else(|(_err, module)| module);
 	*module = module_temp;
 
-	// Algo starts from the top, listing all items that should stay
-	let mut stay = Set::new();
-	for (index, entry) in module.export_section().ok_or(Error::NoExportSection)?.entries().iter().enumerate() {
-		if used_exports.iter().any(|e| *e == entry.field()) {
-			stay.insert(Symbol::Export(index));
-		}
-	}
-
-	// If there is start function in module, it should stary
-	module.start_section().map(|ss| stay.insert(resolve_function(&module, ss)));
-
-	// All symbols used in data/element segments are also should be preserved
-	let mut init_symbols = Vec::new();
-	if let Some(data_section) = module.data_section() {
-		for segment in data_section.entries() {
-			push_code_symbols(
-				&module,
-				segment
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code(),
-				&mut init_symbols,
-			);
-		}
-	}
-	if let Some(elements_section) = module.elements_section() {
-		for segment in elements_section.entries() {
-			push_code_symbols(
-				&module,
-				segment
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code(),
-				&mut init_symbols
-			);
-			for func_index in segment.members() {
-				stay.insert(resolve_function(&module, *func_index));
-			}
-		}
-	}
-	for symbol in init_symbols.drain(..) { stay.insert(symbol); }
-
-	// Call function which will traverse the list recursively, filling stay with all symbols
-	// that are already used by those which already there
-	expand_symbols(module, &mut stay);
-
-	for symbol in stay.iter() {
-		trace!("symbol to stay: {:?}", symbol);
-	}
+	let stay = used_symbols(module, &used_exports)?;
 
 	// Keep track of referreable symbols to rewire calls/globals
 	let mut eliminated_funcs = Vec::new();
@@ -337,6 +289,90 @@ pub fn optimize(
 	Ok(())
 }
 
+/// The symbols of the module which are used, starting from the exports in `used_exports`, the start
+/// function and the data and element segments.
+pub(crate) fn used_symbols(module: &elements::Module, used_exports: &[&str]) -> Result<Set<Symbol>, Error> {
+	// Algo starts from the top, listing all items that should stay
+	let mut stay = Set::new();
+	for (index, entry) in module.export_section().ok_or(Error::NoExportSection)?.entries().iter().enumerate() {
+		if used_exports.iter().any(|e| *e == entry.field()) {
+			stay.insert(Symbol::Export(index));
+		}
+	}
+
+	// If there is start function in module, it should stary
+	module.start_section().map(|ss| stay.insert(resolve_function(module, ss)));
+
+	// All symbols used in data/element segments are also should be preserved
+	let mut init_symbols = Vec::new();
+	if let Some(data_section) = module.data_section() {
+		// Passive segments of the bulk memory operations have no offset.
+		for offset in data_section.entries().iter().filter_map(|segment| segment.offset().as_ref()) {
+			push_code_symbols(module, offset.code(), &mut init_symbols);
+		}
+	}
+	if let Some(elements_section) = module.elements_section() {
+		for segment in elements_section.entries() {
+			if let Some(offset) = segment.offset() {
+				push_code_symbols(module, offset.code(), &mut init_symbols);
+			}
+			for func_index in segment.members() {
+				stay.insert(resolve_function(module, *func_index));
+			}
+		}
+	}
+	for symbol in init_symbols.drain(..) { stay.insert(symbol); }
+
+	// Call function which will traverse the list recursively, filling stay with all symbols
+	// that are already used by those which already there
+	expand_symbols(module, &mut stay);
+
+	for symbol in stay.iter() {
+		trace!("symbol to stay: {:?}", symbol);
+	}
+
+	Ok(stay)
+}
+
+/// Subsections of the name section which parity-wasm can parse: the module, function and local
+/// names.
+const KNOWN_NAME_SUBSECTIONS: u8 = 3;
+
+/// Drop the subsections of the name section which parity-wasm can't parse, e.g. the label and
+/// global names emitted by LLVM, so that the function names survive the optimization and the gas
+/// metering.
+///
+/// The dropped subsections refer to indices which the optimizer may change as well.
+pub(crate) fn drop_extended_names(module: &mut elements::Module) {
+	for section in module.sections_mut() {
+		if let elements::Section::Custom(custom) = section {
+			if custom.name() == "name" {
+				if let Some(payload) = known_name_subsections(custom.payload()) {
+					*custom.payload_mut() = payload;
+				}
+			}
+		}
+	}
+}
+
+/// The name section `payload` without the subsections unknown to parity-wasm, or `None` if it is
+/// malformed.
+fn known_name_subsections(payload: &[u8]) -> Option<Vec<u8>> {
+	let mut known = Vec::with_capacity(payload.len());
+	let mut pos = 0;
+	while pos < payload.len() {
+		let start = pos;
+		let id = payload[pos];
+		pos += 1;
+		let size = read_var_u32(payload, &mut pos)? as usize;
+		let end = pos.checked_add(size).filter(|end| *end <= payload.len())?;
+		if id < KNOWN_NAME_SUBSECTIONS {
+			known.extend_from_slice(&payload[start..end]);
+		}
+		pos = end;
+	}
+	Some(known)
+}
 
 pub fn update_call_index(instructions: &mut elements::Instructions, eliminated_indices: &[usize]) {
 	use parity_wasm::elements::Instruction::*;
@@ -664,4 +700,40 @@ mod tests {
 		}
 	}
 
+	/// @spec 5
+	/// Function names are kept and renumbered, even if the name section has subsections which
+	/// parity-wasm can't parse.
+	#[test]
+	fn names() {
+		let bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(r#"
+				(module
+					(func $unused)
+					(func $helper)
+					(func $call (export "call")
+						call $helper))
+			"#)
+			.expect("failed to parse module");
+		let mut module = elements::deserialize_buffer::<elements::Module>(bytes.as_ref()).unwrap();
+		for section in module.sections_mut() {
+			if let elements::Section::Custom(custom) = section {
+				if custom.name() == "name" {
+					// Global names, with an empty map.
+					custom.payload_mut().extend_from_slice(&[7, 1, 0]);
+				}
+			}
+		}
+
+		optimize(&mut module, vec!["call"]).expect("optimizer to succeed");
+
+		let module = elements::deserialize_buffer::<elements::Module>(&elements::serialize(module).unwrap())
+			.unwrap()
+			.parse_names()
+			.expect("names to be parsed");
+		let names = module.names_section().expect("name section to be kept").functions().unwrap().names();
+		assert_eq!(names.get(0).map(String::as_str), Some("helper"));
+		assert_eq!(names.get(1).map(String::as_str), Some("call"));
+		assert_eq!(names.len(), 2);
+	}
 }
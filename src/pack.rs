@@ -162,12 +162,8 @@ pub fn pack_instance(raw_module: Vec<u8>, mut ctor_module: elements::Module, tar
 	for section in ctor_module.sections_mut() {
 		if let Section::Data(data_section) = section {
 			let (index, offset) = if let Some(entry) = data_section.entries().iter().last() {
-				let init_expr = entry
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code();
-				if let Instruction::I32Const(offst) = init_expr[0] {
+				let init_expr = entry.offset().as_ref().map_or(&[][..], |offset| offset.code());
+				if let Some(&Instruction::I32Const(offst)) = init_expr.first() {
 					let len = entry.value().len() as i32;
 					let offst = offst as i32;
 					(entry.index(), offst + (len + 4) - len % 4)
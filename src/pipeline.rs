@@ -0,0 +1,732 @@
+//! Pruning, gas metering and stack height limiting applied in a single pass.
+//!
+//! Running `optimize`, `inject_gas_counter` and `inject_limiter` one after the other rewrites the
+//! indices of the whole module and traverses every function body once per pass, as each pass
+//! removes or adds entries and shifts the indices of the others. `instrument` instead plans all
+//! removed and added imports, globals and functions up front, so that every reference is remapped
+//! once and every function body is rewritten in a single traversal.
+//!
+//! The result is the one of the passes applied in this order, except for the stack costs of the
+//! metered functions: they are computed from the original function bodies, counting one more
+//! value for the charges, which is an upper bound of the cost of the metered body.
+
+use crate::std::collections::BTreeMap;
+use crate::std::fmt;
+use crate::std::mem;
+use crate::std::vec::Vec;
+
+use parity_wasm::{builder, elements};
+use parity_wasm::elements::{Instruction, ValueType};
+
+use crate::check;
+use crate::debug_names;
+use crate::gas::{self, GasConfig, MeteredBlock};
+use crate::optimizer::{self, drop_extended_names};
+use crate::rules::Rules;
+use crate::stack_height::{self, Context, LimiterConfig, OverflowTrap};
+use crate::symbols::Symbol;
+use crate::trap_reason;
+use crate::validation;
+
+/// The passes applied by `instrument`, all disabled by default.
+#[derive(Clone, Copy, Default)]
+pub struct PipelineConfig<'a> {
+	/// The exports kept by the pruning, see `optimize`.
+	pub used_exports: Option<&'a [&'a str]>,
+	/// The rules and the configuration of the gas metering, see `inject_gas_counter`.
+	pub gas: Option<(&'a dyn Rules, GasConfig<'a>)>,
+	/// The stack limit and the configuration of the stack height limiter, see
+	/// `stack_height::inject_limiter_with_config`.
+	pub stack_limit: Option<(u32, LimiterConfig<'a>)>,
+}
+
+impl<'a> PipelineConfig<'a> {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Remove everything not used by the exports in `used_exports`, as well as the custom
+	/// sections.
+	pub fn with_pruning(mut self, used_exports: &'a [&'a str]) -> Self {
+		self.used_exports = Some(used_exports);
+		self
+	}
+
+	/// Meter the gas according to `rules`.
+	pub fn with_gas(mut self, rules: &'a dyn Rules, config: GasConfig<'a>) -> Self {
+		self.gas = Some((rules, config));
+		self
+	}
+
+	/// Limit the stack height to `stack_limit`.
+	pub fn with_stack_limit(mut self, stack_limit: u32, config: LimiterConfig<'a>) -> Self {
+		self.stack_limit = Some((stack_limit, config));
+		self
+	}
+}
+
+/// Error of one of the passes applied by `instrument`.
+#[derive(Debug)]
+pub enum Error {
+	Pruning(optimizer::Error),
+	Gas(gas::Error),
+	StackHeight(stack_height::Error),
+	/// The module is not well formed, only returned if the gas metering or the stack height
+	/// limiter is configured to check it.
+	Malformed(check::Error),
+	/// The instrumented module is not valid, only returned if the gas metering or the stack height
+	/// limiter is configured to validate it.
+	InvalidOutput(validation::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Pruning(_) => write!(f, "Pruning failed due to missing export section"),
+			Error::Gas(e) => write!(f, "Gas metering failed: {}", e),
+			Error::StackHeight(e) => write!(f, "Stack height limiting failed: {:?}", e),
+			Error::Malformed(e) => write!(f, "Malformed module: {}", e),
+			Error::InvalidOutput(e) => write!(f, "Instrumented module is invalid: {}", e),
+		}
+	}
+}
+
+impl From<stack_height::Error> for Error {
+	fn from(e: stack_height::Error) -> Self {
+		Error::StackHeight(e)
+	}
+}
+
+/// Apply the passes configured by `config` to the module: the pruning, then the gas metering, then
+/// the stack height limiter.
+///
+/// See the module-level documentation for how the result differs from applying the passes one
+/// after the other.
+pub fn instrument(mut module: elements::Module, config: &PipelineConfig) -> Result<elements::Module, Error> {
+	let checked = matches!(config.gas, Some((_, gas_config)) if gas_config.checked)
+		|| matches!(config.stack_limit, Some((_, limiter_config)) if limiter_config.checked);
+	if checked {
+		check::well_formed(&module).map_err(Error::Malformed)?;
+	}
+	// The function names have to be updated along with the indices.
+	drop_extended_names(&mut module);
+	let mut module = module.parse_names().unwrap_or_else(|(_err, module)| module);
+
+	let plan = Plan::new(&module, config)?;
+	plan.remap(&mut module)?;
+	let module = plan.append(module)?;
+
+	let validate_output = matches!(config.gas, Some((_, gas_config)) if gas_config.validate_output)
+		|| matches!(config.stack_limit, Some((_, limiter_config)) if limiter_config.validate_output);
+	if validate_output {
+		validation::validate(&module).map_err(Error::InvalidOutput)?;
+	}
+	Ok(module)
+}
+
+/// The indices of the entries of the module after the instrumentation and what is added.
+struct Plan<'a> {
+	pruning: bool,
+	/// Whether each type, import, defined global, defined function and export is kept.
+	kept_types: Vec<bool>,
+	kept_imports: Vec<bool>,
+	kept_globals: Vec<bool>,
+	kept_functions: Vec<bool>,
+	kept_exports: Vec<bool>,
+	/// The new indices of the kept types, functions and globals by their original indices.
+	types: Vec<Option<u32>>,
+	funcs: Vec<Option<u32>>,
+	globals: Vec<Option<u32>>,
+	gas: Option<GasPlan<'a>>,
+	stack: Option<StackPlan<'a>>,
+}
+
+struct GasPlan<'a> {
+	rules: &'a dyn Rules,
+	config: GasConfig<'a>,
+	/// Index of the imported gas function.
+	func: u32,
+	/// The function already imported by the module whose charges are folded, by original index.
+	prepaid_func: Option<u32>,
+	/// The metered blocks of every original function body, empty for the removed ones.
+	metered_blocks: Vec<Vec<MeteredBlock>>,
+	/// Whether each original function body is exempt from metering.
+	exempt: Vec<bool>,
+	/// Index of the function replacing `memory.grow`, if it is needed.
+	grow_counter: Option<u32>,
+}
+
+struct StackPlan<'a> {
+	config: LimiterConfig<'a>,
+	ctx: Context,
+	/// Index of the trap reason global and whether it is added.
+	trap_reason_global: Option<(u32, bool)>,
+	/// The thunks by the index of the function they call, with their index and type.
+	thunks: BTreeMap<u32, (u32, elements::FunctionType)>,
+}
+
+impl<'a> Plan<'a> {
+	fn new(module: &elements::Module, config: &PipelineConfig<'a>) -> Result<Self, Error> {
+		let stay = match config.used_exports {
+			Some(used_exports) => Some(optimizer::used_symbols(module, used_exports).map_err(Error::Pruning)?),
+			None => None,
+		};
+		let kept = |symbol| match &stay {
+			Some(stay) => stay.contains(&symbol),
+			None => true,
+		};
+
+		let types = module.type_section().map_or(&[][..], |section| section.types());
+		let imports = module.import_section().map_or(&[][..], |section| section.entries());
+		let globals = module.global_section().map_or(&[][..], |section| section.entries());
+		let functions = module.function_section().map_or(&[][..], |section| section.entries());
+		let exports = module.export_section().map_or(&[][..], |section| section.entries());
+
+		// Only imported functions and globals are pruned.
+		let kept_imports: Vec<bool> = imports.iter()
+			.enumerate()
+			.map(|(index, entry)| match entry.external() {
+				elements::External::Function(_) | elements::External::Global(_) => kept(Symbol::Import(index)),
+				_ => true,
+			})
+			.collect();
+		let kept_types: Vec<bool> = (0..types.len()).map(|index| kept(Symbol::Type(index))).collect();
+		let kept_globals: Vec<bool> = (0..globals.len()).map(|index| kept(Symbol::Global(index))).collect();
+		let kept_functions: Vec<bool> = (0..functions.len()).map(|index| kept(Symbol::Function(index))).collect();
+		let kept_exports: Vec<bool> = (0..exports.len()).map(|index| kept(Symbol::Export(index))).collect();
+
+		let imported = |is_kind: fn(&elements::External) -> bool| imports.iter()
+			.zip(&kept_imports)
+			.filter(|(entry, _)| is_kind(entry.external()))
+			.map(|(_, kept)| *kept)
+			.collect::<Vec<_>>();
+
+		// The added imports follow the kept ones, the added functions and globals the kept ones.
+		let mut next_type = 0;
+		let types = renumber(&kept_types, &mut next_type);
+
+		let mut next_func = 0;
+		let mut funcs = renumber(&imported(|external| matches!(external, elements::External::Function(_))), &mut next_func);
+		let gas_func = config.gas.map(|_| take(&mut next_func));
+		let overflow_func = match config.stack_limit {
+			Some((_, LimiterConfig { trap: OverflowTrap::HostFunction { .. }, .. })) => Some(take(&mut next_func)),
+			_ => None,
+		};
+		funcs.extend(renumber(&kept_functions, &mut next_func));
+
+		let mut next_global = 0;
+		let mut globals = renumber(&imported(|external| matches!(external, elements::External::Global(_))), &mut next_global);
+		globals.extend(renumber(&kept_globals, &mut next_global));
+
+		let gas = match (config.gas, gas_func) {
+			(Some((rules, gas_config)), Some(func)) => {
+				let exempt = gas_config.exempt(module);
+				let metered = |index: usize| kept_functions[index] && !exempt[index];
+				let metered_blocks = gas::plan_metering(module, rules, &gas_config, &metered)
+					.map_err(Error::Gas)?;
+				let bodies = module.code_section().map_or(&[][..], |section| section.bodies());
+				let grows_memory = bodies.iter()
+					.enumerate()
+					.filter(|(index, _)| metered(*index))
+					.map(|(_, body)| body)
+					.any(|body| body.code().elements().iter().any(|instruction| matches!(instruction, Instruction::GrowMemory(_))));
+				let grow_counter = if rules.grow_metering().is_charged() && grows_memory {
+					Some(take(&mut next_func))
+				} else {
+					None
+				};
+				Some(GasPlan {
+					rules,
+					config: gas_config,
+					func,
+					prepaid_func: gas_config.prepaid_func(module),
+					metered_blocks,
+					exempt,
+					grow_counter,
+				})
+			},
+			_ => None,
+		};
+
+		let mut plan = Plan {
+			pruning: stay.is_some(),
+			kept_types,
+			kept_imports,
+			kept_globals,
+			kept_functions,
+			kept_exports,
+			types,
+			funcs,
+			globals,
+			gas,
+			stack: None,
+		};
+		if let Some((stack_limit, limiter_config)) = config.stack_limit {
+			plan.stack = Some(plan.plan_stack(module, stack_limit, limiter_config, overflow_func, next_func, next_global)?);
+		}
+		Ok(plan)
+	}
+
+	/// Plan the stack height limiter, given the number of functions and globals before the ones
+	/// it adds.
+	fn plan_stack(
+		&self,
+		module: &elements::Module,
+		stack_limit: u32,
+		config: LimiterConfig<'a>,
+		overflow_func: Option<u32>,
+		functions_space: u32,
+		globals_space: u32,
+	) -> Result<StackPlan<'a>, Error> {
+		let global = globals_space;
+		let exports = module.export_section().map_or(&[][..], |section| section.entries());
+		let trap_reason_global = config.trap_reason.map(|export_name| {
+			let existing = exports.iter()
+				.zip(&self.kept_exports)
+				.find_map(|(entry, kept)| match *entry.internal() {
+					elements::Internal::Global(index) if *kept && entry.field() == export_name => self.globals[index as usize],
+					_ => None,
+				});
+			match existing {
+				Some(index) => (index, false),
+				None => (global + 1, true),
+			}
+		});
+
+		// Imported functions have no stack cost, the metered functions have one more value for the
+		// charges.
+		let mut func_stack_costs = vec![0; functions_space as usize];
+		let imported_funcs = module.import_count(elements::ImportCountType::Function);
+		for (old_index, new_index) in self.funcs.iter().enumerate().skip(imported_funcs) {
+			if let Some(new_index) = new_index {
+				let info = stack_height::function_stack_info(old_index as u32, module)?;
+				let charges = self.gas.as_ref()
+					.map_or(0, |gas| if gas.metered_blocks[old_index - imported_funcs].is_empty() { 0 } else { 1 });
+				func_stack_costs[*new_index as usize] = info.cost(config.frame_cost)
+					.and_then(|cost| cost.checked_add(charges))
+					.ok_or_else(|| stack_height::Error("Overflow in adding locals_count and max_stack_height".into()))?;
+			}
+		}
+		if let Some(gas) = &self.gas {
+			if let Some(grow_counter) = gas.grow_counter {
+				func_stack_costs[grow_counter as usize] = grow_counter_cost(gas, config)?;
+			}
+		}
+
+		let ctx = Context::new(
+			global,
+			func_stack_costs,
+			stack_limit,
+			overflow_func,
+			trap_reason_global.map(|(index, _)| index),
+		);
+
+		// Thunks are generated for the exported functions, the table entries and the start function
+		// in the order of the functions they call.
+		let mut callees = BTreeMap::new();
+		let exported = exports.iter()
+			.zip(&self.kept_exports)
+			.filter_map(|(entry, kept)| match *entry.internal() {
+				elements::Internal::Function(index) if *kept => Some(index),
+				_ => None,
+			});
+		let elements = module.elements_section()
+			.map_or(&[][..], |section| section.entries())
+			.iter()
+			.flat_map(|segment| segment.members())
+			.copied();
+		for old_index in exported.chain(elements).chain(module.start_section()) {
+			let new_index = self.func(old_index);
+			if ctx.instrumented_call(new_index)?.is_some() {
+				callees.entry(new_index)
+					.or_insert(stack_height::resolve_func_type(old_index, module)?.clone());
+			}
+		}
+		let thunks = callees.into_iter()
+			.enumerate()
+			.map(|(position, (callee, signature))| (callee, (functions_space + position as u32, signature)))
+			.collect();
+
+		Ok(StackPlan { config, ctx, trap_reason_global, thunks })
+	}
+
+	fn func(&self, index: u32) -> u32 {
+		self.funcs[index as usize].expect("functions referenced by kept entries are kept; qed")
+	}
+
+	fn global(&self, index: u32) -> u32 {
+		self.globals[index as usize].expect("globals referenced by kept entries are kept; qed")
+	}
+
+	fn type_ref(&self, index: u32) -> u32 {
+		self.types[index as usize].expect("types referenced by kept entries are kept; qed")
+	}
+
+	/// The function to refer to in place of a function called from outside of the module.
+	fn entry(&self, index: u32) -> u32 {
+		let index = self.func(index);
+		self.stack.as_ref()
+			.and_then(|stack| stack.thunks.get(&index))
+			.map_or(index, |(thunk, _)| *thunk)
+	}
+
+	/// Remove the pruned entries and update all references in a single traversal of the module,
+	/// instrumenting the kept function bodies.
+	fn remap(&self, module: &mut elements::Module) -> Result<(), Error> {
+		if self.pruning {
+			module.sections_mut().retain(|section| !matches!(section, elements::Section::Custom(_)));
+		}
+
+		for section in module.sections_mut() {
+			match section {
+				elements::Section::Type(type_section) => retain(type_section.types_mut(), &self.kept_types),
+				elements::Section::Import(import_section) => {
+					retain(import_section.entries_mut(), &self.kept_imports);
+					for entry in import_section.entries_mut() {
+						if let elements::External::Function(type_ref) = entry.external_mut() {
+							*type_ref = self.type_ref(*type_ref);
+						}
+					}
+				},
+				elements::Section::Function(function_section) => {
+					retain(function_section.entries_mut(), &self.kept_functions);
+					for func in function_section.entries_mut() {
+						*func.type_ref_mut() = self.type_ref(func.type_ref());
+					}
+				},
+				elements::Section::Global(global_section) => {
+					retain(global_section.entries_mut(), &self.kept_globals);
+					for entry in global_section.entries_mut() {
+						self.remap_init_expr(entry.init_expr_mut());
+					}
+				},
+				elements::Section::Export(export_section) => {
+					retain(export_section.entries_mut(), &self.kept_exports);
+					for entry in export_section.entries_mut() {
+						match entry.internal_mut() {
+							elements::Internal::Function(index) => *index = self.entry(*index),
+							elements::Internal::Global(index) => *index = self.global(*index),
+							_ => {},
+						}
+					}
+				},
+				elements::Section::Start(index) => *index = self.entry(*index),
+				elements::Section::Element(elements_section) => {
+					for segment in elements_section.entries_mut() {
+						if let Some(offset) = segment.offset_mut() {
+							self.remap_init_expr(offset);
+						}
+						for index in segment.members_mut() {
+							*index = self.entry(*index);
+						}
+					}
+				},
+				elements::Section::Code(code_section) => {
+					let bodies = mem::take(code_section.bodies_mut());
+					for (index, mut body) in bodies.into_iter().enumerate() {
+						if self.kept_functions[index] {
+							self.instrument_body(index, body.code_mut().elements_mut())?;
+							code_section.bodies_mut().push(body);
+						}
+					}
+				},
+				elements::Section::Data(data_section) => {
+					for segment in data_section.entries_mut() {
+						if let Some(offset) = segment.offset_mut() {
+							self.remap_init_expr(offset);
+						}
+					}
+				},
+				elements::Section::Name(name_section) => {
+					if let Some(function_names) = name_section.functions_mut() {
+						*function_names.names_mut() = self.remap_names(mem::take(function_names.names_mut()));
+						if let Some(gas) = &self.gas {
+							function_names.names_mut().insert(gas.func, gas.config.field.into());
+						}
+					}
+					if let Some(local_names) = name_section.locals_mut() {
+						*local_names.local_names_mut() = self.remap_names(mem::take(local_names.local_names_mut()));
+					}
+				},
+				_ => {},
+			}
+		}
+		Ok(())
+	}
+
+	fn remap_init_expr(&self, init_expr: &mut elements::InitExpr) {
+		for instruction in init_expr.code_mut() {
+			if let Instruction::GetGlobal(index) = instruction {
+				*index = self.global(*index);
+			}
+		}
+	}
+
+	fn remap_names<T>(&self, names: elements::IndexMap<T>) -> elements::IndexMap<T> {
+		names.into_iter()
+			.filter_map(|(index, name)| Some((self.funcs.get(index as usize).copied().flatten()?, name)))
+			.collect()
+	}
+
+	/// Insert the charges into the body of the defined function `index`, update the references and
+	/// wrap the calls with the stack height checks.
+	fn instrument_body(&self, index: usize, instructions: &mut Vec<Instruction>) -> Result<(), Error> {
+		let original = mem::take(instructions);
+		let (blocks, prepaid_func, grow_counter) = match &self.gas {
+			Some(gas) if gas.exempt[index] => (&[][..], None, None),
+			Some(gas) => (&gas.metered_blocks[index][..], gas.prepaid_func, gas.grow_counter),
+			None => (&[][..], None, None),
+		};
+		let mut blocks = blocks.iter().peekable();
+		for (pos, instruction) in original.iter().enumerate() {
+			if let Some(block) = blocks.peek() {
+				if block.start_pos == pos {
+					let gas = self.gas.as_ref().expect("there are metered blocks only with gas metering; qed");
+					instructions.push(if gas.config.i64_amounts {
+						Instruction::I64Const(block.cost as i64)
+					} else {
+						Instruction::I32Const(block.cost as i32)
+					});
+					instructions.push(Instruction::Call(gas.func));
+					blocks.next();
+				}
+			}
+
+			if gas::is_prepaid_charge(&original, pos, prepaid_func) {
+				continue;
+			}
+			match *instruction {
+				Instruction::Call(index) => self.push_call(instructions, self.func(index))?,
+				Instruction::CallIndirect(type_ref, table) => {
+					instructions.push(Instruction::CallIndirect(self.type_ref(type_ref), table));
+				},
+				Instruction::GetGlobal(index) => instructions.push(Instruction::GetGlobal(self.global(index))),
+				Instruction::SetGlobal(index) => instructions.push(Instruction::SetGlobal(self.global(index))),
+				Instruction::GrowMemory(_) => match grow_counter {
+					Some(grow_counter) => self.push_call(instructions, grow_counter)?,
+					None => instructions.push(instruction.clone()),
+				},
+				_ => instructions.push(instruction.clone()),
+			}
+		}
+		Ok(())
+	}
+
+	/// Append the call of the function `index`, wrapped with the stack height checks if needed.
+	fn push_call(&self, instructions: &mut Vec<Instruction>, index: u32) -> Result<(), Error> {
+		match self.stack.as_ref().map(|stack| stack.ctx.instrumented_call(index)).transpose()?.flatten() {
+			Some(instrumented_call) => instructions.extend(instrumented_call),
+			None => instructions.push(Instruction::Call(index)),
+		}
+		Ok(())
+	}
+
+	/// Add the planned imports, globals, functions and exports to the remapped module.
+	fn append(self, module: elements::Module) -> Result<elements::Module, Error> {
+		let mut mbuilder = builder::from_module(module);
+		if let Some(gas) = &self.gas {
+			let amount_type = if gas.config.i64_amounts { ValueType::I64 } else { ValueType::I32 };
+			let import_sig = mbuilder.push_signature(builder::signature().with_param(amount_type).build_sig());
+			mbuilder.push_import(
+				builder::import()
+					.module(gas.config.module)
+					.field(gas.config.field)
+					.external().func(import_sig)
+					.build()
+			);
+		}
+		if let Some(stack) = &self.stack {
+			if let OverflowTrap::HostFunction { module, field } = stack.config.trap {
+				let import_sig = mbuilder.push_signature(builder::signature().build_sig());
+				mbuilder.push_import(builder::import().module(module).field(field).external().func(import_sig).build());
+			}
+			mbuilder.push_global(
+				builder::global()
+					.value_type().i32()
+					.mutable()
+					.init_expr(Instruction::I32Const(0))
+					.build()
+			);
+			if let (Some((index, true)), Some(export_name)) = (stack.trap_reason_global, stack.config.trap_reason) {
+				mbuilder.push_global(
+					builder::global()
+						.with_type(ValueType::I32)
+						.mutable()
+						.init_expr(Instruction::I32Const(trap_reason::NONE))
+						.build()
+				);
+				mbuilder.push_export(builder::export().field(export_name).internal().global(index).build());
+			}
+		}
+
+		if let Some(gas) = &self.gas {
+			if gas.grow_counter.is_some() {
+				let grow_metering = gas.rules.grow_metering();
+				mbuilder.push_function(gas::grow_counter(&*grow_metering, gas.func, gas.config.i64_amounts));
+			}
+		}
+		if let Some(stack) = &self.stack {
+			for (callee, (_, signature)) in &stack.thunks {
+				let mut thunk_body: Vec<Instruction> = (0..signature.params().len() as u32)
+					.map(Instruction::GetLocal)
+					.collect();
+				thunk_body.extend(stack.ctx.instrumented_call(*callee)?
+					.expect("thunks are planned for functions with a stack cost; qed"));
+				thunk_body.push(Instruction::End);
+				mbuilder.push_function(
+					builder::function()
+						.signature()
+							.with_params(signature.params().to_vec())
+							.with_results(signature.results().to_vec())
+							.build()
+						.body()
+							.with_instructions(elements::Instructions::new(thunk_body))
+							.build()
+						.build()
+				);
+			}
+		}
+
+		let mut module = mbuilder.build();
+		let grow_counter = self.gas.as_ref().and_then(|gas| gas.grow_counter);
+		if let (Some(grow_counter), Some(function_names)) = (
+			grow_counter,
+			module.names_section_mut().and_then(|name_section| name_section.functions_mut().as_mut()),
+		) {
+			function_names.names_mut().insert(grow_counter, "grow_counter".into());
+		}
+		if let Some(gas) = &self.gas {
+			if let Some(debug) = gas.config.debug_names {
+				debug.apply(&mut module, debug_names::GAS_IMPORT, elements::Internal::Function(gas.func));
+				if let Some(grow_counter) = gas.grow_counter {
+					debug.apply(&mut module, debug_names::GROW_COUNTER, elements::Internal::Function(grow_counter));
+				}
+			}
+		}
+		if let Some(stack) = &self.stack {
+			if let Some(debug) = stack.config.debug_names {
+				let global = elements::Internal::Global(stack.ctx.stack_height_global_idx());
+				debug.apply(&mut module, debug_names::STACK_DEPTH, global);
+			}
+		}
+		Ok(module)
+	}
+}
+
+/// The new indices of the entries of which `kept` are kept, numbered from `next` on.
+fn renumber(kept: &[bool], next: &mut u32) -> Vec<Option<u32>> {
+	kept.iter().map(|kept| if *kept { Some(take(next)) } else { None }).collect()
+}
+
+fn take(next: &mut u32) -> u32 {
+	*next += 1;
+	*next - 1
+}
+
+fn retain<T>(entries: &mut Vec<T>, kept: &[bool]) {
+	let mut kept = kept.iter();
+	entries.retain(|_| *kept.next().expect("an entry is kept or not for every entry; qed"));
+}
+
+/// Stack cost of the function replacing `memory.grow`, determined on a module containing only it
+/// and the gas function it calls.
+fn grow_counter_cost(gas: &GasPlan, config: LimiterConfig) -> Result<u32, Error> {
+	let amount_type = if gas.config.i64_amounts { ValueType::I64 } else { ValueType::I32 };
+	let mut mbuilder = builder::module();
+	let import_sig = mbuilder.push_signature(builder::signature().with_param(amount_type).build_sig());
+	mbuilder.push_import(builder::import().module("env").field("gas").external().func(import_sig).build());
+	mbuilder.push_function(gas::grow_counter(&*gas.rules.grow_metering(), 0, gas.config.i64_amounts));
+	let module = mbuilder.build();
+
+	let cost = stack_height::function_stack_info(1, &module)?
+		.cost(config.frame_cost)
+		.ok_or_else(|| stack_height::Error("Overflow in adding locals_count and max_stack_height".into()))?;
+	Ok(cost)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules::Set as Rules;
+	use crate::testing::parse_wat;
+
+	fn validate(module: &elements::Module) {
+		let binary = elements::serialize(module.clone()).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	const SOURCE: &str = r#"
+	(module
+		(import "env" "unused" (func $unused (param i32)))
+		(import "env" "log" (func $log (param i32)))
+		(global $g (mut i32) (i32.const 0))
+		(global $h (mut i32) (i32.const 1))
+		(memory 1)
+		(table 1 anyfunc)
+		(elem (i32.const 0) $helper)
+		(func $dead (param i32)
+			get_local 0
+			call $unused)
+		(func $helper (param i32) (result i32)
+			get_local 0
+			get_global $h
+			i32.add)
+		(func (export "call") (param i32) (result i32)
+			(local i32)
+			get_local 0
+			call $helper
+			tee_local 1
+			call $log
+			get_local 1
+			grow_memory
+			get_local 1
+			i32.const 0
+			call_indirect (param i32) (result i32)
+			i32.add)
+		(func (export "other")
+			i32.const 1
+			call $dead))
+	"#;
+
+	#[test]
+	fn same_as_passes() {
+		let rules = Rules::default().with_grow_cost(1);
+		let module = parse_wat(SOURCE);
+		let gas_config = GasConfig::new("env", "gas");
+
+		for gas_config in [gas_config, gas_config.with_exempt_exports(&["call"])] {
+			let mut expected = module.clone();
+			crate::optimize(&mut expected, vec!["call"]).unwrap();
+			let expected = crate::inject_gas_counter(expected, &rules, gas_config).unwrap();
+
+			let config = PipelineConfig::new()
+				.with_pruning(&["call"])
+				.with_gas(&rules, gas_config);
+			let instrumented = instrument(module.clone(), &config).unwrap();
+			assert_eq!(elements::serialize(instrumented).unwrap(), elements::serialize(expected).unwrap());
+		}
+	}
+
+	#[test]
+	fn all_passes() {
+		let rules = Rules::default().with_grow_cost(1);
+		let config = PipelineConfig::new()
+			.with_pruning(&["call"])
+			.with_gas(&rules, GasConfig::new("env", "gas"))
+			.with_stack_limit(1024, LimiterConfig::new().with_trap_reason("trap_reason"));
+		let module = instrument(parse_wat(SOURCE), &config).unwrap();
+		validate(&module);
+
+		// $unused and $dead are removed, the gas function imported after $log.
+		let imports: Vec<_> = module.import_section().unwrap().entries().iter().map(|entry| entry.field()).collect();
+		assert_eq!(imports, vec!["log", "gas"]);
+		// $helper, "call", the grow counter and the thunks of "call" and the table entry $helper.
+		assert_eq!(module.functions_space(), 2 + 5);
+		let exports = module.export_section().unwrap().entries();
+		assert_eq!(exports[0].internal(), &elements::Internal::Function(6));
+		assert_eq!(exports[1].field(), "trap_reason");
+		assert_eq!(module.elements_section().unwrap().entries()[0].members(), &[5]);
+		// $g is removed, $h kept, followed by the stack height and the trap reason globals.
+		assert_eq!(module.global_section().unwrap().entries().len(), 3);
+	}
+}
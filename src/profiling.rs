@@ -0,0 +1,200 @@
+//! The pass that counts how many times each function and each metered block is executed, e.g. to
+//! tune the costs of a rule set with the workloads of real contracts.
+//!
+//! Every function body gets an `i64` counter of its calls, and every metered block, as divided by
+//! the gas metering with the same rules, a counter of its executions. The counters are mutable
+//! globals, exported so that the host can read them after running the module in a test VM.
+//! Exporting mutable globals requires the mutable globals extension. Multiplying the count of a
+//! block by its cost gives the gas the block was charged.
+//!
+//! The counters are incremented before anything else, so they still count the executions which
+//! trap. No function indices change.
+
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::{builder, elements};
+use parity_wasm::elements::{Instruction, ValueType};
+
+use crate::gas::{self, Error, GasConfig};
+use crate::rules::Rules;
+
+/// Counter of the executions of a metered block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockCounter {
+	/// Position of the first instruction of the block in the original function body.
+	pub start_pos: usize,
+	/// Cost of the block according to the rules.
+	pub cost: u64,
+	/// Name the counter is exported under.
+	pub export: String,
+}
+
+/// Counters of a function body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCounters {
+	/// Index of the function.
+	pub function: u32,
+	/// Name the counter of the calls of the function is exported under.
+	pub calls: String,
+	/// Counters of the metered blocks of the function, in the order of the body.
+	pub blocks: Vec<BlockCounter>,
+}
+
+/// Append the instructions incrementing the counter `global`.
+fn increment(instructions: &mut Vec<Instruction>, global: u32) {
+	instructions.extend(vec![
+		Instruction::GetGlobal(global),
+		Instruction::I64Const(1),
+		Instruction::I64Add,
+		Instruction::SetGlobal(global),
+	]);
+}
+
+/// Instrument a module to count the executions of its functions and metered blocks.
+///
+/// The counter of the calls of function `n` is exported as `{prefix}{n}`, the one of its `k`-th
+/// metered block as `{prefix}{n}_{k}`. The metered blocks are the ones `inject_gas_counter`
+/// charges with the default `GasConfig`. Returns the module along with its counters, by function
+/// body. See module-level documentation for more details.
+///
+/// The function fails if the gas metering does, returning the original module along with the
+/// `Error`.
+pub fn inject_execution_counters<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	prefix: &str,
+)
+	-> Result<(elements::Module, Vec<FunctionCounters>), (elements::Module, Error)>
+{
+	let metered_blocks = match gas::plan_metering(&module, rules, &GasConfig::default(), &|_| true) {
+		Ok(metered_blocks) => metered_blocks,
+		Err(e) => return Err((module, e)),
+	};
+
+	// Defined globals come after the imported ones, so the new globals are the last ones.
+	let first_global = module.globals_space() as u32;
+	let imported_funcs = module.import_count(elements::ImportCountType::Function) as u32;
+
+	let mut module = module;
+	let mut counters = Vec::with_capacity(metered_blocks.len());
+	let mut global = first_global;
+	if let Some(code_section) = module.code_section_mut() {
+		for (index, (func_body, blocks)) in code_section.bodies_mut().iter_mut().zip(metered_blocks).enumerate() {
+			let function = imported_funcs + index as u32;
+			let calls_global = global;
+			global += 1;
+
+			let original = func_body.code_mut().elements_mut();
+			let mut instructions = Vec::with_capacity(original.len() + 4 * (blocks.len() + 1));
+			increment(&mut instructions, calls_global);
+			let mut blocks = blocks.into_iter().peekable();
+			let mut block_counters = Vec::new();
+			for (pos, instruction) in original.drain(..).enumerate() {
+				if let Some(block) = blocks.next_if(|block| block.start_pos == pos) {
+					increment(&mut instructions, global);
+					global += 1;
+					block_counters.push(BlockCounter {
+						start_pos: block.start_pos,
+						cost: block.cost,
+						export: format!("{}{}_{}", prefix, function, block_counters.len()),
+					});
+				}
+				instructions.push(instruction);
+			}
+			*original = instructions;
+
+			counters.push(FunctionCounters {
+				function,
+				calls: format!("{}{}", prefix, function),
+				blocks: block_counters,
+			});
+		}
+	}
+
+	let mut mbuilder = builder::from_module(module);
+	let exports = counters.iter()
+		.flat_map(|function| Some(&function.calls).into_iter().chain(function.blocks.iter().map(|block| &block.export)));
+	for (global, export) in (first_global..).zip(exports) {
+		mbuilder.push_global(
+			builder::global()
+				.with_type(ValueType::I64)
+				.mutable()
+				.init_expr(Instruction::I64Const(0))
+				.build()
+		);
+		mbuilder.push_export(
+			builder::export()
+				.field(export)
+				.internal().global(global)
+				.build()
+		);
+	}
+
+	Ok((mbuilder.build(), counters))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+	use parity_wasm::elements::Instruction::*;
+	use crate::testing::{get_function_body, parse_wat, validate_module};
+
+	#[test]
+	fn counts_functions_and_blocks() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext))
+				(global $g i32 (i32.const 0))
+				(func (param i32)
+					get_local 0
+					if
+						call $ext
+					end))
+		"#);
+
+		let metered = crate::inject_gas_counter(module.clone(), &rules::Set::default(), "env").unwrap();
+		let (module, counters) = inject_execution_counters(module, &rules::Set::default(), "count_").unwrap();
+
+		assert_eq!(
+			get_function_body(&module, 0).unwrap(),
+			&[
+				GetGlobal(1), I64Const(1), I64Add, SetGlobal(1),
+				GetGlobal(2), I64Const(1), I64Add, SetGlobal(2),
+				GetLocal(0),
+				If(elements::BlockType::NoResult),
+					GetGlobal(3), I64Const(1), I64Add, SetGlobal(3),
+					Call(0),
+				End,
+				End,
+			][..]
+		);
+		assert_eq!(counters, vec![FunctionCounters {
+			function: 1,
+			calls: "count_1".into(),
+			blocks: vec![
+				BlockCounter { start_pos: 0, cost: 2, export: "count_1_0".into() },
+				BlockCounter { start_pos: 2, cost: 1, export: "count_1_1".into() },
+			],
+		}]);
+		// The blocks are the ones charged by the gas metering, with the same costs. The gas
+		// function is imported after `$ext`.
+		let charges: Vec<_> = get_function_body(&metered, 0).unwrap()
+			.windows(2)
+			.filter_map(|pair| match pair {
+				[I32Const(cost), Call(1)] => Some(*cost as u64),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(charges, counters[0].blocks.iter().map(|block| block.cost).collect::<Vec<_>>());
+
+		let export = module.export_section().unwrap().entries().iter()
+			.find(|e| e.field() == "count_1_1")
+			.expect("block counter is exported");
+		assert_eq!(export.internal(), &elements::Internal::Global(3));
+		assert!(module.global_section().unwrap().entries()[3].global_type().is_mutable());
+
+		validate_module(module);
+	}
+}
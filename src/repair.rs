@@ -0,0 +1,319 @@
+//! Repair of the section layout of a module.
+//!
+//! Some producers emit sections out of the order required by the specification, split a section
+//! into several, or emit sections without any entries. parity-wasm and strict validators reject
+//! such modules, so the repair works on the binary before it is deserialized:
+//!
+//! - Sections are moved into the order required by the specification. Custom sections stay
+//!   behind the section they follow, or at the start of the module.
+//! - Sections without entries are dropped.
+//! - Consecutive sections of the same kind are merged by concatenating their entries. This is
+//!   legal for all sections holding a vector of entries, as the index spaces they define are
+//!   built in the order of the entries. The start and data count sections can't be merged.
+
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+/// Id of custom sections.
+const CUSTOM: u8 = 0;
+/// Id of the start section.
+const START: u8 = 8;
+/// Id of the data count section of the bulk memory operations.
+const DATA_COUNT: u8 = 12;
+
+/// Error of the repair of a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+	/// The module does not start with the magic number and version 1.
+	InvalidHeader,
+	/// The section or its entry count starting at the given offset is truncated.
+	Truncated(usize),
+	/// The module contains a section with an unknown id.
+	UnknownSection(u8),
+	/// The module contains several sections of a kind which can't be merged.
+	DuplicatedSection(u8),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::InvalidHeader => write!(f, "Not a WebAssembly 1.0 module"),
+			Error::Truncated(offset) => write!(f, "Truncated section at offset {}", offset),
+			Error::UnknownSection(id) => write!(f, "Unknown section id {}", id),
+			Error::DuplicatedSection(id) =>
+				write!(f, "Several {} sections, which can't be merged", section_name(id)),
+		}
+	}
+}
+
+/// A change made by `repair`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+	/// The sections were moved into the order required by the specification.
+	Reordered,
+	/// A section with the given id and without entries was dropped.
+	DroppedEmpty(u8),
+	/// The given number of sections with the given id were merged into one.
+	Merged { id: u8, sections: usize },
+}
+
+impl fmt::Display for Repair {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Repair::Reordered => write!(f, "Moved sections into the specified order"),
+			Repair::DroppedEmpty(id) => write!(f, "Dropped empty {} section", section_name(id)),
+			Repair::Merged { id, sections } =>
+				write!(f, "Merged {} {} sections into one", sections, section_name(id)),
+		}
+	}
+}
+
+/// Name of the section with the given id.
+pub fn section_name(id: u8) -> &'static str {
+	match id {
+		0 => "custom",
+		1 => "type",
+		2 => "import",
+		3 => "function",
+		4 => "table",
+		5 => "memory",
+		6 => "global",
+		7 => "export",
+		8 => "start",
+		9 => "element",
+		10 => "code",
+		11 => "data",
+		12 => "data count",
+		_ => "unknown",
+	}
+}
+
+/// Position of the section with the given id in the order required by the specification.
+///
+/// The data count section comes before the code section despite its higher id.
+pub(crate) fn order(id: u8) -> Option<u8> {
+	match id {
+		1..=9 => Some(id),
+		DATA_COUNT => Some(10),
+		10 | 11 => Some(id + 1),
+		_ => None,
+	}
+}
+
+/// Read an unsigned LEB128 integer at `pos`, advancing it past the integer.
+pub(crate) fn read_var_u32(bytes: &[u8], pos: &mut usize) -> Option<u32> {
+	let mut result = 0u32;
+	for shift in (0..35).step_by(7) {
+		let byte = *bytes.get(*pos)?;
+		*pos += 1;
+		result |= u32::from(byte & 0x7f).checked_shl(shift)?;
+		if byte & 0x80 == 0 {
+			return Some(result);
+		}
+	}
+	None
+}
+
+/// Append `value` as an unsigned LEB128 integer of minimal length.
+pub(crate) fn write_var_u32(out: &mut Vec<u8>, mut value: u32) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			out.push(byte);
+			return;
+		}
+		out.push(byte | 0x80);
+	}
+}
+
+/// A section of the module being repaired.
+struct Section<'a> {
+	id: u8,
+	/// Number of entries, for sections holding a vector of entries.
+	count: Option<u32>,
+	/// The payload without the entry count, if there is one.
+	entries: Vec<&'a [u8]>,
+}
+
+impl<'a> Section<'a> {
+	fn parse(id: u8, payload: &'a [u8], offset: usize) -> Result<Self, Error> {
+		if id == CUSTOM || id == START || id == DATA_COUNT {
+			return Ok(Section { id, count: None, entries: vec![payload] });
+		}
+		if payload.is_empty() {
+			return Ok(Section { id, count: Some(0), entries: Vec::new() });
+		}
+		let mut pos = 0;
+		let count = read_var_u32(payload, &mut pos).ok_or(Error::Truncated(offset))?;
+		Ok(Section { id, count: Some(count), entries: vec![&payload[pos..]] })
+	}
+
+	fn is_empty(&self) -> bool {
+		self.count == Some(0) && self.entries.iter().all(|entries| entries.is_empty())
+	}
+
+	fn write(&self, out: &mut Vec<u8>) {
+		let mut payload = Vec::new();
+		if let Some(count) = self.count {
+			write_var_u32(&mut payload, count);
+		}
+		for entries in &self.entries {
+			payload.extend_from_slice(entries);
+		}
+		out.push(self.id);
+		write_var_u32(out, payload.len() as u32);
+		out.extend(payload);
+	}
+}
+
+/// Repair the section layout of the module `bytes`.
+///
+/// Returns the repaired module along with the repairs made, which are none if the layout is
+/// already valid. See module-level documentation for the repairs.
+///
+/// # Errors
+///
+/// Returns `Err` if the module is truncated, contains unknown sections or several start or data
+/// count sections.
+pub fn repair(bytes: &[u8]) -> Result<(Vec<u8>, Vec<Repair>), Error> {
+	const HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+
+	if bytes.len() < HEADER.len() || bytes[..HEADER.len()] != HEADER {
+		return Err(Error::InvalidHeader);
+	}
+
+	// Each group is a section along with the custom sections following it. The custom sections at
+	// the start of the module form a group of their own, which is kept first.
+	let mut groups: Vec<(u8, Vec<Section>)> = vec![(0, Vec::new())];
+	let mut pos = HEADER.len();
+	while pos < bytes.len() {
+		let offset = pos;
+		let id = bytes[pos];
+		pos += 1;
+		let size = read_var_u32(bytes, &mut pos).ok_or(Error::Truncated(offset))? as usize;
+		let payload = bytes.get(pos..pos + size).ok_or(Error::Truncated(offset))?;
+		pos += size;
+
+		let section = Section::parse(id, payload, offset)?;
+		if id == CUSTOM {
+			groups.last_mut().expect("there is always the leading group; qed").1.push(section);
+		} else {
+			let order = order(id).ok_or(Error::UnknownSection(id))?;
+			groups.push((order, vec![section]));
+		}
+	}
+
+	let mut repairs = Vec::new();
+	if groups.windows(2).any(|pair| pair[0].0 > pair[1].0) {
+		repairs.push(Repair::Reordered);
+	}
+	groups.sort_by_key(|(order, _)| *order);
+
+	// Sections to write, and the number of sections merged into each of them.
+	let mut sections: Vec<(Section, usize)> = Vec::new();
+	// Index in `sections` of the last section which isn't a custom one.
+	let mut last: Option<usize> = None;
+	for (_, group) in groups {
+		for section in group {
+			if section.id == CUSTOM {
+				sections.push((section, 1));
+				continue;
+			}
+			if section.is_empty() {
+				repairs.push(Repair::DroppedEmpty(section.id));
+				continue;
+			}
+			match last {
+				Some(index) if sections[index].0.id == section.id => {
+					let merged = &mut sections[index];
+					let (count, other) = match (merged.0.count, section.count) {
+						(Some(count), Some(other)) => (count, other),
+						_ => return Err(Error::DuplicatedSection(section.id)),
+					};
+					merged.0.count = Some(count.saturating_add(other));
+					merged.0.entries.extend(section.entries);
+					merged.1 += 1;
+				}
+				_ => {
+					last = Some(sections.len());
+					sections.push((section, 1));
+				}
+			}
+		}
+	}
+
+	let mut out = HEADER.to_vec();
+	for (section, merged) in &sections {
+		if *merged > 1 {
+			repairs.push(Repair::Merged { id: section.id, sections: *merged });
+		}
+		section.write(&mut out);
+	}
+
+	Ok((out, repairs))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements;
+
+	fn section(id: u8, payload: &[u8]) -> Vec<u8> {
+		let mut out = vec![id, payload.len() as u8];
+		out.extend_from_slice(payload);
+		out
+	}
+
+	fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+		let mut out = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+		for section in sections {
+			out.extend_from_slice(section);
+		}
+		out
+	}
+
+	#[test]
+	fn repairs_layout() {
+		let body = [0x02, 0x00, 0x0b];
+		let original = module(&[
+			section(10, &[&[1][..], &body].concat()),
+			section(1, &[1, 0x60, 0, 0]),
+			section(0, &[1, b'x']),
+			section(3, &[1, 0]),
+			section(3, &[1, 0]),
+			section(6, &[0]),
+			section(10, &[&[1][..], &body].concat()),
+		]);
+		assert!(elements::deserialize_buffer::<elements::Module>(&original).is_err());
+
+		let (repaired, repairs) = repair(&original).unwrap();
+
+		assert_eq!(repairs, vec![
+			Repair::Reordered,
+			Repair::DroppedEmpty(6),
+			Repair::Merged { id: 3, sections: 2 },
+			Repair::Merged { id: 10, sections: 2 },
+		]);
+		assert_eq!(repaired, module(&[
+			section(1, &[1, 0x60, 0, 0]),
+			section(0, &[1, b'x']),
+			section(3, &[2, 0, 0]),
+			section(10, &[&[2][..], &body, &body].concat()),
+		]));
+		let module: elements::Module = elements::deserialize_buffer(&repaired).unwrap();
+		assert_eq!(module.functions_space(), 2);
+	}
+
+	#[test]
+	fn keeps_valid_module() {
+		let original = module(&[section(1, &[1, 0x60, 0, 0]), section(8, &[0])]);
+		assert_eq!(repair(&original).unwrap(), (original, Vec::new()));
+	}
+
+	#[test]
+	fn rejects_duplicated_start() {
+		let original = module(&[section(8, &[0]), section(8, &[0])]);
+		assert_eq!(repair(&original), Err(Error::DuplicatedSection(8)));
+	}
+}
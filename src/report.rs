@@ -0,0 +1,748 @@
+//! Hierarchical summary of the contents of a module, as explorers and auditors show it.
+
+use crate::std::collections::BTreeMap;
+use crate::std::fmt::{self, Write};
+use crate::std::string::{String, ToString};
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, External, ImportCountType, Instruction, Internal, Serialize};
+
+use crate::gas::{self, GasConfig};
+use crate::optimizer::drop_extended_names;
+use crate::repair::read_var_u32;
+use crate::rules::Rules;
+
+/// Subsection of the extended name section naming the data segments.
+const DATA_NAMES: u8 = 9;
+
+/// Kind of an imported entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportKind {
+	Function,
+	Table,
+	Memory,
+	Global,
+}
+
+impl ImportKind {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ImportKind::Function => "function",
+			ImportKind::Table => "table",
+			ImportKind::Memory => "memory",
+			ImportKind::Global => "global",
+		}
+	}
+}
+
+/// An imported entity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Import {
+	pub field: String,
+	pub kind: ImportKind,
+	/// Index in the index space of its kind.
+	pub index: u32,
+}
+
+/// The entities imported from one module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportModule {
+	pub module: String,
+	pub imports: Vec<Import>,
+}
+
+/// A function defined in the module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+	/// Index in the function index space.
+	pub index: u32,
+	/// Demangled name without the namespace, if the name section names the function.
+	pub name: Option<String>,
+	/// Names under which the function is exported.
+	pub exports: Vec<String>,
+	/// Number of instructions of the body.
+	pub instructions: usize,
+}
+
+/// The defined functions whose names share a namespace, e.g. a Rust module path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+	/// The namespace, empty for functions without a namespace or a name.
+	pub path: String,
+	pub functions: Vec<Function>,
+}
+
+/// A data segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSegment {
+	pub index: u32,
+	/// Offset in the memory, if it is a constant.
+	pub offset: Option<u32>,
+	pub size: usize,
+	/// Name of the segment in the extended name section, e.g. `.rodata`.
+	pub label: Option<String>,
+}
+
+/// Summary of the imports, functions and data segments of a module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleMap {
+	/// Imports grouped by module, in the order of their first import.
+	pub imports: Vec<ImportModule>,
+	/// Defined functions grouped by namespace, sorted by namespace.
+	pub namespaces: Vec<Namespace>,
+	pub data: Vec<DataSegment>,
+}
+
+/// Demangle a symbol of the legacy Rust mangling scheme, e.g. `_ZN4core3fmt5write17h0123456789abcdefE`
+/// as `core::fmt::write`. Other names are returned unchanged.
+fn demangle(name: &str) -> String {
+	demangle_legacy(name).unwrap_or_else(|| name.to_string())
+}
+
+fn demangle_legacy(name: &str) -> Option<String> {
+	let mut rest = name.strip_prefix("_ZN")?;
+	let mut components = Vec::new();
+	while !rest.starts_with('E') {
+		let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+		let len: usize = rest[..digits].parse().ok()?;
+		components.push(rest.get(digits..digits + len)?);
+		rest = &rest[digits + len..];
+	}
+	// The hash of the crate and the signature.
+	let is_hash = |component: &str| {
+		component.len() == 17 && component.starts_with('h') && component[1..].chars().all(|c| c.is_ascii_hexdigit())
+	};
+	if components.len() > 1 && is_hash(components[components.len() - 1]) {
+		components.pop();
+	}
+
+	let components = components.into_iter()
+		.map(|component| {
+			// Components starting with an escape are prefixed by an underscore.
+			let component = if component.starts_with("_$") { &component[1..] } else { component };
+			[
+				("$LT$", "<"), ("$GT$", ">"), ("$RF$", "&"), ("$BP$", "*"), ("$C$", ","),
+				("$u20$", " "), ("$u27$", "'"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"),
+				("$u7d$", "}"), ("$u7e$", "~"), ("..", "::"),
+			]
+				.iter()
+				.fold(component.to_string(), |component, (escaped, unescaped)| component.replace(escaped, unescaped))
+		})
+		.collect::<Vec<_>>();
+	Some(components.join("::"))
+}
+
+/// Split a demangled `name` into its namespace and its last component, ignoring the separators
+/// within generic arguments, e.g. in `<T as core::fmt::Debug>::fmt`.
+fn split_namespace(name: &str) -> (&str, &str) {
+	let mut depth = 0i32;
+	let mut split = None;
+	for (pos, c) in name.char_indices() {
+		match c {
+			'<' => depth += 1,
+			'>' => depth -= 1,
+			':' if depth == 0 && name[pos..].starts_with("::") => split = Some(pos),
+			_ => {}
+		}
+	}
+	match split {
+		Some(pos) => (&name[..pos], &name[pos + 2..]),
+		None => ("", name),
+	}
+}
+
+/// Read an entry of a name map at `pos`, advancing it past the entry.
+fn read_name(bytes: &[u8], pos: &mut usize) -> Option<(u32, String)> {
+	let index = read_var_u32(bytes, pos)?;
+	let len = read_var_u32(bytes, pos)? as usize;
+	let name = bytes.get(*pos..pos.checked_add(len)?)?;
+	*pos += len;
+	Some((index, String::from_utf8_lossy(name).into_owned()))
+}
+
+/// Names of the data segments in the extended name section of the `module`, which parity-wasm
+/// does not parse.
+fn data_names(module: &elements::Module) -> BTreeMap<u32, String> {
+	let payload = module.custom_sections()
+		.find(|section| section.name() == "name")
+		.map_or(&[][..], |section| section.payload());
+
+	let mut names = BTreeMap::new();
+	let mut pos = 0;
+	while pos < payload.len() {
+		let id = payload[pos];
+		pos += 1;
+		let size = match read_var_u32(payload, &mut pos) {
+			Some(size) => size as usize,
+			None => break,
+		};
+		let end = match pos.checked_add(size).filter(|end| *end <= payload.len()) {
+			Some(end) => end,
+			None => break,
+		};
+		if id == DATA_NAMES {
+			let mut subsection_pos = pos;
+			let subsection = &payload[..end];
+			let count = read_var_u32(subsection, &mut subsection_pos).unwrap_or(0);
+			for _ in 0..count {
+				match read_name(subsection, &mut subsection_pos) {
+					Some((index, name)) => { names.insert(index, name); }
+					None => break,
+				}
+			}
+		}
+		pos = end;
+	}
+	names
+}
+
+/// Names of the functions in the name section of the `module`, which is parsed if necessary.
+fn function_names(module: &elements::Module) -> BTreeMap<u32, String> {
+	let parsed_module;
+	let module = if module.names_section().is_none() && module.has_names_section() {
+		let mut module = module.clone();
+		drop_extended_names(&mut module);
+		parsed_module = module.parse_names().unwrap_or_else(|(_, module)| module);
+		&parsed_module
+	} else {
+		module
+	};
+	module.names_section()
+		.and_then(|name_section| name_section.functions())
+		.map(|function_names| function_names.names().iter().map(|(index, name)| (index, name.clone())).collect())
+		.unwrap_or_default()
+}
+
+/// Summarize the imports, defined functions and data segments of the `module`.
+///
+/// Functions are grouped by the namespace of their demangled name in the name section, data
+/// segments are labelled with their names in the extended name section.
+pub fn module_map(module: &elements::Module) -> ModuleMap {
+	let mut map = ModuleMap::default();
+
+	let mut counts = [0u32; 4];
+	for entry in module.import_section().map_or(&[][..], |import_section| import_section.entries()) {
+		let kind = match entry.external() {
+			External::Function(_) => ImportKind::Function,
+			External::Table(_) => ImportKind::Table,
+			External::Memory(_) => ImportKind::Memory,
+			External::Global(_) => ImportKind::Global,
+		};
+		let index = counts[kind as usize];
+		counts[kind as usize] += 1;
+
+		let import = Import { field: entry.field().into(), kind, index };
+		match map.imports.iter_mut().find(|group| group.module == entry.module()) {
+			Some(group) => group.imports.push(import),
+			None => map.imports.push(ImportModule { module: entry.module().into(), imports: vec![import] }),
+		}
+	}
+
+	let mut exports: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+	for entry in module.export_section().map_or(&[][..], |export_section| export_section.entries()) {
+		if let Internal::Function(index) = *entry.internal() {
+			exports.entry(index).or_default().push(entry.field().into());
+		}
+	}
+
+	let names = function_names(module);
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+	let mut namespaces: BTreeMap<String, Vec<Function>> = BTreeMap::new();
+	for (index, body) in module.code_section().map_or(&[][..], |code_section| code_section.bodies()).iter().enumerate() {
+		let index = imported_funcs + index as u32;
+		let demangled = names.get(&index).map(|name| demangle(name));
+		let (path, name) = match demangled {
+			Some(ref demangled) => {
+				let (path, name) = split_namespace(demangled);
+				(path.to_string(), Some(name.to_string()))
+			}
+			None => (String::new(), None),
+		};
+		namespaces.entry(path).or_default().push(Function {
+			index,
+			name,
+			exports: exports.remove(&index).unwrap_or_default(),
+			instructions: body.code().elements().len(),
+		});
+	}
+	map.namespaces = namespaces.into_iter().map(|(path, functions)| Namespace { path, functions }).collect();
+
+	let labels = data_names(module);
+	map.data = module.data_section()
+		.map_or(&[][..], |data_section| data_section.entries())
+		.iter()
+		.enumerate()
+		.map(|(index, segment)| {
+			let index = index as u32;
+			let offset = match segment.offset().as_ref().map(|offset| offset.code()) {
+				Some([Instruction::I32Const(offset), Instruction::End]) => Some(*offset as u32),
+				_ => None,
+			};
+			DataSegment { index, offset, size: segment.value().len(), label: labels.get(&index).cloned() }
+		})
+		.collect();
+
+	map
+}
+
+/// Escape the special characters of HTML in `text`.
+fn escape_html(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+impl Function {
+	fn write_summary(&self, f: &mut dyn Write, escape: fn(&str) -> String) -> fmt::Result {
+		write!(f, "function {}", self.index)?;
+		if let Some(name) = &self.name {
+			write!(f, " {}", escape(name))?;
+		}
+		for export in &self.exports {
+			write!(f, ", exported as \"{}\"", escape(export))?;
+		}
+		write!(f, ", {} instructions", self.instructions)
+	}
+}
+
+impl DataSegment {
+	fn write_summary(&self, f: &mut dyn Write, escape: fn(&str) -> String) -> fmt::Result {
+		write!(f, "segment {}", self.index)?;
+		match self.offset {
+			Some(offset) => write!(f, " at {}", offset)?,
+			None => write!(f, " at a dynamic offset")?,
+		}
+		write!(f, ", {} bytes", self.size)?;
+		if let Some(label) = &self.label {
+			write!(f, ": {}", escape(label))?;
+		}
+		Ok(())
+	}
+}
+
+/// Display name of the namespace `path`.
+fn namespace_name(path: &str) -> &str {
+	if path.is_empty() { "(root)" } else { path }
+}
+
+impl ModuleMap {
+	/// Render the map as a standalone HTML document of nested lists.
+	pub fn to_html(&self) -> String {
+		let mut html = String::new();
+		self.write_html(&mut html).expect("writing to a string never fails; qed");
+		html
+	}
+
+	fn write_html(&self, f: &mut dyn Write) -> fmt::Result {
+		writeln!(f, "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Module map</title></head>\n<body>")?;
+
+		writeln!(f, "<h2>Imports</h2>\n<ul>")?;
+		for group in &self.imports {
+			writeln!(f, "<li>{}\n<ul>", escape_html(&group.module))?;
+			for import in &group.imports {
+				writeln!(f, "<li>{} {} {}</li>", import.kind.as_str(), import.index, escape_html(&import.field))?;
+			}
+			writeln!(f, "</ul></li>")?;
+		}
+		writeln!(f, "</ul>")?;
+
+		writeln!(f, "<h2>Functions</h2>\n<ul>")?;
+		for namespace in &self.namespaces {
+			writeln!(f, "<li>{}\n<ul>", escape_html(namespace_name(&namespace.path)))?;
+			for function in &namespace.functions {
+				write!(f, "<li>")?;
+				function.write_summary(f, escape_html)?;
+				writeln!(f, "</li>")?;
+			}
+			writeln!(f, "</ul></li>")?;
+		}
+		writeln!(f, "</ul>")?;
+
+		writeln!(f, "<h2>Data</h2>\n<ul>")?;
+		for segment in &self.data {
+			write!(f, "<li>")?;
+			segment.write_summary(f, escape_html)?;
+			writeln!(f, "</li>")?;
+		}
+		writeln!(f, "</ul>\n</body>\n</html>")
+	}
+}
+
+impl fmt::Display for ModuleMap {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let unescaped: fn(&str) -> String = ToString::to_string;
+
+		writeln!(f, "imports")?;
+		for group in &self.imports {
+			writeln!(f, "  {}", group.module)?;
+			for import in &group.imports {
+				writeln!(f, "    {} {} {}", import.kind.as_str(), import.index, import.field)?;
+			}
+		}
+
+		writeln!(f, "functions")?;
+		for namespace in &self.namespaces {
+			writeln!(f, "  {}", namespace_name(&namespace.path))?;
+			for function in &namespace.functions {
+				write!(f, "    ")?;
+				function.write_summary(f, unescaped)?;
+				writeln!(f)?;
+			}
+		}
+
+		writeln!(f, "data")?;
+		for segment in &self.data {
+			write!(f, "  ")?;
+			segment.write_summary(f, unescaped)?;
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+/// Static gas cost of a function, as metered with the default `GasConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionGas {
+	/// Index in the function index space.
+	pub index: u32,
+	/// Sum of the costs of the metered blocks, i.e. the gas charged if each block runs once.
+	pub cost: u64,
+	/// Number of metered blocks.
+	pub blocks: usize,
+}
+
+/// The gas of a function in two builds of a module, see `gas_diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionGasDiff {
+	/// Demangled name in the name section, or else the first export name. Functions without a
+	/// name are matched by signature, in the order of the modules.
+	pub name: Option<String>,
+	/// The function in the old module, if it has one.
+	pub old: Option<FunctionGas>,
+	/// The function in the new module, if it has one.
+	pub new: Option<FunctionGas>,
+	/// Whether the function has a different signature in the new module.
+	pub signature_changed: bool,
+}
+
+impl FunctionGasDiff {
+	/// Change of the static cost, positive if the function got more expensive. Added and removed
+	/// functions change it by their whole cost.
+	pub fn cost_change(&self) -> i128 {
+		i128::from(self.new.map_or(0, |gas| gas.cost)) - i128::from(self.old.map_or(0, |gas| gas.cost))
+	}
+
+	/// Change of the number of metered blocks.
+	pub fn block_change(&self) -> i64 {
+		self.new.map_or(0, |gas| gas.blocks as i64) - self.old.map_or(0, |gas| gas.blocks as i64)
+	}
+
+	/// Whether the function was added, removed or changed its cost, blocks or signature.
+	pub fn is_changed(&self) -> bool {
+		match (self.old, self.new) {
+			(Some(old), Some(new)) => old.cost != new.cost || old.blocks != new.blocks || self.signature_changed,
+			_ => true,
+		}
+	}
+}
+
+/// Differences of the static gas costs between two builds of a module, see `gas_diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GasDiff {
+	/// Every function of either module, the matched ones in the order of the old module followed
+	/// by the added ones.
+	pub functions: Vec<FunctionGasDiff>,
+}
+
+impl GasDiff {
+	/// The functions which were added, removed or changed.
+	pub fn changed(&self) -> impl Iterator<Item = &FunctionGasDiff> {
+		self.functions.iter().filter(|function| function.is_changed())
+	}
+
+	/// Sum of the cost changes of all functions.
+	pub fn cost_change(&self) -> i128 {
+		self.functions.iter().map(FunctionGasDiff::cost_change).sum()
+	}
+}
+
+impl fmt::Display for GasDiff {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for function in self.changed() {
+			match &function.name {
+				Some(name) => write!(f, "{}", name)?,
+				None => {
+					let gas = function.old.or(function.new).expect("every function is in one of the modules; qed");
+					write!(f, "function {}", gas.index)?;
+				}
+			}
+			let cost = |gas: Option<FunctionGas>| gas.map_or("-".into(), |gas| gas.cost.to_string());
+			let blocks = |gas: Option<FunctionGas>| gas.map_or("-".into(), |gas| gas.blocks.to_string());
+			write!(
+				f,
+				": cost {} -> {} ({:+}), blocks {} -> {} ({:+})",
+				cost(function.old), cost(function.new), function.cost_change(),
+				blocks(function.old), blocks(function.new), function.block_change(),
+			)?;
+			if function.signature_changed {
+				write!(f, ", signature changed")?;
+			}
+			writeln!(f)?;
+		}
+		writeln!(f, "total cost change: {:+}", self.cost_change())
+	}
+}
+
+/// A defined function of a module compared by `gas_diff`.
+struct GasEntry {
+	name: Option<String>,
+	/// The signature as encoded in the type section.
+	signature: Vec<u8>,
+	gas: FunctionGas,
+}
+
+/// The static gas costs of the functions defined by the `module`.
+fn gas_entries<R: Rules>(module: &elements::Module, rules: &R) -> Result<Vec<GasEntry>, gas::Error> {
+	let metered_blocks = gas::plan_metering(module, rules, &GasConfig::default(), &|_| true)?;
+
+	let mut exports: BTreeMap<u32, &str> = BTreeMap::new();
+	for entry in module.export_section().map_or(&[][..], |export_section| export_section.entries()) {
+		if let Internal::Function(index) = *entry.internal() {
+			exports.entry(index).or_insert_with(|| entry.field());
+		}
+	}
+	let names = function_names(module);
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let functions = module.function_section().map_or(&[][..], |function_section| function_section.entries());
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+
+	Ok(metered_blocks.into_iter()
+		.zip(functions)
+		.enumerate()
+		.map(|(index, (blocks, function))| {
+			let index = imported_funcs + index as u32;
+			let name = names.get(&index)
+				.map(|name| demangle(name))
+				.or_else(|| exports.get(&index).map(|name| name.to_string()));
+			let mut signature = Vec::new();
+			if let Some(ty) = types.get(function.type_ref() as usize) {
+				ty.clone().serialize(&mut signature).expect("serializing into a vector doesn't fail; qed");
+			}
+			let cost = blocks.iter().fold(0u64, |cost, block| cost.saturating_add(block.cost));
+			GasEntry { name, signature, gas: FunctionGas { index, cost, blocks: blocks.len() } }
+		})
+		.collect())
+}
+
+/// Compare the static gas costs of the functions of two builds of the same contract, e.g. to catch
+/// gas regressions in CI when dependencies are updated.
+///
+/// Functions are matched by their demangled name, which doesn't include the hash of the crate, or
+/// else by their export name. Functions without either are matched by signature in the order in
+/// which they are defined, which only pairs them up reliably if the code around them didn't
+/// change much. The cost of a function is the sum of the costs of its metered blocks under
+/// `rules`, so it changes with the cost of any block even if the gas of an execution doesn't.
+pub fn gas_diff<R: Rules>(
+	old_module: &elements::Module,
+	new_module: &elements::Module,
+	rules: &R,
+) -> Result<GasDiff, gas::Error> {
+	let old = gas_entries(old_module, rules)?;
+	let new = gas_entries(new_module, rules)?;
+
+	// The functions of the new module by name, and the unnamed ones by signature, in reverse
+	// order so that they are matched in order by popping them.
+	let mut named: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+	let mut unnamed: BTreeMap<&[u8], Vec<usize>> = BTreeMap::new();
+	for (position, entry) in new.iter().enumerate().rev() {
+		match &entry.name {
+			Some(name) => named.entry(name.as_str()).or_default().push(position),
+			None => unnamed.entry(&entry.signature[..]).or_default().push(position),
+		}
+	}
+
+	let mut matched = vec![false; new.len()];
+	let mut diff = GasDiff::default();
+	for entry in &old {
+		let position = match &entry.name {
+			Some(name) => named.get_mut(name.as_str()).and_then(|positions| positions.pop()),
+			None => unnamed.get_mut(&entry.signature[..]).and_then(|positions| positions.pop()),
+		};
+		let new_entry = position.map(|position| {
+			matched[position] = true;
+			&new[position]
+		});
+		diff.functions.push(FunctionGasDiff {
+			name: entry.name.clone(),
+			old: Some(entry.gas),
+			new: new_entry.map(|new_entry| new_entry.gas),
+			signature_changed: matches!(new_entry, Some(new_entry) if new_entry.signature != entry.signature),
+		});
+	}
+	for (entry, _) in new.iter().zip(matched).filter(|(_, matched)| !matched) {
+		diff.functions.push(FunctionGasDiff {
+			name: entry.name.clone(),
+			old: None,
+			new: Some(entry.gas),
+			signature_changed: false,
+		});
+	}
+	Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn demangles_rust_names() {
+		assert_eq!(demangle("_ZN4core3fmt5write17h0123456789abcdefE"), "core::fmt::write");
+		assert_eq!(
+			demangle("_ZN60_$LT$alloc..string..String$u20$as$u20$core..fmt..Display$GT$3fmt17h0123456789abcdefE"),
+			"<alloc::string::String as core::fmt::Display>::fmt",
+		);
+		assert_eq!(demangle("memcpy"), "memcpy");
+		assert_eq!(split_namespace("core::fmt::write"), ("core::fmt", "write"));
+		assert_eq!(
+			split_namespace("<alloc::string::String as core::fmt::Display>::fmt"),
+			("<alloc::string::String as core::fmt::Display>", "fmt"),
+		);
+		assert_eq!(split_namespace("memcpy"), ("", "memcpy"));
+	}
+
+	#[test]
+	fn maps_module() {
+		let bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(r#"
+				(module
+					(import "env" "ext" (func $ext))
+					(import "env" "memory" (memory 1))
+					(import "host" "log" (func $log (param i32)))
+					(func $_ZN4core3fmt5write17h0123456789abcdefE
+						nop)
+					(func $main (export "call")
+						call $_ZN4core3fmt5write17h0123456789abcdefE)
+					(data (i32.const 16) "hello"))
+			"#)
+			.unwrap();
+		let module = elements::deserialize_buffer(bytes.as_ref()).unwrap();
+
+		let map = module_map(&module);
+
+		assert_eq!(map.imports.len(), 2);
+		assert_eq!(map.imports[0].module, "env");
+		assert_eq!(
+			map.imports[0].imports,
+			vec![
+				Import { field: "ext".into(), kind: ImportKind::Function, index: 0 },
+				Import { field: "memory".into(), kind: ImportKind::Memory, index: 0 },
+			],
+		);
+		assert_eq!(map.imports[1].imports, vec![Import { field: "log".into(), kind: ImportKind::Function, index: 1 }]);
+
+		assert_eq!(
+			map.namespaces,
+			vec![
+				Namespace {
+					path: "".into(),
+					functions: vec![Function { index: 3, name: Some("main".into()), exports: vec!["call".into()], instructions: 2 }],
+				},
+				Namespace {
+					path: "core::fmt".into(),
+					functions: vec![Function { index: 2, name: Some("write".into()), exports: vec![], instructions: 2 }],
+				},
+			],
+		);
+		assert_eq!(map.data, vec![DataSegment { index: 0, offset: Some(16), size: 5, label: None }]);
+
+		let text = map.to_string();
+		assert!(text.contains("    function 3 main, exported as \"call\", 2 instructions\n"));
+		assert!(text.contains("  segment 0 at 16, 5 bytes\n"));
+		let html = map.to_html();
+		assert!(html.contains("<li>function 3 main, exported as \"call\", 2 instructions</li>"));
+	}
+
+	#[test]
+	fn labels_data_segments() {
+		let mut module = elements::deserialize_buffer::<elements::Module>(
+			&wabt::wat2wasm(r#"(module (memory 1) (data (i32.const 0) "a") (data (i32.const 8) "bc"))"#).unwrap()
+		).unwrap();
+		// The data names subsection, naming the second segment `.rodata`.
+		let mut payload = vec![DATA_NAMES, 10, 1, 1, 7];
+		payload.extend_from_slice(b".rodata");
+		module.set_custom_section("name", payload);
+
+		let map = module_map(&module);
+
+		assert_eq!(
+			map.data,
+			vec![
+				DataSegment { index: 0, offset: Some(0), size: 1, label: None },
+				DataSegment { index: 1, offset: Some(8), size: 2, label: Some(".rodata".into()) },
+			],
+		);
+	}
+
+	#[test]
+	fn diffs_gas() {
+		let parse = |source: &str| {
+			let bytes = wabt::Wat2Wasm::new().write_debug_names(true).convert(source).unwrap();
+			elements::deserialize_buffer::<elements::Module>(bytes.as_ref()).unwrap()
+		};
+		let old = parse(r#"
+			(module
+				(func $_ZN3lib4hash17h0123456789abcdefE (param i32) (result i32)
+					(i32.add (local.get 0) (i32.const 1)))
+				(func (export "call")
+					(drop (call $_ZN3lib4hash17h0123456789abcdefE (i32.const 1))))
+				(func (param i64)
+					nop)
+				(func $removed
+					nop))
+		"#);
+		// The hash of the crate changed with the update.
+		let new = parse(r#"
+			(module
+				(func $_ZN3lib4hash17hfedcba9876543210E (param i32) (result i32)
+					(i32.mul (i32.add (local.get 0) (i32.const 1)) (i32.const 3)))
+				(func (export "call")
+					(drop (call $_ZN3lib4hash17hfedcba9876543210E (i32.const 1))))
+				(func (param i64)
+					nop
+					nop)
+				(func $added (param i32)
+					(if (local.get 0) (then nop))))
+		"#);
+
+		let diff = gas_diff(&old, &new, &crate::rules::Set::default()).unwrap();
+
+		assert_eq!(diff.functions.len(), 5);
+		assert_eq!(
+			diff.functions[0],
+			FunctionGasDiff {
+				name: Some("lib::hash".into()),
+				old: Some(FunctionGas { index: 0, cost: 3, blocks: 1 }),
+				new: Some(FunctionGas { index: 0, cost: 5, blocks: 1 }),
+				signature_changed: false,
+			},
+		);
+		assert!(!diff.functions[1].is_changed());
+		assert_eq!((diff.functions[2].name.as_deref(), diff.functions[2].cost_change()), (None, 1));
+		assert_eq!((diff.functions[3].name.as_deref(), diff.functions[3].new), (Some("removed"), None));
+		assert_eq!((diff.functions[4].name.as_deref(), diff.functions[4].old), (Some("added"), None));
+		assert_eq!(diff.changed().count(), 4);
+
+		let text = diff.to_string();
+		assert!(text.starts_with("lib::hash: cost 3 -> 5 (+2), blocks 1 -> 1 (+0)\nfunction 2: cost 1 -> 2 (+1)"));
+		assert!(text.contains("removed: cost 1 -> - (-1), blocks 1 -> - (-1)\n"));
+	}
+}
@@ -3,14 +3,33 @@ use crate::std::collections::HashMap as Map;
 #[cfg(not(features = "std"))]
 use crate::std::collections::BTreeMap as Map;
 
+use crate::std::boxed::Box;
+use crate::std::mem;
 use crate::std::num::NonZeroU32;
 use crate::std::str::FromStr;
+use crate::std::string::String;
+use crate::std::vec::Vec;
 use parity_wasm::elements::Instruction;
 
+use crate::gas::MeteredBlock;
+
 pub struct UnknownInstruction;
 
+/// `Sync` if the `parallel` feature is enabled, so that function bodies can be metered on
+/// several threads, and implemented by all types otherwise.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+/// `Sync` if the `parallel` feature is enabled, so that function bodies can be metered on
+/// several threads, and implemented by all types otherwise.
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
 /// An interface that describes instruction costs.
-pub trait Rules {
+pub trait Rules: MaybeSync {
 	/// Returns the cost for the passed `instruction`.
 	///
 	/// Returning `None` makes the gas instrumention end with an error. This is meant
@@ -18,6 +37,17 @@ pub trait Rules {
 	/// is considered as forbidden.
 	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32>;
 
+	/// Returns the cost for the passed `instruction` given its effect on the operand stack.
+	///
+	/// This is what the gas instrumentation asks for, so that schedules can price instructions
+	/// by the number of values they move rather than by their opcode, e.g. `call` by the arity of
+	/// the callee. `effect` is `None` for instructions changing the control flow and for calls of
+	/// functions the module doesn't declare. The default ignores the effect and returns
+	/// `instruction_cost`.
+	fn instruction_cost_with_effect(&self, instruction: &Instruction, _effect: Option<StackEffect>) -> Option<u32> {
+		self.instruction_cost(instruction)
+	}
+
 	/// Returns the costs for growing the memory using the `memory.grow` instruction.
 	///
 	/// Please note that these costs are in addition to the costs specified by `instruction_cost`
@@ -27,8 +57,206 @@ pub trait Rules {
 	/// those costs depend on the stack and must be injected as code into the function calling
 	/// `memory.grow`. Therefore returning `Some` comes with a performance cost.
 	fn memory_grow_cost(&self) -> Option<MemoryGrowCost>;
+
+	/// Returns the strategy charging for `memory.grow`.
+	///
+	/// This allows pricing schemes which `memory_grow_cost` can't express. The default charges
+	/// `memory_grow_cost` per page, which is ignored if this is overridden.
+	fn grow_metering(&self) -> Box<dyn GrowMetering + '_> {
+		Box::new(match self.memory_grow_cost() {
+			Some(MemoryGrowCost::Linear(cost)) => GrowStrategy::Flat(cost.get()),
+			None => GrowStrategy::Flat(0),
+		})
+	}
+
+	/// Returns the instruction which the imported function `module`.`field` stands in for.
+	///
+	/// Toolchains lower features missing on the target to calls of helper imports. Calls to a
+	/// function for which `Some` is returned are charged like the returned instruction instead
+	/// of like a call.
+	fn intrinsic(&self, _module: &str, _field: &str) -> Option<Instruction> {
+		None
+	}
+
+	/// Returns the factor for the costs of instructions inside of loops of the function `name`.
+	///
+	/// Modules often link their own copies of helpers like `memcpy` or `memset`, whose loops a
+	/// runtime would price like host intrinsics rather than like the instructions they consist of.
+	/// The function is called with the names a defined function has in the name section and in the
+	/// exports, and the first `Some` is used. `None` for all names leaves the costs unchanged.
+	fn loop_multiplier(&self, _name: &str) -> Option<u32> {
+		None
+	}
+
+	/// Returns the cost of every local a function declares, charged when the function is entered
+	/// for zeroing its locals. Parameters are not included.
+	fn local_cost(&self) -> u32 {
+		0
+	}
+
+	/// Returns the cost of every byte of the locals a function declares, e.g. 16 bytes for a
+	/// `v128` local, charged when the function is entered in addition to `local_cost`.
+	fn local_byte_cost(&self) -> u32 {
+		0
+	}
+
+	/// Returns the maximal number of locals a function may declare, not including its parameters.
+	///
+	/// Functions declaring more are rejected by the gas instrumentation, regardless of
+	/// `local_cost`. `None` means there is no limit.
+	fn max_locals(&self) -> Option<u32> {
+		None
+	}
+
+	/// Returns the fee charged for every `call` on top of its instruction cost.
+	///
+	/// Calls to intrinsics are charged like the instruction they implement and don't pay it.
+	fn call_cost(&self) -> u32 {
+		0
+	}
+
+	/// Returns the fee charged for every `call_indirect` on top of its instruction cost, e.g. for
+	/// checking the signature of the callee.
+	fn call_indirect_cost(&self) -> u32 {
+		0
+	}
+
+	/// Returns `true` if the rules price `instruction` deliberately rather than by a fallback.
+	///
+	/// Instructions which aren't known are handled as returned by `unknown_policy`. The default
+	/// knows all instructions.
+	fn is_known(&self, _instruction: &Instruction) -> bool {
+		true
+	}
+
+	/// Returns what the gas instrumentation does with instructions which aren't known, e.g. the
+	/// instructions a newer parity-wasm decodes which the rules weren't written for.
+	fn unknown_policy(&self) -> UnknownPolicy {
+		UnknownPolicy::Reject
+	}
+
+	/// Returns the cost charged for `block` of the function at index `function`, given the cost
+	/// determined from its instructions.
+	///
+	/// This is applied after the blocks are determined, e.g. to scale the costs by correction
+	/// factors per function calibrated at runtime. The default returns the determined cost. See
+	/// `BlockCosts` for overriding it with a closure.
+	fn block_cost(&self, _function: u32, block: &MeteredBlock) -> u64 {
+		block.cost
+	}
+}
+
+/// Rules charging the metered blocks as computed by a closure and everything else like the wrapped
+/// rules, see `Rules::block_cost`.
+pub struct BlockCosts<'a, R: ?Sized, F> {
+	rules: &'a R,
+	block_cost: F,
+}
+
+impl<'a, R: Rules + ?Sized, F: Fn(u32, &MeteredBlock) -> u64> BlockCosts<'a, R, F> {
+	/// Charge the blocks by `block_cost`, which is given the function index and the block with the
+	/// cost determined by `rules`.
+	pub fn new(rules: &'a R, block_cost: F) -> Self {
+		BlockCosts { rules, block_cost }
+	}
 }
 
+impl<'a, R: Rules + ?Sized, F: Fn(u32, &MeteredBlock) -> u64 + MaybeSync> Rules for BlockCosts<'a, R, F> {
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+		self.rules.instruction_cost(instruction)
+	}
+
+	fn instruction_cost_with_effect(&self, instruction: &Instruction, effect: Option<StackEffect>) -> Option<u32> {
+		self.rules.instruction_cost_with_effect(instruction, effect)
+	}
+
+	fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
+		self.rules.memory_grow_cost()
+	}
+
+	fn grow_metering(&self) -> Box<dyn GrowMetering + '_> {
+		self.rules.grow_metering()
+	}
+
+	fn intrinsic(&self, module: &str, field: &str) -> Option<Instruction> {
+		self.rules.intrinsic(module, field)
+	}
+
+	fn loop_multiplier(&self, name: &str) -> Option<u32> {
+		self.rules.loop_multiplier(name)
+	}
+
+	fn local_cost(&self) -> u32 {
+		self.rules.local_cost()
+	}
+
+	fn local_byte_cost(&self) -> u32 {
+		self.rules.local_byte_cost()
+	}
+
+	fn max_locals(&self) -> Option<u32> {
+		self.rules.max_locals()
+	}
+
+	fn call_cost(&self) -> u32 {
+		self.rules.call_cost()
+	}
+
+	fn call_indirect_cost(&self) -> u32 {
+		self.rules.call_indirect_cost()
+	}
+
+	fn is_known(&self, instruction: &Instruction) -> bool {
+		self.rules.is_known(instruction)
+	}
+
+	fn unknown_policy(&self) -> UnknownPolicy {
+		self.rules.unknown_policy()
+	}
+
+	fn block_cost(&self, function: u32, block: &MeteredBlock) -> u64 {
+		(self.block_cost)(function, block)
+	}
+}
+
+/// What the gas instrumentation does with instructions the rules don't know, see
+/// `Rules::is_known`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum UnknownPolicy {
+	/// Charge the returned cost, i.e. `Rules::instruction_cost`.
+	Charge(u32),
+	/// Fail on the first unknown instruction.
+	Reject,
+	/// Fail after looking at all function bodies, reporting the classes of all unknown
+	/// instructions of the module at once. The streaming instrumentation fails on the first one.
+	Forbid,
+}
+
+/// Number of values an instruction pops from and pushes onto the operand stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+	pub pops: u32,
+	pub pushes: u32,
+}
+
+/// Names of helper functions emitted in place of 64-bit arithmetic, and the instructions they
+/// implement.
+pub const WELL_KNOWN_INTRINSICS: &[(&str, Instruction)] = &[
+	("__wasm_i64_mul", Instruction::I64Mul),
+	("__wasm_i64_sdiv", Instruction::I64DivS),
+	("__wasm_i64_udiv", Instruction::I64DivU),
+	("__wasm_i64_srem", Instruction::I64RemS),
+	("__wasm_i64_urem", Instruction::I64RemU),
+	("__muldi3", Instruction::I64Mul),
+	("__divdi3", Instruction::I64DivS),
+	("__udivdi3", Instruction::I64DivU),
+	("__moddi3", Instruction::I64RemS),
+	("__umoddi3", Instruction::I64RemU),
+	("__ashldi3", Instruction::I64Shl),
+	("__ashrdi3", Instruction::I64ShrS),
+	("__lshrdi3", Instruction::I64ShrU),
+];
+
 /// Dynamic costs for memory growth.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum MemoryGrowCost {
@@ -36,6 +264,140 @@ pub enum MemoryGrowCost {
 	Linear(NonZeroU32),
 }
 
+/// A strategy charging for `memory.grow`.
+///
+/// If `memory.grow` is charged, the gas instrumentation replaces all `memory.grow` instructions
+/// by calls of an added function. It takes the number of pages to grow by as its only parameter,
+/// charges the amount computed by `amount` and grows the memory.
+pub trait GrowMetering {
+	/// Returns `true` if modules growing the memory are rejected by the gas instrumentation.
+	fn is_forbidden(&self) -> bool {
+		false
+	}
+
+	/// Returns `true` if `memory.grow` is charged beyond its instruction cost.
+	fn is_charged(&self) -> bool;
+
+	/// Returns the number of `i64` locals used by `amount`, which follow the parameter.
+	fn scratch_locals(&self) -> u32 {
+		0
+	}
+
+	/// Append the instructions pushing the amount to charge for growing the memory by the number
+	/// of pages in local 0, as `i64` if `i64_amount` is `true` and as `i32` otherwise.
+	///
+	/// The amount must not wrap around for any number of pages. An amount which doesn't fit into
+	/// an `i32` reinterpreted as `u32` should be capped at `u32::MAX` rather than truncated.
+	fn amount(&self, instructions: &mut Vec<Instruction>, i64_amount: bool);
+}
+
+/// Number of pages of a memory beyond which growing it always fails.
+const MAX_PAGES: i64 = 65536;
+
+/// The strategies charging for `memory.grow` which can be selected in a rule set.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum GrowStrategy {
+	/// Charge the specified amount for each page that the memory is grown by. Nothing is charged
+	/// for an amount of zero.
+	Flat(u32),
+	/// Charge per page depending on the size of the memory. Each tier `(pages, price)` sets the
+	/// price of the pages below `pages`, starting where the previous tier ends. The price of the
+	/// last tier also applies to all pages beyond it.
+	Tiered(Vec<(u32, u32)>),
+	/// Charge the fee of each step `(pages, fee)` when the memory grows from below `pages` pages
+	/// to at least `pages` pages, so that crossing a size costs its fee once. Like the other
+	/// strategies, the fees are charged for the requested size even if growing the memory fails.
+	Stepped(Vec<(u32, u32)>),
+	/// Leave `memory.grow` as is, for a host which charges for it in its own implementation.
+	HostDelegated,
+	/// Reject modules growing the memory.
+	Forbidden,
+}
+
+impl GrowMetering for GrowStrategy {
+	fn is_forbidden(&self) -> bool {
+		*self == GrowStrategy::Forbidden
+	}
+
+	fn is_charged(&self) -> bool {
+		match self {
+			GrowStrategy::Flat(cost) => *cost != 0,
+			GrowStrategy::Tiered(tiers) => !tiers.is_empty(),
+			GrowStrategy::Stepped(steps) => !steps.is_empty(),
+			GrowStrategy::HostDelegated | GrowStrategy::Forbidden => false,
+		}
+	}
+
+	fn scratch_locals(&self) -> u32 {
+		match self {
+			GrowStrategy::Flat(_) => 1,
+			GrowStrategy::Tiered(_) => 3,
+			GrowStrategy::Stepped(_) => 2,
+			_ => 0,
+		}
+	}
+
+	fn amount(&self, instructions: &mut Vec<Instruction>, i64_amount: bool) {
+		use Instruction::*;
+
+		match self {
+			// The product of two `u32` fits into an `u64`.
+			GrowStrategy::Flat(cost) =>
+				instructions.extend(vec![GetLocal(0), I64ExtendUI32, I64Const(i64::from(*cost)), I64Mul]),
+			GrowStrategy::Tiered(tiers) => {
+				// The current size is kept in local 1 and the new size in local 3. The new size is
+				// capped just above `MAX_PAGES`, as growing beyond fails anyway, so that the amount
+				// stays below 2^49. For each tier, the pages between the current and the new size
+				// which fall into it are computed with `select` as
+				// `max(0, min(new, end) - max(current, start))`, using local 2 for the difference.
+				instructions.extend(vec![
+					CurrentMemory(0), I64ExtendUI32, TeeLocal(1), GetLocal(0), I64ExtendUI32, I64Add, SetLocal(3),
+					I64Const(MAX_PAGES + 1), GetLocal(3), GetLocal(3), I64Const(MAX_PAGES + 1), I64GtU, Select,
+					SetLocal(3),
+					I64Const(0),
+				]);
+				let mut start = 0;
+				for (index, &(pages, price)) in tiers.iter().enumerate() {
+					let end = if index + 1 == tiers.len() { i64::MAX } else { i64::from(pages) };
+					instructions.extend(vec![GetLocal(3), I64Const(end), GetLocal(3), I64Const(end), I64LtS, Select]);
+					instructions.extend(vec![
+						GetLocal(1), I64Const(start), GetLocal(1), I64Const(start), I64GtS, Select,
+						I64Sub, TeeLocal(2),
+						I64Const(0), GetLocal(2), I64Const(0), I64GtS, Select,
+						I64Const(i64::from(price)), I64Mul, I64Add,
+					]);
+					start = end;
+				}
+			}
+			GrowStrategy::Stepped(steps) => {
+				// The current size is kept in local 1 and the new size in local 2. The fee of each
+				// step is selected if `current < pages && new >= pages`.
+				instructions.extend(vec![
+					CurrentMemory(0), I64ExtendUI32, TeeLocal(1), GetLocal(0), I64ExtendUI32, I64Add, SetLocal(2),
+					I64Const(0),
+				]);
+				for &(pages, fee) in steps {
+					let pages = i64::from(pages);
+					instructions.extend(vec![
+						I64Const(i64::from(fee)), I64Const(0),
+						GetLocal(1), I64Const(pages), I64LtS, GetLocal(2), I64Const(pages), I64GeS, I32And,
+						Select, I64Add,
+					]);
+				}
+			}
+			GrowStrategy::HostDelegated | GrowStrategy::Forbidden => return,
+		}
+
+		if !i64_amount {
+			// Cap the amount at `u32::MAX` using local 1, which is not needed anymore.
+			let cap = i64::from(u32::MAX);
+			instructions.extend(vec![
+				SetLocal(1), I64Const(cap), GetLocal(1), GetLocal(1), I64Const(cap), I64GtU, Select, I32WrapI64,
+			]);
+		}
+	}
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Metering {
 	Regular,
@@ -62,6 +424,23 @@ pub enum InstructionType {
 	Conversion,
 	FloatConversion,
 	Reinterpretation,
+	SignExtension,
+	/// SIMD operators on `v128` values, like `i32x4.add`, including their loads, stores and
+	/// constants.
+	Simd,
+	/// Atomic loads of the threads proposal, like `i32.atomic.load`.
+	AtomicLoad,
+	/// Atomic stores of the threads proposal, like `i32.atomic.store`.
+	AtomicStore,
+	/// Atomic read-modify-write operators of the threads proposal, like `i32.atomic.rmw.add` or
+	/// `i32.atomic.rmw.cmpxchg`.
+	AtomicRmw,
+	/// `memory.atomic.wait32`, `memory.atomic.wait64` and `memory.atomic.notify` of the threads
+	/// proposal. Only a base cost is charged, regardless of the time spent waiting.
+	AtomicWait,
+	/// Bulk memory operations, like `memory.copy`. Only a base cost is charged per instruction,
+	/// regardless of the number of bytes or elements processed.
+	BulkMemory,
 	Unreachable,
 	Nop,
 	CurrentMemory,
@@ -89,6 +468,13 @@ impl FromStr for InstructionType {
 			"conversion" => Ok(InstructionType::Conversion),
 			"float_conversion" => Ok(InstructionType::FloatConversion),
 			"reinterpret" => Ok(InstructionType::Reinterpretation),
+			"sign_ext" => Ok(InstructionType::SignExtension),
+			"simd" => Ok(InstructionType::Simd),
+			"atomic_load" => Ok(InstructionType::AtomicLoad),
+			"atomic_store" => Ok(InstructionType::AtomicStore),
+			"atomic_rmw" => Ok(InstructionType::AtomicRmw),
+			"atomic_wait" => Ok(InstructionType::AtomicWait),
+			"bulk" => Ok(InstructionType::BulkMemory),
 			"unreachable" => Ok(InstructionType::Unreachable),
 			"nop" => Ok(InstructionType::Nop),
 			"current_mem" => Ok(InstructionType::CurrentMemory),
@@ -291,15 +677,322 @@ impl InstructionType {
 			I64ReinterpretF64 => InstructionType::Reinterpretation,
 			F32ReinterpretI32 => InstructionType::Reinterpretation,
 			F64ReinterpretI64 => InstructionType::Reinterpretation,
+
+			#[cfg(feature = "sign_ext")]
+			SignExt(_) => InstructionType::SignExtension,
+
+			#[cfg(feature = "simd")]
+			Simd(_) => InstructionType::Simd,
+
+			#[cfg(feature = "atomics")]
+			Atomics(ref atomic) => {
+				use parity_wasm::elements::AtomicsInstruction::*;
+
+				match *atomic {
+					AtomicWake(_) | I32AtomicWait(_) | I64AtomicWait(_) => InstructionType::AtomicWait,
+					I32AtomicLoad(_) | I64AtomicLoad(_) | I32AtomicLoad8u(_) | I32AtomicLoad16u(_)
+					| I64AtomicLoad8u(_) | I64AtomicLoad16u(_) | I64AtomicLoad32u(_) => InstructionType::AtomicLoad,
+					I32AtomicStore(_) | I64AtomicStore(_) | I32AtomicStore8u(_) | I32AtomicStore16u(_)
+					| I64AtomicStore8u(_) | I64AtomicStore16u(_) | I64AtomicStore32u(_) => InstructionType::AtomicStore,
+					_ => InstructionType::AtomicRmw,
+				}
+			}
+
+			#[cfg(feature = "bulk")]
+			Bulk(_) => InstructionType::BulkMemory,
 		}
 	}
 }
 
-#[derive(Debug)]
+/// The instructions with their mnemonics in the text format, with zero immediates.
+fn named_instructions() -> Vec<(&'static str, Instruction)> {
+	use parity_wasm::elements::{BlockType, BrTableData, Instruction::*};
+
+	#[allow(unused_mut)]
+	let mut named = vec![
+		("unreachable", Unreachable),
+		("nop", Nop),
+		("block", Block(BlockType::NoResult)),
+		("loop", Loop(BlockType::NoResult)),
+		("if", If(BlockType::NoResult)),
+		("else", Else),
+		("end", End),
+		("br", Br(0)),
+		("br_if", BrIf(0)),
+		("br_table", BrTable(Box::new(BrTableData { table: Box::new([]), default: 0 }))),
+		("return", Return),
+		("call", Call(0)),
+		("call_indirect", CallIndirect(0, 0)),
+		("drop", Drop),
+		("select", Select),
+		("local.get", GetLocal(0)),
+		("local.set", SetLocal(0)),
+		("local.tee", TeeLocal(0)),
+		("global.get", GetGlobal(0)),
+		("global.set", SetGlobal(0)),
+		("i32.load", I32Load(0, 0)),
+		("i64.load", I64Load(0, 0)),
+		("f32.load", F32Load(0, 0)),
+		("f64.load", F64Load(0, 0)),
+		("i32.load8_s", I32Load8S(0, 0)),
+		("i32.load8_u", I32Load8U(0, 0)),
+		("i32.load16_s", I32Load16S(0, 0)),
+		("i32.load16_u", I32Load16U(0, 0)),
+		("i64.load8_s", I64Load8S(0, 0)),
+		("i64.load8_u", I64Load8U(0, 0)),
+		("i64.load16_s", I64Load16S(0, 0)),
+		("i64.load16_u", I64Load16U(0, 0)),
+		("i64.load32_s", I64Load32S(0, 0)),
+		("i64.load32_u", I64Load32U(0, 0)),
+		("i32.store", I32Store(0, 0)),
+		("i64.store", I64Store(0, 0)),
+		("f32.store", F32Store(0, 0)),
+		("f64.store", F64Store(0, 0)),
+		("i32.store8", I32Store8(0, 0)),
+		("i32.store16", I32Store16(0, 0)),
+		("i64.store8", I64Store8(0, 0)),
+		("i64.store16", I64Store16(0, 0)),
+		("i64.store32", I64Store32(0, 0)),
+		("memory.size", CurrentMemory(0)),
+		("memory.grow", GrowMemory(0)),
+		("i32.const", I32Const(0)),
+		("i64.const", I64Const(0)),
+		("f32.const", F32Const(0)),
+		("f64.const", F64Const(0)),
+		("i32.eqz", I32Eqz),
+		("i32.eq", I32Eq),
+		("i32.ne", I32Ne),
+		("i32.lt_s", I32LtS),
+		("i32.lt_u", I32LtU),
+		("i32.gt_s", I32GtS),
+		("i32.gt_u", I32GtU),
+		("i32.le_s", I32LeS),
+		("i32.le_u", I32LeU),
+		("i32.ge_s", I32GeS),
+		("i32.ge_u", I32GeU),
+		("i64.eqz", I64Eqz),
+		("i64.eq", I64Eq),
+		("i64.ne", I64Ne),
+		("i64.lt_s", I64LtS),
+		("i64.lt_u", I64LtU),
+		("i64.gt_s", I64GtS),
+		("i64.gt_u", I64GtU),
+		("i64.le_s", I64LeS),
+		("i64.le_u", I64LeU),
+		("i64.ge_s", I64GeS),
+		("i64.ge_u", I64GeU),
+		("f32.eq", F32Eq),
+		("f32.ne", F32Ne),
+		("f32.lt", F32Lt),
+		("f32.gt", F32Gt),
+		("f32.le", F32Le),
+		("f32.ge", F32Ge),
+		("f64.eq", F64Eq),
+		("f64.ne", F64Ne),
+		("f64.lt", F64Lt),
+		("f64.gt", F64Gt),
+		("f64.le", F64Le),
+		("f64.ge", F64Ge),
+		("i32.clz", I32Clz),
+		("i32.ctz", I32Ctz),
+		("i32.popcnt", I32Popcnt),
+		("i32.add", I32Add),
+		("i32.sub", I32Sub),
+		("i32.mul", I32Mul),
+		("i32.div_s", I32DivS),
+		("i32.div_u", I32DivU),
+		("i32.rem_s", I32RemS),
+		("i32.rem_u", I32RemU),
+		("i32.and", I32And),
+		("i32.or", I32Or),
+		("i32.xor", I32Xor),
+		("i32.shl", I32Shl),
+		("i32.shr_s", I32ShrS),
+		("i32.shr_u", I32ShrU),
+		("i32.rotl", I32Rotl),
+		("i32.rotr", I32Rotr),
+		("i64.clz", I64Clz),
+		("i64.ctz", I64Ctz),
+		("i64.popcnt", I64Popcnt),
+		("i64.add", I64Add),
+		("i64.sub", I64Sub),
+		("i64.mul", I64Mul),
+		("i64.div_s", I64DivS),
+		("i64.div_u", I64DivU),
+		("i64.rem_s", I64RemS),
+		("i64.rem_u", I64RemU),
+		("i64.and", I64And),
+		("i64.or", I64Or),
+		("i64.xor", I64Xor),
+		("i64.shl", I64Shl),
+		("i64.shr_s", I64ShrS),
+		("i64.shr_u", I64ShrU),
+		("i64.rotl", I64Rotl),
+		("i64.rotr", I64Rotr),
+		("f32.abs", F32Abs),
+		("f32.neg", F32Neg),
+		("f32.ceil", F32Ceil),
+		("f32.floor", F32Floor),
+		("f32.trunc", F32Trunc),
+		("f32.nearest", F32Nearest),
+		("f32.sqrt", F32Sqrt),
+		("f32.add", F32Add),
+		("f32.sub", F32Sub),
+		("f32.mul", F32Mul),
+		("f32.div", F32Div),
+		("f32.min", F32Min),
+		("f32.max", F32Max),
+		("f32.copysign", F32Copysign),
+		("f64.abs", F64Abs),
+		("f64.neg", F64Neg),
+		("f64.ceil", F64Ceil),
+		("f64.floor", F64Floor),
+		("f64.trunc", F64Trunc),
+		("f64.nearest", F64Nearest),
+		("f64.sqrt", F64Sqrt),
+		("f64.add", F64Add),
+		("f64.sub", F64Sub),
+		("f64.mul", F64Mul),
+		("f64.div", F64Div),
+		("f64.min", F64Min),
+		("f64.max", F64Max),
+		("f64.copysign", F64Copysign),
+		("i32.wrap_i64", I32WrapI64),
+		("i32.trunc_f32_s", I32TruncSF32),
+		("i32.trunc_f32_u", I32TruncUF32),
+		("i32.trunc_f64_s", I32TruncSF64),
+		("i32.trunc_f64_u", I32TruncUF64),
+		("i64.extend_i32_s", I64ExtendSI32),
+		("i64.extend_i32_u", I64ExtendUI32),
+		("i64.trunc_f32_s", I64TruncSF32),
+		("i64.trunc_f32_u", I64TruncUF32),
+		("i64.trunc_f64_s", I64TruncSF64),
+		("i64.trunc_f64_u", I64TruncUF64),
+		("f32.convert_i32_s", F32ConvertSI32),
+		("f32.convert_i32_u", F32ConvertUI32),
+		("f32.convert_i64_s", F32ConvertSI64),
+		("f32.convert_i64_u", F32ConvertUI64),
+		("f32.demote_f64", F32DemoteF64),
+		("f64.convert_i32_s", F64ConvertSI32),
+		("f64.convert_i32_u", F64ConvertUI32),
+		("f64.convert_i64_s", F64ConvertSI64),
+		("f64.convert_i64_u", F64ConvertUI64),
+		("f64.promote_f32", F64PromoteF32),
+		("i32.reinterpret_f32", I32ReinterpretF32),
+		("i64.reinterpret_f64", I64ReinterpretF64),
+		("f32.reinterpret_i32", F32ReinterpretI32),
+		("f64.reinterpret_i64", F64ReinterpretI64),
+	];
+	#[cfg(feature = "sign_ext")]
+	{
+		use parity_wasm::elements::SignExtInstruction::*;
+		named.extend(vec![
+			("i32.extend8_s", SignExt(I32Extend8S)),
+			("i32.extend16_s", SignExt(I32Extend16S)),
+			("i64.extend8_s", SignExt(I64Extend8S)),
+			("i64.extend16_s", SignExt(I64Extend16S)),
+			("i64.extend32_s", SignExt(I64Extend32S)),
+		]);
+	}
+	#[cfg(feature = "bulk")]
+	{
+		use parity_wasm::elements::BulkInstruction::*;
+		named.extend(vec![
+			("memory.init", Bulk(MemoryInit(0))),
+			("data.drop", Bulk(MemoryDrop(0))),
+			("memory.copy", Bulk(MemoryCopy)),
+			("memory.fill", Bulk(MemoryFill)),
+			("table.init", Bulk(TableInit(0))),
+			("elem.drop", Bulk(TableDrop(0))),
+			("table.copy", Bulk(TableCopy)),
+		]);
+	}
+	named
+}
+
+/// The names parity-wasm prints for the instructions renamed by the specification since, e.g. in
+/// the opcode histogram of `wasm-utils analyze`, with their current names.
+const RENAMED_INSTRUCTIONS: &[(&str, &str)] = &[
+	("get_local", "local.get"),
+	("set_local", "local.set"),
+	("tee_local", "local.tee"),
+	("get_global", "global.get"),
+	("set_global", "global.set"),
+	("current_memory", "memory.size"),
+	("grow_memory", "memory.grow"),
+	("i32.wrap/i64", "i32.wrap_i64"),
+	("i32.trunc_s/f32", "i32.trunc_f32_s"),
+	("i32.trunc_u/f32", "i32.trunc_f32_u"),
+	("i32.trunc_s/f64", "i32.trunc_f64_s"),
+	("i32.trunc_u/f64", "i32.trunc_f64_u"),
+	("i64.extend_s/i32", "i64.extend_i32_s"),
+	("i64.extend_u/i32", "i64.extend_i32_u"),
+	("i64.trunc_s/f32", "i64.trunc_f32_s"),
+	("i64.trunc_u/f32", "i64.trunc_f32_u"),
+	("i64.trunc_s/f64", "i64.trunc_f64_s"),
+	("i64.trunc_u/f64", "i64.trunc_f64_u"),
+	("f32.convert_s/i32", "f32.convert_i32_s"),
+	("f32.convert_u/i32", "f32.convert_i32_u"),
+	("f32.convert_s/i64", "f32.convert_i64_s"),
+	("f32.convert_u/i64", "f32.convert_i64_u"),
+	("f32.demote/f64", "f32.demote_f64"),
+	("f64.convert_s/i32", "f64.convert_i32_s"),
+	("f64.convert_u/i32", "f64.convert_i32_u"),
+	("f64.convert_s/i64", "f64.convert_i64_s"),
+	("f64.convert_u/i64", "f64.convert_i64_u"),
+	("f64.promote/f32", "f64.promote_f32"),
+	("i32.reinterpret/f32", "i32.reinterpret_f32"),
+	("i64.reinterpret/f64", "i64.reinterpret_f64"),
+	("f32.reinterpret/i32", "f32.reinterpret_i32"),
+	("f64.reinterpret/i64", "f64.reinterpret_i64"),
+	("memory.drop", "data.drop"),
+	("table.drop", "elem.drop"),
+];
+
+/// The instruction with the mnemonic `name` in the text format, e.g. `"i64.div_s"` or
+/// `"memory.grow"`, with zero immediates. The names parity-wasm prints for renamed instructions,
+/// e.g. `"grow_memory"`, are accepted as well.
+///
+/// The instructions of the MVP and of the sign extension and bulk memory proposals are named, the
+/// SIMD and atomic ones are not.
+pub fn instruction_by_name(name: &str) -> Option<Instruction> {
+	let name = RENAMED_INSTRUCTIONS.iter()
+		.find(|(printed, _)| *printed == name)
+		.map_or(name, |(_, renamed)| *renamed);
+	named_instructions().into_iter()
+		.find(|(named, _)| *named == name)
+		.map(|(_, instruction)| instruction)
+}
+
+/// Whether the instructions have the same opcode, regardless of their immediates.
+fn same_opcode(a: &Instruction, b: &Instruction) -> bool {
+	match (a, b) {
+		#[cfg(feature = "sign_ext")]
+		(Instruction::SignExt(a), Instruction::SignExt(b)) => mem::discriminant(a) == mem::discriminant(b),
+		#[cfg(feature = "simd")]
+		(Instruction::Simd(a), Instruction::Simd(b)) => mem::discriminant(a) == mem::discriminant(b),
+		#[cfg(feature = "atomics")]
+		(Instruction::Atomics(a), Instruction::Atomics(b)) => mem::discriminant(a) == mem::discriminant(b),
+		#[cfg(feature = "bulk")]
+		(Instruction::Bulk(a), Instruction::Bulk(b)) => mem::discriminant(a) == mem::discriminant(b),
+		_ => mem::discriminant(a) == mem::discriminant(b),
+	}
+}
+
+#[derive(Debug, Clone)]
 pub struct Set {
 	regular: u32,
 	entries: Map<InstructionType, Metering>,
-	grow: u32,
+	grow: GrowStrategy,
+	intrinsics: Map<String, Instruction>,
+	loop_multipliers: Map<String, u32>,
+	local_cost: u32,
+	local_byte_cost: u32,
+	max_locals: Option<u32>,
+	unknown: UnknownPolicy,
+	call_cost: u32,
+	call_indirect_cost: u32,
+	forbidden_instructions: Vec<Instruction>,
 }
 
 impl Default for Set {
@@ -307,48 +1000,558 @@ impl Default for Set {
 		Set {
 			regular: 1,
 			entries: Map::new(),
-			grow: 0,
+			grow: GrowStrategy::Flat(0),
+			intrinsics: Map::new(),
+			loop_multipliers: Map::new(),
+			local_cost: 0,
+			local_byte_cost: 0,
+			max_locals: None,
+			unknown: UnknownPolicy::Charge(1),
+			call_cost: 0,
+			call_indirect_cost: 0,
+			forbidden_instructions: Vec::new(),
 		}
 	}
 }
 
 impl Set {
+	/// Rule set charging `regular` for the classes without an entry.
 	pub fn new(regular: u32, entries: Map<InstructionType, Metering>) -> Self {
-		Set { regular, entries, grow: 0 }
+		Set { regular, entries, unknown: UnknownPolicy::Charge(regular), ..Default::default() }
 	}
 
+	/// The flat cost per page of `memory.grow`, which is zero for other strategies.
 	pub fn grow_cost(&self) -> u32 {
-		self.grow
+		match self.grow {
+			GrowStrategy::Flat(val) => val,
+			_ => 0,
+		}
+	}
+
+	pub fn with_grow_cost(self, val: u32) -> Self {
+		self.with_grow_strategy(GrowStrategy::Flat(val))
+	}
+
+	pub fn grow_strategy(&self) -> &GrowStrategy {
+		&self.grow
 	}
 
-	pub fn with_grow_cost(mut self, val: u32) -> Self {
-		self.grow = val;
+	/// Charge for `memory.grow` using `strategy`.
+	pub fn with_grow_strategy(mut self, strategy: GrowStrategy) -> Self {
+		self.grow = strategy;
 		self
 	}
 
-	pub fn with_forbidden_floats(mut self) -> Self {
-		self.entries.insert(InstructionType::Float, Metering::Forbidden);
-		self.entries.insert(InstructionType::FloatComparison, Metering::Forbidden);
-		self.entries.insert(InstructionType::FloatConst, Metering::Forbidden);
-		self.entries.insert(InstructionType::FloatConversion, Metering::Forbidden);
+	/// Charge `cost` for every instruction of the class `instruction_type`.
+	pub fn with_instruction_cost(self, instruction_type: InstructionType, cost: u32) -> Self {
+		self.with_metering(instruction_type, Metering::Fixed(cost))
+	}
+
+	/// Meter the instructions of the class `instruction_type` as specified.
+	pub fn with_metering(mut self, instruction_type: InstructionType, metering: Metering) -> Self {
+		self.entries.insert(instruction_type, metering);
 		self
 	}
+
+	/// The metering of the instructions of the class `instruction_type`.
+	pub fn metering(&self, instruction_type: InstructionType) -> Metering {
+		self.entries.get(&instruction_type).copied().unwrap_or(match self.unknown {
+			UnknownPolicy::Charge(cost) if cost == self.regular => Metering::Regular,
+			UnknownPolicy::Charge(cost) => Metering::Fixed(cost),
+			UnknownPolicy::Reject | UnknownPolicy::Forbid => Metering::Forbidden,
+		})
+	}
+
+	pub fn unknown_policy(&self) -> UnknownPolicy {
+		self.unknown
+	}
+
+	/// Handle the instructions of the classes without an entry according to `policy`.
+	///
+	/// By default they are charged `regular`. Protocols pinning their rules should list every
+	/// class and reject or forbid the others, so that classes added by an upgrade of the parser
+	/// aren't metered until they are priced deliberately.
+	pub fn with_unknown_policy(mut self, policy: UnknownPolicy) -> Self {
+		self.unknown = policy;
+		self
+	}
+
+	/// Forbid the instructions of the class `instruction_type`, so that modules using them are
+	/// rejected with `ErrorKind::ForbiddenInstruction`, e.g. `InstructionType::GrowMemory` for
+	/// modules growing the memory.
+	pub fn with_forbidden(self, instruction_type: InstructionType) -> Self {
+		self.with_metering(instruction_type, Metering::Forbidden)
+	}
+
+	/// Forbid the instructions with the opcode of `instruction`, whatever their immediates, e.g.
+	/// `Instruction::I64DivS`. `instruction_by_name` gives the instruction for a mnemonic.
+	///
+	/// Unlike the classes, this singles out instructions regardless of how their class is metered.
+	pub fn with_forbidden_instruction(mut self, instruction: Instruction) -> Self {
+		if !self.is_forbidden_instruction(&instruction) {
+			self.forbidden_instructions.push(instruction);
+		}
+		self
+	}
+
+	/// The instructions forbidden by `with_forbidden_instruction`.
+	pub fn forbidden_instructions(&self) -> &[Instruction] {
+		&self.forbidden_instructions
+	}
+
+	pub fn with_forbidden_floats(self) -> Self {
+		self.with_forbidden(InstructionType::Float)
+			.with_forbidden(InstructionType::FloatComparison)
+			.with_forbidden(InstructionType::FloatConst)
+			.with_forbidden(InstructionType::FloatConversion)
+	}
+
+	pub fn with_forbidden_simd(self) -> Self {
+		self.with_forbidden(InstructionType::Simd)
+	}
+
+	pub fn with_forbidden_atomics(self) -> Self {
+		self.with_forbidden(InstructionType::AtomicLoad)
+			.with_forbidden(InstructionType::AtomicStore)
+			.with_forbidden(InstructionType::AtomicRmw)
+			.with_forbidden(InstructionType::AtomicWait)
+	}
+
+	fn is_forbidden_instruction(&self, instruction: &Instruction) -> bool {
+		self.forbidden_instructions.iter().any(|forbidden| same_opcode(forbidden, instruction))
+	}
+
+	/// Charge calls to imported functions named `field` like `instruction`.
+	pub fn with_intrinsic(mut self, field: &str, instruction: Instruction) -> Self {
+		self.intrinsics.insert(field.into(), instruction);
+		self
+	}
+
+	/// Charge calls to the `WELL_KNOWN_INTRINSICS` like the instructions they implement.
+	pub fn with_well_known_intrinsics(self) -> Self {
+		WELL_KNOWN_INTRINSICS.iter()
+			.fold(self, |set, (field, instruction)| set.with_intrinsic(field, instruction.clone()))
+	}
+
+	/// Multiply the costs inside of loops of the functions matching `pattern` by `multiplier`.
+	///
+	/// The pattern is either a function name or a prefix followed by `*`, e.g. `"memcpy"` or
+	/// `"__mem*"`. An exact name takes precedence over prefixes, and longer prefixes over shorter.
+	pub fn with_loop_multiplier(mut self, pattern: &str, multiplier: u32) -> Self {
+		self.loop_multipliers.insert(pattern.into(), multiplier);
+		self
+	}
+
+	/// Charge `cost` for every local a function declares when it is entered.
+	pub fn with_local_cost(mut self, cost: u32) -> Self {
+		self.local_cost = cost;
+		self
+	}
+
+	/// Charge `cost` for every byte of the locals a function declares when it is entered, so that
+	/// wide locals cost more to zero than narrow ones.
+	pub fn with_local_byte_cost(mut self, cost: u32) -> Self {
+		self.local_byte_cost = cost;
+		self
+	}
+
+	/// Reject functions declaring more than `limit` locals.
+	pub fn with_max_locals(mut self, limit: u32) -> Self {
+		self.max_locals = Some(limit);
+		self
+	}
+
+	/// Charge `cost` for every `call` on top of its instruction cost.
+	pub fn with_call_cost(mut self, cost: u32) -> Self {
+		self.call_cost = cost;
+		self
+	}
+
+	/// Charge `cost` for every `call_indirect` on top of its instruction cost.
+	pub fn with_call_indirect_cost(mut self, cost: u32) -> Self {
+		self.call_indirect_cost = cost;
+		self
+	}
+
+	/// A description of the rules which is the same for all equal rule sets, e.g. to key caches of
+	/// instrumented modules.
+	pub fn fingerprint(&self) -> String {
+		let mut entries: Vec<_> = self.entries.iter().collect();
+		entries.sort_by_key(|(instruction_type, _)| **instruction_type);
+		let mut intrinsics: Vec<_> = self.intrinsics.iter().collect();
+		intrinsics.sort_by_key(|(field, _)| *field);
+		let mut loop_multipliers: Vec<_> = self.loop_multipliers.iter().collect();
+		loop_multipliers.sort();
+		let mut forbidden_instructions: Vec<_> = self.forbidden_instructions.iter()
+			.map(|instruction| format!("{:?}", instruction))
+			.collect();
+		forbidden_instructions.sort();
+		format!(
+			"{} {:?} {:?} {:?} {:?} {} {:?} {:?} {} {} {} {:?}",
+			self.regular, entries, self.grow, intrinsics, loop_multipliers, self.local_cost, self.max_locals,
+			self.unknown, self.call_cost, self.call_indirect_cost, self.local_byte_cost, forbidden_instructions,
+		)
+	}
 }
 
 impl Rules for Set {
 	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+		if self.is_forbidden_instruction(instruction) {
+			return None;
+		}
 		match self.entries.get(&InstructionType::op(instruction)) {
-			None | Some(Metering::Regular) => Some(self.regular),
+			None => match self.unknown {
+				UnknownPolicy::Charge(cost) => Some(cost),
+				UnknownPolicy::Reject | UnknownPolicy::Forbid => None,
+			},
+			Some(Metering::Regular) => Some(self.regular),
 			Some(Metering::Fixed(val)) => Some(*val),
 			Some(Metering::Forbidden) => None,
 		}
 	}
 
 	fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
-		if let Some(val) = NonZeroU32::new(self.grow) {
+		if let Some(val) = NonZeroU32::new(self.grow_cost()) {
 			Some(MemoryGrowCost::Linear(val))
 		} else {
 			None
 		}
 	}
+
+	fn grow_metering(&self) -> Box<dyn GrowMetering + '_> {
+		Box::new(self.grow.clone())
+	}
+
+	fn intrinsic(&self, _module: &str, field: &str) -> Option<Instruction> {
+		self.intrinsics.get(field).cloned()
+	}
+
+	fn loop_multiplier(&self, name: &str) -> Option<u32> {
+		if let Some(multiplier) = self.loop_multipliers.get(name) {
+			return Some(*multiplier)
+		}
+		self.loop_multipliers.iter()
+			.filter_map(|(pattern, multiplier)| {
+				let prefix = pattern.strip_suffix('*')?;
+				if name.starts_with(prefix) { Some((prefix.len(), *multiplier)) } else { None }
+			})
+			.max_by_key(|(len, _)| *len)
+			.map(|(_, multiplier)| multiplier)
+	}
+
+	fn local_cost(&self) -> u32 {
+		self.local_cost
+	}
+
+	fn local_byte_cost(&self) -> u32 {
+		self.local_byte_cost
+	}
+
+	fn max_locals(&self) -> Option<u32> {
+		self.max_locals
+	}
+
+	fn call_cost(&self) -> u32 {
+		self.call_cost
+	}
+
+	fn call_indirect_cost(&self) -> u32 {
+		self.call_indirect_cost
+	}
+
+	fn is_known(&self, instruction: &Instruction) -> bool {
+		self.entries.contains_key(&InstructionType::op(instruction)) || self.is_forbidden_instruction(instruction)
+	}
+
+	fn unknown_policy(&self) -> UnknownPolicy {
+		self.unknown
+	}
+}
+
+#[cfg(feature = "serde")]
+mod de {
+	use super::{instruction_by_name, GrowStrategy, InstructionType, Map, Metering, Set, UnknownPolicy};
+	use crate::std::string::String;
+	use crate::std::vec::Vec;
+	use serde::de::{Deserialize, Deserializer, Error};
+
+	#[derive(serde::Deserialize)]
+	#[serde(untagged)]
+	enum MeteringSpec {
+		Fixed(u32),
+		Named(String),
+	}
+
+	#[derive(serde::Deserialize)]
+	#[serde(untagged)]
+	enum GrowSpec {
+		Flat(u32),
+		Named(String),
+		Tiered { tiers: Vec<(u32, u32)> },
+		Stepped { steps: Vec<(u32, u32)> },
+	}
+
+	#[derive(serde::Deserialize)]
+	#[serde(untagged)]
+	enum UnknownSpec {
+		Named(String),
+		Charge { charge: u32 },
+	}
+
+	impl Default for GrowSpec {
+		fn default() -> Self {
+			GrowSpec::Flat(0)
+		}
+	}
+
+	#[derive(serde::Deserialize)]
+	#[serde(deny_unknown_fields)]
+	struct SetSpec {
+		#[serde(default = "default_regular")]
+		regular: u32,
+		#[serde(default)]
+		grow: GrowSpec,
+		#[serde(default)]
+		well_known_intrinsics: bool,
+		#[serde(default)]
+		instructions: Map<String, MeteringSpec>,
+		#[serde(default)]
+		loop_multipliers: Map<String, u32>,
+		#[serde(default)]
+		local: u32,
+		#[serde(default)]
+		local_byte: u32,
+		#[serde(default)]
+		max_locals: Option<u32>,
+		#[serde(default)]
+		unknown: Option<UnknownSpec>,
+		#[serde(default)]
+		call: u32,
+		#[serde(default)]
+		call_indirect: u32,
+		#[serde(default)]
+		forbidden_instructions: Vec<String>,
+	}
+
+	fn default_regular() -> u32 {
+		1
+	}
+
+	/// A rule set is described by the `regular` cost, the `grow` strategy, whether to charge
+	/// `well_known_intrinsics`, a table of `instructions` classes, named as accepted by
+	/// `InstructionType::from_str`, each with a fixed cost, `"regular"` or `"forbidden"`, a table of
+	/// `loop_multipliers` by function name pattern, the cost per declared `local` and per byte of
+	/// declared locals, `local_byte`, the `max_locals` of a function, the fees of every `call` and
+	/// `call_indirect`, the mnemonics of the `forbidden_instructions` and the `unknown` policy for the
+	/// classes not in `instructions`. The `grow` strategy is a cost per page, `"host"`, `"forbidden"`,
+	/// a table with the `tiers` as `[pages, price]` pairs or a table with the `steps` as
+	/// `[pages, fee]` pairs. The `unknown` policy is `"reject"`, `"forbid"` or a table with the cost
+	/// to `charge`, and charges `regular` if left out.
+	impl<'de> Deserialize<'de> for Set {
+		fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+			let spec = SetSpec::deserialize(deserializer)?;
+
+			let mut entries = Map::new();
+			for (name, metering) in spec.instructions {
+				let instruction_type: InstructionType = name.parse()
+					.map_err(|_| D::Error::custom(format_args!("unknown instruction class '{}'", name)))?;
+				let metering = match metering {
+					MeteringSpec::Fixed(cost) => Metering::Fixed(cost),
+					MeteringSpec::Named(ref named) if named == "regular" => Metering::Regular,
+					MeteringSpec::Named(ref named) if named == "forbidden" => Metering::Forbidden,
+					MeteringSpec::Named(named) => return Err(D::Error::custom(
+						format_args!("invalid metering '{}' for '{}'", named, name)
+					)),
+				};
+				entries.insert(instruction_type, metering);
+			}
+
+			let grow = match spec.grow {
+				GrowSpec::Flat(cost) => GrowStrategy::Flat(cost),
+				GrowSpec::Named(ref named) if named == "host" => GrowStrategy::HostDelegated,
+				GrowSpec::Named(ref named) if named == "forbidden" => GrowStrategy::Forbidden,
+				GrowSpec::Named(named) => return Err(D::Error::custom(
+					format_args!("invalid grow strategy '{}'", named)
+				)),
+				GrowSpec::Tiered { tiers } => GrowStrategy::Tiered(tiers),
+				GrowSpec::Stepped { steps } => GrowStrategy::Stepped(steps),
+			};
+
+			let mut set = Set::new(spec.regular, entries).with_grow_strategy(grow);
+			set.loop_multipliers = spec.loop_multipliers;
+			set.local_cost = spec.local;
+			set.local_byte_cost = spec.local_byte;
+			set.max_locals = spec.max_locals;
+			set.call_cost = spec.call;
+			set.call_indirect_cost = spec.call_indirect;
+			for name in spec.forbidden_instructions {
+				let instruction = instruction_by_name(&name)
+					.ok_or_else(|| D::Error::custom(format_args!("unknown instruction '{}'", name)))?;
+				set = set.with_forbidden_instruction(instruction);
+			}
+			set.unknown = match spec.unknown {
+				None => set.unknown,
+				Some(UnknownSpec::Charge { charge }) => UnknownPolicy::Charge(charge),
+				Some(UnknownSpec::Named(ref named)) if named == "reject" => UnknownPolicy::Reject,
+				Some(UnknownSpec::Named(ref named)) if named == "forbid" => UnknownPolicy::Forbid,
+				Some(UnknownSpec::Named(named)) => return Err(D::Error::custom(
+					format_args!("invalid policy for unknown instructions '{}'", named)
+				)),
+			};
+			Ok(if spec.well_known_intrinsics { set.with_well_known_intrinsics() } else { set })
+		}
+	}
+}
+
+/// Error of loading a rule set from a file.
+#[cfg(feature = "rules-file")]
+#[derive(Debug)]
+pub enum LoadError {
+	Io(std::io::Error),
+	Toml(toml::de::Error),
+	Json(serde_json::Error),
+}
+
+#[cfg(feature = "rules-file")]
+impl crate::std::fmt::Display for LoadError {
+	fn fmt(&self, f: &mut crate::std::fmt::Formatter) -> crate::std::fmt::Result {
+		match self {
+			LoadError::Io(e) => write!(f, "{}", e),
+			LoadError::Toml(e) => write!(f, "{}", e),
+			LoadError::Json(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+#[cfg(feature = "rules-file")]
+impl std::error::Error for LoadError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			LoadError::Io(e) => Some(e),
+			LoadError::Toml(e) => Some(e),
+			LoadError::Json(e) => Some(e),
+		}
+	}
+}
+
+#[cfg(feature = "rules-file")]
+impl Set {
+	/// Parse a rule set from TOML.
+	///
+	/// ```toml
+	/// regular = 1
+	/// grow = 8192
+	/// well_known_intrinsics = true
+	/// local = 1
+	/// max_locals = 1024
+	///
+	/// [instructions]
+	/// div = 16
+	/// float = "forbidden"
+	///
+	/// [loop_multipliers]
+	/// memcpy = 4
+	/// "memset*" = 4
+	/// ```
+	pub fn from_toml(source: &str) -> Result<Self, LoadError> {
+		toml::from_str(source).map_err(LoadError::Toml)
+	}
+
+	/// Parse a rule set from JSON, with the same fields as accepted by `from_toml`.
+	pub fn from_json(source: &str) -> Result<Self, LoadError> {
+		serde_json::from_str(source).map_err(LoadError::Json)
+	}
+
+	/// Load a rule set from the file at `path`. Files with the `json` extension are parsed as
+	/// JSON, all others as TOML.
+	pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, LoadError> {
+		let path = path.as_ref();
+		let source = std::fs::read_to_string(path).map_err(LoadError::Io)?;
+		match path.extension() {
+			Some(extension) if extension == "json" => Self::from_json(&source),
+			_ => Self::from_toml(&source),
+		}
+	}
+}
+
+#[cfg(all(test, feature = "rules-file"))]
+mod tests {
+	use super::*;
+	use crate::std::string::ToString;
+
+	#[test]
+	fn loads_toml_and_json() {
+		let toml = Set::from_toml(r#"
+			regular = 2
+			grow = 100
+			local = 3
+			max_locals = 1024
+
+			[instructions]
+			div = 16
+			float = "forbidden"
+		"#).unwrap();
+		let json = Set::from_json(r#"{
+			"regular": 2,
+			"grow": 100,
+			"local": 3,
+			"max_locals": 1024,
+			"instructions": { "div": 16, "float": "forbidden" }
+		}"#).unwrap();
+
+		for rules in &[toml, json] {
+			assert_eq!(rules.instruction_cost(&Instruction::I32DivU), Some(16));
+			assert_eq!(rules.instruction_cost(&Instruction::F32Add), None);
+			assert_eq!(rules.instruction_cost(&Instruction::GetLocal(0)), Some(2));
+			assert_eq!(rules.grow_cost(), 100);
+			assert_eq!(rules.local_cost(), 3);
+			assert_eq!(rules.max_locals(), Some(1024));
+			assert_eq!(rules.unknown_policy(), UnknownPolicy::Charge(2));
+		}
+		assert_eq!(Set::from_toml("local_byte = 2").unwrap().local_byte_cost(), 2);
+		let denied = Set::from_toml("forbidden_instructions = [\"i64.div_s\"]").unwrap();
+		assert_eq!(denied.instruction_cost(&Instruction::I64DivS), None);
+		assert_eq!(denied.instruction_cost(&Instruction::I64DivU), Some(1));
+		let renamed = Set::from_toml("forbidden_instructions = [\"grow_memory\", \"memory.grow\"]").unwrap();
+		assert_eq!(renamed.forbidden_instructions(), &[Instruction::GrowMemory(0)]);
+		assert!(Set::from_toml("forbidden_instructions = [\"i64.div\"]").is_err());
+		let calls = Set::from_toml("call = 5\ncall_indirect = 8").unwrap();
+		assert_eq!((calls.call_cost(), calls.call_indirect_cost()), (5, 8));
+
+		let tiered = Set::from_toml("grow = { tiers = [[16, 10], [64, 100]] }").unwrap();
+		assert_eq!(tiered.grow_strategy(), &GrowStrategy::Tiered(vec![(16, 10), (64, 100)]));
+		let stepped = Set::from_toml("grow = { steps = [[64, 1000]] }").unwrap();
+		assert_eq!(stepped.grow_strategy(), &GrowStrategy::Stepped(vec![(64, 1000)]));
+		let forbidden = Set::from_json(r#"{ "grow": "forbidden" }"#).unwrap();
+		assert_eq!(forbidden.grow_strategy(), &GrowStrategy::Forbidden);
+
+		assert!(Set::from_toml("[instructions]\nfoo = 1").is_err());
+		assert!(Set::from_toml("grow = \"free\"").is_err());
+		let error = Set::from_json("{").unwrap_err();
+		assert_eq!(std::error::Error::source(&error).unwrap().to_string(), error.to_string());
+
+		let charged = Set::from_toml("unknown = { charge = 7 }").unwrap();
+		assert_eq!(charged.instruction_cost(&Instruction::I32Add), Some(7));
+		assert_eq!(Set::from_json(r#"{ "unknown": "forbid" }"#).unwrap().unknown_policy(), UnknownPolicy::Forbid);
+		let rejecting = Set::from_toml("unknown = \"reject\"\n[instructions]\nlocal = 1").unwrap();
+		assert_eq!(rejecting.instruction_cost(&Instruction::GetLocal(0)), Some(1));
+		assert_eq!(rejecting.instruction_cost(&Instruction::I32Add), None);
+		assert!(Set::from_toml("unknown = \"free\"").is_err());
+		assert!(Set::from_json(r#"{ "instructions": { "float": "free" } }"#).is_err());
+	}
+	#[test]
+	fn instruction_names() {
+		// The names parity-wasm prints resolve to the instructions they are printed for.
+		for (name, instruction) in named_instructions() {
+			let printed = instruction.to_string();
+			let printed = printed.split_whitespace().next().unwrap();
+			let resolved = instruction_by_name(printed).unwrap_or_else(|| panic!("'{}' is not resolved", printed));
+			assert!(same_opcode(&resolved, &instruction), "'{}' is not '{}'", printed, name);
+		}
+		assert!(same_opcode(&instruction_by_name("br_table").unwrap(), &Instruction::BrTable(Box::new(
+			parity_wasm::elements::BrTableData { table: Box::new([1, 2]), default: 0 },
+		))));
+		assert_eq!(instruction_by_name("get_local"), Some(Instruction::GetLocal(0)));
+		assert_eq!(instruction_by_name("local"), None);
+	}
 }
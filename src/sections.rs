@@ -0,0 +1,187 @@
+//! Appending entries to the sections of a module in place.
+//!
+//! Unlike with `builder::from_module`, the sections are neither rebuilt nor cloned and the other
+//! sections stay in place: the builder moves custom sections and the data count section behind
+//! the data section, where the latter is invalid. A missing section is inserted at its position.
+
+use parity_wasm::elements;
+
+/// Index of the type entry `signature`, which is added to the type section unless the module has an
+/// identical one already.
+pub(crate) fn resolve_type(module: &mut elements::Module, signature: elements::FunctionType) -> u32 {
+	let existing = module.type_section()
+		.map_or(&[][..], |type_section| type_section.types())
+		.iter()
+		.position(|elements::Type::Function(ty)| *ty == signature);
+	if let Some(index) = existing {
+		return index as u32;
+	}
+	match module.type_section_mut() {
+		Some(type_section) => {
+			type_section.types_mut().push(elements::Type::Function(signature));
+			type_section.types().len() as u32 - 1
+		}
+		None => {
+			module.insert_section(elements::Section::Type(elements::TypeSection::with_types(vec![
+				elements::Type::Function(signature),
+			])))
+				.expect("there is no type section yet; qed");
+			0
+		}
+	}
+}
+
+/// Add the `global` to the module, returning its index in the global index space.
+pub(crate) fn push_global(module: &mut elements::Module, global: elements::GlobalEntry) -> u32 {
+	// Defined globals come after the imported ones, so the new global is the last one.
+	let index = module.globals_space() as u32;
+	match module.global_section_mut() {
+		Some(global_section) => global_section.entries_mut().push(global),
+		None => module.insert_section(elements::Section::Global(elements::GlobalSection::with_entries(vec![global])))
+			.expect("there is no global section yet; qed"),
+	}
+	index
+}
+
+/// Add the `export` to the module.
+pub(crate) fn push_export(module: &mut elements::Module, export: elements::ExportEntry) {
+	match module.export_section_mut() {
+		Some(export_section) => export_section.entries_mut().push(export),
+		None => module.insert_section(elements::Section::Export(elements::ExportSection::with_entries(vec![export])))
+			.expect("there is no export section yet; qed"),
+	}
+}
+
+/// Add a function of the type `signature` with the `body` to the module, returning its index in
+/// the function index space.
+pub(crate) fn push_function(
+	module: &mut elements::Module,
+	signature: elements::FunctionType,
+	body: elements::FuncBody,
+) -> u32 {
+	let type_ref = resolve_type(module, signature);
+	// Defined functions come after the imported ones, so the new function is the last one.
+	let index = module.functions_space() as u32;
+	match module.function_section_mut() {
+		Some(function_section) => function_section.entries_mut().push(elements::Func::new(type_ref)),
+		None => module.insert_section(elements::Section::Function(elements::FunctionSection::with_entries(vec![
+			elements::Func::new(type_ref),
+		])))
+			.expect("there is no function section yet; qed"),
+	}
+	match module.code_section_mut() {
+		Some(code_section) => code_section.bodies_mut().push(body),
+		None => module.insert_section(elements::Section::Code(elements::CodeSection::with_bodies(vec![body])))
+			.expect("there is no code section yet; qed"),
+	}
+	index
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::validate_module;
+	use parity_wasm::builder;
+	use parity_wasm::elements::Instruction::*;
+	use parity_wasm::elements::ValueType::*;
+
+	fn is_custom(section: &elements::Section) -> bool {
+		matches!(section, elements::Section::Custom(_) | elements::Section::Name(_))
+	}
+
+	#[test]
+	fn same_as_rebuilding() {
+		let bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(r#"
+				(module
+					(import "env" "ext" (func $ext (param i32)))
+					(import "env" "base" (global i32))
+					(table 2 funcref)
+					(elem (i32.const 0) $ext $f)
+					(memory 1)
+					(func $f (param i32)
+						get_local 0
+						call $ext)
+					(func $main
+						get_global 0
+						call $f)
+					(start $main)
+					(data (i32.const 0) "x"))
+			"#)
+			.expect("failed to parse module");
+		let mut module = elements::deserialize_buffer::<elements::Module>(bytes.as_ref())
+			.unwrap()
+			.parse_names()
+			.expect("names to be parsed");
+		module.sections_mut().insert(0, elements::Section::Custom(elements::CustomSection::new("meta".into(), vec![1])));
+
+		let global = || elements::GlobalEntry::new(
+			elements::GlobalType::new(I64, true),
+			elements::InitExpr::new(vec![I64Const(0), End]),
+		);
+		let export = |index| elements::ExportEntry::new("gas_left".into(), elements::Internal::Global(index));
+		let body = |instructions| elements::FuncBody::new(
+			vec![elements::Local::new(1, I64)],
+			elements::Instructions::new(instructions),
+		);
+
+		// In place, with a new and an existing signature.
+		let mut in_place = module.clone();
+		let in_place_global = push_global(&mut in_place, global());
+		push_export(&mut in_place, export(in_place_global));
+		let in_place_funcs = [
+			push_function(&mut in_place, elements::FunctionType::new(vec![I32], vec![I32]), body(vec![GetLocal(0), End])),
+			push_function(&mut in_place, elements::FunctionType::new(vec![I32], Vec::new()), body(vec![End])),
+		];
+
+		// As the passes did before, through the builder; its indices exclude the imported entries.
+		let mut b = builder::from_module(module.clone());
+		let rebuilt_global = b.push_global(global()) + module.import_count(elements::ImportCountType::Global) as u32;
+		b.push_export(export(rebuilt_global));
+		let imported_funcs = module.import_count(elements::ImportCountType::Function) as u32;
+		let rebuilt_funcs = [
+			b.push_function(
+				builder::function()
+					.signature().with_param(I32).with_result(I32).build()
+					.with_body(body(vec![GetLocal(0), End]))
+					.build()
+			).body + imported_funcs,
+			b.push_function(
+				builder::function()
+					.signature().with_param(I32).build()
+					.with_body(body(vec![End]))
+					.build()
+			).body + imported_funcs,
+		];
+		let rebuilt = b.build();
+
+		assert_eq!(in_place_global, rebuilt_global);
+		assert_eq!(in_place_funcs, rebuilt_funcs);
+		assert_eq!(in_place_funcs, [3, 4]);
+		assert_eq!(in_place.type_section().unwrap().types().len(), 3);
+
+		// The same sections, except that the builder moves and reorders the custom ones.
+		let standard = |module: &elements::Module| module.sections()
+			.iter()
+			.filter(|section| !is_custom(section))
+			.cloned()
+			.collect::<Vec<_>>();
+		let custom = |module: &elements::Module| module.sections()
+			.iter()
+			.filter(|section| is_custom(section))
+			.cloned()
+			.collect::<Vec<_>>();
+		assert_eq!(standard(&in_place), standard(&rebuilt));
+		assert_eq!(custom(&in_place).len(), custom(&rebuilt).len());
+		assert!(custom(&in_place).iter().all(|section| custom(&rebuilt).contains(section)));
+		assert!(is_custom(&in_place.sections()[0]));
+
+		// Nothing is inserted in front of existing entries, so no index refers elsewhere.
+		assert_eq!(in_place.start_section(), module.start_section());
+		assert_eq!(in_place.elements_section(), module.elements_section());
+		assert_eq!(in_place.names_section(), module.names_section());
+
+		validate_module(in_place);
+	}
+}
@@ -0,0 +1,380 @@
+//! Smoke test of the gas metering of a module, for deploy tools to run on every instrumented
+//! module before deploying it.
+//!
+//! `harness` builds a module importing every exported function of the instrumented module from
+//! `CONTRACT_MODULE` and exporting a function without parameters and results under the same name,
+//! which calls it with zeros as arguments and drops its results. `self_test` runs the harness
+//! against the instrumented module on wasmi, behind the `self-test` feature: the gas function
+//! counts its calls, other imported functions return zeros and imported memories, tables and
+//! globals are created empty. Every export has to call the gas function at least once, as it is
+//! charged on entry, as are all functions it reaches.
+
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::builder;
+use parity_wasm::elements::{self, Instruction, Internal, Type, ValueType};
+
+/// Module name under which the harness imports the exports of the instrumented module.
+pub const CONTRACT_MODULE: &str = "contract";
+
+/// The constant zero of the type.
+fn zero(value_type: ValueType) -> Instruction {
+	match value_type {
+		ValueType::I32 => Instruction::I32Const(0),
+		ValueType::I64 => Instruction::I64Const(0),
+		ValueType::F32 => Instruction::F32Const(0),
+		ValueType::F64 => Instruction::F64Const(0),
+		#[cfg(feature = "simd")]
+		ValueType::V128 => Instruction::Simd(elements::SimdInstruction::V128Const(Default::default())),
+	}
+}
+
+/// The name and type of every exported function of the `module`.
+fn exported_functions(module: &elements::Module) -> Vec<(String, elements::FunctionType)> {
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let type_refs = module.import_section()
+		.map_or(&[][..], |import_section| import_section.entries())
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Function(type_ref) => Some(*type_ref),
+			_ => None,
+		})
+		.chain(
+			module.function_section()
+				.map_or(&[][..], |function_section| function_section.entries())
+				.iter()
+				.map(|func| func.type_ref())
+		)
+		.collect::<Vec<_>>();
+
+	module.export_section()
+		.map_or(&[][..], |export_section| export_section.entries())
+		.iter()
+		.filter_map(|entry| match *entry.internal() {
+			Internal::Function(function) => {
+				let type_ref = type_refs.get(function as usize)?;
+				let Type::Function(func_type) = types.get(*type_ref as usize)?;
+				Some((entry.field().into(), func_type.clone()))
+			}
+			_ => None,
+		})
+		.collect()
+}
+
+/// Build the harness calling every exported function of the instrumented `module`, see the
+/// module-level documentation.
+pub fn harness(module: &elements::Module) -> elements::Module {
+	let exports = exported_functions(module);
+	let mut harness = builder::module();
+	for (name, func_type) in &exports {
+		let signature = harness.push_signature(
+			builder::signature()
+				.with_params(func_type.params().to_vec())
+				.with_results(func_type.results().to_vec())
+				.build_sig()
+		);
+		harness.push_import(
+			builder::import().module(CONTRACT_MODULE).field(name).external().func(signature).build()
+		);
+	}
+	for (index, (name, func_type)) in exports.iter().enumerate() {
+		let mut instructions = func_type.params().iter().map(|param| zero(*param)).collect::<Vec<_>>();
+		instructions.push(Instruction::Call(index as u32));
+		instructions.extend(func_type.results().iter().map(|_| Instruction::Drop));
+		instructions.push(Instruction::End);
+		let location = harness.push_function(
+			builder::function()
+				.signature().build()
+				.body().with_instructions(elements::Instructions::new(instructions)).build()
+				.build()
+		);
+		harness.push_export(
+			builder::export()
+				.field(name)
+				.internal().func(exports.len() as u32 + location.body)
+				.build()
+		);
+	}
+	harness.build()
+}
+
+#[cfg(feature = "self-test")]
+pub use self::runner::{self_test, Error, ExportRun, SelfTest};
+
+#[cfg(feature = "self-test")]
+mod runner {
+	use std::fmt;
+
+	use parity_wasm::elements::{self, External, Type, ValueType};
+	use wasmi::core::ValueType as WasmiType;
+	use wasmi::{Caller, Engine, Func, FuncType, Global, Linker, Memory, MemoryType, Module, Mutability, Store, Table, TableType, Value};
+
+	use super::{exported_functions, harness, CONTRACT_MODULE};
+	use crate::gas::GasConfig;
+
+	/// Error of `self_test`.
+	#[derive(Debug)]
+	pub enum Error {
+		/// The module does not import the gas function, so it is not metered.
+		NoGasImport,
+		/// The instrumented module or the harness can't be encoded.
+		Encoding(elements::Error),
+		/// wasmi rejects or can't instantiate the instrumented module or the harness.
+		Engine(String),
+	}
+
+	impl fmt::Display for Error {
+		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+			match self {
+				Error::NoGasImport => write!(f, "Module does not import the gas function"),
+				Error::Encoding(e) => write!(f, "Encoding failed: {}", e),
+				Error::Engine(e) => write!(f, "wasmi failed: {}", e),
+			}
+		}
+	}
+
+	/// The call of an export by the harness.
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub struct ExportRun {
+		pub name: String,
+		/// Number of calls of the gas function.
+		pub charges: u64,
+		/// Sum of the amounts charged.
+		pub gas: u64,
+		/// The trap ending the call, if it trapped, e.g. as the zero arguments are out of bounds.
+		pub trap: Option<String>,
+	}
+
+	/// Outcome of `self_test`.
+	#[derive(Debug, Clone, Default, PartialEq, Eq)]
+	pub struct SelfTest {
+		/// The calls of all exported functions, in the order of the export section.
+		pub exports: Vec<ExportRun>,
+	}
+
+	impl SelfTest {
+		/// Names of the exports whose calls didn't charge any gas.
+		pub fn uncharged(&self) -> Vec<&str> {
+			self.exports.iter().filter(|run| run.charges == 0).map(|run| run.name.as_str()).collect()
+		}
+
+		/// Whether all calls charged gas.
+		pub fn passed(&self) -> bool {
+			self.uncharged().is_empty()
+		}
+	}
+
+	/// Gas charged since the last export was called.
+	#[derive(Default)]
+	struct Charges {
+		charges: u64,
+		gas: u64,
+	}
+
+	/// The type of the value in wasmi, which doesn't support `v128`.
+	fn wasmi_type(value_type: ValueType) -> Result<WasmiType, Error> {
+		Ok(match value_type {
+			ValueType::I32 => WasmiType::I32,
+			ValueType::I64 => WasmiType::I64,
+			ValueType::F32 => WasmiType::F32,
+			ValueType::F64 => WasmiType::F64,
+			#[cfg(feature = "simd")]
+			ValueType::V128 => return Err(Error::Engine("v128 values are not supported by wasmi".into())),
+		})
+	}
+
+	fn wasmi_types(value_types: &[ValueType]) -> Result<Vec<WasmiType>, Error> {
+		value_types.iter().map(|value_type| wasmi_type(*value_type)).collect()
+	}
+
+	fn func_type(func_type: &elements::FunctionType) -> Result<FuncType, Error> {
+		Ok(FuncType::new(wasmi_types(func_type.params())?, wasmi_types(func_type.results())?))
+	}
+
+	/// Run the harness of the `module`, instrumented with the gas function given by `config`,
+	/// on wasmi and record the gas charged by each call, see the module-level documentation.
+	///
+	/// wasmi doesn't support SIMD, so modules with `v128` values in the signatures of their
+	/// exports or in their imports fail with `Error::Engine`.
+	pub fn self_test(module: &elements::Module, config: &GasConfig) -> Result<SelfTest, Error> {
+		let engine_error = |e: &dyn fmt::Display| Error::Engine(e.to_string());
+		let amount_type = if config.i64_amounts { ValueType::I64 } else { ValueType::I32 };
+		let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+		let imports = module.import_section().map_or(&[][..], |import_section| import_section.entries());
+		let is_gas_func = |entry: &elements::ImportEntry| match *entry.external() {
+			External::Function(type_ref) => entry.module() == config.module
+				&& entry.field() == config.field
+				&& matches!(types.get(type_ref as usize), Some(Type::Function(ty)) if ty.params() == [amount_type]),
+			_ => false,
+		};
+		if !imports.iter().any(is_gas_func) {
+			return Err(Error::NoGasImport);
+		}
+		let exports = exported_functions(module);
+		for (_, ty) in &exports {
+			func_type(ty)?;
+		}
+
+		let engine = Engine::default();
+		let mut store = Store::new(&engine, Charges::default());
+		let mut linker = Linker::<Charges>::new(&engine);
+		for entry in imports {
+			let (module_name, field) = (entry.module(), entry.field());
+			let defined = match *entry.external() {
+				External::Function(type_ref) if is_gas_func(entry) => {
+					let Type::Function(ty) = &types[type_ref as usize];
+					let func = Func::new(&mut store, func_type(ty)?, |mut caller: Caller<'_, Charges>, params, _| {
+						let amount = match params[0] {
+							Value::I32(amount) => amount as u32 as u64,
+							Value::I64(amount) => amount as u64,
+							_ => 0,
+						};
+						let charges = caller.data_mut();
+						charges.charges += 1;
+						charges.gas = charges.gas.saturating_add(amount);
+						Ok(())
+					});
+					linker.define(module_name, field, func).map(|_| ())
+				}
+				External::Function(type_ref) => {
+					let Type::Function(ty) = &types[type_ref as usize];
+					let results = wasmi_types(ty.results())?.into_iter().map(Value::default).collect::<Vec<_>>();
+					let func = Func::new(&mut store, func_type(ty)?, move |_, _, out| {
+						out.clone_from_slice(&results);
+						Ok(())
+					});
+					linker.define(module_name, field, func).map(|_| ())
+				}
+				External::Memory(memory_type) => {
+					let limits = memory_type.limits();
+					let ty = MemoryType::new(limits.initial(), limits.maximum()).map_err(|e| engine_error(&e))?;
+					let memory = Memory::new(&mut store, ty).map_err(|e| engine_error(&e))?;
+					linker.define(module_name, field, memory).map(|_| ())
+				}
+				External::Table(table_type) => {
+					let limits = table_type.limits();
+					let ty = TableType::new(WasmiType::FuncRef, limits.initial(), limits.maximum());
+					let table = Table::new(&mut store, ty, Value::default(WasmiType::FuncRef)).map_err(|e| engine_error(&e))?;
+					linker.define(module_name, field, table).map(|_| ())
+				}
+				External::Global(global_type) => {
+					let mutability = if global_type.is_mutable() { Mutability::Var } else { Mutability::Const };
+					let global = Global::new(&mut store, Value::default(wasmi_type(global_type.content_type())?), mutability);
+					linker.define(module_name, field, global).map(|_| ())
+				}
+			};
+			defined.map_err(|e| engine_error(&e))?;
+		}
+
+		let binary = elements::serialize(module.clone()).map_err(Error::Encoding)?;
+		let contract = Module::new(&engine, &binary[..]).map_err(|e| engine_error(&e))?;
+		let contract = linker.instantiate(&mut store, &contract)
+			.and_then(|instance| instance.start(&mut store))
+			.map_err(|e| engine_error(&e))?;
+
+		let mut harness_linker = Linker::<Charges>::new(&engine);
+		for (name, _) in &exports {
+			let func = contract.get_func(&store, name).expect("the export is a function of the module; qed");
+			harness_linker.define(CONTRACT_MODULE, name, func).map_err(|e| engine_error(&e))?;
+		}
+		let binary = elements::serialize(harness(module)).map_err(Error::Encoding)?;
+		let harness = Module::new(&engine, &binary[..]).map_err(|e| engine_error(&e))?;
+		let harness = harness_linker.instantiate(&mut store, &harness)
+			.and_then(|instance| instance.start(&mut store))
+			.map_err(|e| engine_error(&e))?;
+
+		let mut report = SelfTest::default();
+		for (name, _) in exports {
+			*store.data_mut() = Charges::default();
+			let func = harness.get_func(&store, &name).expect("the harness exports every function; qed");
+			let trap = func.call(&mut store, &[], &mut []).err().map(|e| e.to_string());
+			let Charges { charges, gas } = *store.data();
+			report.exports.push(ExportRun { name, charges, gas, trap });
+		}
+		Ok(report)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	const CONTRACT: &str = r#"
+		(module
+			(import "env" "ext" (func $ext (param i32) (result i64)))
+			(import "env" "memory" (memory 1))
+			(func (export "call") (param i32 f64) (result i64)
+				get_local 0
+				call $ext)
+			(func (export "load") (result i32)
+				i32.const 0
+				i32.load)
+			(func (export "fail")
+				unreachable))
+	"#;
+
+	#[test]
+	fn builds_harness() {
+		let module = parse_wat(CONTRACT);
+
+		let harness = harness(&module);
+
+		assert_eq!(harness, parse_wat(r#"
+			(module
+				(import "contract" "call" (func (param i32 f64) (result i64)))
+				(import "contract" "load" (func (result i32)))
+				(import "contract" "fail" (func))
+				(func (export "call")
+					i32.const 0
+					f64.const 0
+					call 0
+					drop)
+				(func (export "load")
+					call 1
+					drop)
+				(func (export "fail")
+					call 2))
+		"#));
+	}
+
+	#[cfg(feature = "self-test")]
+	#[test]
+	fn runs_harness() {
+		let module = parse_wat(CONTRACT);
+		let rules = crate::rules::Set::default();
+		let config = crate::gas::GasConfig::new("env", "gas");
+		assert!(matches!(self_test(&module, &config), Err(Error::NoGasImport)));
+
+		let exempt = ["load"];
+		let metered = crate::gas::inject_gas_counter(module, &rules, config.with_exempt_exports(&exempt))
+			.unwrap();
+
+		let report = self_test(&metered, &config).unwrap();
+
+		assert_eq!(
+			report.exports.iter().map(|run| (run.name.as_str(), run.charges, run.gas, run.trap.is_some())).collect::<Vec<_>>(),
+			vec![("call", 1, 2, false), ("load", 0, 0, false), ("fail", 1, 1, true)]
+		);
+		assert_eq!(report.uncharged(), vec!["load"]);
+		assert!(!report.passed());
+	}
+
+	#[cfg(all(feature = "self-test", feature = "simd"))]
+	#[test]
+	fn rejects_simd_exports() {
+		let module = parse_wat(r#"
+			(module
+				(func (export "id") (param v128) (result v128)
+					get_local 0))
+		"#);
+		let config = crate::gas::GasConfig::new("env", "gas");
+		let metered = crate::gas::inject_gas_counter(module, &crate::rules::Set::default(), config).unwrap();
+
+		match self_test(&metered, &config) {
+			Err(Error::Engine(e)) => assert_eq!(e, "v128 values are not supported by wasmi"),
+			result => panic!("unexpected result: {:?}", result),
+		}
+	}
+}
@@ -0,0 +1,76 @@
+//! Translation of instruction positions between original and instrumented function bodies.
+//!
+//! The gas metering and the stack height limiter insert instructions into the function bodies,
+//! so the position of a trap reported by a runtime refers to the instrumented code. A `SourceMap`
+//! returned by the instrumentation translates it back to the original code, e.g. for error
+//! messages and coverage tools.
+
+use crate::std::vec::Vec;
+
+/// Positions of the instructions of the original function bodies in the instrumented ones.
+///
+/// Positions are indices into the instructions of a body, bodies are indexed by their position in
+/// the code section of the original module. Functions added by the instrumentation, e.g. thunks,
+/// follow the original ones and are not covered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+	/// For every function body, the instrumented position of each original instruction, in
+	/// increasing order.
+	pub functions: Vec<Vec<usize>>,
+}
+
+impl SourceMap {
+	/// The position in the instrumented body `body` of the original instruction at `pos`.
+	///
+	/// An original instruction removed by the instrumentation, e.g. a folded charge, is mapped to
+	/// the position of the instruction following it.
+	pub fn translate(&self, body: usize, pos: usize) -> Option<usize> {
+		self.functions.get(body)?.get(pos).copied()
+	}
+
+	/// The position in the original body `body` of the instrumented instruction at `pos`.
+	///
+	/// Inserted instructions are attributed to the original instruction following them, which they
+	/// instrument: a gas charge to the first instruction of the metered block, the stack height
+	/// check to the call. Only the stack height decrement after a call follows it, it can't trap.
+	pub fn original(&self, body: usize, pos: usize) -> Option<usize> {
+		let positions = self.functions.get(body)?;
+		let original = positions.partition_point(|translated| *translated < pos);
+		if original < positions.len() { Some(original) } else { None }
+	}
+
+	/// The map from the original bodies to the bodies instrumented further as described by `next`.
+	pub fn then(&self, next: &SourceMap) -> SourceMap {
+		SourceMap {
+			functions: self.functions.iter()
+				.enumerate()
+				.map(|(body, positions)| positions.iter()
+					.map(|pos| next.translate(body, *pos).unwrap_or(*pos))
+					.collect())
+				.collect(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn translates_positions() {
+		let gas = SourceMap { functions: vec![vec![2, 3, 6, 7]] };
+		let stack = SourceMap { functions: vec![vec![0, 1, 2, 3, 4, 5, 18, 23]] };
+
+		assert_eq!(gas.translate(0, 2), Some(6));
+		assert_eq!(gas.translate(0, 4), None);
+		assert_eq!(gas.translate(1, 0), None);
+		assert_eq!(gas.original(0, 0), Some(0));
+		assert_eq!(gas.original(0, 5), Some(2));
+		assert_eq!(gas.original(0, 7), Some(3));
+		assert_eq!(gas.original(0, 8), None);
+
+		let both = gas.then(&stack);
+		assert_eq!(both.functions, vec![vec![2, 3, 18, 23]]);
+		assert_eq!(both.original(0, 10), Some(2));
+	}
+}
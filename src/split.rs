@@ -0,0 +1,170 @@
+//! Splitting of a module into several modules by its exports, e.g. for deploy pipelines shipping
+//! rarely used entry points separately from the ones called all the time.
+//!
+//! Experimental: the split modules don't share memory or globals with each other, so the split is
+//! only sound for exports which don't communicate through them with the exports of other groups.
+
+use crate::std::fmt;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, ImportCountType, Serialize};
+
+use crate::optimizer::{self, optimize};
+use crate::symbols::Symbol;
+
+/// Error of `by_exports`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The module has no export section.
+	NoExportSection,
+	/// A group lists an export the module doesn't have.
+	UnknownExport(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::NoExportSection => write!(f, "Module has no export section"),
+			Error::UnknownExport(name) => write!(f, "Module has no export named '{}'", name),
+		}
+	}
+}
+
+impl From<optimizer::Error> for Error {
+	fn from(e: optimizer::Error) -> Self {
+		match e {
+			optimizer::Error::NoExportSection => Error::NoExportSection,
+		}
+	}
+}
+
+/// A function of the original module which ends up in several of the split modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedFunction {
+	/// Index of the function in the function index space of the original module.
+	pub function: u32,
+	/// Indices of the groups whose modules contain the function.
+	pub groups: Vec<usize>,
+	/// Size of the body of the function, in bytes.
+	pub size: usize,
+}
+
+/// Modules split by `by_exports`.
+#[derive(Debug, Clone)]
+pub struct Split {
+	/// The module of each group, in the order of the groups.
+	pub modules: Vec<elements::Module>,
+	/// The functions contained in more than one module, by function index.
+	pub shared: Vec<SharedFunction>,
+}
+
+impl Split {
+	/// Number of bytes of function bodies deployed more than once, i.e. the size of every shared
+	/// function for every module containing it but the first.
+	pub fn duplicated_size(&self) -> usize {
+		self.shared.iter().map(|function| function.size * (function.groups.len() - 1)).sum()
+	}
+}
+
+/// Split the module into one module per group of exports, each exporting the exports of its group
+/// and containing the code reachable from them.
+///
+/// Every module keeps the imports its code uses, the memory, the tables and their segments, and
+/// the start function, so the code reachable from the segments and the start function is shared
+/// by all modules. Exports which are in no group are dropped.
+pub fn by_exports(module: &elements::Module, groups: &[&[&str]]) -> Result<Split, Error> {
+	let exports = module.export_section().ok_or(Error::NoExportSection)?;
+	if let Some(name) = groups.iter()
+		.flat_map(|group| group.iter())
+		.find(|name| !exports.entries().iter().any(|entry| entry.field() == **name))
+	{
+		return Err(Error::UnknownExport((*name).into()));
+	}
+
+	let imported_funcs = module.import_count(ImportCountType::Function);
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	// Groups containing each function defined by the module.
+	let mut containing = vec![Vec::new(); bodies.len()];
+	let mut modules = Vec::with_capacity(groups.len());
+	for (index, group) in groups.iter().enumerate() {
+		for symbol in optimizer::used_symbols(module, group)? {
+			if let Symbol::Function(function) = symbol {
+				containing[function].push(index);
+			}
+		}
+
+		let mut split = module.clone();
+		optimize(&mut split, group.to_vec())?;
+		modules.push(split);
+	}
+
+	let shared = containing.into_iter()
+		.zip(bodies)
+		.enumerate()
+		.filter(|(_, (groups, _))| groups.len() > 1)
+		.map(|(index, (groups, body))| {
+			let mut bytes = Vec::new();
+			body.clone().serialize(&mut bytes).expect("serializing into a vector doesn't fail; qed");
+			SharedFunction { function: (imported_funcs + index) as u32, groups, size: bytes.len() }
+		})
+		.collect();
+
+	Ok(Split { modules, shared })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	fn exports(module: &elements::Module) -> Vec<&str> {
+		module.export_section().unwrap().entries().iter().map(|entry| entry.field()).collect()
+	}
+
+	#[test]
+	fn splits_by_exports() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "log" (func $log (param i32)))
+				(import "env" "abort" (func $abort))
+				(memory (export "memory") 1)
+				(func $helper (param i32) (result i32)
+					(i32.mul (local.get 0) (i32.const 2)))
+				(func (export "hot") (param i32) (result i32)
+					(call $helper (local.get 0)))
+				(func (export "cold") (param i32)
+					(call $log (call $helper (local.get 0))))
+				(func (export "migrate")
+					(call $abort)))
+		"#);
+
+		let split = by_exports(&module, &[&["hot", "memory"], &["cold", "migrate"]]).unwrap();
+
+		let hot = &split.modules[0];
+		assert_eq!(exports(hot), vec!["memory", "hot"]);
+		assert_eq!(hot.import_count(ImportCountType::Function), 0);
+		assert_eq!(hot.functions_space(), 2);
+
+		let cold = &split.modules[1];
+		assert_eq!(exports(cold), vec!["cold", "migrate"]);
+		assert_eq!(cold.import_count(ImportCountType::Function), 2);
+		assert_eq!(cold.functions_space(), 5);
+
+		// Only the helper is in both modules.
+		assert_eq!(split.shared.len(), 1);
+		assert_eq!((split.shared[0].function, &split.shared[0].groups[..]), (2, &[0, 1][..]));
+		assert_eq!(split.duplicated_size(), split.shared[0].size);
+		assert!(split.shared[0].size > 0);
+
+		for split in split.modules {
+			elements::serialize(split).unwrap();
+		}
+	}
+
+	#[test]
+	fn unknown_export() {
+		let module = parse_wat(r#"(module (func (export "call")))"#);
+		assert_eq!(by_exports(&module, &[&["call"], &["missing"]]).unwrap_err(), Error::UnknownExport("missing".into()));
+	}
+}
@@ -113,23 +113,26 @@ impl Stack {
 		if value_count == 0 {
 			return Ok(());
 		}
-		{
+		let (start_height, is_polymorphic) = {
 			let top_frame = self.frame(0)?;
-			if self.height == top_frame.start_height {
-				// It is an error to pop more values than was pushed in the current frame
-				// (ie pop values pushed in the parent frame), unless the frame became
-				// polymorphic.
-				return if top_frame.is_polymorphic {
-					Ok(())
-				} else {
-					Err(Error("trying to pop more values than pushed".into()))
-				}
+			(top_frame.start_height, top_frame.is_polymorphic)
+		};
+		let pushed = self.height
+			.checked_sub(start_height)
+			.ok_or_else(|| Error("stack underflow".into()))?;
+		if value_count > pushed {
+			// It is an error to pop more values than was pushed in the current frame
+			// (ie pop values pushed in the parent frame), unless the frame became
+			// polymorphic.
+			return if is_polymorphic {
+				self.height = start_height;
+				Ok(())
+			} else {
+				Err(Error("trying to pop more values than pushed".into()))
 			}
 		}
 
-		self.height = self.height
-			.checked_sub(value_count)
-			.ok_or_else(|| Error("stack underflow".into()))?;
+		self.height -= value_count;
 
 		Ok(())
 	}
@@ -194,11 +197,15 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 			max_height = stack.height();
 		}
 
+		if crate::visit::is_copy_padding(instructions.elements(), pc) {
+			pc += 1;
+			continue;
+		}
+
 		let opcode = &instructions.elements()[pc];
 		trace!(target: "max_height", "{:?}", opcode);
 
 		match opcode {
-			Nop => {}
 			Block(ty) | Loop(ty) | If(ty) => {
 				let end_arity = if *ty == BlockType::NoResult { 0 } else { 1 };
 				let branch_arity = if let Loop(_) = *opcode { 0 } else { end_arity };
@@ -298,127 +305,11 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 				let callee_arity = ty.results().len() as u32;
 				stack.push_values(callee_arity)?;
 			}
-			Drop => {
-				stack.pop_values(1)?;
-			}
-			Select => {
-				// Pop two values and one condition.
-				stack.pop_values(2)?;
-				stack.pop_values(1)?;
-
-				// Push the selected value.
-				stack.push_values(1)?;
-			}
-			GetLocal(_) => {
-				stack.push_values(1)?;
-			}
-			SetLocal(_) => {
-				stack.pop_values(1)?;
-			}
-			TeeLocal(_) => {
-				// This instruction pops and pushes the value, so
-				// effectively it doesn't modify the stack height.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			}
-			GetGlobal(_) => {
-				stack.push_values(1)?;
-			}
-			SetGlobal(_) => {
-				stack.pop_values(1)?;
-			}
-			I32Load(_, _)
-			| I64Load(_, _)
-			| F32Load(_, _)
-			| F64Load(_, _)
-			| I32Load8S(_, _)
-			| I32Load8U(_, _)
-			| I32Load16S(_, _)
-			| I32Load16U(_, _)
-			| I64Load8S(_, _)
-			| I64Load8U(_, _)
-			| I64Load16S(_, _)
-			| I64Load16U(_, _)
-			| I64Load32S(_, _)
-			| I64Load32U(_, _) => {
-				// These instructions pop the address and pushes the result,
-				// which effictively don't modify the stack height.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			}
-
-			I32Store(_, _)
-			| I64Store(_, _)
-			| F32Store(_, _)
-			| F64Store(_, _)
-			| I32Store8(_, _)
-			| I32Store16(_, _)
-			| I64Store8(_, _)
-			| I64Store16(_, _)
-			| I64Store32(_, _) => {
-				// These instructions pop the address and the value.
-				stack.pop_values(2)?;
-			}
-
-			CurrentMemory(_) => {
-				// Pushes current memory size
-				stack.push_values(1)?;
-			}
-			GrowMemory(_) => {
-				// Grow memory takes the value of pages to grow and pushes
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			}
-
-			I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => {
-				// These instructions just push the single literal value onto the stack.
-				stack.push_values(1)?;
-			}
-
-			I32Eqz | I64Eqz => {
-				// These instructions pop the value and compare it against zero, and pushes
-				// the result of the comparison.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			}
-
-			I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS
-			| I32GeU | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU
-			| I64GeS | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne
-			| F64Lt | F64Gt | F64Le | F64Ge => {
-				// Comparison operations take two operands and produce one result.
-				stack.pop_values(2)?;
-				stack.push_values(1)?;
-			}
-
-			I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs | F32Neg
-			| F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg | F64Ceil
-			| F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
-				// Unary operators take one operand and produce one result.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			}
-
-			I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or
-			| I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Add | I64Sub
-			| I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl
-			| I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div
-			| F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min
-			| F64Max | F64Copysign => {
-				// Binary operators take two operands and produce one result.
-				stack.pop_values(2)?;
-				stack.push_values(1)?;
-			}
-
-			I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64
-			| I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64
-			| I64TruncUF64 | F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64
-			| F32DemoteF64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64
-			| F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32
-			| F64ReinterpretI64 => {
-				// Conversion operators take one value and produce one result.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
+			_ => {
+				let (pop, push) = crate::visit::operator_stack_effect(opcode)
+					.expect("control flow and calls are handled above; qed");
+				stack.pop_values(pop as u32)?;
+				stack.push_values(push as u32)?;
 			}
 		}
 		pc += 1;
@@ -526,6 +417,33 @@ mod tests {
 		assert_eq!(height, 2);
 	}
 
+	#[test]
+	fn operators_after_unreachable() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $main
+		i32.const 0
+		drop
+
+		;; The operators take values pushed before `unreachable`, of which
+		;; there are none.
+		unreachable
+		i32.const 1
+		i32.add
+		i32.const 2
+		i32.const 3
+		select
+		drop
+	)
+)
+"#,
+		);
+
+		let height = compute(0, &module).unwrap();
+		assert_eq!(height, 1);
+	}
+
 	#[test]
 	fn call_indirect() {
 		let module = parse_wat(
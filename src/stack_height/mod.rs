@@ -13,6 +13,11 @@
 //! the limit (specified by the `rules`) then execution traps.
 //! Otherwise, the call is executed.
 //!
+//! The trap is an `unreachable` by default. With `inject_limiter_with_trap`, an imported host
+//! function is called first, so that the runtime can report a stack overflow as such rather than
+//! as a generic trap. Alternatively, `LimiterConfig::with_trap_reason` stores the reason of the
+//! trap in an exported global before, see the `trap_reason` module.
+//!
 //! The postamble is inserted after the call. The purpose of the postamble is to decrease
 //! the stack height by the "stack cost" of the callee function.
 //!
@@ -34,8 +39,9 @@
 //!
 //! # Stack cost
 //!
-//! Stack cost of the function is calculated as a sum of it's locals
-//! and the maximal height of the value stack.
+//! Stack cost of the function is calculated as a sum of it's declared locals
+//! and the maximal height of the value stack. With `FrameCost::Frame`, the
+//! parameters are counted as well, so that the stack cost covers the whole frame.
 //!
 //! All values are treated equally, as they have the same size.
 //!
@@ -55,21 +61,41 @@ use crate::std::vec::Vec;
 use parity_wasm::elements::{self, Type};
 use parity_wasm::builder;
 
+use crate::debug_names::{self, DebugNames};
+use crate::gas::update_call_index;
+use crate::source_map::SourceMap;
+use crate::trap_reason;
+
 /// Macro to generate preamble and postamble.
 macro_rules! instrument_call {
-	($callee_idx: expr, $callee_stack_cost: expr, $stack_height_global_idx: expr, $stack_limit: expr) => {{
+	(
+		$callee_idx: expr,
+		$callee_stack_cost: expr,
+		$stack_height_global_idx: expr,
+		$stack_limit: expr,
+		$overflow_func_idx: expr,
+		$trap_reason_global_idx: expr
+	) => {{
 		use $crate::parity_wasm::elements::Instruction::*;
-		[
+		let mut instructions = vec![
 			// stack_height += stack_cost(F)
 			GetGlobal($stack_height_global_idx),
 			I32Const($callee_stack_cost),
 			I32Add,
 			SetGlobal($stack_height_global_idx),
-			// if stack_counter > LIMIT: unreachable
+			// if stack_counter > LIMIT: [call OVERFLOW_FUNC] unreachable
 			GetGlobal($stack_height_global_idx),
 			I32Const($stack_limit as i32),
 			I32GtU,
 			If(elements::BlockType::NoResult),
+		];
+		if let Some(trap_reason_global_idx) = $trap_reason_global_idx {
+			$crate::trap_reason::set_reason(&mut instructions, trap_reason_global_idx, $crate::trap_reason::STACK_OVERFLOW);
+		}
+		if let Some(overflow_func_idx) = $overflow_func_idx {
+			instructions.push(Call(overflow_func_idx));
+		}
+		instructions.extend_from_slice(&[
 			Unreachable,
 			End,
 			// Original call
@@ -79,7 +105,8 @@ macro_rules! instrument_call {
 			I32Const($callee_stack_cost),
 			I32Sub,
 			SetGlobal($stack_height_global_idx),
-		]
+		]);
+		instructions
 	}};
 }
 
@@ -90,17 +117,144 @@ mod thunk;
 ///
 /// This means that the module is invalid.
 #[derive(Debug)]
-pub struct Error(String);
+pub struct Error(pub(crate) String);
+
+/// What the instrumented code does when the stack limit is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowTrap<'a> {
+	/// Execute `unreachable`.
+	Unreachable,
+	/// Call the function imported as `field` from `module`, which has the type signature
+	/// [] -> []. The host is expected to abort the execution, but an `unreachable` follows the
+	/// call in case it returns.
+	HostFunction { module: &'a str, field: &'a str },
+}
+
+/// What the stack cost of a function covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCost {
+	/// The declared locals and the maximal height of the value stack.
+	Locals,
+	/// The parameters, the declared locals and the maximal height of the value stack.
+	Frame,
+}
+
+/// Configuration of the stack height limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimiterConfig<'a> {
+	/// What the instrumented code does when the stack limit is exceeded.
+	pub trap: OverflowTrap<'a>,
+	/// What the stack cost of a function covers.
+	pub frame_cost: FrameCost,
+	/// Export name of the trap reason global, if one is set before trapping.
+	pub trap_reason: Option<&'a str>,
+	/// How the stack height global is named, if at all.
+	pub debug_names: Option<DebugNames>,
+	/// Whether the module is checked to be well formed first.
+	pub checked: bool,
+	/// Whether the instrumented module is validated.
+	pub validate_output: bool,
+}
+
+impl<'a> LimiterConfig<'a> {
+	pub fn new() -> Self {
+		LimiterConfig {
+			trap: OverflowTrap::Unreachable,
+			frame_cost: FrameCost::Locals,
+			trap_reason: None,
+			debug_names: None,
+			checked: false,
+			validate_output: false,
+		}
+	}
+
+	/// Execute `trap` when the stack limit is exceeded.
+	pub fn with_trap(mut self, trap: OverflowTrap<'a>) -> Self {
+		self.trap = trap;
+		self
+	}
+
+	/// Compute the stack costs of the functions as specified by `frame_cost`.
+	pub fn with_frame_cost(mut self, frame_cost: FrameCost) -> Self {
+		self.frame_cost = frame_cost;
+		self
+	}
+
+	/// Store `trap_reason::STACK_OVERFLOW` in the global exported as `export_name` before
+	/// trapping, see the `trap_reason` module.
+	pub fn with_trap_reason(mut self, export_name: &'a str) -> Self {
+		self.trap_reason = Some(export_name);
+		self
+	}
+
+	/// Name the stack height global as configured by `debug`, see the `debug_names` module.
+	pub fn with_debug_names(mut self, debug: DebugNames) -> Self {
+		self.debug_names = Some(debug);
+		self
+	}
+
+	/// Check that the module is well formed before limiting it, see `check::well_formed`, so that
+	/// malformed modules are rejected with an error instead of making the limiter panic.
+	pub fn with_checks(mut self) -> Self {
+		self.checked = true;
+		self
+	}
+
+	/// Validate the instrumented module, see `validation::validate`, so that a bug of the limiter
+	/// producing an invalid module is reported with an error instead of being found by the runtime
+	/// loading it.
+	pub fn with_output_validation(mut self) -> Self {
+		self.validate_output = true;
+		self
+	}
+}
+
+impl Default for LimiterConfig<'static> {
+	fn default() -> Self {
+		LimiterConfig::new()
+	}
+}
 
 pub(crate) struct Context {
 	stack_height_global_idx: u32,
 	func_stack_costs: Vec<u32>,
 	stack_limit: u32,
+	overflow_func_idx: Option<u32>,
+	trap_reason_global_idx: Option<u32>,
 }
 
 impl Context {
+	pub(crate) fn new(
+		stack_height_global_idx: u32,
+		func_stack_costs: Vec<u32>,
+		stack_limit: u32,
+		overflow_func_idx: Option<u32>,
+		trap_reason_global_idx: Option<u32>,
+	) -> Self {
+		Context { stack_height_global_idx, func_stack_costs, stack_limit, overflow_func_idx, trap_reason_global_idx }
+	}
+
+	/// Returns the call of `callee_idx` wrapped with the preamble and the postamble, or `None` if
+	/// its stack cost is zero and the call is left as is.
+	pub(crate) fn instrumented_call(&self, callee_idx: u32) -> Result<Option<Vec<elements::Instruction>>, Error> {
+		let callee_stack_cost = self.stack_cost(callee_idx).ok_or_else(|| {
+			Error(format!("Call to function that out-of-bounds: {}", callee_idx))
+		})?;
+		if callee_stack_cost == 0 {
+			return Ok(None);
+		}
+		Ok(Some(instrument_call!(
+			callee_idx,
+			callee_stack_cost as i32,
+			self.stack_height_global_idx(),
+			self.stack_limit(),
+			self.overflow_func_idx(),
+			self.trap_reason_global_idx()
+		)))
+	}
+
 	/// Returns index in a global index space of a stack_height global variable.
-	fn stack_height_global_idx(&self) -> u32 {
+	pub(crate) fn stack_height_global_idx(&self) -> u32 {
 		self.stack_height_global_idx
 	}
 
@@ -113,6 +267,16 @@ impl Context {
 	fn stack_limit(&self) -> u32 {
 		self.stack_limit
 	}
+
+	/// Returns index in a function index space of the function called on overflow, if any.
+	fn overflow_func_idx(&self) -> Option<u32> {
+		self.overflow_func_idx
+	}
+
+	/// Returns index in a global index space of the trap reason global, if any.
+	fn trap_reason_global_idx(&self) -> Option<u32> {
+		self.trap_reason_global_idx
+	}
 }
 
 /// Instrument a module with stack height limiter.
@@ -123,19 +287,131 @@ impl Context {
 ///
 /// Returns `Err` if module is invalid and can't be
 pub fn inject_limiter(
-	mut module: elements::Module,
+	module: elements::Module,
+	stack_limit: u32,
+) -> Result<elements::Module, Error> {
+	inject_limiter_with_trap(module, stack_limit, OverflowTrap::Unreachable)
+}
+
+/// Instrument a module with stack height limiter, which executes `trap` when the limit is
+/// exceeded.
+///
+/// If `trap` is a host function, it is added as the last function import, so the indices of all
+/// defined functions increase by one.
+///
+/// # Errors
+///
+/// Returns `Err` if module is invalid and can't be
+pub fn inject_limiter_with_trap(
+	module: elements::Module,
 	stack_limit: u32,
+	trap: OverflowTrap,
 ) -> Result<elements::Module, Error> {
+	inject_limiter_with_config(module, stack_limit, LimiterConfig::new().with_trap(trap))
+}
+
+/// Instrument a module with stack height limiter configured by `config`.
+///
+/// See `inject_limiter_with_trap` for the trap.
+///
+/// # Errors
+///
+/// Returns `Err` if module is invalid and can't be
+pub fn inject_limiter_with_config(
+	module: elements::Module,
+	stack_limit: u32,
+	config: LimiterConfig,
+) -> Result<elements::Module, Error> {
+	inject_limiter_with_source_map(module, stack_limit, config).map(|(module, _)| module)
+}
+
+/// Same as `inject_limiter_with_config`, but also returns the positions of the original
+/// instructions in the instrumented function bodies.
+///
+/// # Errors
+///
+/// Returns `Err` if module is invalid and can't be
+pub fn inject_limiter_with_source_map(
+	module: elements::Module,
+	stack_limit: u32,
+	config: LimiterConfig,
+) -> Result<(elements::Module, SourceMap), Error> {
+	if config.checked {
+		crate::check::well_formed(&module).map_err(|e| Error(format!("{}", e)))?;
+	}
+	let (mut module, overflow_func_idx) = match config.trap {
+		OverflowTrap::Unreachable => (module, None),
+		OverflowTrap::HostFunction { module: module_name, field } => {
+			let (module, overflow_func_idx) = import_overflow_func(module, module_name, field);
+			(module, Some(overflow_func_idx))
+		}
+	};
+	let stack_height_global_idx = generate_stack_height_global(&mut module);
+	let trap_reason_global_idx = config.trap_reason
+		.map(|export_name| trap_reason::reason_global(&mut module, export_name));
 	let mut ctx = Context {
-		stack_height_global_idx: generate_stack_height_global(&mut module),
-		func_stack_costs: compute_stack_costs(&module)?,
+		stack_height_global_idx,
+		func_stack_costs: compute_stack_costs_with(&module, config.frame_cost)?,
 		stack_limit,
+		overflow_func_idx,
+		trap_reason_global_idx,
 	};
 
-	instrument_functions(&mut ctx, &mut module)?;
-	let module = thunk::generate_thunks(&mut ctx, module)?;
+	let source_map = instrument_functions(&mut ctx, &mut module)?;
+	let mut module = thunk::generate_thunks(&mut ctx, module)?;
+	if let Some(debug) = config.debug_names {
+		debug.apply(&mut module, debug_names::STACK_DEPTH, elements::Internal::Global(stack_height_global_idx));
+	}
+	if config.validate_output {
+		crate::validation::validate(&module).map_err(|e| Error(format!("Instrumented module is invalid: {}", e)))?;
+	}
 
-	Ok(module)
+	Ok((module, source_map))
+}
+
+/// Import the function called on overflow, returning its index.
+///
+/// All references to defined functions are updated for the new import.
+fn import_overflow_func(module: elements::Module, module_name: &str, field: &str) -> (elements::Module, u32) {
+	let mut mbuilder = builder::from_module(module);
+	let import_sig = mbuilder.push_signature(builder::signature().build_sig());
+	mbuilder.push_import(
+		builder::import()
+			.module(module_name)
+			.field(field)
+			.external().func(import_sig)
+			.build()
+	);
+	let mut module = mbuilder.build();
+
+	let overflow_func_idx = module.import_count(elements::ImportCountType::Function) as u32 - 1;
+	for section in module.sections_mut() {
+		match section {
+			elements::Section::Code(code_section) => {
+				for func_body in code_section.bodies_mut() {
+					update_call_index(func_body.code_mut(), overflow_func_idx);
+				}
+			}
+			elements::Section::Export(export_section) => {
+				for export in export_section.entries_mut() {
+					if let elements::Internal::Function(func_idx) = export.internal_mut() {
+						if *func_idx >= overflow_func_idx { *func_idx += 1 }
+					}
+				}
+			}
+			elements::Section::Element(elements_section) => {
+				for segment in elements_section.entries_mut() {
+					for func_idx in segment.members_mut() {
+						if *func_idx >= overflow_func_idx { *func_idx += 1 }
+					}
+				}
+			}
+			elements::Section::Start(start_idx) if *start_idx >= overflow_func_idx => *start_idx += 1,
+			_ => {}
+		}
+	}
+
+	(module, overflow_func_idx)
 }
 
 /// Generate a new global that will be used for tracking current stack height.
@@ -164,8 +440,17 @@ fn generate_stack_height_global(module: &mut elements::Module) -> u32 {
 
 /// Calculate stack costs for all functions.
 ///
-/// Returns a vector with a stack cost for each function, including imports.
-fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, Error> {
+/// Returns a vector with a stack cost for each function, including imports. Imported functions
+/// have a stack cost of zero since their bodies are unknown. See module-level documentation for
+/// how the stack cost is defined.
+pub fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, Error> {
+	compute_stack_costs_with(module, FrameCost::Locals)
+}
+
+/// Calculate stack costs for all functions, covering what is specified by `frame_cost`.
+///
+/// See `compute_stack_costs`.
+pub fn compute_stack_costs_with(module: &elements::Module, frame_cost: FrameCost) -> Result<Vec<u32>, Error> {
 	let func_imports = module.import_count(elements::ImportCountType::Function);
 
 	// TODO: optimize!
@@ -175,16 +460,52 @@ fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, Error> {
 				// We can't calculate stack_cost of the import functions.
 				Ok(0)
 			} else {
-				compute_stack_cost(func_idx as u32, &module)
+				compute_stack_cost(func_idx as u32, &module, frame_cost)
 			}
 		})
 		.collect()
 }
 
-/// Stack cost of the given *defined* function is the sum of it's locals count (that is,
-/// number of arguments plus number of local variables) and the maximal stack
-/// height.
-fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, Error> {
+/// Stack usage of a defined function, as analyzed by `analyze`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionStackInfo {
+	/// Index of the function in the function index space.
+	pub index: u32,
+	/// Number of parameters.
+	pub params: u32,
+	/// Number of declared local variables.
+	pub locals: u32,
+	/// Maximal height of the value stack.
+	pub max_height: u32,
+}
+
+impl FunctionStackInfo {
+	/// Stack cost of the function, covering what is specified by `frame_cost`.
+	///
+	/// Returns `None` on overflow.
+	pub fn cost(&self, frame_cost: FrameCost) -> Option<u32> {
+		let frame = match frame_cost {
+			FrameCost::Locals => self.locals,
+			FrameCost::Frame => self.params.checked_add(self.locals)?,
+		};
+		frame.checked_add(self.max_height)
+	}
+}
+
+/// Analyze the stack usage of all defined functions of the module, without instrumenting it.
+///
+/// Tooling can report the stack usage with it or check a module against the limits of a runtime
+/// before deploying it.
+pub fn analyze(module: &elements::Module) -> Result<Vec<FunctionStackInfo>, Error> {
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+
+	(func_imports..module.functions_space())
+		.map(|func_idx| function_stack_info(func_idx as u32, module))
+		.collect()
+}
+
+/// Stack usage of the given *defined* function.
+pub(crate) fn function_stack_info(func_idx: u32, module: &elements::Module) -> Result<FunctionStackInfo, Error> {
 	// To calculate the cost of a function we need to convert index from
 	// function index space to defined function spaces.
 	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
@@ -200,33 +521,41 @@ fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, E
 		.get(defined_func_idx as usize)
 		.ok_or_else(|| Error("Function body is out of bounds".into()))?;
 
-	let mut locals_count: u32 = 0;
+	let mut locals: u32 = 0;
 	for local_group in body.locals() {
-		locals_count = locals_count
+		locals = locals
 			.checked_add(local_group.count())
 			.ok_or_else(|| Error("Overflow in local count".into()))?;
 	}
 
-	let max_stack_height =
-		max_height::compute(
-			defined_func_idx,
-			module
-		)?;
+	Ok(FunctionStackInfo {
+		index: func_idx,
+		params: resolve_func_type(func_idx, module)?.params().len() as u32,
+		locals,
+		max_height: max_height::compute(defined_func_idx, module)?,
+	})
+}
 
-	locals_count.checked_add(max_stack_height)
+/// Stack cost of the given *defined* function is the sum of it's locals count (that is,
+/// number of local variables, plus the number of arguments for `FrameCost::Frame`) and the
+/// maximal stack height.
+fn compute_stack_cost(func_idx: u32, module: &elements::Module, frame_cost: FrameCost) -> Result<u32, Error> {
+	function_stack_info(func_idx, module)?
+		.cost(frame_cost)
 		.ok_or_else(|| Error("Overflow in adding locals_count and max_stack_height".into()))
 }
 
-fn instrument_functions(ctx: &mut Context, module: &mut elements::Module) -> Result<(), Error> {
+fn instrument_functions(ctx: &mut Context, module: &mut elements::Module) -> Result<SourceMap, Error> {
+	let mut source_map = SourceMap::default();
 	for section in module.sections_mut() {
 		if let elements::Section::Code(code_section) = section {
 			for func_body in code_section.bodies_mut() {
 				let opcodes = func_body.code_mut();
-				instrument_function(ctx, opcodes)?;
+				source_map.functions.push(instrument_function(ctx, opcodes)?);
 			}
 		}
 	}
-	Ok(())
+	Ok(source_map)
 }
 
 /// This function searches `call` instructions and wrap each call
@@ -255,12 +584,15 @@ fn instrument_functions(ctx: &mut Context, module: &mut elements::Module) -> Res
 ///
 /// drop
 /// ```
+///
+/// Returns the new positions of the original instructions.
 fn instrument_function(
 	ctx: &mut Context,
 	instructions: &mut elements::Instructions,
-) -> Result<(), Error> {
+) -> Result<Vec<usize>, Error> {
 	use parity_wasm::elements::Instruction::*;
 
+	let mut positions = Vec::with_capacity(instructions.elements().len());
 	let mut cursor = 0;
 	loop {
 		if cursor >= instructions.elements().len() {
@@ -311,7 +643,9 @@ fn instrument_function(
 					callee_idx,
 					callee_stack_cost as i32,
 					ctx.stack_height_global_idx(),
-					ctx.stack_limit()
+					ctx.stack_limit(),
+					ctx.overflow_func_idx(),
+					ctx.trap_reason_global_idx()
 				);
 
 				// Replace the original `call idx` instruction with
@@ -324,20 +658,24 @@ fn instrument_function(
 					.splice(cursor..(cursor + 1), new_seq.iter().cloned())
 					.count();
 
+				// The original call is followed by the postamble of 4 instructions.
+				positions.push(cursor + new_seq.len() - 5);
+
 				// Advance cursor to be after the inserted sequence.
 				cursor += new_seq.len();
 			}
 			// Do nothing for other instructions.
 			_ => {
+				positions.push(cursor);
 				cursor += 1;
 			}
 		}
 	}
 
-	Ok(())
+	Ok(positions)
 }
 
-fn resolve_func_type(
+pub(crate) fn resolve_func_type(
 	func_idx: u32,
 	module: &elements::Module,
 ) -> Result<&elements::FunctionType, Error> {
@@ -415,4 +753,129 @@ mod tests {
 			.expect("Failed to inject stack counter");
 		validate_module(module);
 	}
+	#[test]
+	fn overflow_host_function() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = parse_wat(
+			r#"
+(module
+	(func $callee (result i32)
+		i32.const 1
+	)
+	(func (export "call") (result i32)
+		call $callee
+	)
+)
+"#,
+		);
+
+		let trap = OverflowTrap::HostFunction { module: "env", field: "stack_overflow" };
+		let module = inject_limiter_with_trap(module, 1024, trap)
+			.expect("Failed to inject stack counter");
+
+		let import = &module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("env", "stack_overflow"));
+		let body = module.code_section().unwrap().bodies()[1].code().elements();
+		assert_eq!(&body[8..12], &[Call(0), Unreachable, End, Call(1)]);
+		validate_module(module);
+	}
+	#[test]
+	fn source_map() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = parse_wat(
+			r#"
+(module
+	(func $callee (result i32)
+		i32.const 1
+	)
+	(func (export "call")
+		i32.const 2
+		drop
+		call $callee
+		drop
+	)
+)
+"#,
+		);
+
+		let (module, source_map) = inject_limiter_with_source_map(module, 1024, LimiterConfig::new())
+			.expect("Failed to inject stack counter");
+
+		assert_eq!(source_map.functions, vec![vec![0, 1], vec![0, 1, 12, 17, 18]]);
+		let body = module.code_section().unwrap().bodies()[1].code().elements();
+		assert_eq!(body[12], Call(0));
+		// The check of the stack height is attributed to the call.
+		assert_eq!(source_map.original(1, 9), Some(2));
+	}
+
+	#[test]
+	fn trap_reason() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = parse_wat(
+			r#"
+(module
+	(func $callee (result i32)
+		i32.const 1
+	)
+	(func (export "call") (result i32)
+		call $callee
+	)
+)
+"#,
+		);
+
+		let config = LimiterConfig::new().with_trap_reason("trap_reason");
+		let module = inject_limiter_with_config(module, 1024, config)
+			.expect("Failed to inject stack counter");
+
+		let export = module.export_section().unwrap().entries().iter()
+			.find(|entry| entry.field() == "trap_reason")
+			.expect("trap reason global is exported");
+		assert_eq!(export.internal(), &elements::Internal::Global(1));
+		let body = module.code_section().unwrap().bodies()[1].code().elements();
+		assert_eq!(&body[8..11], &[I32Const(trap_reason::STACK_OVERFLOW), SetGlobal(1), Unreachable]);
+		validate_module(module);
+	}
+
+	#[test]
+	fn frame_cost() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i64 i64 i64)
+		(local i32)
+	)
+)
+"#,
+		);
+
+		assert_eq!(compute_stack_costs(&module).unwrap(), vec![1]);
+		assert_eq!(compute_stack_costs_with(&module, FrameCost::Frame).unwrap(), vec![4]);
+	}
+
+	#[test]
+	fn analyze_stack() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func))
+	(func (param i32) (result i32)
+		(local i64 i64)
+		get_local 0
+		i32.const 1
+		i32.add
+	)
+)
+"#,
+		);
+
+		let info = analyze(&module).unwrap();
+		assert_eq!(info, vec![FunctionStackInfo { index: 1, params: 1, locals: 2, max_height: 2 }]);
+		assert_eq!(info[0].cost(FrameCost::Locals), Some(4));
+		assert_eq!(info[0].cost(FrameCost::Frame), Some(5));
+		assert_eq!(compute_stack_costs(&module).unwrap(), vec![0, 4]);
+	}
 }
@@ -75,7 +75,9 @@ pub(crate) fn generate_thunks(
 			*func_idx,
 			thunk.callee_stack_cost as i32,
 			ctx.stack_height_global_idx(),
-			ctx.stack_limit()
+			ctx.stack_limit(),
+			ctx.overflow_func_idx(),
+			ctx.trap_reason_global_idx()
 		);
 		// Thunk body consist of:
 		//  - argument pushing
@@ -0,0 +1,543 @@
+//! Passes which shrink a module without changing its behaviour.
+
+use crate::std::collections::{BTreeMap, BTreeSet};
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{
+	self, External, FunctionType, ImportCountType, Instruction, Internal, Section, Type, ValueType,
+};
+use parity_wasm::elements::Instruction::{Call, Drop, GetLocal, SetLocal, TeeLocal};
+
+/// Name of the custom section holding debug names of the module entities.
+const NAME_SECTION: &str = "name";
+
+/// Remove all custom sections from the module.
+///
+/// If `keep_names` is set, the name section is preserved. Returns the number of removed sections.
+pub fn strip_custom_sections(module: &mut elements::Module, keep_names: bool) -> usize {
+	let sections = module.sections_mut();
+	let before = sections.len();
+	sections.retain(|section| match section {
+		Section::Custom(custom) => keep_names && custom.name() == NAME_SECTION,
+		Section::Name(_) => keep_names,
+		Section::Reloc(_) => false,
+		_ => true,
+	});
+	before - sections.len()
+}
+
+/// Remove the name section from the module, keeping all other custom sections.
+///
+/// Returns whether there was a name section.
+pub fn strip_names(module: &mut elements::Module) -> bool {
+	let sections = module.sections_mut();
+	let before = sections.len();
+	sections.retain(|section| match section {
+		Section::Custom(custom) => custom.name() != NAME_SECTION,
+		Section::Name(_) => false,
+		_ => true,
+	});
+	before != sections.len()
+}
+
+/// Merge identical entries of the type section.
+///
+/// All references to types from function declarations, imports and `call_indirect`
+/// instructions are rewritten accordingly. Returns the number of removed types.
+pub fn dedup_types(module: &mut elements::Module) -> usize {
+	let types = match module.type_section_mut() {
+		Some(section) => section.types_mut(),
+		None => return 0,
+	};
+
+	// `remap[old_index]` is the new index of the type.
+	let mut remap = Vec::with_capacity(types.len());
+	let mut unique: Vec<Type> = Vec::with_capacity(types.len());
+	for ty in types.drain(..) {
+		match unique.iter().position(|existing| *existing == ty) {
+			Some(index) => remap.push(index as u32),
+			None => {
+				remap.push(unique.len() as u32);
+				unique.push(ty);
+			}
+		}
+	}
+	let removed = remap.len() - unique.len();
+	*types = unique;
+
+	if removed == 0 {
+		return 0;
+	}
+
+	let fixup = |type_ref: &mut u32| {
+		if let Some(new_ref) = remap.get(*type_ref as usize) {
+			*type_ref = *new_ref;
+		}
+	};
+
+	for section in module.sections_mut() {
+		match section {
+			Section::Import(import_section) => {
+				for entry in import_section.entries_mut() {
+					if let External::Function(type_ref) = entry.external_mut() {
+						fixup(type_ref);
+					}
+				}
+			}
+			Section::Function(function_section) => {
+				for func in function_section.entries_mut() {
+					fixup(func.type_ref_mut());
+				}
+			}
+			Section::Code(code_section) => {
+				for body in code_section.bodies_mut() {
+					for instruction in body.code_mut().elements_mut() {
+						if let Instruction::CallIndirect(type_ref, _) = instruction {
+							fixup(type_ref);
+						}
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+
+	removed
+}
+
+/// Remove the parameters which functions never access, passing only the others at all call sites.
+///
+/// Functions called through tables keep their signatures, as do exported functions unless
+/// `change_exports` is set, which changes the ABI of the module. The arguments of removed
+/// parameters are still evaluated and dropped by the callers. If a removed parameter is followed
+/// by kept ones, the kept arguments are moved aside through added locals of the caller. Local
+/// names of the parsed name section are updated. Returns the number of removed parameters.
+pub fn remove_unused_params(module: &mut elements::Module, change_exports: bool) -> usize {
+	let imported_funcs = module.import_count(ImportCountType::Function) as u32;
+	let signatures: Vec<FunctionType> = module.type_section()
+		.map_or(&[][..], |type_section| type_section.types())
+		.iter()
+		.map(|Type::Function(signature)| signature.clone())
+		.collect();
+
+	// Functions whose signatures must stay.
+	let mut fixed = BTreeSet::new();
+	if let Some(start) = module.start_section() {
+		fixed.insert(start);
+	}
+	if let Some(elements_section) = module.elements_section() {
+		fixed.extend(elements_section.entries().iter().flat_map(|segment| segment.members()).copied());
+	}
+	if !change_exports {
+		if let Some(export_section) = module.export_section() {
+			fixed.extend(export_section.entries().iter().filter_map(|entry| match entry.internal() {
+				Internal::Function(index) => Some(*index),
+				_ => None,
+			}));
+		}
+	}
+
+	// The parameters to remove of every defined function, by function index.
+	let funcs = module.function_section().map_or(&[][..], |function_section| function_section.entries());
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	let mut removals = BTreeMap::new();
+	let mut new_signatures = Vec::new();
+	for (index, (func, body)) in funcs.iter().zip(bodies).enumerate() {
+		let index = imported_funcs + index as u32;
+		let signature = match signatures.get(func.type_ref() as usize) {
+			Some(signature) if !fixed.contains(&index) => signature,
+			_ => continue,
+		};
+		let params = signature.params();
+		let mut removed = vec![true; params.len()];
+		for instruction in body.code().elements() {
+			if let GetLocal(local) | SetLocal(local) | TeeLocal(local) = instruction {
+				if let Some(removed) = removed.get_mut(*local as usize) {
+					*removed = false;
+				}
+			}
+		}
+		if removed.contains(&true) {
+			let params: Vec<_> = params.iter().copied().zip(removed).collect();
+			let kept = params.iter().filter(|(_, removed)| !removed).map(|(value_type, _)| *value_type).collect();
+			new_signatures.push((index, Type::Function(FunctionType::new(kept, signature.results().to_vec()))));
+			removals.insert(index, params);
+		}
+	}
+	if removals.is_empty() {
+		return 0;
+	}
+
+	// New signatures, reusing or adding types.
+	let mut type_refs = BTreeMap::new();
+	for (index, new_signature) in new_signatures {
+		let types = match module.type_section_mut() {
+			Some(type_section) => type_section.types_mut(),
+			None => unreachable!("the signatures of the functions are in the type section; qed"),
+		};
+		let type_ref = match types.iter().position(|ty| *ty == new_signature) {
+			Some(type_ref) => type_ref,
+			None => {
+				types.push(new_signature);
+				types.len() - 1
+			}
+		};
+		type_refs.insert(index, type_ref as u32);
+	}
+	if let Some(function_section) = module.function_section_mut() {
+		for (index, func) in function_section.entries_mut().iter_mut().enumerate() {
+			if let Some(type_ref) = type_refs.get(&(imported_funcs + index as u32)) {
+				*func.type_ref_mut() = *type_ref;
+			}
+		}
+	}
+
+	let param_counts: Vec<u32> = module.function_section()
+		.map_or(&[][..], |function_section| function_section.entries())
+		.iter()
+		.map(|func| match module.type_section().and_then(|section| section.types().get(func.type_ref() as usize)) {
+			Some(Type::Function(signature)) => signature.params().len() as u32,
+			None => 0,
+		})
+		.collect();
+	if let Some(code_section) = module.code_section_mut() {
+		for (index, body) in code_section.bodies_mut().iter_mut().enumerate() {
+			let index = imported_funcs + index as u32;
+			if let Some(params) = removals.get(&index) {
+				let remap = local_remap(params);
+				for instruction in body.code_mut().elements_mut() {
+					if let GetLocal(local) | SetLocal(local) | TeeLocal(local) = instruction {
+						*local = remap(*local);
+					}
+				}
+			}
+			pass_kept_arguments(body, param_counts[(index - imported_funcs) as usize], &removals);
+		}
+	}
+
+	if let Some(local_names) = module.names_section_mut().and_then(|names| names.locals_mut().as_mut()) {
+		let local_names = local_names.local_names_mut();
+		for (index, params) in &removals {
+			if let Some(names) = local_names.remove(*index) {
+				let remap = local_remap(params);
+				let mut remapped = elements::IndexMap::default();
+				for (local, name) in names {
+					if !matches!(params.get(local as usize), Some((_, true))) {
+						remapped.insert(remap(local), name);
+					}
+				}
+				local_names.insert(*index, remapped);
+			}
+		}
+	}
+
+	removals.values().flatten().filter(|(_, removed)| *removed).count()
+}
+
+/// The new index of every local of a function whose parameters are removed as given by `params`.
+fn local_remap(params: &[(ValueType, bool)]) -> impl Fn(u32) -> u32 + '_ {
+	move |local| {
+		let removed_before = params.iter().take(local as usize).filter(|(_, removed)| *removed).count();
+		local - removed_before as u32
+	}
+}
+
+/// Drop the arguments of the removed parameters before the calls in `body`, which has `params`
+/// parameters, to the functions in `removals`.
+fn pass_kept_arguments(
+	body: &mut elements::FuncBody,
+	params: u32,
+	removals: &BTreeMap<u32, Vec<(ValueType, bool)>>,
+) {
+	// The arguments from the first removed one on, for every call to rewrite.
+	let moved_args = |instruction: &Instruction| match instruction {
+		Call(func) => removals.get(func).map(|args| {
+			let first_removed = args.iter().position(|(_, removed)| *removed).unwrap_or(args.len());
+			&args[first_removed..]
+		}),
+		_ => None,
+	};
+	// Number of kept arguments of every type among `args`, in the order of their first occurrence.
+	let kept_counts = |args: &[(ValueType, bool)]| {
+		let mut counts: Vec<(ValueType, u32)> = Vec::new();
+		for (value_type, _) in args.iter().filter(|(_, removed)| !removed) {
+			match counts.iter_mut().find(|(counted, _)| counted == value_type) {
+				Some((_, count)) => *count += 1,
+				None => counts.push((*value_type, 1)),
+			}
+		}
+		counts
+	};
+
+	if !body.code().elements().iter().any(|instruction| moved_args(instruction).is_some()) {
+		return;
+	}
+
+	// Added locals holding the kept arguments while the others are dropped, as the first index and
+	// the number of locals of every type. They are shared by all calls, as each call reads them
+	// right after writing them.
+	let mut scratch: Vec<(ValueType, u32, u32)> = Vec::new();
+	for args in body.code().elements().iter().filter_map(moved_args) {
+		for (value_type, count) in kept_counts(args) {
+			match scratch.iter_mut().find(|(scratch_type, _, _)| *scratch_type == value_type) {
+				Some((_, _, max)) => *max = (*max).max(count),
+				None => scratch.push((value_type, 0, count)),
+			}
+		}
+	}
+	let mut next_local = params + body.locals().iter().map(|local| local.count()).sum::<u32>();
+	for (_, first, count) in &mut scratch {
+		*first = next_local;
+		next_local += *count;
+	}
+
+	let mut rewritten = Vec::with_capacity(body.code().elements().len());
+	for instruction in body.code().elements() {
+		if let Some(args) = moved_args(instruction) {
+			let mut used: Vec<(ValueType, u32)> = Vec::new();
+			let slots: Vec<_> = args.iter()
+				.map(|(value_type, removed)| {
+					if *removed {
+						return None;
+					}
+					let nth = match used.iter_mut().find(|(used_type, _)| used_type == value_type) {
+						Some((_, count)) => {
+							*count += 1;
+							*count - 1
+						}
+						None => {
+							used.push((*value_type, 1));
+							0
+						}
+					};
+					let (_, first, _) = scratch.iter()
+						.find(|(scratch_type, _, _)| scratch_type == value_type)
+						.expect("scratch locals are added for all kept argument types; qed");
+					Some(first + nth)
+				})
+				.collect();
+
+			rewritten.extend(slots.iter().rev().map(|slot| match slot {
+				Some(local) => SetLocal(*local),
+				None => Drop,
+			}));
+			rewritten.extend(slots.iter().flatten().map(|local| GetLocal(*local)));
+		}
+		rewritten.push(instruction.clone());
+	}
+	*body.code_mut().elements_mut() = rewritten;
+
+	body.locals_mut().extend(
+		scratch.into_iter().map(|(value_type, _, count)| elements::Local::new(count, value_type)),
+	);
+}
+
+/// Remove the instructions which can never run from all function bodies: the ones following a
+/// `br`, `br_table`, `return` or `unreachable` up to the `end` or `else` of their block, along with
+/// the blocks they hold.
+///
+/// The code following the `end` of a block is kept even if it can't be reached, as it is typed as
+/// reachable again. Running this before the gas metering keeps dead code from adding to the cost
+/// of the metered blocks. Offsets into the code section, e.g. of DWARF debug info, are not updated.
+/// Returns the number of removed instructions.
+pub fn remove_dead_code(module: &mut elements::Module) -> usize {
+	let bodies = match module.code_section_mut() {
+		Some(code_section) => code_section.bodies_mut(),
+		None => return 0,
+	};
+	let mut removed = 0;
+	for body in bodies {
+		let instructions = body.code_mut().elements_mut();
+		let before = instructions.len();
+		let mut live = true;
+		// Number of blocks opened in dead code which aren't closed yet.
+		let mut dead_depth = 0usize;
+		instructions.retain(|instruction| {
+			use parity_wasm::elements::Instruction::*;
+
+			if !live {
+				match instruction {
+					// The `else` or `end` of the block holding the dead code is kept.
+					Else | End if dead_depth == 0 => {}
+					Block(_) | Loop(_) | If(_) => {
+						dead_depth += 1;
+						return false;
+					}
+					End => {
+						dead_depth -= 1;
+						return false;
+					}
+					_ => return false,
+				}
+			}
+			live = !matches!(instruction, Br(_) | BrTable(_) | Return | Unreachable);
+			true
+		});
+		removed += before - instructions.len();
+	}
+	removed
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::{get_function_body, parse_wat, validate_module};
+
+	#[test]
+	fn dedups_types() {
+		let mut module = parse_wat(r#"
+			(module
+				(type (func (param i32)))
+				(type (func))
+				(type (func (param i32)))
+				(import "env" "f" (func (type 2)))
+				(table 1 anyfunc)
+				(func (type 1)
+					i32.const 0
+					i32.const 0
+					call_indirect (type 2))
+				(func (type 2)))
+		"#);
+
+		assert_eq!(dedup_types(&mut module), 1);
+		assert_eq!(module.type_section().unwrap().types().len(), 2);
+
+		let import_type = match module.import_section().unwrap().entries()[0].external() {
+			External::Function(type_ref) => *type_ref,
+			_ => panic!("import is a function"),
+		};
+		assert_eq!(import_type, 0);
+		assert_eq!(
+			module.function_section().unwrap().entries().iter().map(|f| f.type_ref()).collect::<Vec<_>>(),
+			vec![1, 0]
+		);
+		assert!(module.code_section().unwrap().bodies()[0].code().elements()
+			.contains(&Instruction::CallIndirect(0, 0)));
+	}
+
+	#[test]
+	fn removes_unused_params() {
+		let source = r#"
+			(module
+				(table 1 anyfunc)
+				(elem (i32.const 0) $indirect)
+				(func $f (param i32 i64 i32) (result i32)
+					get_local 2)
+				(func $g (param i32 f32) (result i32)
+					get_local 0)
+				(func (export "run") (param i32 i32) (result i32)
+					(i32.add
+						(call $f (get_local 0) (i64.const 1) (i32.const 7))
+						(call $g (i32.const 3) (f32.const 1))))
+				(func $indirect (param i32)))
+		"#;
+		let mut module = parse_wat(source);
+
+		assert_eq!(remove_unused_params(&mut module, false), 3);
+		let signature = |module: &elements::Module, index: usize| {
+			let type_ref = module.function_section().unwrap().entries()[index].type_ref();
+			match &module.type_section().unwrap().types()[type_ref as usize] {
+				Type::Function(signature) => signature.params().to_vec(),
+			}
+		};
+		assert_eq!(signature(&module, 0), vec![ValueType::I32]);
+		assert_eq!(signature(&module, 1), vec![ValueType::I32]);
+		assert_eq!(signature(&module, 2), vec![ValueType::I32, ValueType::I32]);
+		assert_eq!(signature(&module, 3), vec![ValueType::I32]);
+
+		let bodies = module.code_section().unwrap().bodies();
+		assert_eq!(bodies[0].code().elements(), &[GetLocal(0), Instruction::End]);
+		// The kept argument of `$f` is moved aside through the added local 2, the trailing argument
+		// of `$g` is dropped.
+		assert_eq!(bodies[2].code().elements(), &[
+			GetLocal(0), Instruction::I64Const(1), Instruction::I32Const(7),
+			SetLocal(2), Drop, Drop, GetLocal(2), Call(0),
+			Instruction::I32Const(3), Instruction::F32Const(1f32.to_bits()), Drop, Call(1),
+			Instruction::I32Add, Instruction::End,
+		]);
+		assert_eq!(bodies[2].locals(), &[elements::Local::new(1, ValueType::I32)]);
+		let binary = elements::serialize(module).unwrap();
+		wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+
+		let mut module = parse_wat(source);
+		assert_eq!(remove_unused_params(&mut module, true), 4);
+		let body = &module.code_section().unwrap().bodies()[2];
+		assert_eq!(body.code().elements()[0], GetLocal(0));
+		assert_eq!(body.locals(), &[elements::Local::new(1, ValueType::I32)]);
+		assert_eq!(signature(&module, 2), vec![ValueType::I32]);
+	}
+
+	#[test]
+	fn removes_dead_code() {
+		use parity_wasm::elements::Instruction::*;
+
+		let mut module = parse_wat(r#"
+			(module
+				(func (param i32) (result i32)
+					get_local 0
+					if
+						unreachable
+						nop
+					else
+						br 0
+						block
+							br 0
+						end
+						drop
+					end
+					block (result i32)
+						i32.const 1
+						br 0
+						i32.const 2
+					end
+					return
+					i32.const 3))
+		"#);
+
+		// The sum of the costs of the metered blocks, which the dead code no longer adds to.
+		let charged = |module: &elements::Module| {
+			let metered = crate::inject_gas_counter(module.clone(), &crate::rules::Set::default(), "env").unwrap();
+			get_function_body(&metered, 0).unwrap()
+				.windows(2)
+				.map(|pair| match pair {
+					[I32Const(cost), Call(0)] => *cost,
+					_ => 0,
+				})
+				.sum::<i32>()
+		};
+		assert_eq!(charged(&module), 14);
+
+		assert_eq!(remove_dead_code(&mut module), 7);
+		assert_eq!(get_function_body(&module, 0).unwrap(), &[
+			GetLocal(0),
+			If(elements::BlockType::NoResult),
+				Unreachable,
+			Else,
+				Br(0),
+			End,
+			Block(elements::BlockType::Value(ValueType::I32)),
+				I32Const(1),
+				Br(0),
+			End,
+			Return,
+			End,
+		][..]);
+		assert_eq!(charged(&module), 8);
+		validate_module(module);
+	}
+
+	#[test]
+	fn strips_custom_sections() {
+		let mut module = parse_wat("(module)");
+		module.set_custom_section("name", vec![]);
+		module.set_custom_section("producers", vec![1, 2, 3]);
+
+		let mut with_names = module.clone();
+		assert_eq!(strip_custom_sections(&mut with_names, true), 1);
+		assert_eq!(with_names.custom_sections().map(|s| s.name()).collect::<Vec<_>>(), vec!["name"]);
+
+		assert!(strip_names(&mut module));
+		assert_eq!(strip_custom_sections(&mut module, false), 1);
+		assert_eq!(module.custom_sections().count(), 0);
+	}
+}
@@ -0,0 +1,106 @@
+//! Helpers for writing tests against instrumented modules.
+//!
+//! These are the helpers used throughout the tests of this crate, exported behind the `testing`
+//! feature so that runtimes and other downstream crates can use them in their own test suites.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+
+/// Returns the instructions of the function body with the given index in the code section.
+///
+/// Note that `index` counts only the functions defined in the module, imported functions are not
+/// included.
+pub fn get_function_body(module: &elements::Module, index: usize)
+	-> Option<&[elements::Instruction]>
+{
+	module.code_section()
+		.and_then(|code_section| code_section.bodies().get(index))
+		.map(|func_body| func_body.code().elements())
+}
+
+/// Parse a module from its text representation without validating it.
+///
+/// This is convenient for writing expected outputs of passes, which are not always valid
+/// modules on their own (e.g. they call the injected gas function without importing it).
+///
+/// # Panics
+///
+/// Panics if the source can't be parsed.
+pub fn parse_wat(source: &str) -> elements::Module {
+	let module_bytes = wabt::Wat2Wasm::new()
+		.validate(false)
+		.convert(source)
+		.expect("failed to parse module");
+	elements::deserialize_buffer(module_bytes.as_ref())
+		.expect("failed to parse module")
+}
+
+/// Parse a module from its text representation, validating it.
+///
+/// # Panics
+///
+/// Panics if the source can't be parsed or the module is invalid.
+pub fn parse_valid_wat(source: &str) -> elements::Module {
+	let module_bytes = wabt::wat2wasm(source).expect("failed to parse module");
+	elements::deserialize_buffer(module_bytes.as_ref())
+		.expect("failed to parse module")
+}
+
+/// Convert a module from its text representation to the binary format, with the proposals the
+/// cargo features of this crate cover enabled, e.g. for analyses working on the binary.
+///
+/// # Panics
+///
+/// Panics if the source can't be parsed or the module is invalid.
+pub fn wat2wasm(source: &str) -> Vec<u8> {
+	let mut features = wabt::Features::new();
+	features.enable_sign_extension();
+	features.enable_sat_float_to_int();
+	features.enable_multi_value();
+	features.enable_bulk_memory();
+	features.enable_simd();
+	features.enable_threads();
+	wabt::wat2wasm_with_features(source, features).expect("failed to parse module")
+}
+
+/// Serialize the module and check that it is valid.
+///
+/// # Panics
+///
+/// Panics if the module can't be serialized or is invalid.
+pub fn validate_module(module: elements::Module) {
+	let binary = elements::serialize(module).expect("Failed to serialize");
+	wabt::Module::read_binary(&binary, &Default::default())
+		.expect("Wabt failed to read final binary")
+		.validate()
+		.expect("Invalid module");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_without_validation() {
+		// Calls the injected gas function without importing it, as expected outputs do.
+		let module = parse_wat(r#"
+			(module
+				(func
+					i32.const 1
+					call 0))
+		"#);
+
+		assert_eq!(
+			get_function_body(&module, 0).unwrap(),
+			&[elements::Instruction::I32Const(1), elements::Instruction::Call(0), elements::Instruction::End][..]
+		);
+		assert_eq!(get_function_body(&module, 1), None);
+	}
+
+	#[test]
+	fn converts_proposals() {
+		let bytes = wat2wasm("(module (func (param i32) (result i32) local.get 0 i32.extend8_s))");
+		validate_module(elements::deserialize_buffer(&bytes).unwrap());
+	}
+}
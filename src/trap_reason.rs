@@ -0,0 +1,54 @@
+//! Reasons stored in a trap reason global before the instrumentation traps.
+//!
+//! Engines report all traps of `unreachable` alike. Passes which trap this way can store why in a
+//! mutable `i32` global before, which is exported so that the host can tell running out of gas
+//! from a stack overflow without engine specific trap metadata. The global is mutable only to be
+//! set by the code: hosts are expected to read it after a trap and to reset it to `NONE` before
+//! reusing the instance. Exporting a mutable global requires the mutable globals extension.
+//!
+//! Passes configured with the same export name share the global.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+use parity_wasm::elements::{Instruction, ValueType};
+
+use crate::sections;
+
+/// No instrumentation trap occurred.
+pub const NONE: i32 = 0;
+/// The gas metering ran out of gas.
+pub const OUT_OF_GAS: i32 = 1;
+/// The stack height limiter exceeded the stack limit.
+pub const STACK_OVERFLOW: i32 = 2;
+/// The execution was interrupted on request of the host. No pass of this crate traps for this
+/// reason, it is reserved for interruption checks.
+pub const INTERRUPTED: i32 = 3;
+
+/// Add the trap reason global exported as `export_name`, unless a global is already exported
+/// under the name, and return its index.
+pub(crate) fn reason_global(module: &mut elements::Module, export_name: &str) -> u32 {
+	let existing = module.export_section()
+		.map_or(&[][..], |export_section| export_section.entries())
+		.iter()
+		.find_map(|entry| match *entry.internal() {
+			elements::Internal::Global(index) if entry.field() == export_name => Some(index),
+			_ => None,
+		});
+	if let Some(index) = existing {
+		return index;
+	}
+
+	let index = sections::push_global(module, elements::GlobalEntry::new(
+		elements::GlobalType::new(ValueType::I32, true),
+		elements::InitExpr::new(vec![Instruction::I32Const(NONE), Instruction::End]),
+	));
+	sections::push_export(module, elements::ExportEntry::new(export_name.into(), elements::Internal::Global(index)));
+	index
+}
+
+/// Append the instructions storing `reason` in the trap reason global `global`.
+pub(crate) fn set_reason(instructions: &mut Vec<Instruction>, global: u32, reason: i32) {
+	instructions.push(Instruction::I32Const(reason));
+	instructions.push(Instruction::SetGlobal(global));
+}
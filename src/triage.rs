@@ -0,0 +1,336 @@
+//! Diagnosis of modules which fail to deserialize.
+//!
+//! parity-wasm reports the first error it runs into without saying where it happened. `inspect`
+//! instead walks the module section by section, decoding each one on its own, so that it can tell
+//! how far the module is intact, which section is broken and what the likely cause is.
+
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+
+use crate::repair::{order, read_var_u32, section_name};
+use crate::version;
+
+/// A section whose header could be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionInfo {
+	pub id: u8,
+	/// Offset of the section id.
+	pub offset: usize,
+	/// Size of the payload as given in the header.
+	pub size: usize,
+}
+
+/// The first problem found in a module.
+#[derive(Debug, Clone)]
+pub enum Problem {
+	/// The module is shorter than the magic number and version.
+	TruncatedHeader,
+	/// The module does not start with the magic number.
+	InvalidMagic,
+	/// The module is for a version of the binary format other than the supported one.
+	UnsupportedVersion(u32),
+	/// The size of the section at `offset` is not a valid LEB128 integer.
+	InvalidSize { offset: usize },
+	/// The section at `offset` extends `missing` bytes beyond the end of the module.
+	TruncatedSection { id: u8, offset: usize, missing: usize },
+	/// The section at `offset` has an unknown id.
+	UnknownSection { id: u8, offset: usize },
+	/// The section at `offset` comes after a section which must follow it.
+	SectionOutOfOrder { id: u8, offset: usize },
+	/// The section at `offset` is the second one of its kind.
+	DuplicatedSection { id: u8, offset: usize },
+	/// The contents of the section at `offset` can't be decoded.
+	MalformedSection { id: u8, offset: usize, error: elements::Error },
+	/// All sections can be decoded, but they are inconsistent with each other.
+	Inconsistent(elements::Error),
+}
+
+impl Problem {
+	/// The likely cause of the problem.
+	pub fn cause(&self) -> &'static str {
+		use parity_wasm::elements::Error::*;
+
+		match self {
+			Problem::TruncatedHeader | Problem::TruncatedSection { .. } =>
+				"the file is truncated, e.g. by an interrupted download or copy",
+			Problem::InvalidMagic => "the file is not a WebAssembly binary, e.g. the text format",
+			Problem::UnsupportedVersion(_) =>
+				"the module is for another version of the binary format, or the header is corrupted",
+			Problem::InvalidSize { .. } =>
+				"the bytes are corrupted, or the size is encoded with more bytes than LEB128 allows",
+			Problem::UnknownSection { .. } =>
+				"the bytes are corrupted, or the previous section is longer than its header says",
+			Problem::SectionOutOfOrder { .. } | Problem::DuplicatedSection { .. } =>
+				"the producer emitted a nonstandard section layout, which `repair` can fix",
+			// `return_call` and `return_call_indirect` of the tail call proposal.
+			Problem::MalformedSection { error: UnknownOpcode(0x12), .. }
+			| Problem::MalformedSection { error: UnknownOpcode(0x13), .. } =>
+				"the code uses tail calls, which parity-wasm does not support",
+			// The prefix shared by the saturating float-to-int conversions, e.g. `i32.trunc_sat_f32_s`,
+			// `table.grow`, `table.size`, `table.fill` and the bulk memory operations, which parity-wasm
+			// only decodes with the `bulk` feature.
+			Problem::MalformedSection { error: UnknownOpcode(0xfc), .. } =>
+				"the code uses saturating float-to-int conversions or `table.grow`, `table.size` and `table.fill`, which parity-wasm does not support, or bulk memory operations without the `bulk` feature",
+			// With the `bulk` feature, the opcodes of the conversions following the prefix are unknown.
+			#[cfg(feature = "bulk")]
+			Problem::MalformedSection { error: UnknownOpcode(0x00..=0x07), .. } =>
+				"the code uses saturating float-to-int conversions, which parity-wasm does not support, or is corrupted",
+			// With the `bulk` feature, the opcodes of the table instructions following the prefix are unknown.
+			#[cfg(feature = "bulk")]
+			Problem::MalformedSection { error: UnknownOpcode(0x0f..=0x11), .. } =>
+				"the code uses `table.grow`, `table.size` or `table.fill`, which parity-wasm does not support",
+			// `select` with types, `table.get`, `table.set`, `ref.null`, `ref.is_null`, `ref.func` and
+			// the `funcref` and `externref` types of the reference types proposal.
+			Problem::MalformedSection { error: UnknownOpcode(0x1c), .. }
+			| Problem::MalformedSection { error: UnknownOpcode(0x25..=0x26), .. }
+			| Problem::MalformedSection { error: UnknownOpcode(0xd0..=0xd2), .. }
+			| Problem::MalformedSection { error: UnknownValueType(-0x11..=-0x10), .. }
+			| Problem::MalformedSection { error: UnknownTableElementType(-0x11), .. } =>
+				"the module uses reference types, which parity-wasm does not support",
+			Problem::MalformedSection { error: UnknownOpcode(_), .. } =>
+				"the code uses instructions of a proposal which is not enabled, or is corrupted",
+			#[cfg(feature = "simd")]
+			Problem::MalformedSection { error: UnknownSimdOpcode(_), .. } =>
+				"the code uses SIMD instructions which parity-wasm does not support, or is corrupted",
+			// The flag of 64-bit limits of the memory64 proposal.
+			Problem::MalformedSection { error: InvalidLimitsFlags(flags), .. } if flags & 0x04 != 0 =>
+				"the module uses 64-bit memories, which parity-wasm does not support",
+			Problem::MalformedSection { error: UnknownValueType(_), .. } =>
+				"the module uses value or block types of a proposal which is not supported, or is corrupted",
+			Problem::MalformedSection { error: UnexpectedEof, .. }
+			| Problem::MalformedSection { error: InconsistentLength { .. }, .. } =>
+				"the size of the section does not match its contents",
+			Problem::MalformedSection { .. } => "the contents of the section are corrupted",
+			Problem::Inconsistent(_) =>
+				"the number of function declarations and bodies differs, e.g. after a bad merge of sections",
+		}
+	}
+}
+
+impl fmt::Display for Problem {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Problem::TruncatedHeader => write!(f, "Truncated header"),
+			Problem::InvalidMagic => write!(f, "Invalid magic number"),
+			Problem::UnsupportedVersion(version) => write!(f, "Unsupported version {}", version),
+			Problem::InvalidSize { offset } => write!(f, "Invalid section size at offset {}", offset),
+			Problem::TruncatedSection { id, offset, missing } => write!(
+				f, "The {} section at offset {} lacks {} bytes", section_name(*id), offset, missing,
+			),
+			Problem::UnknownSection { id, offset } =>
+				write!(f, "Unknown section id {} at offset {}", id, offset),
+			Problem::SectionOutOfOrder { id, offset } =>
+				write!(f, "The {} section at offset {} is out of order", section_name(*id), offset),
+			Problem::DuplicatedSection { id, offset } =>
+				write!(f, "The {} section at offset {} is duplicated", section_name(*id), offset),
+			Problem::MalformedSection { id, offset, error } =>
+				write!(f, "The {} section at offset {} is malformed: {}", section_name(*id), offset, error),
+			Problem::Inconsistent(error) => write!(f, "Inconsistent sections: {}", error),
+		}
+	}
+}
+
+/// Result of the inspection of a module.
+#[derive(Debug, Clone)]
+pub struct Report {
+	/// The sections whose headers could be read, including the one with the problem.
+	pub sections: Vec<SectionInfo>,
+	/// Number of bytes from the start of the module up to the first problem, which is the whole
+	/// module if its sections are only inconsistent with each other.
+	pub valid_bytes: usize,
+	/// The first problem found, if any.
+	pub problem: Option<Problem>,
+}
+
+impl Report {
+	fn problem(sections: Vec<SectionInfo>, valid_bytes: usize, problem: Problem) -> Self {
+		Report { sections, valid_bytes, problem: Some(problem) }
+	}
+}
+
+/// Inspect the module `bytes`, finding the first problem which prevents deserialization.
+pub fn inspect(bytes: &[u8]) -> Report {
+	const MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6d];
+
+	if bytes.len() >= MAGIC.len() && bytes[..MAGIC.len()] != MAGIC {
+		return Report::problem(Vec::new(), 0, Problem::InvalidMagic);
+	}
+	if bytes.len() < 8 {
+		return Report::problem(Vec::new(), bytes.len(), Problem::TruncatedHeader);
+	}
+	let found = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+	if found != version::SUPPORTED {
+		return Report::problem(Vec::new(), MAGIC.len(), Problem::UnsupportedVersion(found));
+	}
+
+	let mut sections = Vec::new();
+	let mut last_order = 0;
+	let mut pos = 8;
+	while pos < bytes.len() {
+		let offset = pos;
+		let id = bytes[pos];
+		pos += 1;
+		let size = match read_var_u32(bytes, &mut pos) {
+			Some(size) => size as usize,
+			None if pos >= bytes.len() =>
+				return Report::problem(sections, offset, Problem::TruncatedSection { id, offset, missing: 1 }),
+			None => return Report::problem(sections, offset, Problem::InvalidSize { offset }),
+		};
+		sections.push(SectionInfo { id, offset, size });
+
+		if id != 0 {
+			let section_order = match order(id) {
+				Some(section_order) => section_order,
+				None => return Report::problem(sections, offset, Problem::UnknownSection { id, offset }),
+			};
+			if section_order == last_order {
+				return Report::problem(sections, offset, Problem::DuplicatedSection { id, offset });
+			}
+			if section_order < last_order {
+				return Report::problem(sections, offset, Problem::SectionOutOfOrder { id, offset });
+			}
+			last_order = section_order;
+		}
+
+		let end = pos + size;
+		if end > bytes.len() {
+			let missing = end - bytes.len();
+			return Report::problem(sections, offset, Problem::TruncatedSection { id, offset, missing });
+		}
+		if let Err(error) = elements::deserialize_buffer::<elements::Section>(&bytes[offset..end]) {
+			return Report::problem(sections, offset, Problem::MalformedSection { id, offset, error });
+		}
+		pos = end;
+	}
+
+	let problem = elements::deserialize_buffer::<elements::Module>(bytes).err().map(Problem::Inconsistent);
+	Report { sections, valid_bytes: bytes.len(), problem }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn module() -> Vec<u8> {
+		wabt::wat2wasm(r#"
+			(module
+				(memory 1)
+				(func (export "call") (param i32) (result i32)
+					get_local 0))
+		"#).unwrap()
+	}
+
+	#[test]
+	fn valid_module() {
+		let bytes = module();
+		let report = inspect(&bytes);
+		assert!(report.problem.is_none());
+		assert_eq!(report.valid_bytes, bytes.len());
+		assert_eq!(report.sections.iter().map(|section| section.id).collect::<Vec<_>>(), vec![1, 3, 5, 7, 10]);
+	}
+
+	#[test]
+	fn truncated_module() {
+		let bytes = module();
+		let report = inspect(&bytes[..bytes.len() - 2]);
+		let code = *report.sections.last().unwrap();
+		assert_eq!(code.id, 10);
+		assert_eq!(report.valid_bytes, code.offset);
+		match report.problem {
+			Some(Problem::TruncatedSection { id: 10, missing: 2, .. }) => {}
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+
+		assert!(matches!(inspect(&bytes[..6]).problem, Some(Problem::TruncatedHeader)));
+		assert!(matches!(inspect(b"(module)").problem, Some(Problem::InvalidMagic)));
+	}
+
+	#[test]
+	fn malformed_code() {
+		let mut bytes = module();
+		// Replace the `local.get` opcode with one which does not exist.
+		let len = bytes.len();
+		assert_eq!(bytes[len - 3], 0x20);
+		bytes[len - 3] = 0xff;
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 10, error: elements::Error::UnknownOpcode(0xff), .. }) =>
+				assert!(problem.cause().contains("proposal")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+
+		// Use `return_call 0` instead, which parity-wasm can't decode either.
+		bytes[len - 3] = 0x12;
+		bytes[len - 2] = 0x00;
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 10, .. }) => assert!(problem.cause().contains("tail calls")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+	}
+
+	#[test]
+	fn saturating_conversions() {
+		let mut bytes = module();
+		// Replace `local.get 0` with `i32.trunc_sat_f32_s`.
+		let len = bytes.len();
+		bytes[len - 3] = 0xfc;
+		bytes[len - 2] = 0x00;
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 10, .. }) =>
+				assert!(problem.cause().contains("saturating float-to-int")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+	}
+
+	#[test]
+	fn reference_types() {
+		let mut bytes = module();
+		// Replace `local.get 0` with `ref.null func`.
+		let len = bytes.len();
+		bytes[len - 3] = 0xd0;
+		bytes[len - 2] = 0x70;
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 10, .. }) => assert!(problem.cause().contains("reference types")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+
+		// A type section declaring a function with a `funcref` parameter.
+		let bytes = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x01, 0x70, 0x00];
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 1, .. }) => assert!(problem.cause().contains("reference types")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+
+		// A table section declaring a table of `externref`.
+		let bytes = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x04, 0x04, 0x01, 0x6f, 0x00, 0x01];
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 4, .. }) => assert!(problem.cause().contains("reference types")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+	}
+
+	#[test]
+	fn table_grow() {
+		let mut bytes = module();
+		// Replace `local.get 0` with `table.grow 0`.
+		let len = bytes.len();
+		bytes[len - 3] = 0xfc;
+		bytes[len - 2] = 0x0f;
+		bytes[len - 1] = 0x00;
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 10, .. }) => assert!(problem.cause().contains("`table.grow`")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+	}
+
+	#[test]
+	fn memory64() {
+		// A memory section declaring a 64-bit memory of one page.
+		let bytes = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x04, 0x01];
+		match inspect(&bytes).problem {
+			Some(problem @ Problem::MalformedSection { id: 5, .. }) => assert!(problem.cause().contains("64-bit")),
+			problem => panic!("unexpected problem {:?}", problem),
+		}
+	}
+}
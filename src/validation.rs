@@ -0,0 +1,700 @@
+//! Validation of modules, e.g. of the output of the passes.
+//!
+//! The passes assume valid input and are meant to produce valid output, which a bug in one of them
+//! can break without anything failing until the module is loaded by a runtime. `validate` checks
+//! a module without a round trip through an external validator: the order of the sections, the
+//! indices as checked by `check::well_formed`, the initializers, the start function, the export
+//! names and the types of the instructions of all function bodies. Its errors tell the function
+//! and the position of the offending instruction.
+//!
+//! The passes can be configured to validate their output, see `GasConfig::with_output_validation`
+//! and `LimiterConfig::with_output_validation`, which the pipeline follows as well.
+//!
+//! The instructions of the MVP and of the sign extension and bulk memory proposals are type checked
+//! in full. The ones of the SIMD and atomics proposals are only checked to find enough values on
+//! the stack, whose types are not checked.
+
+use crate::std::collections::BTreeSet;
+use crate::std::fmt;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, BlockType, External, FunctionType, Instruction, Type, ValueType};
+
+use crate::check;
+use crate::repair::order;
+
+/// Something an initializer expression initializes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Initializer {
+	/// A global, by index in the global index space.
+	Global(u32),
+	/// The offset of the element segment at the index in the element section.
+	Element(usize),
+	/// The offset of the data segment at the index in the data section.
+	Data(usize),
+}
+
+/// Why an instruction is not typed correctly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+	/// A value of type `expected` is taken from the stack, but the value on top is of type `found`,
+	/// or the block has no value on the stack if it is `None`.
+	Mismatch { expected: ValueType, found: Option<ValueType> },
+	/// A value is taken from the stack, but the block has none on it.
+	MissingValue,
+	/// The block leaves `count` values on the stack besides its results.
+	ExtraValues { count: usize },
+	/// The targets of a `br_table` take different values.
+	BranchTargets,
+	/// An `if` with a result has no `else`.
+	MissingElse,
+	/// `set_global` assigns the immutable global with the given index.
+	ImmutableGlobal(u32),
+	/// The instruction accesses the memory, but the module has none.
+	NoMemory,
+	/// The alignment of a memory access exceeds the natural one, both as exponents of 2.
+	Alignment { align: u32, max: u32 },
+}
+
+impl fmt::Display for TypeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TypeError::Mismatch { expected, found: Some(found) } => write!(f, "expected {}, found {}", expected, found),
+			TypeError::Mismatch { expected, found: None } => write!(f, "expected {}, found an empty stack", expected),
+			TypeError::MissingValue => write!(f, "expected a value, found an empty stack"),
+			TypeError::ExtraValues { count } => write!(f, "{} values left on the stack", count),
+			TypeError::BranchTargets => write!(f, "br_table targets take different values"),
+			TypeError::MissingElse => write!(f, "if with a result has no else"),
+			TypeError::ImmutableGlobal(index) => write!(f, "global {} is immutable", index),
+			TypeError::NoMemory => write!(f, "the module has no memory"),
+			TypeError::Alignment { align, max } =>
+				write!(f, "alignment 2^{} exceeds the natural alignment 2^{}", align, max),
+		}
+	}
+}
+
+/// Error of `validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The section at `index` among the sections of the module, with the given id, comes after one
+	/// which must follow it or after another one of its kind.
+	SectionOrder { id: u8, index: usize },
+	/// An index refers to nothing or the blocks of a body aren't properly nested.
+	Malformed(check::Error),
+	/// The initializer is not a constant of the type `expected`.
+	Initializer { of: Initializer, expected: ValueType },
+	/// The start function with the given index takes parameters or returns results.
+	StartSignature(u32),
+	/// Several exports have the given name.
+	DuplicateExport(String),
+	/// The instruction at `pos` in the body of the function with the given index, in the function
+	/// index space, is not typed correctly.
+	Body { function: u32, pos: usize, error: TypeError },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::SectionOrder { id, index } =>
+				write!(f, "Section {} with id {} is out of order or duplicated", index, id),
+			Error::Malformed(e) => write!(f, "{}", e),
+			Error::Initializer { of, expected } =>
+				write!(f, "Initializer of {:?} is not a constant {}", of, expected),
+			Error::StartSignature(function) =>
+				write!(f, "Start function {} takes parameters or returns results", function),
+			Error::DuplicateExport(name) => write!(f, "Duplicate export `{}`", name),
+			Error::Body { function, pos, error } =>
+				write!(f, "Type error (function {}, instruction {}): {}", function, pos, error),
+		}
+	}
+}
+
+/// Id of a section which is not custom, as in the binary format.
+fn section_id(section: &elements::Section) -> Option<u8> {
+	use parity_wasm::elements::Section::*;
+
+	match section {
+		Unparsed { id, .. } if *id != 0 => Some(*id),
+		Type(_) => Some(1),
+		Import(_) => Some(2),
+		Function(_) => Some(3),
+		Table(_) => Some(4),
+		Memory(_) => Some(5),
+		Global(_) => Some(6),
+		Export(_) => Some(7),
+		Start(_) => Some(8),
+		Element(_) => Some(9),
+		Code(_) => Some(10),
+		Data(_) => Some(11),
+		DataCount(_) => Some(12),
+		_ => None,
+	}
+}
+
+/// Type of the value an initializer expression computes, if it is a single constant or global.
+fn init_expr_type(init_expr: &elements::InitExpr, globals: &[(ValueType, bool)]) -> Option<ValueType> {
+	match init_expr.code() {
+		[instruction, Instruction::End] => match *instruction {
+			Instruction::I32Const(_) => Some(ValueType::I32),
+			Instruction::I64Const(_) => Some(ValueType::I64),
+			Instruction::F32Const(_) => Some(ValueType::F32),
+			Instruction::F64Const(_) => Some(ValueType::F64),
+			Instruction::GetGlobal(index) => globals.get(index as usize).map(|(value_type, _)| *value_type),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+/// Validate the `module`, see the module-level documentation for what is checked.
+pub fn validate(module: &elements::Module) -> Result<(), Error> {
+	let mut last = 0;
+	for (index, section) in module.sections().iter().enumerate() {
+		if let Some(id) = section_id(section) {
+			let position = order(id).unwrap_or(u8::MAX);
+			if position <= last {
+				return Err(Error::SectionOrder { id, index });
+			}
+			last = position;
+		}
+	}
+
+	check::well_formed(module).map_err(Error::Malformed)?;
+
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let imports = module.import_section().map_or(&[][..], |import_section| import_section.entries());
+	let func_type = |type_ref: u32| match &types[type_ref as usize] {
+		Type::Function(func_type) => func_type,
+	};
+	let functions = imports.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Function(type_ref) => Some(func_type(*type_ref)),
+			_ => None,
+		})
+		.chain(
+			module.function_section()
+				.map_or(&[][..], |function_section| function_section.entries())
+				.iter()
+				.map(|func| func_type(func.type_ref()))
+		)
+		.collect::<Vec<_>>();
+
+	// Initializers may only refer to imported globals, which `well_formed` checks.
+	let mut globals = imports.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Global(global_type) => Some((global_type.content_type(), global_type.is_mutable())),
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+	for entry in module.global_section().map_or(&[][..], |global_section| global_section.entries()) {
+		let expected = entry.global_type().content_type();
+		if init_expr_type(entry.init_expr(), &globals) != Some(expected) {
+			return Err(Error::Initializer { of: Initializer::Global(globals.len() as u32), expected });
+		}
+		globals.push((expected, entry.global_type().is_mutable()));
+	}
+	let offsets = module.elements_section()
+		.map_or(&[][..], |elements_section| elements_section.entries())
+		.iter()
+		.enumerate()
+		.map(|(index, segment)| (Initializer::Element(index), segment.offset()))
+		.chain(
+			module.data_section()
+				.map_or(&[][..], |data_section| data_section.entries())
+				.iter()
+				.enumerate()
+				.map(|(index, segment)| (Initializer::Data(index), segment.offset()))
+		);
+	for (of, offset) in offsets {
+		if let Some(offset) = offset {
+			if init_expr_type(offset, &globals) != Some(ValueType::I32) {
+				return Err(Error::Initializer { of, expected: ValueType::I32 });
+			}
+		}
+	}
+
+	if let Some(start) = module.start_section() {
+		let func_type = functions[start as usize];
+		if !func_type.params().is_empty() || !func_type.results().is_empty() {
+			return Err(Error::StartSignature(start));
+		}
+	}
+
+	let mut names = BTreeSet::new();
+	for entry in module.export_section().map_or(&[][..], |export_section| export_section.entries()) {
+		if !names.insert(entry.field()) {
+			return Err(Error::DuplicateExport(entry.field().into()));
+		}
+	}
+
+	let imported_funcs = module.import_count(elements::ImportCountType::Function);
+	let bodies = module.code_section().map_or(&[][..], |code_section| code_section.bodies());
+	for (index, body) in bodies.iter().enumerate() {
+		let function = (imported_funcs + index) as u32;
+		let func_type = functions[function as usize];
+		let mut checker = BodyChecker {
+			types,
+			functions: &functions,
+			globals: &globals,
+			memory: module.memory_space() != 0,
+			locals: Vec::new(),
+			values: Vec::new(),
+			blocks: Vec::new(),
+		};
+		let mut end = 0;
+		for value_type in func_type.params() {
+			end += 1;
+			checker.locals.push((end, *value_type));
+		}
+		for local in body.locals() {
+			end += u64::from(local.count());
+			checker.locals.push((end, local.value_type()));
+		}
+		checker.push_block(BlockKind::Function, func_type.results());
+		for (pos, instruction) in body.code().elements().iter().enumerate() {
+			checker.check(instruction, func_type)
+				.map_err(|error| Error::Body { function, pos, error })?;
+		}
+	}
+
+	Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockKind {
+	Function,
+	Block,
+	Loop,
+	If,
+	Else,
+}
+
+/// A block enclosing the instructions being checked.
+struct ValidatedBlock {
+	kind: BlockKind,
+	results: Vec<ValueType>,
+	/// Number of values on the stack when the block was entered.
+	height: usize,
+	/// Whether the rest of the block can't be reached, so that the stack is polymorphic.
+	unreachable: bool,
+}
+
+impl ValidatedBlock {
+	/// The values a branch to the block takes.
+	fn label_types(&self) -> &[ValueType] {
+		if self.kind == BlockKind::Loop { &[] } else { &self.results }
+	}
+}
+
+/// Checks the types of the instructions of a function body.
+struct BodyChecker<'a> {
+	types: &'a [Type],
+	/// Type of every function, by function index.
+	functions: &'a [&'a FunctionType],
+	/// Type and mutability of every global, by global index.
+	globals: &'a [(ValueType, bool)],
+	/// Whether the module has a memory.
+	memory: bool,
+	/// The end of each run of locals of the same type along with the type, in the order of the
+	/// local indices.
+	locals: Vec<(u64, ValueType)>,
+	/// Types of the values on the stack, `None` for the ones of unknown type.
+	values: Vec<Option<ValueType>>,
+	blocks: Vec<ValidatedBlock>,
+}
+
+impl<'a> BodyChecker<'a> {
+	fn block(&self) -> &ValidatedBlock {
+		self.blocks.last().expect("the function body is a block until its end; qed")
+	}
+
+	fn push_block(&mut self, kind: BlockKind, results: &[ValueType]) {
+		self.blocks.push(ValidatedBlock { kind, results: results.to_vec(), height: self.values.len(), unreachable: false });
+	}
+
+	/// Pop the values of the current block and the block itself.
+	fn pop_block(&mut self) -> Result<ValidatedBlock, TypeError> {
+		let results = self.block().results.clone();
+		self.pop_all(&results)?;
+		let block = self.blocks.pop().expect("the function body is a block until its end; qed");
+		if self.values.len() != block.height {
+			return Err(TypeError::ExtraValues { count: self.values.len() - block.height });
+		}
+		Ok(block)
+	}
+
+	/// Make the stack of the current block polymorphic, as the rest of it can't be reached.
+	fn set_unreachable(&mut self) {
+		let height = self.block().height;
+		self.values.truncate(height);
+		self.blocks.last_mut().expect("the function body is a block until its end; qed").unreachable = true;
+	}
+
+	fn pop(&mut self) -> Result<Option<ValueType>, TypeError> {
+		let block = self.block();
+		if self.values.len() == block.height {
+			return if block.unreachable { Ok(None) } else { Err(TypeError::MissingValue) };
+		}
+		Ok(self.values.pop().expect("the stack holds the values of the block; qed"))
+	}
+
+	fn pop_expected(&mut self, expected: ValueType) -> Result<(), TypeError> {
+		match self.pop() {
+			Ok(Some(found)) if found != expected => Err(TypeError::Mismatch { expected, found: Some(found) }),
+			Ok(_) => Ok(()),
+			Err(_) => Err(TypeError::Mismatch { expected, found: None }),
+		}
+	}
+
+	/// Pop values of the `expected` types, the last one first.
+	fn pop_all(&mut self, expected: &[ValueType]) -> Result<(), TypeError> {
+		expected.iter().rev().try_for_each(|value_type| self.pop_expected(*value_type))
+	}
+
+	fn push(&mut self, value_type: ValueType) {
+		self.values.push(Some(value_type));
+	}
+
+	fn label_types(&self, label: u32) -> Vec<ValueType> {
+		self.blocks[self.blocks.len() - 1 - label as usize].label_types().to_vec()
+	}
+
+	fn local(&self, index: u32) -> ValueType {
+		let run = self.locals.partition_point(|(end, _)| *end <= u64::from(index));
+		self.locals[run].1
+	}
+
+	fn access(&mut self, align: u32, max: u32) -> Result<(), TypeError> {
+		if !self.memory {
+			return Err(TypeError::NoMemory);
+		}
+		if align > max {
+			return Err(TypeError::Alignment { align, max });
+		}
+		self.pop_expected(ValueType::I32)
+	}
+
+	fn load(&mut self, align: u32, max: u32, value_type: ValueType) -> Result<(), TypeError> {
+		self.access(align, max)?;
+		self.push(value_type);
+		Ok(())
+	}
+
+	fn store(&mut self, align: u32, max: u32, value_type: ValueType) -> Result<(), TypeError> {
+		self.pop_expected(value_type)?;
+		self.access(align, max)
+	}
+
+	/// Pop the values of the `params` types and push one of the `result` type.
+	fn operator(&mut self, params: &[ValueType], result: ValueType) -> Result<(), TypeError> {
+		self.pop_all(params)?;
+		self.push(result);
+		Ok(())
+	}
+
+	/// Pop `pops` values of any type and push `pushes` values of unknown types.
+	#[cfg(any(feature = "simd", feature = "atomics"))]
+	fn untyped(&mut self, pops: u32, pushes: u32) -> Result<(), TypeError> {
+		for _ in 0..pops {
+			self.pop()?;
+		}
+		self.values.extend((0..pushes).map(|_| None));
+		Ok(())
+	}
+
+	fn check(&mut self, instruction: &Instruction, func_type: &FunctionType) -> Result<(), TypeError> {
+		use parity_wasm::elements::Instruction::*;
+		use parity_wasm::elements::ValueType::{F32, F64, I32, I64};
+
+		let block_results = |block_type: &BlockType| match *block_type {
+			BlockType::Value(value_type) => vec![value_type],
+			BlockType::NoResult => Vec::new(),
+		};
+		match instruction {
+			Unreachable => self.set_unreachable(),
+			Nop => {}
+			Block(block_type) => self.push_block(BlockKind::Block, &block_results(block_type)),
+			Loop(block_type) => self.push_block(BlockKind::Loop, &block_results(block_type)),
+			If(block_type) => {
+				self.pop_expected(I32)?;
+				self.push_block(BlockKind::If, &block_results(block_type));
+			}
+			Else => {
+				let block = self.pop_block()?;
+				self.push_block(BlockKind::Else, &block.results);
+			}
+			End => {
+				let block = self.pop_block()?;
+				if block.kind == BlockKind::If && !block.results.is_empty() {
+					return Err(TypeError::MissingElse);
+				}
+				// The values of the function are its results, which aren't pushed anywhere.
+				if block.kind != BlockKind::Function {
+					self.values.extend(block.results.into_iter().map(Some));
+				}
+			}
+			Br(label) => {
+				self.pop_all(&self.label_types(*label))?;
+				self.set_unreachable();
+			}
+			BrIf(label) => {
+				self.pop_expected(I32)?;
+				let label_types = self.label_types(*label);
+				self.pop_all(&label_types)?;
+				self.values.extend(label_types.into_iter().map(Some));
+			}
+			BrTable(br_table_data) => {
+				self.pop_expected(I32)?;
+				let label_types = self.label_types(br_table_data.default);
+				if br_table_data.table.iter().any(|label| self.label_types(*label) != label_types) {
+					return Err(TypeError::BranchTargets);
+				}
+				self.pop_all(&label_types)?;
+				self.set_unreachable();
+			}
+			Return => {
+				self.pop_all(func_type.results())?;
+				self.set_unreachable();
+			}
+			Call(function) => {
+				let callee = self.functions[*function as usize];
+				self.pop_all(callee.params())?;
+				self.values.extend(callee.results().iter().map(|value_type| Some(*value_type)));
+			}
+			CallIndirect(type_ref, _) => {
+				self.pop_expected(I32)?;
+				let Type::Function(callee) = &self.types[*type_ref as usize];
+				self.pop_all(callee.params())?;
+				self.values.extend(callee.results().iter().map(|value_type| Some(*value_type)));
+			}
+			Drop => {
+				self.pop()?;
+			}
+			Select => {
+				self.pop_expected(I32)?;
+				let second = self.pop()?;
+				let first = match second {
+					Some(value_type) => {
+						self.pop_expected(value_type)?;
+						second
+					}
+					None => self.pop()?,
+				};
+				self.values.push(first);
+			}
+
+			GetLocal(index) => self.push(self.local(*index)),
+			SetLocal(index) => self.pop_expected(self.local(*index))?,
+			TeeLocal(index) => {
+				let value_type = self.local(*index);
+				self.pop_expected(value_type)?;
+				self.push(value_type);
+			}
+			GetGlobal(index) => self.push(self.globals[*index as usize].0),
+			SetGlobal(index) => {
+				let (value_type, mutable) = self.globals[*index as usize];
+				if !mutable {
+					return Err(TypeError::ImmutableGlobal(*index));
+				}
+				self.pop_expected(value_type)?;
+			}
+
+			I32Load(align, _) => self.load(*align, 2, I32)?,
+			I64Load(align, _) => self.load(*align, 3, I64)?,
+			F32Load(align, _) => self.load(*align, 2, F32)?,
+			F64Load(align, _) => self.load(*align, 3, F64)?,
+			I32Load8S(align, _) | I32Load8U(align, _) => self.load(*align, 0, I32)?,
+			I32Load16S(align, _) | I32Load16U(align, _) => self.load(*align, 1, I32)?,
+			I64Load8S(align, _) | I64Load8U(align, _) => self.load(*align, 0, I64)?,
+			I64Load16S(align, _) | I64Load16U(align, _) => self.load(*align, 1, I64)?,
+			I64Load32S(align, _) | I64Load32U(align, _) => self.load(*align, 2, I64)?,
+			I32Store(align, _) => self.store(*align, 2, I32)?,
+			I64Store(align, _) => self.store(*align, 3, I64)?,
+			F32Store(align, _) => self.store(*align, 2, F32)?,
+			F64Store(align, _) => self.store(*align, 3, F64)?,
+			I32Store8(align, _) => self.store(*align, 0, I32)?,
+			I32Store16(align, _) => self.store(*align, 1, I32)?,
+			I64Store8(align, _) => self.store(*align, 0, I64)?,
+			I64Store16(align, _) => self.store(*align, 1, I64)?,
+			I64Store32(align, _) => self.store(*align, 2, I64)?,
+			CurrentMemory(_) | GrowMemory(_) => {
+				if !self.memory {
+					return Err(TypeError::NoMemory);
+				}
+				if let GrowMemory(_) = instruction {
+					self.pop_expected(I32)?;
+				}
+				self.push(I32);
+			}
+
+			I32Const(_) => self.push(I32),
+			I64Const(_) => self.push(I64),
+			F32Const(_) => self.push(F32),
+			F64Const(_) => self.push(F64),
+
+			I32Eqz => self.operator(&[I32], I32)?,
+			I64Eqz => self.operator(&[I64], I32)?,
+			I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU =>
+				self.operator(&[I32, I32], I32)?,
+			I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU =>
+				self.operator(&[I64, I64], I32)?,
+			F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge => self.operator(&[F32, F32], I32)?,
+			F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => self.operator(&[F64, F64], I32)?,
+
+			I32Clz | I32Ctz | I32Popcnt => self.operator(&[I32], I32)?,
+			I64Clz | I64Ctz | I64Popcnt => self.operator(&[I64], I64)?,
+			F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt => self.operator(&[F32], F32)?,
+			F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => self.operator(&[F64], F64)?,
+
+			I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or | I32Xor
+			| I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr => self.operator(&[I32, I32], I32)?,
+			I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor
+			| I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => self.operator(&[I64, I64], I64)?,
+			F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => self.operator(&[F32, F32], F32)?,
+			F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => self.operator(&[F64, F64], F64)?,
+
+			I32WrapI64 => self.operator(&[I64], I32)?,
+			I32TruncSF32 | I32TruncUF32 | I32ReinterpretF32 => self.operator(&[F32], I32)?,
+			I32TruncSF64 | I32TruncUF64 => self.operator(&[F64], I32)?,
+			I64ExtendSI32 | I64ExtendUI32 => self.operator(&[I32], I64)?,
+			I64TruncSF32 | I64TruncUF32 => self.operator(&[F32], I64)?,
+			I64TruncSF64 | I64TruncUF64 | I64ReinterpretF64 => self.operator(&[F64], I64)?,
+			F32ConvertSI32 | F32ConvertUI32 | F32ReinterpretI32 => self.operator(&[I32], F32)?,
+			F32ConvertSI64 | F32ConvertUI64 => self.operator(&[I64], F32)?,
+			F32DemoteF64 => self.operator(&[F64], F32)?,
+			F64ConvertSI32 | F64ConvertUI32 => self.operator(&[I32], F64)?,
+			F64ConvertSI64 | F64ConvertUI64 | F64ReinterpretI64 => self.operator(&[I64], F64)?,
+			F64PromoteF32 => self.operator(&[F32], F64)?,
+
+			#[cfg(feature = "sign_ext")]
+			SignExt(sign_ext) => {
+				use parity_wasm::elements::SignExtInstruction::*;
+
+				match sign_ext {
+					I32Extend8S | I32Extend16S => self.operator(&[I32], I32)?,
+					I64Extend8S | I64Extend16S | I64Extend32S => self.operator(&[I64], I64)?,
+				}
+			}
+
+			#[cfg(feature = "simd")]
+			Simd(simd) => {
+				let (pops, pushes) = crate::visit::simd_stack_effect(simd);
+				self.untyped(pops, pushes)?;
+			}
+			#[cfg(feature = "atomics")]
+			Atomics(atomic) => {
+				let (pops, pushes) = crate::visit::atomic_stack_effect(atomic);
+				self.untyped(pops, pushes)?;
+			}
+			#[cfg(feature = "bulk")]
+			Bulk(elements::BulkInstruction::MemoryDrop(_)) | Bulk(elements::BulkInstruction::TableDrop(_)) => {}
+			#[cfg(feature = "bulk")]
+			Bulk(_) => self.pop_all(&[I32, I32, I32])?,
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::parse_wat;
+
+	fn with_body(instructions: Vec<Instruction>) -> elements::Module {
+		let mut module = parse_wat(r#"
+			(module
+				(global i32 (i32.const 0))
+				(func (param i32) (result i32)
+					get_local 0))
+		"#);
+		*module.code_section_mut().unwrap().bodies_mut()[0].code_mut().elements_mut() = instructions;
+		module
+	}
+
+	#[test]
+	fn valid_module() {
+		let module = parse_wat(r#"
+			(module
+				(import "env" "ext" (func $ext (param i64) (result f32)))
+				(memory 1)
+				(table 1 anyfunc)
+				(global $g (mut i64) (i64.const 0))
+				(elem (i32.const 0) $f)
+				(data (i32.const 8) "data")
+				(func $f (export "f") (param i32) (result i32)
+					(local f64)
+					block (result i32)
+						get_local 0
+						get_local 0
+						br_if 0
+						drop
+						get_global $g
+						call $ext
+						i32.trunc_s/f32
+						get_local 0
+						i32.load16_u offset=4
+						i32.add
+					end
+					block
+						loop
+							get_local 0
+							br_table 0 1
+						end
+					end
+					unreachable
+					f64.add
+					i32.trunc_s/f64)
+				(func (export "g")
+					i32.const 0
+					i32.const 0
+					call_indirect (param i32) (result i32)
+					if (result i64)
+						i64.const 1
+					else
+						i64.const 2
+					end
+					set_global $g))
+		"#);
+
+		assert_eq!(validate(&module), Ok(()));
+	}
+
+	#[test]
+	fn type_errors() {
+		use parity_wasm::elements::Instruction::*;
+
+		let error = |instructions| match validate(&with_body(instructions)) {
+			Err(Error::Body { function: 0, pos, error }) => (pos, error),
+			other => panic!("Unexpected result {:?}", other),
+		};
+		assert_eq!(
+			error(vec![I64Const(0), End]),
+			(1, TypeError::Mismatch { expected: ValueType::I32, found: Some(ValueType::I64) })
+		);
+		assert_eq!(error(vec![GetLocal(0), GetLocal(0), End]), (2, TypeError::ExtraValues { count: 1 }));
+		assert_eq!(error(vec![Drop, End]), (0, TypeError::MissingValue));
+		assert_eq!(error(vec![GetLocal(0), SetGlobal(0), GetLocal(0), End]), (1, TypeError::ImmutableGlobal(0)));
+		assert_eq!(error(vec![GetLocal(0), I32Load(0, 0), End]), (1, TypeError::NoMemory));
+		assert_eq!(
+			error(vec![GetLocal(0), If(BlockType::Value(ValueType::I32)), GetLocal(0), End, End]),
+			(3, TypeError::MissingElse)
+		);
+		// The stack of unreachable code takes values of any type.
+		assert_eq!(validate(&with_body(vec![Unreachable, F32Add, Drop, End])), Ok(()));
+	}
+
+	#[test]
+	fn module_errors() {
+		let mut module = with_body(vec![Instruction::GetLocal(0), Instruction::End]);
+		let global = module.sections_mut().remove(2);
+		module.sections_mut().push(global);
+		assert_eq!(validate(&module), Err(Error::SectionOrder { id: 6, index: 3 }));
+
+		let module = with_body(vec![Instruction::Call(1), Instruction::End]);
+		assert!(matches!(validate(&module), Err(Error::Malformed(_))));
+
+		let module = parse_wat(r#"(module (func (export "f")) (export "f" (func 0)))"#);
+		assert_eq!(validate(&module), Err(Error::DuplicateExport("f".into())));
+	}
+}
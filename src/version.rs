@@ -0,0 +1,114 @@
+//! Checking of the version of the binary format.
+//!
+//! parity-wasm supports version 1 of the binary format only and rejects other versions with the
+//! same kind of error as any corrupted module. The functions of this module check the version
+//! before deserializing, so that a toolchain emitting a newer version is reported as such.
+
+use crate::std::fmt;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+
+/// The version of the binary format supported by parity-wasm.
+pub const SUPPORTED: u32 = 1;
+
+const MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6d];
+
+/// Error of the deserialization of a module.
+#[derive(Debug)]
+pub enum Error {
+	/// The module is for a version of the binary format other than the supported one.
+	UnsupportedVersion { found: u32, supported: u32 },
+	/// The module can't be deserialized for another reason.
+	Decoding(elements::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::UnsupportedVersion { found, supported } => write!(
+				f, "Unsupported version {} of the binary format, only version {} is supported", found, supported,
+			),
+			Error::Decoding(err) => write!(f, "{}", err),
+		}
+	}
+}
+
+impl From<elements::Error> for Error {
+	fn from(err: elements::Error) -> Self {
+		Error::Decoding(err)
+	}
+}
+
+/// The version of the binary format of the module `bytes`.
+///
+/// Returns `None` if `bytes` does not start with the magic number and version.
+pub fn version(bytes: &[u8]) -> Option<u32> {
+	if bytes.len() < 8 || bytes[..MAGIC.len()] != MAGIC {
+		return None;
+	}
+	Some(u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]))
+}
+
+/// Like `parity_wasm::elements::deserialize_buffer`, but fails with `Error::UnsupportedVersion`
+/// if the module is for another version of the binary format.
+pub fn deserialize_buffer(bytes: &[u8]) -> Result<elements::Module, Error> {
+	match version(bytes) {
+		Some(found) if found != SUPPORTED => Err(Error::UnsupportedVersion { found, supported: SUPPORTED }),
+		_ => Ok(elements::deserialize_buffer(bytes)?),
+	}
+}
+
+/// Deserialize the module `bytes` as if it was for the supported version of the binary format.
+///
+/// This is a best effort for modules of a newer version which don't use any of its changes. If
+/// such a module can't be deserialized, the error is still `Error::UnsupportedVersion`, as the
+/// version is the most likely cause. The deserialized module has the supported version.
+pub fn deserialize_buffer_any_version(bytes: &[u8]) -> Result<elements::Module, Error> {
+	let found = match version(bytes) {
+		Some(found) if found != SUPPORTED => found,
+		_ => return deserialize_buffer(bytes),
+	};
+	let mut patched = Vec::with_capacity(bytes.len());
+	patched.extend_from_slice(&bytes[..MAGIC.len()]);
+	patched.extend_from_slice(&SUPPORTED.to_le_bytes());
+	patched.extend_from_slice(&bytes[8..]);
+	elements::deserialize_buffer(&patched)
+		.map_err(|_| Error::UnsupportedVersion { found, supported: SUPPORTED })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn module(version: u32) -> Vec<u8> {
+		let mut bytes = wabt::wat2wasm("(module (func (export \"call\")))").unwrap();
+		bytes[4..8].copy_from_slice(&version.to_le_bytes());
+		bytes
+	}
+
+	#[test]
+	fn rejects_other_versions() {
+		assert_eq!(version(&module(2)), Some(2));
+		assert!(deserialize_buffer(&module(1)).is_ok());
+		match deserialize_buffer(&module(2)) {
+			Err(Error::UnsupportedVersion { found: 2, supported: 1 }) => {}
+			result => panic!("unexpected result {:?}", result),
+		}
+		assert!(matches!(deserialize_buffer(b"\0asm\x01\0\0\0\x7f"), Err(Error::Decoding(_))));
+	}
+
+	#[test]
+	fn parses_other_versions_on_request() {
+		let module = deserialize_buffer_any_version(&module(2)).unwrap();
+		assert_eq!(module.version(), SUPPORTED);
+		assert_eq!(module.export_section().map(|s| s.entries().len()), Some(1));
+
+		let mut corrupted = self::module(2);
+		corrupted.push(0x7f);
+		assert!(matches!(
+			deserialize_buffer_any_version(&corrupted),
+			Err(Error::UnsupportedVersion { found: 2, .. })
+		));
+	}
+}
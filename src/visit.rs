@@ -0,0 +1,480 @@
+//! Visitor over the instruction stream of a function body.
+//!
+//! Most passes in this crate walk the instructions of a function body while keeping track of the
+//! control blocks opened by `block`, `loop` and `if` and closed by `end`. The `visit` function
+//! does this bookkeeping once and reports the structure to a `Visitor`, sax-style:
+//!
+//! - `block`, `loop` and `if` are reported by `Visitor::enter_block` after the new frame is pushed,
+//! - `else` is reported by `Visitor::visit_else`,
+//! - `end` is reported by `Visitor::leave_block` after the frame is popped,
+//! - every other instruction is reported by `Visitor::visit_instruction`.
+//!
+//! The function body itself forms the outermost frame. It is open before the first instruction
+//! is visited and is closed by the final `end` of the body.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Instruction, Type};
+
+/// Error returned by `visit`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error<E> {
+	/// One of the `Visitor` callbacks returned an error.
+	Visitor(E),
+	/// An `else` was found at the given position outside of an `if` block.
+	UnexpectedElse(usize),
+	/// An instruction was found at the given position after the final `end` of the body.
+	TrailingInstruction(usize),
+	/// The body ended while some control blocks, including the function itself, were still open.
+	UnclosedBlocks,
+}
+
+/// Kind of a control frame.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameKind {
+	/// The implicit frame of the function body.
+	Function,
+	/// Frame opened by `block`.
+	Block,
+	/// Frame opened by `loop`. Branches to it jump to its beginning.
+	Loop,
+	/// Frame opened by `if`.
+	If,
+}
+
+/// A control frame opened by `block`, `loop`, `if` or by the function itself.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frame {
+	/// Kind of the frame.
+	pub kind: FrameKind,
+	/// Position of the instruction which opened the frame. This is 0 for the function frame.
+	pub start_pos: usize,
+}
+
+impl Frame {
+	/// Whether branches to this frame jump to its beginning rather than to its end.
+	pub fn is_loop(&self) -> bool {
+		self.kind == FrameKind::Loop
+	}
+}
+
+/// The stack of currently open control frames.
+///
+/// The first frame on the stack always corresponds to the function body.
+#[derive(Debug, Default)]
+pub struct Frames {
+	stack: Vec<Frame>,
+}
+
+impl Frames {
+	/// Number of open frames, including the function frame.
+	pub fn len(&self) -> usize {
+		self.stack.len()
+	}
+
+	/// Whether there are no open frames. This is only the case after the final `end`.
+	pub fn is_empty(&self) -> bool {
+		self.stack.is_empty()
+	}
+
+	/// Returns the frame at the given stack index, where 0 is the function frame.
+	pub fn get(&self, index: usize) -> Option<&Frame> {
+		self.stack.get(index)
+	}
+
+	/// Returns the innermost open frame.
+	pub fn top(&self) -> Option<&Frame> {
+		self.stack.last()
+	}
+
+	/// Returns the stack index of the frame targeted by a branch with the given relative `label`.
+	pub fn target_index(&self, label: u32) -> Option<usize> {
+		self.stack.len().checked_sub(1)?.checked_sub(label as usize)
+	}
+
+	/// Returns the frame targeted by a branch with the given relative `label`.
+	pub fn target(&self, label: u32) -> Option<&Frame> {
+		self.target_index(label).and_then(|index| self.get(index))
+	}
+
+	/// Iterate over the open frames, from the function frame to the innermost one.
+	pub fn iter(&self) -> impl Iterator<Item = &Frame> {
+		self.stack.iter()
+	}
+}
+
+/// Callbacks invoked by `visit` while walking a function body.
+///
+/// All methods do nothing by default, so implementors only need to override the events they
+/// are interested in. Returning an error aborts the walk.
+pub trait Visitor {
+	/// Error type returned by the callbacks.
+	type Error;
+
+	/// Called for `block`, `loop` and `if`. The new frame is already on top of `frames`.
+	fn enter_block(
+		&mut self,
+		_pos: usize,
+		_instruction: &Instruction,
+		_frames: &Frames,
+	) -> Result<(), Self::Error> {
+		Ok(())
+	}
+
+	/// Called for `else`. The `if` frame is on top of `frames`.
+	fn visit_else(&mut self, _pos: usize, _frames: &Frames) -> Result<(), Self::Error> {
+		Ok(())
+	}
+
+	/// Called for `end`. The closed `frame` is already removed from `frames`.
+	fn leave_block(
+		&mut self,
+		_pos: usize,
+		_frame: &Frame,
+		_frames: &Frames,
+	) -> Result<(), Self::Error> {
+		Ok(())
+	}
+
+	/// Called for every other instruction.
+	fn visit_instruction(
+		&mut self,
+		_pos: usize,
+		_instruction: &Instruction,
+		_frames: &Frames,
+	) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+/// Whether the `unreachable` at `pos` is the second reserved byte of a `memory.copy` or
+/// `table.copy` instruction.
+///
+/// parity-wasm decodes these instructions with a single reserved byte, as in earlier drafts of the
+/// bulk memory operations, so the second one comes out as an `unreachable`. It is encoded back to
+/// the same byte, but must not be treated as an instruction of its own. Without the `bulk`
+/// feature, this is always `false`.
+pub fn is_copy_padding(instructions: &[Instruction], pos: usize) -> bool {
+	#[cfg(feature = "bulk")]
+	{
+		use parity_wasm::elements::BulkInstruction::{MemoryCopy, TableCopy};
+
+		pos > 0
+			&& instructions[pos] == Instruction::Unreachable
+			&& matches!(instructions[pos - 1], Instruction::Bulk(MemoryCopy) | Instruction::Bulk(TableCopy))
+	}
+	#[cfg(not(feature = "bulk"))]
+	{
+		let _ = (instructions, pos);
+		false
+	}
+}
+
+/// Number of values taken and produced by a SIMD instruction.
+#[cfg(feature = "simd")]
+pub(crate) fn simd_stack_effect(instruction: &parity_wasm::elements::SimdInstruction) -> (u32, u32) {
+	use parity_wasm::elements::SimdInstruction::*;
+
+	match *instruction {
+		V128Const(_) => (0, 1),
+		V128Store(_) => (2, 0),
+		V128Bitselect => (3, 1),
+		V128Load(_) | I8x16Splat | I16x8Splat | I32x4Splat | I64x2Splat | F32x4Splat | F64x2Splat
+		| I8x16ExtractLaneS(_) | I8x16ExtractLaneU(_) | I16x8ExtractLaneS(_) | I16x8ExtractLaneU(_)
+		| I32x4ExtractLane(_) | I64x2ExtractLane(_) | F32x4ExtractLane(_) | F64x2ExtractLane(_)
+		| I8x16Neg | I16x8Neg | I32x4Neg | I64x2Neg | V128Not | I8x16AnyTrue | I16x8AnyTrue
+		| I32x4AnyTrue | I64x2AnyTrue | I8x16AllTrue | I16x8AllTrue | I32x4AllTrue | I64x2AllTrue
+		| F32x4Neg | F64x2Neg | F32x4Abs | F64x2Abs | F32x4Sqrt | F64x2Sqrt | F32x4ConvertSI32x4
+		| F32x4ConvertUI32x4 | F64x2ConvertSI64x2 | F64x2ConvertUI64x2 | I32x4TruncSF32x4Sat
+		| I32x4TruncUF32x4Sat | I64x2TruncSF64x2Sat | I64x2TruncUF64x2Sat => (1, 1),
+		// All remaining instructions are lane replacements, shuffles, shifts, comparisons and
+		// binary operators.
+		_ => (2, 1),
+	}
+}
+
+/// Number of values taken and produced by an atomic memory instruction.
+#[cfg(feature = "atomics")]
+pub(crate) fn atomic_stack_effect(instruction: &parity_wasm::elements::AtomicsInstruction) -> (u32, u32) {
+	use parity_wasm::elements::AtomicsInstruction::*;
+
+	match *instruction {
+		I32AtomicWait(_) | I64AtomicWait(_) => (3, 1),
+		AtomicWake(_) => (2, 1),
+		I32AtomicLoad(_) | I64AtomicLoad(_) | I32AtomicLoad8u(_) | I32AtomicLoad16u(_)
+		| I64AtomicLoad8u(_) | I64AtomicLoad16u(_) | I64AtomicLoad32u(_) => (1, 1),
+		I32AtomicStore(_) | I64AtomicStore(_) | I32AtomicStore8u(_) | I32AtomicStore16u(_)
+		| I64AtomicStore8u(_) | I64AtomicStore16u(_) | I64AtomicStore32u(_) => (2, 0),
+		I32AtomicRmwCmpxchg(_) | I64AtomicRmwCmpxchg(_) | I32AtomicRmwCmpxchg8u(_)
+		| I32AtomicRmwCmpxchg16u(_) | I64AtomicRmwCmpxchg8u(_) | I64AtomicRmwCmpxchg16u(_)
+		| I64AtomicRmwCmpxchg32u(_) => (3, 1),
+		// All remaining instructions are read-modify-write operators.
+		_ => (2, 1),
+	}
+}
+
+/// Number of parameters and results of every function, by function index.
+pub(crate) fn function_arities(module: &elements::Module) -> Vec<(usize, usize)> {
+	let types = module.type_section().map_or(&[][..], |type_section| type_section.types());
+	let arity = |type_ref: u32| match types.get(type_ref as usize) {
+		Some(Type::Function(ty)) => (ty.params().len(), ty.results().len()),
+		None => (0, 0),
+	};
+
+	module.import_section()
+		.map_or(&[][..], |import_section| import_section.entries())
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Function(type_ref) => Some(arity(*type_ref)),
+			_ => None,
+		})
+		.chain(
+			module.function_section()
+				.map_or(&[][..], |function_section| function_section.entries())
+				.iter()
+				.map(|func| arity(func.type_ref()))
+		)
+		.collect()
+}
+
+/// Number of values popped and pushed by `instruction`, or `None` if it changes the control flow.
+pub(crate) fn stack_effect(
+	instruction: &Instruction,
+	module: &elements::Module,
+	arities: &[(usize, usize)],
+) -> Option<(usize, usize)> {
+	match *instruction {
+		Instruction::Call(func) => arities.get(func as usize).copied(),
+		Instruction::CallIndirect(type_ref, _) => match module.type_section()?.types().get(type_ref as usize)? {
+			Type::Function(ty) => Some((ty.params().len() + 1, ty.results().len())),
+		},
+		_ => operator_stack_effect(instruction),
+	}
+}
+
+/// Number of values popped and pushed by `instruction`, or `None` if it changes the control flow or
+/// is a call, whose effect depends on the callee.
+pub(crate) fn operator_stack_effect(instruction: &Instruction) -> Option<(usize, usize)> {
+	use parity_wasm::elements::Instruction::*;
+
+	Some(match *instruction {
+		Unreachable | Block(_) | Loop(_) | If(_) | Else | End | Br(_) | BrIf(_) | BrTable(_)
+		| Return | Call(_) | CallIndirect(_, _) => return None,
+
+		Nop => (0, 0),
+		Drop => (1, 0),
+		Select => (3, 1),
+
+		GetLocal(_) | GetGlobal(_) | CurrentMemory(_) => (0, 1),
+		I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => (0, 1),
+		SetLocal(_) | SetGlobal(_) => (1, 0),
+		TeeLocal(_) | GrowMemory(_) => (1, 1),
+
+		I32Load(_, _) | I64Load(_, _) | F32Load(_, _) | F64Load(_, _) | I32Load8S(_, _)
+		| I32Load8U(_, _) | I32Load16S(_, _) | I32Load16U(_, _) | I64Load8S(_, _)
+		| I64Load8U(_, _) | I64Load16S(_, _) | I64Load16U(_, _) | I64Load32S(_, _)
+		| I64Load32U(_, _) => (1, 1),
+
+		I32Store(_, _) | I64Store(_, _) | F32Store(_, _) | F64Store(_, _) | I32Store8(_, _)
+		| I32Store16(_, _) | I64Store8(_, _) | I64Store16(_, _) | I64Store32(_, _) => (2, 0),
+
+		I32Eqz | I64Eqz | I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs
+		| F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg
+		| F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => (1, 1),
+
+		I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 | I64ExtendSI32
+		| I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64 | I64TruncUF64
+		| F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64
+		| F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32
+		| I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => (1, 1),
+
+		#[cfg(feature = "sign_ext")]
+		SignExt(_) => (1, 1),
+
+		#[cfg(feature = "simd")]
+		Simd(ref simd) => {
+			let (pop, push) = simd_stack_effect(simd);
+			(pop as usize, push as usize)
+		}
+
+		#[cfg(feature = "atomics")]
+		Atomics(ref atomic) => {
+			let (pop, push) = atomic_stack_effect(atomic);
+			(pop as usize, push as usize)
+		}
+
+		#[cfg(feature = "bulk")]
+		Bulk(elements::BulkInstruction::MemoryDrop(_))
+		| Bulk(elements::BulkInstruction::TableDrop(_)) => (0, 0),
+		#[cfg(feature = "bulk")]
+		Bulk(_) => (3, 0),
+
+		// All remaining instructions are comparisons and binary operators.
+		_ => (2, 1),
+	})
+}
+
+/// Walk the instructions of a function body, reporting them to `visitor`.
+///
+/// Returns an error if the control blocks of the body are not properly nested or if one of the
+/// callbacks fails.
+pub fn visit<V: Visitor>(
+	instructions: &[Instruction],
+	visitor: &mut V,
+) -> Result<(), Error<V::Error>> {
+	let mut frames = Frames::default();
+	frames.stack.push(Frame { kind: FrameKind::Function, start_pos: 0 });
+
+	for (pos, instruction) in instructions.iter().enumerate() {
+		if frames.is_empty() {
+			return Err(Error::TrailingInstruction(pos));
+		}
+
+		if is_copy_padding(instructions, pos) {
+			continue;
+		}
+
+		match instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) => {
+				let kind = match instruction {
+					Instruction::Block(_) => FrameKind::Block,
+					Instruction::Loop(_) => FrameKind::Loop,
+					_ => FrameKind::If,
+				};
+				frames.stack.push(Frame { kind, start_pos: pos });
+				visitor.enter_block(pos, instruction, &frames).map_err(Error::Visitor)?;
+			}
+			Instruction::Else => {
+				if frames.top().map(|frame| frame.kind) != Some(FrameKind::If) {
+					return Err(Error::UnexpectedElse(pos));
+				}
+				visitor.visit_else(pos, &frames).map_err(Error::Visitor)?;
+			}
+			Instruction::End => {
+				let frame = frames.stack.pop().expect("frames are not empty; checked above; qed");
+				visitor.leave_block(pos, &frame, &frames).map_err(Error::Visitor)?;
+			}
+			_ => {
+				visitor.visit_instruction(pos, instruction, &frames).map_err(Error::Visitor)?;
+			}
+		}
+	}
+
+	if !frames.is_empty() {
+		return Err(Error::UnclosedBlocks);
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements::{BlockType, Instruction::*};
+
+	#[derive(Default)]
+	struct Recorder {
+		events: Vec<(&'static str, usize, usize)>,
+	}
+
+	impl Visitor for Recorder {
+		type Error = ();
+
+		fn enter_block(&mut self, pos: usize, _: &Instruction, frames: &Frames) -> Result<(), ()> {
+			self.events.push(("enter", pos, frames.len()));
+			Ok(())
+		}
+
+		fn visit_else(&mut self, pos: usize, frames: &Frames) -> Result<(), ()> {
+			self.events.push(("else", pos, frames.len()));
+			Ok(())
+		}
+
+		fn leave_block(&mut self, pos: usize, _: &Frame, frames: &Frames) -> Result<(), ()> {
+			self.events.push(("leave", pos, frames.len()));
+			Ok(())
+		}
+
+		fn visit_instruction(&mut self, pos: usize, _: &Instruction, frames: &Frames) -> Result<(), ()> {
+			self.events.push(("instr", pos, frames.len()));
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn nesting() {
+		let body = vec![
+			GetLocal(0),
+			If(BlockType::NoResult),
+				Loop(BlockType::NoResult),
+					Br(1),
+				End,
+			Else,
+				Nop,
+			End,
+			End,
+		];
+
+		let mut recorder = Recorder::default();
+		visit(&body, &mut recorder).unwrap();
+
+		assert_eq!(
+			recorder.events,
+			vec![
+				("instr", 0, 1),
+				("enter", 1, 2),
+				("enter", 2, 3),
+				("instr", 3, 3),
+				("leave", 4, 2),
+				("else", 5, 2),
+				("instr", 6, 2),
+				("leave", 7, 1),
+				("leave", 8, 0),
+			]
+		);
+	}
+
+	#[test]
+	fn branch_targets() {
+		struct Targets(Vec<Option<FrameKind>>);
+
+		impl Visitor for Targets {
+			type Error = ();
+
+			fn visit_instruction(&mut self, _: usize, instruction: &Instruction, frames: &Frames) -> Result<(), ()> {
+				if let Br(label) = instruction {
+					self.0.push(frames.target(*label).map(|frame| frame.kind));
+				}
+				Ok(())
+			}
+		}
+
+		let body = vec![
+			Block(BlockType::NoResult),
+				Loop(BlockType::NoResult),
+					Br(0),
+					Br(1),
+					Br(2),
+					Br(3),
+				End,
+			End,
+			End,
+		];
+
+		let mut targets = Targets(Vec::new());
+		visit(&body, &mut targets).unwrap();
+
+		assert_eq!(
+			targets.0,
+			vec![Some(FrameKind::Loop), Some(FrameKind::Block), Some(FrameKind::Function), None]
+		);
+	}
+
+	#[test]
+	fn unbalanced() {
+		let mut recorder = Recorder::default();
+		assert_eq!(visit(&[Nop], &mut recorder), Err(Error::UnclosedBlocks));
+		assert_eq!(visit(&[End, Nop], &mut recorder), Err(Error::TrailingInstruction(1)));
+		assert_eq!(visit(&[Else, End], &mut recorder), Err(Error::UnexpectedElse(0)));
+	}
+}
@@ -0,0 +1,133 @@
+//! Variants of the passes taking and returning modules in the text format.
+//!
+//! Exported behind the `wat` feature, so that tooling and tests can run the passes on text modules
+//! without depending on wabt themselves. Modules are converted to and from the binary format with
+//! wabt, so the output is printed the way `wasm2wat` does.
+
+use crate::std::fmt;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+
+use crate::gas::{self, GasConfig};
+use crate::optimizer::{self, optimize};
+use crate::pipeline::{self, PipelineConfig};
+use crate::rules::Rules;
+use crate::stack_height;
+
+/// Error of a pass run on a module in the text format.
+#[derive(Debug)]
+pub enum Error {
+	/// wabt failed to parse the source or to print the output.
+	Text(String),
+	/// The parsed module couldn't be decoded or the output couldn't be encoded.
+	Encoding(elements::Error),
+	Pruning(optimizer::Error),
+	Gas(gas::Error),
+	StackHeight(stack_height::Error),
+	Pipeline(pipeline::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Text(e) => write!(f, "{}", e),
+			Error::Encoding(e) => write!(f, "{}", e),
+			Error::Pruning(_) => write!(f, "Pruning failed due to missing export section"),
+			Error::Gas(e) => write!(f, "Gas metering failed: {}", e),
+			Error::StackHeight(e) => write!(f, "Stack height limiting failed: {:?}", e),
+			Error::Pipeline(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl From<elements::Error> for Error {
+	fn from(e: elements::Error) -> Self {
+		Error::Encoding(e)
+	}
+}
+
+fn parse(source: &str) -> Result<elements::Module, Error> {
+	let binary = wabt::wat2wasm(source).map_err(|e| Error::Text(e.to_string()))?;
+	Ok(elements::deserialize_buffer(&binary)?)
+}
+
+fn print(module: elements::Module) -> Result<String, Error> {
+	let binary: Vec<u8> = elements::serialize(module)?;
+	wabt::wasm2wat(binary).map_err(|e| Error::Text(e.to_string()))
+}
+
+/// Like `inject_gas_counter` with the gas function imported from `env`, but on a module in the
+/// text format.
+pub fn inject_gas_counter_wat<R: Rules>(source: &str, rules: &R) -> Result<String, Error> {
+	inject_gas_counter_wat_with_config(source, rules, GasConfig::default())
+}
+
+/// Like `inject_gas_counter`, but on a module in the text format.
+pub fn inject_gas_counter_wat_with_config<'a, R: Rules, C: Into<GasConfig<'a>>>(
+	source: &str,
+	rules: &R,
+	config: C,
+) -> Result<String, Error> {
+	let module = gas::inject_gas_counter(parse(source)?, rules, config).map_err(|(_, e)| Error::Gas(e))?;
+	print(module)
+}
+
+/// Like `stack_height::inject_limiter`, but on a module in the text format.
+pub fn inject_limiter_wat(source: &str, stack_limit: u32) -> Result<String, Error> {
+	let module = stack_height::inject_limiter(parse(source)?, stack_limit).map_err(Error::StackHeight)?;
+	print(module)
+}
+
+/// Like `optimize`, but on a module in the text format.
+pub fn optimize_wat(source: &str, used_exports: Vec<&str>) -> Result<String, Error> {
+	let mut module = parse(source)?;
+	optimize(&mut module, used_exports).map_err(Error::Pruning)?;
+	print(module)
+}
+
+/// Like `pipeline::instrument`, but on a module in the text format.
+pub fn instrument_wat(source: &str, config: &PipelineConfig) -> Result<String, Error> {
+	let module = pipeline::instrument(parse(source)?, config).map_err(Error::Pipeline)?;
+	print(module)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+
+	#[test]
+	fn same_as_binary() {
+		let source = r#"
+		(module
+			(func)
+			(func (export "call") (param i32) (result i32)
+				(i32.add (local.get 0) (i32.const 1))))
+		"#;
+
+		let rules = rules::Set::default();
+		let expected = gas::inject_gas_counter(parse(source).unwrap(), &rules, "env").unwrap();
+		let output = inject_gas_counter_wat(source, &rules).unwrap();
+		assert_eq!(parse(&output).unwrap(), expected);
+
+		let expected = stack_height::inject_limiter(parse(source).unwrap(), 1024).unwrap();
+		let output = inject_limiter_wat(source, 1024).unwrap();
+		assert_eq!(parse(&output).unwrap(), expected);
+
+		let mut expected = parse(source).unwrap();
+		optimize(&mut expected, vec!["call"]).unwrap();
+		let output = optimize_wat(source, vec!["call"]).unwrap();
+		assert_eq!(parse(&output).unwrap(), expected);
+	}
+
+	#[test]
+	fn errors() {
+		assert!(matches!(inject_limiter_wat("(module", 1024), Err(Error::Text(_))));
+		assert!(matches!(
+			optimize_wat(r#"(module (func))"#, vec![]),
+			Err(Error::Pruning(optimizer::Error::NoExportSection)),
+		));
+	}
+}
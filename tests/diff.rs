@@ -26,6 +26,15 @@ fn validate_wasm(binary: &[u8]) -> Result<(), wabt::Error> {
 }
 
 fn run_diff_test<F: FnOnce(&[u8]) -> Vec<u8>>(test_dir: &str, name: &str, test: F) {
+	run_diff_test_with_expectation(test_dir, name, name, test)
+}
+
+fn run_diff_test_with_expectation<F: FnOnce(&[u8]) -> Vec<u8>>(
+	test_dir: &str,
+	name: &str,
+	expectation: &str,
+	test: F,
+) {
 	// FIXME: not going to work on windows?
 	let mut fixture_path = PathBuf::from(concat!(
 		env!("CARGO_MANIFEST_DIR"),
@@ -40,7 +49,7 @@ fn run_diff_test<F: FnOnce(&[u8]) -> Vec<u8>>(test_dir: &str, name: &str, test:
 		"/tests/expectations/"
 	));
 	expected_path.push(test_dir);
-	expected_path.push(name);
+	expected_path.push(expectation);
 
 	let fixture_wat = slurp(&fixture_path).expect("Failed to read fixture");
 	let fixture_wasm = wabt::wat2wasm(fixture_wat).expect("Failed to read fixture");
@@ -101,15 +110,22 @@ mod stack_height {
 mod gas {
 	use super::*;
 
+	// A fixture metered with the default configuration, or with the given one and an expectation
+	// named after the test, e.g. with a conservative choice of the division into metered blocks
+	// relaxed.
 	macro_rules! def_gas_test {
 		( $name:ident ) => {
+			def_gas_test!($name, $name, "env");
+		};
+		( $name:ident, $fixture:ident, $config:expr ) => {
 			#[test]
 			fn $name() {
-				run_diff_test("gas", concat!(stringify!($name), ".wat"), |input| {
+				let fixture = concat!(stringify!($fixture), ".wat");
+				run_diff_test_with_expectation("gas", fixture, concat!(stringify!($name), ".wat"), |input| {
 					let rules = utils::rules::Set::default();
 
 					let module = elements::deserialize_buffer(input).expect("Failed to deserialize");
-					let instrumented = utils::inject_gas_counter(module, &rules, "env")
+					let instrumented = utils::inject_gas_counter(module, &rules, $config)
 						.expect("Failed to instrument with gas metering");
 					elements::serialize(instrumented).expect("Failed to serialize")
 				});
@@ -123,4 +139,46 @@ mod gas {
 	def_gas_test!(start);
 	def_gas_test!(call);
 	def_gas_test!(branch);
+	def_gas_test!(loop_return);
+
+	def_gas_test!(loop_return_merged_returns, loop_return, utils::GasConfig::default().with_merged_returns());
+	def_gas_test!(
+		loop_return_merged_loop_branches,
+		loop_return,
+		utils::GasConfig::default().with_merged_loop_branches()
+	);
+}
+
+#[cfg(feature = "legacy")]
+mod gas_legacy {
+	use super::*;
+
+	// The fixtures are binaries, so that the output doesn't depend on the version of wabt, and
+	// the expectations are the output of pwasm-utils 0.18.1, which must be matched byte for byte.
+	macro_rules! def_gas_legacy_test {
+		( $name:ident ) => {
+			#[test]
+			fn $name() {
+				let name = concat!(stringify!($name), ".wasm");
+				let fixture = slurp(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/gas-legacy/").to_owned() + name)
+					.expect("Failed to read fixture");
+				let expected = slurp(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/expectations/gas-legacy/").to_owned() + name)
+					.expect("Failed to read expectation");
+
+				let rules = utils::rules::Set::default().with_grow_cost(10000);
+				let module = elements::deserialize_buffer(&fixture).expect("Failed to deserialize");
+				let instrumented = utils::inject_gas_counter_legacy(module, &rules, "env")
+					.expect("Failed to instrument with gas metering");
+
+				assert!(elements::serialize(instrumented).expect("Failed to serialize") == expected);
+			}
+		};
+	}
+
+	def_gas_legacy_test!(ifs);
+	def_gas_legacy_test!(simple);
+	def_gas_legacy_test!(start);
+	def_gas_legacy_test!(call);
+	def_gas_legacy_test!(branch);
+	def_gas_legacy_test!(grow);
 }
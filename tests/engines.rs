@@ -0,0 +1,226 @@
+//! Runs gas metered modules on wasmi and wasmtime and checks that both engines charge the same
+//! amount of gas and compute the same results as the original modules, so that the injected code
+//! doesn't depend on behaviour which differs between engines.
+//!
+//! Requires the `engines` feature.
+
+use parity_wasm::elements;
+use pwasm_utils::{self as utils, rules, GasConfig};
+
+/// Outcome of calling an export: its result, or `None` if it trapped, and the gas charged.
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+	result: Option<i32>,
+	gas: u64,
+}
+
+struct Case {
+	name: &'static str,
+	source: &'static str,
+	export: &'static str,
+	args: &'static [i32],
+}
+
+const CASES: &[Case] = &[
+	Case {
+		name: "loop",
+		source: r#"
+			(module
+				(func (export "fib") (param $n i32) (result i32)
+					(local $a i32) (local $b i32)
+					(local.set $b (i32.const 1))
+					(block $done
+						(loop $next
+							(br_if $done (i32.eqz (local.get $n)))
+							(local.set $b (i32.add (local.get $a) (local.tee $a (local.get $b))))
+							(local.set $n (i32.sub (local.get $n) (i32.const 1)))
+							(br $next)))
+					(local.get $a)))
+		"#,
+		export: "fib",
+		args: &[20],
+	},
+	Case {
+		name: "grow",
+		source: r#"
+			(module
+				(memory 1 4)
+				(func (export "grow") (param $pages i32) (result i32)
+					(i32.add
+						(i32.mul (memory.grow (local.get $pages)) (i32.const 100))
+						(memory.size))))
+		"#,
+		export: "grow",
+		args: &[2],
+	},
+	Case {
+		name: "failed_grow",
+		source: r#"
+			(module
+				(memory 1 4)
+				(func (export "grow") (param $pages i32) (result i32)
+					(memory.grow (local.get $pages))))
+		"#,
+		export: "grow",
+		args: &[10],
+	},
+	Case {
+		name: "calls",
+		source: r#"
+			(module
+				(type $binary (func (param i32 i32) (result i32)))
+				(table 2 funcref)
+				(elem (i32.const 0) $add $sub)
+				(func $add (type $binary) (i32.add (local.get 0) (local.get 1)))
+				(func $sub (type $binary)
+					(if (result i32) (i32.gt_s (local.get 0) (local.get 1))
+						(then (i32.sub (local.get 0) (local.get 1)))
+						(else (i32.sub (local.get 1) (local.get 0)))))
+				(func (export "apply") (param $op i32) (result i32)
+					(call_indirect (type $binary)
+						(call $add (i32.const 3) (i32.const 4))
+						(i32.const 10)
+						(local.get $op))))
+		"#,
+		export: "apply",
+		args: &[1],
+	},
+	Case {
+		name: "trap",
+		source: r#"
+			(module
+				(func (export "trap") (param i32) (result i32)
+					(if (local.get 0)
+						(then unreachable))
+					(i32.const 1)))
+		"#,
+		export: "trap",
+		args: &[1],
+	},
+];
+
+fn configs() -> Vec<GasConfig<'static>> {
+	vec![
+		GasConfig::default(),
+		GasConfig::default().with_i64_amounts(),
+		GasConfig::default().with_exit_charges(),
+		GasConfig::default().with_inline_grow_charges(),
+	]
+}
+
+fn instrument(source: &str, rules: &rules::Set, config: GasConfig) -> Vec<u8> {
+	let module = elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to parse case"))
+		.expect("Failed to decode case");
+	let module = utils::inject_gas_counter(module, rules, config).expect("Failed to inject gas");
+	elements::serialize(module).expect("Failed to encode instrumented module")
+}
+
+mod wasmi_engine {
+	use super::Outcome;
+	use wasmi::{Caller, Engine, Linker, Module, Store, Value};
+
+	pub fn run(binary: &[u8], export: &str, args: &[i32], i64_amounts: bool) -> Outcome {
+		let engine = Engine::default();
+		let module = Module::new(&engine, binary).expect("wasmi rejected the module");
+		let mut linker = Linker::new(&engine);
+		if i64_amounts {
+			linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i64| {
+				*caller.data_mut() += amount as u64;
+			})
+		} else {
+			linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i32| {
+				*caller.data_mut() += amount as u32 as u64;
+			})
+		}.expect("Failed to define the gas function");
+
+		let mut store = Store::new(&engine, 0);
+		let instance = linker.instantiate(&mut store, &module)
+			.and_then(|instance| instance.start(&mut store))
+			.expect("Failed to instantiate on wasmi");
+		let func = instance.get_func(&store, export).expect("Export not found");
+		let args: Vec<_> = args.iter().map(|arg| Value::I32(*arg)).collect();
+		let mut results = [Value::I32(0)];
+		let result = match func.call(&mut store, &args, &mut results) {
+			Ok(()) => results[0].i32(),
+			Err(_) => None,
+		};
+		Outcome { result, gas: *store.data() }
+	}
+}
+
+mod wasmtime_engine {
+	use super::Outcome;
+	use wasmtime::{Caller, Engine, Linker, Module, Store, Val};
+
+	pub fn run(binary: &[u8], export: &str, args: &[i32], i64_amounts: bool) -> Outcome {
+		let engine = Engine::default();
+		let module = Module::new(&engine, binary).expect("wasmtime rejected the module");
+		let mut linker = Linker::new(&engine);
+		if i64_amounts {
+			linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i64| {
+				*caller.data_mut() += amount as u64;
+			})
+		} else {
+			linker.func_wrap("env", "gas", |mut caller: Caller<'_, u64>, amount: i32| {
+				*caller.data_mut() += amount as u32 as u64;
+			})
+		}.expect("Failed to define the gas function");
+
+		let mut store = Store::new(&engine, 0);
+		let instance = linker.instantiate(&mut store, &module).expect("Failed to instantiate on wasmtime");
+		let func = instance.get_func(&mut store, export).expect("Export not found");
+		let args: Vec<_> = args.iter().map(|arg| Val::I32(*arg)).collect();
+		let mut results = [Val::I32(0)];
+		let result = match func.call(&mut store, &args, &mut results) {
+			Ok(()) => Some(results[0].unwrap_i32()),
+			Err(_) => None,
+		};
+		Outcome { result, gas: *store.data() }
+	}
+}
+
+#[test]
+fn same_gas_on_both_engines() {
+	let rules = rules::Set::default().with_grow_cost(1000).with_local_cost(1);
+	for case in CASES {
+		let original = wabt::wat2wasm(case.source).expect("Failed to parse case");
+		let expected = wasmi_engine::run(&original, case.export, case.args, false).result;
+		assert_eq!(wasmtime_engine::run(&original, case.export, case.args, false).result, expected, "{}", case.name);
+
+		for config in configs() {
+			let binary = instrument(case.source, &rules, config);
+			let on_wasmi = wasmi_engine::run(&binary, case.export, case.args, config.i64_amounts);
+			let on_wasmtime = wasmtime_engine::run(&binary, case.export, case.args, config.i64_amounts);
+			assert_eq!(on_wasmi.result, expected, "{} with {:?}", case.name, config);
+			assert_eq!(on_wasmi, on_wasmtime, "{} with {:?}", case.name, config);
+			// Functions charged at their exits are not charged if they trap.
+			assert!(on_wasmi.gas > 0 || on_wasmi.result.is_none(), "{} with {:?}", case.name, config);
+		}
+	}
+}
+
+#[test]
+fn grow_charges() {
+	let source = r#"
+		(module
+			(memory 1)
+			(func (export "grow") (param i32) (result i32)
+				(memory.grow (local.get 0))))
+	"#;
+	// Growing from 1 to 21 pages, after charging 2 for the instructions.
+	let strategies = vec![
+		(rules::GrowStrategy::Flat(10), 2 + 20 * 10),
+		(rules::GrowStrategy::Tiered(vec![(16, 10), (64, 100)]), 2 + 15 * 10 + 5 * 100),
+		(rules::GrowStrategy::Stepped(vec![(8, 1000), (21, 20_000), (64, 300_000)]), 2 + 1000 + 20_000),
+	];
+
+	for (strategy, expected) in strategies {
+		let rules = rules::Set::default().with_grow_strategy(strategy.clone());
+		for config in configs() {
+			let binary = instrument(source, &rules, config);
+			let outcome = Outcome { result: Some(1), gas: expected };
+			assert_eq!(wasmi_engine::run(&binary, "grow", &[20], config.i64_amounts), outcome, "{:?}", strategy);
+			assert_eq!(wasmtime_engine::run(&binary, "grow", &[20], config.i64_amounts), outcome, "{:?}", strategy);
+		}
+	}
+}
@@ -0,0 +1,37 @@
+(module
+  (type (;0;) (func (param i32 i32) (result i32)))
+  (type (;1;) (func (param i32)))
+  (import "env" "gas" (func (;0;) (type 1)))
+  (func (;1;) (type 0) (param i32 i32) (result i32)
+    (local i32)
+    i32.const 2
+    call 0
+    loop  ;; label = @1
+      i32.const 14
+      call 0
+      local.get 0
+      local.get 2
+      i32.add
+      i32.load8_u
+      i32.eqz
+      if  ;; label = @2
+        i32.const 2
+        call 0
+        local.get 2
+        return
+      end
+      local.get 2
+      i32.const 1
+      i32.add
+      local.set 2
+      local.get 2
+      local.get 1
+      i32.lt_u
+      br_if 0 (;@1;)
+      i32.const 2
+      call 0
+      i32.const -1
+      local.set 2
+    end
+    local.get 2)
+  (memory (;0;) 1))
@@ -0,0 +1,19 @@
+(module
+	(memory 1)
+
+	(func $find (param $ptr i32) (param $len i32) (result i32)
+		(local $i i32)
+
+		(loop $next
+			(if (i32.eqz (i32.load8_u (i32.add (get_local $ptr) (get_local $i))))
+				(then (return (get_local $i))))
+
+			(set_local $i (i32.add (get_local $i) (i32.const 1)))
+			(br_if $next (i32.lt_u (get_local $i) (get_local $len)))
+
+			(set_local $i (i32.const -1))
+		)
+
+		get_local $i
+	)
+)
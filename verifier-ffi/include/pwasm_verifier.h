@@ -0,0 +1,46 @@
+/* C ABI of the artifact verifier of pwasm-utils, see verifier-ffi/src/lib.rs. */
+
+#ifndef PWASM_VERIFIER_H
+#define PWASM_VERIFIER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PWASM_VERIFIER_ABI_VERSION 1
+
+/* Status codes of verify_instrumentation. */
+#define PWASM_VERIFIED 0
+#define PWASM_REJECTED 1
+#define PWASM_INVALID_PROFILE (-1)
+#define PWASM_INVALID_ARGUMENT (-2)
+#define PWASM_INTERNAL_ERROR (-3)
+
+/* The version of the ABI the library implements. */
+uint32_t pwasm_verifier_abi_version(void);
+
+/*
+ * Verify that the artifact `bytes` is instrumented according to the profile given as UTF-8
+ * encoded JSON in `profile_json`. Returns one of the status codes above.
+ *
+ * The report is written to `report`, truncated to `report_capacity` bytes and not NUL-terminated.
+ * Its full length is stored at `report_len` unless it is null. `report` may be null if
+ * `report_capacity` is zero.
+ */
+int32_t verify_instrumentation(
+	const uint8_t *bytes,
+	size_t bytes_len,
+	const uint8_t *profile_json,
+	size_t profile_json_len,
+	uint8_t *report,
+	size_t report_capacity,
+	size_t *report_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PWASM_VERIFIER_H */
@@ -0,0 +1,156 @@
+//! C ABI of `pwasm_utils::attestation::verify_artifact`, for light clients and validators which
+//! are not written in Rust.
+//!
+//! The ABI consists of `verify_instrumentation` and `pwasm_verifier_abi_version` only, declared in
+//! `include/pwasm_verifier.h`. The caller owns all memory: the artifact and the profile are
+//! passed as buffers and the report is written into a buffer of the caller, so no allocation
+//! callbacks are needed. Changes of the ABI increment `ABI_VERSION`.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice, str};
+
+use pwasm_utils::attestation::verify_artifact;
+use pwasm_utils::build_support::Profile;
+
+/// Version of the ABI, returned by `pwasm_verifier_abi_version`.
+pub const ABI_VERSION: u32 = 1;
+
+/// The artifact passed all checks of the profile.
+pub const VERIFIED: i32 = 0;
+/// The artifact failed a check of the profile. The report lists the violations.
+pub const REJECTED: i32 = 1;
+/// The profile is not valid UTF-8 or not a valid profile, see `Profile::from_json`. The report
+/// holds the parse error.
+pub const INVALID_PROFILE: i32 = -1;
+/// A pointer is null while its length is not zero.
+pub const INVALID_ARGUMENT: i32 = -2;
+/// The verifier panicked, which is a bug. The report holds the panic message if there is one.
+pub const INTERNAL_ERROR: i32 = -3;
+
+/// The version of the ABI the library implements.
+#[no_mangle]
+pub extern "C" fn pwasm_verifier_abi_version() -> u32 {
+	ABI_VERSION
+}
+
+/// The `len` bytes at `data`, which may be null if `len` is zero.
+unsafe fn buffer<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+	if len == 0 {
+		Some(&[])
+	} else if data.is_null() {
+		None
+	} else {
+		Some(slice::from_raw_parts(data, len))
+	}
+}
+
+fn verify(bytes: &[u8], profile_json: &[u8]) -> (i32, String) {
+	let profile = match str::from_utf8(profile_json) {
+		Ok(profile_json) => Profile::from_json(profile_json),
+		Err(e) => return (INVALID_PROFILE, e.to_string()),
+	};
+	let profile = match profile {
+		Ok(profile) => profile,
+		Err(e) => return (INVALID_PROFILE, e.to_string()),
+	};
+
+	let attestation = verify_artifact(bytes, &profile);
+	let status = if attestation.passed() { VERIFIED } else { REJECTED };
+	(status, attestation.to_string())
+}
+
+/// Verify that the artifact of `bytes_len` bytes at `bytes` is instrumented according to the
+/// profile given as the `profile_json_len` bytes of UTF-8 encoded JSON at `profile_json`.
+///
+/// Returns one of the status codes, `VERIFIED` if the artifact passed all checks. A report in
+/// UTF-8, e.g. the checks run and the violations found, is written to the buffer of
+/// `report_capacity` bytes at `report`, truncated to its capacity and without a terminating NUL.
+/// Its full length is stored at `report_len`, so that it can be read in full by calling again
+/// with a larger buffer. `report` may be null if `report_capacity` is zero, `report_len` may be
+/// null if the length is not needed.
+///
+/// # Safety
+///
+/// The pointers which are not null have to be valid for the given number of bytes, and the
+/// buffers of the caller must not overlap with the report.
+#[no_mangle]
+pub unsafe extern "C" fn verify_instrumentation(
+	bytes: *const u8,
+	bytes_len: usize,
+	profile_json: *const u8,
+	profile_json_len: usize,
+	report: *mut u8,
+	report_capacity: usize,
+	report_len: *mut usize,
+) -> i32 {
+	if report.is_null() && report_capacity != 0 {
+		return INVALID_ARGUMENT;
+	}
+
+	let (status, message) = match (buffer(bytes, bytes_len), buffer(profile_json, profile_json_len)) {
+		(Some(bytes), Some(profile_json)) => {
+			panic::catch_unwind(AssertUnwindSafe(|| verify(bytes, profile_json)))
+				.unwrap_or_else(|payload| {
+					let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+						.or_else(|| payload.downcast_ref::<String>().cloned())
+						.unwrap_or_default();
+					(INTERNAL_ERROR, message)
+				})
+		}
+		_ => (INVALID_ARGUMENT, String::from("null pointer with a non-zero length")),
+	};
+
+	let written = message.len().min(report_capacity);
+	if written != 0 {
+		ptr::copy_nonoverlapping(message.as_ptr(), report, written);
+	}
+	if !report_len.is_null() {
+		*report_len = message.len();
+	}
+	status
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// `(module (func (export "f")))`.
+	const UNMETERED: &[u8] = &[
+		0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03, 0x02,
+		0x01, 0x00, 0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00, 0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b,
+	];
+
+	fn call(bytes: &[u8], profile_json: &str, capacity: usize) -> (i32, String, usize) {
+		let mut report = vec![0u8; capacity];
+		let mut report_len = 0;
+		let status = unsafe {
+			verify_instrumentation(
+				bytes.as_ptr(),
+				bytes.len(),
+				profile_json.as_ptr(),
+				profile_json.len(),
+				if capacity == 0 { ptr::null_mut() } else { report.as_mut_ptr() },
+				capacity,
+				&mut report_len,
+			)
+		};
+		report.truncate(report_len.min(capacity));
+		(status, String::from_utf8(report).unwrap(), report_len)
+	}
+
+	#[test]
+	fn statuses() {
+		let (status, report, _) = call(UNMETERED, r#"{ "limits": { "max_functions": 1 } }"#, 1024);
+		assert_eq!(status, VERIFIED);
+		assert!(report.starts_with("ok"));
+
+		let (status, report, report_len) = call(UNMETERED, r#"{ "limits": { "max_functions": 0 } }"#, 6);
+		assert_eq!(status, REJECTED);
+		assert_eq!(report, "FAILED");
+		assert!(report_len > 6);
+
+		assert_eq!(call(UNMETERED, r#"{ "gas_limit": 1 }"#, 0).0, INVALID_PROFILE);
+		assert_eq!(call(&UNMETERED[..12], "{}", 0).0, REJECTED);
+		assert_eq!(unsafe { verify_instrumentation(ptr::null(), 1, ptr::null(), 0, ptr::null_mut(), 0, ptr::null_mut()) }, INVALID_ARGUMENT);
+	}
+}